@@ -101,6 +101,15 @@ fi
 echo "Build test"
 RUST_BACKTRACE=1 cargo build $FEATURES
 
+if [ "$PROVIDER_NAME" = "mbed-crypto" ]; then
+    echo "Build test of the service with only the Mbed provider"
+    SLIM_FEATURES="--no-default-features --features=mbed-crypto-provider,direct-authenticator,unix-socket-listener,on-disk-manager"
+    RUST_BACKTRACE=1 cargo build $SLIM_FEATURES
+    if rustup component list | grep -q clippy; then
+        cargo clippy --all-targets $SLIM_FEATURES -- -D clippy::all -D clippy::cargo
+    fi
+fi
+
 echo "Static checks"
 # On native target clippy or fmt might not be available.
 if rustup component list | grep -q fmt; then
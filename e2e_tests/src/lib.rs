@@ -11,9 +11,10 @@ pub use parsec_client::error;
 use log::error;
 use parsec_client::auth::AuthenticationData;
 use parsec_client::core::basic_client::BasicClient;
+use parsec_client::core::interface::operations::list_keys::KeyInfo;
 use parsec_client::core::interface::operations::list_providers::ProviderInfo;
 use parsec_client::core::interface::operations::psa_algorithm::{
-    Algorithm, AsymmetricSignature, Hash,
+    Aead, Algorithm, AsymmetricEncryption, AsymmetricSignature, Hash, RawKeyAgreement,
 };
 use parsec_client::core::interface::operations::psa_key_attributes::{
     Attributes, Lifetime, Policy, Type, UsageFlags,
@@ -154,6 +155,39 @@ impl TestClient {
         )
     }
 
+    /// Generate a 1024 bits RSA key pair.
+    /// The key can only be used for encrypting/decrypting with the algorithm given and exporting
+    /// its public part.
+    pub fn generate_rsa_encryption_key(
+        &mut self,
+        key_name: String,
+        alg: AsymmetricEncryption,
+    ) -> Result<()> {
+        self.generate_key(
+            key_name,
+            Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::RsaKeyPair,
+                bits: 1024,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: false,
+                        verify_hash: false,
+                        sign_message: false,
+                        verify_message: false,
+                        export: true,
+                        encrypt: true,
+                        decrypt: true,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::AsymmetricEncryption(alg),
+                },
+            },
+        )
+    }
+
     /// Imports and creates a key with specific attributes.
     pub fn import_key(
         &mut self,
@@ -284,6 +318,86 @@ impl TestClient {
         )
     }
 
+    /// Encrypts a short message with a key.
+    pub fn asymmetric_encrypt(
+        &mut self,
+        key_name: String,
+        alg: AsymmetricEncryption,
+        plaintext: &[u8],
+        salt: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        self.basic_client
+            .psa_asymmetric_encrypt(key_name, alg, plaintext, salt)
+            .map_err(convert_error)
+    }
+
+    /// Decrypts a short message with a key.
+    pub fn asymmetric_decrypt(
+        &mut self,
+        key_name: String,
+        alg: AsymmetricEncryption,
+        ciphertext: &[u8],
+        salt: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        self.basic_client
+            .psa_asymmetric_decrypt(key_name, alg, ciphertext, salt)
+            .map_err(convert_error)
+    }
+
+    /// Encrypts and authenticates a message with a symmetric key.
+    pub fn aead_encrypt(
+        &mut self,
+        key_name: String,
+        alg: Aead,
+        nonce: &[u8],
+        additional_data: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.basic_client
+            .psa_aead_encrypt(key_name, alg, nonce, additional_data, plaintext)
+            .map_err(convert_error)
+    }
+
+    /// Decrypts and authenticates a message with a symmetric key.
+    pub fn aead_decrypt(
+        &mut self,
+        key_name: String,
+        alg: Aead,
+        nonce: &[u8],
+        additional_data: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.basic_client
+            .psa_aead_decrypt(key_name, alg, nonce, additional_data, ciphertext)
+            .map_err(convert_error)
+    }
+
+    /// Computes the shared secret of a private key and the public key of a peer.
+    pub fn raw_key_agreement(
+        &mut self,
+        alg: RawKeyAgreement,
+        private_key_name: String,
+        peer_key: &[u8],
+    ) -> Result<Vec<u8>> {
+        self.basic_client
+            .psa_raw_key_agreement(alg, private_key_name, peer_key)
+            .map_err(convert_error)
+    }
+
+    /// Hashes a message, no key being used.
+    pub fn hash_compute(&mut self, alg: Hash, input: &[u8]) -> Result<Vec<u8>> {
+        self.basic_client
+            .psa_hash_compute(alg, input)
+            .map_err(convert_error)
+    }
+
+    /// Checks that the hash of a message is the one given.
+    pub fn hash_compare(&mut self, alg: Hash, input: &[u8], hash: &[u8]) -> Result<()> {
+        self.basic_client
+            .psa_hash_compare(alg, input, hash)
+            .map_err(convert_error)
+    }
+
     /// Lists the provider available for the Parsec service.
     pub fn list_providers(&mut self) -> Result<Vec<ProviderInfo>> {
         self.basic_client.list_providers().map_err(convert_error)
@@ -296,6 +410,23 @@ impl TestClient {
             .map_err(convert_error)
     }
 
+    /// Lists the keys of the client, in all the providers.
+    pub fn list_keys(&mut self) -> Result<Vec<KeyInfo>> {
+        self.basic_client.list_keys().map_err(convert_error)
+    }
+
+    /// Lists the applications owning keys, for an administrator client.
+    pub fn list_clients(&mut self) -> Result<Vec<String>> {
+        self.basic_client.list_clients().map_err(convert_error)
+    }
+
+    /// Destroys all the keys of an application, for an administrator client.
+    pub fn delete_client(&mut self, client: String) -> Result<()> {
+        self.basic_client
+            .delete_client(client)
+            .map_err(convert_error)
+    }
+
     /// Executes a ping operation.
     pub fn ping(&mut self) -> Result<(u8, u8)> {
         self.basic_client.ping().map_err(convert_error)
@@ -0,0 +1,45 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use e2e_tests::TestClient;
+use parsec_client::core::interface::requests::{ResponseStatus, Result};
+
+// Administrator application of the test configurations.
+const ADMIN: &str = "parsec-e2e-admin";
+
+#[test]
+fn admin_operations_refused() {
+    let mut client = TestClient::new();
+
+    assert_eq!(
+        client.list_clients().unwrap_err(),
+        ResponseStatus::PsaErrorNotPermitted
+    );
+    assert_eq!(
+        client
+            .delete_client(String::from("any-client"))
+            .unwrap_err(),
+        ResponseStatus::PsaErrorNotPermitted
+    );
+}
+
+#[test]
+fn delete_client() -> Result<()> {
+    let mut client = TestClient::new();
+    client.do_not_destroy_keys();
+    client.set_auth(String::from("delete_client_app"));
+    client.generate_rsa_sign_key(String::from("delete_client_key"))?;
+
+    let mut admin = TestClient::new();
+    admin.set_auth(String::from(ADMIN));
+    assert!(admin
+        .list_clients()?
+        .contains(&String::from("delete_client_app")));
+
+    admin.delete_client(String::from("delete_client_app"))?;
+    assert!(!admin
+        .list_clients()?
+        .contains(&String::from("delete_client_app")));
+    assert!(client.list_keys()?.is_empty());
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use e2e_tests::TestClient;
+use parsec_client::core::interface::operations::psa_algorithm::*;
+use parsec_client::core::interface::operations::psa_key_attributes::*;
+use parsec_client::core::interface::requests::{Opcode, ResponseStatus, Result};
+
+const NONCE: [u8; 12] = [
+    0x48, 0x1C, 0x2B, 0x7A, 0x9D, 0x05, 0xE3, 0x66, 0x12, 0xF0, 0x8B, 0x34,
+];
+const ADDITIONAL_DATA: [u8; 8] = [0x64, 0x65, 0x76, 0x69, 0x63, 0x65, 0x2D, 0x31];
+const PLAINTEXT: [u8; 16] = [
+    0x69, 0x3E, 0xDB, 0x1B, 0x22, 0x79, 0x03, 0xF4, 0xC0, 0xBF, 0xD6, 0x91, 0x76, 0x37, 0x84, 0xA2,
+];
+
+const AES_GCM: Aead = Aead::AeadWithDefaultLengthTag(AeadWithDefaultLengthTag::Gcm);
+
+fn generate_aes_key(client: &mut TestClient, key_name: String) -> Result<()> {
+    client.generate_key(
+        key_name,
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type: Type::Aes,
+            bits: 256,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    sign_hash: false,
+                    verify_hash: false,
+                    sign_message: false,
+                    verify_message: false,
+                    export: false,
+                    encrypt: true,
+                    decrypt: true,
+                    cache: false,
+                    copy: false,
+                    derive: false,
+                },
+                permitted_algorithms: Algorithm::Aead(AES_GCM),
+            },
+        },
+    )
+}
+
+fn aead_supported(client: &mut TestClient) -> Result<bool> {
+    let provider = client.provider().unwrap();
+    Ok(client
+        .list_opcodes(provider)?
+        .contains(&Opcode::PsaAeadEncrypt))
+}
+
+#[test]
+fn aead_encrypt_no_key() -> Result<()> {
+    let key_name = String::from("aead_encrypt_no_key");
+    let mut client = TestClient::new();
+    if !aead_supported(&mut client)? {
+        return Ok(());
+    }
+
+    let status = client
+        .aead_encrypt(key_name, AES_GCM, &NONCE, &ADDITIONAL_DATA, &PLAINTEXT)
+        .expect_err("Key should not exist.");
+    assert_eq!(status, ResponseStatus::PsaErrorDoesNotExist);
+
+    Ok(())
+}
+
+#[test]
+fn aead_encrypt_and_decrypt_aes_gcm() -> Result<()> {
+    let key_name = String::from("aead_encrypt_and_decrypt_aes_gcm");
+    let mut client = TestClient::new();
+    if !aead_supported(&mut client)? {
+        return Ok(());
+    }
+
+    generate_aes_key(&mut client, key_name.clone())?;
+    let ciphertext = client.aead_encrypt(
+        key_name.clone(),
+        AES_GCM,
+        &NONCE,
+        &ADDITIONAL_DATA,
+        &PLAINTEXT,
+    )?;
+    let plaintext =
+        client.aead_decrypt(key_name, AES_GCM, &NONCE, &ADDITIONAL_DATA, &ciphertext)?;
+    assert_eq!(&PLAINTEXT[..], &plaintext[..]);
+
+    Ok(())
+}
+
+#[test]
+fn aead_decrypt_tampered() -> Result<()> {
+    let key_name = String::from("aead_decrypt_tampered");
+    let mut client = TestClient::new();
+    if !aead_supported(&mut client)? {
+        return Ok(());
+    }
+
+    generate_aes_key(&mut client, key_name.clone())?;
+    let mut ciphertext = client.aead_encrypt(
+        key_name.clone(),
+        AES_GCM,
+        &NONCE,
+        &ADDITIONAL_DATA,
+        &PLAINTEXT,
+    )?;
+    ciphertext[0] ^= 0x01;
+    let status = client
+        .aead_decrypt(key_name, AES_GCM, &NONCE, &ADDITIONAL_DATA, &ciphertext)
+        .expect_err("Decryption of a tampered ciphertext should fail.");
+    assert_eq!(status, ResponseStatus::PsaErrorInvalidSignature);
+
+    Ok(())
+}
@@ -0,0 +1,105 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use e2e_tests::TestClient;
+use parsec_client::core::interface::operations::psa_algorithm::*;
+use parsec_client::core::interface::requests::{Opcode, ResponseStatus, Result};
+
+const PLAINTEXT_MESSAGE: [u8; 32] = [
+    0x69, 0x3E, 0xDB, 0x1B, 0x22, 0x79, 0x03, 0xF4, 0xC0, 0xBF, 0xD6, 0x91, 0x76, 0x37, 0x84, 0xA2,
+    0x94, 0x8E, 0x92, 0x50, 0x35, 0xC2, 0x8C, 0x5C, 0x3C, 0xCA, 0xFE, 0x18, 0xE8, 0x81, 0x37, 0x78,
+];
+
+const OAEP_SHA256: AsymmetricEncryption = AsymmetricEncryption::RsaOaep {
+    hash_alg: Hash::Sha256,
+};
+
+fn encryption_supported(client: &mut TestClient) -> Result<bool> {
+    let provider = client.provider().unwrap();
+    Ok(client
+        .list_opcodes(provider)?
+        .contains(&Opcode::PsaAsymmetricEncrypt))
+}
+
+#[test]
+fn asym_encrypt_no_key() -> Result<()> {
+    let key_name = String::from("asym_encrypt_no_key");
+    let mut client = TestClient::new();
+    if !encryption_supported(&mut client)? {
+        return Ok(());
+    }
+
+    let status = client
+        .asymmetric_encrypt(
+            key_name,
+            AsymmetricEncryption::RsaPkcs1v15Crypt,
+            &PLAINTEXT_MESSAGE,
+            None,
+        )
+        .expect_err("Key should not exist.");
+    assert_eq!(status, ResponseStatus::PsaErrorDoesNotExist);
+
+    Ok(())
+}
+
+#[test]
+fn asym_encrypt_and_decrypt_rsa_pkcs() -> Result<()> {
+    let key_name = String::from("asym_encrypt_and_decrypt_rsa_pkcs");
+    let mut client = TestClient::new();
+    if !encryption_supported(&mut client)? {
+        return Ok(());
+    }
+
+    let alg = AsymmetricEncryption::RsaPkcs1v15Crypt;
+    client.generate_rsa_encryption_key(key_name.clone(), alg)?;
+    let ciphertext = client.asymmetric_encrypt(key_name.clone(), alg, &PLAINTEXT_MESSAGE, None)?;
+    let plaintext = client.asymmetric_decrypt(key_name, alg, &ciphertext, None)?;
+    assert_eq!(&PLAINTEXT_MESSAGE[..], &plaintext[..]);
+
+    Ok(())
+}
+
+#[test]
+fn asym_encrypt_and_decrypt_rsa_oaep_with_salt() -> Result<()> {
+    let key_name = String::from("asym_encrypt_and_decrypt_rsa_oaep_with_salt");
+    let salt: &[u8] = b"label";
+    let mut client = TestClient::new();
+    if !encryption_supported(&mut client)? {
+        return Ok(());
+    }
+
+    client.generate_rsa_encryption_key(key_name.clone(), OAEP_SHA256)?;
+    let ciphertext = client.asymmetric_encrypt(
+        key_name.clone(),
+        OAEP_SHA256,
+        &PLAINTEXT_MESSAGE,
+        Some(salt),
+    )?;
+    let plaintext =
+        client.asymmetric_decrypt(key_name.clone(), OAEP_SHA256, &ciphertext, Some(salt))?;
+    assert_eq!(&PLAINTEXT_MESSAGE[..], &plaintext[..]);
+
+    let status = client
+        .asymmetric_decrypt(key_name, OAEP_SHA256, &ciphertext, None)
+        .expect_err("Decryption with a different salt should fail.");
+    assert_eq!(status, ResponseStatus::PsaErrorInvalidPadding);
+
+    Ok(())
+}
+
+#[test]
+fn asym_encrypt_pkcs_with_salt() -> Result<()> {
+    let key_name = String::from("asym_encrypt_pkcs_with_salt");
+    let mut client = TestClient::new();
+    if !encryption_supported(&mut client)? {
+        return Ok(());
+    }
+
+    let alg = AsymmetricEncryption::RsaPkcs1v15Crypt;
+    client.generate_rsa_encryption_key(key_name.clone(), alg)?;
+    let status = client
+        .asymmetric_encrypt(key_name, alg, &PLAINTEXT_MESSAGE, Some(&b"label"[..]))
+        .expect_err("A salt can not be used with PKCS#1 v1.5.");
+    assert_eq!(status, ResponseStatus::PsaErrorInvalidArgument);
+
+    Ok(())
+}
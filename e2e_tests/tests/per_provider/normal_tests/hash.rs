@@ -0,0 +1,50 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use e2e_tests::TestClient;
+use parsec_client::core::interface::operations::psa_algorithm::Hash;
+use parsec_client::core::interface::requests::{Opcode, ResponseStatus, Result};
+
+const MESSAGE: &[u8] = b"abc";
+const SHA_256: [u8; 32] = [
+    0xBA, 0x78, 0x16, 0xBF, 0x8F, 0x01, 0xCF, 0xEA, 0x41, 0x41, 0x40, 0xDE, 0x5D, 0xAE, 0x22, 0x23,
+    0xB0, 0x03, 0x61, 0xA3, 0x96, 0x17, 0x7A, 0x9C, 0xB4, 0x10, 0xFF, 0x61, 0xF2, 0x00, 0x15, 0xAD,
+];
+
+fn hash_supported(client: &mut TestClient) -> Result<bool> {
+    let provider = client.provider().unwrap();
+    Ok(client
+        .list_opcodes(provider)?
+        .contains(&Opcode::PsaHashCompute))
+}
+
+#[test]
+fn hash_compute_sha_256() -> Result<()> {
+    let mut client = TestClient::new();
+    if !hash_supported(&mut client)? {
+        return Ok(());
+    }
+
+    let hash = client.hash_compute(Hash::Sha256, MESSAGE)?;
+    assert_eq!(&hash[..], &SHA_256[..]);
+
+    Ok(())
+}
+
+#[test]
+fn hash_compare_sha_256() -> Result<()> {
+    let mut client = TestClient::new();
+    if !hash_supported(&mut client)? {
+        return Ok(());
+    }
+
+    client.hash_compare(Hash::Sha256, MESSAGE, &SHA_256)?;
+
+    let mut wrong_hash = SHA_256;
+    wrong_hash[0] ^= 0x01;
+    let status = client
+        .hash_compare(Hash::Sha256, MESSAGE, &wrong_hash)
+        .expect_err("The hash should not match.");
+    assert_eq!(status, ResponseStatus::PsaErrorInvalidSignature);
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use e2e_tests::TestClient;
+use parsec_client::core::interface::operations::psa_algorithm::*;
+use parsec_client::core::interface::operations::psa_key_attributes::*;
+use parsec_client::core::interface::requests::{Opcode, ResponseStatus, Result};
+
+const ECDH: Algorithm = Algorithm::KeyAgreement(KeyAgreement::Raw(RawKeyAgreement::Ecdh));
+
+fn generate_ecdh_key(client: &mut TestClient, key_name: String) -> Result<()> {
+    client.generate_key(
+        key_name,
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type: Type::EccKeyPair {
+                curve_family: EccFamily::SecpR1,
+            },
+            bits: 256,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    sign_hash: false,
+                    verify_hash: false,
+                    sign_message: false,
+                    verify_message: false,
+                    export: false,
+                    encrypt: false,
+                    decrypt: false,
+                    cache: false,
+                    copy: false,
+                    derive: true,
+                },
+                permitted_algorithms: ECDH,
+            },
+        },
+    )
+}
+
+fn key_agreement_supported(client: &mut TestClient) -> Result<bool> {
+    let provider = client.provider().unwrap();
+    Ok(client
+        .list_opcodes(provider)?
+        .contains(&Opcode::PsaRawKeyAgreement))
+}
+
+#[test]
+fn raw_key_agreement_no_key() -> Result<()> {
+    let key_name = String::from("raw_key_agreement_no_key");
+    let mut client = TestClient::new();
+    if !key_agreement_supported(&mut client)? {
+        return Ok(());
+    }
+
+    let status = client
+        .raw_key_agreement(RawKeyAgreement::Ecdh, key_name, &[0x04; 65])
+        .expect_err("Key should not exist.");
+    assert_eq!(status, ResponseStatus::PsaErrorDoesNotExist);
+
+    Ok(())
+}
+
+#[test]
+fn raw_key_agreement_shared_secret() -> Result<()> {
+    let key_name_a = String::from("raw_key_agreement_shared_secret_a");
+    let key_name_b = String::from("raw_key_agreement_shared_secret_b");
+    let mut client = TestClient::new();
+    if !key_agreement_supported(&mut client)? {
+        return Ok(());
+    }
+
+    generate_ecdh_key(&mut client, key_name_a.clone())?;
+    generate_ecdh_key(&mut client, key_name_b.clone())?;
+    let public_key_a = client.export_public_key(key_name_a.clone())?;
+    let public_key_b = client.export_public_key(key_name_b.clone())?;
+
+    let secret_a = client.raw_key_agreement(RawKeyAgreement::Ecdh, key_name_a, &public_key_b)?;
+    let secret_b = client.raw_key_agreement(RawKeyAgreement::Ecdh, key_name_b, &public_key_a)?;
+    assert_eq!(secret_a.len(), 32);
+    assert_eq!(secret_a, secret_b);
+
+    Ok(())
+}
+
+#[test]
+fn raw_key_agreement_not_permitted() -> Result<()> {
+    let key_name = String::from("raw_key_agreement_not_permitted");
+    let mut client = TestClient::new();
+    if !key_agreement_supported(&mut client)? {
+        return Ok(());
+    }
+
+    client.generate_key(
+        key_name.clone(),
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type: Type::EccKeyPair {
+                curve_family: EccFamily::SecpR1,
+            },
+            bits: 256,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    sign_hash: true,
+                    verify_hash: true,
+                    sign_message: false,
+                    verify_message: false,
+                    export: false,
+                    encrypt: false,
+                    decrypt: false,
+                    cache: false,
+                    copy: false,
+                    derive: false,
+                },
+                permitted_algorithms: Algorithm::AsymmetricSignature(AsymmetricSignature::Ecdsa {
+                    hash_alg: Hash::Sha256.into(),
+                }),
+            },
+        },
+    )?;
+    let public_key = client.export_public_key(key_name.clone())?;
+    let status = client
+        .raw_key_agreement(RawKeyAgreement::Ecdh, key_name, &public_key)
+        .expect_err("The key is not permitted to be used for key agreement.");
+    assert_eq!(status, ResponseStatus::PsaErrorNotPermitted);
+
+    Ok(())
+}
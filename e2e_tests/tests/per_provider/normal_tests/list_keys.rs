@@ -0,0 +1,27 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use e2e_tests::TestClient;
+use parsec_client::core::interface::requests::Result;
+
+#[test]
+fn list_created_keys() -> Result<()> {
+    let mut client = TestClient::new();
+    let provider = client.provider().unwrap();
+    let key_name = String::from("list_created_keys");
+
+    client.generate_rsa_sign_key(key_name.clone())?;
+    let keys = client.list_keys()?;
+    let key = keys
+        .iter()
+        .find(|key| key.name == key_name && key.provider_id == provider)
+        .expect("The key created should be listed.");
+    assert!(key.attributes.policy.usage_flags.sign_hash);
+
+    client.destroy_key(key_name.clone())?;
+    assert!(!client
+        .list_keys()?
+        .iter()
+        .any(|key| key.name == key_name && key.provider_id == provider));
+
+    Ok(())
+}
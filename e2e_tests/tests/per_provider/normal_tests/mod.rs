@@ -1,10 +1,16 @@
 // Copyright 2019 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
+mod admin;
+mod aead;
+mod asym_encryption;
 mod asym_sign_verify;
 mod auth;
 mod basic;
 mod create_destroy_key;
 mod export_public_key;
+mod hash;
 mod import_key;
+mod key_agreement;
 mod key_attributes;
+mod list_keys;
 mod ping;
@@ -0,0 +1,245 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Chain of the authenticators accepted by the service
+//!
+//! The configuration declares the authenticators accepted, in their order of priority: the list of
+//! authenticators returned to the clients follows it, so that a client picks the first one it
+//! supports. Each request is authenticated by the authenticator of the type given in its header,
+//! which admits it under the policy configured for that authenticator:
+//! * the application names it returns can be prefixed, namespacing the keys of the applications
+//!   per authentication method. Migrating from the direct authenticator to the Unix peer
+//!   credentials one, the keys created through the former keep their owner while the user IDs
+//!   authenticated by the latter can not be claimed by a direct client.
+//! * the providers the applications admitted can use can be restricted, the Core provider being
+//!   always usable.
+//!
+//! The names of the applications of the guests connected over vsock are qualified with the context
+//! ID of their virtual machine, as `vsock:<cid>/<name>`, before being prefixed: the guests share the
+//! service without being able to use the keys of each other.
+//!
+//! The administrators are configured under the names returned by the authenticators: the prefix of
+//! the chain is removed before the names are checked. The names of the guests stay qualified with
+//! their context ID, a guest only being an administrator if listed as `vsock:<cid>/<name>`.
+use super::{ApplicationName, Authenticate, AuthenticatorInfo};
+use crate::front::listener::ConnectionMetadata;
+use derivative::Derivative;
+use log::error;
+use parsec_interface::requests::request::RequestAuth;
+use parsec_interface::requests::{AuthType, ProviderID, ResponseStatus, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// Configuration of an authenticator of the chain
+#[derive(Clone, Deserialize, Debug)]
+pub struct AuthenticatorConfig {
+    /// Type of the authenticator: "Direct", "UnixPeerCredentials" or "JwtSvid"
+    pub auth_type: String,
+    /// Prefix of the names of the applications admitted by the authenticator
+    pub app_name_prefix: Option<String>,
+    /// Types of the providers the applications admitted can use, defaults to all
+    pub providers: Option<Vec<String>>,
+}
+
+/// Gets the authentication type of the given name, as used in the configuration.
+pub fn auth_type_from_name(auth_type: &str) -> Option<AuthType> {
+    match auth_type {
+        "Direct" => Some(AuthType::Direct),
+        "UnixPeerCredentials" => Some(AuthType::UnixPeerCredentials),
+        "JwtSvid" => Some(AuthType::JwtSvid),
+        _ => None,
+    }
+}
+
+/// Authenticator of the chain, with the policy applied to the applications it admits
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct ChainedAuthenticator {
+    // Send and Sync are required for Arc<FrontEndHandler> to be Send.
+    #[derivative(Debug = "ignore")]
+    authenticator: Box<dyn Authenticate + Send + Sync>,
+    app_name_prefix: Option<String>,
+    providers: Option<HashSet<ProviderID>>,
+}
+
+impl ChainedAuthenticator {
+    /// Creates the chained authenticator admitting the applications of the authenticator under
+    /// their own name, for all the providers.
+    pub fn new(authenticator: Box<dyn Authenticate + Send + Sync>) -> Self {
+        ChainedAuthenticator {
+            authenticator,
+            app_name_prefix: None,
+            providers: None,
+        }
+    }
+
+    /// Prefixes the names of the applications admitted.
+    pub fn with_app_name_prefix(mut self, app_name_prefix: String) -> Self {
+        self.app_name_prefix = Some(app_name_prefix);
+        self
+    }
+
+    /// Restricts the providers the applications admitted can use.
+    pub fn with_providers(mut self, providers: HashSet<ProviderID>) -> Self {
+        self.providers = Some(providers);
+        self
+    }
+
+    /// Checks if the applications admitted can send their requests to the provider.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the provider is not one of the configured ones.
+    pub fn check_provider(&self, provider_id: ProviderID) -> Result<()> {
+        match &self.providers {
+            Some(providers)
+                if provider_id != ProviderID::Core && !providers.contains(&provider_id) =>
+            {
+                error!(
+                    "The provider {} can not be used by the applications of this authenticator.",
+                    provider_id
+                );
+                Err(ResponseStatus::PsaErrorNotPermitted)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Authenticate for ChainedAuthenticator {
+    fn describe(&self) -> Result<AuthenticatorInfo> {
+        self.authenticator.describe()
+    }
+
+    fn authenticate(
+        &self,
+        auth: &RequestAuth,
+        meta: Option<ConnectionMetadata>,
+    ) -> Result<ApplicationName> {
+        let app_name = match (self.authenticator.authenticate(auth, meta)?, meta) {
+            (app_name, Some(ConnectionMetadata::VsockPeer { cid })) => {
+                ApplicationName(format!("vsock:{}/{}", cid, app_name))
+            }
+            (app_name, _) => app_name,
+        };
+
+        Ok(match &self.app_name_prefix {
+            Some(app_name_prefix) => ApplicationName(format!("{}{}", app_name_prefix, app_name)),
+            None => app_name,
+        })
+    }
+
+    fn is_admin(&self, app_name: &ApplicationName) -> bool {
+        match &self.app_name_prefix {
+            Some(app_name_prefix) => match app_name.get_name().strip_prefix(app_name_prefix) {
+                Some(name) => self
+                    .authenticator
+                    .is_admin(&ApplicationName(name.to_string())),
+                None => false,
+            },
+            None => self.authenticator.is_admin(app_name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{ApplicationName, Authenticate, AuthenticatorInfo};
+    use super::ChainedAuthenticator;
+    use crate::front::listener::ConnectionMetadata;
+    use parsec_interface::requests::request::RequestAuth;
+    use parsec_interface::requests::{AuthType, ProviderID, ResponseStatus, Result};
+
+    #[derive(Copy, Clone, Debug)]
+    struct TestAuthenticator;
+
+    impl Authenticate for TestAuthenticator {
+        fn describe(&self) -> Result<AuthenticatorInfo> {
+            Ok(AuthenticatorInfo {
+                description: String::from("Test authenticator"),
+                version_maj: 0,
+                version_min: 1,
+                version_rev: 0,
+                id: AuthType::UnixPeerCredentials,
+            })
+        }
+
+        fn authenticate(
+            &self,
+            _auth: &RequestAuth,
+            _meta: Option<ConnectionMetadata>,
+        ) -> Result<ApplicationName> {
+            Ok(ApplicationName::new(String::from("1000")))
+        }
+
+        fn is_admin(&self, app_name: &ApplicationName) -> bool {
+            app_name.get_name() == "1000"
+        }
+    }
+
+    #[test]
+    fn app_names_prefixed() {
+        let req_auth = RequestAuth::from_bytes(Vec::new());
+        let authenticator = ChainedAuthenticator::new(Box::new(TestAuthenticator));
+        assert_eq!(
+            authenticator
+                .authenticate(&req_auth, None)
+                .unwrap()
+                .get_name(),
+            "1000"
+        );
+
+        let authenticator = authenticator.with_app_name_prefix(String::from("uid:"));
+        assert_eq!(
+            authenticator
+                .authenticate(&req_auth, None)
+                .unwrap()
+                .get_name(),
+            "uid:1000"
+        );
+    }
+
+    #[test]
+    fn guest_cid_qualified() {
+        let req_auth = RequestAuth::from_bytes(Vec::new());
+        let authenticator = ChainedAuthenticator::new(Box::new(TestAuthenticator))
+            .with_app_name_prefix(String::from("uid:"));
+        let meta = Some(ConnectionMetadata::VsockPeer { cid: 3 });
+        assert_eq!(
+            authenticator
+                .authenticate(&req_auth, meta)
+                .unwrap()
+                .get_name(),
+            "uid:vsock:3/1000"
+        );
+    }
+
+    #[test]
+    fn admins_unprefixed() {
+        let req_auth = RequestAuth::from_bytes(Vec::new());
+        let authenticator = ChainedAuthenticator::new(Box::new(TestAuthenticator))
+            .with_app_name_prefix(String::from("uid:"));
+        let app_name = authenticator.authenticate(&req_auth, None).unwrap();
+        assert!(authenticator.is_admin(&app_name));
+        // Names not admitted by this authenticator are not those of its administrators.
+        assert!(!authenticator.is_admin(&ApplicationName::new(String::from("1000"))));
+
+        let meta = Some(ConnectionMetadata::VsockPeer { cid: 3 });
+        let guest_name = authenticator.authenticate(&req_auth, meta).unwrap();
+        assert!(!authenticator.is_admin(&guest_name));
+    }
+
+    #[test]
+    fn providers_restricted() {
+        let authenticator = ChainedAuthenticator::new(Box::new(TestAuthenticator));
+        assert_eq!(authenticator.check_provider(ProviderID::Tpm), Ok(()));
+
+        let authenticator =
+            authenticator.with_providers([ProviderID::Pkcs11].iter().copied().collect());
+        assert_eq!(authenticator.check_provider(ProviderID::Pkcs11), Ok(()));
+        assert_eq!(authenticator.check_provider(ProviderID::Core), Ok(()));
+        assert_eq!(
+            authenticator.check_provider(ProviderID::Tpm),
+            Err(ResponseStatus::PsaErrorNotPermitted)
+        );
+    }
+}
@@ -7,50 +7,99 @@
 //! authentication field into an UTF-8 string and returns the result as an application name.
 //! This authenticator does not offer any security value and should only be used in environments
 //! where all the clients and the service are mutually trustworthy.
+//!
+//! The applications named as administrators in the configuration can execute the administrative
+//! operations. As any client can claim any name with this authenticator, so can they.
+//!
+//! Clients connected with mutual TLS can only claim the common name of their certificate, which
+//! the TLS listener verified.
 
 use super::ApplicationName;
-use super::Authenticate;
+use super::{Authenticate, AuthenticatorInfo};
+use crate::front::listener::ConnectionMetadata;
 use log::error;
 use parsec_interface::requests::request::RequestAuth;
-use parsec_interface::requests::{ResponseStatus, Result};
+use parsec_interface::requests::{AuthType, ResponseStatus, Result};
+use std::collections::HashSet;
 use std::str;
 
-#[derive(Copy, Clone, Debug)]
-pub struct DirectAuthenticator;
+#[derive(Clone, Debug, Default)]
+pub struct DirectAuthenticator {
+    admins: HashSet<String>,
+}
+
+impl DirectAuthenticator {
+    /// Creates the authenticator with the names of the administrator applications.
+    pub fn new(admins: Vec<String>) -> Self {
+        DirectAuthenticator {
+            admins: admins.into_iter().collect(),
+        }
+    }
+}
 
 impl Authenticate for DirectAuthenticator {
-    fn authenticate(&self, auth: &RequestAuth) -> Result<ApplicationName> {
+    fn describe(&self) -> Result<AuthenticatorInfo> {
+        Ok(AuthenticatorInfo {
+            description: String::from(
+                "Directly parses the authentication field as a UTF-8 string and uses that as application identity. Should be used for testing only.",
+            ),
+            version_maj: 0,
+            version_min: 1,
+            version_rev: 0,
+            id: AuthType::Direct,
+        })
+    }
+
+    fn authenticate(
+        &self,
+        auth: &RequestAuth,
+        meta: Option<ConnectionMetadata>,
+    ) -> Result<ApplicationName> {
         if auth.is_empty() {
             error!("The direct authenticator does not expect empty authentication values.");
             Err(ResponseStatus::AuthenticationError)
         } else {
-            match str::from_utf8(auth.bytes()) {
-                Ok(str) => Ok(ApplicationName(String::from(str))),
-                Err(_) => {
+            match (str::from_utf8(auth.bytes()), meta) {
+                (Ok(str), Some(ConnectionMetadata::TlsClientCertificate { common_name }))
+                    if str != common_name.as_str() =>
+                {
+                    error!(
+                        "The application name claimed is not the common name of the client certificate."
+                    );
+                    Err(ResponseStatus::AuthenticationError)
+                }
+                (Ok(str), _) => Ok(ApplicationName(String::from(str))),
+                (Err(_), _) => {
                     error!("Error parsing the authentication value as a UTF-8 string.");
                     Err(ResponseStatus::AuthenticationError)
                 }
             }
         }
     }
+
+    fn is_admin(&self, app_name: &ApplicationName) -> bool {
+        self.admins.contains(app_name.get_name())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::super::Authenticate;
     use super::DirectAuthenticator;
+    use crate::authenticators::ApplicationName;
+    use crate::front::listener::{CommonName, ConnectionMetadata};
     use parsec_interface::requests::request::RequestAuth;
-    use parsec_interface::requests::ResponseStatus;
+    use parsec_interface::requests::{AuthType, ResponseStatus};
 
     #[test]
     fn successful_authentication() {
-        let authenticator = DirectAuthenticator {};
+        let authenticator = DirectAuthenticator::default();
 
         let app_name = "app_name".to_string();
         let req_auth = RequestAuth::from_bytes(app_name.clone().into_bytes());
 
         let auth_name = authenticator
-            .authenticate(&req_auth)
+            .authenticate(&req_auth, None)
             .expect("Failed to authenticate");
 
         assert_eq!(auth_name.get_name(), app_name);
@@ -58,19 +107,53 @@ mod test {
 
     #[test]
     fn failed_authentication() {
-        let authenticator = DirectAuthenticator {};
+        let authenticator = DirectAuthenticator::default();
         let status = authenticator
-            .authenticate(&RequestAuth::from_bytes(vec![0xff; 5]))
+            .authenticate(&RequestAuth::from_bytes(vec![0xff; 5]), None)
             .expect_err("Authentication should have failed");
 
         assert_eq!(status, ResponseStatus::AuthenticationError);
     }
 
+    #[test]
+    fn certificate_name_claimed() {
+        let authenticator = DirectAuthenticator::default();
+        let meta = Some(ConnectionMetadata::TlsClientCertificate {
+            common_name: CommonName::new("app_name").unwrap(),
+        });
+
+        let auth_name = authenticator
+            .authenticate(&RequestAuth::from_bytes(b"app_name".to_vec()), meta)
+            .expect("Failed to authenticate");
+        assert_eq!(auth_name.get_name(), "app_name");
+
+        let status = authenticator
+            .authenticate(&RequestAuth::from_bytes(b"other_app".to_vec()), meta)
+            .expect_err("Claiming another name should have failed");
+        assert_eq!(status, ResponseStatus::AuthenticationError);
+    }
+
+    #[test]
+    fn admins_recognized() {
+        let authenticator = DirectAuthenticator::new(vec![String::from("admin")]);
+
+        assert!(authenticator.is_admin(&ApplicationName(String::from("admin"))));
+        assert!(!authenticator.is_admin(&ApplicationName(String::from("app_name"))));
+    }
+
+    #[test]
+    fn describe() {
+        let authenticator = DirectAuthenticator::default();
+        let info = authenticator.describe().expect("Failed to describe");
+
+        assert_eq!(info.id, AuthType::Direct);
+    }
+
     #[test]
     fn empty_auth() {
-        let authenticator = DirectAuthenticator {};
+        let authenticator = DirectAuthenticator::default();
         let status = authenticator
-            .authenticate(&RequestAuth::from_bytes(Vec::new()))
+            .authenticate(&RequestAuth::from_bytes(Vec::new()), None)
             .expect_err("Empty auth should have failed");
 
         assert_eq!(status, ResponseStatus::AuthenticationError);
@@ -0,0 +1,128 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! JWT-SVID authenticator
+//!
+//! The `JwtSvidAuthenticator` authenticates the workloads of a service mesh with their SPIFFE
+//! identity. The authentication field of the requests carries a JWT-SVID, a JSON Web Token whose
+//! subject is the SPIFFE ID of the workload, fetched by the client from the Workload API of its
+//! SPIRE agent for the "parsec" audience. The token is checked by the local SPIRE agent through the
+//! `ValidateJWTSVID` call of the Workload API: its signature, expiry and audience are verified
+//! against the trust bundles the agent holds, and the SPIFFE ID it asserts is used as the
+//! application name, for example `spiffe://example.org/ns/payments/sa/signer`.
+//!
+//! The applications named as administrators in the configuration, by their SPIFFE ID, can execute
+//! the administrative operations. This module is only compiled with the `jwt-svid-authenticator`
+//! feature.
+
+use super::ApplicationName;
+use super::{Authenticate, AuthenticatorInfo};
+use crate::front::listener::ConnectionMetadata;
+use derivative::Derivative;
+use log::error;
+use parsec_interface::requests::request::RequestAuth;
+use parsec_interface::requests::{AuthType, ResponseStatus, Result};
+use serde::Deserialize;
+use spiffe::workload::jwt::JWTClient;
+use std::collections::HashSet;
+use std::str;
+
+/// Audience the JWT-SVIDs must be issued for
+pub const AUDIENCE: &str = "parsec";
+
+/// Configuration of the JWT-SVID authenticator
+#[derive(Clone, Deserialize, Debug)]
+pub struct JwtSvidConfig {
+    /// Address of the Workload API of the SPIRE agent, such as `unix:///run/spire/agent.sock`
+    pub workload_endpoint: String,
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct JwtSvidAuthenticator {
+    #[derivative(Debug = "ignore")]
+    client: JWTClient,
+    admins: HashSet<String>,
+}
+
+impl JwtSvidAuthenticator {
+    /// Creates the authenticator validating the JWT-SVIDs with the Workload API at the configured
+    /// address, with the SPIFFE IDs of the administrator applications.
+    pub fn new(config: &JwtSvidConfig, admins: Vec<String>) -> Self {
+        JwtSvidAuthenticator {
+            client: JWTClient::new(&config.workload_endpoint, None, None),
+            admins: admins.into_iter().collect(),
+        }
+    }
+}
+
+// Gets the JWT-SVID carried in the authentication field of a request.
+fn svid_token(auth: &RequestAuth) -> Result<&str> {
+    if auth.is_empty() {
+        error!("The JWT-SVID authenticator does not expect empty authentication values.");
+        return Err(ResponseStatus::AuthenticationError);
+    }
+    str::from_utf8(auth.bytes()).map_err(|_| {
+        error!("Error parsing the JWT-SVID as a UTF-8 string.");
+        ResponseStatus::AuthenticationError
+    })
+}
+
+impl Authenticate for JwtSvidAuthenticator {
+    fn describe(&self) -> Result<AuthenticatorInfo> {
+        Ok(AuthenticatorInfo {
+            description: String::from(
+                "Validates the JWT-SVID of the authentication field with the SPIFFE Workload API and uses its SPIFFE ID as application identity.",
+            ),
+            version_maj: 0,
+            version_min: 1,
+            version_rev: 0,
+            id: AuthType::JwtSvid,
+        })
+    }
+
+    fn authenticate(
+        &self,
+        auth: &RequestAuth,
+        _meta: Option<ConnectionMetadata>,
+    ) -> Result<ApplicationName> {
+        let token = svid_token(auth)?;
+        let response = self
+            .client
+            .validate(AUDIENCE.to_string(), token.to_string())
+            .map_err(|e| {
+                format_error!("The JWT-SVID could not be validated", e);
+                ResponseStatus::AuthenticationError
+            })?;
+
+        Ok(ApplicationName(response.get_spiffe_id().to_string()))
+    }
+
+    fn is_admin(&self, app_name: &ApplicationName) -> bool {
+        self.admins.contains(app_name.get_name())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::svid_token;
+    use parsec_interface::requests::request::RequestAuth;
+    use parsec_interface::requests::ResponseStatus;
+
+    #[test]
+    fn token_parsed() {
+        assert_eq!(
+            svid_token(&RequestAuth::from_bytes(
+                b"header.payload.signature".to_vec()
+            )),
+            Ok("header.payload.signature")
+        );
+        assert_eq!(
+            svid_token(&RequestAuth::from_bytes(Vec::new())),
+            Err(ResponseStatus::AuthenticationError)
+        );
+        assert_eq!(
+            svid_token(&RequestAuth::from_bytes(vec![0xff; 5])),
+            Err(ResponseStatus::AuthenticationError)
+        );
+    }
+}
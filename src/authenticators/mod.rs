@@ -9,10 +9,22 @@
 //! is the `RequestAuth` field of a request, which is parsed by the authenticator specified in the header.
 //! The authentication functionality is abstracted through an `Authenticate` trait.
 //!
-//! Currently only a simple Direct Authenticator component is implemented.
+//! A simple Direct Authenticator component is available with the `direct-authenticator` feature
+//! and a Unix Peer Credentials Authenticator with the `unix-peer-credentials-authenticator`
+//! feature, both compiled by default, and a JWT-SVID Authenticator, validating SPIFFE identities,
+//! with the `jwt-svid-authenticator` feature. The authenticators accepted and their policy are
+//! configured as a chain, see the `authenticator_chain` module.
 
+pub mod authenticator_chain;
+#[cfg(feature = "direct-authenticator")]
 pub mod direct_authenticator;
 
+#[cfg(feature = "jwt-svid-authenticator")]
+pub mod jwt_svid_authenticator;
+#[cfg(feature = "unix-peer-credentials-authenticator")]
+pub mod unix_peer_credentials_authenticator;
+
+use crate::front::listener::ConnectionMetadata;
 use parsec_interface::requests::request::RequestAuth;
 use parsec_interface::requests::Result;
 
@@ -20,16 +32,41 @@ use parsec_interface::requests::Result;
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct ApplicationName(String);
 
+/// Description of an authenticator, as returned to the clients by the ListAuthenticators operation
+///
+/// Authenticators advertise this information so that clients can discover which
+/// authentication methods the service accepts instead of assuming one.
+pub use parsec_interface::operations::list_authenticators::AuthenticatorInfo;
+
 /// Authentication interface
 ///
 /// Interface that must be implemented for each authentication type available for the service.
 pub trait Authenticate {
-    /// Authenticates a `RequestAuth` payload and returns the `ApplicationName` if successfull.
+    /// Return a description of the authenticator.
+    ///
+    /// The descriptions are gathered in the Core Provider so that clients can discover the
+    /// authenticators supported by the service.
+    fn describe(&self) -> Result<AuthenticatorInfo>;
+
+    /// Authenticates a `RequestAuth` payload and returns the `ApplicationName` if successfull. The
+    /// metadata of the connection identifies the client as seen by the listener, if it could
+    /// retrieve any.
     ///
     /// # Errors
     ///
     /// If the authentification fails, returns a `ResponseStatus::AuthenticationError`.
-    fn authenticate(&self, auth: &RequestAuth) -> Result<ApplicationName>;
+    fn authenticate(
+        &self,
+        auth: &RequestAuth,
+        meta: Option<ConnectionMetadata>,
+    ) -> Result<ApplicationName>;
+
+    /// Checks if an authenticated application is an administrator, allowed to execute the
+    /// administrative operations such as ListClients and DeleteClient. No application is an
+    /// administrator by default.
+    fn is_admin(&self, _app_name: &ApplicationName) -> bool {
+        false
+    }
 }
 
 impl ApplicationName {
@@ -0,0 +1,126 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Unix peer credentials authenticator
+//!
+//! The `UnixPeerCredentialsAuthenticator` identifies the clients by the user ID of their process,
+//! as given by the kernel for the Unix domain socket they connected on. The authentication field
+//! of the requests carries the user ID the client claims, as a 32-bit little-endian integer, which
+//! has to match the one of the peer process: the claim only guards against a client using the
+//! wrong authentication type by mistake, the identity coming from the kernel. The user ID, in
+//! decimal, is used as the application name.
+//!
+//! Unlike the direct authenticator, a client can not claim the name of another one, so that the
+//! keys of an application are only usable by the processes of its user. The applications named as
+//! administrators in the configuration can execute the administrative operations.
+
+use super::ApplicationName;
+use super::{Authenticate, AuthenticatorInfo};
+use crate::front::listener::ConnectionMetadata;
+use log::error;
+use parsec_interface::requests::request::RequestAuth;
+use parsec_interface::requests::{AuthType, ResponseStatus, Result};
+use std::collections::HashSet;
+use std::convert::TryInto;
+
+#[derive(Clone, Debug, Default)]
+pub struct UnixPeerCredentialsAuthenticator {
+    admins: HashSet<String>,
+}
+
+impl UnixPeerCredentialsAuthenticator {
+    /// Creates the authenticator with the names of the administrator applications.
+    pub fn new(admins: Vec<String>) -> Self {
+        UnixPeerCredentialsAuthenticator {
+            admins: admins.into_iter().collect(),
+        }
+    }
+}
+
+impl Authenticate for UnixPeerCredentialsAuthenticator {
+    fn describe(&self) -> Result<AuthenticatorInfo> {
+        Ok(AuthenticatorInfo {
+            description: String::from(
+                "Uses the user ID of the peer process of the Unix domain socket as application identity, checking it against the one claimed in the authentication field.",
+            ),
+            version_maj: 0,
+            version_min: 1,
+            version_rev: 0,
+            id: AuthType::UnixPeerCredentials,
+        })
+    }
+
+    fn authenticate(
+        &self,
+        auth: &RequestAuth,
+        meta: Option<ConnectionMetadata>,
+    ) -> Result<ApplicationName> {
+        let uid = match meta {
+            Some(ConnectionMetadata::UnixPeerCredentials { uid, .. }) => uid,
+            _ => {
+                error!("The credentials of the peer process are needed for the authentication.");
+                return Err(ResponseStatus::AuthenticationError);
+            }
+        };
+        let claimed_uid: [u8; 4] = auth.bytes().try_into().map_err(|_| {
+            error!("The authentication value is not a 32-bit user ID.");
+            ResponseStatus::AuthenticationError
+        })?;
+        if u32::from_le_bytes(claimed_uid) != uid {
+            error!("The user ID claimed does not match the one of the peer process.");
+            return Err(ResponseStatus::AuthenticationError);
+        }
+
+        Ok(ApplicationName(uid.to_string()))
+    }
+
+    fn is_admin(&self, app_name: &ApplicationName) -> bool {
+        self.admins.contains(app_name.get_name())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::Authenticate;
+    use super::UnixPeerCredentialsAuthenticator;
+    use crate::front::listener::ConnectionMetadata;
+    use parsec_interface::requests::request::RequestAuth;
+    use parsec_interface::requests::ResponseStatus;
+
+    fn peer(uid: u32) -> Option<ConnectionMetadata> {
+        Some(ConnectionMetadata::UnixPeerCredentials {
+            uid,
+            gid: uid,
+            pid: 4242,
+        })
+    }
+
+    #[test]
+    fn peer_user_authenticated() {
+        let authenticator = UnixPeerCredentialsAuthenticator::default();
+        let req_auth = RequestAuth::from_bytes(1000u32.to_le_bytes().to_vec());
+
+        let app_name = authenticator
+            .authenticate(&req_auth, peer(1000))
+            .expect("Failed to authenticate");
+        assert_eq!(app_name.get_name(), "1000");
+    }
+
+    #[test]
+    fn other_user_refused() {
+        let authenticator = UnixPeerCredentialsAuthenticator::default();
+        let req_auth = RequestAuth::from_bytes(0u32.to_le_bytes().to_vec());
+
+        assert_eq!(
+            authenticator.authenticate(&req_auth, peer(1000)),
+            Err(ResponseStatus::AuthenticationError)
+        );
+        assert_eq!(
+            authenticator.authenticate(&req_auth, None),
+            Err(ResponseStatus::AuthenticationError)
+        );
+        assert_eq!(
+            authenticator.authenticate(&RequestAuth::from_bytes(vec![0; 5]), peer(0)),
+            Err(ResponseStatus::AuthenticationError)
+        );
+    }
+}
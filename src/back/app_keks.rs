@@ -0,0 +1,292 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Root key encryption keys of the applications
+//!
+//! When the `app_kek_provider` core setting is set, each application gets a root key encryption
+//! key (KEK) in that provider, created the first time it is needed. Envelope encryption schemes
+//! can then wrap the keys of a tenant with its KEK without having to provision it.
+//!
+//! KEKs are stored under a reserved key name which the usual key management and cryptographic
+//! operations refuse, in all providers: they can not be exported, destroyed or directly used by
+//! the clients and are only reachable through the wrap and unwrap operations of the service.
+//!
+//! Keys are wrapped with AES-GCM, under a nonce drawn by the service, and bound to a label given
+//! by the client as additional data: a wrapped key is only unwrapped with the label it was wrapped
+//! with. The wrapped key is the nonce followed by the ciphertext and its tag. Deriving keys from
+//! the KEKs needs a key derivation operation, which the interface does not define yet.
+use crate::authenticators::ApplicationName;
+use crate::providers::Provide;
+use log::{error, info};
+use parsec_interface::operations::psa_algorithm::{Aead, AeadWithDefaultLengthTag, Algorithm};
+use parsec_interface::operations::psa_key_attributes::{
+    Attributes, Lifetime, Policy, Type, UsageFlags,
+};
+use parsec_interface::operations::{psa_aead_decrypt, psa_aead_encrypt, psa_generate_key};
+use parsec_interface::requests::{ResponseStatus, Result};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// Name under which the KEK of each application is stored
+pub const APP_KEK_NAME: &str = "parsec-app-kek";
+/// Algorithm the keys are wrapped with
+const WRAP_ALG: Aead = Aead::AeadWithDefaultLengthTag(AeadWithDefaultLengthTag::Gcm);
+/// Length of the nonces starting the wrapped keys
+const NONCE_LEN: usize = 12;
+
+/// Checks that a key name given by a client is not the reserved name of the KEKs.
+///
+/// # Errors
+///
+/// Returns `PsaErrorNotPermitted` if it is.
+pub fn check_key_name(key_name: &str) -> Result<()> {
+    if key_name == APP_KEK_NAME {
+        error!("The key name is reserved for the key encryption key of the application.");
+        return Err(ResponseStatus::PsaErrorNotPermitted);
+    }
+
+    Ok(())
+}
+
+/// KEKs of the applications in one provider
+#[derive(Debug, Default)]
+pub struct AppKeks {
+    // Applications whose KEK is known to exist.
+    created: Mutex<HashSet<String>>,
+}
+
+impl AppKeks {
+    /// Attributes of the KEKs: 256 bits AES keys, only usable to wrap other keys, which can not be
+    /// exported.
+    pub fn attributes() -> Attributes {
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type: Type::Aes,
+            bits: 256,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    export: false,
+                    copy: false,
+                    cache: false,
+                    encrypt: true,
+                    decrypt: true,
+                    sign_message: false,
+                    verify_message: false,
+                    sign_hash: false,
+                    verify_hash: false,
+                    derive: false,
+                },
+                permitted_algorithms: Algorithm::Aead(WRAP_ALG),
+            },
+        }
+    }
+
+    /// Makes sure that the KEK of the application exists in the provider, creating it if needed,
+    /// and returns its key name.
+    pub fn get_or_create(
+        &self,
+        provider: &dyn Provide,
+        app_name: &ApplicationName,
+    ) -> Result<&'static str> {
+        let mut created = self.created.lock().expect("Application KEKs lock poisoned");
+        if created.contains(app_name.get_name()) {
+            return Ok(APP_KEK_NAME);
+        }
+
+        let op = psa_generate_key::Operation {
+            key_name: String::from(APP_KEK_NAME),
+            attributes: AppKeks::attributes(),
+        };
+        match provider.psa_generate_key(app_name.clone(), op) {
+            Ok(_) => {
+                if crate::utils::GlobalConfig::log_error_details() {
+                    info!(
+                        "Created the key encryption key of application \"{}\".",
+                        app_name
+                    );
+                } else {
+                    info!("Created the key encryption key of an application.");
+                }
+            }
+            // Created by a previous run of the service.
+            Err(ResponseStatus::PsaErrorAlreadyExists) => (),
+            Err(status) => {
+                format_error!("Failed to create the key encryption key", status);
+                return Err(status);
+            }
+        }
+        let _ = created.insert(app_name.get_name().to_string());
+
+        Ok(APP_KEK_NAME)
+    }
+}
+
+/// Wraps key material with the KEK of the given name of the application, bound to the label.
+///
+/// # Errors
+///
+/// Returns `PsaErrorInsufficientEntropy` if the nonce could not be drawn, and the error of the
+/// provider if the key material could not be encrypted.
+pub fn wrap(
+    provider: &dyn Provide,
+    app_name: &ApplicationName,
+    kek_name: &str,
+    key_material: Zeroizing<Vec<u8>>,
+    label: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let mut nonce = vec![0; NONCE_LEN];
+    OsRng.try_fill_bytes(&mut nonce).or_else(|e| {
+        format_error!("Failed to draw the nonce of a wrapped key", e);
+        Err(ResponseStatus::PsaErrorInsufficientEntropy)
+    })?;
+    let result = provider.psa_aead_encrypt(
+        app_name.clone(),
+        psa_aead_encrypt::Operation {
+            key_name: kek_name.to_string(),
+            alg: WRAP_ALG,
+            nonce: nonce.clone().into(),
+            additional_data: label.into(),
+            plaintext: key_material,
+        },
+    )?;
+    nonce.extend_from_slice(&result.ciphertext);
+
+    Ok(nonce)
+}
+
+/// Unwraps key material wrapped with the KEK of the given name of the application, with the same
+/// label.
+///
+/// # Errors
+///
+/// Returns `PsaErrorInvalidArgument` if the wrapped key is too short to have been wrapped by the
+/// service, and the error of the provider if it could not be decrypted, for example
+/// `PsaErrorInvalidSignature` if the label does not match.
+pub fn unwrap(
+    provider: &dyn Provide,
+    app_name: &ApplicationName,
+    kek_name: &str,
+    wrapped: Vec<u8>,
+    label: Vec<u8>,
+) -> Result<Zeroizing<Vec<u8>>> {
+    if wrapped.len() < NONCE_LEN {
+        error!("The wrapped key is too short.");
+        return Err(ResponseStatus::PsaErrorInvalidArgument);
+    }
+    let (nonce, ciphertext) = wrapped.split_at(NONCE_LEN);
+    let result = provider.psa_aead_decrypt(
+        app_name.clone(),
+        psa_aead_decrypt::Operation {
+            key_name: kek_name.to_string(),
+            alg: WRAP_ALG,
+            nonce: nonce.to_vec().into(),
+            additional_data: label.into(),
+            ciphertext: ciphertext.to_vec().into(),
+        },
+    )?;
+
+    Ok(result.plaintext)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_key_name, unwrap, wrap, APP_KEK_NAME, NONCE_LEN};
+    use crate::authenticators::ApplicationName;
+    use crate::providers::Provide;
+    use parsec_interface::operations::{psa_aead_decrypt, psa_aead_encrypt};
+    use parsec_interface::requests::{ResponseStatus, Result};
+
+    // "Encrypts" by appending the additional data to the plaintext.
+    #[derive(Debug)]
+    struct AppendProvider;
+
+    impl Provide for AppendProvider {
+        fn psa_aead_encrypt(
+            &self,
+            _app_name: ApplicationName,
+            op: psa_aead_encrypt::Operation,
+        ) -> Result<psa_aead_encrypt::Result> {
+            assert_eq!(op.key_name, APP_KEK_NAME);
+            let mut ciphertext = op.plaintext.to_vec();
+            ciphertext.extend_from_slice(&op.additional_data);
+            Ok(psa_aead_encrypt::Result {
+                ciphertext: ciphertext.into(),
+            })
+        }
+
+        fn psa_aead_decrypt(
+            &self,
+            _app_name: ApplicationName,
+            op: psa_aead_decrypt::Operation,
+        ) -> Result<psa_aead_decrypt::Result> {
+            let plaintext_len = op.ciphertext.len().saturating_sub(op.additional_data.len());
+            let (plaintext, additional_data) = op.ciphertext.split_at(plaintext_len);
+            if additional_data != op.additional_data.as_slice() {
+                return Err(ResponseStatus::PsaErrorInvalidSignature);
+            }
+            Ok(psa_aead_decrypt::Result {
+                plaintext: plaintext.to_vec().into(),
+            })
+        }
+    }
+
+    #[test]
+    fn key_wrapped() {
+        let app_name = ApplicationName::new(String::from("tenant"));
+        let wrapped = wrap(
+            &AppendProvider,
+            &app_name,
+            APP_KEK_NAME,
+            vec![1, 2, 3].into(),
+            b"label".to_vec(),
+        )
+        .unwrap();
+        assert_eq!(wrapped.len(), NONCE_LEN + 3 + 5);
+
+        assert_eq!(
+            unwrap(
+                &AppendProvider,
+                &app_name,
+                APP_KEK_NAME,
+                wrapped.clone(),
+                b"label".to_vec()
+            )
+            .unwrap()
+            .to_vec(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            unwrap(
+                &AppendProvider,
+                &app_name,
+                APP_KEK_NAME,
+                wrapped,
+                b"other".to_vec()
+            )
+            .unwrap_err(),
+            ResponseStatus::PsaErrorInvalidSignature
+        );
+        assert_eq!(
+            unwrap(
+                &AppendProvider,
+                &app_name,
+                APP_KEK_NAME,
+                vec![0; NONCE_LEN - 1],
+                b"label".to_vec()
+            )
+            .unwrap_err(),
+            ResponseStatus::PsaErrorInvalidArgument
+        );
+    }
+
+    #[test]
+    fn reserved_key_name_refused() {
+        assert_eq!(
+            check_key_name(APP_KEK_NAME).unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        check_key_name("my-key").unwrap();
+    }
+}
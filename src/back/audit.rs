@@ -0,0 +1,435 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Audit log of the security-relevant operations
+//!
+//! Every key creation, destruction, signature and export requested from a provider is recorded,
+//! whether it succeeded or not, with the application, the provider, the key and the status of the
+//! operation. The keys created and destroyed by the service itself are recorded as well: the
+//! copies, the keys of a batch generation and the peer keys when they are imported, and the peer
+//! keys expiring and the keys of the clients which disconnected when they are destroyed. Unlike the signing log, which keeps the signatures of a few high-value keys, the
+//! audit log covers all the keys but does not record what they signed.
+//!
+//! Each record is a line of tab-separated fields: the UNIX time in seconds, the provider, the
+//! application name, the key name, the opcode (`CopyKey` for the copies, which have none), the
+//! status, the hash of the previous record and
+//! the hash of the record, in hexadecimal. The hash of a record is the SHA-256 of its first seven
+//! fields, the first record of a chain following an all-zero hash: a record modified, removed or
+//! inserted breaks the chain, which `verify` checks. The chain is resumed from the last record of
+//! the first file sink when the service starts.
+//!
+//! The records are written to sinks: files opened for appending, the syslog socket, or sinks
+//! added programmatically by implementing `AuditSink`. A record which could not be written to a
+//! sink is logged as an error but does not fail the operation, which has already been executed;
+//! the gap it leaves in the file is found when the chain is verified.
+use crate::key_info_managers::KeyTriple;
+use derivative::Derivative;
+use log::{error, warn};
+use parsec_interface::requests::{Opcode, ResponseStatus};
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Path of the socket of the system logger
+const DEFAULT_SYSLOG_SOCKET: &str = "/dev/log";
+/// Priority of the messages sent to the system logger: the authpriv facility with the info
+/// severity
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+/// Operation recorded for the copies of keys, which have no opcode
+const COPY_KEY: &str = "CopyKey";
+/// Hash preceding the first record of a chain
+const CHAIN_START: [u8; 32] = [0; 32];
+
+/// Sink the audit records are written to
+#[derive(Clone, Deserialize, Debug)]
+#[serde(tag = "sink_type")]
+pub enum AuditSinkConfig {
+    /// File the records are appended to
+    File { path: String },
+    /// System logger, through its datagram socket
+    Syslog { socket: Option<String> },
+}
+
+/// Configuration of the audit log
+#[derive(Clone, Deserialize, Debug)]
+pub struct AuditConfig {
+    /// Sinks the records are written to
+    pub sinks: Vec<AuditSinkConfig>,
+}
+
+/// Destination of the audit records
+pub trait AuditSink: Send + Sync {
+    /// Writes a record, given without its line feed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the record could not be written.
+    fn write(&self, record: &str) -> Result<()>;
+}
+
+/// Sink appending the records to a file, synced after each of them
+#[derive(Debug)]
+pub struct FileSink {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl FileSink {
+    /// Opens the file for appending, creating it if needed.
+    pub fn new(path: &str) -> Result<FileSink> {
+        let path = PathBuf::from(path);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .or_else(|e| {
+                format_error!("Failed to open the audit log", e);
+                Err(e)
+            })?;
+
+        Ok(FileSink {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for FileSink {
+    fn write(&self, record: &str) -> Result<()> {
+        let mut file = self.file.lock().expect("Audit file lock poisoned");
+        file.write_all(format!("{}\n", record).as_bytes())?;
+        file.sync_data()
+    }
+}
+
+/// Sink sending the records to the system logger
+#[derive(Debug)]
+pub struct SyslogSink {
+    socket_path: PathBuf,
+    socket: UnixDatagram,
+}
+
+impl SyslogSink {
+    /// Creates a sink sending the records to the socket of the system logger at the given path.
+    pub fn new(socket_path: &str) -> Result<SyslogSink> {
+        Ok(SyslogSink {
+            socket_path: PathBuf::from(socket_path),
+            socket: UnixDatagram::unbound()?,
+        })
+    }
+}
+
+impl AuditSink for SyslogSink {
+    fn write(&self, record: &str) -> Result<()> {
+        let message = format!("<{}>parsec: {}", SYSLOG_PRIORITY, record);
+        let _ = self.socket.send_to(message.as_bytes(), &self.socket_path)?;
+        Ok(())
+    }
+}
+
+/// Record of an operation
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditRecord {
+    pub timestamp: u64,
+    pub provider: String,
+    pub app_name: String,
+    pub key_name: String,
+    pub opcode: String,
+    pub status: String,
+    pub previous_hash: Vec<u8>,
+    pub hash: Vec<u8>,
+}
+
+impl AuditRecord {
+    // Gets the fields covered by the hash of the record.
+    fn hashed_fields(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.timestamp,
+            self.provider,
+            self.app_name,
+            self.key_name,
+            self.opcode,
+            self.status,
+            hex::encode(&self.previous_hash)
+        )
+    }
+
+    // Computes the hash of the record.
+    fn compute_hash(&self) -> Vec<u8> {
+        digest(&SHA256, self.hashed_fields().as_bytes())
+            .as_ref()
+            .to_vec()
+    }
+
+    // Formats the record as a line, without its line feed.
+    fn to_line(&self) -> String {
+        format!("{}\t{}", self.hashed_fields(), hex::encode(&self.hash))
+    }
+}
+
+/// Hash-chained audit log of the operations of the providers
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct AuditLog {
+    #[derivative(Debug = "ignore")]
+    sinks: Vec<Box<dyn AuditSink>>,
+    last_hash: Mutex<Vec<u8>>,
+}
+
+impl AuditLog {
+    /// Creates the sinks of the configuration, resuming the chain from the last record of the
+    /// first file sink.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a sink could not be created or if the file of a sink could not be read.
+    pub fn new(config: &AuditConfig) -> Result<AuditLog> {
+        let mut last_hash = None;
+        let mut sinks: Vec<Box<dyn AuditSink>> = Vec::new();
+        for sink_config in &config.sinks {
+            match sink_config {
+                AuditSinkConfig::File { path } => {
+                    if last_hash.is_none() {
+                        last_hash = Some(last_record_hash(path)?);
+                    }
+                    sinks.push(Box::new(FileSink::new(path)?));
+                }
+                AuditSinkConfig::Syslog { socket } => sinks.push(Box::new(SyslogSink::new(
+                    socket.as_deref().unwrap_or(DEFAULT_SYSLOG_SOCKET),
+                )?)),
+            }
+        }
+
+        Ok(AuditLog {
+            sinks,
+            last_hash: Mutex::new(last_hash.unwrap_or_else(|| CHAIN_START.to_vec())),
+        })
+    }
+
+    /// Adds a sink to the log.
+    pub fn with_sink(mut self, sink: Box<dyn AuditSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Records an operation on a key with the status of its result.
+    pub fn record(&self, key_triple: &KeyTriple, opcode: Opcode, status: ResponseStatus) {
+        self.write_record(key_triple, format!("{:?}", opcode), status)
+    }
+
+    /// Records the copy of a key, under the name of the copy, with the status of its result.
+    pub fn record_copy(&self, destination: &KeyTriple, status: ResponseStatus) {
+        self.write_record(destination, String::from(COPY_KEY), status)
+    }
+
+    fn write_record(&self, key_triple: &KeyTriple, opcode: String, status: ResponseStatus) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or(0);
+        // The lock is held until the record is written so that the records are written in the
+        // order of the chain.
+        let mut last_hash = self.last_hash.lock().expect("Audit log lock poisoned");
+        let mut record = AuditRecord {
+            timestamp,
+            provider: key_triple.provider_id().to_string(),
+            app_name: key_triple.app_name().to_string(),
+            key_name: key_triple.key_name().to_string(),
+            opcode,
+            status: format!("{:?}", status),
+            previous_hash: last_hash.clone(),
+            hash: Vec::new(),
+        };
+        record.hash = record.compute_hash();
+        *last_hash = record.hash.clone();
+
+        let line = record.to_line();
+        for sink in &self.sinks {
+            if let Err(e) = sink.write(&line) {
+                format_error!("Failed to write an audit record", e);
+            }
+        }
+    }
+}
+
+// Gets the hash of the last record of the file, or the start of a chain if it does not exist, is
+// empty or if its last record is invalid.
+fn last_record_hash<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(CHAIN_START.to_vec()),
+        Err(e) => {
+            format_error!("Failed to read the audit log", e);
+            return Err(e);
+        }
+    };
+    match content.lines().last() {
+        Some(line) => match parse_record(line) {
+            Some(record) => Ok(record.hash),
+            None => {
+                warn!("The last record of the audit log is invalid, a new chain is started.");
+                Ok(CHAIN_START.to_vec())
+            }
+        },
+        None => Ok(CHAIN_START.to_vec()),
+    }
+}
+
+/// Reads the records of an audit log file.
+pub fn read_records<P: AsRef<Path>>(path: P) -> Result<Vec<AuditRecord>> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(|line| {
+            parse_record(line)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid audit record"))
+        })
+        .collect()
+}
+
+/// Checks the chain of the records of an audit log file, returning the number of records.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if a record is invalid, if its hash does not match its
+/// fields or if it does not follow the previous record.
+pub fn verify<P: AsRef<Path>>(path: P) -> Result<usize> {
+    let records = read_records(path)?;
+    let mut previous_hash = CHAIN_START.to_vec();
+    for (index, record) in records.iter().enumerate() {
+        if record.hash != record.compute_hash() {
+            error!("The audit record {} was modified.", index + 1);
+            return Err(Error::new(ErrorKind::InvalidData, "audit record modified"));
+        }
+        if record.previous_hash != previous_hash {
+            error!(
+                "The audit record {} does not follow the previous one.",
+                index + 1
+            );
+            return Err(Error::new(ErrorKind::InvalidData, "audit chain broken"));
+        }
+        previous_hash = record.hash.clone();
+    }
+
+    Ok(records.len())
+}
+
+fn parse_record(line: &str) -> Option<AuditRecord> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 8 {
+        return None;
+    }
+
+    Some(AuditRecord {
+        timestamp: fields[0].parse().ok()?,
+        provider: fields[1].to_string(),
+        app_name: fields[2].to_string(),
+        key_name: fields[3].to_string(),
+        opcode: fields[4].to_string(),
+        status: fields[5].to_string(),
+        previous_hash: hex::decode(fields[6]).ok()?,
+        hash: hex::decode(fields[7]).ok()?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_records, verify, AuditConfig, AuditLog, AuditSink, AuditSinkConfig};
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::KeyTriple;
+    use parsec_interface::requests::{Opcode, ProviderID, ResponseStatus};
+    use std::fs;
+    use std::io::Result;
+    use std::sync::{Arc, Mutex};
+
+    fn key_triple(key_name: &str) -> KeyTriple {
+        KeyTriple::new(
+            ApplicationName::new(String::from("app")),
+            ProviderID::MbedCrypto,
+            String::from(key_name),
+        )
+    }
+
+    fn file_config(path: &str) -> AuditConfig {
+        AuditConfig {
+            sinks: vec![AuditSinkConfig::File {
+                path: path.to_string(),
+            }],
+        }
+    }
+
+    struct MemorySink(Arc<Mutex<Vec<String>>>);
+
+    impl AuditSink for MemorySink {
+        fn write(&self, record: &str) -> Result<()> {
+            self.0.lock().unwrap().push(record.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records_chained() {
+        let path = env!("OUT_DIR").to_owned() + "/audit_log";
+        let _ = fs::remove_file(&path);
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let log = AuditLog::new(&file_config(&path))
+            .unwrap()
+            .with_sink(Box::new(MemorySink(written.clone())));
+        log.record(
+            &key_triple("key"),
+            Opcode::PsaGenerateKey,
+            ResponseStatus::Success,
+        );
+        log.record(
+            &key_triple("key"),
+            Opcode::PsaSignHash,
+            ResponseStatus::PsaErrorNotPermitted,
+        );
+        log.record_copy(&key_triple("copy"), ResponseStatus::Success);
+        assert_eq!(written.lock().unwrap().len(), 3);
+
+        // The chain is resumed by the next log.
+        let log = AuditLog::new(&file_config(&path)).unwrap();
+        log.record(
+            &key_triple("key"),
+            Opcode::PsaDestroyKey,
+            ResponseStatus::Success,
+        );
+        assert_eq!(verify(&path).unwrap(), 4);
+
+        let records = read_records(&path).unwrap();
+        assert_eq!(records[0].previous_hash, vec![0; 32]);
+        assert_eq!(records[1].opcode, "PsaSignHash");
+        assert_eq!(records[1].status, "PsaErrorNotPermitted");
+        assert_eq!(records[2].key_name, "copy");
+        assert_eq!(records[2].opcode, "CopyKey");
+        assert_eq!(records[3].previous_hash, records[2].hash);
+    }
+
+    #[test]
+    fn tampering_detected() {
+        let path = env!("OUT_DIR").to_owned() + "/audit_log_tampered";
+        let _ = fs::remove_file(&path);
+        let log = AuditLog::new(&file_config(&path)).unwrap();
+        for key_name in &["first", "second", "third"] {
+            log.record(
+                &key_triple(key_name),
+                Opcode::PsaGenerateKey,
+                ResponseStatus::Success,
+            );
+        }
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(verify(&path).unwrap(), 3);
+
+        fs::write(&path, content.replace("second", "other")).unwrap();
+        assert!(verify(&path).is_err());
+
+        let lines: Vec<&str> = content.lines().collect();
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(verify(&path).is_err());
+    }
+}
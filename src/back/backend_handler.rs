@@ -5,17 +5,72 @@
 //! The backend handler embodies the last processing step from external request
 //! to internal function call - parsing of the request body and conversion to a
 //! native operation which is then passed to the provider.
+//!
+//! The conversion itself is not code of the service: the converters come from the
+//! `parsec-interface` crate and the checks of the key attributes against the operations (such as
+//! `can_sign_hash` or `permits_alg`) from the `psa-crypto` crate. The latter is already used here
+//! without its default features and builds without `std`, so that a TEE-side component can share
+//! the attribute validation. A `no_std` build of the operation conversion has to be done in
+//! `parsec-interface`, whose converters and operation types currently depend on `std`; the
+//! service would keep using them through `Convert` as it does now.
+use super::app_keks::{self, AppKeks};
+#[cfg(feature = "audit-log")]
+use super::audit::AuditLog;
+use super::canary_keys::CanaryKeys;
+use super::concurrency_limit::ConcurrencyLimit;
+#[cfg(feature = "dead-letters")]
+use super::dead_letters::{self, DeadLetter, DeadLetters};
+use super::error_metadata::ErrorMetadata;
+#[cfg(feature = "event-hooks")]
+use super::event_hooks::{Event, EventHooks, EventKind};
+use super::fault_injection::FaultInjection;
+use super::key_activation::{KeyActivation, PreActiveCreation};
+use super::key_binding::KeyBindings;
+use super::key_certificates::KeyCertificates;
+#[cfg(feature = "key-counters")]
+use super::key_counters::{self, KeyCounters};
+use super::key_creation_policy::KeyCreationPolicy;
+use super::key_labels::{self, KeyLabels};
+use super::key_naming_policy::KeyNamingPolicy;
+#[cfg(feature = "event-hooks")]
+use super::key_publisher::{KeyPublisher, PublishedKey};
+use super::key_slots::{KeySlots, KeySlotsUsage};
+use super::key_unlocks::KeyUnlocks;
+use super::memory_limits::{InFlight, MemoryLimits, MemoryUsage};
+use super::operation_deadlines::OperationDeadlines;
+use super::operation_metrics::{MetricsSnapshot, OperationMetrics};
+use super::operation_statistics::{OperationStatistics, StatisticsSnapshot};
+use super::peer_keys::PeerKeys;
+use super::platform_evidence::{self, PlatformEvidence};
+#[cfg(feature = "signing-log")]
+use super::signing_log::SigningLog;
 use crate::authenticators::ApplicationName;
-use crate::providers::Provide;
+use crate::front::listener::ConnectionMetadata;
+use crate::key_info_managers::{Compaction, KeyInfo, KeyTriple, MappingHealth};
+use crate::providers::{deadline, Provide, StorageSnapshot};
+use crate::utils::name_policy::NamePolicy;
 use derivative::Derivative;
-use log::trace;
+use log::{error, info, trace, warn};
+use parsec_interface::operations::list_providers::ProviderInfo;
+use parsec_interface::operations::psa_algorithm::AsymmetricSignature;
+#[cfg(feature = "event-hooks")]
+use parsec_interface::operations::psa_export_public_key;
+use parsec_interface::operations::psa_key_attributes::{Attributes, UsageFlags};
 use parsec_interface::operations::Convert;
+use parsec_interface::operations::{
+    psa_destroy_key, psa_generate_key, psa_import_key, psa_sign_hash, psa_verify_hash,
+};
 use parsec_interface::operations::{NativeOperation, NativeResult};
 use parsec_interface::requests::{
-    request::RequestHeader, Request, Response, ResponseStatus, Result,
+    request::RequestBody, request::RequestHeader, response::ResponseBody, Opcode, Request,
+    Response, ResponseStatus, Result,
 };
 use parsec_interface::requests::{BodyType, ProviderID};
+use std::collections::{BTreeMap, HashSet};
 use std::io::{Error, ErrorKind};
+use std::sync::{Arc, MutexGuard};
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
 
 /// Back end handler component
 ///
@@ -35,6 +90,36 @@ pub struct BackEndHandler {
     provider_id: ProviderID,
     content_type: BodyType,
     accept_type: BodyType,
+    key_bindings: Arc<KeyBindings>,
+    key_creation_policy: Arc<KeyCreationPolicy>,
+    key_naming_policy: Arc<KeyNamingPolicy>,
+    name_policy: NamePolicy,
+    key_slots: Option<KeySlots>,
+    key_activation: Option<KeyActivation>,
+    #[cfg(feature = "key-counters")]
+    key_counters: Option<KeyCounters>,
+    key_certificates: Option<KeyCertificates>,
+    key_labels: Option<KeyLabels>,
+    peer_keys: PeerKeys,
+    key_unlocks: KeyUnlocks,
+    app_keks: Option<AppKeks>,
+    statistics: OperationStatistics,
+    metrics: OperationMetrics,
+    #[cfg(feature = "signing-log")]
+    signing_log: Option<Arc<SigningLog>>,
+    #[cfg(feature = "event-hooks")]
+    event_hooks: EventHooks,
+    #[cfg(feature = "event-hooks")]
+    key_publisher: KeyPublisher,
+    canary_keys: CanaryKeys,
+    #[cfg(feature = "dead-letters")]
+    dead_letters: Option<Arc<DeadLetters>>,
+    #[cfg(feature = "audit-log")]
+    audit_log: Option<Arc<AuditLog>>,
+    memory_limits: MemoryLimits,
+    fault_injection: Option<FaultInjection>,
+    concurrency_limit: Option<ConcurrencyLimit>,
+    deadlines: OperationDeadlines,
 }
 
 impl BackEndHandler {
@@ -43,11 +128,247 @@ impl BackEndHandler {
         let mut response = Response::from_request_header(request_hdr, ResponseStatus::Success);
         match self.converter.result_to_body(result) {
             Ok(body) => response.body = body,
-            Err(status) => response.header.status = status,
+            Err(status) => {
+                ErrorMetadata::new(status, self.provider_id, response.header.opcode).log();
+                response.header.status = status;
+            }
         };
         response
     }
 
+    /// Notify the hooks of an event concerning a key.
+    #[cfg(feature = "event-hooks")]
+    fn notify_key_event(&self, kind: EventKind, app_name: &ApplicationName, key_name: &str) {
+        match kind {
+            EventKind::KeyCreated => self.publish_key(app_name, key_name),
+            EventKind::KeyDestroyed => self.key_publisher.publish(PublishedKey::new(
+                self.provider_id,
+                app_name,
+                key_name,
+                None,
+                None,
+            )),
+            _ => (),
+        }
+        let mut event = Event::new(kind, self.provider_id);
+        event.app_name = Some(app_name.to_string());
+        event.key_name = Some(key_name.to_string());
+        self.event_hooks.notify(event);
+    }
+
+    /// Publish the public key of a key just created, if a publisher selects it.
+    #[cfg(feature = "event-hooks")]
+    fn publish_key(&self, app_name: &ApplicationName, key_name: &str) {
+        if !self.key_publisher.selects(app_name, key_name) {
+            return;
+        }
+        let op = psa_export_public_key::Operation {
+            key_name: key_name.to_string(),
+        };
+        let public_key = match self.provider.psa_export_public_key(app_name.clone(), op) {
+            Ok(result) => result.data,
+            Err(status) => {
+                // Symmetric keys do not have a public key.
+                format_error!("Failed to export the public key to publish", status);
+                return;
+            }
+        };
+        let certificate = self
+            .provider
+            .get_certificate(app_name.clone(), key_name.to_string())
+            .ok();
+        self.key_publisher.publish(PublishedKey::new(
+            self.provider_id,
+            app_name,
+            key_name,
+            Some(&public_key[..]),
+            certificate.as_deref(),
+        ));
+    }
+
+    /// Raise the alarm if the operation is requested on a canary key.
+    fn check_canary(&self, operation: &NativeOperation, app_name: Option<&ApplicationName>) {
+        let key_name = match operation {
+            NativeOperation::PsaGenerateKey(op) => &op.key_name,
+            NativeOperation::PsaImportKey(op) => &op.key_name,
+            NativeOperation::PsaExportPublicKey(op) => &op.key_name,
+            NativeOperation::PsaDestroyKey(op) => &op.key_name,
+            NativeOperation::PsaSignHash(op) => &op.key_name,
+            NativeOperation::PsaVerifyHash(op) => &op.key_name,
+            NativeOperation::PsaAsymmetricEncrypt(op) => &op.key_name,
+            NativeOperation::PsaAsymmetricDecrypt(op) => &op.key_name,
+            NativeOperation::PsaAeadEncrypt(op) => &op.key_name,
+            NativeOperation::PsaAeadDecrypt(op) => &op.key_name,
+            NativeOperation::PsaRawKeyAgreement(op) => &op.private_key_name,
+            _ => return,
+        };
+        let (app_name, key_name) = match (app_name, self.name_policy.normalize_key_name(key_name)) {
+            (Some(app_name), Ok(key_name)) => (app_name, key_name),
+            _ => return,
+        };
+        if self.canary_keys.is_canary(app_name, &key_name) {
+            self.canary_keys.log_use(app_name, &key_name);
+            #[cfg(feature = "event-hooks")]
+            {
+                let mut event = Event::new(EventKind::CanaryKeyUsed, self.provider_id);
+                event.app_name = Some(app_name.to_string());
+                event.key_name = Some(key_name);
+                event.opcode = Some(format!("{:?}", operation.opcode()));
+                self.event_hooks.notify(event);
+            }
+        }
+    }
+
+    /// Reserve a key slot for a new key of the application, notifying the hooks if there is none.
+    #[cfg_attr(not(feature = "event-hooks"), allow(unused_variables))]
+    fn reserve_slot(
+        &self,
+        app_name: &ApplicationName,
+        key_name: &str,
+    ) -> Result<Option<MutexGuard<'_, ()>>> {
+        let key_slots = match &self.key_slots {
+            Some(key_slots) => key_slots,
+            None => return Ok(None),
+        };
+        let result = key_slots.reserve_slot(app_name).map(Some);
+        #[cfg(feature = "event-hooks")]
+        {
+            if let Err(ResponseStatus::PsaErrorInsufficientStorage) = result {
+                self.notify_key_event(EventKind::QuotaExceeded, app_name, key_name);
+            }
+        }
+
+        result
+    }
+
+    /// Normalize a key name given by a client and check that it is not reserved.
+    fn check_key_name(&self, key_name: &str) -> Result<String> {
+        let key_name = self.name_policy.normalize_key_name(key_name)?;
+        app_keks::check_key_name(&key_name)?;
+
+        Ok(key_name)
+    }
+
+    /// Normalize the name of a new key of the application and check that it is not reserved and
+    /// that it follows the key naming rules.
+    fn check_new_key_name(&self, app_name: &ApplicationName, key_name: &str) -> Result<String> {
+        let key_name = self.check_key_name(key_name)?;
+        self.key_naming_policy.check(app_name, &key_name)?;
+
+        Ok(key_name)
+    }
+
+    /// Give the cached credential of a key to the provider if the key needs to be unlocked before
+    /// being used.
+    fn check_unlocked(
+        &self,
+        app_name: &ApplicationName,
+        key_name: &str,
+        metadata: Option<ConnectionMetadata>,
+    ) -> Result<()> {
+        if !self.provider.key_requires_unlock(app_name, key_name)? {
+            return Ok(());
+        }
+
+        let key_triple = KeyTriple::new(app_name.clone(), self.provider_id, key_name.to_string());
+        let credential = metadata
+            .and_then(|client| self.key_unlocks.credential(&key_triple, client))
+            .ok_or_else(|| {
+                error!("The key needs to be unlocked before being used.");
+                ResponseStatus::PsaErrorNotPermitted
+            })?;
+        self.provider
+            .unlock_key(app_name.clone(), key_name.to_string(), &credential)
+    }
+
+    /// Check that a key is not pre-active before it is used for a cryptographic operation.
+    fn check_active(&self, app_name: &ApplicationName, key_name: &str) -> Result<()> {
+        match &self.key_activation {
+            Some(key_activation) => key_activation.check_active(&KeyTriple::new(
+                app_name.clone(),
+                self.provider_id,
+                key_name.to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Create a key of the application with the given function, leaving it pre-active if the new
+    /// keys of the application are.
+    fn create_key<T>(
+        &self,
+        app_name: &ApplicationName,
+        key_name: &str,
+        create: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        let key_activation = match &self.key_activation {
+            Some(key_activation) if key_activation.creates_pre_active(app_name) => key_activation,
+            _ => return create(),
+        };
+        let key_triple = KeyTriple::new(app_name.clone(), self.provider_id, key_name.to_string());
+        let creation = key_activation.begin_creation(vec![key_triple.clone()]);
+        let result = create()?;
+        self.set_pre_active(&creation, &key_triple)?;
+
+        Ok(result)
+    }
+
+    /// Store the pre-active state of a key just created, destroying the key if it can not be
+    /// stored as the key would otherwise be active.
+    fn set_pre_active(
+        &self,
+        creation: &PreActiveCreation<'_>,
+        key_triple: &KeyTriple,
+    ) -> Result<()> {
+        creation.set_pre_active(key_triple).map_err(|status| {
+            let op = psa_destroy_key::Operation {
+                key_name: key_triple.key_name().to_string(),
+            };
+            if let Err(e) = self
+                .provider
+                .psa_destroy_key(key_triple.app_name().clone(), op)
+            {
+                format_error!(
+                    "Failed to destroy the key which could not be made pre-active",
+                    e
+                );
+            }
+            status
+        })
+    }
+
+    /// Sign the hash with the provider, recording the signature in the signing log if the key is
+    /// a high-value one.
+    #[cfg(feature = "signing-log")]
+    fn sign_hash(
+        &self,
+        app_name: ApplicationName,
+        op: psa_sign_hash::Operation,
+    ) -> Result<psa_sign_hash::Result> {
+        let key_triple = KeyTriple::new(app_name.clone(), self.provider_id, op.key_name.clone());
+        let signing_log = self
+            .signing_log
+            .as_ref()
+            .filter(|signing_log| signing_log.is_high_value(&key_triple));
+        let (alg, hash) = (op.alg, op.hash.to_vec());
+        let result = self.provider.psa_sign_hash(app_name, op)?;
+        if let Some(signing_log) = signing_log {
+            signing_log.record(&key_triple, alg, &hash, &result.signature[..])?;
+        }
+
+        Ok(result)
+    }
+
+    /// Sign the hash with the provider.
+    #[cfg(not(feature = "signing-log"))]
+    fn sign_hash(
+        &self,
+        app_name: ApplicationName,
+        op: psa_sign_hash::Operation,
+    ) -> Result<psa_sign_hash::Result> {
+        self.provider.psa_sign_hash(app_name, op)
+    }
+
     /// Assess whether the backend handler-provider pair is capable of handling
     /// the request.
     ///
@@ -78,86 +399,1354 @@ impl BackEndHandler {
     ///
     /// If any of the steps fails, a response containing an appropriate status code is
     /// returned.
-    pub fn execute_request(&self, request: Request, app_name: Option<ApplicationName>) -> Response {
+    ///
+    /// The connection metadata is used to check that keys bound to a client process are only used
+    /// by that process.
+    ///
+    /// If the request created a key, it is returned along with the response so that the key can
+    /// be rolled back with `roll_back_key_creation` if the response never reaches the client.
+    pub fn execute_request(
+        &self,
+        request: Request,
+        app_name: Option<ApplicationName>,
+        metadata: Option<ConnectionMetadata>,
+    ) -> (Response, Option<KeyTriple>) {
         trace!("execute_request ingress");
-        let opcode = request.header.opcode;
-        let header = request.header;
-
-        macro_rules! unwrap_or_else_return {
-            ($result:expr) => {
-                match $result {
-                    Ok(value) => value,
-                    Err(status) => return Response::from_request_header(header, status),
+        let _in_flight = match self.reserve_in_flight(request.body.len()) {
+            Ok(in_flight) => in_flight,
+            Err(status) => {
+                ErrorMetadata::new(status, self.provider_id, request.header.opcode).log();
+                return (Response::from_request_header(request.header, status), None);
+            }
+        };
+        let operation = self.decode(request.body, request.header.opcode);
+        self.execute_decoded(request.header, operation, app_name, metadata)
+    }
+
+    /// Account for a request body of the given length, in flight until the returned guard is
+    /// dropped, shrinking the caches of the provider if the soft memory limit is exceeded.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInsufficientMemory` if the hard memory limit is reached.
+    pub fn reserve_in_flight(&self, len: usize) -> Result<InFlight> {
+        let in_flight = self.memory_limits.reserve(len)?;
+        if in_flight.above_soft_limit {
+            self.provider.shrink_caches();
+        }
+
+        Ok(in_flight)
+    }
+
+    /// Unmarshall the body of a request.
+    pub fn decode(&self, body: RequestBody, opcode: Opcode) -> Result<NativeOperation> {
+        self.converter.body_to_operation(body, opcode)
+    }
+
+    /// Marshall the result of an operation into the body of a response.
+    pub fn encode(&self, result: NativeResult) -> Result<ResponseBody> {
+        self.converter.result_to_body(result)
+    }
+
+    /// Pass the operation unmarshalled from a request to the provider and marshall the result
+    /// back, as `execute_request` does.
+    pub fn execute_decoded(
+        &self,
+        header: RequestHeader,
+        operation: Result<NativeOperation>,
+        app_name: Option<ApplicationName>,
+        metadata: Option<ConnectionMetadata>,
+    ) -> (Response, Option<KeyTriple>) {
+        let opcode = header.opcode;
+        let start = Instant::now();
+        let mut created_key = None;
+        #[cfg(feature = "dead-letters")]
+        let mut dead_letter = None;
+        let result = operation.and_then(|operation| {
+            created_key = self.created_key(&operation, app_name.as_ref());
+            #[cfg(feature = "dead-letters")]
+            {
+                dead_letter = self.dead_letter(&operation, app_name.as_ref());
+            }
+            self.execute_operation(operation, app_name, metadata)
+        });
+        let error = result.as_ref().err().copied();
+        #[cfg(feature = "dead-letters")]
+        if let (Some(dead_letters), Some(mut dead_letter), Some(status)) =
+            (&self.dead_letters, dead_letter, error)
+        {
+            if dead_letters::is_unavailability(status) {
+                dead_letter.status = status.to_string();
+                dead_letters.record(&dead_letter);
+            }
+        }
+        let latency = start.elapsed();
+        self.statistics.record(latency, error);
+        self.metrics.record(opcode, latency, error);
+        #[cfg(feature = "event-hooks")]
+        self.event_hooks.check_health(self.provider_id, error);
+        let (response, created_key) = match result {
+            Ok(result) => {
+                let response = self.result_to_response(result, header);
+                if response.header.status == ResponseStatus::Success {
+                    (response, created_key)
+                } else {
+                    (response, None)
                 }
-            };
+            }
+            Err(status) => {
+                ErrorMetadata::new(status, self.provider_id, opcode).log();
+                (Response::from_request_header(header, status), None)
+            }
+        };
+
+        (response, created_key)
+    }
+
+    /// Get the key the operation would create if it succeeds.
+    fn created_key(
+        &self,
+        operation: &NativeOperation,
+        app_name: Option<&ApplicationName>,
+    ) -> Option<KeyTriple> {
+        let key_name = match operation {
+            NativeOperation::PsaGenerateKey(op_generate_key) => &op_generate_key.key_name,
+            NativeOperation::PsaImportKey(op_import_key) => &op_import_key.key_name,
+            _ => return None,
+        };
+        let key_name = self.check_key_name(key_name).ok()?;
+
+        Some(KeyTriple::new(
+            app_name?.clone(),
+            self.provider_id,
+            key_name,
+        ))
+    }
+
+    /// Get the record to store if the operation fails because its backend is unavailable.
+    #[cfg(feature = "dead-letters")]
+    fn dead_letter(
+        &self,
+        operation: &NativeOperation,
+        app_name: Option<&ApplicationName>,
+    ) -> Option<DeadLetter> {
+        let _ = self.dead_letters.as_ref()?;
+        let key_name = match operation {
+            NativeOperation::PsaGenerateKey(op_generate_key) => &op_generate_key.key_name,
+            NativeOperation::PsaImportKey(op_import_key) => &op_import_key.key_name,
+            NativeOperation::PsaDestroyKey(op_destroy_key) => &op_destroy_key.key_name,
+            _ => return None,
+        };
+        let key_name = self.check_key_name(key_name).ok()?;
+        let key_triple = KeyTriple::new(app_name?.clone(), self.provider_id, key_name);
+
+        DeadLetter::from_operation(operation, &key_triple)
+    }
+
+    /// Get the key of the operation if it has to be recorded in the audit log.
+    #[cfg(feature = "audit-log")]
+    fn audited_key(
+        &self,
+        operation: &NativeOperation,
+        app_name: Option<&ApplicationName>,
+    ) -> Option<KeyTriple> {
+        let _ = self.audit_log.as_ref()?;
+        let key_name = match operation {
+            NativeOperation::PsaGenerateKey(op_generate_key) => &op_generate_key.key_name,
+            NativeOperation::PsaImportKey(op_import_key) => &op_import_key.key_name,
+            NativeOperation::PsaDestroyKey(op_destroy_key) => &op_destroy_key.key_name,
+            NativeOperation::PsaSignHash(op_sign_hash) => &op_sign_hash.key_name,
+            NativeOperation::PsaExportPublicKey(op_export_public_key) => {
+                &op_export_public_key.key_name
+            }
+            _ => return None,
+        };
+
+        Some(self.audited_key_triple(app_name, key_name))
+    }
+
+    /// Get the key triple under which an operation on a key is recorded in the audit log, before
+    /// its name is checked.
+    #[cfg(feature = "audit-log")]
+    fn audited_key_triple(&self, app_name: Option<&ApplicationName>, key_name: &str) -> KeyTriple {
+        // Requests refused before being authenticated are recorded without an application.
+        let app_name = app_name
+            .cloned()
+            .unwrap_or_else(|| ApplicationName::new(String::new()));
+        // Invalid names are escaped as they may contain the separators of the records.
+        let key_name = self
+            .name_policy
+            .normalize_key_name(key_name)
+            .unwrap_or_else(|_| key_name.escape_default().to_string());
+
+        KeyTriple::new(app_name, self.provider_id, key_name)
+    }
+
+    /// Record an operation on a key in the audit log, with the status of its result.
+    #[cfg_attr(not(feature = "audit-log"), allow(unused_variables))]
+    fn audit<T>(&self, key_triple: &KeyTriple, opcode: Opcode, result: &Result<T>) {
+        #[cfg(feature = "audit-log")]
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(
+                key_triple,
+                opcode,
+                result
+                    .as_ref()
+                    .err()
+                    .copied()
+                    .unwrap_or(ResponseStatus::Success),
+            );
+        }
+    }
+
+    /// Cancel the operations in flight, which give up at the next point where they check their
+    /// deadline, and the ones starting from now.
+    pub fn cancel_operations(&self) {
+        self.deadlines.cancel();
+    }
+
+    /// Destroy a key created by a request whose response could not be sent, as the client does
+    /// not know that the key exists.
+    pub fn roll_back_key_creation(&self, key_triple: &KeyTriple) {
+        let op = psa_destroy_key::Operation {
+            key_name: key_triple.key_name().to_string(),
+        };
+        let result = self
+            .provider
+            .psa_destroy_key(key_triple.app_name().clone(), op);
+        self.audit(key_triple, Opcode::PsaDestroyKey, &result);
+        match result {
+            Ok(_) => {
+                self.key_bindings.unbind(
+                    key_triple.app_name(),
+                    self.provider_id,
+                    key_triple.key_name(),
+                );
+                self.key_unlocks.forget(key_triple);
+                #[cfg(feature = "event-hooks")]
+                self.notify_key_event(
+                    EventKind::KeyDestroyed,
+                    key_triple.app_name(),
+                    key_triple.key_name(),
+                );
+                if crate::utils::GlobalConfig::log_error_details() {
+                    warn!(
+                        "Destroyed the key created for a client which disconnected ({}).",
+                        key_triple
+                    );
+                } else {
+                    warn!("Destroyed the key created for a client which disconnected.");
+                }
+            }
+            Err(status) => {
+                ErrorMetadata::new(status, self.provider_id, Opcode::PsaDestroyKey).log();
+            }
+        }
+    }
+
+    /// Pass an operation to the provider, after the same checks as for the operation of a
+    /// request, and return its result.
+    ///
+    /// This is what requests go through once unmarshalled, but it can also be used by the service
+    /// itself to execute operations on behalf of a client, see `Dispatcher::execute_nested`. The
+    /// key creations, destructions, signatures and exports are recorded in the audit log, whether
+    /// they succeed or not.
+    pub fn execute_operation(
+        &self,
+        operation: NativeOperation,
+        app_name: Option<ApplicationName>,
+        metadata: Option<ConnectionMetadata>,
+    ) -> Result<NativeResult> {
+        self.execute_audited(operation, app_name, metadata, false)
+    }
+
+    /// Pass an operation executed by an administrator on the keys of a client to the provider,
+    /// after the same checks as `execute_operation` except for the key bindings: the keys bound to
+    /// a process of the client are destroyed whichever process they are bound to.
+    ///
+    /// This is how the keys of a deleted client are destroyed, see `CallerContext::admin`.
+    pub fn execute_admin_operation(
+        &self,
+        operation: NativeOperation,
+        app_name: Option<ApplicationName>,
+    ) -> Result<NativeResult> {
+        self.execute_audited(operation, app_name, None, true)
+    }
+
+    fn execute_audited(
+        &self,
+        operation: NativeOperation,
+        app_name: Option<ApplicationName>,
+        metadata: Option<ConnectionMetadata>,
+        admin: bool,
+    ) -> Result<NativeResult> {
+        #[cfg(feature = "audit-log")]
+        let audited_key = self
+            .audited_key(&operation, app_name.as_ref())
+            .map(|key_triple| (key_triple, operation.opcode()));
+        let result = self.execute_checked(operation, app_name, metadata, admin);
+        #[cfg(feature = "audit-log")]
+        if let Some((key_triple, opcode)) = audited_key {
+            self.audit(&key_triple, opcode, &result);
         }
 
-        match unwrap_or_else_return!(self.converter.body_to_operation(request.body, opcode)) {
+        result
+    }
+
+    /// Pass an operation to the provider after its checks, without recording it in the audit log.
+    /// The key bindings are not checked for the destructions of an administrator.
+    fn execute_checked(
+        &self,
+        operation: NativeOperation,
+        app_name: Option<ApplicationName>,
+        metadata: Option<ConnectionMetadata>,
+        admin: bool,
+    ) -> Result<NativeResult> {
+        let _deadline = deadline::enter(self.deadlines.deadline(operation.opcode()));
+        self.check_canary(&operation, app_name.as_ref());
+        if let Some(fault_injection) = &self.fault_injection {
+            fault_injection.inject(operation.opcode())?;
+        }
+        let _concurrency_guard = self
+            .concurrency_limit
+            .as_ref()
+            .map(ConcurrencyLimit::acquire)
+            .transpose()?;
+        match operation {
             NativeOperation::ListProviders(op_list_providers) => {
-                let result =
-                    unwrap_or_else_return!(self.provider.list_providers(op_list_providers));
+                let result = self.provider.list_providers(op_list_providers)?;
                 trace!("list_providers egress");
-                self.result_to_response(NativeResult::ListProviders(result), header)
+                Ok(NativeResult::ListProviders(result))
             }
             NativeOperation::ListOpcodes(op_list_opcodes) => {
-                let result = unwrap_or_else_return!(self.provider.list_opcodes(op_list_opcodes));
+                let result = self.provider.list_opcodes(op_list_opcodes)?;
                 trace!("list_opcodes egress");
-                self.result_to_response(NativeResult::ListOpcodes(result), header)
+                Ok(NativeResult::ListOpcodes(result))
+            }
+            NativeOperation::ListAuthenticators(op_list_authenticators) => {
+                let result = self.provider.list_authenticators(op_list_authenticators)?;
+                trace!("list_authenticators egress");
+                Ok(NativeResult::ListAuthenticators(result))
             }
             NativeOperation::Ping(op_ping) => {
-                let result = unwrap_or_else_return!(self.provider.ping(op_ping));
+                let result = self.provider.ping(op_ping)?;
                 trace!("ping egress");
-                self.result_to_response(NativeResult::Ping(result), header)
+                Ok(NativeResult::Ping(result))
             }
-            NativeOperation::PsaGenerateKey(op_generate_key) => {
-                let app_name =
-                    unwrap_or_else_return!(app_name.ok_or(ResponseStatus::NotAuthenticated));
-                let result = unwrap_or_else_return!(self
-                    .provider
-                    .psa_generate_key(app_name, op_generate_key));
+            NativeOperation::ListKeys(op_list_keys) => {
+                let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+                let result = self.provider.list_keys(app_name, op_list_keys)?;
+                trace!("list_keys egress");
+                Ok(NativeResult::ListKeys(result))
+            }
+            NativeOperation::ListClients(op_list_clients) => {
+                if app_name.is_none() {
+                    return Err(ResponseStatus::NotAuthenticated);
+                }
+                let result = self.provider.list_clients(op_list_clients)?;
+                trace!("list_clients egress");
+                Ok(NativeResult::ListClients(result))
+            }
+            NativeOperation::DeleteClient(op_delete_client) => {
+                if app_name.is_none() {
+                    return Err(ResponseStatus::NotAuthenticated);
+                }
+                let result = self.provider.delete_client(op_delete_client)?;
+                trace!("delete_client egress");
+                Ok(NativeResult::DeleteClient(result))
+            }
+            NativeOperation::PsaGenerateKey(mut op_generate_key) => {
+                let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+                op_generate_key.key_name =
+                    self.check_new_key_name(&app_name, &op_generate_key.key_name)?;
+                self.key_creation_policy.check(
+                    &app_name,
+                    self.provider_id,
+                    &op_generate_key.attributes,
+                )?;
+                let binding = self.key_bindings.new_binding(
+                    &app_name,
+                    self.provider_id,
+                    &op_generate_key.key_name,
+                    metadata,
+                )?;
+                let _slot_guard = self.reserve_slot(&app_name, &op_generate_key.key_name)?;
+                let key_name = op_generate_key.key_name.clone();
+                let result = self.create_key(&app_name, &key_name, || {
+                    self.provider
+                        .psa_generate_key(app_name.clone(), op_generate_key)
+                })?;
+                if let Some(binding) = binding {
+                    self.key_bindings.bind(binding);
+                }
+                #[cfg(feature = "event-hooks")]
+                self.notify_key_event(EventKind::KeyCreated, &app_name, &key_name);
                 trace!("psa_generate_key egress");
-                self.result_to_response(NativeResult::PsaGenerateKey(result), header)
+                Ok(NativeResult::PsaGenerateKey(result))
             }
-            NativeOperation::PsaImportKey(op_import_key) => {
-                let app_name =
-                    unwrap_or_else_return!(app_name.ok_or(ResponseStatus::NotAuthenticated));
-                let result =
-                    unwrap_or_else_return!(self.provider.psa_import_key(app_name, op_import_key));
+            NativeOperation::PsaImportKey(mut op_import_key) => {
+                let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+                op_import_key.key_name =
+                    self.check_new_key_name(&app_name, &op_import_key.key_name)?;
+                self.key_creation_policy.check(
+                    &app_name,
+                    self.provider_id,
+                    &op_import_key.attributes,
+                )?;
+                let binding = self.key_bindings.new_binding(
+                    &app_name,
+                    self.provider_id,
+                    &op_import_key.key_name,
+                    metadata,
+                )?;
+                let _slot_guard = self.reserve_slot(&app_name, &op_import_key.key_name)?;
+                let key_name = op_import_key.key_name.clone();
+                let result = self.create_key(&app_name, &key_name, || {
+                    self.provider
+                        .psa_import_key(app_name.clone(), op_import_key)
+                })?;
+                if let Some(binding) = binding {
+                    self.key_bindings.bind(binding);
+                }
+                #[cfg(feature = "event-hooks")]
+                self.notify_key_event(EventKind::KeyCreated, &app_name, &key_name);
                 trace!("psa_import_key egress");
-                self.result_to_response(NativeResult::PsaImportKey(result), header)
+                Ok(NativeResult::PsaImportKey(result))
             }
-            NativeOperation::PsaExportPublicKey(op_export_public_key) => {
-                let app_name =
-                    unwrap_or_else_return!(app_name.ok_or(ResponseStatus::NotAuthenticated));
-                let result = unwrap_or_else_return!(self
+            NativeOperation::PsaExportPublicKey(mut op_export_public_key) => {
+                let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+                op_export_public_key.key_name =
+                    self.check_key_name(&op_export_public_key.key_name)?;
+                self.key_bindings.check_use(
+                    &app_name,
+                    self.provider_id,
+                    &op_export_public_key.key_name,
+                    metadata,
+                )?;
+                let result = self
                     .provider
-                    .psa_export_public_key(app_name, op_export_public_key));
+                    .psa_export_public_key(app_name, op_export_public_key)?;
                 trace!("psa_export_public_key egress");
-                self.result_to_response(NativeResult::PsaExportPublicKey(result), header)
+                Ok(NativeResult::PsaExportPublicKey(result))
             }
-            NativeOperation::PsaDestroyKey(op_destroy_key) => {
-                let app_name =
-                    unwrap_or_else_return!(app_name.ok_or(ResponseStatus::NotAuthenticated));
-                let result =
-                    unwrap_or_else_return!(self.provider.psa_destroy_key(app_name, op_destroy_key));
+            NativeOperation::PsaDestroyKey(mut op_destroy_key) => {
+                let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+                op_destroy_key.key_name = self.check_key_name(&op_destroy_key.key_name)?;
+                let key_name = op_destroy_key.key_name.clone();
+                if !admin {
+                    self.key_bindings.check_destroy(
+                        &app_name,
+                        self.provider_id,
+                        &key_name,
+                        metadata,
+                    )?;
+                }
+                let result = self
+                    .provider
+                    .psa_destroy_key(app_name.clone(), op_destroy_key)?;
+                self.key_bindings
+                    .unbind(&app_name, self.provider_id, &key_name);
+                #[cfg(feature = "event-hooks")]
+                self.notify_key_event(EventKind::KeyDestroyed, &app_name, &key_name);
+                let key_triple = KeyTriple::new(app_name, self.provider_id, key_name);
+                self.peer_keys.forget(&key_triple);
+                self.key_unlocks.forget(&key_triple);
                 trace!("psa_destroy_key egress");
-                self.result_to_response(NativeResult::PsaDestroyKey(result), header)
+                Ok(NativeResult::PsaDestroyKey(result))
             }
-            NativeOperation::PsaSignHash(op_sign_hash) => {
-                let app_name =
-                    unwrap_or_else_return!(app_name.ok_or(ResponseStatus::NotAuthenticated));
-                let result =
-                    unwrap_or_else_return!(self.provider.psa_sign_hash(app_name, op_sign_hash));
+            NativeOperation::PsaSignHash(mut op_sign_hash) => {
+                let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+                op_sign_hash.key_name = self.check_key_name(&op_sign_hash.key_name)?;
+                self.key_bindings.check_use(
+                    &app_name,
+                    self.provider_id,
+                    &op_sign_hash.key_name,
+                    metadata,
+                )?;
+                self.check_active(&app_name, &op_sign_hash.key_name)?;
+                self.check_unlocked(&app_name, &op_sign_hash.key_name, metadata)?;
+                let result = self.sign_hash(app_name, op_sign_hash)?;
                 trace!("psa_sign_hash egress");
-                self.result_to_response(NativeResult::PsaSignHash(result), header)
+                Ok(NativeResult::PsaSignHash(result))
             }
-            NativeOperation::PsaVerifyHash(op_verify_hash) => {
-                let app_name =
-                    unwrap_or_else_return!(app_name.ok_or(ResponseStatus::NotAuthenticated));
-                let result =
-                    unwrap_or_else_return!(self.provider.psa_verify_hash(app_name, op_verify_hash));
+            NativeOperation::PsaVerifyHash(mut op_verify_hash) => {
+                let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+                op_verify_hash.key_name = self.check_key_name(&op_verify_hash.key_name)?;
+                self.key_bindings.check_use(
+                    &app_name,
+                    self.provider_id,
+                    &op_verify_hash.key_name,
+                    metadata,
+                )?;
+                self.check_active(&app_name, &op_verify_hash.key_name)?;
+                let result = self.provider.psa_verify_hash(app_name, op_verify_hash)?;
                 trace!("psa_verify_hash egress");
-                self.result_to_response(NativeResult::PsaVerifyHash(result), header)
+                Ok(NativeResult::PsaVerifyHash(result))
+            }
+            NativeOperation::PsaAsymmetricEncrypt(mut op_asymmetric_encrypt) => {
+                let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+                op_asymmetric_encrypt.key_name =
+                    self.check_key_name(&op_asymmetric_encrypt.key_name)?;
+                self.key_bindings.check_use(
+                    &app_name,
+                    self.provider_id,
+                    &op_asymmetric_encrypt.key_name,
+                    metadata,
+                )?;
+                self.check_active(&app_name, &op_asymmetric_encrypt.key_name)?;
+                let result = self
+                    .provider
+                    .psa_asymmetric_encrypt(app_name, op_asymmetric_encrypt)?;
+                trace!("psa_asymmetric_encrypt egress");
+                Ok(NativeResult::PsaAsymmetricEncrypt(result))
+            }
+            NativeOperation::PsaAsymmetricDecrypt(mut op_asymmetric_decrypt) => {
+                let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+                op_asymmetric_decrypt.key_name =
+                    self.check_key_name(&op_asymmetric_decrypt.key_name)?;
+                self.key_bindings.check_use(
+                    &app_name,
+                    self.provider_id,
+                    &op_asymmetric_decrypt.key_name,
+                    metadata,
+                )?;
+                self.check_active(&app_name, &op_asymmetric_decrypt.key_name)?;
+                self.check_unlocked(&app_name, &op_asymmetric_decrypt.key_name, metadata)?;
+                let result = self
+                    .provider
+                    .psa_asymmetric_decrypt(app_name, op_asymmetric_decrypt)?;
+                trace!("psa_asymmetric_decrypt egress");
+                Ok(NativeResult::PsaAsymmetricDecrypt(result))
+            }
+            NativeOperation::PsaAeadEncrypt(mut op_aead_encrypt) => {
+                let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+                op_aead_encrypt.key_name = self.check_key_name(&op_aead_encrypt.key_name)?;
+                self.key_bindings.check_use(
+                    &app_name,
+                    self.provider_id,
+                    &op_aead_encrypt.key_name,
+                    metadata,
+                )?;
+                self.check_active(&app_name, &op_aead_encrypt.key_name)?;
+                self.check_unlocked(&app_name, &op_aead_encrypt.key_name, metadata)?;
+                let result = self.provider.psa_aead_encrypt(app_name, op_aead_encrypt)?;
+                trace!("psa_aead_encrypt egress");
+                Ok(NativeResult::PsaAeadEncrypt(result))
+            }
+            NativeOperation::PsaAeadDecrypt(mut op_aead_decrypt) => {
+                let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+                op_aead_decrypt.key_name = self.check_key_name(&op_aead_decrypt.key_name)?;
+                self.key_bindings.check_use(
+                    &app_name,
+                    self.provider_id,
+                    &op_aead_decrypt.key_name,
+                    metadata,
+                )?;
+                self.check_active(&app_name, &op_aead_decrypt.key_name)?;
+                self.check_unlocked(&app_name, &op_aead_decrypt.key_name, metadata)?;
+                let result = self.provider.psa_aead_decrypt(app_name, op_aead_decrypt)?;
+                trace!("psa_aead_decrypt egress");
+                Ok(NativeResult::PsaAeadDecrypt(result))
+            }
+            NativeOperation::PsaRawKeyAgreement(mut op_raw_key_agreement) => {
+                let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+                op_raw_key_agreement.private_key_name =
+                    self.check_key_name(&op_raw_key_agreement.private_key_name)?;
+                self.key_bindings.check_use(
+                    &app_name,
+                    self.provider_id,
+                    &op_raw_key_agreement.private_key_name,
+                    metadata,
+                )?;
+                self.check_active(&app_name, &op_raw_key_agreement.private_key_name)?;
+                self.check_unlocked(&app_name, &op_raw_key_agreement.private_key_name, metadata)?;
+                let result = self
+                    .provider
+                    .psa_raw_key_agreement(app_name, op_raw_key_agreement)?;
+                trace!("psa_raw_key_agreement egress");
+                Ok(NativeResult::PsaRawKeyAgreement(result))
+            }
+            NativeOperation::PsaHashCompute(op_hash_compute) => {
+                let result = self.provider.psa_hash_compute(op_hash_compute)?;
+                trace!("psa_hash_compute egress");
+                Ok(NativeResult::PsaHashCompute(result))
+            }
+            NativeOperation::PsaHashCompare(op_hash_compare) => {
+                let result = self.provider.psa_hash_compare(op_hash_compare)?;
+                trace!("psa_hash_compare egress");
+                Ok(NativeResult::PsaHashCompare(result))
+            }
+        }
+    }
+
+    /// Generate several keys for the application in a single call to the provider, for example to
+    /// provision devices.
+    ///
+    /// The key creation rules, process bindings and key slots apply to all the keys as they would
+    /// for individual requests. The result of each key is returned in the order of the operations
+    /// given: a key failing to be created does not prevent the others from being created.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error, and does not create any key, if the application is not authenticated, if
+    /// there are not enough key slots for all the keys or if the provider could not process any
+    /// of them.
+    pub fn generate_keys(
+        &self,
+        app_name: Option<ApplicationName>,
+        ops: Vec<psa_generate_key::Operation>,
+        metadata: Option<ConnectionMetadata>,
+    ) -> Result<Vec<Result<psa_generate_key::Result>>> {
+        trace!("generate_keys ingress");
+        let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+
+        // Results of the keys refused before reaching the provider, None for the others.
+        let mut results = Vec::with_capacity(ops.len());
+        let mut accepted_ops = Vec::new();
+        let mut bindings = Vec::new();
+        for mut op in ops {
+            let binding = self
+                .check_new_key_name(&app_name, &op.key_name)
+                .and_then(|key_name| {
+                    op.key_name = key_name;
+                    self.key_creation_policy
+                        .check(&app_name, self.provider_id, &op.attributes)
+                })
+                .and_then(|_| {
+                    self.key_bindings.new_binding(
+                        &app_name,
+                        self.provider_id,
+                        &op.key_name,
+                        metadata,
+                    )
+                });
+            match binding {
+                Ok(binding) => {
+                    bindings.push(binding);
+                    accepted_ops.push(op);
+                    results.push(None);
+                }
+                Err(status) => {
+                    ErrorMetadata::new(status, self.provider_id, Opcode::PsaGenerateKey).log();
+                    let result = Err(status);
+                    #[cfg(feature = "audit-log")]
+                    self.audit(
+                        &self.audited_key_triple(Some(&app_name), &op.key_name),
+                        Opcode::PsaGenerateKey,
+                        &result,
+                    );
+                    results.push(Some(result));
+                }
+            }
+        }
+
+        let key_triples: Vec<KeyTriple> = accepted_ops
+            .iter()
+            .map(|op| KeyTriple::new(app_name.clone(), self.provider_id, op.key_name.clone()))
+            .collect();
+        // The keys refused all together are recorded as failing with the status of the call.
+        let audit_refused = |status| {
+            for key_triple in &key_triples {
+                self.audit(key_triple, Opcode::PsaGenerateKey, &Err::<(), _>(status));
+            }
+            status
+        };
+        let _slots_guard = match &self.key_slots {
+            Some(key_slots) => Some(
+                key_slots
+                    .reserve_slots(&app_name, accepted_ops.len())
+                    .map_err(audit_refused)?,
+            ),
+            None => None,
+        };
+        let creation = match &self.key_activation {
+            Some(key_activation) if key_activation.creates_pre_active(&app_name) => {
+                Some(key_activation.begin_creation(key_triples.clone()))
+            }
+            _ => None,
+        };
+        let mut provider_results = self
+            .provider
+            .psa_generate_keys(app_name, accepted_ops)
+            .map_err(audit_refused)?
+            .into_iter()
+            .zip(bindings.into_iter().zip(key_triples.iter()));
+
+        let results = results
+            .into_iter()
+            .map(|result| match result {
+                Some(result) => result,
+                None => match provider_results.next() {
+                    Some((result, (binding, key_triple))) => {
+                        let result = result.and_then(|result| {
+                            if let Some(creation) = &creation {
+                                self.set_pre_active(creation, key_triple)?;
+                            }
+                            if let Some(binding) = binding {
+                                self.key_bindings.bind(binding);
+                            }
+                            #[cfg(feature = "event-hooks")]
+                            self.notify_key_event(
+                                EventKind::KeyCreated,
+                                key_triple.app_name(),
+                                key_triple.key_name(),
+                            );
+                            Ok(result)
+                        });
+                        self.audit(key_triple, Opcode::PsaGenerateKey, &result);
+                        if let Err(status) = result {
+                            ErrorMetadata::new(status, self.provider_id, Opcode::PsaGenerateKey)
+                                .log();
+                        }
+                        result
+                    }
+                    None => {
+                        error!("The provider did not return the result of all the keys.");
+                        Err(ResponseStatus::PsaErrorGenericError)
+                    }
+                },
+            })
+            .collect();
+        trace!("generate_keys egress");
+
+        Ok(results)
+    }
+
+    /// Copy a key of the application under a new name, with usages restricted to the given ones.
+    ///
+    /// The key slots and process bindings apply to the copy as to a new key. The key creation
+    /// rules are not checked again: the copy belongs to the same application and is of the same
+    /// type and size as the original, with fewer usages. The copy is recorded in the audit log
+    /// under the name of the new key, whether it succeeds or not.
+    pub fn copy_key(
+        &self,
+        app_name: Option<ApplicationName>,
+        key_name: String,
+        destination_key_name: String,
+        usage_flags: UsageFlags,
+        metadata: Option<ConnectionMetadata>,
+    ) -> Result<()> {
+        trace!("copy_key ingress");
+        let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        #[cfg(feature = "audit-log")]
+        let audited_key = self.audited_key_triple(Some(&app_name), &destination_key_name);
+        let result = self.create_copy(
+            app_name,
+            key_name,
+            destination_key_name,
+            usage_flags,
+            metadata,
+        );
+        #[cfg(feature = "audit-log")]
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record_copy(
+                &audited_key,
+                result
+                    .as_ref()
+                    .err()
+                    .copied()
+                    .unwrap_or(ResponseStatus::Success),
+            );
+        }
+        trace!("copy_key egress");
+
+        result
+    }
+
+    /// Copy a key after the checks of `copy_key`, without recording it in the audit log.
+    fn create_copy(
+        &self,
+        app_name: ApplicationName,
+        key_name: String,
+        destination_key_name: String,
+        usage_flags: UsageFlags,
+        metadata: Option<ConnectionMetadata>,
+    ) -> Result<()> {
+        let key_name = self.check_key_name(&key_name)?;
+        let destination_key_name = self.check_new_key_name(&app_name, &destination_key_name)?;
+        self.key_bindings
+            .check_use(&app_name, self.provider_id, &key_name, metadata)?;
+        self.check_active(&app_name, &key_name)?;
+        self.check_unlocked(&app_name, &key_name, metadata)?;
+        let binding = self.key_bindings.new_binding(
+            &app_name,
+            self.provider_id,
+            &destination_key_name,
+            metadata,
+        )?;
+        let _slot_guard = self.reserve_slot(&app_name, &destination_key_name)?;
+        self.create_key(&app_name, &destination_key_name, || {
+            self.provider.psa_copy_key(
+                app_name.clone(),
+                key_name,
+                destination_key_name.clone(),
+                usage_flags,
+            )
+        })?;
+        if let Some(binding) = binding {
+            self.key_bindings.bind(binding);
+        }
+        #[cfg(feature = "event-hooks")]
+        self.notify_key_event(EventKind::KeyCreated, &app_name, &destination_key_name);
+
+        Ok(())
+    }
+
+    /// Import the public key of a peer, only usable to verify signatures or derive keys, which is
+    /// destroyed once the time to live given has elapsed.
+    ///
+    /// The key creation rules and key slots apply as for a usual import. Peer keys are not bound
+    /// to the client process importing them. The import is recorded in the audit log, and so is
+    /// the destruction of the key once it expired.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInvalidArgument` if the key is not a public key or if its policy allows
+    /// other uses than verification or derivation.
+    pub fn import_peer_key(
+        &self,
+        app_name: Option<ApplicationName>,
+        mut op: psa_import_key::Operation,
+        time_to_live: Duration,
+    ) -> Result<psa_import_key::Result> {
+        trace!("import_peer_key ingress");
+        let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        #[cfg(feature = "audit-log")]
+        let audited_key = self.audited_key_triple(Some(&app_name), &op.key_name);
+        let result = self
+            .check_new_key_name(&app_name, &op.key_name)
+            .and_then(|key_name| {
+                op.key_name = key_name;
+                PeerKeys::check_attributes(&op.attributes)?;
+                self.key_creation_policy
+                    .check(&app_name, self.provider_id, &op.attributes)?;
+                let _slot_guard = match &self.key_slots {
+                    Some(key_slots) => Some(key_slots.reserve_slot(&app_name)?),
+                    None => None,
+                };
+                let key_triple =
+                    KeyTriple::new(app_name.clone(), self.provider_id, op.key_name.clone());
+                let result = self.provider.psa_import_key(app_name, op)?;
+                self.peer_keys.track(key_triple, time_to_live);
+                Ok(result)
+            });
+        #[cfg(feature = "audit-log")]
+        self.audit(&audited_key, Opcode::PsaImportKey, &result);
+        trace!("import_peer_key egress");
+
+        result
+    }
+
+    /// Verify a signature with the public key given by the client instead of a stored key, which
+    /// is only used for this verification.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInvalidSignature` if the signature is not valid, and `PsaErrorNotSupported`
+    /// if the provider can not verify with a public key which is not stored.
+    pub fn verify_hash_with_public_key(
+        &self,
+        app_name: Option<ApplicationName>,
+        attributes: Attributes,
+        public_key: Vec<u8>,
+        alg: AsymmetricSignature,
+        hash: Vec<u8>,
+        signature: Vec<u8>,
+    ) -> Result<psa_verify_hash::Result> {
+        trace!("verify_hash_with_public_key ingress");
+        let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        let result = self.provider.psa_verify_hash_with_public_key(
+            app_name, attributes, public_key, alg, hash, signature,
+        );
+        if let Err(status) = result {
+            ErrorMetadata::new(status, self.provider_id, Opcode::PsaVerifyHash).log();
+        }
+        trace!("verify_hash_with_public_key egress");
+
+        result
+    }
+
+    /// Statistics of the requests executed by the provider over the last minute.
+    pub fn statistics(&self) -> StatisticsSnapshot {
+        self.statistics.snapshot()
+    }
+
+    /// Metrics of the requests executed by the provider since the backend handler was built.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Unlock a key needing a credential at the time it is used, such as a key on a smartcard
+    /// protected by a PIN. The credential is checked by the provider and then cached for the
+    /// client, identified by its connection metadata, until the unlock time to live elapses.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the client can not be identified, as the credential could
+    /// then not be cached for it only, and the error of the provider if the credential is wrong.
+    pub fn unlock_key(
+        &self,
+        app_name: Option<ApplicationName>,
+        key_name: String,
+        credential: Vec<u8>,
+        metadata: Option<ConnectionMetadata>,
+    ) -> Result<()> {
+        trace!("unlock_key ingress");
+        let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        let key_name = self.check_key_name(&key_name)?;
+        self.key_bindings
+            .check_use(&app_name, self.provider_id, &key_name, metadata)?;
+        let client = metadata.ok_or_else(|| {
+            error!("Keys can only be unlocked by clients identified by their connection.");
+            ResponseStatus::PsaErrorNotPermitted
+        })?;
+        let credential = Zeroizing::new(credential);
+        self.provider
+            .unlock_key(app_name.clone(), key_name.clone(), &credential)?;
+        self.key_unlocks.store(
+            KeyTriple::new(app_name, self.provider_id, key_name),
+            client,
+            credential,
+        );
+        trace!("unlock_key egress");
+
+        Ok(())
+    }
+
+    /// Activate a key created pre-active, on behalf of its owner or of an administrator, so that
+    /// it can be used for cryptographic operations.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotSupported` if the provider does not store the state of its keys and
+    /// `PsaErrorNotPermitted` if the application is not allowed to activate the key.
+    pub fn activate_key(
+        &self,
+        app_name: Option<ApplicationName>,
+        is_admin: bool,
+        key_owner: ApplicationName,
+        key_name: String,
+    ) -> Result<()> {
+        trace!("activate_key ingress");
+        let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        let key_name = self.check_key_name(&key_name)?;
+        let key_activation = self
+            .key_activation
+            .as_ref()
+            .ok_or(ResponseStatus::PsaErrorNotSupported)?;
+        key_activation.activate(
+            &app_name,
+            is_admin,
+            &KeyTriple::new(key_owner, self.provider_id, key_name),
+        )?;
+        trace!("activate_key egress");
+
+        Ok(())
+    }
+
+    /// Create the monotonic counter of a key of the application, starting at 0.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotSupported` if the provider does not store its keys locally and
+    /// `PsaErrorAlreadyExists` if the key already has a counter.
+    #[cfg(feature = "key-counters")]
+    pub fn create_counter(
+        &self,
+        app_name: Option<ApplicationName>,
+        key_name: String,
+    ) -> Result<()> {
+        trace!("create_counter ingress");
+        let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        let key_name = self.check_key_name(&key_name)?;
+        let key_counters = self
+            .key_counters
+            .as_ref()
+            .ok_or(ResponseStatus::PsaErrorNotSupported)?;
+        key_counters.create(&KeyTriple::new(app_name, self.provider_id, key_name))?;
+        trace!("create_counter egress");
+
+        Ok(())
+    }
+
+    /// Increment the counter of a key of the application and sign its new value along with the
+    /// data given, returning the value and the signature. The value is persisted before the
+    /// signature is made, and is not used again if the signature fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotSupported` if the provider does not store its keys locally or if the
+    /// algorithm does not hash the data, `PsaErrorDoesNotExist` if the key has no counter and the
+    /// error of the signature otherwise.
+    #[cfg(feature = "key-counters")]
+    pub fn increment_and_sign(
+        &self,
+        app_name: Option<ApplicationName>,
+        key_name: String,
+        alg: AsymmetricSignature,
+        data: &[u8],
+        metadata: Option<ConnectionMetadata>,
+    ) -> Result<(u64, Vec<u8>)> {
+        trace!("increment_and_sign ingress");
+        let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        let key_name = self.check_key_name(&key_name)?;
+        let key_counters = self
+            .key_counters
+            .as_ref()
+            .ok_or(ResponseStatus::PsaErrorNotSupported)?;
+        // Values are not spent on keys which can certainly not sign.
+        self.key_bindings
+            .check_use(&app_name, self.provider_id, &key_name, metadata)?;
+        self.check_active(&app_name, &key_name)?;
+        let _ = key_counters::counter_hash(alg, 0, data)?;
+
+        let counter = key_counters.increment(&KeyTriple::new(
+            app_name.clone(),
+            self.provider_id,
+            key_name.clone(),
+        ))?;
+        let sign = NativeOperation::PsaSignHash(psa_sign_hash::Operation {
+            key_name,
+            alg,
+            hash: key_counters::counter_hash(alg, counter, data)?.into(),
+        });
+        let signature = match self.execute_operation(sign, Some(app_name), metadata)? {
+            NativeResult::PsaSignHash(result) => result.signature.to_vec(),
+            _ => return Err(ResponseStatus::PsaErrorGenericError),
+        };
+        trace!("increment_and_sign egress");
+
+        Ok((counter, signature))
+    }
+
+    /// Change the authentication value the provider presents to its backend, such as the hierarchy
+    /// authentication value of a TPM or the user PIN of a PKCS 11 token. Reserved to the
+    /// administrators, which the caller checks.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotSupported` if the provider has no such authentication value or can not
+    /// change it.
+    pub fn change_backend_auth(
+        &self,
+        app_name: Option<ApplicationName>,
+        auth: Vec<u8>,
+    ) -> Result<()> {
+        trace!("change_backend_auth ingress");
+        let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        self.provider.change_backend_auth(Zeroizing::new(auth))?;
+        if crate::utils::GlobalConfig::log_error_details() {
+            info!(
+                "The authentication value of the backend of the provider was changed by {}.",
+                app_name
+            );
+        } else {
+            info!("The authentication value of the backend of the provider was changed.");
+        }
+        trace!("change_backend_auth egress");
+
+        Ok(())
+    }
+
+    /// Gather the measured boot evidence of the platform, with a quote of the provider over the
+    /// nonce given by a remote verifier.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInvalidArgument` if the nonce is empty or too long and
+    /// `PsaErrorNotSupported` if the provider can not quote the state of the platform.
+    pub fn platform_evidence(
+        &self,
+        app_name: Option<ApplicationName>,
+        nonce: Vec<u8>,
+    ) -> Result<PlatformEvidence> {
+        trace!("platform_evidence ingress");
+        let _ = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        platform_evidence::check_nonce(&nonce)?;
+        // The logs are read after quoting so that they contain all the events covered by the
+        // quote: verifiers replay them until the PCRs quoted are found, events appended meanwhile
+        // being ignored.
+        let quote = self.provider.quote(nonce)?;
+        let tpm_event_log = platform_evidence::read_log(platform_evidence::TPM_EVENT_LOG_PATH)?;
+        let ima_log = platform_evidence::read_log(platform_evidence::IMA_LOG_PATH)?;
+        trace!("platform_evidence egress");
+
+        Ok(PlatformEvidence {
+            tpm_event_log,
+            ima_log,
+            quote,
+        })
+    }
+
+    /// Get the name of the key encryption key of the application, creating it if it does not exist
+    /// yet. The key must only be used for the wrap and unwrap operations of the service.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotSupported` if the KEKs are not stored in this provider.
+    pub fn app_kek(&self, app_name: &ApplicationName) -> Result<&'static str> {
+        match &self.app_keks {
+            Some(app_keks) => app_keks.get_or_create(&*self.provider, app_name),
+            None => Err(ResponseStatus::PsaErrorNotSupported),
+        }
+    }
+
+    /// Wrap key material with the key encryption key of the application, bound to the label
+    /// given, see the `app_keks` module.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotSupported` if the KEKs are not stored in this provider.
+    pub fn wrap_key(
+        &self,
+        app_name: Option<ApplicationName>,
+        key_material: Zeroizing<Vec<u8>>,
+        label: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        trace!("wrap_key ingress");
+        let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        let kek_name = self.app_kek(&app_name)?;
+        let result = app_keks::wrap(&*self.provider, &app_name, kek_name, key_material, label);
+        trace!("wrap_key egress");
+
+        result
+    }
+
+    /// Unwrap key material wrapped with the key encryption key of the application, with the same
+    /// label.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotSupported` if the KEKs are not stored in this provider, and
+    /// `PsaErrorInvalidSignature` if the wrapped key was altered or the label does not match.
+    pub fn unwrap_key(
+        &self,
+        app_name: Option<ApplicationName>,
+        wrapped: Vec<u8>,
+        label: Vec<u8>,
+    ) -> Result<Zeroizing<Vec<u8>>> {
+        trace!("unwrap_key ingress");
+        let app_name = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        let kek_name = self.app_kek(&app_name)?;
+        let result = app_keks::unwrap(&*self.provider, &app_name, kek_name, wrapped, label);
+        trace!("unwrap_key egress");
+
+        result
+    }
+
+    /// Store a certificate alongside a key of the application, in the provider or, if it can not
+    /// store certificates, with the information of the key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotSupported` if neither the provider nor the Key Info Manager can store
+    /// certificates.
+    pub fn store_certificate(
+        &self,
+        app_name: &ApplicationName,
+        key_name: &str,
+        certificate: Vec<u8>,
+    ) -> Result<()> {
+        trace!("store_certificate ingress");
+        let key_name = self.check_key_name(key_name)?;
+        let result = match (
+            self.provider.store_certificate(
+                app_name.clone(),
+                key_name.clone(),
+                certificate.clone(),
+            ),
+            &self.key_certificates,
+        ) {
+            (Err(ResponseStatus::PsaErrorNotSupported), Some(key_certificates)) => {
+                key_certificates.store(&self.key_triple(app_name, key_name), certificate)
+            }
+            (result, _) => result,
+        };
+        trace!("store_certificate egress");
+
+        result
+    }
+
+    /// Get the certificate stored alongside a key of the application.
+    pub fn get_certificate(&self, app_name: &ApplicationName, key_name: &str) -> Result<Vec<u8>> {
+        trace!("get_certificate ingress");
+        let key_name = self.check_key_name(key_name)?;
+        let result = match (
+            self.provider
+                .get_certificate(app_name.clone(), key_name.clone()),
+            &self.key_certificates,
+        ) {
+            (Err(ResponseStatus::PsaErrorNotSupported), Some(key_certificates)) => {
+                key_certificates.get(&self.key_triple(app_name, key_name))
+            }
+            (result, _) => result,
+        };
+        trace!("get_certificate egress");
+
+        result
+    }
+
+    /// Delete the certificate stored alongside a key of the application.
+    pub fn delete_certificate(&self, app_name: &ApplicationName, key_name: &str) -> Result<()> {
+        trace!("delete_certificate ingress");
+        let key_name = self.check_key_name(key_name)?;
+        let result = match (
+            self.provider
+                .delete_certificate(app_name.clone(), key_name.clone()),
+            &self.key_certificates,
+        ) {
+            (Err(ResponseStatus::PsaErrorNotSupported), Some(key_certificates)) => {
+                key_certificates.delete(&self.key_triple(app_name, key_name))
+            }
+            (result, _) => result,
+        };
+        trace!("delete_certificate egress");
+
+        result
+    }
+
+    /// Check that the labels given can be set on the keys of the application.
+    pub fn check_key_labels(&self, labels: &BTreeMap<String, String>) -> Result<()> {
+        match &self.key_labels {
+            Some(_) => key_labels::check(labels),
+            None => Err(ResponseStatus::PsaErrorNotSupported),
+        }
+    }
+
+    /// Set the labels of a key of the application, replacing its previous ones.
+    pub fn set_key_labels(
+        &self,
+        app_name: &ApplicationName,
+        key_name: &str,
+        labels: BTreeMap<String, String>,
+    ) -> Result<()> {
+        trace!("set_key_labels ingress");
+        let key_name = self.check_key_name(key_name)?;
+        let result = match &self.key_labels {
+            Some(key_labels) => key_labels.set(&self.key_triple(app_name, key_name), labels),
+            None => Err(ResponseStatus::PsaErrorNotSupported),
+        };
+        trace!("set_key_labels egress");
+
+        result
+    }
+
+    /// List the names of the keys of the application having all the labels given, with their
+    /// labels.
+    pub fn list_labeled_keys(
+        &self,
+        app_name: &ApplicationName,
+        labels: &BTreeMap<String, String>,
+    ) -> Result<Vec<(String, BTreeMap<String, String>)>> {
+        trace!("list_labeled_keys ingress");
+        let result = match &self.key_labels {
+            Some(key_labels) => key_labels.list(app_name, self.provider_id, labels),
+            None => Err(ResponseStatus::PsaErrorNotSupported),
+        };
+        trace!("list_labeled_keys egress");
+
+        result
+    }
+
+    fn key_triple(&self, app_name: &ApplicationName, key_name: String) -> KeyTriple {
+        KeyTriple::new(app_name.clone(), self.provider_id, key_name)
+    }
+
+    /// Get the body type of the requests accepted by the provider.
+    pub fn content_type(&self) -> BodyType {
+        self.content_type
+    }
+
+    /// Get the body type of the responses returned by the provider.
+    pub fn accept_type(&self) -> BodyType {
+        self.accept_type
+    }
+
+    /// Get the memory used by the requests and the caches of the provider.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.memory_limits.usage(self.provider.cache_usage())
+    }
+
+    /// Get the result of the reconciliation of the mappings of the provider with its keys, done
+    /// when it started, if it checks them.
+    pub fn mapping_health(&self) -> Option<MappingHealth> {
+        self.provider.mapping_health()
+    }
+
+    /// Check whether the capabilities of the provider may have changed since they were discovered.
+    pub fn capabilities_stale(&self) -> bool {
+        self.provider.capabilities_stale()
+    }
+
+    /// Discover the capabilities of the provider again, returning its new description if they
+    /// can change.
+    pub fn refresh_capabilities(&self) -> Result<Option<(ProviderInfo, HashSet<Opcode>)>> {
+        self.provider.refresh_capabilities()
+    }
+
+    /// Replace the description of a provider served by the core provider.
+    pub fn update_provider_details(&self, provider_info: ProviderInfo, opcodes: HashSet<Opcode>) {
+        self.provider
+            .update_provider_details(provider_info, opcodes)
+    }
+
+    /// Get all the keys stored locally with their information, only given by the core provider.
+    pub fn key_inventory(&self) -> Result<Vec<(KeyTriple, KeyInfo)>> {
+        self.provider.key_inventory()
+    }
+
+    /// Check that the provider still holds the key of a mapping, if it can tell.
+    pub fn key_exists(&self, key_triple: &KeyTriple) -> Result<bool> {
+        self.provider.key_exists(key_triple)
+    }
+
+    /// Compact all the Key Info Managers, only done by the core provider.
+    pub fn compact_key_stores(&self) -> Result<Compaction> {
+        self.provider.compact_key_stores()
+    }
+
+    /// Copy the files of the keys of the provider with the mappings of its Key Info Manager, if
+    /// it keeps its keys in files.
+    pub fn snapshot_storage(&self) -> Result<StorageSnapshot> {
+        self.provider.snapshot_storage()
+    }
+
+    /// Get the store of the key operations failing because the provider is unavailable, if
+    /// configured.
+    #[cfg(feature = "dead-letters")]
+    pub fn dead_letters(&self) -> Option<&DeadLetters> {
+        self.dead_letters.as_deref()
+    }
+
+    /// Get the usage of the key slots of the provider, if they are accounted for.
+    pub fn key_slots_usage(&self) -> Option<Result<KeySlotsUsage>> {
+        self.key_slots.as_ref().map(KeySlots::usage)
+    }
+
+    /// Destroy the peer keys whose time to live has elapsed, recording their destruction in the
+    /// audit log.
+    pub fn reap_expired_peer_keys(&self) {
+        for key_triple in self.peer_keys.take_expired() {
+            let op = psa_destroy_key::Operation {
+                key_name: key_triple.key_name().to_string(),
+            };
+            let result = self
+                .provider
+                .psa_destroy_key(key_triple.app_name().clone(), op);
+            self.audit(&key_triple, Opcode::PsaDestroyKey, &result);
+            match result {
+                Ok(_) => {
+                    if crate::utils::GlobalConfig::log_error_details() {
+                        info!("Destroyed expired peer key ({}).", key_triple);
+                    } else {
+                        info!("Destroyed an expired peer key.");
+                    }
+                }
+                // The key might have been destroyed by its owner.
+                Err(ResponseStatus::PsaErrorDoesNotExist) => (),
+                Err(status) => {
+                    ErrorMetadata::new(status, self.provider_id, Opcode::PsaDestroyKey).log();
+                }
             }
         }
     }
@@ -174,6 +1763,33 @@ pub struct BackEndHandlerBuilder {
     provider_id: Option<ProviderID>,
     content_type: Option<BodyType>,
     accept_type: Option<BodyType>,
+    key_bindings: Option<Arc<KeyBindings>>,
+    key_creation_policy: Option<Arc<KeyCreationPolicy>>,
+    key_naming_policy: Option<Arc<KeyNamingPolicy>>,
+    name_policy: Option<NamePolicy>,
+    key_slots: Option<KeySlots>,
+    key_activation: Option<KeyActivation>,
+    #[cfg(feature = "key-counters")]
+    key_counters: Option<KeyCounters>,
+    key_certificates: Option<KeyCertificates>,
+    key_labels: Option<KeyLabels>,
+    unlock_time_to_live: Option<Duration>,
+    app_keks: Option<AppKeks>,
+    #[cfg(feature = "signing-log")]
+    signing_log: Option<Arc<SigningLog>>,
+    #[cfg(feature = "event-hooks")]
+    event_hooks: Option<EventHooks>,
+    #[cfg(feature = "event-hooks")]
+    key_publisher: Option<KeyPublisher>,
+    canary_keys: Option<CanaryKeys>,
+    #[cfg(feature = "dead-letters")]
+    dead_letters: Option<Arc<DeadLetters>>,
+    #[cfg(feature = "audit-log")]
+    audit_log: Option<Arc<AuditLog>>,
+    memory_limits: Option<MemoryLimits>,
+    fault_injection: Option<FaultInjection>,
+    max_concurrency: Option<usize>,
+    deadlines: Option<OperationDeadlines>,
 }
 
 impl BackEndHandlerBuilder {
@@ -184,6 +1800,33 @@ impl BackEndHandlerBuilder {
             provider_id: None,
             content_type: None,
             accept_type: None,
+            key_bindings: None,
+            key_creation_policy: None,
+            key_naming_policy: None,
+            name_policy: None,
+            key_slots: None,
+            key_activation: None,
+            #[cfg(feature = "key-counters")]
+            key_counters: None,
+            key_certificates: None,
+            key_labels: None,
+            unlock_time_to_live: None,
+            app_keks: None,
+            #[cfg(feature = "signing-log")]
+            signing_log: None,
+            #[cfg(feature = "event-hooks")]
+            event_hooks: None,
+            #[cfg(feature = "event-hooks")]
+            key_publisher: None,
+            canary_keys: None,
+            #[cfg(feature = "dead-letters")]
+            dead_letters: None,
+            #[cfg(feature = "audit-log")]
+            audit_log: None,
+            memory_limits: None,
+            fault_injection: None,
+            max_concurrency: None,
+            deadlines: None,
         }
     }
 
@@ -212,23 +1855,206 @@ impl BackEndHandlerBuilder {
         self
     }
 
+    /// Sets the record of key bindings to client processes. If not set, no key is bound.
+    pub fn with_key_bindings(mut self, key_bindings: Arc<KeyBindings>) -> Self {
+        self.key_bindings = Some(key_bindings);
+        self
+    }
+
+    /// Sets the policy restricting the keys applications can create. If not set, all keys can be
+    /// created.
+    pub fn with_key_creation_policy(mut self, key_creation_policy: Arc<KeyCreationPolicy>) -> Self {
+        self.key_creation_policy = Some(key_creation_policy);
+        self
+    }
+
+    /// Sets the rules the names of the new keys of the applications must follow. If not set, keys
+    /// can be created with any valid name.
+    pub fn with_key_naming_policy(mut self, key_naming_policy: Arc<KeyNamingPolicy>) -> Self {
+        self.key_naming_policy = Some(key_naming_policy);
+        self
+    }
+
+    /// Sets the rules the names of the keys and applications must follow. If not set, the names are
+    /// normalized with the default length limits.
+    pub fn with_name_policy(mut self, name_policy: NamePolicy) -> Self {
+        self.name_policy = Some(name_policy);
+        self
+    }
+
+    /// Sets the accounting of the key slots of the provider. If not set, the number of keys is not
+    /// limited.
+    pub fn with_key_slots(mut self, key_slots: KeySlots) -> Self {
+        self.key_slots = Some(key_slots);
+        self
+    }
+
+    /// Sets the states of the keys of the provider. If not set, all keys are active.
+    pub fn with_key_activation(mut self, key_activation: KeyActivation) -> Self {
+        self.key_activation = Some(key_activation);
+        self
+    }
+
+    pub fn with_unlock_time_to_live(mut self, unlock_time_to_live: Duration) -> Self {
+        self.unlock_time_to_live = Some(unlock_time_to_live);
+        self
+    }
+
+    /// Stores the key encryption keys of the applications in the provider.
+    pub fn with_app_keks(mut self) -> Self {
+        self.app_keks = Some(Default::default());
+        self
+    }
+
+    /// Binds monotonic counters to the keys created with one, incremented by each signature of
+    /// `increment_and_sign`.
+    #[cfg(feature = "key-counters")]
+    pub fn with_key_counters(mut self, key_counters: KeyCounters) -> Self {
+        self.key_counters = Some(key_counters);
+        self
+    }
+
+    /// Stores the certificates of the keys with their information when the provider can not store
+    /// them itself.
+    pub fn with_key_certificates(mut self, key_certificates: KeyCertificates) -> Self {
+        self.key_certificates = Some(key_certificates);
+        self
+    }
+
+    /// Stores the labels of the keys with their information.
+    pub fn with_key_labels(mut self, key_labels: KeyLabels) -> Self {
+        self.key_labels = Some(key_labels);
+        self
+    }
+
+    #[cfg(feature = "signing-log")]
+    pub fn with_signing_log(mut self, signing_log: Arc<SigningLog>) -> Self {
+        self.signing_log = Some(signing_log);
+        self
+    }
+
+    #[cfg(feature = "event-hooks")]
+    pub fn with_event_hooks(mut self, event_hooks: EventHooks) -> Self {
+        self.event_hooks = Some(event_hooks);
+        self
+    }
+
+    /// Sets the publishers of the public keys created with the provider.
+    #[cfg(feature = "event-hooks")]
+    pub fn with_key_publisher(mut self, key_publisher: KeyPublisher) -> Self {
+        self.key_publisher = Some(key_publisher);
+        self
+    }
+
+    /// Sets the canary keys of the provider, which are created when the handler is built.
+    pub fn with_canary_keys(mut self, canary_keys: CanaryKeys) -> Self {
+        self.canary_keys = Some(canary_keys);
+        self
+    }
+
+    /// Sets the store of the key operations failing because the provider is unavailable.
+    #[cfg(feature = "dead-letters")]
+    pub fn with_dead_letters(mut self, dead_letters: Arc<DeadLetters>) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// Sets the audit log recording the key creations, destructions, signatures and exports.
+    #[cfg(feature = "audit-log")]
+    pub fn with_audit_log(mut self, audit_log: Arc<AuditLog>) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Sets the deadlines of the operations of the provider. If not set, they have none.
+    pub fn with_operation_deadlines(mut self, deadlines: OperationDeadlines) -> Self {
+        self.deadlines = Some(deadlines);
+        self
+    }
+
+    /// Sets the limits on the memory used by the requests of the provider. If not set, it is not
+    /// limited.
+    pub fn with_memory_limits(mut self, memory_limits: MemoryLimits) -> Self {
+        self.memory_limits = Some(memory_limits);
+        self
+    }
+
+    /// Sets the faults injected in the operations before they reach the provider, for soak
+    /// testing. If not set, no fault is injected.
+    pub fn with_fault_injection(mut self, fault_injection: FaultInjection) -> Self {
+        self.fault_injection = Some(fault_injection);
+        self
+    }
+
+    /// Sets the number of operations the provider executes concurrently, the provider keeping its
+    /// own limit if it is lower. If neither is set, the operations are not limited.
+    pub fn with_max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = Some(max_concurrency);
+        self
+    }
+
     pub fn build(self) -> std::io::Result<BackEndHandler> {
+        let provider = self
+            .provider
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "provider is missing"))?;
+        let canary_keys = self.canary_keys.unwrap_or_default();
+        canary_keys
+            .create(&*provider)
+            .map_err(|_| Error::new(ErrorKind::Other, "canary key creation failed"))?;
+        let provider_id = self
+            .provider_id
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "provider_id is missing"))?;
+        let concurrency_limit = ConcurrencyLimit::new(
+            provider_id,
+            self.max_concurrency,
+            provider.max_concurrency(),
+        )?;
+
         Ok(BackEndHandler {
-            provider: self
-                .provider
-                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "provider is missing"))?,
+            provider,
             converter: self
                 .converter
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "converter is missing"))?,
-            provider_id: self
-                .provider_id
-                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "provider_id is missing"))?,
+            provider_id,
             content_type: self
                 .content_type
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "content_type is missing"))?,
             accept_type: self
                 .accept_type
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "accept_type is missing"))?,
+            key_bindings: self.key_bindings.unwrap_or_default(),
+            key_creation_policy: self.key_creation_policy.unwrap_or_default(),
+            key_naming_policy: self.key_naming_policy.unwrap_or_default(),
+            name_policy: self.name_policy.unwrap_or_default(),
+            key_slots: self.key_slots,
+            key_activation: self.key_activation,
+            #[cfg(feature = "key-counters")]
+            key_counters: self.key_counters,
+            key_certificates: self.key_certificates,
+            key_labels: self.key_labels,
+            peer_keys: Default::default(),
+            key_unlocks: self
+                .unlock_time_to_live
+                .map(KeyUnlocks::new)
+                .unwrap_or_default(),
+            app_keks: self.app_keks,
+            statistics: Default::default(),
+            metrics: Default::default(),
+            #[cfg(feature = "signing-log")]
+            signing_log: self.signing_log,
+            #[cfg(feature = "event-hooks")]
+            event_hooks: self.event_hooks.unwrap_or_default(),
+            #[cfg(feature = "event-hooks")]
+            key_publisher: self.key_publisher.unwrap_or_default(),
+            canary_keys,
+            #[cfg(feature = "dead-letters")]
+            dead_letters: self.dead_letters,
+            #[cfg(feature = "audit-log")]
+            audit_log: self.audit_log,
+            memory_limits: self.memory_limits.unwrap_or_default(),
+            fault_injection: self.fault_injection,
+            concurrency_limit,
+            deadlines: self.deadlines.unwrap_or_default(),
         })
     }
 }
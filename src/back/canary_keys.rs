@@ -0,0 +1,135 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Canary keys, which no legitimate client ever uses
+//!
+//! Canary keys are created in their provider when the service starts, under application and key
+//! names chosen to look attractive to an intruder. As no application uses them, any operation on
+//! one of them, made with stolen credentials of its application, is a cheap sign of intrusion: it
+//! is logged as an error and notified to the `CanaryKeyUsed` event hooks. The operation is still
+//! executed as usual so that the intruder is not told that the key is a canary.
+use crate::authenticators::ApplicationName;
+use crate::providers::Provide;
+use log::{error, info};
+use parsec_interface::operations::psa_algorithm::{Algorithm, AsymmetricSignature, Hash, SignHash};
+use parsec_interface::operations::psa_generate_key;
+use parsec_interface::operations::psa_key_attributes::{
+    Attributes, Lifetime, Policy, Type, UsageFlags,
+};
+use parsec_interface::requests::{ResponseStatus, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+
+/// Configuration of a canary key
+#[derive(Clone, Deserialize, Debug)]
+pub struct CanaryKeyConfig {
+    /// Type of the provider in which the key is created
+    pub provider_type: String,
+    /// Application owning the key
+    pub app_name: String,
+    /// Name of the key
+    pub key_name: String,
+}
+
+/// Canary keys of one provider
+#[derive(Debug, Default)]
+pub struct CanaryKeys {
+    // Application and key names of the canary keys.
+    keys: HashSet<(String, String)>,
+}
+
+impl CanaryKeys {
+    /// Creates the set of canary keys with the given application and key names.
+    pub fn new(keys: HashSet<(String, String)>) -> CanaryKeys {
+        CanaryKeys { keys }
+    }
+
+    /// Attributes of the canary keys: 2048 bits RSA signing keys, which are supported by all the
+    /// providers.
+    pub fn attributes() -> Attributes {
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type: Type::RsaKeyPair,
+            bits: 2048,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    export: false,
+                    copy: false,
+                    cache: false,
+                    encrypt: false,
+                    decrypt: false,
+                    sign_message: true,
+                    verify_message: true,
+                    sign_hash: true,
+                    verify_hash: true,
+                    derive: false,
+                },
+                permitted_algorithms: Algorithm::AsymmetricSignature(
+                    AsymmetricSignature::RsaPkcs1v15Sign {
+                        hash_alg: SignHash::Specific(Hash::Sha256),
+                    },
+                ),
+            },
+        }
+    }
+
+    /// Creates the canary keys which do not exist yet in the provider.
+    pub fn create(&self, provider: &dyn Provide) -> Result<()> {
+        for (app_name, key_name) in &self.keys {
+            let op = psa_generate_key::Operation {
+                key_name: key_name.clone(),
+                attributes: CanaryKeys::attributes(),
+            };
+            match provider.psa_generate_key(ApplicationName::new(app_name.clone()), op) {
+                Ok(_) => info!("Created a canary key."),
+                // Created by a previous run of the service.
+                Err(ResponseStatus::PsaErrorAlreadyExists) => (),
+                Err(status) => {
+                    format_error!("Failed to create a canary key", status);
+                    return Err(status);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if the key is a canary key.
+    pub fn is_canary(&self, app_name: &ApplicationName, key_name: &str) -> bool {
+        // Avoids allocating for the common case of a provider without canary keys.
+        !self.keys.is_empty()
+            && self
+                .keys
+                .contains(&(app_name.get_name().to_string(), key_name.to_string()))
+    }
+
+    /// Logs the use of a canary key.
+    pub fn log_use(&self, app_name: &ApplicationName, key_name: &str) {
+        if crate::utils::GlobalConfig::log_error_details() {
+            error!(
+                "Canary key \"{}\" of application \"{}\" used, the credentials of the application might be compromised.",
+                key_name, app_name
+            );
+        } else {
+            error!("Canary key used, the credentials of an application might be compromised.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CanaryKeys;
+    use crate::authenticators::ApplicationName;
+
+    #[test]
+    fn canaries_recognized() {
+        let canary_keys = CanaryKeys::new(
+            vec![(String::from("backup"), String::from("root-ca"))]
+                .into_iter()
+                .collect(),
+        );
+        assert!(canary_keys.is_canary(&ApplicationName::new(String::from("backup")), "root-ca"));
+        assert!(!canary_keys.is_canary(&ApplicationName::new(String::from("other")), "root-ca"));
+        assert!(!CanaryKeys::default()
+            .is_canary(&ApplicationName::new(String::from("backup")), "root-ca"));
+    }
+}
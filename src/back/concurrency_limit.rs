@@ -0,0 +1,192 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Limit of the operations executed concurrently by a provider
+//!
+//! All the worker threads of the thread pool can execute requests for the same provider at once,
+//! while some providers can only take a few operations at a time: Mbed Crypto has a fixed number
+//! of key slots that concurrent operations must not overflow, and hardware tokens serialise or
+//! refuse the sessions above their own limit. Providers declare the number of operations they can
+//! execute concurrently and the configuration of the provider can lower it. Once the limit is
+//! reached, the next operations of the provider wait for the running ones to finish, holding their
+//! worker thread, instead of failing, until their deadline expires if they have one.
+use crate::providers::deadline;
+use log::{error, warn};
+use parsec_interface::requests::{self, ProviderID};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+// Longest wait between two checks of the cancellation of an operation waiting for the limit.
+const CANCELLATION_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+/// Limit of the operations executed concurrently by a provider
+#[derive(Debug)]
+pub struct ConcurrencyLimit {
+    max_concurrency: usize,
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+/// Operation counted in the concurrency limit until it is dropped
+#[derive(Debug)]
+pub struct ConcurrencyGuard<'a> {
+    limit: &'a ConcurrencyLimit,
+}
+
+impl Drop for ConcurrencyGuard<'_> {
+    fn drop(&mut self) {
+        *self
+            .limit
+            .running
+            .lock()
+            .expect("Concurrency limit lock poisoned") -= 1;
+        self.limit.finished.notify_one();
+    }
+}
+
+impl ConcurrencyLimit {
+    /// Creates the limit of the provider from the one configured and the one the provider
+    /// declares, the lowest of the two being used. Returns `None` if neither is set.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the configured limit is 0.
+    pub fn new(
+        provider_id: ProviderID,
+        configured: Option<usize>,
+        declared: Option<usize>,
+    ) -> Result<Option<ConcurrencyLimit>> {
+        let max_concurrency = match (configured, declared) {
+            (Some(0), _) => {
+                error!(
+                    "The concurrency limit of the {} provider must allow at least one operation.",
+                    provider_id
+                );
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid concurrency limit",
+                ));
+            }
+            (Some(configured), Some(declared)) if configured > declared => {
+                warn!(
+                    "The {} provider can only execute {} operations concurrently, ignoring the configured limit of {}.",
+                    provider_id, declared, configured
+                );
+                declared
+            }
+            (Some(configured), _) => configured,
+            (None, Some(declared)) => declared,
+            (None, None) => return Ok(None),
+        };
+
+        Ok(Some(ConcurrencyLimit {
+            max_concurrency,
+            running: Mutex::new(0),
+            finished: Condvar::new(),
+        }))
+    }
+
+    /// Gets the number of operations the provider executes concurrently.
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    /// Waits for the provider to be able to execute one more operation, which it can until the
+    /// returned guard is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorCommunicationFailure` if the deadline of the operation expired or if it was
+    /// cancelled while waiting.
+    pub fn acquire(&self) -> requests::Result<ConcurrencyGuard<'_>> {
+        let deadline = deadline::current();
+        let mut running = self
+            .running
+            .lock()
+            .expect("Concurrency limit lock poisoned");
+        while *running >= self.max_concurrency {
+            deadline.check()?;
+            let wait = deadline
+                .remaining()
+                .map_or(CANCELLATION_CHECK_PERIOD, |remaining| {
+                    remaining.min(CANCELLATION_CHECK_PERIOD)
+                });
+            running = self
+                .finished
+                .wait_timeout(running, wait)
+                .expect("Concurrency limit lock poisoned")
+                .0;
+        }
+        *running += 1;
+
+        Ok(ConcurrencyGuard { limit: self })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConcurrencyLimit;
+    use crate::providers::deadline::{self, Deadline};
+    use parsec_interface::requests::{ProviderID, ResponseStatus};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn lowest_limit_used() {
+        let limit = |configured, declared| {
+            ConcurrencyLimit::new(ProviderID::MbedCrypto, configured, declared)
+                .unwrap()
+                .map(|limit| limit.max_concurrency())
+        };
+        assert_eq!(limit(None, None), None);
+        assert_eq!(limit(Some(4), None), Some(4));
+        assert_eq!(limit(None, Some(32)), Some(32));
+        assert_eq!(limit(Some(4), Some(32)), Some(4));
+        assert_eq!(limit(Some(64), Some(32)), Some(32));
+        assert!(ConcurrencyLimit::new(ProviderID::MbedCrypto, Some(0), None).is_err());
+    }
+
+    #[test]
+    fn operations_queued() {
+        let limit = Arc::new(
+            ConcurrencyLimit::new(ProviderID::Pkcs11, Some(2), None)
+                .unwrap()
+                .unwrap(),
+        );
+        let running = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..6)
+            .map(|_| {
+                let limit = limit.clone();
+                let running = running.clone();
+                thread::spawn(move || {
+                    let _guard = limit.acquire().unwrap();
+                    assert!(running.fetch_add(1, Ordering::SeqCst) < 2);
+                    thread::sleep(Duration::from_millis(10));
+                    let _ = running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn wait_given_up_at_deadline() {
+        let limit = ConcurrencyLimit::new(ProviderID::Pkcs11, Some(1), None)
+            .unwrap()
+            .unwrap();
+        let _running = limit.acquire().unwrap();
+        let _deadline = deadline::enter(Deadline::new(
+            Some(Duration::from_millis(10)),
+            Arc::new(AtomicBool::new(false)),
+        ));
+        assert_eq!(
+            limit.acquire().err(),
+            Some(ResponseStatus::PsaErrorCommunicationFailure)
+        );
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Store of the key operations which failed because their backend was unavailable
+//!
+//! A key creation or destruction failing with a communication or hardware failure might have been
+//! partly executed, and the client only knows that it failed. These operations are recorded, as a
+//! JSON object per line in a dedicated file, with the time, the provider, the application, the key
+//! and, for key creations, the attributes. Key material is never recorded: imports can not be
+//! retried and are only kept for the operator to know about them.
+//!
+//! Once the backend is available again, the operator starts the service with the
+//! `--retry-failed-operations` flag to execute the recorded operations again on behalf of their
+//! application. A key creation whose key already exists, or a destruction whose key does not exist
+//! anymore, was completed before the failure and is considered resolved. The operations still
+//! failing are kept for the next retry.
+use super::dispatcher::Dispatcher;
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::KeyTriple;
+use log::{error, info, warn};
+use parsec_interface::operations::psa_key_attributes::Attributes;
+use parsec_interface::operations::{psa_destroy_key, psa_generate_key, NativeOperation};
+use parsec_interface::requests::{ProviderID, ResponseStatus};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Configuration of the failed operations store
+#[derive(Clone, Deserialize, Debug)]
+pub struct DeadLettersConfig {
+    /// Path of the file the operations are recorded in
+    pub path: String,
+}
+
+/// Operation which failed
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub enum FailedOperation {
+    GenerateKey { attributes: Attributes },
+    ImportKey { attributes: Attributes },
+    DestroyKey,
+}
+
+/// Record of an operation which failed because its backend was unavailable
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct DeadLetter {
+    pub timestamp: u64,
+    pub provider: u8,
+    pub app_name: String,
+    pub key_name: String,
+    pub operation: FailedOperation,
+    pub status: String,
+}
+
+impl DeadLetter {
+    /// Creates the record of the operation if it is a key creation or destruction, its status
+    /// being set once it failed.
+    pub fn from_operation(
+        operation: &NativeOperation,
+        key_triple: &KeyTriple,
+    ) -> Option<DeadLetter> {
+        let operation = match operation {
+            NativeOperation::PsaGenerateKey(op) => FailedOperation::GenerateKey {
+                attributes: op.attributes,
+            },
+            NativeOperation::PsaImportKey(op) => FailedOperation::ImportKey {
+                attributes: op.attributes,
+            },
+            NativeOperation::PsaDestroyKey(_) => FailedOperation::DestroyKey,
+            _ => return None,
+        };
+
+        Some(DeadLetter {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_secs())
+                .unwrap_or(0),
+            provider: key_triple.provider_id() as u8,
+            app_name: key_triple.app_name().to_string(),
+            key_name: key_triple.key_name().to_string(),
+            operation,
+            status: String::new(),
+        })
+    }
+}
+
+/// Returns true if the status means that the backend of the provider was unavailable.
+pub fn is_unavailability(status: ResponseStatus) -> bool {
+    status == ResponseStatus::PsaErrorCommunicationFailure
+        || status == ResponseStatus::PsaErrorHardwareFailure
+}
+
+/// Outcome of a retry of the failed operations
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RetryOutcome {
+    /// Number of operations which are now completed, or which can not be retried
+    pub resolved: usize,
+    /// Number of operations still failing, kept for the next retry
+    pub remaining: usize,
+}
+
+/// Store of the failed operations
+#[derive(Debug)]
+pub struct DeadLetters {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+fn open_for_appending(path: &Path) -> Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl DeadLetters {
+    /// Opens the store file for appending, creating it if needed.
+    pub fn new(config: &DeadLettersConfig) -> Result<DeadLetters> {
+        let path = PathBuf::from(&config.path);
+        let file = open_for_appending(&path).map_err(|e| {
+            format_error!("Failed to open the failed operations store", e);
+            e
+        })?;
+
+        Ok(DeadLetters {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Records a failed operation. A failure to record it is only logged, as the operation
+    /// already failed.
+    pub fn record(&self, dead_letter: &DeadLetter) {
+        let mut line = match serde_json::to_string(dead_letter) {
+            Ok(line) => line,
+            Err(e) => {
+                format_error!("Failed to serialize a failed operation", e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        let mut file = self.file.lock().expect("Dead letters lock poisoned");
+        if let Err(e) = file
+            .write_all(line.as_bytes())
+            .and_then(|_| file.sync_data())
+        {
+            format_error!("Failed to record a failed operation", e);
+        }
+    }
+
+    /// Reads the failed operations recorded.
+    pub fn read(&self) -> Result<Vec<DeadLetter>> {
+        let _file = self.file.lock().expect("Dead letters lock poisoned");
+        read_dead_letters(&self.path)
+    }
+
+    /// Executes the recorded operations again, keeping only the ones still failing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store can not be read or rewritten.
+    pub fn retry(&self, dispatcher: &Dispatcher) -> Result<RetryOutcome> {
+        let mut file = self.file.lock().expect("Dead letters lock poisoned");
+        let dead_letters = read_dead_letters(&self.path)?;
+        let mut remaining = Vec::new();
+        let mut resolved = 0;
+        for dead_letter in dead_letters {
+            if retry_dead_letter(&dead_letter, dispatcher) {
+                resolved += 1;
+            } else {
+                remaining.push(dead_letter);
+            }
+        }
+
+        let mut contents = String::new();
+        for dead_letter in &remaining {
+            contents.push_str(&serde_json::to_string(dead_letter)?);
+            contents.push('\n');
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, &self.path)?;
+        *file = open_for_appending(&self.path)?;
+
+        Ok(RetryOutcome {
+            resolved,
+            remaining: remaining.len(),
+        })
+    }
+}
+
+fn read_dead_letters(path: &Path) -> Result<Vec<DeadLetter>> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(|line| {
+            serde_json::from_str(line)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid failed operation"))
+        })
+        .collect()
+}
+
+// Executes a failed operation again, returning true if it is resolved.
+fn retry_dead_letter(dead_letter: &DeadLetter, dispatcher: &Dispatcher) -> bool {
+    let backend = match ProviderID::try_from(dead_letter.provider)
+        .ok()
+        .and_then(|provider_id| dispatcher.backend(provider_id))
+    {
+        Some(backend) => backend,
+        None => {
+            warn!("The provider of a failed operation is not available, keeping it.");
+            return false;
+        }
+    };
+    let app_name = ApplicationName::new(dead_letter.app_name.clone());
+    let key_name = dead_letter.key_name.clone();
+    let (operation, completed_status) = match dead_letter.operation {
+        FailedOperation::GenerateKey { attributes } => (
+            NativeOperation::PsaGenerateKey(psa_generate_key::Operation {
+                key_name,
+                attributes,
+            }),
+            ResponseStatus::PsaErrorAlreadyExists,
+        ),
+        FailedOperation::DestroyKey => (
+            NativeOperation::PsaDestroyKey(psa_destroy_key::Operation { key_name }),
+            ResponseStatus::PsaErrorDoesNotExist,
+        ),
+        FailedOperation::ImportKey { .. } => {
+            warn!(
+                "A failed key import can not be retried, the client has to import the key again."
+            );
+            return true;
+        }
+    };
+
+    match backend.execute_operation(operation, Some(app_name), None) {
+        Ok(_) => {
+            info!("Retried a failed operation successfully.");
+            true
+        }
+        Err(status) if status == completed_status => {
+            info!("A failed operation had been completed, nothing to retry.");
+            true
+        }
+        Err(status) => {
+            error!(
+                "Retrying a failed operation failed with {}, keeping it.",
+                status
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DeadLetter, DeadLetters, DeadLettersConfig, FailedOperation};
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::KeyTriple;
+    use parsec_interface::operations::{psa_destroy_key, NativeOperation};
+    use parsec_interface::requests::{ProviderID, ResponseStatus};
+    use std::fs;
+
+    #[test]
+    fn failed_operations_recorded() {
+        let path = String::from("./dead_letters_test");
+        let _ = fs::remove_file(&path);
+        let dead_letters = DeadLetters::new(&DeadLettersConfig { path: path.clone() }).unwrap();
+        let key_triple = KeyTriple::new(
+            ApplicationName::new(String::from("app")),
+            ProviderID::Pkcs11,
+            String::from("key"),
+        );
+        let operation = NativeOperation::PsaDestroyKey(psa_destroy_key::Operation {
+            key_name: String::from("key"),
+        });
+        let mut dead_letter = DeadLetter::from_operation(&operation, &key_triple).unwrap();
+        dead_letter.status = ResponseStatus::PsaErrorCommunicationFailure.to_string();
+        dead_letters.record(&dead_letter);
+
+        let read = dead_letters.read().unwrap();
+        assert_eq!(read, vec![dead_letter]);
+        assert_eq!(read[0].operation, FailedOperation::DestroyKey);
+        assert_eq!(read[0].provider, ProviderID::Pkcs11 as u8);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Time-limited delegation of the use of a key to another application
+//!
+//! The keys of an application can only be used by that application. A key owner can mint a
+//! delegation token allowing another named application to use one of its keys, for the opcodes
+//! listed, until an expiry. The token is signed by the service with a HMAC key drawn when the
+//! dispatcher is built: tokens are not valid after the service restarts or reloads its
+//! configuration, and can not be minted by anyone else than the service.
+//!
+//! The delegate presents the token along with its operation, which the dispatcher executes on
+//! behalf of the owner once it checked that the token was signed by the service, that it has not
+//! expired and that it delegates this key and this opcode to the authenticated application. The
+//! operation then goes through the same policies as an operation of the owner, except for the keys
+//! bound to a process or unlocked with a credential, which the delegate process has to satisfy on
+//! its own.
+//!
+//! A token is the bincode serialization of its claims, followed by their HMAC-SHA256 tag. There is
+//! no opcode for minting and presenting tokens in the wire protocol: until they are added to
+//! `parsec-interface`, clients mint and present them through the extension API.
+use crate::authenticators::ApplicationName;
+use log::error;
+use parsec_interface::operations::NativeOperation;
+use parsec_interface::requests::{Opcode, ProviderID, ResponseStatus, Result};
+use ring::hmac;
+use ring::rand::SystemRandom;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default longest validity of the tokens, in seconds
+pub const DEFAULT_MAX_VALIDITY: u64 = 3600;
+/// Length of the tag ending the tokens
+const TAG_LEN: usize = 32;
+
+/// Configuration of the delegation tokens
+#[derive(Clone, Deserialize, Debug)]
+pub struct DelegationTokensConfig {
+    /// Longest validity of the tokens minted, in seconds
+    pub max_validity: Option<u64>,
+}
+
+// What a token allows, serialized and tagged.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Claims {
+    owner: String,
+    delegate: String,
+    provider: u8,
+    key_name: String,
+    opcodes: Vec<u32>,
+    // Seconds since the Unix epoch.
+    expiry: u64,
+}
+
+/// Minting and validation of the delegation tokens
+#[derive(Debug)]
+pub struct DelegationTokens {
+    key: hmac::Key,
+    max_validity: Duration,
+}
+
+impl DelegationTokens {
+    /// Creates the delegation tokens, signed with a new key, valid at most for the given duration.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInsufficientEntropy` if the key could not be generated.
+    pub fn new(max_validity: Duration) -> Result<DelegationTokens> {
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new()).map_err(|_| {
+            error!("Failed to generate the key of the delegation tokens.");
+            ResponseStatus::PsaErrorInsufficientEntropy
+        })?;
+
+        Ok(DelegationTokens { key, max_validity })
+    }
+
+    /// Mints a token allowing the delegate to use a key of the owner for the opcodes given, during
+    /// the validity given.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInvalidArgument` if no opcode is given, if the validity is zero or longer
+    /// than the longest one configured, or if the delegate is the owner.
+    pub fn mint(
+        &self,
+        owner: &ApplicationName,
+        delegate: &ApplicationName,
+        provider_id: ProviderID,
+        key_name: &str,
+        opcodes: &[Opcode],
+        validity: Duration,
+    ) -> Result<Vec<u8>> {
+        if opcodes.is_empty() || validity == Duration::from_secs(0) {
+            error!("A delegation token needs opcodes and a validity.");
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        if validity > self.max_validity {
+            error!(
+                "Delegation tokens can not be valid for more than {} seconds.",
+                self.max_validity.as_secs()
+            );
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        if owner == delegate {
+            error!("Keys can not be delegated to their owner.");
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+
+        let claims = Claims {
+            owner: owner.get_name().to_string(),
+            delegate: delegate.get_name().to_string(),
+            provider: provider_id as u8,
+            key_name: key_name.to_string(),
+            opcodes: opcodes.iter().map(|opcode| *opcode as u32).collect(),
+            expiry: now() + validity.as_secs(),
+        };
+        let mut token = bincode::serialize(&claims).map_err(|e| {
+            format_error!("Error serializing the delegation token", e);
+            ResponseStatus::PsaErrorGenericError
+        })?;
+        let tag = hmac::sign(&self.key, &token);
+        token.extend_from_slice(tag.as_ref());
+
+        Ok(token)
+    }
+
+    /// Validates a token presented by the delegate for an operation on a provider, and returns
+    /// the owner on behalf of which the operation is executed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the token was not signed by the service, if it expired or
+    /// if it does not delegate the key and the opcode of the operation to the delegate.
+    pub fn validate(
+        &self,
+        token: &[u8],
+        delegate: &ApplicationName,
+        provider_id: ProviderID,
+        operation: &NativeOperation,
+    ) -> Result<ApplicationName> {
+        if token.len() < TAG_LEN {
+            error!("The delegation token is too short.");
+            return Err(ResponseStatus::PsaErrorNotPermitted);
+        }
+        let (serialized, tag) = token.split_at(token.len() - TAG_LEN);
+        hmac::verify(&self.key, serialized, tag).map_err(|_| {
+            error!("The delegation token was not signed by this service.");
+            ResponseStatus::PsaErrorNotPermitted
+        })?;
+        let claims: Claims = bincode::deserialize(serialized).map_err(|e| {
+            format_error!("Error deserializing the delegation token", e);
+            ResponseStatus::PsaErrorNotPermitted
+        })?;
+
+        if claims.expiry <= now() {
+            error!("The delegation token expired.");
+            return Err(ResponseStatus::PsaErrorNotPermitted);
+        }
+        let opcode = operation.opcode();
+        if claims.delegate != delegate.get_name()
+            || claims.provider != provider_id as u8
+            || Some(claims.key_name.as_str()) != key_name(operation)
+            || !claims.opcodes.contains(&(opcode as u32))
+        {
+            error!(
+                "The delegation token does not allow {:?} on this key for this application.",
+                opcode
+            );
+            return Err(ResponseStatus::PsaErrorNotPermitted);
+        }
+
+        Ok(ApplicationName::new(claims.owner))
+    }
+}
+
+// Gets the name of the key an operation uses, if it is one which can be delegated.
+fn key_name(operation: &NativeOperation) -> Option<&str> {
+    let key_name = match operation {
+        NativeOperation::PsaSignHash(op) => &op.key_name,
+        NativeOperation::PsaVerifyHash(op) => &op.key_name,
+        NativeOperation::PsaExportPublicKey(op) => &op.key_name,
+        NativeOperation::PsaAsymmetricEncrypt(op) => &op.key_name,
+        NativeOperation::PsaAsymmetricDecrypt(op) => &op.key_name,
+        NativeOperation::PsaAeadEncrypt(op) => &op.key_name,
+        NativeOperation::PsaAeadDecrypt(op) => &op.key_name,
+        NativeOperation::PsaRawKeyAgreement(op) => &op.private_key_name,
+        _ => return None,
+    };
+
+    Some(key_name)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::DelegationTokens;
+    use crate::authenticators::ApplicationName;
+    use parsec_interface::operations::psa_algorithm::{AsymmetricSignature, Hash, SignHash};
+    use parsec_interface::operations::{psa_sign_hash, NativeOperation};
+    use parsec_interface::requests::{Opcode, ProviderID, ResponseStatus};
+    use std::time::Duration;
+
+    fn sign(key_name: &str) -> NativeOperation {
+        NativeOperation::PsaSignHash(psa_sign_hash::Operation {
+            key_name: key_name.to_string(),
+            alg: AsymmetricSignature::Ecdsa {
+                hash_alg: SignHash::Specific(Hash::Sha256),
+            },
+            hash: vec![0; 32].into(),
+        })
+    }
+
+    #[test]
+    fn token_validated() {
+        let tokens = DelegationTokens::new(Duration::from_secs(60)).unwrap();
+        let owner = ApplicationName::new(String::from("owner"));
+        let delegate = ApplicationName::new(String::from("delegate"));
+        let token = tokens
+            .mint(
+                &owner,
+                &delegate,
+                ProviderID::MbedCrypto,
+                "key",
+                &[Opcode::PsaSignHash],
+                Duration::from_secs(60),
+            )
+            .unwrap();
+
+        assert_eq!(
+            tokens
+                .validate(&token, &delegate, ProviderID::MbedCrypto, &sign("key"))
+                .unwrap(),
+            owner
+        );
+        // Other applications, keys and providers are not delegated.
+        assert!(tokens
+            .validate(&token, &owner, ProviderID::MbedCrypto, &sign("key"))
+            .is_err());
+        assert!(tokens
+            .validate(&token, &delegate, ProviderID::MbedCrypto, &sign("other"))
+            .is_err());
+        assert!(tokens
+            .validate(&token, &delegate, ProviderID::Tpm, &sign("key"))
+            .is_err());
+
+        let mut tampered = token.clone();
+        tampered[0] ^= 1;
+        assert_eq!(
+            tokens.validate(&tampered, &delegate, ProviderID::MbedCrypto, &sign("key")),
+            Err(ResponseStatus::PsaErrorNotPermitted)
+        );
+        // Tokens minted by another instance of the service are not accepted.
+        let other_tokens = DelegationTokens::new(Duration::from_secs(60)).unwrap();
+        assert!(other_tokens
+            .validate(&token, &delegate, ProviderID::MbedCrypto, &sign("key"))
+            .is_err());
+    }
+
+    #[test]
+    fn validity_limited() {
+        let tokens = DelegationTokens::new(Duration::from_secs(60)).unwrap();
+        let owner = ApplicationName::new(String::from("owner"));
+        let delegate = ApplicationName::new(String::from("delegate"));
+        assert!(tokens
+            .mint(
+                &owner,
+                &delegate,
+                ProviderID::MbedCrypto,
+                "key",
+                &[Opcode::PsaSignHash],
+                Duration::from_secs(61),
+            )
+            .is_err());
+        assert!(tokens
+            .mint(
+                &owner,
+                &owner,
+                ProviderID::MbedCrypto,
+                "key",
+                &[Opcode::PsaSignHash],
+                Duration::from_secs(60),
+            )
+            .is_err());
+    }
+}
@@ -4,14 +4,119 @@
 //!
 //! The dispatcher's role is to direct requests to the provider they specify, if
 //! said provider is available on the system, thus acting as a multiplexer.
+//!
+//! It also destroys the keys of a client deleted with the DeleteClient operation, as they are
+//! spread over the providers. Each key is destroyed by an operation nested in the DeleteClient
+//! request, executed through the backend of its provider on behalf of the client, so that the
+//! bindings, slots and events of the keys are handled. As the administrator deleting the client
+//! is not one of its processes, the keys bound to them are destroyed too.
+//!
+//! The certificate of the device identity, if bootstrapped, can be fetched by all the
+//! applications.
+//!
+//! It mints the delegation tokens allowing an application to use a key of another one, and
+//! validates them before executing the operations of the delegates, see the `delegation_tokens`
+//! module. Both are requested through the extension API.
 use super::backend_handler::BackEndHandler;
+#[cfg(feature = "dead-letters")]
+use super::dead_letters::DeadLetters;
+#[cfg(feature = "delegation-tokens")]
+use super::delegation_tokens::DelegationTokens;
+use super::error_metadata::ErrorMetadata;
+use super::shadow::{self, Shadow, ShadowStatistics};
 use crate::authenticators::ApplicationName;
-use log::trace;
+use crate::front::listener::ConnectionMetadata;
+use crate::key_info_managers::KeyTriple;
+#[cfg(feature = "device-identity")]
+use crate::utils::device_identity::DeviceIdentity;
+use log::{error, info, trace};
+use parsec_interface::operations::{list_keys, psa_destroy_key, NativeOperation, NativeResult};
 use parsec_interface::requests::request::Request;
-use parsec_interface::requests::ProviderID;
+use parsec_interface::requests::{Opcode, ProviderID};
 use parsec_interface::requests::{Response, ResponseStatus};
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
+#[cfg(feature = "delegation-tokens")]
+use std::time::Duration;
+use std::time::Instant;
+
+// Maximum number of operations nested in each other, to catch composite operations calling each
+// other endlessly.
+const MAX_NESTING_DEPTH: usize = 4;
+
+/// Client on behalf of which an operation is executed
+///
+/// Composite operations executing other operations pass the context of the request they are
+/// part of, so that the nested operations are checked by the back end handler and audited as
+/// operations of the same client.
+#[derive(Clone, Debug)]
+pub struct CallerContext {
+    app_name: Option<ApplicationName>,
+    metadata: Option<ConnectionMetadata>,
+    // Opcodes of the operations the current one is nested in, outermost first.
+    parent_opcodes: Vec<Opcode>,
+    // Operation of an administrator, which the key bindings do not apply to.
+    admin: bool,
+}
+
+impl CallerContext {
+    /// Creates the context of a request of the given opcode, sent by a client.
+    pub fn new(
+        app_name: Option<ApplicationName>,
+        metadata: Option<ConnectionMetadata>,
+        opcode: Opcode,
+    ) -> CallerContext {
+        CallerContext {
+            app_name,
+            metadata,
+            parent_opcodes: vec![opcode],
+            admin: false,
+        }
+    }
+
+    /// Creates the context of a request of the given opcode, sent by an administrator, executing
+    /// operations on the keys of a client on its behalf. The keys bound to the processes of the
+    /// client can be destroyed in this context.
+    pub fn admin(client: ApplicationName, opcode: Opcode) -> CallerContext {
+        CallerContext {
+            app_name: Some(client),
+            metadata: None,
+            parent_opcodes: vec![opcode],
+            admin: true,
+        }
+    }
+
+    /// Creates the context of a composite operation of the given opcode, executed as part of the
+    /// current one.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the maximum nesting depth is reached.
+    pub fn nested(&self, opcode: Opcode) -> std::result::Result<CallerContext, ResponseStatus> {
+        self.check_depth(opcode)?;
+        let mut context = self.clone();
+        context.parent_opcodes.push(opcode);
+
+        Ok(context)
+    }
+
+    fn check_depth(&self, opcode: Opcode) -> std::result::Result<(), ResponseStatus> {
+        if self.parent_opcodes.len() >= MAX_NESTING_DEPTH {
+            error!(
+                "Maximum depth of nested operations reached executing {:?} in {:?}.",
+                opcode, self.parent_opcodes
+            );
+            return Err(ResponseStatus::PsaErrorNotPermitted);
+        }
+
+        Ok(())
+    }
+
+    /// Gets the name of the application sending the request, if authenticated.
+    pub fn app_name(&self) -> Option<&ApplicationName> {
+        self.app_name.as_ref()
+    }
+}
 
 /// Dispatcher to backend
 ///
@@ -23,6 +128,12 @@ use std::io::{Error, ErrorKind, Result};
 #[derive(Debug)]
 pub struct Dispatcher {
     backends: HashMap<ProviderID, BackEndHandler>,
+    // Shadowing of the operations of the primary providers.
+    shadows: HashMap<ProviderID, Shadow>,
+    #[cfg(feature = "device-identity")]
+    device_identity: Option<DeviceIdentity>,
+    #[cfg(feature = "delegation-tokens")]
+    delegation_tokens: Option<DelegationTokens>,
 }
 
 impl Dispatcher {
@@ -31,25 +142,414 @@ impl Dispatcher {
     ///
     /// Returns either the response coming from the backend handler, or a response
     /// containing a status code consistent with the error encountered during
-    /// processing.
+    /// processing, along with the key created by the request, if any.
     pub fn dispatch_request(
         &self,
         request: Request,
         app_name: Option<ApplicationName>,
-    ) -> Response {
+        metadata: Option<ConnectionMetadata>,
+    ) -> (Response, Option<KeyTriple>) {
         trace!("dispatch_request ingress");
         if let Some(backend) = self.backends.get(&request.header.provider) {
             if let Err(status) = backend.is_capable(&request) {
-                Response::from_request_header(request.header, status)
+                ErrorMetadata::new(status, request.header.provider, request.header.opcode).log();
+                (Response::from_request_header(request.header, status), None)
             } else {
-                {
-                    let response = backend.execute_request(request, app_name);
-                    trace!("execute_request egress");
-                    response
-                }
+                let response = match self.shadows.get(&request.header.provider) {
+                    Some(shadow) if shadow.shadows(request.header.opcode) => {
+                        self.execute_shadowed(backend, shadow, request, app_name, metadata)
+                    }
+                    _ if request.header.opcode == Opcode::DeleteClient => {
+                        self.execute_delete_client(backend, request, app_name, metadata)
+                    }
+                    _ => backend.execute_request(request, app_name, metadata),
+                };
+                trace!("execute_request egress");
+                response
             }
         } else {
-            Response::from_request_header(request.header, ResponseStatus::ProviderNotRegistered)
+            ErrorMetadata::new(
+                ResponseStatus::ProviderNotRegistered,
+                request.header.provider,
+                request.header.opcode,
+            )
+            .log();
+            (
+                Response::from_request_header(
+                    request.header,
+                    ResponseStatus::ProviderNotRegistered,
+                ),
+                None,
+            )
+        }
+    }
+
+    /// Executes a request on the primary provider, then again on the secondary provider of the
+    /// shadow, and returns the response of the primary provider.
+    fn execute_shadowed(
+        &self,
+        backend: &BackEndHandler,
+        shadow: &Shadow,
+        request: Request,
+        app_name: Option<ApplicationName>,
+        metadata: Option<ConnectionMetadata>,
+    ) -> (Response, Option<KeyTriple>) {
+        let header = request.header;
+        let _in_flight = match backend.reserve_in_flight(request.body.len()) {
+            Ok(in_flight) => in_flight,
+            Err(status) => {
+                ErrorMetadata::new(status, header.provider, header.opcode).log();
+                return (Response::from_request_header(header, status), None);
+            }
+        };
+        let operation = backend.decode(request.body, header.opcode);
+        // The payload is only copied if there is a secondary provider to execute it.
+        let secondary_backend = self.backends.get(&shadow.secondary());
+        let shadow_operation = secondary_backend
+            .and(operation.as_ref().ok())
+            .and_then(shadow::copy_operation);
+
+        let start = Instant::now();
+        let response = backend.execute_decoded(header, operation, app_name.clone(), metadata);
+        let primary_latency = start.elapsed();
+
+        if let (Some(shadow_operation), Some(secondary_backend)) =
+            (shadow_operation, secondary_backend)
+        {
+            let mut secondary_header = header;
+            secondary_header.provider = shadow.secondary();
+            let start = Instant::now();
+            let (secondary_response, _) = secondary_backend.execute_decoded(
+                secondary_header,
+                Ok(shadow_operation),
+                app_name,
+                metadata,
+            );
+            let _ = shadow.record(
+                (&response.0, primary_latency),
+                (&secondary_response, start.elapsed()),
+            );
+        }
+
+        response
+    }
+
+    /// Destroys all the keys of the client to delete, then completes the DeleteClient request on
+    /// the core provider. The request fails if one of the keys could not be destroyed, the keys
+    /// already destroyed staying so.
+    fn execute_delete_client(
+        &self,
+        core_backend: &BackEndHandler,
+        request: Request,
+        app_name: Option<ApplicationName>,
+        metadata: Option<ConnectionMetadata>,
+    ) -> (Response, Option<KeyTriple>) {
+        let header = request.header;
+        let operation = core_backend.decode(request.body, header.opcode);
+        if let (Some(_), Ok(NativeOperation::DeleteClient(op))) = (&app_name, &operation) {
+            let client = ApplicationName::new(op.client.clone());
+            if let Err(status) = self.destroy_client_keys(header.provider, &client) {
+                ErrorMetadata::new(status, header.provider, header.opcode).log();
+                return (Response::from_request_header(header, status), None);
+            }
+        }
+
+        core_backend.execute_decoded(header, operation, app_name, metadata)
+    }
+
+    fn destroy_client_keys(
+        &self,
+        core_provider_id: ProviderID,
+        client: &ApplicationName,
+    ) -> std::result::Result<(), ResponseStatus> {
+        // The keys are listed and destroyed as nested operations of the client deleted.
+        let context = CallerContext::admin(client.clone(), Opcode::DeleteClient);
+        let keys = match self.execute_nested(
+            &context,
+            core_provider_id,
+            NativeOperation::ListKeys(list_keys::Operation {}),
+        )? {
+            NativeResult::ListKeys(result) => result.keys,
+            _ => return Err(ResponseStatus::PsaErrorGenericError),
+        };
+
+        let mut result = Ok(());
+        let mut destroyed = 0;
+        for key in keys {
+            if !self.backends.contains_key(&key.provider_id) {
+                continue;
+            }
+            let operation =
+                NativeOperation::PsaDestroyKey(psa_destroy_key::Operation { key_name: key.name });
+            match self.execute_nested(&context, key.provider_id, operation) {
+                Ok(_) => destroyed += 1,
+                Err(ResponseStatus::PsaErrorDoesNotExist) => (),
+                Err(status) => result = Err(status),
+            }
+        }
+
+        if crate::utils::GlobalConfig::log_error_details() {
+            info!("{} keys of client \"{}\" destroyed.", destroyed, client);
+        } else {
+            info!("{} keys of a deleted client destroyed.", destroyed);
+        }
+
+        result
+    }
+
+    /// Gets the statistics of the operations shadowed, for each primary provider.
+    pub fn shadow_statistics(&self) -> Vec<(ProviderID, ProviderID, ShadowStatistics)> {
+        self.shadows
+            .iter()
+            .map(|(primary, shadow)| (*primary, shadow.secondary(), shadow.statistics()))
+            .collect()
+    }
+
+    /// Gets the store of the operations which failed because their backend was unavailable, if
+    /// configured.
+    #[cfg(feature = "dead-letters")]
+    pub fn dead_letters(&self) -> Option<&DeadLetters> {
+        // All the back end handlers share the same store.
+        self.backends
+            .values()
+            .find_map(BackEndHandler::dead_letters)
+    }
+
+    /// Destroys a key created by a request whose response could not be sent to the client.
+    pub fn roll_back_key_creation(&self, key_triple: &KeyTriple) {
+        if let Some(backend) = self.backends.get(&key_triple.provider_id()) {
+            backend.roll_back_key_creation(key_triple);
+        }
+    }
+
+    /// Executes an operation as part of a composite operation, on behalf of the same client.
+    ///
+    /// The nested operation goes through the checks of the back end handler as if it was
+    /// requested by the client itself, such as the key naming and creation policies, the key
+    /// bindings and the key slots, and is logged along with the operations it is nested in. The
+    /// checks of the front end handler, the denied opcodes and the policy engine, only apply to the
+    /// request the composite operation is part of: the nested operations are the service's
+    /// decision, not the client's. The context given is the one of the composite operation calling
+    /// this method.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ProviderNotRegistered` if the provider is not available and
+    /// `PsaErrorNotPermitted` if the maximum nesting depth is reached. Otherwise, returns the
+    /// error of the operation.
+    pub fn execute_nested(
+        &self,
+        context: &CallerContext,
+        provider_id: ProviderID,
+        operation: NativeOperation,
+    ) -> std::result::Result<NativeResult, ResponseStatus> {
+        let opcode = operation.opcode();
+        context.check_depth(opcode)?;
+        let backend = self.backends.get(&provider_id).ok_or_else(|| {
+            ErrorMetadata::new(ResponseStatus::ProviderNotRegistered, provider_id, opcode).log();
+            ResponseStatus::ProviderNotRegistered
+        })?;
+
+        if crate::utils::GlobalConfig::log_error_details() {
+            info!(
+                "Executing {:?} nested in {:?} on behalf of {:?}.",
+                opcode,
+                context.parent_opcodes,
+                context
+                    .app_name
+                    .as_ref()
+                    .map(|app_name| app_name.get_name())
+            );
+        } else {
+            info!(
+                "Executing {:?} nested in {:?}.",
+                opcode, context.parent_opcodes
+            );
+        }
+        let result = if context.admin {
+            backend.execute_admin_operation(operation, context.app_name.clone())
+        } else {
+            backend.execute_operation(operation, context.app_name.clone(), context.metadata)
+        };
+        result.or_else(|status| {
+            ErrorMetadata::new(status, provider_id, opcode).log();
+            Err(status)
+        })
+    }
+
+    /// Gets the backend handler of a provider, if it is available.
+    pub fn backend(&self, provider_id: ProviderID) -> Option<&BackEndHandler> {
+        self.backends.get(&provider_id)
+    }
+
+    /// Gets the certificate of the device identity, as DER, on behalf of an application.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NotAuthenticated` if the application is not authenticated and
+    /// `PsaErrorDoesNotExist` if the device identity is not configured or not certified yet.
+    #[cfg(feature = "device-identity")]
+    pub fn device_certificate(
+        &self,
+        app_name: Option<&ApplicationName>,
+    ) -> std::result::Result<Vec<u8>, ResponseStatus> {
+        let _ = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        let device_identity = self.device_identity.as_ref().ok_or_else(|| {
+            error!("The device identity is not configured.");
+            ResponseStatus::PsaErrorDoesNotExist
+        })?;
+        let backend = self
+            .backends
+            .get(&device_identity.provider_id())
+            .ok_or(ResponseStatus::ProviderNotRegistered)?;
+
+        device_identity.certificate(backend)
+    }
+
+    /// Gets the device identity, if bootstrapped.
+    #[cfg(feature = "device-identity")]
+    pub fn device_identity(&self) -> Option<&DeviceIdentity> {
+        self.device_identity.as_ref()
+    }
+
+    /// Mints a token allowing the delegate to use a key of the application for the opcodes given,
+    /// during the validity given.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NotAuthenticated` if the application is not authenticated,
+    /// `PsaErrorNotSupported` if delegation tokens are not configured and
+    /// `PsaErrorInvalidArgument` if the token requested is not valid.
+    #[cfg(feature = "delegation-tokens")]
+    pub fn mint_delegation_token(
+        &self,
+        app_name: Option<&ApplicationName>,
+        delegate: &ApplicationName,
+        provider_id: ProviderID,
+        key_name: &str,
+        opcodes: &[Opcode],
+        validity: Duration,
+    ) -> std::result::Result<Vec<u8>, ResponseStatus> {
+        let owner = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        let delegation_tokens = self.delegation_tokens.as_ref().ok_or_else(|| {
+            error!("Delegation tokens are not configured.");
+            ResponseStatus::PsaErrorNotSupported
+        })?;
+        let token =
+            delegation_tokens.mint(owner, delegate, provider_id, key_name, opcodes, validity)?;
+        if crate::utils::GlobalConfig::log_error_details() {
+            info!(
+                "Use of \"{}\" of {} delegated to \"{}\" for {:?} during {} seconds.",
+                key_name,
+                provider_id,
+                delegate,
+                opcodes,
+                validity.as_secs()
+            );
+        } else {
+            info!(
+                "Use of a key delegated for {:?} during {} seconds.",
+                opcodes,
+                validity.as_secs()
+            );
+        }
+
+        Ok(token)
+    }
+
+    /// Executes an operation of a delegate on a key of another application, on behalf of the
+    /// owner of the key, once the delegation token presented is validated.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NotAuthenticated` if the delegate is not authenticated, `PsaErrorNotSupported` if
+    /// delegation tokens are not configured and `PsaErrorNotPermitted` if the token does not allow
+    /// the operation. Otherwise, returns the error of the operation.
+    #[cfg(feature = "delegation-tokens")]
+    pub fn execute_delegated(
+        &self,
+        token: &[u8],
+        app_name: Option<&ApplicationName>,
+        metadata: Option<ConnectionMetadata>,
+        provider_id: ProviderID,
+        operation: NativeOperation,
+    ) -> std::result::Result<NativeResult, ResponseStatus> {
+        let opcode = operation.opcode();
+        let delegate = app_name.ok_or(ResponseStatus::NotAuthenticated)?;
+        let delegation_tokens = self.delegation_tokens.as_ref().ok_or_else(|| {
+            error!("Delegation tokens are not configured.");
+            ResponseStatus::PsaErrorNotSupported
+        })?;
+        let owner = delegation_tokens
+            .validate(token, delegate, provider_id, &operation)
+            .or_else(|status| {
+                ErrorMetadata::new(status, provider_id, opcode).log();
+                Err(status)
+            })?;
+        let backend = self.backends.get(&provider_id).ok_or_else(|| {
+            ErrorMetadata::new(ResponseStatus::ProviderNotRegistered, provider_id, opcode).log();
+            ResponseStatus::ProviderNotRegistered
+        })?;
+
+        if crate::utils::GlobalConfig::log_error_details() {
+            info!(
+                "Executing {:?} delegated by \"{}\" to \"{}\".",
+                opcode, owner, delegate
+            );
+        } else {
+            info!("Executing a delegated {:?}.", opcode);
+        }
+        backend
+            .execute_operation(operation, Some(owner), metadata)
+            .or_else(|status| {
+                ErrorMetadata::new(status, provider_id, opcode).log();
+                Err(status)
+            })
+    }
+
+    /// Cancels the operations in flight in all the providers, and the ones starting from now.
+    pub fn cancel_operations(&self) {
+        for backend in self.backends.values() {
+            backend.cancel_operations();
+        }
+    }
+
+    /// Discovers again the capabilities of the providers, of all of them or only of the ones whose
+    /// capabilities are stale, and updates the descriptions served by the core provider. Returns
+    /// the number of providers whose capabilities were discovered again.
+    pub fn refresh_capabilities(&self, stale_only: bool) -> usize {
+        let core_backend = match self.backends.get(&ProviderID::Core) {
+            Some(core_backend) => core_backend,
+            None => return 0,
+        };
+        let mut refreshed = 0;
+        for (provider_id, backend) in &self.backends {
+            if *provider_id == ProviderID::Core || (stale_only && !backend.capabilities_stale()) {
+                continue;
+            }
+            match backend.refresh_capabilities() {
+                Ok(Some((provider_info, opcodes))) => {
+                    core_backend.update_provider_details(provider_info, opcodes);
+                    refreshed += 1;
+                }
+                Ok(None) => (),
+                Err(status) => format_error!(
+                    &format!(
+                        "Failed to discover the capabilities of the {} provider again",
+                        provider_id
+                    ),
+                    status
+                ),
+            }
+        }
+
+        refreshed
+    }
+
+    /// Destroys the expired peer keys of all the providers.
+    pub fn reap_expired_peer_keys(&self) {
+        for backend in self.backends.values() {
+            backend.reap_expired_peer_keys();
         }
     }
 }
@@ -58,11 +558,23 @@ impl Dispatcher {
 #[derive(Debug, Default)]
 pub struct DispatcherBuilder {
     backends: Option<HashMap<ProviderID, BackEndHandler>>,
+    shadows: HashMap<ProviderID, Shadow>,
+    #[cfg(feature = "device-identity")]
+    device_identity: Option<DeviceIdentity>,
+    #[cfg(feature = "delegation-tokens")]
+    delegation_tokens: Option<DelegationTokens>,
 }
 
 impl DispatcherBuilder {
     pub fn new() -> Self {
-        DispatcherBuilder { backends: None }
+        DispatcherBuilder {
+            backends: None,
+            shadows: HashMap::new(),
+            #[cfg(feature = "device-identity")]
+            device_identity: None,
+            #[cfg(feature = "delegation-tokens")]
+            delegation_tokens: None,
+        }
     }
 
     pub fn with_backend(
@@ -85,11 +597,242 @@ impl DispatcherBuilder {
         self
     }
 
+    pub fn with_shadow(mut self, primary: ProviderID, shadow: Shadow) -> Self {
+        let _ = self.shadows.insert(primary, shadow);
+
+        self
+    }
+
+    #[cfg(feature = "device-identity")]
+    pub fn with_device_identity(mut self, device_identity: DeviceIdentity) -> Self {
+        self.device_identity = Some(device_identity);
+
+        self
+    }
+
+    #[cfg(feature = "delegation-tokens")]
+    pub fn with_delegation_tokens(mut self, delegation_tokens: DelegationTokens) -> Self {
+        self.delegation_tokens = Some(delegation_tokens);
+
+        self
+    }
+
     pub fn build(self) -> Result<Dispatcher> {
+        let backends = self
+            .backends
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "backends is missing"))?;
+        for (primary, shadow) in self.shadows.iter() {
+            if !backends.contains_key(primary) || !backends.contains_key(&shadow.secondary()) {
+                error!(
+                    "The shadowing of {} on {} needs both providers.",
+                    primary,
+                    shadow.secondary()
+                );
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "shadowed provider is missing",
+                ));
+            }
+        }
+
         Ok(Dispatcher {
-            backends: self
-                .backends
-                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "backends is missing"))?,
+            backends,
+            shadows: self.shadows,
+            #[cfg(feature = "device-identity")]
+            device_identity: self.device_identity,
+            #[cfg(feature = "delegation-tokens")]
+            delegation_tokens: self.delegation_tokens,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CallerContext, Dispatcher, DispatcherBuilder};
+    use crate::authenticators::ApplicationName;
+    use crate::back::backend_handler::BackEndHandlerBuilder;
+    use crate::back::canary_keys::CanaryKeys;
+    use crate::back::key_binding::KeyBindings;
+    use crate::front::listener::ConnectionMetadata;
+    use crate::providers::Provide;
+    use parsec_interface::operations::{list_keys, psa_destroy_key, psa_export_public_key};
+    use parsec_interface::operations::{NativeOperation, NativeResult};
+    use parsec_interface::operations_protobuf::ProtobufConverter;
+    use parsec_interface::requests::{BodyType, Opcode, ProviderID, ResponseStatus, Result};
+    use std::sync::Arc;
+
+    // Exports the name of the application as the public key of all keys.
+    #[derive(Debug)]
+    struct CallerProvider;
+
+    impl Provide for CallerProvider {
+        fn psa_export_public_key(
+            &self,
+            app_name: ApplicationName,
+            _op: psa_export_public_key::Operation,
+        ) -> Result<psa_export_public_key::Result> {
+            Ok(psa_export_public_key::Result {
+                data: app_name.get_name().as_bytes().to_vec(),
+            })
+        }
+    }
+
+    // Has a single key, of the client, which can be destroyed.
+    #[derive(Debug)]
+    struct KeyProvider;
+
+    impl Provide for KeyProvider {
+        fn list_keys(
+            &self,
+            _app_name: ApplicationName,
+            _op: list_keys::Operation,
+        ) -> Result<list_keys::Result> {
+            Ok(list_keys::Result {
+                keys: vec![list_keys::KeyInfo {
+                    provider_id: ProviderID::MbedCrypto,
+                    name: String::from("key"),
+                    attributes: CanaryKeys::attributes(),
+                }],
+            })
+        }
+
+        fn psa_destroy_key(
+            &self,
+            _app_name: ApplicationName,
+            _op: psa_destroy_key::Operation,
+        ) -> Result<psa_destroy_key::Result> {
+            Ok(psa_destroy_key::Result {})
+        }
+    }
+
+    fn dispatcher() -> Dispatcher {
+        let backend = BackEndHandlerBuilder::new()
+            .with_provider(Box::from(CallerProvider))
+            .with_converter(Box::from(ProtobufConverter {}))
+            .with_provider_id(ProviderID::MbedCrypto)
+            .with_content_type(BodyType::Protobuf)
+            .with_accept_type(BodyType::Protobuf)
+            .build()
+            .unwrap();
+
+        DispatcherBuilder::new()
+            .with_backend(ProviderID::MbedCrypto, backend)
+            .build()
+            .unwrap()
+    }
+
+    fn export() -> NativeOperation {
+        NativeOperation::PsaExportPublicKey(psa_export_public_key::Operation {
+            key_name: String::from("key"),
         })
     }
+
+    #[test]
+    fn nested_operation_executed_on_behalf_of_caller() {
+        let dispatcher = dispatcher();
+        let context = CallerContext::new(
+            Some(ApplicationName::new(String::from("client"))),
+            None,
+            Opcode::PsaSignHash,
+        );
+
+        match dispatcher.execute_nested(&context, ProviderID::MbedCrypto, export()) {
+            Ok(NativeResult::PsaExportPublicKey(result)) => {
+                assert_eq!(result.data.to_vec(), b"client".to_vec())
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert_eq!(
+            dispatcher
+                .execute_nested(&context, ProviderID::Tpm, export())
+                .unwrap_err(),
+            ResponseStatus::ProviderNotRegistered
+        );
+        // The policies of the client apply to its nested operations.
+        let context = CallerContext::new(None, None, Opcode::PsaSignHash);
+        assert_eq!(
+            dispatcher
+                .execute_nested(&context, ProviderID::MbedCrypto, export())
+                .unwrap_err(),
+            ResponseStatus::NotAuthenticated
+        );
+    }
+
+    #[test]
+    fn bound_keys_of_deleted_client_destroyed() {
+        let client = ApplicationName::new(String::from("client"));
+        let key_bindings = Arc::new(KeyBindings::new(vec![String::from("client")]));
+        let metadata = Some(ConnectionMetadata::UnixPeerCredentials {
+            uid: 0,
+            gid: 0,
+            pid: std::process::id() as i32,
+        });
+        key_bindings.bind(
+            key_bindings
+                .new_binding(&client, ProviderID::MbedCrypto, "key", metadata)
+                .unwrap()
+                .expect("The key should be bound"),
+        );
+        let backend = BackEndHandlerBuilder::new()
+            .with_provider(Box::from(KeyProvider))
+            .with_converter(Box::from(ProtobufConverter {}))
+            .with_provider_id(ProviderID::MbedCrypto)
+            .with_content_type(BodyType::Protobuf)
+            .with_accept_type(BodyType::Protobuf)
+            .with_key_bindings(key_bindings.clone())
+            .build()
+            .unwrap();
+        let dispatcher = DispatcherBuilder::new()
+            .with_backend(ProviderID::MbedCrypto, backend)
+            .build()
+            .unwrap();
+        let destroy = NativeOperation::PsaDestroyKey(psa_destroy_key::Operation {
+            key_name: String::from("key"),
+        });
+
+        // Another process of the client can not destroy the key.
+        let context = CallerContext::new(Some(client.clone()), None, Opcode::PsaDestroyKey);
+        assert_eq!(
+            dispatcher
+                .execute_nested(&context, ProviderID::MbedCrypto, destroy)
+                .unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        dispatcher
+            .destroy_client_keys(ProviderID::MbedCrypto, &client)
+            .unwrap();
+        // The binding is gone with the key.
+        key_bindings
+            .check_destroy(&client, ProviderID::MbedCrypto, "key", None)
+            .unwrap();
+    }
+
+    #[test]
+    fn nesting_depth_limited() {
+        let dispatcher = dispatcher();
+        let context = CallerContext::new(
+            Some(ApplicationName::new(String::from("client"))),
+            None,
+            Opcode::PsaSignHash,
+        );
+        let context = context
+            .nested(Opcode::PsaSignHash)
+            .and_then(|context| context.nested(Opcode::PsaSignHash))
+            .unwrap();
+        let _ = dispatcher
+            .execute_nested(&context, ProviderID::MbedCrypto, export())
+            .unwrap();
+
+        let context = context.nested(Opcode::PsaSignHash).unwrap();
+        assert_eq!(
+            dispatcher
+                .execute_nested(&context, ProviderID::MbedCrypto, export())
+                .unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        assert_eq!(
+            context.nested(Opcode::PsaSignHash).unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+    }
 }
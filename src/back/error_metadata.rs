@@ -0,0 +1,95 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Machine-readable metadata of failed requests
+//!
+//! The wire protocol only carries a status code for failed requests. To help tooling pinpoint
+//! failures, the service logs, for each of them, a single line in a stable `key=value` format
+//! containing the numerical status code, its name, the provider and the opcode of the request, as
+//! well as any parameter given by the component that failed. The format does not depend on the
+//! locale and fields are only ever added to it.
+use log::error;
+use parsec_interface::requests::{Opcode, ProviderID, ResponseStatus};
+use std::fmt;
+
+/// Metadata of a failed request
+#[derive(Clone, Debug)]
+pub struct ErrorMetadata {
+    status: ResponseStatus,
+    provider_id: ProviderID,
+    opcode: Opcode,
+    parameters: Vec<(&'static str, String)>,
+}
+
+impl ErrorMetadata {
+    /// Creates the metadata of the failure of a request for the given provider and opcode.
+    pub fn new(status: ResponseStatus, provider_id: ProviderID, opcode: Opcode) -> ErrorMetadata {
+        ErrorMetadata {
+            status,
+            provider_id,
+            opcode,
+            parameters: Vec::new(),
+        }
+    }
+
+    /// Adds a named parameter describing the failure, for example the invalid attribute. Names
+    /// should only contain lowercase letters and underscores.
+    pub fn with_parameter(mut self, name: &'static str, value: impl fmt::Display) -> Self {
+        self.parameters.push((name, value.to_string()));
+        self
+    }
+
+    /// Gets the stable numerical code of the error, as sent on the wire.
+    pub fn code(&self) -> u16 {
+        self.status as u16
+    }
+
+    /// Logs the metadata.
+    pub fn log(&self) {
+        error!("Request failed: {}", self);
+    }
+}
+
+impl fmt::Display for ErrorMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "code={} status={:?} provider={:?} opcode={:?}",
+            self.code(),
+            self.status,
+            self.provider_id,
+            self.opcode
+        )?;
+        for (name, value) in &self.parameters {
+            write!(f, " {}={:?}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ErrorMetadata;
+    use parsec_interface::requests::{Opcode, ProviderID, ResponseStatus};
+
+    #[test]
+    fn stable_format() {
+        let metadata = ErrorMetadata::new(
+            ResponseStatus::PsaErrorInvalidArgument,
+            ProviderID::Pkcs11,
+            Opcode::PsaSignHash,
+        )
+        .with_parameter("attribute", "bits");
+
+        assert_eq!(
+            metadata.code(),
+            ResponseStatus::PsaErrorInvalidArgument as u16
+        );
+        assert_eq!(
+            metadata.to_string(),
+            format!(
+                "code={} status=PsaErrorInvalidArgument provider=Pkcs11 opcode=PsaSignHash attribute=\"bits\"",
+                metadata.code()
+            )
+        );
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Hooks notifying external systems of service events
+//!
+//! Inventory and alerting systems can be told about the events of the service as they happen,
+//! instead of polling it. Each configured hook selects some of the following events:
+//! * `KeyCreated`: a key was generated or imported by a client
+//! * `KeyDestroyed`: a key was destroyed by a client
+//! * `ProviderUnhealthy`: a provider started failing with hardware or communication errors, only
+//!   notified again once it has succeeded an operation in the mean time
+//! * `QuotaExceeded`: a key could not be created as no key slot was available for the application
+//! * `CanaryKeyUsed`: an operation was requested on a canary key, see the `canary_keys` module
+//!
+//! The event is given as a JSON object to the hook, which is either a local command, receiving it
+//! on its standard input, or an HTTP webhook, receiving it in the body of a `POST` request. Only
+//! plain HTTP is supported, the webhook should hence be a local endpoint. Hooks are run in their
+//! own thread so that they never delay the requests, and their failures are only logged.
+use crate::utils::correlation_id;
+use log::{error, warn};
+use parsec_interface::requests::{ProviderID, ResponseStatus};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+const HTTP_PREFIX: &str = "http://";
+
+/// Kind of event hooks can be notified of
+#[derive(Copy, Clone, Deserialize, Serialize, Debug, PartialEq)]
+pub enum EventKind {
+    KeyCreated,
+    KeyDestroyed,
+    ProviderUnhealthy,
+    QuotaExceeded,
+    CanaryKeyUsed,
+}
+
+/// Configuration of a hook, which must have either a command or a webhook
+#[derive(Clone, Deserialize, Debug)]
+pub struct EventHookConfig {
+    /// Events notified to the hook
+    pub events: Vec<EventKind>,
+    /// Path of the command to run
+    pub command: Option<String>,
+    /// URL of the webhook, starting with `http://`
+    pub webhook: Option<String>,
+}
+
+/// Event notified to the hooks
+#[derive(Clone, Serialize, Debug)]
+pub struct Event {
+    pub kind: EventKind,
+    pub provider: String,
+    pub app_name: Option<String>,
+    pub key_name: Option<String>,
+    pub status: Option<String>,
+    pub opcode: Option<String>,
+    pub correlation_id: Option<String>,
+}
+
+impl Event {
+    /// Creates an event happening in a provider.
+    pub fn new(kind: EventKind, provider_id: ProviderID) -> Event {
+        Event {
+            kind,
+            provider: provider_id.to_string(),
+            app_name: None,
+            key_name: None,
+            status: None,
+            opcode: None,
+            correlation_id: correlation_id::current()
+                .map(|correlation_id| correlation_id.to_string()),
+        }
+    }
+}
+
+/// External system notified, also used by the `key_publisher` module
+#[derive(Clone, Debug)]
+pub(super) enum Target {
+    Command(String),
+    Webhook { address: String, path: String },
+}
+
+#[derive(Clone, Debug)]
+struct EventHook {
+    events: Vec<EventKind>,
+    target: Target,
+}
+
+/// Hooks notified of the events of a provider
+#[derive(Debug, Default)]
+pub struct EventHooks {
+    hooks: Vec<EventHook>,
+    unhealthy: AtomicBool,
+}
+
+impl EventHooks {
+    /// Creates the hooks from their configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if a hook does not have exactly one of a command or
+    /// a webhook, or if the webhook is not a plain HTTP URL.
+    pub fn new(configs: &[EventHookConfig]) -> Result<EventHooks> {
+        let hooks = configs
+            .iter()
+            .map(|config| {
+                let target = match (&config.command, &config.webhook) {
+                    (Some(command), None) => Target::Command(command.clone()),
+                    (None, Some(webhook)) => parse_webhook(webhook)?,
+                    _ => {
+                        error!("An event hook needs either a command or a webhook.");
+                        return Err(Error::new(ErrorKind::InvalidData, "invalid event hook"));
+                    }
+                };
+                Ok(EventHook {
+                    events: config.events.clone(),
+                    target,
+                })
+            })
+            .collect::<Result<Vec<EventHook>>>()?;
+
+        Ok(EventHooks {
+            hooks,
+            unhealthy: AtomicBool::new(false),
+        })
+    }
+
+    /// Notifies the hooks selecting the event.
+    pub fn notify(&self, event: Event) {
+        let targets: Vec<Target> = self
+            .hooks
+            .iter()
+            .filter(|hook| hook.events.contains(&event.kind))
+            .map(|hook| hook.target.clone())
+            .collect();
+        if targets.is_empty() {
+            return;
+        }
+        let payload = match serde_json::to_vec(&event) {
+            Ok(payload) => payload,
+            Err(e) => {
+                format_error!("Failed to serialize an event", e);
+                return;
+            }
+        };
+
+        let _ = thread::spawn(move || {
+            for target in targets {
+                if let Err(e) = run(&target, "POST", &payload) {
+                    format_error!("Failed to run an event hook", e);
+                }
+            }
+        });
+    }
+
+    /// Notifies the hooks that a provider turned unhealthy if the status of the operation says so,
+    /// and remembers that it is healthy again if the operation succeeded.
+    pub fn check_health(&self, provider_id: ProviderID, status: Option<ResponseStatus>) {
+        match status {
+            Some(status)
+                if status == ResponseStatus::PsaErrorHardwareFailure
+                    || status == ResponseStatus::PsaErrorCommunicationFailure =>
+            {
+                if !self.unhealthy.swap(true, Ordering::Relaxed) {
+                    warn!("Provider {} is unhealthy.", provider_id);
+                    let mut event = Event::new(EventKind::ProviderUnhealthy, provider_id);
+                    event.status = Some(status.to_string());
+                    self.notify(event);
+                }
+            }
+            Some(_) => (),
+            None => self.unhealthy.store(false, Ordering::Relaxed),
+        }
+    }
+}
+
+pub(super) fn parse_webhook(webhook: &str) -> Result<Target> {
+    if !webhook.starts_with(HTTP_PREFIX) {
+        error!("Only plain HTTP webhooks are supported.");
+        return Err(Error::new(ErrorKind::InvalidData, "invalid webhook"));
+    }
+    let rest = &webhook[HTTP_PREFIX.len()..];
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        error!("The webhook has no host.");
+        return Err(Error::new(ErrorKind::InvalidData, "invalid webhook"));
+    }
+    let address = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    Ok(Target::Webhook {
+        address,
+        path: path.to_string(),
+    })
+}
+
+/// Gives the JSON payload to the target, in a request of the given method for a webhook.
+pub(super) fn run(target: &Target, method: &str, payload: &[u8]) -> Result<()> {
+    match target {
+        Target::Command(command) => {
+            let mut child = Command::new(command)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .spawn()?;
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(payload)?;
+            }
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("event hook command exited with {}", status),
+                ));
+            }
+        }
+        Target::Webhook { address, path } => {
+            let socket_address = address
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "webhook address not resolved"))?;
+            let mut stream = TcpStream::connect_timeout(&socket_address, WEBHOOK_TIMEOUT)?;
+            stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+            stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+            write!(
+                stream,
+                "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                method,
+                path,
+                address,
+                payload.len()
+            )?;
+            stream.write_all(payload)?;
+            stream.flush()?;
+
+            let mut response = [0; 12];
+            stream.read_exact(&mut response)?;
+            // The status line starts with "HTTP/1.1 2xx" for a success.
+            if response[9] != b'2' {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "webhook answered {}",
+                        String::from_utf8_lossy(&response[9..])
+                    ),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_webhook, EventHookConfig, EventHooks, EventKind, Target};
+
+    #[test]
+    fn webhooks_parsed() {
+        match parse_webhook("http://localhost:8080/events").unwrap() {
+            Target::Webhook { address, path } => {
+                assert_eq!(address, "localhost:8080");
+                assert_eq!(path, "/events");
+            }
+            _ => panic!("expected a webhook"),
+        }
+        match parse_webhook("http://localhost").unwrap() {
+            Target::Webhook { address, path } => {
+                assert_eq!(address, "localhost:80");
+                assert_eq!(path, "/");
+            }
+            _ => panic!("expected a webhook"),
+        }
+        assert!(parse_webhook("https://localhost/events").is_err());
+        assert!(parse_webhook("http:///events").is_err());
+    }
+
+    #[test]
+    fn hook_needs_one_target() {
+        let config = EventHookConfig {
+            events: vec![EventKind::KeyCreated],
+            command: Some(String::from("/bin/true")),
+            webhook: Some(String::from("http://localhost/events")),
+        };
+        assert!(EventHooks::new(&[config]).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Injection of faults at the boundary of a provider, for soak testing
+//!
+//! Long-running reliability tests of the service need the failures met with real backends, such as
+//! a slow or unplugged token, to check how the clients retry, how the event hooks report the health
+//! of the provider and how the failed operations are recorded. When configured for a provider, the
+//! operations passed to it are randomly delayed and failed with `PsaErrorCommunicationFailure`, the
+//! status of an unavailable backend, instead of reaching it.
+//!
+//! The choices are drawn from a generator seeded with the configured seed and the rank of the
+//! operation at the provider: the n-th operation is always disrupted the same way, so that a soak
+//! test sending its requests in the same order meets the same faults. This must never be configured
+//! in production, the service warns when it starts with faults injected.
+use log::{error, warn};
+use parsec_interface::requests::{Opcode, ResponseStatus, Result};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Default maximum latency added to an operation, in milliseconds
+pub const DEFAULT_MAX_LATENCY_MS: u64 = 500;
+
+/// Configuration of the faults injected in the operations of a provider
+#[derive(Clone, Deserialize, Debug)]
+pub struct FaultInjectionConfig {
+    /// Type of the provider whose operations are disrupted
+    pub provider_type: String,
+    /// Seed of the random choices of the faults
+    pub seed: u64,
+    /// Probability for an operation to be delayed, defaults to 0
+    pub latency_probability: Option<f64>,
+    /// Maximum latency added to a delayed operation, in milliseconds, defaults to 500
+    pub max_latency_ms: Option<u64>,
+    /// Probability for an operation to fail, defaults to 0
+    pub failure_probability: Option<f64>,
+    /// Names of the operations disrupted, defaults to all
+    pub opcodes: Option<Vec<String>>,
+}
+
+/// Faults chosen for an operation
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Faults {
+    latency: Option<Duration>,
+    failure: bool,
+}
+
+/// Faults injected in the operations of a provider
+#[derive(Debug)]
+pub struct FaultInjection {
+    seed: u64,
+    latency_probability: f64,
+    max_latency_ms: u64,
+    failure_probability: f64,
+    opcodes: Option<HashSet<Opcode>>,
+    // Number of operations seen, giving the rank of the next one.
+    operations: AtomicU64,
+}
+
+impl FaultInjection {
+    /// Creates the faults of the configuration, injected in the given operations or in all of them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if a probability is not between 0 and 1.
+    pub fn new(
+        config: &FaultInjectionConfig,
+        opcodes: Option<HashSet<Opcode>>,
+    ) -> std::io::Result<FaultInjection> {
+        let latency_probability = config.latency_probability.unwrap_or(0.0);
+        let failure_probability = config.failure_probability.unwrap_or(0.0);
+        for probability in [latency_probability, failure_probability].iter() {
+            if !(0.0..=1.0).contains(probability) {
+                error!(
+                    "The fault injection probability {} is not between 0 and 1.",
+                    probability
+                );
+                return Err(Error::new(ErrorKind::InvalidData, "invalid probability"));
+            }
+        }
+
+        Ok(FaultInjection {
+            seed: config.seed,
+            latency_probability,
+            max_latency_ms: config.max_latency_ms.unwrap_or(DEFAULT_MAX_LATENCY_MS),
+            failure_probability,
+            opcodes,
+            operations: AtomicU64::new(0),
+        })
+    }
+
+    /// Chooses the faults of the operation of the given rank.
+    fn draw(&self, rank: u64) -> Faults {
+        // Seeds close to each other give unrelated sequences, the generator expanding them.
+        let mut rng = SmallRng::seed_from_u64(self.seed ^ rank.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let latency = if rng.gen_bool(self.latency_probability) {
+            Some(Duration::from_millis(
+                rng.gen_range(0, self.max_latency_ms + 1),
+            ))
+        } else {
+            None
+        };
+
+        Faults {
+            latency,
+            failure: rng.gen_bool(self.failure_probability),
+        }
+    }
+
+    /// Delays the operation and makes it fail if chosen to, before it reaches the provider.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorCommunicationFailure` if the operation is chosen to fail.
+    pub fn inject(&self, opcode: Opcode) -> Result<()> {
+        if let Some(opcodes) = &self.opcodes {
+            if !opcodes.contains(&opcode) {
+                return Ok(());
+            }
+        }
+        let rank = self.operations.fetch_add(1, Ordering::Relaxed);
+        let faults = self.draw(rank);
+        if let Some(latency) = faults.latency {
+            thread::sleep(latency);
+        }
+        if faults.failure {
+            warn!("Injected a failure in operation {} ({:?}).", rank, opcode);
+            return Err(ResponseStatus::PsaErrorCommunicationFailure);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FaultInjection, FaultInjectionConfig, Faults};
+    use parsec_interface::requests::{Opcode, ResponseStatus};
+    use std::time::Duration;
+
+    fn config(seed: u64) -> FaultInjectionConfig {
+        FaultInjectionConfig {
+            provider_type: String::from("Pkcs11"),
+            seed,
+            latency_probability: Some(0.5),
+            max_latency_ms: Some(10),
+            failure_probability: Some(0.5),
+            opcodes: None,
+        }
+    }
+
+    #[test]
+    fn faults_reproducible() {
+        let first = FaultInjection::new(&config(42), None).unwrap();
+        let second = FaultInjection::new(&config(42), None).unwrap();
+        let other_seed = FaultInjection::new(&config(43), None).unwrap();
+        let draws = |faults: &FaultInjection| -> Vec<Faults> {
+            (0..64).map(|rank| faults.draw(rank)).collect()
+        };
+        let first_draws = draws(&first);
+
+        assert_eq!(first_draws, draws(&second));
+        assert_ne!(first_draws, draws(&other_seed));
+        assert!(first_draws.iter().any(|faults| faults.failure));
+        assert!(first_draws.iter().any(|faults| !faults.failure));
+        assert!(first_draws
+            .iter()
+            .filter_map(|faults| faults.latency)
+            .all(|latency| latency <= Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn probabilities_checked() {
+        let mut config = config(42);
+        config.failure_probability = Some(1.0);
+        config.latency_probability = None;
+        let faults = FaultInjection::new(
+            &config,
+            Some([Opcode::PsaSignHash].iter().copied().collect()),
+        )
+        .unwrap();
+        assert_eq!(
+            faults.inject(Opcode::PsaSignHash),
+            Err(ResponseStatus::PsaErrorCommunicationFailure)
+        );
+        assert_eq!(faults.inject(Opcode::Ping), Ok(()));
+
+        config.failure_probability = Some(1.5);
+        assert!(FaultInjection::new(&config, None).is_err());
+    }
+}
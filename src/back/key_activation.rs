@@ -0,0 +1,328 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Activation of the keys created pre-active
+//!
+//! Key management systems commonly let keys be created before they are put in service: the key is
+//! provisioned, its public part exported and certified, and it is only activated once the rest of
+//! the deployment is ready, possibly by an administrator. When configured for a provider, the keys
+//! created by the applications selected are pre-active: they can be exported, if their policy
+//! allows it, and destroyed, but they can not be used for any cryptographic operation until they
+//! are activated.
+//!
+//! The state is stored with the key information in the Key Info Manager, so that a pre-active key
+//! stays so across restarts and even if the configuration is removed: the state is checked for all
+//! the providers storing their keys locally. Keys being created are considered pre-active until
+//! their state is stored. Peer keys, only usable to verify signatures, are always active.
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::key_info_store::KeyInfoStore;
+use crate::key_info_managers::{self, KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+use derivative::Derivative;
+use log::{error, info};
+use parsec_interface::requests::{ResponseStatus, Result};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// Configuration of the keys created pre-active in a provider
+#[derive(Clone, Deserialize, Debug)]
+pub struct KeyActivationConfig {
+    /// Type of the provider ("MbedCrypto", "Pkcs11" or "Tpm")
+    pub provider_type: String,
+    /// Names of the applications whose new keys are pre-active, defaults to all
+    pub app_names: Option<Vec<String>>,
+    /// If only the administrators can activate the keys, instead of their owner too, defaults to
+    /// false
+    pub admin_only: Option<bool>,
+}
+
+/// States of the keys of a provider
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct KeyActivation {
+    // Applications whose new keys are pre-active, all of them if not set.
+    app_names: Option<HashSet<String>>,
+    creates_pre_active: bool,
+    admin_only: bool,
+    #[derivative(Debug = "ignore")]
+    key_info_store: Arc<KeyInfoStore>,
+    // Keys being created pre-active, whose state is not stored yet.
+    creating: Mutex<HashSet<KeyTriple>>,
+}
+
+/// Keys being created pre-active, which can not be used until the guard is dropped
+#[derive(Debug)]
+pub struct PreActiveCreation<'a> {
+    key_activation: &'a KeyActivation,
+    key_triples: Vec<KeyTriple>,
+}
+
+impl PreActiveCreation<'_> {
+    /// Stores the pre-active state of a key once it is created.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KeyInfoManagerError` if the state could not be stored, in which case the key must
+    /// be destroyed as it would otherwise be active.
+    pub fn set_pre_active(&self, key_triple: &KeyTriple) -> Result<()> {
+        self.key_activation
+            .set_state(key_triple, KeyState::PreActive)
+            .map(|_| ())
+    }
+}
+
+impl Drop for PreActiveCreation<'_> {
+    fn drop(&mut self) {
+        let mut creating = self
+            .key_activation
+            .creating
+            .lock()
+            .expect("Key activation lock poisoned");
+        for key_triple in &self.key_triples {
+            let _ = creating.remove(key_triple);
+        }
+    }
+}
+
+impl KeyActivation {
+    /// Creates the states of the keys of a provider, stored in the given Key Info Manager. Keys
+    /// are only created pre-active if a configuration is given.
+    pub fn new(
+        config: Option<&KeyActivationConfig>,
+        key_info_store: Arc<KeyInfoStore>,
+    ) -> KeyActivation {
+        KeyActivation {
+            app_names: config
+                .and_then(|config| config.app_names.clone())
+                .map(|app_names| app_names.into_iter().collect()),
+            creates_pre_active: config.is_some(),
+            admin_only: config.and_then(|config| config.admin_only).unwrap_or(false),
+            key_info_store,
+            creating: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Checks if the new keys of the application are created pre-active.
+    pub fn creates_pre_active(&self, app_name: &ApplicationName) -> bool {
+        self.creates_pre_active
+            && self
+                .app_names
+                .as_ref()
+                .map_or(true, |app_names| app_names.contains(app_name.get_name()))
+    }
+
+    /// Considers the keys pre-active while they are being created, until the returned guard is
+    /// dropped.
+    pub fn begin_creation(&self, key_triples: Vec<KeyTriple>) -> PreActiveCreation<'_> {
+        let mut creating = self.creating.lock().expect("Key activation lock poisoned");
+        for key_triple in &key_triples {
+            let _ = creating.insert(key_triple.clone());
+        }
+
+        PreActiveCreation {
+            key_activation: self,
+            key_triples,
+        }
+    }
+
+    // Stores the state of a key, returning the previous one.
+    fn set_state(&self, key_triple: &KeyTriple, state: KeyState) -> Result<KeyState> {
+        let mut store_handle = self.key_info_store.write();
+        let key_info = store_handle
+            .get(key_triple)
+            .map_err(key_info_managers::to_response_status)?
+            .cloned()
+            .ok_or_else(|| {
+                error!("The key to change the state of does not exist.");
+                ResponseStatus::PsaErrorDoesNotExist
+            })?;
+        let previous_state = key_info.state;
+        if previous_state != state {
+            let _ = store_handle
+                .insert(key_triple.clone(), KeyInfo { state, ..key_info })
+                .map_err(key_info_managers::to_response_status)?;
+        }
+
+        Ok(previous_state)
+    }
+
+    /// Checks that the key can be used for cryptographic operations.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the key is pre-active or being created pre-active.
+    pub fn check_active(&self, key_triple: &KeyTriple) -> Result<()> {
+        let is_creating = self
+            .creating
+            .lock()
+            .expect("Key activation lock poisoned")
+            .contains(key_triple);
+        // Keys without information are left to the provider to report.
+        let state = match self.key_info_store.read().get(key_triple) {
+            Ok(Some(key_info)) => key_info.state,
+            _ => KeyState::Active,
+        };
+        if is_creating || state == KeyState::PreActive {
+            error!("The key is pre-active, it needs to be activated before being used.");
+            return Err(ResponseStatus::PsaErrorNotPermitted);
+        }
+
+        Ok(())
+    }
+
+    /// Activates a pre-active key on behalf of the given application. Activating an active key has
+    /// no effect.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the application is neither an administrator nor, unless
+    /// only the administrators can activate the keys, the owner of the key, and
+    /// `PsaErrorDoesNotExist` if the key does not exist.
+    pub fn activate(
+        &self,
+        app_name: &ApplicationName,
+        is_admin: bool,
+        key_triple: &KeyTriple,
+    ) -> Result<()> {
+        if !is_admin && (self.admin_only || app_name != key_triple.app_name()) {
+            error!("The application is not allowed to activate the key.");
+            return Err(ResponseStatus::PsaErrorNotPermitted);
+        }
+        if self
+            .creating
+            .lock()
+            .expect("Key activation lock poisoned")
+            .contains(key_triple)
+        {
+            error!("The key to activate is still being created.");
+            return Err(ResponseStatus::PsaErrorDoesNotExist);
+        }
+        if self.set_state(key_triple, KeyState::Active)? == KeyState::PreActive {
+            if crate::utils::GlobalConfig::log_error_details() {
+                info!("Activated key ({}).", key_triple);
+            } else {
+                info!("Activated a pre-active key.");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "memory-manager"))]
+mod test {
+    use super::{KeyActivation, KeyActivationConfig};
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::key_info_store::KeyInfoStore;
+    use crate::key_info_managers::memory_manager::MemoryKeyInfoManager;
+    use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+    use parsec_interface::operations::psa_algorithm::{Algorithm, Cipher};
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::{ProviderID, ResponseStatus};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    fn test_key_info() -> KeyInfo {
+        KeyInfo {
+            id: vec![0x11, 0x22, 0x33],
+            attributes: Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::Aes,
+                bits: 128,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: false,
+                        verify_hash: false,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: true,
+                        decrypt: true,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::Cipher(Cipher::Ctr),
+                },
+            },
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    fn key_activation(admin_only: bool) -> (KeyActivation, Arc<KeyInfoStore>) {
+        let store = Arc::new(KeyInfoStore::new(Box::new(MemoryKeyInfoManager::new())).unwrap());
+        let config = KeyActivationConfig {
+            provider_type: String::from("Pkcs11"),
+            app_names: Some(vec![String::from("app")]),
+            admin_only: Some(admin_only),
+        };
+
+        (KeyActivation::new(Some(&config), store.clone()), store)
+    }
+
+    #[test]
+    fn pre_active_key_activated_by_owner() {
+        let (key_activation, store) = key_activation(false);
+        let app = ApplicationName::new(String::from("app"));
+        let other = ApplicationName::new(String::from("other"));
+        let key_triple = KeyTriple::new(app.clone(), ProviderID::Pkcs11, String::from("key"));
+        assert!(key_activation.creates_pre_active(&app));
+        assert!(!key_activation.creates_pre_active(&other));
+
+        let creation = key_activation.begin_creation(vec![key_triple.clone()]);
+        assert_eq!(
+            key_activation.check_active(&key_triple),
+            Err(ResponseStatus::PsaErrorNotPermitted)
+        );
+        let _ = store
+            .write()
+            .insert(key_triple.clone(), test_key_info())
+            .unwrap();
+        creation.set_pre_active(&key_triple).unwrap();
+        drop(creation);
+        assert_eq!(
+            key_activation.check_active(&key_triple),
+            Err(ResponseStatus::PsaErrorNotPermitted)
+        );
+
+        assert_eq!(
+            key_activation.activate(&other, false, &key_triple),
+            Err(ResponseStatus::PsaErrorNotPermitted)
+        );
+        key_activation.activate(&app, false, &key_triple).unwrap();
+        assert_eq!(key_activation.check_active(&key_triple), Ok(()));
+        assert_eq!(
+            store.read().get(&key_triple).unwrap().unwrap().state,
+            KeyState::Active
+        );
+    }
+
+    #[test]
+    fn admin_only_activation() {
+        let (key_activation, store) = key_activation(true);
+        let app = ApplicationName::new(String::from("app"));
+        let admin = ApplicationName::new(String::from("admin"));
+        let key_triple = KeyTriple::new(app.clone(), ProviderID::Pkcs11, String::from("key"));
+        let mut key_info = test_key_info();
+        key_info.state = KeyState::PreActive;
+        let _ = store.write().insert(key_triple.clone(), key_info).unwrap();
+
+        assert_eq!(
+            key_activation.activate(&app, false, &key_triple),
+            Err(ResponseStatus::PsaErrorNotPermitted)
+        );
+        key_activation.activate(&admin, true, &key_triple).unwrap();
+        assert_eq!(key_activation.check_active(&key_triple), Ok(()));
+
+        let missing = KeyTriple::new(app, ProviderID::Pkcs11, String::from("missing"));
+        assert_eq!(
+            key_activation.activate(&admin, true, &missing),
+            Err(ResponseStatus::PsaErrorDoesNotExist)
+        );
+    }
+}
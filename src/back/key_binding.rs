@@ -0,0 +1,269 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Binding of keys to the client process that created them
+//!
+//! The keys of the applications listed in the `process_bound_apps` core setting can only be used
+//! by the client process that created them. Processes are identified by the peer credentials of
+//! their connection and by their start time, to not be fooled by PID reuse. Compromised
+//! credentials of such an application can then not be used to access its keys from another
+//! process. As the Parsec interface has no key attribute to request this policy, it is applied to
+//! all the keys of an application.
+//!
+//! Bindings are only kept in memory. Keys left from a previous run of the service, or from before
+//! a configuration reload, are not usable any more and can only be destroyed.
+use crate::authenticators::ApplicationName;
+use crate::front::listener::ConnectionMetadata;
+use crate::key_info_managers::KeyTriple;
+use log::error;
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::RwLock;
+
+/// Identity of a client process
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct ClientProcess {
+    pid: i32,
+    // Start time of the process after boot, in clock ticks.
+    start_time: u64,
+}
+
+impl ClientProcess {
+    fn from_metadata(metadata: Option<ConnectionMetadata>) -> Option<ClientProcess> {
+        let pid = match metadata? {
+            ConnectionMetadata::UnixPeerCredentials { pid, .. } => pid,
+            // Remote clients have no process on this host to be bound to.
+            ConnectionMetadata::TlsClientCertificate { .. }
+            | ConnectionMetadata::VsockPeer { .. } => return None,
+        };
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // The second field is the executable name between parenthesis, which can contain spaces.
+        // The start time is the 22nd field, hence the 20th after the executable name.
+        let start_time = stat
+            .get(stat.rfind(')')? + 1..)?
+            .split_whitespace()
+            .nth(19)?
+            .parse()
+            .ok()?;
+
+        Some(ClientProcess { pid, start_time })
+    }
+}
+
+/// Binding of a key to a client process, to be recorded once the key has been created
+#[derive(Debug)]
+pub struct KeyBinding {
+    key_triple: KeyTriple,
+    client: ClientProcess,
+}
+
+/// Record of the client processes keys are bound to
+#[derive(Debug, Default)]
+pub struct KeyBindings {
+    bound_apps: HashSet<String>,
+    bindings: RwLock<HashMap<KeyTriple, ClientProcess>>,
+}
+
+impl KeyBindings {
+    /// Creates an empty record, for the keys of the given applications.
+    pub fn new(bound_apps: Vec<String>) -> KeyBindings {
+        KeyBindings {
+            bound_apps: bound_apps.into_iter().collect(),
+            bindings: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn is_bound(&self, app_name: &ApplicationName) -> bool {
+        self.bound_apps.contains(app_name.get_name())
+    }
+
+    /// Checks if a key about to be created needs to be bound and returns the binding to record
+    /// once it is.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the key needs to be bound but the client process can not
+    /// be identified.
+    pub fn new_binding(
+        &self,
+        app_name: &ApplicationName,
+        provider_id: ProviderID,
+        key_name: &str,
+        metadata: Option<ConnectionMetadata>,
+    ) -> Result<Option<KeyBinding>> {
+        if !self.is_bound(app_name) {
+            return Ok(None);
+        }
+
+        let client = ClientProcess::from_metadata(metadata).ok_or_else(|| {
+            error!("The client process can not be identified to bind the key to.");
+            ResponseStatus::PsaErrorNotPermitted
+        })?;
+
+        Ok(Some(KeyBinding {
+            key_triple: KeyTriple::new(app_name.clone(), provider_id, key_name.to_string()),
+            client,
+        }))
+    }
+
+    /// Records a key binding.
+    pub fn bind(&self, binding: KeyBinding) {
+        let _ = self
+            .bindings
+            .write()
+            .expect("Key bindings lock poisoned")
+            .insert(binding.key_triple, binding.client);
+    }
+
+    /// Removes the binding of a key, if any.
+    pub fn unbind(&self, app_name: &ApplicationName, provider_id: ProviderID, key_name: &str) {
+        let key_triple = KeyTriple::new(app_name.clone(), provider_id, key_name.to_string());
+        let _ = self
+            .bindings
+            .write()
+            .expect("Key bindings lock poisoned")
+            .remove(&key_triple);
+    }
+
+    /// Checks that the client process is allowed to use a key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the key is bound to another process or not bound at all.
+    pub fn check_use(
+        &self,
+        app_name: &ApplicationName,
+        provider_id: ProviderID,
+        key_name: &str,
+        metadata: Option<ConnectionMetadata>,
+    ) -> Result<()> {
+        self.check(app_name, provider_id, key_name, metadata, false)
+    }
+
+    /// Checks that the client process is allowed to destroy a key. Keys which are not bound, for
+    /// example because they were created by a previous run of the service, can be destroyed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the key is bound to another process.
+    pub fn check_destroy(
+        &self,
+        app_name: &ApplicationName,
+        provider_id: ProviderID,
+        key_name: &str,
+        metadata: Option<ConnectionMetadata>,
+    ) -> Result<()> {
+        self.check(app_name, provider_id, key_name, metadata, true)
+    }
+
+    fn check(
+        &self,
+        app_name: &ApplicationName,
+        provider_id: ProviderID,
+        key_name: &str,
+        metadata: Option<ConnectionMetadata>,
+        allow_unbound: bool,
+    ) -> Result<()> {
+        if !self.is_bound(app_name) {
+            return Ok(());
+        }
+
+        let key_triple = KeyTriple::new(app_name.clone(), provider_id, key_name.to_string());
+        let bindings = self.bindings.read().expect("Key bindings lock poisoned");
+        match bindings.get(&key_triple) {
+            Some(owner) if Some(*owner) == ClientProcess::from_metadata(metadata) => Ok(()),
+            None if allow_unbound => Ok(()),
+            _ => {
+                if crate::utils::GlobalConfig::log_error_details() {
+                    error!(
+                        "Key {} is not bound to the client process, access denied.",
+                        key_triple
+                    );
+                } else {
+                    error!("Key is not bound to the client process, access denied.");
+                }
+                Err(ResponseStatus::PsaErrorNotPermitted)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KeyBindings;
+    use crate::authenticators::ApplicationName;
+    use crate::front::listener::ConnectionMetadata;
+    use parsec_interface::requests::{ProviderID, ResponseStatus};
+
+    fn own_metadata() -> Option<ConnectionMetadata> {
+        Some(ConnectionMetadata::UnixPeerCredentials {
+            uid: 0,
+            gid: 0,
+            pid: std::process::id() as i32,
+        })
+    }
+
+    #[test]
+    fn unbound_app_is_not_checked() {
+        let key_bindings = KeyBindings::new(vec![String::from("bound app")]);
+        let app_name = ApplicationName::new(String::from("other app"));
+
+        assert!(key_bindings
+            .new_binding(&app_name, ProviderID::MbedCrypto, "key", None)
+            .unwrap()
+            .is_none());
+        key_bindings
+            .check_use(&app_name, ProviderID::MbedCrypto, "key", None)
+            .unwrap();
+    }
+
+    #[test]
+    fn bound_key_only_usable_by_owner() {
+        let key_bindings = KeyBindings::new(vec![String::from("bound app")]);
+        let app_name = ApplicationName::new(String::from("bound app"));
+
+        let binding = key_bindings
+            .new_binding(&app_name, ProviderID::MbedCrypto, "key", own_metadata())
+            .unwrap()
+            .expect("The key should be bound");
+        key_bindings.bind(binding);
+
+        key_bindings
+            .check_use(&app_name, ProviderID::MbedCrypto, "key", own_metadata())
+            .unwrap();
+        assert_eq!(
+            key_bindings
+                .check_use(&app_name, ProviderID::MbedCrypto, "key", None)
+                .unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        assert_eq!(
+            key_bindings
+                .check_destroy(&app_name, ProviderID::MbedCrypto, "key", None)
+                .unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+    }
+
+    #[test]
+    fn unbound_key_of_bound_app() {
+        let key_bindings = KeyBindings::new(vec![String::from("bound app")]);
+        let app_name = ApplicationName::new(String::from("bound app"));
+
+        assert_eq!(
+            key_bindings
+                .new_binding(&app_name, ProviderID::MbedCrypto, "key", None)
+                .unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        assert_eq!(
+            key_bindings
+                .check_use(&app_name, ProviderID::MbedCrypto, "key", own_metadata())
+                .unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        key_bindings
+            .check_destroy(&app_name, ProviderID::MbedCrypto, "key", own_metadata())
+            .unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Certificates stored with the key information
+//!
+//! The PKCS 11 provider stores the certificate of a key on its token, next to the key. The other
+//! providers can not store certificates: theirs are stored with the key information in the Key Info
+//! Manager instead, so that they live and go away with the key as well.
+//!
+//! The certificates stored this way are not protected by the provider and are as trusted as the
+//! mappings themselves: they are public, but an attacker able to modify the mappings can replace
+//! them.
+use crate::key_info_managers::key_info_store::KeyInfoStore;
+use crate::key_info_managers::{self, KeyInfo, KeyTriple, ManageKeyInfo};
+use derivative::Derivative;
+use log::error;
+use parsec_interface::requests::{ResponseStatus, Result};
+use picky::x509::Cert;
+use std::sync::Arc;
+
+/// Certificates of the keys of a provider
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct KeyCertificates {
+    #[derivative(Debug = "ignore")]
+    key_info_store: Arc<KeyInfoStore>,
+}
+
+impl KeyCertificates {
+    /// Creates the certificates of the keys stored in the given Key Info Manager.
+    pub fn new(key_info_store: Arc<KeyInfoStore>) -> KeyCertificates {
+        KeyCertificates { key_info_store }
+    }
+
+    // Stores the new certificate of a key, or removes it.
+    fn set(&self, key_triple: &KeyTriple, certificate: Option<Vec<u8>>) -> Result<()> {
+        let mut store_handle = self.key_info_store.write();
+        let key_info = store_handle
+            .get(key_triple)
+            .map_err(key_info_managers::to_response_status)?
+            .cloned()
+            .ok_or_else(|| {
+                error!("The key of the certificate does not exist.");
+                ResponseStatus::PsaErrorDoesNotExist
+            })?;
+        let _ = store_handle
+            .insert(
+                key_triple.clone(),
+                KeyInfo {
+                    certificate,
+                    ..key_info
+                },
+            )
+            .map_err(key_info_managers::to_response_status)?;
+
+        Ok(())
+    }
+
+    /// Stores a DER-encoded X.509 certificate with a key, replacing any certificate previously
+    /// stored with it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInvalidArgument` if the certificate is not a DER-encoded X.509 certificate
+    /// and `PsaErrorDoesNotExist` if the key does not exist.
+    pub fn store(&self, key_triple: &KeyTriple, certificate: Vec<u8>) -> Result<()> {
+        if let Err(e) = Cert::from_der(&certificate) {
+            format_error!(
+                "The certificate is not a valid DER-encoded X.509 certificate",
+                e
+            );
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+
+        self.set(key_triple, Some(certificate))
+    }
+
+    /// Gets the certificate stored with a key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorDoesNotExist` if the key does not exist or has no certificate.
+    pub fn get(&self, key_triple: &KeyTriple) -> Result<Vec<u8>> {
+        self.key_info_store
+            .read()
+            .get(key_triple)
+            .map_err(key_info_managers::to_response_status)?
+            .and_then(|key_info| key_info.certificate.clone())
+            .ok_or_else(|| {
+                error!("The key does not have a certificate.");
+                ResponseStatus::PsaErrorDoesNotExist
+            })
+    }
+
+    /// Deletes the certificate stored with a key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorDoesNotExist` if the key does not exist or has no certificate.
+    pub fn delete(&self, key_triple: &KeyTriple) -> Result<()> {
+        let _ = self.get(key_triple)?;
+
+        self.set(key_triple, None)
+    }
+}
+
+#[cfg(all(test, feature = "memory-manager", feature = "device-identity"))]
+mod test {
+    use super::KeyCertificates;
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::key_info_store::KeyInfoStore;
+    use crate::key_info_managers::memory_manager::MemoryKeyInfoManager;
+    use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+    use crate::utils::enrollment::der;
+    use parsec_interface::operations::psa_algorithm::{Algorithm, Cipher};
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::{ProviderID, ResponseStatus};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    fn test_key_info() -> KeyInfo {
+        KeyInfo {
+            id: vec![0x11, 0x22, 0x33],
+            attributes: Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::Aes,
+                bits: 128,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: false,
+                        verify_hash: false,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: true,
+                        decrypt: true,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::Cipher(Cipher::Ctr),
+                },
+            },
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn certificate_stored_with_key() {
+        let store = Arc::new(KeyInfoStore::new(Box::new(MemoryKeyInfoManager::new())).unwrap());
+        let key_certificates = KeyCertificates::new(store.clone());
+        let key_triple = KeyTriple::new(
+            ApplicationName::new(String::from("app")),
+            ProviderID::Tpm,
+            String::from("key"),
+        );
+        let not_before = SystemTime::UNIX_EPOCH;
+        let tbs_certificate = der::tbs_certificate(
+            &[0x04; 65],
+            "key",
+            &[1],
+            not_before,
+            not_before + Duration::from_secs(60),
+        );
+        let certificate = der::certificate(&tbs_certificate, &[0; 64]);
+
+        assert_eq!(
+            key_certificates.store(&key_triple, certificate.clone()),
+            Err(ResponseStatus::PsaErrorDoesNotExist)
+        );
+        let _ = store
+            .write()
+            .insert(key_triple.clone(), test_key_info())
+            .unwrap();
+        assert_eq!(
+            key_certificates.store(&key_triple, vec![0x30, 0x00]),
+            Err(ResponseStatus::PsaErrorInvalidArgument)
+        );
+        assert_eq!(
+            key_certificates.get(&key_triple),
+            Err(ResponseStatus::PsaErrorDoesNotExist)
+        );
+
+        key_certificates
+            .store(&key_triple, certificate.clone())
+            .unwrap();
+        assert_eq!(key_certificates.get(&key_triple), Ok(certificate));
+        key_certificates.delete(&key_triple).unwrap();
+        assert_eq!(
+            key_certificates.delete(&key_triple),
+            Err(ResponseStatus::PsaErrorDoesNotExist)
+        );
+        // The key itself is kept.
+        assert_eq!(
+            store.read().get(&key_triple).unwrap().unwrap().id,
+            test_key_info().id
+        );
+    }
+}
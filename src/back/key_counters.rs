@@ -0,0 +1,279 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Monotonic counters bound to keys
+//!
+//! Some protocols need a counter which only ever increases, bound to a key: anti-rollback schemes
+//! sign the counter along with the state they protect and stateful signature modes must never use
+//! a value twice. A counter is created for a key by its owner and stored with the key information
+//! in the Key Info Manager, so that it lives and goes away with the key.
+//!
+//! The counter is incremented and the key signs with its new value in a single operation of the
+//! back end. The new value is persisted before the signature is made: a crash can skip a value but
+//! never lets one be signed twice. The on-disk manager replaces the mapping of the keys with a
+//! counter atomically and the SQLite manager commits it synchronously; the counters of the keys
+//! stored in memory do not survive a restart.
+//!
+//! The hash signed is the one of the value of the counter, as 8 big-endian bytes, followed by the
+//! data given. There is no opcode for these operations in the wire protocol: they are only
+//! available to the service itself until they are added to `parsec-interface`.
+use crate::key_info_managers::key_info_store::KeyInfoStore;
+use crate::key_info_managers::{self, KeyInfo, KeyTriple, ManageKeyInfo};
+use derivative::Derivative;
+use log::error;
+use parsec_interface::operations::psa_algorithm::{AsymmetricSignature, Hash, SignHash};
+use parsec_interface::requests::{ResponseStatus, Result};
+use ring::digest::{self, SHA256, SHA384, SHA512};
+use std::sync::Arc;
+
+/// Counters of the keys of a provider
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct KeyCounters {
+    #[derivative(Debug = "ignore")]
+    key_info_store: Arc<KeyInfoStore>,
+}
+
+impl KeyCounters {
+    /// Creates the counters of the keys stored in the given Key Info Manager.
+    pub fn new(key_info_store: Arc<KeyInfoStore>) -> KeyCounters {
+        KeyCounters { key_info_store }
+    }
+
+    // Stores the new counter of a key given the current one, returning the value stored.
+    fn update(
+        &self,
+        key_triple: &KeyTriple,
+        update: impl FnOnce(Option<u64>) -> Result<u64>,
+    ) -> Result<u64> {
+        let mut store_handle = self.key_info_store.write();
+        let key_info = store_handle
+            .get(key_triple)
+            .map_err(key_info_managers::to_response_status)?
+            .cloned()
+            .ok_or_else(|| {
+                error!("The key of the counter does not exist.");
+                ResponseStatus::PsaErrorDoesNotExist
+            })?;
+        let counter = update(key_info.counter)?;
+        let _ = store_handle
+            .insert(
+                key_triple.clone(),
+                KeyInfo {
+                    counter: Some(counter),
+                    ..key_info
+                },
+            )
+            .map_err(key_info_managers::to_response_status)?;
+
+        Ok(counter)
+    }
+
+    /// Creates the counter of a key, starting at 0.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorDoesNotExist` if the key does not exist and `PsaErrorAlreadyExists` if it
+    /// already has a counter.
+    pub fn create(&self, key_triple: &KeyTriple) -> Result<()> {
+        let _ = self.update(key_triple, |counter| match counter {
+            Some(_) => {
+                error!("The key already has a counter.");
+                Err(ResponseStatus::PsaErrorAlreadyExists)
+            }
+            None => Ok(0),
+        })?;
+
+        Ok(())
+    }
+
+    /// Increments the counter of a key and returns its new value, once it is persisted.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorDoesNotExist` if the key or its counter does not exist and
+    /// `PsaErrorBadState` if the counter reached its maximum value.
+    pub fn increment(&self, key_triple: &KeyTriple) -> Result<u64> {
+        self.update(key_triple, |counter| {
+            let counter = counter.ok_or_else(|| {
+                error!("The key has no counter.");
+                ResponseStatus::PsaErrorDoesNotExist
+            })?;
+            counter.checked_add(1).ok_or_else(|| {
+                error!("The counter of the key reached its maximum value.");
+                ResponseStatus::PsaErrorBadState
+            })
+        })
+    }
+
+    /// Gets the current value of the counter of a key, if it has one.
+    pub fn value(&self, key_triple: &KeyTriple) -> Option<u64> {
+        match self.key_info_store.read().get(key_triple) {
+            Ok(Some(key_info)) => key_info.counter,
+            _ => None,
+        }
+    }
+}
+
+/// Computes the hash to sign for the value of a counter and the data given, with the hash
+/// algorithm of the signature algorithm.
+///
+/// # Errors
+///
+/// Returns `PsaErrorNotSupported` if the signature algorithm does not specify a hash algorithm
+/// supported.
+pub fn counter_hash(alg: AsymmetricSignature, counter: u64, data: &[u8]) -> Result<Vec<u8>> {
+    let hash_alg = match alg {
+        AsymmetricSignature::RsaPkcs1v15Sign { hash_alg }
+        | AsymmetricSignature::RsaPss { hash_alg }
+        | AsymmetricSignature::Ecdsa { hash_alg }
+        | AsymmetricSignature::DeterministicEcdsa { hash_alg } => hash_alg,
+        _ => {
+            error!("The counters can only be signed with an algorithm hashing the data.");
+            return Err(ResponseStatus::PsaErrorNotSupported);
+        }
+    };
+    let algorithm = match hash_alg {
+        SignHash::Specific(Hash::Sha256) => &SHA256,
+        SignHash::Specific(Hash::Sha384) => &SHA384,
+        SignHash::Specific(Hash::Sha512) => &SHA512,
+        _ => {
+            error!("The counters can only be signed with SHA-256, SHA-384 or SHA-512.");
+            return Err(ResponseStatus::PsaErrorNotSupported);
+        }
+    };
+    let mut context = digest::Context::new(algorithm);
+    context.update(&counter.to_be_bytes());
+    context.update(data);
+
+    Ok(context.finish().as_ref().to_vec())
+}
+
+#[cfg(all(test, feature = "memory-manager"))]
+mod test {
+    use super::{counter_hash, KeyCounters};
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::key_info_store::KeyInfoStore;
+    use crate::key_info_managers::memory_manager::MemoryKeyInfoManager;
+    use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+    use parsec_interface::operations::psa_algorithm::{
+        Algorithm, AsymmetricSignature, Hash, SignHash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, EccFamily, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::{ProviderID, ResponseStatus};
+    use ring::digest::{digest, SHA256};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    fn test_key_info() -> KeyInfo {
+        KeyInfo {
+            id: vec![0x11, 0x22, 0x33],
+            attributes: Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::EccKeyPair {
+                    curve_family: EccFamily::SecpR1,
+                },
+                bits: 256,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: true,
+                        verify_hash: false,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: false,
+                        decrypt: false,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::AsymmetricSignature(
+                        AsymmetricSignature::Ecdsa {
+                            hash_alg: SignHash::Specific(Hash::Sha256),
+                        },
+                    ),
+                },
+            },
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn counter_incremented() {
+        let store = Arc::new(KeyInfoStore::new(Box::new(MemoryKeyInfoManager::new())).unwrap());
+        let key_counters = KeyCounters::new(store.clone());
+        let key_triple = KeyTriple::new(
+            ApplicationName::new(String::from("app")),
+            ProviderID::Tpm,
+            String::from("key"),
+        );
+        assert_eq!(
+            key_counters.create(&key_triple),
+            Err(ResponseStatus::PsaErrorDoesNotExist)
+        );
+
+        let _ = store
+            .write()
+            .insert(key_triple.clone(), test_key_info())
+            .unwrap();
+        assert_eq!(
+            key_counters.increment(&key_triple),
+            Err(ResponseStatus::PsaErrorDoesNotExist)
+        );
+        key_counters.create(&key_triple).unwrap();
+        assert_eq!(
+            key_counters.create(&key_triple),
+            Err(ResponseStatus::PsaErrorAlreadyExists)
+        );
+        assert_eq!(key_counters.increment(&key_triple), Ok(1));
+        assert_eq!(key_counters.increment(&key_triple), Ok(2));
+
+        // Other modifications of the mapping keep the counter.
+        let _ = store
+            .write()
+            .insert(
+                key_triple.clone(),
+                KeyInfo {
+                    state: KeyState::PreActive,
+                    ..test_key_info()
+                },
+            )
+            .unwrap();
+        assert_eq!(key_counters.value(&key_triple), Some(2));
+
+        let _ = store
+            .write()
+            .insert(
+                key_triple.clone(),
+                KeyInfo {
+                    counter: Some(u64::MAX),
+                    ..test_key_info()
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            key_counters.increment(&key_triple),
+            Err(ResponseStatus::PsaErrorBadState)
+        );
+    }
+
+    #[test]
+    fn counter_hashed_first() {
+        let alg = AsymmetricSignature::Ecdsa {
+            hash_alg: SignHash::Specific(Hash::Sha256),
+        };
+        assert_eq!(
+            counter_hash(alg, 258, b"state").unwrap(),
+            digest(&SHA256, b"\0\0\0\0\0\0\x01\x02state").as_ref()
+        );
+        assert_eq!(
+            counter_hash(AsymmetricSignature::EcdsaAny, 1, b"state"),
+            Err(ResponseStatus::PsaErrorNotSupported)
+        );
+    }
+}
@@ -0,0 +1,372 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Restriction of the keys applications can create
+//!
+//! Some keys are expensive to create or use scarce resources of a hardware provider, for example
+//! 4096 bits RSA keys in a TPM. Key creation rules, evaluated for all providers before the
+//! operation is passed on, make sure that only the applications of some groups can create them,
+//! so that an application can not monopolize the hardware.
+//!
+//! Key size rules set the minimum size of the RSA and ECC keys, key pairs or public keys, created
+//! or imported in a provider, for example 3072 bits RSA keys in a hardware security module and 2048
+//! bits in software. They apply to all applications, whatever their groups. Keys imported without
+//! their size, to be derived from the key data, are refused by the rules applying to them, as
+//! their size can not be checked beforehand.
+use crate::authenticators::ApplicationName;
+use crate::providers::provider_id_from_type;
+use log::error;
+use parsec_interface::operations::psa_key_attributes::{Attributes, Type};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+
+/// Named group of applications
+#[derive(Clone, Deserialize, Debug)]
+pub struct AppGroupConfig {
+    pub name: String,
+    pub apps: Vec<String>,
+}
+
+/// Rule restricting the creation of some keys to groups of applications
+///
+/// A rule applies to a key when all the criteria set match it.
+#[derive(Clone, Deserialize, Debug)]
+pub struct KeyCreationRule {
+    /// Type of the provider ("MbedCrypto", "Pkcs11" or "Tpm"), all providers if not set
+    pub provider_type: Option<String>,
+    /// Type of the key, all types if not set
+    pub key_type: Option<Type>,
+    /// Minimum size of the key in bits
+    pub min_bits: Option<usize>,
+    /// Groups of the applications allowed to create the key
+    pub allowed_groups: Vec<String>,
+}
+
+impl KeyCreationRule {
+    fn applies_to(&self, provider_id: ProviderID, attributes: &Attributes) -> bool {
+        let provider_matches = match &self.provider_type {
+            None => true,
+            Some(provider_type) => provider_id_from_type(provider_type) == Some(provider_id),
+        };
+        let key_type_matches = match &self.key_type {
+            None => true,
+            Some(key_type) => *key_type == attributes.key_type,
+        };
+        let bits_match = match self.min_bits {
+            None => true,
+            Some(min_bits) => attributes.bits >= min_bits,
+        };
+
+        provider_matches && key_type_matches && bits_match
+    }
+}
+
+/// Family of keys whose size is limited by a key size rule
+#[derive(Copy, Clone, Deserialize, Debug, PartialEq)]
+pub enum KeyFamily {
+    /// RSA key pairs and public keys
+    Rsa,
+    /// Elliptic curve key pairs and public keys, of all curve families
+    Ecc,
+}
+
+impl KeyFamily {
+    fn contains(self, key_type: Type) -> bool {
+        match (self, key_type) {
+            (KeyFamily::Rsa, Type::RsaKeyPair)
+            | (KeyFamily::Rsa, Type::RsaPublicKey)
+            | (KeyFamily::Ecc, Type::EccKeyPair { .. })
+            | (KeyFamily::Ecc, Type::EccPublicKey { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Rule setting the minimum size of the keys of a family
+#[derive(Clone, Deserialize, Debug)]
+pub struct KeySizeRule {
+    /// Type of the provider ("MbedCrypto", "Pkcs11" or "Tpm"), all providers if not set
+    pub provider_type: Option<String>,
+    /// Family of the keys
+    pub key_family: KeyFamily,
+    /// Minimum size of the keys in bits
+    pub min_bits: usize,
+}
+
+impl KeySizeRule {
+    fn applies_to(&self, provider_id: ProviderID, attributes: &Attributes) -> bool {
+        let provider_matches = match &self.provider_type {
+            None => true,
+            Some(provider_type) => provider_id_from_type(provider_type) == Some(provider_id),
+        };
+
+        provider_matches && self.key_family.contains(attributes.key_type)
+    }
+}
+
+/// Central evaluation of the key creation and key size rules
+#[derive(Debug, Default)]
+pub struct KeyCreationPolicy {
+    groups: HashMap<String, HashSet<String>>,
+    rules: Vec<KeyCreationRule>,
+    size_rules: Vec<KeySizeRule>,
+}
+
+impl KeyCreationPolicy {
+    /// Creates the policy from the configured application groups, key creation rules and key
+    /// size rules.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if a rule refers to an unknown group or provider
+    /// type.
+    pub fn new(
+        groups: &[AppGroupConfig],
+        rules: &[KeyCreationRule],
+        size_rules: &[KeySizeRule],
+    ) -> std::io::Result<KeyCreationPolicy> {
+        let mut groups_map: HashMap<String, HashSet<String>> = HashMap::new();
+        for group in groups {
+            groups_map
+                .entry(group.name.clone())
+                .or_default()
+                .extend(group.apps.iter().cloned());
+        }
+
+        for rule in rules {
+            if let Some(provider_type) = &rule.provider_type {
+                if provider_id_from_type(provider_type).is_none() {
+                    format_error!("Unknown provider type in key creation rule", provider_type);
+                    return Err(Error::new(ErrorKind::InvalidData, "unknown provider type"));
+                }
+            }
+            for group in &rule.allowed_groups {
+                if !groups_map.contains_key(group) {
+                    format_error!("Unknown application group in key creation rule", group);
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "unknown application group",
+                    ));
+                }
+            }
+        }
+
+        for size_rule in size_rules {
+            if let Some(provider_type) = &size_rule.provider_type {
+                if provider_id_from_type(provider_type).is_none() {
+                    format_error!("Unknown provider type in key size rule", provider_type);
+                    return Err(Error::new(ErrorKind::InvalidData, "unknown provider type"));
+                }
+            }
+        }
+
+        Ok(KeyCreationPolicy {
+            groups: groups_map,
+            rules: rules.to_vec(),
+            size_rules: size_rules.to_vec(),
+        })
+    }
+
+    /// Checks that the application is allowed to create a key with the given attributes in the
+    /// provider.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the key is smaller than a key size rule applying to it
+    /// allows, or if a key creation rule applying to the key does not allow any group of the
+    /// application.
+    pub fn check(
+        &self,
+        app_name: &ApplicationName,
+        provider_id: ProviderID,
+        attributes: &Attributes,
+    ) -> Result<()> {
+        for size_rule in &self.size_rules {
+            if !size_rule.applies_to(provider_id, attributes) {
+                continue;
+            }
+            if attributes.bits == 0 {
+                error!(
+                    "The size of the {:?} keys of the {} provider must be given, for it to be checked against the minimum of {} bits.",
+                    size_rule.key_family, provider_id, size_rule.min_bits
+                );
+                return Err(ResponseStatus::PsaErrorNotPermitted);
+            }
+            if attributes.bits < size_rule.min_bits {
+                error!(
+                    "{:?} keys of the {} provider must have at least {} bits, {} requested.",
+                    size_rule.key_family, provider_id, size_rule.min_bits, attributes.bits
+                );
+                return Err(ResponseStatus::PsaErrorNotPermitted);
+            }
+        }
+        for rule in &self.rules {
+            if !rule.applies_to(provider_id, attributes) {
+                continue;
+            }
+            let is_allowed = rule.allowed_groups.iter().any(|group| {
+                self.groups
+                    .get(group)
+                    .map_or(false, |apps| apps.contains(app_name.get_name()))
+            });
+            if !is_allowed {
+                if crate::utils::GlobalConfig::log_error_details() {
+                    error!(
+                        "Application \"{}\" is not allowed to create a {:?} key of {} bits in provider {}.",
+                        app_name, attributes.key_type, attributes.bits, provider_id
+                    );
+                } else {
+                    error!("Application not allowed to create the key by the key creation rules.");
+                }
+                return Err(ResponseStatus::PsaErrorNotPermitted);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AppGroupConfig, KeyCreationPolicy, KeyCreationRule, KeyFamily, KeySizeRule};
+    use crate::authenticators::ApplicationName;
+    use crate::providers::provider_id_from_type;
+    use parsec_interface::operations::psa_algorithm::{
+        Algorithm, AsymmetricSignature, Hash, SignHash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::{ProviderID, ResponseStatus};
+
+    fn rsa_attributes(bits: usize) -> Attributes {
+        Attributes {
+            lifetime: Lifetime::Persistent,
+            key_type: Type::RsaKeyPair,
+            bits,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    export: false,
+                    copy: false,
+                    cache: false,
+                    encrypt: false,
+                    decrypt: false,
+                    sign_message: true,
+                    verify_message: false,
+                    sign_hash: true,
+                    verify_hash: false,
+                    derive: false,
+                },
+                permitted_algorithms: Algorithm::AsymmetricSignature(
+                    AsymmetricSignature::RsaPkcs1v15Sign {
+                        hash_alg: SignHash::Specific(Hash::Sha256),
+                    },
+                ),
+            },
+        }
+    }
+
+    fn policy() -> KeyCreationPolicy {
+        KeyCreationPolicy::new(
+            &[AppGroupConfig {
+                name: String::from("signers"),
+                apps: vec![String::from("signer")],
+            }],
+            &[KeyCreationRule {
+                provider_type: Some(String::from("Tpm")),
+                key_type: Some(Type::RsaKeyPair),
+                min_bits: Some(4096),
+                allowed_groups: vec![String::from("signers")],
+            }],
+            &[],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn rule_restricts_matching_keys() {
+        let policy = policy();
+        let signer = ApplicationName::new(String::from("signer"));
+        let other = ApplicationName::new(String::from("other"));
+
+        policy
+            .check(&signer, ProviderID::Tpm, &rsa_attributes(4096))
+            .unwrap();
+        assert_eq!(
+            policy
+                .check(&other, ProviderID::Tpm, &rsa_attributes(4096))
+                .unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+    }
+
+    #[test]
+    fn rule_does_not_restrict_other_keys() {
+        let policy = policy();
+        let other = ApplicationName::new(String::from("other"));
+
+        policy
+            .check(&other, ProviderID::Tpm, &rsa_attributes(2048))
+            .unwrap();
+        policy
+            .check(&other, ProviderID::MbedCrypto, &rsa_attributes(4096))
+            .unwrap();
+    }
+
+    #[test]
+    fn unknown_group_is_refused() {
+        let _ = KeyCreationPolicy::new(
+            &[],
+            &[KeyCreationRule {
+                provider_type: None,
+                key_type: None,
+                min_bits: None,
+                allowed_groups: vec![String::from("signers")],
+            }],
+            &[],
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn small_keys_refused() {
+        let policy = KeyCreationPolicy::new(
+            &[],
+            &[],
+            &[
+                KeySizeRule {
+                    provider_type: None,
+                    key_family: KeyFamily::Rsa,
+                    min_bits: 2048,
+                },
+                KeySizeRule {
+                    provider_type: Some(String::from("Pkcs11")),
+                    key_family: KeyFamily::Rsa,
+                    min_bits: 3072,
+                },
+            ],
+        )
+        .unwrap();
+        let app = ApplicationName::new(String::from("app"));
+
+        policy
+            .check(&app, ProviderID::MbedCrypto, &rsa_attributes(2048))
+            .unwrap();
+        assert_eq!(
+            policy
+                .check(&app, ProviderID::MbedCrypto, &rsa_attributes(1024))
+                .unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        assert_eq!(
+            policy
+                .check(&app, ProviderID::Pkcs11, &rsa_attributes(2048))
+                .unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        policy
+            .check(&app, ProviderID::Pkcs11, &rsa_attributes(3072))
+            .unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Labels of the keys
+//!
+//! Clients can tag their keys with small key-value labels, for example the ID of a device, the
+//! purpose of a key or its rotation cohort, when generating them or later, and list their keys
+//! having some labels. The labels are stored with the key information in the Key Info Manager, for
+//! any provider storing its keys there, and are replaced as a whole when set again.
+//!
+//! The labels are not protected by the provider and are as trusted as the mappings themselves: they
+//! must not be relied on to authorize the use of a key.
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::key_info_store::KeyInfoStore;
+use crate::key_info_managers::{self, KeyInfo, KeyTriple, ManageKeyInfo};
+use derivative::Derivative;
+use log::error;
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Maximum number of labels of a key
+pub const MAX_LABELS: usize = 16;
+/// Maximum length of the name of a label, in bytes
+pub const MAX_LABEL_NAME_LENGTH: usize = 64;
+/// Maximum length of the value of a label, in bytes
+pub const MAX_LABEL_VALUE_LENGTH: usize = 256;
+
+/// Checks that labels can be stored with a key: at most `MAX_LABELS` of them, with names not empty
+/// and of at most `MAX_LABEL_NAME_LENGTH` bytes and values of at most `MAX_LABEL_VALUE_LENGTH`
+/// bytes.
+///
+/// # Errors
+///
+/// Returns `PsaErrorInvalidArgument` if the labels can not be stored.
+pub fn check(labels: &BTreeMap<String, String>) -> Result<()> {
+    if labels.len() > MAX_LABELS {
+        error!("A key can not have more than {} labels.", MAX_LABELS);
+        return Err(ResponseStatus::PsaErrorInvalidArgument);
+    }
+    for (name, value) in labels {
+        if name.is_empty() || name.len() > MAX_LABEL_NAME_LENGTH {
+            error!(
+                "The name of a label must be between 1 and {} bytes long.",
+                MAX_LABEL_NAME_LENGTH
+            );
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        if value.len() > MAX_LABEL_VALUE_LENGTH {
+            error!(
+                "The value of a label can not be longer than {} bytes.",
+                MAX_LABEL_VALUE_LENGTH
+            );
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+    }
+
+    Ok(())
+}
+
+/// Labels of the keys of a provider
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct KeyLabels {
+    #[derivative(Debug = "ignore")]
+    key_info_store: Arc<KeyInfoStore>,
+}
+
+impl KeyLabels {
+    /// Creates the labels of the keys stored in the given Key Info Manager.
+    pub fn new(key_info_store: Arc<KeyInfoStore>) -> KeyLabels {
+        KeyLabels { key_info_store }
+    }
+
+    /// Sets the labels of a key, replacing the ones previously set. Setting no label removes them.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInvalidArgument` if the labels can not be stored, see `check`, and
+    /// `PsaErrorDoesNotExist` if the key does not exist.
+    pub fn set(&self, key_triple: &KeyTriple, labels: BTreeMap<String, String>) -> Result<()> {
+        check(&labels)?;
+        let mut store_handle = self.key_info_store.write();
+        let key_info = store_handle
+            .get(key_triple)
+            .map_err(key_info_managers::to_response_status)?
+            .cloned()
+            .ok_or_else(|| {
+                error!("The key of the labels does not exist.");
+                ResponseStatus::PsaErrorDoesNotExist
+            })?;
+        let _ = store_handle
+            .insert(key_triple.clone(), KeyInfo { labels, ..key_info })
+            .map_err(key_info_managers::to_response_status)?;
+
+        Ok(())
+    }
+
+    /// Lists the names of the keys of the application on the provider having all the labels
+    /// given, with the same values, along with all their labels. Listing with no label gives all
+    /// the keys of the application.
+    pub fn list(
+        &self,
+        app_name: &ApplicationName,
+        provider_id: ProviderID,
+        labels: &BTreeMap<String, String>,
+    ) -> Result<Vec<(String, BTreeMap<String, String>)>> {
+        let store_handle = self.key_info_store.read();
+        let mut keys = Vec::new();
+        for key_triple in store_handle
+            .get_all(provider_id)
+            .map_err(key_info_managers::to_response_status)?
+        {
+            if key_triple.app_name() != app_name {
+                continue;
+            }
+            if let Some(key_info) = store_handle
+                .get(key_triple)
+                .map_err(key_info_managers::to_response_status)?
+            {
+                if labels
+                    .iter()
+                    .all(|(name, value)| key_info.labels.get(name) == Some(value))
+                {
+                    keys.push((key_triple.key_name().to_string(), key_info.labels.clone()));
+                }
+            }
+        }
+        keys.sort();
+
+        Ok(keys)
+    }
+}
+
+#[cfg(all(test, feature = "memory-manager"))]
+mod test {
+    use super::{KeyLabels, MAX_LABELS, MAX_LABEL_NAME_LENGTH, MAX_LABEL_VALUE_LENGTH};
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::key_info_store::KeyInfoStore;
+    use crate::key_info_managers::memory_manager::MemoryKeyInfoManager;
+    use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+    use parsec_interface::operations::psa_algorithm::{Algorithm, Cipher};
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::{ProviderID, ResponseStatus};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    fn test_key_info() -> KeyInfo {
+        KeyInfo {
+            id: vec![0x11, 0x22, 0x33],
+            attributes: Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::Aes,
+                bits: 128,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: false,
+                        verify_hash: false,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: true,
+                        decrypt: true,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::Cipher(Cipher::Ctr),
+                },
+            },
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    fn key_triple(app_name: &str, key_name: &str) -> KeyTriple {
+        KeyTriple::new(
+            ApplicationName::new(String::from(app_name)),
+            ProviderID::Tpm,
+            String::from(key_name),
+        )
+    }
+
+    fn app() -> ApplicationName {
+        ApplicationName::new(String::from("app"))
+    }
+
+    fn labels(labels: &[(&str, &str)]) -> BTreeMap<String, String> {
+        labels
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn keys_listed_by_labels() {
+        let store = Arc::new(KeyInfoStore::new(Box::new(MemoryKeyInfoManager::new())).unwrap());
+        let key_labels = KeyLabels::new(store.clone());
+
+        assert_eq!(
+            key_labels.set(&key_triple("app", "key1"), labels(&[("device", "1")])),
+            Err(ResponseStatus::PsaErrorDoesNotExist)
+        );
+        for stored in [
+            key_triple("app", "key1"),
+            key_triple("app", "key2"),
+            key_triple("other", "key3"),
+        ]
+        .iter()
+        {
+            let _ = store
+                .write()
+                .insert(stored.clone(), test_key_info())
+                .unwrap();
+        }
+        key_labels
+            .set(
+                &key_triple("app", "key1"),
+                labels(&[("device", "1"), ("purpose", "tls")]),
+            )
+            .unwrap();
+        key_labels
+            .set(&key_triple("app", "key2"), labels(&[("device", "2")]))
+            .unwrap();
+        key_labels
+            .set(&key_triple("other", "key3"), labels(&[("device", "1")]))
+            .unwrap();
+
+        assert_eq!(
+            key_labels
+                .list(&app(), ProviderID::Tpm, &labels(&[("device", "1")]))
+                .unwrap(),
+            vec![(
+                String::from("key1"),
+                labels(&[("device", "1"), ("purpose", "tls")])
+            )]
+        );
+        assert_eq!(
+            key_labels
+                .list(&app(), ProviderID::Tpm, &BTreeMap::new())
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(key_labels
+            .list(&app(), ProviderID::Tpm, &labels(&[("purpose", "signing")]))
+            .unwrap()
+            .is_empty());
+
+        // The labels are replaced as a whole.
+        key_labels
+            .set(&key_triple("app", "key1"), BTreeMap::new())
+            .unwrap();
+        assert!(key_labels
+            .list(&app(), ProviderID::Tpm, &labels(&[("device", "1")]))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn labels_checked() {
+        let store = Arc::new(KeyInfoStore::new(Box::new(MemoryKeyInfoManager::new())).unwrap());
+        let key_labels = KeyLabels::new(store.clone());
+        let _ = store
+            .write()
+            .insert(key_triple("app", "key"), test_key_info())
+            .unwrap();
+
+        let too_many: BTreeMap<String, String> = (0..=MAX_LABELS)
+            .map(|i| (i.to_string(), String::new()))
+            .collect();
+        let long_name = "n".repeat(MAX_LABEL_NAME_LENGTH + 1);
+        let long_value = "v".repeat(MAX_LABEL_VALUE_LENGTH + 1);
+        for invalid in [
+            too_many,
+            labels(&[("", "value")]),
+            labels(&[(long_name.as_str(), "value")]),
+            labels(&[("name", long_value.as_str())]),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                key_labels.set(&key_triple("app", "key"), invalid.clone()),
+                Err(ResponseStatus::PsaErrorInvalidArgument)
+            );
+        }
+        assert!(store
+            .read()
+            .get(&key_triple("app", "key"))
+            .unwrap()
+            .unwrap()
+            .labels
+            .is_empty());
+    }
+}
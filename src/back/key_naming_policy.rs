@@ -0,0 +1,171 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Conventions for the names of the keys of each application
+//!
+//! Several software components often share the identity of one application, and the key inventory
+//! of a fleet stays consistent only if the key names follow conventions. Key naming rules,
+//! evaluated for all providers when a key is created, make sure that the names of the new keys of
+//! an application start with one of the prefixes given to it, for example one per component, and
+//! match a regular expression. Existing keys are not checked so that rules can be introduced
+//! without making the keys stored before them unreachable.
+use crate::authenticators::ApplicationName;
+use log::error;
+use parsec_interface::requests::{ResponseStatus, Result};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+/// Rule the names of the keys created by an application must follow
+///
+/// A key name follows the rule when all the criteria set match it.
+#[derive(Clone, Deserialize, Debug)]
+pub struct KeyNamingRule {
+    /// Name of the application the rule applies to
+    pub app_name: String,
+    /// Prefixes one of which the key names must start with, any name if not set
+    pub prefixes: Option<Vec<String>>,
+    /// Regular expression the whole key names must match, any name if not set
+    pub pattern: Option<String>,
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    prefixes: Option<Vec<String>>,
+    pattern: Option<Regex>,
+}
+
+impl CompiledRule {
+    fn matches(&self, key_name: &str) -> bool {
+        let prefix_matches = match &self.prefixes {
+            None => true,
+            Some(prefixes) => prefixes.iter().any(|prefix| key_name.starts_with(prefix)),
+        };
+        let pattern_matches = match &self.pattern {
+            None => true,
+            Some(pattern) => pattern.is_match(key_name),
+        };
+
+        prefix_matches && pattern_matches
+    }
+}
+
+/// Central evaluation of the key naming rules
+#[derive(Debug, Default)]
+pub struct KeyNamingPolicy {
+    rules: HashMap<String, Vec<CompiledRule>>,
+}
+
+impl KeyNamingPolicy {
+    /// Creates the policy from the configured rules.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if a pattern is not a valid regular expression.
+    pub fn new(rules: &[KeyNamingRule]) -> std::io::Result<KeyNamingPolicy> {
+        let mut rules_map: HashMap<String, Vec<CompiledRule>> = HashMap::new();
+        for rule in rules {
+            let pattern = match &rule.pattern {
+                // The pattern is anchored so that it has to match the whole name.
+                Some(pattern) => Some(Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| {
+                    format_error!("Invalid pattern in key naming rule", e);
+                    Error::new(ErrorKind::InvalidData, "invalid key naming pattern")
+                })?),
+                None => None,
+            };
+            rules_map
+                .entry(rule.app_name.clone())
+                .or_default()
+                .push(CompiledRule {
+                    prefixes: rule.prefixes.clone(),
+                    pattern,
+                });
+        }
+
+        Ok(KeyNamingPolicy { rules: rules_map })
+    }
+
+    /// Checks that the name of a new key of the application follows the rules applying to it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the name does not follow one of the rules of the
+    /// application.
+    pub fn check(&self, app_name: &ApplicationName, key_name: &str) -> Result<()> {
+        let rules = match self.rules.get(app_name.get_name()) {
+            Some(rules) => rules,
+            None => return Ok(()),
+        };
+        if rules.iter().all(|rule| rule.matches(key_name)) {
+            return Ok(());
+        }
+        if crate::utils::GlobalConfig::log_error_details() {
+            error!(
+                "Key name \"{}\" of application \"{}\" does not follow the key naming rules.",
+                key_name, app_name
+            );
+        } else {
+            error!("Key name not allowed by the key naming rules.");
+        }
+
+        Err(ResponseStatus::PsaErrorNotPermitted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KeyNamingPolicy, KeyNamingRule};
+    use crate::authenticators::ApplicationName;
+    use parsec_interface::requests::ResponseStatus;
+
+    fn policy() -> KeyNamingPolicy {
+        KeyNamingPolicy::new(&[
+            KeyNamingRule {
+                app_name: String::from("gateway"),
+                prefixes: Some(vec![String::from("mqtt-"), String::from("vpn-")]),
+                pattern: None,
+            },
+            KeyNamingRule {
+                app_name: String::from("gateway"),
+                prefixes: None,
+                pattern: Some(String::from("[a-z0-9-]+")),
+            },
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn names_following_the_rules_accepted() {
+        let policy = policy();
+        let gateway = ApplicationName::new(String::from("gateway"));
+        let other = ApplicationName::new(String::from("other"));
+
+        policy.check(&gateway, "mqtt-client-2").unwrap();
+        policy.check(&gateway, "vpn-ike").unwrap();
+        policy.check(&other, "Any Name").unwrap();
+    }
+
+    #[test]
+    fn names_breaking_a_rule_refused() {
+        let policy = policy();
+        let gateway = ApplicationName::new(String::from("gateway"));
+
+        // Only one of the two rules is followed by each name.
+        for key_name in &["ota-signing", "mqtt-Client", "vpn-ike\n"] {
+            assert_eq!(
+                policy.check(&gateway, key_name).unwrap_err(),
+                ResponseStatus::PsaErrorNotPermitted
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_pattern_is_refused() {
+        let _ = KeyNamingPolicy::new(&[KeyNamingRule {
+            app_name: String::from("gateway"),
+            prefixes: None,
+            pattern: Some(String::from("mqtt-(")),
+        }])
+        .unwrap_err();
+    }
+}
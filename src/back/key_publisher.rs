@@ -0,0 +1,248 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Publication of the public keys to a distribution endpoint
+//!
+//! Verifiers of the signatures made on a fleet of devices need fresh public keys without polling
+//! each device. Each configured publisher selects keys by application name and key name prefix,
+//! and gets their public half, as exported by PsaExportPublicKey, with the certificate the
+//! provider stores alongside if there is one, whenever a selected key is created. When such a key
+//! is destroyed, the publisher is told to withdraw it.
+//!
+//! The key is given as a JSON object with its provider, application and key names and the public
+//! key and certificate encoded in base64, both being null for a withdrawal. A publisher is either:
+//! * a directory, in which the object is written in a file per key, removed on withdrawal
+//! * a local command receiving the object on its standard input, for example to publish it to an
+//!   MQTT topic with `mosquitto_pub -s`
+//! * a plain HTTP endpoint receiving the object in the body of a `PUT` request
+//!
+//! The public key is exported before the response of the creation is sent, but it is published
+//! in its own thread, and publication failures are only logged.
+use super::event_hooks::{self, Target};
+use crate::authenticators::ApplicationName;
+use log::error;
+use parsec_interface::requests::ProviderID;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// Configuration of a publisher, which must have exactly one of a directory, a command or a
+/// webhook
+#[derive(Clone, Deserialize, Debug)]
+pub struct KeyPublisherConfig {
+    /// Name of the application whose keys are published, defaults to all the applications
+    pub app_name: Option<String>,
+    /// Prefix of the names of the keys published, defaults to all the keys
+    pub key_name_prefix: Option<String>,
+    /// Path of the directory the keys are written in
+    pub directory: Option<String>,
+    /// Path of the command to run
+    pub command: Option<String>,
+    /// URL of the endpoint, starting with `http://`
+    pub webhook: Option<String>,
+}
+
+/// Key published, or withdrawn if it has no public key
+#[derive(Clone, Serialize, Debug, PartialEq)]
+pub struct PublishedKey {
+    pub provider: String,
+    pub app_name: String,
+    pub key_name: String,
+    pub public_key: Option<String>,
+    pub certificate: Option<String>,
+}
+
+impl PublishedKey {
+    /// Creates the record of a key, withdrawn if no public key is given.
+    pub fn new(
+        provider_id: ProviderID,
+        app_name: &ApplicationName,
+        key_name: &str,
+        public_key: Option<&[u8]>,
+        certificate: Option<&[u8]>,
+    ) -> PublishedKey {
+        PublishedKey {
+            provider: provider_id.to_string(),
+            app_name: app_name.to_string(),
+            key_name: key_name.to_string(),
+            public_key: public_key.map(base64::encode),
+            certificate: certificate.map(base64::encode),
+        }
+    }
+
+    // Name of the file of the key in a directory, the names being encoded as they can contain any
+    // character.
+    fn file_name(&self) -> String {
+        format!(
+            "{}.{}.{}.json",
+            base64::encode_config(self.app_name.as_bytes(), base64::URL_SAFE),
+            base64::encode_config(self.provider.as_bytes(), base64::URL_SAFE),
+            base64::encode_config(self.key_name.as_bytes(), base64::URL_SAFE)
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Destination {
+    Directory(PathBuf),
+    Target(Target),
+}
+
+#[derive(Clone, Debug)]
+struct Publisher {
+    app_name: Option<String>,
+    key_name_prefix: String,
+    destination: Destination,
+}
+
+impl Publisher {
+    fn selects(&self, app_name: &ApplicationName, key_name: &str) -> bool {
+        self.app_name
+            .as_ref()
+            .map_or(true, |selected| selected == app_name.get_name())
+            && key_name.starts_with(&self.key_name_prefix)
+    }
+}
+
+/// Publishers of the public keys of a provider
+#[derive(Debug, Default)]
+pub struct KeyPublisher {
+    publishers: Vec<Publisher>,
+}
+
+impl KeyPublisher {
+    /// Creates the publishers from their configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if a publisher does not have exactly one of a
+    /// directory, a command or a webhook, or if the webhook is not a plain HTTP URL.
+    pub fn new(configs: &[KeyPublisherConfig]) -> Result<KeyPublisher> {
+        let publishers = configs
+            .iter()
+            .map(|config| {
+                let destination = match (&config.directory, &config.command, &config.webhook) {
+                    (Some(directory), None, None) => {
+                        Destination::Directory(PathBuf::from(directory))
+                    }
+                    (None, Some(command), None) => {
+                        Destination::Target(Target::Command(command.clone()))
+                    }
+                    (None, None, Some(webhook)) => {
+                        Destination::Target(event_hooks::parse_webhook(webhook)?)
+                    }
+                    _ => {
+                        error!("A key publisher needs one of a directory, a command or a webhook.");
+                        return Err(Error::new(ErrorKind::InvalidData, "invalid key publisher"));
+                    }
+                };
+                Ok(Publisher {
+                    app_name: config.app_name.clone(),
+                    key_name_prefix: config.key_name_prefix.clone().unwrap_or_default(),
+                    destination,
+                })
+            })
+            .collect::<Result<Vec<Publisher>>>()?;
+
+        Ok(KeyPublisher { publishers })
+    }
+
+    /// Checks if a publisher selects the key.
+    pub fn selects(&self, app_name: &ApplicationName, key_name: &str) -> bool {
+        self.publishers
+            .iter()
+            .any(|publisher| publisher.selects(app_name, key_name))
+    }
+
+    /// Publishes or withdraws the key to the publishers selecting it.
+    pub fn publish(&self, key: PublishedKey) {
+        let app_name = ApplicationName::new(key.app_name.clone());
+        let destinations: Vec<Destination> = self
+            .publishers
+            .iter()
+            .filter(|publisher| publisher.selects(&app_name, &key.key_name))
+            .map(|publisher| publisher.destination.clone())
+            .collect();
+        if destinations.is_empty() {
+            return;
+        }
+        let payload = match serde_json::to_vec(&key) {
+            Ok(payload) => payload,
+            Err(e) => {
+                format_error!("Failed to serialize a published key", e);
+                return;
+            }
+        };
+
+        let _ = thread::spawn(move || {
+            for destination in destinations {
+                let result = match &destination {
+                    Destination::Directory(directory) => {
+                        write_to_directory(directory, &key, &payload)
+                    }
+                    Destination::Target(target) => event_hooks::run(target, "PUT", &payload),
+                };
+                if let Err(e) = result {
+                    format_error!("Failed to publish a key", e);
+                }
+            }
+        });
+    }
+}
+
+fn write_to_directory(directory: &Path, key: &PublishedKey, payload: &[u8]) -> Result<()> {
+    let path = directory.join(key.file_name());
+    if key.public_key.is_none() {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+
+    // The file is replaced atomically so that readers never see it half written.
+    let mut temporary_path = path.clone().into_os_string();
+    temporary_path.push(".new");
+    fs::write(&temporary_path, payload)?;
+    fs::rename(&temporary_path, &path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KeyPublisher, KeyPublisherConfig, PublishedKey};
+    use crate::authenticators::ApplicationName;
+    use parsec_interface::requests::ProviderID;
+
+    fn config() -> KeyPublisherConfig {
+        KeyPublisherConfig {
+            app_name: Some(String::from("signer")),
+            key_name_prefix: Some(String::from("release-")),
+            directory: Some(String::from("/var/lib/parsec/public_keys")),
+            command: None,
+            webhook: None,
+        }
+    }
+
+    #[test]
+    fn keys_selected() {
+        let publisher = KeyPublisher::new(&[config()]).unwrap();
+        let signer = ApplicationName::new(String::from("signer"));
+        assert!(publisher.selects(&signer, "release-2020"));
+        assert!(!publisher.selects(&signer, "debug-2020"));
+        assert!(!publisher.selects(&ApplicationName::new(String::from("other")), "release-2020"));
+
+        let mut two_destinations = config();
+        two_destinations.command = Some(String::from("/usr/bin/publish"));
+        assert!(KeyPublisher::new(&[two_destinations]).is_err());
+
+        let key = PublishedKey::new(
+            ProviderID::Pkcs11,
+            &signer,
+            "release-2020",
+            Some(&[1, 2]),
+            None,
+        );
+        assert_eq!(key.public_key, Some(String::from("AQI=")));
+        assert!(key.file_name().ends_with(".json"));
+    }
+}
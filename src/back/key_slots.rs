@@ -0,0 +1,330 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Accounting of the key slots of hardware providers
+//!
+//! Hardware providers can only store a limited number of keys. PKCS 11 tokens for example do not
+//! report how many objects they can hold, so the capacity is configured per provider and the keys
+//! stored are counted from the Key Info Manager. Part of the capacity can be reserved for some
+//! applications so that critical applications always have slots available, whatever the others
+//! create.
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::key_info_store::KeyInfoStore;
+use crate::key_info_managers::ManageKeyInfo;
+use derivative::Derivative;
+use log::{error, warn};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Configuration of the key slots of a provider
+#[derive(Clone, Deserialize, Debug)]
+pub struct KeySlotsConfig {
+    /// Type of the provider ("MbedCrypto", "Pkcs11" or "Tpm")
+    pub provider_type: String,
+    /// Number of keys the provider can store
+    pub capacity: usize,
+    /// Number of slots reserved per application name
+    pub reservations: Option<HashMap<String, usize>>,
+}
+
+/// Usage of the key slots of a provider
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeySlotsUsage {
+    /// Number of keys the provider can store
+    pub capacity: usize,
+    /// Number of keys stored
+    pub used: usize,
+    /// Number of slots reserved for applications which they do not use yet
+    pub reserved: usize,
+}
+
+/// Key slots accounting for one provider
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct KeySlots {
+    provider_id: ProviderID,
+    capacity: usize,
+    reservations: HashMap<String, usize>,
+    #[derivative(Debug = "ignore")]
+    key_info_store: Arc<KeyInfoStore>,
+    // Serializes the key creations so that two of them can not take the same free slot.
+    creation_lock: Mutex<()>,
+}
+
+impl KeySlots {
+    /// Creates the accounting of the keys of a provider, stored in the given Key Info Manager.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if more slots are reserved than the capacity.
+    pub fn new(
+        provider_id: ProviderID,
+        config: &KeySlotsConfig,
+        key_info_store: Arc<KeyInfoStore>,
+    ) -> std::io::Result<KeySlots> {
+        let reservations = config.reservations.clone().unwrap_or_default();
+        if reservations.values().sum::<usize>() > config.capacity {
+            error!(
+                "More key slots are reserved than the capacity of provider {}.",
+                provider_id
+            );
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "key slot reservations exceed capacity",
+            ));
+        }
+
+        Ok(KeySlots {
+            provider_id,
+            capacity: config.capacity,
+            reservations,
+            key_info_store,
+            creation_lock: Mutex::new(()),
+        })
+    }
+
+    // Counts the keys stored, per application.
+    fn count_keys(&self) -> Result<HashMap<String, usize>> {
+        let store_handle = self.key_info_store.read();
+        let mut counts = HashMap::new();
+        for key_triple in store_handle
+            .get_all(self.provider_id)
+            .or_else(|e| Err(crate::key_info_managers::to_response_status(e)))?
+        {
+            *counts
+                .entry(key_triple.app_name().get_name().to_string())
+                .or_insert(0) += 1;
+        }
+
+        Ok(counts)
+    }
+
+    // Number of reserved slots not used yet by applications other than the one given.
+    fn outstanding_reservations(&self, counts: &HashMap<String, usize>, app_name: &str) -> usize {
+        self.reservations
+            .iter()
+            .filter(|(app, _)| *app != app_name)
+            .map(|(app, reserved)| reserved.saturating_sub(*counts.get(app).unwrap_or(&0)))
+            .sum()
+    }
+
+    /// Gets the current usage of the key slots.
+    pub fn usage(&self) -> Result<KeySlotsUsage> {
+        let counts = self.count_keys()?;
+
+        Ok(KeySlotsUsage {
+            capacity: self.capacity,
+            used: counts.values().sum(),
+            reserved: self.outstanding_reservations(&counts, ""),
+        })
+    }
+
+    /// Checks that a slot is available for a new key of the application. The returned guard must be
+    /// held until the key is created.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInsufficientStorage` if all the slots are used or reserved for other
+    /// applications.
+    pub fn reserve_slot(&self, app_name: &ApplicationName) -> Result<MutexGuard<'_, ()>> {
+        self.reserve_slots(app_name, 1)
+    }
+
+    /// Checks that enough slots are available for the given number of new keys of the
+    /// application. The returned guard must be held until the keys are created.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInsufficientStorage` if not enough slots are free or reserved for the
+    /// application.
+    pub fn reserve_slots(
+        &self,
+        app_name: &ApplicationName,
+        count: usize,
+    ) -> Result<MutexGuard<'_, ()>> {
+        let guard = self.creation_lock.lock().expect("Key slots lock poisoned");
+        let counts = self.count_keys()?;
+        let app_name = app_name.get_name();
+        let used_by_app = *counts.get(app_name).unwrap_or(&0);
+        let reserved_for_app = self
+            .reservations
+            .get(app_name)
+            .unwrap_or(&0)
+            .saturating_sub(used_by_app);
+
+        if count <= reserved_for_app {
+            return Ok(guard);
+        }
+
+        let used: usize = counts.values().sum();
+        let unavailable = used + self.outstanding_reservations(&counts, app_name);
+        // The slots still reserved for the application are counted as free ones here.
+        if unavailable + count > self.capacity {
+            if crate::utils::GlobalConfig::log_error_details() {
+                error!(
+                    "No key slot available for application \"{}\" in provider {} ({} used out of {}).",
+                    app_name, self.provider_id, used, self.capacity
+                );
+            } else {
+                error!("No key slot available for the application.");
+            }
+            return Err(ResponseStatus::PsaErrorInsufficientStorage);
+        }
+        if unavailable + count == self.capacity {
+            warn!(
+                "Last available key slot of provider {} being used.",
+                self.provider_id
+            );
+        }
+
+        Ok(guard)
+    }
+}
+
+#[cfg(all(test, feature = "on-disk-manager"))]
+mod test {
+    use super::{KeySlots, KeySlotsConfig, KeySlotsUsage};
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::key_info_store::KeyInfoStore;
+    use crate::key_info_managers::on_disk_manager::OnDiskKeyInfoManagerBuilder;
+    use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+    use parsec_interface::operations::psa_algorithm::{
+        Algorithm, AsymmetricSignature, Hash, SignHash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::{ProviderID, ResponseStatus};
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn test_key_info() -> KeyInfo {
+        KeyInfo {
+            id: vec![0x11, 0x22, 0x33],
+            attributes: Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::RsaKeyPair,
+                bits: 2048,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: true,
+                        verify_hash: false,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: false,
+                        decrypt: false,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::AsymmetricSignature(
+                        AsymmetricSignature::RsaPkcs1v15Sign {
+                            hash_alg: SignHash::Specific(Hash::Sha256),
+                        },
+                    ),
+                },
+            },
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn reserved_slots_kept_for_application() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/reserved_slots_mappings");
+        let manager = OnDiskKeyInfoManagerBuilder::new()
+            .with_mappings_dir_path(path)
+            .build()
+            .unwrap();
+        let store = Arc::new(KeyInfoStore::new(Box::new(manager)).unwrap());
+        let config = KeySlotsConfig {
+            provider_type: String::from("Pkcs11"),
+            capacity: 2,
+            reservations: Some(vec![(String::from("critical"), 1)].into_iter().collect()),
+        };
+        let key_slots = KeySlots::new(ProviderID::Pkcs11, &config, store.clone()).unwrap();
+        let critical = ApplicationName::new(String::from("critical"));
+        let other = ApplicationName::new(String::from("other"));
+        let key_triple = KeyTriple::new(other.clone(), ProviderID::Pkcs11, String::from("key"));
+
+        drop(key_slots.reserve_slot(&other).unwrap());
+        let _ = store
+            .write()
+            .insert(key_triple.clone(), test_key_info())
+            .unwrap();
+
+        assert_eq!(
+            key_slots.usage().unwrap(),
+            KeySlotsUsage {
+                capacity: 2,
+                used: 1,
+                reserved: 1,
+            }
+        );
+        assert_eq!(
+            key_slots.reserve_slot(&other).unwrap_err(),
+            ResponseStatus::PsaErrorInsufficientStorage
+        );
+        drop(key_slots.reserve_slot(&critical).unwrap());
+
+        let _ = store.write().remove(&key_triple).unwrap();
+    }
+
+    #[test]
+    fn several_slots_reserved_at_once() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/several_slots_mappings");
+        let manager = OnDiskKeyInfoManagerBuilder::new()
+            .with_mappings_dir_path(path)
+            .build()
+            .unwrap();
+        let config = KeySlotsConfig {
+            provider_type: String::from("Pkcs11"),
+            capacity: 3,
+            reservations: Some(vec![(String::from("critical"), 1)].into_iter().collect()),
+        };
+        let key_slots = KeySlots::new(
+            ProviderID::Pkcs11,
+            &config,
+            Arc::new(KeyInfoStore::new(Box::new(manager)).unwrap()),
+        )
+        .unwrap();
+        let critical = ApplicationName::new(String::from("critical"));
+        let other = ApplicationName::new(String::from("other"));
+
+        drop(key_slots.reserve_slots(&other, 2).unwrap());
+        assert_eq!(
+            key_slots.reserve_slots(&other, 3).unwrap_err(),
+            ResponseStatus::PsaErrorInsufficientStorage
+        );
+        drop(key_slots.reserve_slots(&critical, 3).unwrap());
+    }
+
+    #[test]
+    fn reservations_over_capacity() {
+        let path =
+            PathBuf::from(env!("OUT_DIR").to_owned() + "/reservations_over_capacity_mappings");
+        let manager = OnDiskKeyInfoManagerBuilder::new()
+            .with_mappings_dir_path(path)
+            .build()
+            .unwrap();
+        let config = KeySlotsConfig {
+            provider_type: String::from("Pkcs11"),
+            capacity: 1,
+            reservations: Some(vec![(String::from("critical"), 2)].into_iter().collect()),
+        };
+
+        let _ = KeySlots::new(
+            ProviderID::Pkcs11,
+            &config,
+            Arc::new(KeyInfoStore::new(Box::new(manager)).unwrap()),
+        )
+        .unwrap_err();
+    }
+}
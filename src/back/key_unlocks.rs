@@ -0,0 +1,149 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Credentials unlocking keys at the time they are used
+//!
+//! Some keys need a credential, such as a PIN or a passphrase, every time they are used: keys on
+//! smartcards requiring a context specific login for example. Providers report these keys and the
+//! clients first unlock them with their credential, which the service then caches for a
+//! configurable time to live to give it again to the provider on each use of the key. Once the
+//! time to live has elapsed, the key needs to be unlocked again.
+//!
+//! Credentials are cached per client, identified by the metadata of its connection, so that a key
+//! unlocked by a client can not be used by other clients of the same application. They are only
+//! kept in memory and zeroized when dropped.
+use crate::front::listener::ConnectionMetadata;
+use crate::key_info_managers::KeyTriple;
+use derivative::Derivative;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+/// Default time for which the keys stay unlocked
+pub const DEFAULT_UNLOCK_TIME_TO_LIVE: Duration = Duration::from_secs(300);
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct Unlock {
+    #[derivative(Debug = "ignore")]
+    credential: Zeroizing<Vec<u8>>,
+    expiry: Instant,
+}
+
+/// Cache of the credentials which unlocked keys of a provider
+#[derive(Debug)]
+pub struct KeyUnlocks {
+    time_to_live: Duration,
+    unlocks: Mutex<HashMap<(KeyTriple, ConnectionMetadata), Unlock>>,
+}
+
+impl Default for KeyUnlocks {
+    fn default() -> Self {
+        KeyUnlocks::new(DEFAULT_UNLOCK_TIME_TO_LIVE)
+    }
+}
+
+impl KeyUnlocks {
+    /// Creates an empty cache whose credentials expire after the given time to live.
+    pub fn new(time_to_live: Duration) -> KeyUnlocks {
+        KeyUnlocks {
+            time_to_live,
+            unlocks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Caches the credential which unlocked the key for the client.
+    pub fn store(
+        &self,
+        key_triple: KeyTriple,
+        client: ConnectionMetadata,
+        credential: Zeroizing<Vec<u8>>,
+    ) {
+        let unlock = Unlock {
+            credential,
+            expiry: Instant::now() + self.time_to_live,
+        };
+        let _ = self
+            .unlocks
+            .lock()
+            .expect("Key unlocks lock poisoned")
+            .insert((key_triple, client), unlock);
+    }
+
+    /// Gets the credential unlocking the key for the client, if it has not expired.
+    pub fn credential(
+        &self,
+        key_triple: &KeyTriple,
+        client: ConnectionMetadata,
+    ) -> Option<Zeroizing<Vec<u8>>> {
+        let now = Instant::now();
+        let mut unlocks = self.unlocks.lock().expect("Key unlocks lock poisoned");
+        unlocks.retain(|_, unlock| unlock.expiry > now);
+
+        unlocks
+            .get(&(key_triple.clone(), client))
+            .map(|unlock| unlock.credential.clone())
+    }
+
+    /// Forgets the credentials of a key for all the clients, for example once it has been
+    /// destroyed.
+    pub fn forget(&self, key_triple: &KeyTriple) {
+        self.unlocks
+            .lock()
+            .expect("Key unlocks lock poisoned")
+            .retain(|(unlocked_key, _), _| unlocked_key != key_triple);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KeyUnlocks;
+    use crate::authenticators::ApplicationName;
+    use crate::front::listener::ConnectionMetadata;
+    use crate::key_info_managers::KeyTriple;
+    use parsec_interface::requests::ProviderID;
+    use std::time::Duration;
+    use zeroize::Zeroizing;
+
+    fn key_triple() -> KeyTriple {
+        KeyTriple::new(
+            ApplicationName::new(String::from("app")),
+            ProviderID::Pkcs11,
+            String::from("key"),
+        )
+    }
+
+    fn client(pid: i32) -> ConnectionMetadata {
+        ConnectionMetadata::UnixPeerCredentials {
+            uid: 1000,
+            gid: 1000,
+            pid,
+        }
+    }
+
+    #[test]
+    fn credential_only_given_to_unlocking_client() {
+        let unlocks = KeyUnlocks::new(Duration::from_secs(3600));
+        unlocks.store(key_triple(), client(1), Zeroizing::new(vec![1, 2, 3, 4]));
+
+        assert_eq!(
+            unlocks
+                .credential(&key_triple(), client(1))
+                .unwrap()
+                .to_vec(),
+            vec![1, 2, 3, 4]
+        );
+        assert!(unlocks.credential(&key_triple(), client(2)).is_none());
+
+        unlocks.forget(&key_triple());
+        assert!(unlocks.credential(&key_triple(), client(1)).is_none());
+    }
+
+    #[test]
+    fn credential_expires() {
+        let unlocks = KeyUnlocks::new(Duration::from_secs(0));
+        unlocks.store(key_triple(), client(1), Zeroizing::new(vec![1, 2, 3, 4]));
+
+        assert!(unlocks.credential(&key_triple(), client(1)).is_none());
+    }
+}
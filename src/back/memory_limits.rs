@@ -0,0 +1,193 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Limits on the memory used by the requests of a provider
+//!
+//! The request bodies being executed by a provider, and the buffers the provider allocates for
+//! their results, grow with the number of concurrent requests. So that one slow provider piling up
+//! requests can not push the whole service over its cgroup memory limit, the bytes of the request
+//! bodies in flight can be limited per provider:
+//! * above the soft limit, the request is executed but the provider is asked to shrink its caches
+//!   and a warning is logged
+//! * above the hard limit, the request is refused with `PsaErrorInsufficientMemory`, unless it is
+//!   the only one in flight
+//!
+//! The caches of the providers have their own capacity, set in the provider configuration.
+use crate::providers::CacheUsage;
+use log::{error, warn};
+use parsec_interface::requests::{ResponseStatus, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+/// Configuration of the memory limits of a provider
+#[derive(Clone, Deserialize, Debug)]
+pub struct MemoryLimitsConfig {
+    /// Type of the provider ("MbedCrypto", "Pkcs11" or "Tpm")
+    pub provider_type: String,
+    /// Bytes of request bodies in flight above which the caches of the provider are shrunk
+    pub soft_in_flight_bytes: Option<usize>,
+    /// Bytes of request bodies in flight above which requests are refused
+    pub hard_in_flight_bytes: Option<usize>,
+}
+
+/// Memory used by a provider
+#[derive(Copy, Clone, Serialize, Debug, PartialEq)]
+pub struct MemoryUsage {
+    /// Bytes of the request bodies being executed
+    pub in_flight_bytes: usize,
+    /// Largest number of bytes in flight since the service started
+    pub peak_in_flight_bytes: usize,
+    /// Number of requests executed above the soft limit
+    pub soft_limit_exceeded: u64,
+    /// Number of requests refused because of the hard limit
+    pub refused: u64,
+    /// Usage of the caches of the provider, if it has any
+    pub cache: Option<CacheUsage>,
+}
+
+#[derive(Debug, Default)]
+struct InFlightBytes {
+    current: usize,
+    peak: usize,
+}
+
+/// Accounting of the bytes in flight of a provider
+#[derive(Debug, Default)]
+pub struct MemoryLimits {
+    soft_in_flight_bytes: Option<usize>,
+    hard_in_flight_bytes: Option<usize>,
+    in_flight_bytes: Mutex<InFlightBytes>,
+    soft_limit_exceeded: AtomicU64,
+    refused: AtomicU64,
+}
+
+/// Bytes of a request in flight, released when dropped
+#[derive(Debug)]
+pub struct InFlight<'a> {
+    limits: &'a MemoryLimits,
+    len: usize,
+    /// True if the soft limit is exceeded with this request
+    pub above_soft_limit: bool,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.limits.lock_in_flight_bytes().current -= self.len;
+    }
+}
+
+impl MemoryLimits {
+    /// Creates the limits. The soft limit can not be above the hard one.
+    pub fn new(config: &MemoryLimitsConfig) -> std::io::Result<MemoryLimits> {
+        if let (Some(soft), Some(hard)) = (config.soft_in_flight_bytes, config.hard_in_flight_bytes)
+        {
+            if soft > hard {
+                error!(
+                    "The soft memory limit of the {} provider is above its hard limit.",
+                    config.provider_type
+                );
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "invalid memory limits",
+                ));
+            }
+        }
+
+        Ok(MemoryLimits {
+            soft_in_flight_bytes: config.soft_in_flight_bytes,
+            hard_in_flight_bytes: config.hard_in_flight_bytes,
+            ..Default::default()
+        })
+    }
+
+    /// Accounts for a request body of the given length until the returned guard is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInsufficientMemory` if the hard limit would be exceeded while other
+    /// requests are in flight.
+    pub fn reserve(&self, len: usize) -> Result<InFlight> {
+        let total = {
+            let mut in_flight_bytes = self.lock_in_flight_bytes();
+            let total = in_flight_bytes.current + len;
+            match self.hard_in_flight_bytes {
+                Some(hard) if total > hard && in_flight_bytes.current != 0 => {
+                    let _ = self.refused.fetch_add(1, Ordering::Relaxed);
+                    error!(
+                        "The hard memory limit of the provider is reached, refusing the request."
+                    );
+                    return Err(ResponseStatus::PsaErrorInsufficientMemory);
+                }
+                _ => (),
+            }
+            in_flight_bytes.current = total;
+            in_flight_bytes.peak = in_flight_bytes.peak.max(total);
+            total
+        };
+        let above_soft_limit = match self.soft_in_flight_bytes {
+            Some(soft) if total > soft => {
+                let _ = self.soft_limit_exceeded.fetch_add(1, Ordering::Relaxed);
+                warn!("The soft memory limit of the provider is exceeded, shrinking its caches.");
+                true
+            }
+            _ => false,
+        };
+
+        Ok(InFlight {
+            limits: self,
+            len,
+            above_soft_limit,
+        })
+    }
+
+    fn lock_in_flight_bytes(&self) -> MutexGuard<InFlightBytes> {
+        self.in_flight_bytes
+            .lock()
+            .expect("In flight bytes lock poisoned")
+    }
+
+    /// Gets the memory used, with the usage of the caches of the provider.
+    pub fn usage(&self, cache: Option<CacheUsage>) -> MemoryUsage {
+        let in_flight_bytes = self.lock_in_flight_bytes();
+        MemoryUsage {
+            in_flight_bytes: in_flight_bytes.current,
+            peak_in_flight_bytes: in_flight_bytes.peak,
+            soft_limit_exceeded: self.soft_limit_exceeded.load(Ordering::Relaxed),
+            refused: self.refused.load(Ordering::Relaxed),
+            cache,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MemoryLimits, MemoryLimitsConfig};
+    use parsec_interface::requests::ResponseStatus;
+
+    #[test]
+    fn in_flight_bytes_limited() {
+        let limits = MemoryLimits::new(&MemoryLimitsConfig {
+            provider_type: String::from("Pkcs11"),
+            soft_in_flight_bytes: Some(100),
+            hard_in_flight_bytes: Some(200),
+        })
+        .unwrap();
+
+        let first = limits.reserve(150).unwrap();
+        assert!(first.above_soft_limit);
+        assert_eq!(
+            limits.reserve(100).unwrap_err(),
+            ResponseStatus::PsaErrorInsufficientMemory
+        );
+        drop(first);
+        // A request alone is never refused.
+        let alone = limits.reserve(300).unwrap();
+        drop(alone);
+
+        let usage = limits.usage(None);
+        assert_eq!(usage.in_flight_bytes, 0);
+        assert_eq!(usage.peak_in_flight_bytes, 300);
+        assert_eq!(usage.soft_limit_exceeded, 2);
+        assert_eq!(usage.refused, 1);
+    }
+}
@@ -1,5 +1,39 @@
 // Copyright 2019 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
 //! Routing and parsing requests for processing by providers
+pub mod app_keks;
+#[cfg(feature = "audit-log")]
+pub mod audit;
 pub mod backend_handler;
+pub mod canary_keys;
+pub mod concurrency_limit;
+#[cfg(feature = "dead-letters")]
+pub mod dead_letters;
+#[cfg(feature = "delegation-tokens")]
+pub mod delegation_tokens;
 pub mod dispatcher;
+pub mod error_metadata;
+#[cfg(feature = "event-hooks")]
+pub mod event_hooks;
+pub mod fault_injection;
+pub mod key_activation;
+pub mod key_binding;
+pub mod key_certificates;
+#[cfg(feature = "key-counters")]
+pub mod key_counters;
+pub mod key_creation_policy;
+pub mod key_labels;
+pub mod key_naming_policy;
+#[cfg(feature = "event-hooks")]
+pub mod key_publisher;
+pub mod key_slots;
+pub mod key_unlocks;
+pub mod memory_limits;
+pub mod operation_deadlines;
+pub mod operation_metrics;
+pub mod operation_statistics;
+pub mod peer_keys;
+pub mod platform_evidence;
+pub mod shadow;
+#[cfg(feature = "signing-log")]
+pub mod signing_log;
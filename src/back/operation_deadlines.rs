@@ -0,0 +1,99 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Deadlines of the operations of a provider
+//!
+//! An operation stuck on a slow or unresponsive backend holds its worker thread, and the clients
+//! waiting for it time out on their side long before. A deadline can be configured for all the
+//! operations of a provider and for specific opcodes, after which the provider gives the operation
+//! up with `PsaErrorCommunicationFailure` at the next point where it checks it, see the `deadline`
+//! module of the providers. The operations waiting for the concurrency limit of the provider also
+//! give up once their deadline expires.
+//!
+//! The operations in flight are cancelled when the service shuts down and they outlive the
+//! shutdown timeout, whether a deadline is configured or not, so that their threads finish instead
+//! of being left behind.
+use crate::providers::deadline::Deadline;
+use parsec_interface::requests::Opcode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Deadline of an opcode
+#[derive(Clone, Deserialize, Debug)]
+pub struct OpcodeDeadlineConfig {
+    /// Name of the opcode
+    pub name: String,
+    /// Deadline of its operations, in milliseconds
+    pub deadline_ms: u64,
+}
+
+/// Configuration of the deadlines of the operations of a provider
+#[derive(Clone, Deserialize, Debug)]
+pub struct OperationDeadlinesConfig {
+    /// Type of the provider whose operations have deadlines
+    pub provider_type: String,
+    /// Deadline of the operations without one of their own, in milliseconds
+    pub deadline_ms: Option<u64>,
+    /// Deadlines of specific opcodes
+    pub opcode: Option<Vec<OpcodeDeadlineConfig>>,
+}
+
+/// Deadlines of the operations of a provider
+#[derive(Debug, Default)]
+pub struct OperationDeadlines {
+    default: Option<Duration>,
+    opcodes: HashMap<Opcode, Duration>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl OperationDeadlines {
+    /// Creates the deadlines of the operations of a provider, given per opcode.
+    pub fn new(default: Option<Duration>, opcodes: HashMap<Opcode, Duration>) -> Self {
+        OperationDeadlines {
+            default,
+            opcodes,
+            cancelled: Default::default(),
+        }
+    }
+
+    /// Gets the deadline of an operation of the opcode starting now.
+    pub fn deadline(&self, opcode: Opcode) -> Deadline {
+        let duration = self.opcodes.get(&opcode).copied().or(self.default);
+        Deadline::new(duration, self.cancelled.clone())
+    }
+
+    /// Cancels the operations in flight and the ones starting from now.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OperationDeadlines;
+    use parsec_interface::requests::Opcode;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[test]
+    fn deadline_per_opcode() {
+        let mut opcodes = HashMap::new();
+        let _ = opcodes.insert(Opcode::PsaGenerateKey, Duration::from_secs(30));
+        let deadlines = OperationDeadlines::new(Some(Duration::from_secs(1)), opcodes);
+
+        let generate_key = deadlines.deadline(Opcode::PsaGenerateKey);
+        assert!(generate_key.remaining().unwrap() > Duration::from_secs(1));
+        assert!(
+            deadlines.deadline(Opcode::PsaSignHash).remaining().unwrap() <= Duration::from_secs(1)
+        );
+        assert!(!OperationDeadlines::default()
+            .deadline(Opcode::PsaSignHash)
+            .expires());
+
+        deadlines.cancel();
+        assert!(generate_key.is_exceeded());
+        assert!(deadlines.deadline(Opcode::Ping).is_exceeded());
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Cumulative metrics of the operations executed by a provider
+//!
+//! Unlike the rolling statistics of `operation_statistics`, these only ever increase from the time
+//! the backend handler was built, as expected by the monitoring systems scraping them: the latency
+//! of the operations is counted in a histogram per opcode and their failures per response status,
+//! so that an alert can be raised on a hardware backend starting to fail or to slow down.
+//!
+//! They are exposed in the Prometheus text format by the `/metrics` path of the administration
+//! API. The metrics are reset when the configuration is reloaded, which the counters of Prometheus
+//! handle like a restart of the service.
+use parsec_interface::requests::{Opcode, ResponseStatus};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the buckets of the latency histograms, in microseconds
+pub const LATENCY_BUCKETS_US: [u64; 14] = [
+    500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000,
+    2_500_000, 5_000_000, 10_000_000,
+];
+
+/// Histogram of the latency of the operations of an opcode
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LatencyHistogram {
+    /// Number of operations which took at most the matching bound of `LATENCY_BUCKETS_US`
+    pub buckets: [u64; 14],
+    /// Number of operations
+    pub count: u64,
+    /// Total latency of the operations, in microseconds
+    pub sum_us: u64,
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, latency: Duration) {
+        let latency_us = latency.as_micros() as u64;
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS_US.iter()) {
+            if latency_us <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(latency_us);
+    }
+}
+
+/// Snapshot of the metrics of a provider
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Latency of the operations, per opcode
+    pub latencies: HashMap<Opcode, LatencyHistogram>,
+    /// Number of operations which failed, per name of their response status
+    pub errors: BTreeMap<String, u64>,
+}
+
+/// Metrics of the operations of a provider
+#[derive(Debug, Default)]
+pub struct OperationMetrics {
+    metrics: Mutex<MetricsSnapshot>,
+}
+
+impl OperationMetrics {
+    /// Records an operation of the opcode which took the given time, and its failure status if it
+    /// failed.
+    pub fn record(&self, opcode: Opcode, latency: Duration, error: Option<ResponseStatus>) {
+        let mut metrics = self.metrics.lock().expect("Metrics lock poisoned");
+        metrics.latencies.entry(opcode).or_default().record(latency);
+        if let Some(status) = error {
+            *metrics.errors.entry(format!("{:?}", status)).or_default() += 1;
+        }
+    }
+
+    /// Gets the metrics recorded so far.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.metrics.lock().expect("Metrics lock poisoned").clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OperationMetrics;
+    use parsec_interface::requests::{Opcode, ResponseStatus};
+    use std::time::Duration;
+
+    #[test]
+    fn operations_counted() {
+        let metrics = OperationMetrics::default();
+        metrics.record(Opcode::PsaSignHash, Duration::from_micros(800), None);
+        metrics.record(
+            Opcode::PsaSignHash,
+            Duration::from_millis(20),
+            Some(ResponseStatus::PsaErrorCommunicationFailure),
+        );
+        metrics.record(
+            Opcode::PsaGenerateKey,
+            Duration::from_secs(60),
+            Some(ResponseStatus::PsaErrorCommunicationFailure),
+        );
+
+        let snapshot = metrics.snapshot();
+        let sign_hash = snapshot.latencies[&Opcode::PsaSignHash];
+        assert_eq!(sign_hash.count, 2);
+        assert_eq!(sign_hash.sum_us, 20_800);
+        assert_eq!(sign_hash.buckets[0], 0);
+        assert_eq!(sign_hash.buckets[1], 1);
+        assert_eq!(sign_hash.buckets[5], 2);
+        // Operations slower than the last bound are only in the count.
+        let generate_key = snapshot.latencies[&Opcode::PsaGenerateKey];
+        assert_eq!(generate_key.count, 1);
+        assert_eq!(generate_key.buckets[13], 0);
+        assert_eq!(snapshot.errors["PsaErrorCommunicationFailure"], 2);
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Rolling statistics of the operations executed by a provider
+//!
+//! Each backend handler counts the requests it executes, the ones failing and their latency over
+//! a sliding window of the last minute, and remembers the status of the last failure. This is
+//! enough for fleet monitoring to notice a provider getting slow or failing without having to
+//! collect the logs of the service.
+//!
+//! The statistics are exposed through `BackEndHandler::statistics` and by the administration API.
+//! They can not be added to the results of ListProviders, whose content is defined by the
+//! interface, until an operation is assigned to them there.
+use parsec_interface::requests::ResponseStatus;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Duration of the window over which the statistics are computed
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Copy, Clone, Debug)]
+struct Bucket {
+    // Seconds elapsed since the creation of the statistics when this bucket started.
+    second: u64,
+    operations: u64,
+    errors: u64,
+    latency: Duration,
+}
+
+#[derive(Debug)]
+struct Window {
+    buckets: VecDeque<Bucket>,
+    last_error: Option<ResponseStatus>,
+}
+
+/// Statistics of the operations of a provider
+#[derive(Debug)]
+pub struct OperationStatistics {
+    start: Instant,
+    window: Mutex<Window>,
+}
+
+/// Snapshot of the statistics over the last window
+#[derive(Clone, Debug, Serialize)]
+pub struct StatisticsSnapshot {
+    /// Number of operations executed per second
+    pub operations_per_second: f64,
+    /// Proportion of the operations which failed, between 0 and 1
+    pub error_rate: f64,
+    /// Average latency of the operations, in microseconds
+    pub average_latency_us: u64,
+    /// Status of the last operation which failed, even if it is older than the window
+    pub last_error: Option<String>,
+}
+
+impl Default for OperationStatistics {
+    fn default() -> Self {
+        OperationStatistics {
+            start: Instant::now(),
+            window: Mutex::new(Window {
+                buckets: VecDeque::new(),
+                last_error: None,
+            }),
+        }
+    }
+}
+
+impl OperationStatistics {
+    /// Records an operation which took the given time, and its failure status if it failed.
+    pub fn record(&self, latency: Duration, error: Option<ResponseStatus>) {
+        let second = self.start.elapsed().as_secs();
+        let mut window = self.window.lock().expect("Statistics lock poisoned");
+        prune(&mut window.buckets, second);
+        if window.buckets.back().map(|bucket| bucket.second) != Some(second) {
+            window.buckets.push_back(Bucket {
+                second,
+                operations: 0,
+                errors: 0,
+                latency: Duration::from_secs(0),
+            });
+        }
+        let errored = error.is_some();
+        if let Some(bucket) = window.buckets.back_mut() {
+            bucket.operations += 1;
+            bucket.latency += latency;
+            if errored {
+                bucket.errors += 1;
+            }
+        }
+        if errored {
+            window.last_error = error;
+        }
+    }
+
+    /// Computes the statistics over the last window.
+    pub fn snapshot(&self) -> StatisticsSnapshot {
+        let second = self.start.elapsed().as_secs();
+        let mut window = self.window.lock().expect("Statistics lock poisoned");
+        prune(&mut window.buckets, second);
+
+        let (operations, errors, latency) = window.buckets.iter().fold(
+            (0, 0, Duration::from_secs(0)),
+            |(operations, errors, latency), bucket| {
+                (
+                    operations + bucket.operations,
+                    errors + bucket.errors,
+                    latency + bucket.latency,
+                )
+            },
+        );
+        // The window is shorter while the service has just started.
+        let window_secs = std::cmp::min(second + 1, WINDOW.as_secs());
+        let (error_rate, average_latency_us) = if operations == 0 {
+            (0.0, 0)
+        } else {
+            (
+                errors as f64 / operations as f64,
+                latency.as_micros() as u64 / operations,
+            )
+        };
+
+        StatisticsSnapshot {
+            operations_per_second: operations as f64 / window_secs as f64,
+            error_rate,
+            average_latency_us,
+            last_error: window.last_error.map(|status| status.to_string()),
+        }
+    }
+}
+
+// Removes the buckets which are out of the window ending at the given second.
+fn prune(buckets: &mut VecDeque<Bucket>, second: u64) {
+    while let Some(bucket) = buckets.front() {
+        if bucket.second + WINDOW.as_secs() > second {
+            break;
+        }
+        let _ = buckets.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OperationStatistics;
+    use parsec_interface::requests::ResponseStatus;
+    use std::time::Duration;
+
+    #[test]
+    fn operations_recorded() {
+        let statistics = OperationStatistics::default();
+        statistics.record(Duration::from_micros(100), None);
+        statistics.record(Duration::from_micros(300), None);
+        statistics.record(
+            Duration::from_micros(200),
+            Some(ResponseStatus::PsaErrorDoesNotExist),
+        );
+        statistics.record(Duration::from_micros(200), None);
+
+        let snapshot = statistics.snapshot();
+        assert!(snapshot.operations_per_second > 0.0);
+        assert!((snapshot.error_rate - 0.25).abs() < f64::EPSILON);
+        assert_eq!(snapshot.average_latency_us, 200);
+        assert_eq!(
+            snapshot.last_error,
+            Some(ResponseStatus::PsaErrorDoesNotExist.to_string())
+        );
+    }
+}
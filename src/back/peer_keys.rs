@@ -0,0 +1,116 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Expiry of the public keys imported from peers
+//!
+//! Public keys of peers are often only needed for a short time, for example to verify the
+//! signatures of a session or to perform a key agreement. They can be imported as peer keys, only
+//! usable for verification or derivation, with a time to live after which they are destroyed by the
+//! service, instead of accumulating in the key store.
+//!
+//! Expiries are only kept in memory. Peer keys left from a previous run of the service, or from
+//! before a configuration reload, are not destroyed automatically.
+use crate::key_info_managers::KeyTriple;
+use log::error;
+use parsec_interface::operations::psa_key_attributes::{Attributes, Type};
+use parsec_interface::requests::{ResponseStatus, Result};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Record of the expiry of the peer keys of a provider
+#[derive(Debug, Default)]
+pub struct PeerKeys {
+    expiries: Mutex<HashMap<KeyTriple, Instant>>,
+}
+
+impl PeerKeys {
+    /// Checks that a key can be imported as a peer key: it must be a public key only usable to
+    /// verify signatures or to derive keys.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInvalidArgument` otherwise.
+    pub fn check_attributes(attributes: &Attributes) -> Result<()> {
+        let is_public_key = matches!(
+            attributes.key_type,
+            Type::RsaPublicKey | Type::EccPublicKey { .. } | Type::DhPublicKey { .. }
+        );
+        let usage_flags = &attributes.policy.usage_flags;
+        let is_verification_only = !(usage_flags.sign_hash
+            || usage_flags.sign_message
+            || usage_flags.encrypt
+            || usage_flags.decrypt
+            || usage_flags.export
+            || usage_flags.copy);
+
+        if !is_public_key || !is_verification_only {
+            error!("Peer keys must be public keys only usable for verification or derivation.");
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+
+        Ok(())
+    }
+
+    /// Records that the key expires after the given time to live.
+    pub fn track(&self, key_triple: KeyTriple, time_to_live: Duration) {
+        let _ = self
+            .expiries
+            .lock()
+            .expect("Peer keys lock poisoned")
+            .insert(key_triple, Instant::now() + time_to_live);
+    }
+
+    /// Forgets about the expiry of a key, for example once it has been destroyed.
+    pub fn forget(&self, key_triple: &KeyTriple) {
+        let _ = self
+            .expiries
+            .lock()
+            .expect("Peer keys lock poisoned")
+            .remove(key_triple);
+    }
+
+    /// Removes and returns the keys which expired.
+    pub fn take_expired(&self) -> Vec<KeyTriple> {
+        let now = Instant::now();
+        let mut expiries = self.expiries.lock().expect("Peer keys lock poisoned");
+        let expired: Vec<KeyTriple> = expiries
+            .iter()
+            .filter(|(_, expiry)| **expiry <= now)
+            .map(|(key_triple, _)| key_triple.clone())
+            .collect();
+        for key_triple in &expired {
+            let _ = expiries.remove(key_triple);
+        }
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PeerKeys;
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::KeyTriple;
+    use parsec_interface::requests::ProviderID;
+    use std::time::Duration;
+
+    fn key_triple(key_name: &str) -> KeyTriple {
+        KeyTriple::new(
+            ApplicationName::new(String::from("app")),
+            ProviderID::MbedCrypto,
+            String::from(key_name),
+        )
+    }
+
+    #[test]
+    fn only_expired_keys_taken() {
+        let peer_keys = PeerKeys::default();
+        peer_keys.track(key_triple("expired"), Duration::from_secs(0));
+        peer_keys.track(key_triple("valid"), Duration::from_secs(3600));
+        peer_keys.track(key_triple("destroyed"), Duration::from_secs(0));
+        peer_keys.forget(&key_triple("destroyed"));
+
+        assert_eq!(peer_keys.take_expired(), vec![key_triple("expired")]);
+        assert!(peer_keys.take_expired().is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Measured boot evidence of the platform
+//!
+//! Remote verifiers attesting a device need the measurements taken during its boot, found in the
+//! TPM event log, and the measurements of the files loaded since, found in the IMA log, along with
+//! a quote of the TPM over a fresh nonce proving that the logs match the PCRs. The service gathers
+//! them so that it is the single attestation access point of the device.
+//!
+//! The logs are returned in their binary format, as exposed by the kernel in securityfs, and the
+//! quote as the marshalled `TPMS_ATTEST` structure produced by the TPM with its signature. A log
+//! which the kernel does not expose, for example when IMA is disabled, is left out.
+//!
+//! Quotes are produced by the provider through `Provide::quote`. The TPM provider quotes all the
+//! PCRs of the SHA-256 bank with an RSA attestation key derived in the endorsement hierarchy, which
+//! is the same across restarts: its public key is returned with each quote for the verifiers to
+//! enroll it. The evidence is requested through the extension API.
+use log::{error, warn};
+use parsec_interface::requests::{ResponseStatus, Result};
+use serde::Serialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Path of the TPM event log exposed by the kernel
+pub const TPM_EVENT_LOG_PATH: &str = "/sys/kernel/security/tpm0/binary_bios_measurements";
+/// Path of the IMA measurement log exposed by the kernel
+pub const IMA_LOG_PATH: &str = "/sys/kernel/security/ima/binary_runtime_measurements";
+/// Maximum size of the nonces, which is the size of the largest digest a TPM can produce
+pub const MAX_NONCE_LEN: usize = 64;
+
+/// Quote of the PCRs, signed by the TPM
+#[derive(Clone, Debug, Serialize)]
+pub struct Quote {
+    /// Marshalled `TPMS_ATTEST` structure, including the nonce and the digest of the PCRs quoted
+    pub attest: Vec<u8>,
+    /// RSASSA-PKCS1-v1_5 signature, with SHA-256, of the attest structure
+    pub signature: Vec<u8>,
+    /// DER-encoded `RSAPublicKey` of the attestation key which signed the quote
+    pub public_key: Vec<u8>,
+}
+
+/// Evidence of the state of the platform, to be checked by a remote verifier
+#[derive(Clone, Debug, Serialize)]
+pub struct PlatformEvidence {
+    /// TPM event log of the measured boot, if exposed by the kernel
+    pub tpm_event_log: Option<Vec<u8>>,
+    /// IMA runtime measurement log, if exposed by the kernel
+    pub ima_log: Option<Vec<u8>>,
+    /// Quote over the nonce given by the verifier
+    pub quote: Quote,
+}
+
+/// Checks that a nonce given by a verifier can be quoted.
+///
+/// # Errors
+///
+/// Returns `PsaErrorInvalidArgument` if the nonce is empty or longer than `MAX_NONCE_LEN`.
+pub fn check_nonce(nonce: &[u8]) -> Result<()> {
+    if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+        error!(
+            "The nonce must be between 1 and {} bytes long.",
+            MAX_NONCE_LEN
+        );
+        return Err(ResponseStatus::PsaErrorInvalidArgument);
+    }
+
+    Ok(())
+}
+
+/// Reads a measurement log, returning `None` if it is not exposed by the kernel.
+///
+/// # Errors
+///
+/// Returns `PsaErrorStorageFailure` if the log exists but can not be read.
+pub fn read_log<P: AsRef<Path>>(path: P) -> Result<Option<Vec<u8>>> {
+    match fs::read(path.as_ref()) {
+        Ok(log) => Ok(Some(log)),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            warn!(
+                "Measurement log {} is not exposed by the kernel, leaving it out.",
+                path.as_ref().display()
+            );
+            Ok(None)
+        }
+        Err(e) => {
+            format_error!("Failed to read a measurement log", e);
+            Err(ResponseStatus::PsaErrorStorageFailure)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_nonce, read_log, MAX_NONCE_LEN};
+    use parsec_interface::requests::ResponseStatus;
+
+    #[test]
+    fn nonce_length_checked() {
+        check_nonce(&[0; 32]).unwrap();
+        check_nonce(&[0; MAX_NONCE_LEN]).unwrap();
+        for nonce in &[vec![], vec![0; MAX_NONCE_LEN + 1]] {
+            assert_eq!(
+                check_nonce(nonce).unwrap_err(),
+                ResponseStatus::PsaErrorInvalidArgument
+            );
+        }
+    }
+
+    #[test]
+    fn missing_log_left_out() {
+        assert!(read_log("/nonexistent/measurements").unwrap().is_none());
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Shadow execution of operations on a secondary provider
+//!
+//! Before migrating the keys of a provider to another one, both can be run side by side: the
+//! selected operations sent to the primary provider are executed again on the secondary provider,
+//! with the same application and key names. The client only gets the response of the primary
+//! provider. The statuses of both responses are compared and, if configured, their bodies too,
+//! which is only meaningful for deterministic operations such as exporting a public key or signing
+//! with RSA PKCS#1 v1.5 using imported keys. Discrepancies are logged and counted along with the
+//! latencies of both providers.
+//!
+//! Key creation and destruction should be shadowed as well so that the secondary provider has
+//! keys of the same names. The secondary execution happens after the primary one, on the same
+//! thread, so shadowed requests take as long as both executions. Their operations are also copied
+//! once decoded, which is avoided for the other requests.
+use log::warn;
+use parsec_interface::operations::NativeOperation;
+use parsec_interface::requests::{Opcode, ProviderID, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Configuration of the shadowing of a provider
+#[derive(Clone, Deserialize, Debug)]
+pub struct ShadowConfig {
+    /// Type of the provider whose operations are shadowed
+    pub primary: String,
+    /// Type of the provider executing the operations again
+    pub secondary: String,
+    /// Names of the operations shadowed
+    pub opcodes: Vec<String>,
+    /// Compare the bodies of the responses, and not only their statuses, defaults to false
+    pub compare_results: Option<bool>,
+}
+
+/// Operations which can be shadowed
+pub const SHADOWABLE_OPCODES: [Opcode; 6] = [
+    Opcode::PsaGenerateKey,
+    Opcode::PsaImportKey,
+    Opcode::PsaExportPublicKey,
+    Opcode::PsaDestroyKey,
+    Opcode::PsaSignHash,
+    Opcode::PsaVerifyHash,
+];
+
+/// Statistics of the operations shadowed
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ShadowStatistics {
+    /// Number of operations executed on both providers
+    pub operations: u64,
+    /// Number of operations whose responses differ
+    pub discrepancies: u64,
+    /// Average latency of the primary provider, in microseconds
+    pub average_primary_latency_us: u64,
+    /// Average latency of the secondary provider, in microseconds
+    pub average_secondary_latency_us: u64,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Counters {
+    operations: u64,
+    discrepancies: u64,
+    primary_latency: Duration,
+    secondary_latency: Duration,
+}
+
+/// Shadowing of the operations of a provider
+#[derive(Debug)]
+pub struct Shadow {
+    secondary: ProviderID,
+    opcodes: HashSet<Opcode>,
+    compare_results: bool,
+    counters: Mutex<Counters>,
+}
+
+impl Shadow {
+    /// Creates the shadowing of the given operations on the secondary provider.
+    pub fn new(secondary: ProviderID, opcodes: HashSet<Opcode>, compare_results: bool) -> Shadow {
+        Shadow {
+            secondary,
+            opcodes,
+            compare_results,
+            counters: Mutex::new(Default::default()),
+        }
+    }
+
+    /// Provider executing the operations again.
+    pub fn secondary(&self) -> ProviderID {
+        self.secondary
+    }
+
+    /// Returns true if the operation is shadowed.
+    pub fn shadows(&self, opcode: Opcode) -> bool {
+        self.opcodes.contains(&opcode)
+    }
+
+    /// Compares the responses of both providers and records their latencies. Returns true if they
+    /// match.
+    pub fn record(&self, primary: (&Response, Duration), secondary: (&Response, Duration)) -> bool {
+        let (primary_response, primary_latency) = primary;
+        let (secondary_response, secondary_latency) = secondary;
+        let opcode = primary_response.header.opcode;
+        let matching = if primary_response.header.status != secondary_response.header.status {
+            warn!(
+                "Shadowed {:?} on {} returned {} where the primary provider returned {}.",
+                opcode,
+                self.secondary,
+                secondary_response.header.status,
+                primary_response.header.status
+            );
+            false
+        } else if self.compare_results && primary_response.body != secondary_response.body {
+            warn!(
+                "Shadowed {:?} on {} returned a different result than the primary provider.",
+                opcode, self.secondary
+            );
+            false
+        } else {
+            true
+        };
+
+        let mut counters = self.counters.lock().expect("Shadow counters lock poisoned");
+        counters.operations += 1;
+        if !matching {
+            counters.discrepancies += 1;
+        }
+        counters.primary_latency += primary_latency;
+        counters.secondary_latency += secondary_latency;
+
+        matching
+    }
+
+    /// Gets the statistics of the operations shadowed since the service started.
+    pub fn statistics(&self) -> ShadowStatistics {
+        let counters = *self.counters.lock().expect("Shadow counters lock poisoned");
+        if counters.operations == 0 {
+            return Default::default();
+        }
+
+        ShadowStatistics {
+            operations: counters.operations,
+            discrepancies: counters.discrepancies,
+            average_primary_latency_us: counters.primary_latency.as_micros() as u64
+                / counters.operations,
+            average_secondary_latency_us: counters.secondary_latency.as_micros() as u64
+                / counters.operations,
+        }
+    }
+}
+
+/// Copies an operation to execute it on the secondary provider, if it can be shadowed.
+pub fn copy_operation(operation: &NativeOperation) -> Option<NativeOperation> {
+    match operation {
+        NativeOperation::PsaGenerateKey(op) => Some(NativeOperation::PsaGenerateKey(op.clone())),
+        NativeOperation::PsaImportKey(op) => Some(NativeOperation::PsaImportKey(op.clone())),
+        NativeOperation::PsaExportPublicKey(op) => {
+            Some(NativeOperation::PsaExportPublicKey(op.clone()))
+        }
+        NativeOperation::PsaDestroyKey(op) => Some(NativeOperation::PsaDestroyKey(op.clone())),
+        NativeOperation::PsaSignHash(op) => Some(NativeOperation::PsaSignHash(op.clone())),
+        NativeOperation::PsaVerifyHash(op) => Some(NativeOperation::PsaVerifyHash(op.clone())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Shadow;
+    use parsec_interface::requests::{Opcode, ProviderID, Response, ResponseStatus};
+    use std::time::Duration;
+
+    #[test]
+    fn discrepancies_counted() {
+        let shadow = Shadow::new(
+            ProviderID::Pkcs11,
+            vec![Opcode::PsaSignHash].into_iter().collect(),
+            true,
+        );
+        assert!(shadow.shadows(Opcode::PsaSignHash));
+        assert!(!shadow.shadows(Opcode::PsaGenerateKey));
+
+        let success = Response::from_status(ResponseStatus::Success);
+        let failure = Response::from_status(ResponseStatus::PsaErrorHardwareFailure);
+        assert!(shadow.record(
+            (&success, Duration::from_micros(100)),
+            (&success, Duration::from_micros(300))
+        ));
+        assert!(!shadow.record(
+            (&success, Duration::from_micros(100)),
+            (&failure, Duration::from_micros(100))
+        ));
+
+        let statistics = shadow.statistics();
+        assert_eq!(statistics.operations, 2);
+        assert_eq!(statistics.discrepancies, 1);
+        assert_eq!(statistics.average_primary_latency_us, 100);
+        assert_eq!(statistics.average_secondary_latency_us, 200);
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Log of the hashes signed by high-value keys
+//!
+//! For forensic purposes, it is necessary to know exactly what some keys, such as the keys of a
+//! certificate authority, have signed. The keys configured as high-value have each of their
+//! signatures recorded, with the hash signed, the algorithm, the application and the time, in a
+//! dedicated append-only file. If the record can not be written, the signature is not returned to
+//! the client.
+//!
+//! Each record is a line of tab-separated fields: the UNIX time in seconds, the provider, the
+//! application name, the key name, the algorithm, then the hash and the signature in hexadecimal.
+//! Names can not contain tabulations as control characters are refused by the name policy. The
+//! records are read back with `read_records`, by an administrator having access to the file.
+use crate::key_info_managers::KeyTriple;
+use log::error;
+use parsec_interface::operations::psa_algorithm::AsymmetricSignature;
+use parsec_interface::requests::{ResponseStatus, Result};
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Key, or all the keys of an application if the key name is not set, to log the signatures of
+#[derive(Clone, Deserialize, Debug)]
+pub struct HighValueKey {
+    pub app_name: String,
+    pub key_name: Option<String>,
+}
+
+/// Configuration of the signing log
+#[derive(Clone, Deserialize, Debug)]
+pub struct SigningLogConfig {
+    /// Path of the log file
+    pub path: String,
+    /// Keys whose signatures are logged
+    pub high_value_keys: Vec<HighValueKey>,
+}
+
+/// Record of a signature
+#[derive(Clone, Debug, PartialEq)]
+pub struct SigningRecord {
+    pub timestamp: u64,
+    pub provider: String,
+    pub app_name: String,
+    pub key_name: String,
+    pub alg: String,
+    pub hash: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Append-only log of the signatures of the high-value keys
+#[derive(Debug)]
+pub struct SigningLog {
+    path: PathBuf,
+    file: Mutex<File>,
+    high_value_keys: Vec<HighValueKey>,
+}
+
+impl SigningLog {
+    /// Opens the log file for appending, creating it if needed.
+    pub fn new(config: &SigningLogConfig) -> std::io::Result<SigningLog> {
+        let path = PathBuf::from(&config.path);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .or_else(|e| {
+                format_error!("Failed to open the signing log", e);
+                Err(e)
+            })?;
+
+        Ok(SigningLog {
+            path,
+            file: Mutex::new(file),
+            high_value_keys: config.high_value_keys.clone(),
+        })
+    }
+
+    /// Path of the log file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns true if the signatures of the key are logged.
+    pub fn is_high_value(&self, key_triple: &KeyTriple) -> bool {
+        self.high_value_keys.iter().any(|key| {
+            key.app_name == key_triple.app_name().get_name()
+                && key
+                    .key_name
+                    .as_ref()
+                    .map_or(true, |key_name| key_name == key_triple.key_name())
+        })
+    }
+
+    /// Records a signature made by a key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorStorageFailure` if the record could not be written to the file.
+    pub fn record(
+        &self,
+        key_triple: &KeyTriple,
+        alg: AsymmetricSignature,
+        hash: &[u8],
+        signature: &[u8],
+    ) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or(0);
+        let line = format!(
+            "{}\t{}\t{}\t{}\t{:?}\t{}\t{}\n",
+            timestamp,
+            key_triple.provider_id(),
+            key_triple.app_name(),
+            key_triple.key_name(),
+            alg,
+            hex::encode(hash),
+            hex::encode(signature)
+        );
+
+        let mut file = self.file.lock().expect("Signing log lock poisoned");
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.sync_data())
+            .or_else(|e| {
+                format_error!("Failed to write to the signing log", e);
+                Err(ResponseStatus::PsaErrorStorageFailure)
+            })
+    }
+}
+
+/// Reads the records of a signing log file.
+pub fn read_records<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<SigningRecord>> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(|line| {
+            parse_record(line).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "invalid signing log record",
+                )
+            })
+        })
+        .collect()
+}
+
+fn parse_record(line: &str) -> Option<SigningRecord> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 7 {
+        return None;
+    }
+
+    Some(SigningRecord {
+        timestamp: fields[0].parse().ok()?,
+        provider: fields[1].to_string(),
+        app_name: fields[2].to_string(),
+        key_name: fields[3].to_string(),
+        alg: fields[4].to_string(),
+        hash: hex::decode(fields[5]).ok()?,
+        signature: hex::decode(fields[6]).ok()?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_records, HighValueKey, SigningLog, SigningLogConfig};
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::KeyTriple;
+    use parsec_interface::operations::psa_algorithm::{AsymmetricSignature, Hash, SignHash};
+    use parsec_interface::requests::ProviderID;
+    use std::fs;
+
+    fn key_triple(app_name: &str, key_name: &str) -> KeyTriple {
+        KeyTriple::new(
+            ApplicationName::new(String::from(app_name)),
+            ProviderID::Pkcs11,
+            String::from(key_name),
+        )
+    }
+
+    #[test]
+    fn signatures_recorded() {
+        let path = env!("OUT_DIR").to_owned() + "/signing_log";
+        let _ = fs::remove_file(&path);
+        let log = SigningLog::new(&SigningLogConfig {
+            path: path.clone(),
+            high_value_keys: vec![
+                HighValueKey {
+                    app_name: String::from("ca"),
+                    key_name: Some(String::from("root")),
+                },
+                HighValueKey {
+                    app_name: String::from("payments"),
+                    key_name: None,
+                },
+            ],
+        })
+        .unwrap();
+
+        assert!(log.is_high_value(&key_triple("ca", "root")));
+        assert!(!log.is_high_value(&key_triple("ca", "intermediate")));
+        assert!(log.is_high_value(&key_triple("payments", "any")));
+        assert!(!log.is_high_value(&key_triple("other", "root")));
+
+        let alg = AsymmetricSignature::RsaPkcs1v15Sign {
+            hash_alg: SignHash::Specific(Hash::Sha256),
+        };
+        log.record(&key_triple("ca", "root"), alg, &[1, 2, 3], &[4, 5, 6])
+            .unwrap();
+        let records = read_records(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].app_name, "ca");
+        assert_eq!(records[0].key_name, "root");
+        assert_eq!(records[0].hash, vec![1, 2, 3]);
+        assert_eq!(records[0].signature, vec![4, 5, 6]);
+    }
+}
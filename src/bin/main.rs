@@ -31,15 +31,28 @@
 // This one is hard to avoid.
 #![allow(clippy::multiple_crate_versions)]
 
-use log::{info, trace};
-use parsec_service::utils::{ServiceBuilder, ServiceConfig};
-use signal_hook::{flag, SIGHUP, SIGTERM};
-use std::io::{Error, ErrorKind, Result};
+use log::{error, info, warn, LevelFilter};
+use parsec_service::front::front_end::FrontEndHandler;
+#[cfg(feature = "signed-config")]
+use parsec_service::utils::config_signature;
+#[cfg(feature = "key-manifest")]
+use parsec_service::utils::key_manifest;
+#[cfg(feature = "policy-bundle")]
+use parsec_service::utils::policy_bundle;
+#[cfg(feature = "self-check")]
+use parsec_service::utils::self_check;
+#[cfg(feature = "on-disk-manager")]
+use parsec_service::utils::storage_snapshot;
+use parsec_service::utils::{
+    correlation_id, key_import, key_store_compaction, warm_restart, EmbeddedServiceBuilder,
+    ServiceBuilder, ServiceConfig,
+};
+use signal_hook::{flag, SIGHUP, SIGTERM, SIGUSR1, SIGUSR2};
+use std::io::{Error, ErrorKind, Result, Write};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
-use std::time::Duration;
 use structopt::StructOpt;
 
 /// Parsec is the Platform AbstRaction for SECurity, a new open-source initiative to provide a
@@ -56,10 +69,37 @@ struct Opts {
     /// Sets the configuration file path
     #[structopt(short, long, default_value = "config.toml")]
     config: String,
+    /// Sets the public key verifying the signature of the configuration file, instead of the one
+    /// baked in at build time. It can be a `file:`, `env:` or `cred:` secret or the path of a file
+    /// containing the key encoded in hexadecimal.
+    #[cfg(feature = "signed-config")]
+    #[structopt(long)]
+    config_policy_key: Option<String>,
+    /// Imports the keys described in the key_import section of the configuration file when
+    /// starting
+    #[structopt(long)]
+    import_keys: bool,
+    /// Executes again the key operations which failed because their provider was unavailable,
+    /// recorded in the file of the dead_letters section of the configuration file, when starting
+    #[cfg(feature = "dead-letters")]
+    #[structopt(long)]
+    retry_failed_operations: bool,
+    /// Writes the manifest of all the keys, signed with the device identity, to the given path
+    /// when starting
+    #[cfg(feature = "key-manifest")]
+    #[structopt(long)]
+    export_key_manifest: Option<String>,
+    /// Checks all the mappings against their provider and compacts the Key Info Managers when
+    /// starting
+    #[structopt(long)]
+    compact_key_stores: bool,
+    /// Writes a snapshot of the storage of the Mbed Crypto provider and of its mappings to the
+    /// archive of the storage_snapshot section of the configuration file when starting
+    #[cfg(feature = "on-disk-manager")]
+    #[structopt(long)]
+    snapshot_storage: bool,
 }
 
-const MAIN_LOOP_DEFAULT_SLEEP: u64 = 10;
-
 fn main() -> Result<()> {
     // Parsing the command line arguments.
     let opts: Opts = Opts::from_args();
@@ -68,28 +108,45 @@ fn main() -> Result<()> {
     let kill_signal = Arc::new(AtomicBool::new(false));
     // Register a boolean set to true when the SIGHUP signal is received.
     let reload_signal = Arc::new(AtomicBool::new(false));
+    // Register a boolean set to true when the SIGUSR2 signal is received.
+    let upgrade_signal = Arc::new(AtomicBool::new(false));
+    // Register a boolean set to true when the SIGUSR1 signal is received.
+    let refresh_signal = Arc::new(AtomicBool::new(false));
     let _ = flag::register(SIGTERM, kill_signal.clone())?;
     let _ = flag::register(SIGHUP, reload_signal.clone())?;
+    let _ = flag::register(SIGUSR2, upgrade_signal.clone())?;
+    let _ = flag::register(SIGUSR1, refresh_signal.clone())?;
 
-    let mut config_file = ::std::fs::read_to_string(opts.config.clone())?;
-    let mut config: ServiceConfig = toml::from_str(&config_file).or_else(|e| {
-        Err(Error::new(
-            ErrorKind::InvalidInput,
-            format!("Failed to parse service configuration ({})", e),
-        ))
-    })?;
+    let config = read_config(&opts)?;
 
     log_setup(&config);
 
     info!("Parsec started. Configuring the service...");
 
-    let front_end_handler = ServiceBuilder::build_service(&config)?;
-    // Multiple threads can not just have a reference of the front end handler because they could
-    // outlive the run function. It is needed to give them all ownership of the front end handler
-    // through an Arc.
-    let mut front_end_handler = Arc::from(front_end_handler);
-    let mut listener = ServiceBuilder::start_listener(config.listener)?;
-    let mut threadpool = ServiceBuilder::build_threadpool(config.core_settings.thread_pool_size);
+    #[cfg(feature = "self-check")]
+    {
+        if let Some(self_check_config) = &config.self_check {
+            self_check::check(self_check_config)?;
+        }
+    }
+
+    let mut service = EmbeddedServiceBuilder::new(config).build()?;
+    import_keys(&opts, service.config(), service.front_end_handler())?;
+    #[cfg(feature = "dead-letters")]
+    retry_failed_operations(&opts, service.front_end_handler())?;
+    #[cfg(feature = "key-manifest")]
+    {
+        if let Some(path) = &opts.export_key_manifest {
+            let _ = key_manifest::export(service.front_end_handler().dispatcher(), path)?;
+        }
+    }
+    if opts.compact_key_stores {
+        let _ = key_store_compaction::compact(service.front_end_handler().dispatcher())?;
+    }
+    #[cfg(feature = "on-disk-manager")]
+    snapshot_storage(&opts, service.config(), service.front_end_handler())?;
+    let mut threadpool =
+        ServiceBuilder::build_threadpool(service.config().core_settings.thread_pool_size);
 
     // Notify systemd that the daemon is ready, the start command will block until this point.
     let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]);
@@ -97,68 +154,193 @@ fn main() -> Result<()> {
     info!("Parsec is ready.");
 
     while !kill_signal.load(Ordering::Relaxed) {
-        if reload_signal.swap(false, Ordering::Relaxed) {
+        let upgrade = upgrade_signal.swap(false, Ordering::Relaxed);
+        if reload_signal.swap(false, Ordering::Relaxed) || upgrade {
             let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Reloading]);
-            info!("SIGHUP signal received. Reloading the configuration...");
-
-            threadpool.join();
+            if upgrade {
+                info!("SIGUSR2 signal received. Handing off the listener to the service binary...");
+            } else {
+                info!("SIGHUP signal received. Reloading the configuration...");
+            }
 
-            // Explicitely call drop now because otherwise Rust will drop these variables only
-            // after they have been overwritten, in which case some values/libraries might be
-            // initialized twice.
-            drop(front_end_handler);
-            drop(listener);
+            // The providers are dropped before the new ones are built, some libraries can not be
+            // initialized twice. The listener and the Key Info Managers are kept.
+            let stopped_service = service.stop(&threadpool);
             drop(threadpool);
 
-            config_file = ::std::fs::read_to_string(opts.config.clone())?;
-            config = toml::from_str(&config_file).or_else(|e| {
-                Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("Failed to parse service configuration ({})", e),
-                ))
-            })?;
-            front_end_handler = Arc::from(ServiceBuilder::build_service(&config)?);
-            listener = ServiceBuilder::start_listener(config.listener)?;
-            threadpool = ServiceBuilder::build_threadpool(config.core_settings.thread_pool_size);
+            if upgrade {
+                // The providers were dropped above, the exec would not run their destructors.
+                let error = warm_restart::exec(
+                    stopped_service
+                        .config()
+                        .core_settings
+                        .upgrade_executable
+                        .as_deref(),
+                    stopped_service.listener_raw_fd(),
+                );
+                error!(
+                    "Failed to execute the service binary ({}), reloading the configuration instead.",
+                    error
+                );
+            }
+
+            let config = read_config(&opts)?;
+            set_log_level(&config);
+            service = EmbeddedServiceBuilder::new(config)
+                .reusing(stopped_service)
+                .build()?;
+            threadpool =
+                ServiceBuilder::build_threadpool(service.config().core_settings.thread_pool_size);
 
             let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]);
             info!("Parsec configuration reloaded.");
         }
 
-        if let Some(stream) = listener.accept() {
-            let front_end_handler = front_end_handler.clone();
-            threadpool.execute(move || {
-                front_end_handler.handle_request(stream);
-                trace!("handle_request egress");
-            });
-        } else {
-            ::std::thread::sleep(Duration::from_millis(
-                config
-                    .core_settings
-                    .idle_listener_sleep_duration
-                    .unwrap_or(MAIN_LOOP_DEFAULT_SLEEP),
-            ));
+        // All the capabilities are discovered again on SIGUSR1, only the stale ones periodically.
+        if refresh_signal.swap(false, Ordering::Relaxed) {
+            info!(
+                "SIGUSR1 signal received. Discovering the capabilities of the providers again..."
+            );
+            service.refresh_capabilities(&threadpool, false);
+        }
+
+        if !service.poll(&threadpool) {
+            service.idle();
         }
     }
 
     let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Stopping]);
     info!("SIGTERM signal received. Shutting down Parsec, waiting for all threads to finish...");
-    threadpool.join();
-    info!("Parsec is now terminated.");
+    if service.shut_down(&threadpool) {
+        info!("Parsec is now terminated.");
+    }
 
     Ok(())
 }
 
+// Reads and parses the configuration file, verifying its signature first if needed, and applies
+// the policy bundle if one is configured.
+fn read_config(opts: &Opts) -> Result<ServiceConfig> {
+    let config_file = ::std::fs::read_to_string(opts.config.clone())?;
+    #[cfg(feature = "signed-config")]
+    {
+        let policy_key = config_signature::policy_key(opts.config_policy_key.as_deref())?;
+        config_signature::verify_config_file(&opts.config, config_file.as_bytes(), &policy_key)?;
+    }
+
+    #[cfg_attr(not(feature = "policy-bundle"), allow(unused_mut))]
+    let mut config = toml::from_str(&config_file).or_else(|e| {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Failed to parse service configuration ({})", e),
+        ))
+    })?;
+    #[cfg(feature = "policy-bundle")]
+    policy_bundle::apply_configured(&mut config)?;
+
+    Ok(config)
+}
+
+// Imports the configured keys, only if asked for on the command line.
+fn import_keys(
+    opts: &Opts,
+    config: &ServiceConfig,
+    front_end_handler: &FrontEndHandler,
+) -> Result<()> {
+    match (&config.key_import, opts.import_keys) {
+        (Some(key_import_config), true) => {
+            let imported =
+                key_import::import_keys(key_import_config, front_end_handler.dispatcher())?;
+            info!("{} keys imported.", imported);
+        }
+        (Some(_), false) => {
+            info!("Keys to import configured, but the --import-keys flag is not set.")
+        }
+        (None, true) => {
+            warn!("The --import-keys flag is set, but no keys to import are configured.")
+        }
+        (None, false) => (),
+    }
+
+    Ok(())
+}
+
+// Retries the recorded failed operations, only if asked for on the command line.
+#[cfg(feature = "dead-letters")]
+fn retry_failed_operations(opts: &Opts, front_end_handler: &FrontEndHandler) -> Result<()> {
+    if !opts.retry_failed_operations {
+        return Ok(());
+    }
+    match front_end_handler.dispatcher().dead_letters() {
+        Some(dead_letters) => {
+            let outcome = dead_letters.retry(front_end_handler.dispatcher())?;
+            info!(
+                "{} failed operations resolved, {} still failing.",
+                outcome.resolved, outcome.remaining
+            );
+        }
+        None => warn!(
+            "The --retry-failed-operations flag is set, but failed operations are not recorded."
+        ),
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "on-disk-manager")]
+fn snapshot_storage(
+    opts: &Opts,
+    config: &ServiceConfig,
+    front_end_handler: &FrontEndHandler,
+) -> Result<()> {
+    if !opts.snapshot_storage {
+        return Ok(());
+    }
+    match &config.storage_snapshot {
+        Some(storage_snapshot_config) => {
+            storage_snapshot::snapshot(storage_snapshot_config, front_end_handler.dispatcher())
+        }
+        None => {
+            warn!(
+                "The --snapshot-storage flag is set, but the storage_snapshot section is missing."
+            );
+            Ok(())
+        }
+    }
+}
+
 fn log_setup(config: &ServiceConfig) {
     let mut env_log_builder = env_logger::builder();
 
-    if let Some(level) = config.core_settings.log_level {
-        let _ = env_log_builder.filter_level(level);
-    }
-    if let Some(true) = config.core_settings.log_timestamp {
-        let _ = env_log_builder.format_timestamp_millis();
-    } else {
-        let _ = env_log_builder.format_timestamp(None);
+    // The logger lets everything through and the configured level is set as the maximum level of
+    // the log crate, which can be changed when the configuration is reloaded.
+    if config.core_settings.log_level.is_some() {
+        let _ = env_log_builder.filter_level(LevelFilter::Trace);
     }
+    // The default format, followed by the correlation ID of the request the line was logged for.
+    let log_timestamp = config.core_settings.log_timestamp == Some(true);
+    let _ = env_log_builder.format(move |buf, record| {
+        write!(buf, "[")?;
+        if log_timestamp {
+            write!(buf, "{} ", buf.timestamp_millis())?;
+        }
+        write!(
+            buf,
+            "{:<5} {}",
+            buf.default_styled_level(record.level()),
+            record.module_path().unwrap_or_default()
+        )?;
+        if let Some(correlation_id) = correlation_id::current() {
+            write!(buf, " {}", correlation_id)?;
+        }
+        writeln!(buf, "] {}", record.args())
+    });
     env_log_builder.init();
+    set_log_level(config);
+}
+
+fn set_log_level(config: &ServiceConfig) {
+    if let Some(level) = config.core_settings.log_level {
+        log::set_max_level(level);
+    }
 }
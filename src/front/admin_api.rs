@@ -0,0 +1,576 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Read-only HTTP administration API
+//!
+//! Small HTTP server giving the status of the service as JSON, for dashboards and node agents
+//! which do not speak the Parsec wire protocol. It only answers `GET` requests on the following
+//! paths:
+//! * `/health`: whether the service answers to Ping, and the result of the reconciliation of the
+//!   mappings of the Key Info Managers with the keys of the providers done when they started
+//! * `/providers`: the providers available, with the opcodes they support
+//! * `/statistics`: the number of requests handled, of responses lost and of connections active,
+//!   the usage of the key slots of the providers, the rolling statistics of the operations of each
+//!   provider, the memory used by their requests and caches and the statistics of the shadowed
+//!   operations
+//! * `/metrics`: the number of requests handled and of connections active, the latency histograms
+//!   of the operations of each provider per opcode, their errors per response status, the number
+//!   of mappings of each provider and the placement of the worker threads and of the requests on
+//!   the CPUs they are pinned to, in the Prometheus text format
+//!
+//! The API does not authenticate its clients and can not modify anything. It only listens on a
+//! loopback address unless `allow_remote` is set, and never returns the names of applications or
+//! keys.
+use super::front_end::FrontEndHandler;
+use crate::back::dispatcher::Dispatcher;
+use crate::back::memory_limits::MemoryUsage;
+use crate::back::operation_metrics::LATENCY_BUCKETS_US;
+use crate::back::operation_statistics::StatisticsSnapshot;
+use crate::back::shadow::ShadowStatistics;
+use crate::key_info_managers::MappingHealth;
+use crate::utils::cpu_affinity;
+use log::{error, info, warn};
+use parsec_interface::operations::{list_opcodes, list_providers, ping};
+use parsec_interface::operations::{NativeOperation, NativeResult};
+use parsec_interface::requests::ProviderID;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:9290";
+// Time to wait between two checks for new connections or for the server to stop.
+const ACCEPT_SLEEP: Duration = Duration::from_millis(100);
+const STREAM_TIMEOUT: Duration = Duration::from_secs(1);
+// Maximum size of the request line and headers read from a client.
+const MAX_REQUEST_LEN: u64 = 8192;
+const JSON_CONTENT_TYPE: &str = "application/json";
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+// Providers whose backend handler can be found in the dispatcher.
+const PROVIDER_IDS: [ProviderID; 4] = [
+    ProviderID::Core,
+    ProviderID::MbedCrypto,
+    ProviderID::Pkcs11,
+    ProviderID::Tpm,
+];
+
+/// Configuration of the administration API
+#[derive(Clone, Deserialize, Debug)]
+pub struct AdminApiConfig {
+    /// Socket address to listen on, defaults to 127.0.0.1:9290
+    pub address: Option<String>,
+    /// Allow listening on an address which is not a loopback one, defaults to false
+    pub allow_remote: Option<bool>,
+}
+
+/// Running administration API server
+///
+/// The server thread is stopped when this is dropped.
+#[derive(Debug)]
+pub struct AdminApiServer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AdminApiServer {
+    /// Starts serving the status of the service handled by the front end handler.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the address is invalid, or not a loopback one
+    /// while remote access is not allowed, and the error of binding to the address otherwise.
+    pub fn start(
+        config: &AdminApiConfig,
+        front_end_handler: Arc<FrontEndHandler>,
+    ) -> Result<AdminApiServer> {
+        let address: SocketAddr = config
+            .address
+            .as_deref()
+            .unwrap_or(DEFAULT_ADDRESS)
+            .parse()
+            .or_else(|e| {
+                format_error!("Failed to parse the administration API address", e);
+                Err(Error::new(ErrorKind::InvalidData, "invalid address"))
+            })?;
+        if !address.ip().is_loopback() {
+            if config.allow_remote.unwrap_or(false) {
+                warn!("The administration API is reachable from other hosts.");
+            } else {
+                error!("The administration API address is not a loopback one while remote access is not allowed.");
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "remote access to the administration API not allowed",
+                ));
+            }
+        }
+
+        let listener = TcpListener::bind(address)?;
+        // Non-blocking so that the thread can notice when the server is stopped.
+        listener.set_nonblocking(true)?;
+        info!("Administration API listening on {}.", address);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if let Err(e) = handle_connection(stream, &front_end_handler) {
+                            format_error!("Failed to answer an administration API request", e);
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_SLEEP),
+                    Err(e) => {
+                        format_error!("Failed to accept an administration API connection", e);
+                        thread::sleep(ACCEPT_SLEEP);
+                    }
+                }
+            }
+        });
+
+        Ok(AdminApiServer {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for AdminApiServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The administration API thread panicked.");
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Health {
+    status: &'static str,
+    mappings: Vec<ProviderMappings>,
+}
+
+#[derive(Serialize, Debug)]
+struct ProviderMappings {
+    provider: String,
+    #[serde(flatten)]
+    health: MappingHealth,
+}
+
+#[derive(Serialize, Debug)]
+struct Provider {
+    id: String,
+    uuid: String,
+    description: String,
+    vendor: String,
+    version: String,
+    opcodes: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct KeySlots {
+    provider: String,
+    capacity: usize,
+    used: usize,
+    reserved: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct ProviderStatistics {
+    provider: String,
+    #[serde(flatten)]
+    operations: StatisticsSnapshot,
+}
+
+#[derive(Serialize, Debug)]
+struct ProviderMemory {
+    provider: String,
+    #[serde(flatten)]
+    usage: MemoryUsage,
+}
+
+#[derive(Serialize, Debug)]
+struct ShadowedStatistics {
+    primary: String,
+    secondary: String,
+    #[serde(flatten)]
+    operations: ShadowStatistics,
+}
+
+#[derive(Serialize, Debug)]
+struct Statistics {
+    requests_received: u64,
+    requests_failed: u64,
+    responses_lost: u64,
+    connections_active: u64,
+    key_slots: Vec<KeySlots>,
+    providers: Vec<ProviderStatistics>,
+    memory: Vec<ProviderMemory>,
+    shadows: Vec<ShadowedStatistics>,
+}
+
+fn handle_connection(mut stream: TcpStream, front_end_handler: &FrontEndHandler) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(STREAM_TIMEOUT))?;
+    stream.set_write_timeout(Some(STREAM_TIMEOUT))?;
+
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_LEN));
+    let mut request_line = String::new();
+    let _ = reader.read_line(&mut request_line)?;
+    // Read the headers, which are not needed, so that the connection is not reset when closed
+    // with data left unread.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            PROMETHEUS_CONTENT_TYPE,
+            Some(metrics(front_end_handler).into_bytes()),
+        ),
+        (Some("GET"), Some(path)) => {
+            let (status, body) = route(path, front_end_handler);
+            (status, JSON_CONTENT_TYPE, body)
+        }
+        (Some(_), Some(_)) => ("405 Method Not Allowed", JSON_CONTENT_TYPE, None),
+        _ => ("400 Bad Request", JSON_CONTENT_TYPE, None),
+    };
+    let body = body.unwrap_or_default();
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+fn route(path: &str, front_end_handler: &FrontEndHandler) -> (&'static str, Option<Vec<u8>>) {
+    let dispatcher = front_end_handler.dispatcher();
+    let body = match path {
+        "/health" => {
+            let health = health(dispatcher);
+            let status = if health.status == "ok" {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            return (status, serde_json::to_vec(&health).ok());
+        }
+        "/providers" => providers(dispatcher).map(|providers| serde_json::to_vec(&providers)),
+        "/statistics" => Some(serde_json::to_vec(&statistics(
+            dispatcher,
+            front_end_handler,
+        ))),
+        _ => return ("404 Not Found", None),
+    };
+
+    match body {
+        Some(Ok(body)) => ("200 OK", Some(body)),
+        _ => ("500 Internal Server Error", None),
+    }
+}
+
+fn execute(dispatcher: &Dispatcher, operation: NativeOperation) -> Option<NativeResult> {
+    dispatcher
+        .backend(ProviderID::Core)?
+        .execute_operation(operation, None, None)
+        .ok()
+}
+
+fn health(dispatcher: &Dispatcher) -> Health {
+    let status = match execute(dispatcher, NativeOperation::Ping(ping::Operation {})) {
+        Some(NativeResult::Ping(_)) => "ok",
+        _ => "unavailable",
+    };
+    let mappings = [ProviderID::MbedCrypto, ProviderID::Pkcs11, ProviderID::Tpm]
+        .iter()
+        .filter_map(|provider_id| {
+            Some(ProviderMappings {
+                provider: provider_id.to_string(),
+                health: dispatcher.backend(*provider_id)?.mapping_health()?,
+            })
+        })
+        .collect();
+
+    Health { status, mappings }
+}
+
+fn providers(dispatcher: &Dispatcher) -> Option<Vec<Provider>> {
+    let providers = match execute(
+        dispatcher,
+        NativeOperation::ListProviders(list_providers::Operation {}),
+    )? {
+        NativeResult::ListProviders(result) => result.providers,
+        _ => return None,
+    };
+
+    providers
+        .into_iter()
+        .map(|provider| {
+            let opcodes = match execute(
+                dispatcher,
+                NativeOperation::ListOpcodes(list_opcodes::Operation {
+                    provider_id: provider.id,
+                }),
+            )? {
+                NativeResult::ListOpcodes(result) => result.opcodes,
+                _ => return None,
+            };
+            let mut opcodes: Vec<String> = opcodes
+                .iter()
+                .map(|opcode| format!("{:?}", opcode))
+                .collect();
+            opcodes.sort();
+
+            Some(Provider {
+                id: provider.id.to_string(),
+                uuid: provider.uuid.to_string(),
+                description: provider.description,
+                vendor: provider.vendor,
+                version: format!(
+                    "{}.{}.{}",
+                    provider.version_maj, provider.version_min, provider.version_rev
+                ),
+                opcodes,
+            })
+        })
+        .collect()
+}
+
+fn statistics(dispatcher: &Dispatcher, front_end_handler: &FrontEndHandler) -> Statistics {
+    let requests = front_end_handler.statistics();
+    let key_slots = [ProviderID::MbedCrypto, ProviderID::Pkcs11, ProviderID::Tpm]
+        .iter()
+        .filter_map(|provider_id| {
+            let usage = dispatcher.backend(*provider_id)?.key_slots_usage()?.ok()?;
+            Some(KeySlots {
+                provider: provider_id.to_string(),
+                capacity: usage.capacity,
+                used: usage.used,
+                reserved: usage.reserved,
+            })
+        })
+        .collect();
+    let providers = PROVIDER_IDS
+        .iter()
+        .filter_map(|provider_id| {
+            Some(ProviderStatistics {
+                provider: provider_id.to_string(),
+                operations: dispatcher.backend(*provider_id)?.statistics(),
+            })
+        })
+        .collect();
+    let memory = PROVIDER_IDS
+        .iter()
+        .filter_map(|provider_id| {
+            Some(ProviderMemory {
+                provider: provider_id.to_string(),
+                usage: dispatcher.backend(*provider_id)?.memory_usage(),
+            })
+        })
+        .collect();
+
+    Statistics {
+        requests_received: requests.received,
+        requests_failed: requests.failed,
+        responses_lost: requests.lost,
+        connections_active: requests.active,
+        key_slots,
+        providers,
+        memory,
+        shadows: dispatcher
+            .shadow_statistics()
+            .into_iter()
+            .map(|(primary, secondary, operations)| ShadowedStatistics {
+                primary: primary.to_string(),
+                secondary: secondary.to_string(),
+                operations,
+            })
+            .collect(),
+    }
+}
+
+// Writes the help and type lines of a metric.
+fn metric_header(text: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, metric_type);
+}
+
+// Writes the metrics of the service in the Prometheus text format. Writing to a string can not
+// fail.
+fn metrics(front_end_handler: &FrontEndHandler) -> String {
+    let dispatcher = front_end_handler.dispatcher();
+    let requests = front_end_handler.statistics();
+    let placement = cpu_affinity::placement_statistics();
+    let mut text = String::new();
+
+    for (name, metric_type, help, value) in &[
+        (
+            "parsec_requests_received_total",
+            "counter",
+            "Number of requests read from the clients.",
+            requests.received,
+        ),
+        (
+            "parsec_requests_failed_total",
+            "counter",
+            "Number of requests whose response does not have a success status.",
+            requests.failed,
+        ),
+        (
+            "parsec_responses_lost_total",
+            "counter",
+            "Number of responses which could not be written back to the clients.",
+            requests.lost,
+        ),
+        (
+            "parsec_connections_active",
+            "gauge",
+            "Number of client connections being handled.",
+            requests.active,
+        ),
+        (
+            "parsec_worker_threads_pinned",
+            "gauge",
+            "Number of worker threads pinned to their CPU set.",
+            placement.pinned_threads,
+        ),
+        (
+            "parsec_worker_pinning_failures_total",
+            "counter",
+            "Number of worker threads which could not be pinned to their CPU set.",
+            placement.pinning_failures,
+        ),
+    ] {
+        metric_header(&mut text, name, metric_type, help);
+        let _ = writeln!(text, "{} {}", name, value);
+    }
+
+    metric_header(
+        &mut text,
+        "parsec_worker_requests_total",
+        "counter",
+        "Number of requests started by the pinned worker threads, per CPU placement.",
+    );
+    for (cpu_set, count) in &[
+        ("in", placement.requests_in_cpu_set),
+        ("outside", placement.requests_outside_cpu_set),
+    ] {
+        let _ = writeln!(
+            text,
+            "parsec_worker_requests_total{{cpu_set=\"{}\"}} {}",
+            cpu_set, count
+        );
+    }
+
+    let backends: Vec<_> = PROVIDER_IDS
+        .iter()
+        .filter_map(|provider_id| Some((*provider_id, dispatcher.backend(*provider_id)?.metrics())))
+        .collect();
+
+    metric_header(
+        &mut text,
+        "parsec_operation_duration_seconds",
+        "histogram",
+        "Latency of the operations executed by the providers.",
+    );
+    for (provider_id, metrics) in &backends {
+        let mut latencies: Vec<_> = metrics
+            .latencies
+            .iter()
+            .map(|(opcode, histogram)| (format!("{:?}", opcode), histogram))
+            .collect();
+        latencies.sort_by(|(first, _), (second, _)| first.cmp(second));
+        for (opcode, histogram) in latencies {
+            let labels = format!("provider=\"{:?}\",opcode=\"{}\"", provider_id, opcode);
+            for (bound_us, count) in LATENCY_BUCKETS_US.iter().zip(histogram.buckets.iter()) {
+                let _ = writeln!(
+                    text,
+                    "parsec_operation_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels,
+                    *bound_us as f64 / 1e6,
+                    count
+                );
+            }
+            let _ = writeln!(
+                text,
+                "parsec_operation_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                text,
+                "parsec_operation_duration_seconds_sum{{{}}} {}",
+                labels,
+                histogram.sum_us as f64 / 1e6
+            );
+            let _ = writeln!(
+                text,
+                "parsec_operation_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+    }
+
+    metric_header(
+        &mut text,
+        "parsec_operation_errors_total",
+        "counter",
+        "Number of operations of the providers which failed, per response status.",
+    );
+    for (provider_id, metrics) in &backends {
+        for (status, count) in &metrics.errors {
+            let _ = writeln!(
+                text,
+                "parsec_operation_errors_total{{provider=\"{:?}\",status=\"{}\"}} {}",
+                provider_id, status, count
+            );
+        }
+    }
+
+    // The mappings of all the Key Info Managers are only listed by the core provider.
+    if let Some(Ok(inventory)) = dispatcher
+        .backend(ProviderID::Core)
+        .map(|backend| backend.key_inventory())
+    {
+        let mut mappings: BTreeMap<String, usize> = backends
+            .iter()
+            .filter(|(provider_id, _)| *provider_id != ProviderID::Core)
+            .map(|(provider_id, _)| (format!("{:?}", provider_id), 0))
+            .collect();
+        for (key_triple, _) in inventory {
+            *mappings
+                .entry(format!("{:?}", key_triple.provider_id()))
+                .or_default() += 1;
+        }
+        metric_header(
+            &mut text,
+            "parsec_key_info_mappings",
+            "gauge",
+            "Number of mappings stored in the Key Info Managers, per provider.",
+        );
+        for (provider, count) in mappings {
+            let _ = writeln!(
+                text,
+                "parsec_key_info_mappings{{provider=\"{}\"}} {}",
+                provider, count
+            );
+        }
+    }
+
+    text
+}
@@ -5,13 +5,12 @@
 //! Expose Parsec functionality using Unix domain sockets as an IPC layer.
 //! The local socket is created at a predefined location.
 use super::listener;
-use listener::Listen;
-use listener::ReadWrite;
+use listener::{Connection, Listen};
 use log::error;
 use std::fs;
 use std::io::{Error, ErrorKind, Result};
-use std::os::unix::io::FromRawFd;
-use std::os::unix::net::UnixListener;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::Path;
 use std::time::Duration;
 
@@ -39,6 +38,8 @@ impl DomainSocketListener {
         // If this Parsec instance was socket activated (see the `parsec.socket`
         // file), the listener will be opened by systemd and passed to the
         // process.
+        // The listener of a previous binary of the service is handed off the
+        // same way, see the `warm_restart` module.
         // If Parsec was service activated or not started under systemd, this
         // will return `0`.
         let listener = match sd_notify::listen_fds()? {
@@ -56,7 +57,8 @@ impl DomainSocketListener {
             }
             1 => {
                 // No need to set the socket as non-blocking, parsec.service
-                // already requests that.
+                // already requests that and a socket handed off by a previous
+                // binary of the service already is.
                 let nfd = sd_notify::SD_LISTEN_FDS_START;
                 // Safe as listen_fds gives us the information that one file descriptor was
                 // received and its value starts from SD_LISTEN_FDS_START.
@@ -83,7 +85,7 @@ impl Listen for DomainSocketListener {
         self.timeout = duration;
     }
 
-    fn accept(&self) -> Option<Box<dyn ReadWrite + Send>> {
+    fn accept(&self) -> Option<Connection> {
         let stream_result = self.listener.accept();
         match stream_result {
             Ok((stream, _)) => {
@@ -97,7 +99,17 @@ impl Listen for DomainSocketListener {
                     format_error!("Failed to set stream as blocking", err);
                     None
                 } else {
-                    Some(Box::from(stream))
+                    let metadata = match listener::peer_credentials(&stream) {
+                        Ok(metadata) => Some(metadata),
+                        Err(err) => {
+                            format_error!("Failed to get the peer credentials", err);
+                            None
+                        }
+                    };
+                    Some(Connection {
+                        stream: Box::from(stream),
+                        metadata,
+                    })
                 }
             }
             Err(err) => {
@@ -110,6 +122,10 @@ impl Listen for DomainSocketListener {
             }
         }
     }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.listener.as_raw_fd())
+    }
 }
 
 /// Builder for `DomainSocketListener`
@@ -0,0 +1,2259 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Operations of the service which are not part of the wire protocol yet
+//!
+//! Some operations of the service have no opcode in `parsec-interface`, and requests with an
+//! unknown opcode can not be read from the listener. Until they are added to it, they are served
+//! on a separate Unix domain socket, on which a client writes a single request as a JSON object on
+//! one line and reads back its response, a JSON object on one line too.
+//!
+//! A request names its `operation` and is authenticated by its `auth_type`, named as in the
+//! authenticators configuration, and its `auth` field, the base64 of the authentication field of
+//! the wire protocol. The other fields depend on the operation:
+//! * `MintDelegationToken`: mints a token allowing the `delegate` application to use the key
+//!   `key_name` of the application on the `provider` for the `opcodes` listed, during `validity`
+//!   seconds, returned as the base64 `token` field
+//! * `ExecuteDelegated`: executes the request, of the `opcode` and base64 protobuf `body` given,
+//!   of a delegate on a key of another application, presenting the base64 `token` minted by the
+//!   owner of the key. The protobuf body of the result is returned as the base64 `body` field
+//! * `PlatformEvidence`: gathers the measured boot evidence of the platform with a quote of the
+//!   `provider` over the base64 `nonce` of a verifier, see the `platform_evidence` module. The
+//!   `tpm_event_log` and `ima_log`, if exposed by the kernel, and the `attest`, `signature` and
+//!   `public_key` of the quote are returned in base64
+//! * `GenerateKeys`: generates the keys of the application on the `provider` described by the base64
+//!   protobuf `bodies` of `PsaGenerateKey` operations given, in a single call to the provider, with
+//!   the `labels` given, if any, set on each of them. The `statuses` of the generation of each key
+//!   are returned in the same order
+//! * `VerifyHashWithPublicKey`: verifies the base64 `signature` of the base64 `hash` with the `alg`
+//!   given, using the base64 `public_key` given, in the format of `PsaExportPublicKey`, instead of a
+//!   stored key. The type and size of the key are taken from its `attributes`. The attributes and
+//!   the algorithm are in the serde representation of the `parsec-interface` types. An invalid
+//!   signature has the `PsaErrorInvalidSignature` status
+//! * `ImportPeerKey`: imports the public key of a peer, described by the base64 protobuf `body` of
+//!   a `PsaImportKey` operation, only usable for verification or derivation, which is destroyed
+//!   once its `time_to_live`, in seconds, elapsed
+//! * `CopyKey`: copies the key `key_name` of the application on the `provider` under the
+//!   `destination_key_name`, the copy being only allowed the `usage_flags` given, in the serde
+//!   representation of the `parsec-interface` type, which must all be allowed to the original key
+//! * `CreateCounter`: creates the monotonic counter of the key `key_name` of the application on the
+//!   `provider`, starting at 0, see the `key_counters` module
+//! * `IncrementAndSign`: increments the counter of the key `key_name` of the application on the
+//!   `provider` and signs its new value along with the base64 `data` given, with the `alg` given in
+//!   the serde representation of the `parsec-interface` type. The new `counter` value and the
+//!   base64 `signature` are returned
+//! * `WrapKey`: wraps the base64 `key_material` given with the key encryption key of the
+//!   application, see the `app_keks` module, bound to the base64 `label` given, empty if omitted.
+//!   The base64 `wrapped` key is returned
+//! * `UnwrapKey`: unwraps the base64 `wrapped` key with the key encryption key of the application
+//!   and the base64 `label` it was wrapped with. The base64 `key_material` is returned
+//! * `UnlockKey`: unlocks the key `key_name` of the application on the `provider`, which needs the
+//!   base64 `credential` given, such as the PIN of a smartcard key, before each use. The credential
+//!   is cached for the peer process of the connection: the key is unlocked for its requests only
+//! * `ActivateKey`: activates the pre-active key `key_name` on the `provider` of the `key_owner`
+//!   application, the application of the request if omitted, see the `key_activation` module.
+//!   Administrators can activate the keys of any application, the owners only their own keys unless
+//!   the configuration reserves it to the administrators
+//! * `StoreCertificate`: stores the base64 DER X.509 `certificate` given alongside the key
+//!   `key_name` of the application on the `provider`, replacing the previous one, in the provider or,
+//!   if it can not store certificates, with the information of the key, see the `key_certificates`
+//!   module
+//! * `GetCertificate`: gets the certificate stored alongside the key `key_name` of the application
+//!   on the `provider`, returned as the base64 DER `certificate` field
+//! * `DeleteCertificate`: deletes the certificate stored alongside the key `key_name` of the
+//!   application on the `provider`
+//! * `SetKeyLabels`: sets the `labels` of the key `key_name` of the application on the `provider`,
+//!   an object of string values, replacing its previous ones, see the `key_labels` module
+//! * `ListKeysByLabels`: lists the keys of the application on the `provider` having all the `labels`
+//!   given, with the same values, all its keys if omitted. The `keys` are returned as objects with
+//!   their `name` and all their `labels`
+//! * `ChangeBackendAuth`: changes the authentication value the `provider` presents to its backend,
+//!   such as the hierarchy authentication value of a TPM or the user PIN of a PKCS 11 token once
+//!   changed on it, to the base64 `new_auth` given. Reserved to the administrators, who then have to
+//!   update the configuration of the provider
+//! * `DeviceCertificate`: gets the certificate of the device identity, see the `device_identity`
+//!   module, returned as the base64 DER `certificate` field
+//!
+//! * `Handshake`: describes what the service accepts, without authentication, so that clients can
+//!   pick compatible options instead of assuming them: the `wire_protocol_version` of the core
+//!   provider, as "major.minor", the `content_types` of the request bodies and the `accept_types` of
+//!   the response bodies, the `authenticators` configured, named as their `auth_type`, and the
+//!   operations of this API, listed as `extensions`, which depend on the features compiled
+//!
+//! Providers are named by their type, as in the provider configurations, and opcodes as in the
+//! `denied_opcodes` configuration. The response always has the `status` of the operation, named as
+//! the variants of `ResponseStatus`, and the fields of the result of the operation if it has one.
+//!
+//! The requests are authenticated by the authenticators of the front end handler and go through
+//! the same policies as the requests read from the listener, the credentials of the peer process
+//! being their connection metadata. Each operation can be denied by its name in the
+//! `denied_opcodes` configuration and is submitted to the policy engine under its name, along with
+//! the opcodes of the operations it executes on behalf of the request, if any.
+use super::front_end::{FrontEndHandler, Operation};
+use super::listener::{self, ConnectionMetadata};
+use crate::authenticators::authenticator_chain;
+use crate::authenticators::ApplicationName;
+use crate::providers::provider_id_from_type;
+use crate::utils::opcode_from_name;
+use log::{error, info};
+use parsec_interface::operations::psa_algorithm::AsymmetricSignature;
+use parsec_interface::operations::psa_key_attributes::{Attributes, UsageFlags};
+use parsec_interface::operations::{list_authenticators, ping, NativeOperation, NativeResult};
+use parsec_interface::requests::request::{RequestAuth, RequestBody};
+use parsec_interface::requests::{Opcode, ProviderID, ResponseStatus};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use threadpool::ThreadPool;
+use zeroize::Zeroizing;
+
+const DEFAULT_SOCKET_PATH: &str = "/tmp/parsec-extension-socket";
+const STREAM_TIMEOUT: Duration = Duration::from_secs(1);
+// Maximum size of a request line, the bodies it carries being encoded in base64.
+const MAX_REQUEST_LEN: u64 = 1 << 20;
+
+/// Configuration of the extension API
+#[derive(Clone, Deserialize, Debug)]
+pub struct ExtensionApiConfig {
+    /// Path of the socket to listen on, defaults to /tmp/parsec-extension-socket
+    pub socket_path: Option<String>,
+}
+
+/// Running extension API server
+///
+/// The connections are accepted when the service polls the server and their requests are executed
+/// on the thread pool of the service, as the ones of the listener. The server stops listening when
+/// this is dropped.
+#[derive(Debug)]
+pub struct ExtensionApiServer {
+    listener: UnixListener,
+    front_end_handler: Arc<FrontEndHandler>,
+}
+
+impl ExtensionApiServer {
+    /// Starts serving the operations which are not part of the wire protocol to the clients of
+    /// the front end handler.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of removing a previous socket or of binding to the socket path.
+    pub fn start(
+        config: &ExtensionApiConfig,
+        front_end_handler: Arc<FrontEndHandler>,
+    ) -> Result<ExtensionApiServer> {
+        let socket_path = config.socket_path.as_deref().unwrap_or(DEFAULT_SOCKET_PATH);
+        if Path::new(socket_path).exists() {
+            fs::remove_file(socket_path)?;
+        }
+        let listener = UnixListener::bind(socket_path)?;
+        // Non-blocking so that polling the server does not wait for a connection.
+        listener.set_nonblocking(true)?;
+        info!("Extension API listening on {}.", socket_path);
+
+        Ok(ExtensionApiServer {
+            listener,
+            front_end_handler,
+        })
+    }
+
+    /// Accepts the next connection, if any, and answers its request on the thread pool. Returns
+    /// false if no connection was waiting.
+    pub fn poll(&self, threadpool: &ThreadPool) -> bool {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                let front_end_handler = self.front_end_handler.clone();
+                threadpool.execute(move || {
+                    if let Err(e) = handle_connection(stream, &front_end_handler) {
+                        format_error!("Failed to answer an extension API request", e);
+                    }
+                });
+                true
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(e) => {
+                format_error!("Failed to accept an extension API connection", e);
+                false
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ExtensionRequest {
+    #[serde(default)]
+    auth_type: String,
+    #[serde(default)]
+    auth: String,
+    #[serde(flatten)]
+    operation: ExtensionOperation,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "operation")]
+enum ExtensionOperation {
+    MintDelegationToken {
+        provider: String,
+        key_name: String,
+        delegate: String,
+        opcodes: Vec<String>,
+        validity: u64,
+    },
+    ExecuteDelegated {
+        provider: String,
+        token: String,
+        opcode: String,
+        body: String,
+    },
+    PlatformEvidence {
+        provider: String,
+        nonce: String,
+    },
+    GenerateKeys {
+        provider: String,
+        bodies: Vec<String>,
+        #[serde(default)]
+        labels: BTreeMap<String, String>,
+    },
+    VerifyHashWithPublicKey {
+        provider: String,
+        attributes: Attributes,
+        public_key: String,
+        alg: AsymmetricSignature,
+        hash: String,
+        signature: String,
+    },
+    ImportPeerKey {
+        provider: String,
+        body: String,
+        time_to_live: u64,
+    },
+    CopyKey {
+        provider: String,
+        key_name: String,
+        destination_key_name: String,
+        usage_flags: UsageFlags,
+    },
+    #[cfg(feature = "key-counters")]
+    CreateCounter {
+        provider: String,
+        key_name: String,
+    },
+    #[cfg(feature = "key-counters")]
+    IncrementAndSign {
+        provider: String,
+        key_name: String,
+        alg: AsymmetricSignature,
+        data: String,
+    },
+    WrapKey {
+        provider: String,
+        key_material: String,
+        #[serde(default)]
+        label: String,
+    },
+    UnwrapKey {
+        provider: String,
+        wrapped: String,
+        #[serde(default)]
+        label: String,
+    },
+    UnlockKey {
+        provider: String,
+        key_name: String,
+        credential: String,
+    },
+    ActivateKey {
+        provider: String,
+        key_owner: Option<String>,
+        key_name: String,
+    },
+    StoreCertificate {
+        provider: String,
+        key_name: String,
+        certificate: String,
+    },
+    GetCertificate {
+        provider: String,
+        key_name: String,
+    },
+    DeleteCertificate {
+        provider: String,
+        key_name: String,
+    },
+    SetKeyLabels {
+        provider: String,
+        key_name: String,
+        labels: BTreeMap<String, String>,
+    },
+    ListKeysByLabels {
+        provider: String,
+        #[serde(default)]
+        labels: BTreeMap<String, String>,
+    },
+    ChangeBackendAuth {
+        provider: String,
+        new_auth: String,
+    },
+    #[cfg(feature = "device-identity")]
+    DeviceCertificate,
+    Handshake,
+}
+
+impl ExtensionOperation {
+    // Type of the provider the operation is executed on, if any.
+    fn provider(&self) -> Option<&str> {
+        match self {
+            ExtensionOperation::MintDelegationToken { provider, .. }
+            | ExtensionOperation::ExecuteDelegated { provider, .. }
+            | ExtensionOperation::PlatformEvidence { provider, .. }
+            | ExtensionOperation::GenerateKeys { provider, .. }
+            | ExtensionOperation::VerifyHashWithPublicKey { provider, .. }
+            | ExtensionOperation::ImportPeerKey { provider, .. }
+            | ExtensionOperation::CopyKey { provider, .. }
+            | ExtensionOperation::WrapKey { provider, .. }
+            | ExtensionOperation::UnwrapKey { provider, .. }
+            | ExtensionOperation::UnlockKey { provider, .. }
+            | ExtensionOperation::ActivateKey { provider, .. }
+            | ExtensionOperation::StoreCertificate { provider, .. }
+            | ExtensionOperation::GetCertificate { provider, .. }
+            | ExtensionOperation::DeleteCertificate { provider, .. }
+            | ExtensionOperation::SetKeyLabels { provider, .. }
+            | ExtensionOperation::ListKeysByLabels { provider, .. }
+            | ExtensionOperation::ChangeBackendAuth { provider, .. } => Some(provider),
+            #[cfg(feature = "key-counters")]
+            ExtensionOperation::CreateCounter { provider, .. }
+            | ExtensionOperation::IncrementAndSign { provider, .. } => Some(provider),
+            #[cfg(feature = "device-identity")]
+            ExtensionOperation::DeviceCertificate => None,
+            ExtensionOperation::Handshake => None,
+        }
+    }
+
+    // Name of the operation, as listed by `extension_operations`.
+    fn name(&self) -> &'static str {
+        match self {
+            ExtensionOperation::MintDelegationToken { .. } => "MintDelegationToken",
+            ExtensionOperation::ExecuteDelegated { .. } => "ExecuteDelegated",
+            ExtensionOperation::PlatformEvidence { .. } => "PlatformEvidence",
+            ExtensionOperation::GenerateKeys { .. } => "GenerateKeys",
+            ExtensionOperation::VerifyHashWithPublicKey { .. } => "VerifyHashWithPublicKey",
+            ExtensionOperation::ImportPeerKey { .. } => "ImportPeerKey",
+            ExtensionOperation::CopyKey { .. } => "CopyKey",
+            #[cfg(feature = "key-counters")]
+            ExtensionOperation::CreateCounter { .. } => "CreateCounter",
+            #[cfg(feature = "key-counters")]
+            ExtensionOperation::IncrementAndSign { .. } => "IncrementAndSign",
+            ExtensionOperation::WrapKey { .. } => "WrapKey",
+            ExtensionOperation::UnwrapKey { .. } => "UnwrapKey",
+            ExtensionOperation::UnlockKey { .. } => "UnlockKey",
+            ExtensionOperation::ActivateKey { .. } => "ActivateKey",
+            ExtensionOperation::StoreCertificate { .. } => "StoreCertificate",
+            ExtensionOperation::GetCertificate { .. } => "GetCertificate",
+            ExtensionOperation::DeleteCertificate { .. } => "DeleteCertificate",
+            ExtensionOperation::SetKeyLabels { .. } => "SetKeyLabels",
+            ExtensionOperation::ListKeysByLabels { .. } => "ListKeysByLabels",
+            ExtensionOperation::ChangeBackendAuth { .. } => "ChangeBackendAuth",
+            #[cfg(feature = "device-identity")]
+            ExtensionOperation::DeviceCertificate => "DeviceCertificate",
+            ExtensionOperation::Handshake => "Handshake",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct ExtensionResponse {
+    status: String,
+    #[serde(flatten)]
+    result: Option<ExtensionResult>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum ExtensionResult {
+    Handshake {
+        wire_protocol_version: String,
+        content_types: Vec<String>,
+        accept_types: Vec<String>,
+        authenticators: Vec<String>,
+        extensions: Vec<String>,
+    },
+    Token {
+        token: String,
+    },
+    Body {
+        body: String,
+    },
+    Evidence {
+        tpm_event_log: Option<String>,
+        ima_log: Option<String>,
+        attest: String,
+        signature: String,
+        public_key: String,
+    },
+    Statuses {
+        statuses: Vec<String>,
+    },
+    #[cfg(feature = "key-counters")]
+    Counter {
+        counter: u64,
+        signature: String,
+    },
+    Certificate {
+        certificate: String,
+    },
+    Wrapped {
+        wrapped: String,
+    },
+    KeyMaterial {
+        key_material: String,
+    },
+    Keys {
+        keys: Vec<LabeledKey>,
+    },
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct LabeledKey {
+    name: String,
+    labels: BTreeMap<String, String>,
+}
+
+impl ExtensionResponse {
+    fn from_status(status: ResponseStatus) -> ExtensionResponse {
+        ExtensionResponse {
+            status: format!("{:?}", status),
+            result: None,
+        }
+    }
+}
+
+fn handle_connection(mut stream: UnixStream, front_end_handler: &FrontEndHandler) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(STREAM_TIMEOUT))?;
+    stream.set_write_timeout(Some(STREAM_TIMEOUT))?;
+    let metadata = match listener::peer_credentials(&stream) {
+        Ok(metadata) => Some(metadata),
+        Err(e) => {
+            format_error!("Failed to get the peer credentials", e);
+            None
+        }
+    };
+
+    let mut line = String::new();
+    let _ = BufReader::new((&stream).take(MAX_REQUEST_LEN)).read_line(&mut line)?;
+    let response = handle_request(&line, metadata, front_end_handler);
+    let mut response = serde_json::to_vec(&response).or_else(|e| {
+        format_error!("Failed to serialize an extension API response", e);
+        Err(Error::new(
+            ErrorKind::Other,
+            "response serialization failed",
+        ))
+    })?;
+    response.push(b'\n');
+
+    stream.write_all(&response)?;
+    stream.flush()
+}
+
+// Parses and executes a request line.
+fn handle_request(
+    line: &str,
+    metadata: Option<ConnectionMetadata>,
+    front_end_handler: &FrontEndHandler,
+) -> ExtensionResponse {
+    let result = serde_json::from_str(line)
+        .or_else(|e| {
+            format_error!("Failed to parse an extension API request", e);
+            Err(ResponseStatus::DeserializingBodyFailed)
+        })
+        .and_then(|request| execute(request, line.len(), metadata, front_end_handler));
+
+    match result {
+        Ok(result) => ExtensionResponse {
+            status: format!("{:?}", ResponseStatus::Success),
+            result,
+        },
+        Err(status) => ExtensionResponse::from_status(status),
+    }
+}
+
+fn execute(
+    request: ExtensionRequest,
+    len: usize,
+    metadata: Option<ConnectionMetadata>,
+    front_end_handler: &FrontEndHandler,
+) -> parsec_interface::requests::Result<Option<ExtensionResult>> {
+    let extension = Operation::Extension(request.operation.name());
+    // The handshake is the only operation which does not need authentication, as a Ping.
+    if let ExtensionOperation::Handshake = request.operation {
+        if front_end_handler.is_denied(extension) {
+            error!("Operation Handshake is denied by the configuration.");
+            return Err(ResponseStatus::PsaErrorNotPermitted);
+        }
+        return handshake(front_end_handler).map(Some);
+    }
+    let auth_type =
+        authenticator_chain::auth_type_from_name(&request.auth_type).ok_or_else(|| {
+            error!("Unknown authenticator type in an extension API request.");
+            ResponseStatus::AuthenticatorDoesNotExist
+        })?;
+    let auth = RequestAuth::new(decode(&request.auth)?);
+    let dispatcher = front_end_handler.dispatcher();
+    // The request is in flight on its provider as the requests read from the listener are.
+    let _in_flight = match request.operation.provider() {
+        Some(provider) => match dispatcher.backend(provider_id_of(provider)?) {
+            Some(backend) => Some(backend.reserve_in_flight(len)?),
+            None => None,
+        },
+        None => None,
+    };
+
+    match request.operation {
+        ExtensionOperation::MintDelegationToken {
+            provider,
+            key_name,
+            delegate,
+            opcodes,
+            validity,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let opcodes = opcodes
+                .iter()
+                .map(|opcode_name| opcode_of(opcode_name))
+                .collect::<parsec_interface::requests::Result<Vec<Opcode>>>()?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension],
+                false,
+            )?;
+            let token = dispatcher.mint_delegation_token(
+                Some(&app_name),
+                &ApplicationName::new(delegate),
+                provider_id,
+                &key_name,
+                &opcodes,
+                Duration::from_secs(validity),
+            )?;
+
+            Ok(Some(ExtensionResult::Token {
+                token: base64::encode(&token),
+            }))
+        }
+        ExtensionOperation::ExecuteDelegated {
+            provider,
+            token,
+            opcode: opcode_name,
+            body,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let opcode = opcode_of(&opcode_name)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension, Operation::Opcode(opcode)],
+                false,
+            )?;
+            let backend = dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?;
+            let operation = backend.decode(RequestBody::from_bytes(decode(&body)?), opcode)?;
+            let result = dispatcher.execute_delegated(
+                &decode(&token)?,
+                Some(&app_name),
+                metadata,
+                provider_id,
+                operation,
+            )?;
+
+            Ok(Some(ExtensionResult::Body {
+                body: base64::encode(backend.encode(result)?.bytes()),
+            }))
+        }
+        ExtensionOperation::PlatformEvidence { provider, nonce } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension],
+                false,
+            )?;
+            let evidence = dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .platform_evidence(Some(app_name), decode(&nonce)?)?;
+
+            Ok(Some(ExtensionResult::Evidence {
+                tpm_event_log: evidence.tpm_event_log.as_ref().map(base64::encode),
+                ima_log: evidence.ima_log.as_ref().map(base64::encode),
+                attest: base64::encode(&evidence.quote.attest),
+                signature: base64::encode(&evidence.quote.signature),
+                public_key: base64::encode(&evidence.quote.public_key),
+            }))
+        }
+        ExtensionOperation::GenerateKeys {
+            provider,
+            bodies,
+            labels,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension, Operation::Opcode(Opcode::PsaGenerateKey)],
+                false,
+            )?;
+            let backend = dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?;
+            let ops = bodies
+                .iter()
+                .map(|body| {
+                    match backend.decode(
+                        RequestBody::from_bytes(decode(body)?),
+                        Opcode::PsaGenerateKey,
+                    )? {
+                        NativeOperation::PsaGenerateKey(op) => Ok(op),
+                        _ => Err(ResponseStatus::InvalidEncoding),
+                    }
+                })
+                .collect::<parsec_interface::requests::Result<Vec<_>>>()?;
+            if !labels.is_empty() {
+                backend.check_key_labels(&labels)?;
+            }
+            let key_names: Vec<String> = ops.iter().map(|op| op.key_name.clone()).collect();
+            let statuses = backend
+                .generate_keys(Some(app_name.clone()), ops, metadata)?
+                .into_iter()
+                .zip(key_names)
+                .map(|(result, key_name)| {
+                    let status = match result {
+                        Ok(_) if !labels.is_empty() => backend
+                            .set_key_labels(&app_name, &key_name, labels.clone())
+                            .err()
+                            .unwrap_or(ResponseStatus::Success),
+                        Ok(_) => ResponseStatus::Success,
+                        Err(status) => status,
+                    };
+                    format!("{:?}", status)
+                })
+                .collect();
+
+            Ok(Some(ExtensionResult::Statuses { statuses }))
+        }
+        ExtensionOperation::VerifyHashWithPublicKey {
+            provider,
+            attributes,
+            public_key,
+            alg,
+            hash,
+            signature,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension, Operation::Opcode(Opcode::PsaVerifyHash)],
+                false,
+            )?;
+            let _ = dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .verify_hash_with_public_key(
+                    Some(app_name),
+                    attributes,
+                    decode(&public_key)?,
+                    alg,
+                    decode(&hash)?,
+                    decode(&signature)?,
+                )?;
+
+            Ok(None)
+        }
+        ExtensionOperation::ImportPeerKey {
+            provider,
+            body,
+            time_to_live,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension, Operation::Opcode(Opcode::PsaImportKey)],
+                false,
+            )?;
+            let backend = dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?;
+            let op = match backend.decode(
+                RequestBody::from_bytes(decode(&body)?),
+                Opcode::PsaImportKey,
+            )? {
+                NativeOperation::PsaImportKey(op) => op,
+                _ => return Err(ResponseStatus::InvalidEncoding),
+            };
+            let _ =
+                backend.import_peer_key(Some(app_name), op, Duration::from_secs(time_to_live))?;
+
+            Ok(None)
+        }
+        ExtensionOperation::CopyKey {
+            provider,
+            key_name,
+            destination_key_name,
+            usage_flags,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension],
+                false,
+            )?;
+            dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .copy_key(
+                    Some(app_name),
+                    key_name,
+                    destination_key_name,
+                    usage_flags,
+                    metadata,
+                )?;
+
+            Ok(None)
+        }
+        #[cfg(feature = "key-counters")]
+        ExtensionOperation::CreateCounter { provider, key_name } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension],
+                false,
+            )?;
+            dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .create_counter(Some(app_name), key_name)?;
+
+            Ok(None)
+        }
+        #[cfg(feature = "key-counters")]
+        ExtensionOperation::IncrementAndSign {
+            provider,
+            key_name,
+            alg,
+            data,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension, Operation::Opcode(Opcode::PsaSignHash)],
+                false,
+            )?;
+            let (counter, signature) = dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .increment_and_sign(Some(app_name), key_name, alg, &decode(&data)?, metadata)?;
+
+            Ok(Some(ExtensionResult::Counter {
+                counter,
+                signature: base64::encode(&signature),
+            }))
+        }
+        ExtensionOperation::WrapKey {
+            provider,
+            key_material,
+            label,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension, Operation::Opcode(Opcode::PsaAeadEncrypt)],
+                false,
+            )?;
+            let wrapped = dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .wrap_key(
+                    Some(app_name),
+                    Zeroizing::new(decode(&key_material)?),
+                    decode(&label)?,
+                )?;
+
+            Ok(Some(ExtensionResult::Wrapped {
+                wrapped: base64::encode(&wrapped),
+            }))
+        }
+        ExtensionOperation::UnwrapKey {
+            provider,
+            wrapped,
+            label,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension, Operation::Opcode(Opcode::PsaAeadDecrypt)],
+                false,
+            )?;
+            let key_material = dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .unwrap_key(Some(app_name), decode(&wrapped)?, decode(&label)?)?;
+
+            Ok(Some(ExtensionResult::KeyMaterial {
+                key_material: base64::encode(&*key_material),
+            }))
+        }
+        ExtensionOperation::UnlockKey {
+            provider,
+            key_name,
+            credential,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension],
+                false,
+            )?;
+            dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .unlock_key(Some(app_name), key_name, decode(&credential)?, metadata)?;
+
+            Ok(None)
+        }
+        ExtensionOperation::ActivateKey {
+            provider,
+            key_owner,
+            key_name,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension],
+                false,
+            )?;
+            let is_admin = front_end_handler.is_admin(auth_type, &app_name);
+            let key_owner = key_owner.map_or_else(|| app_name.clone(), ApplicationName::new);
+            dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .activate_key(Some(app_name), is_admin, key_owner, key_name)?;
+
+            Ok(None)
+        }
+        ExtensionOperation::StoreCertificate {
+            provider,
+            key_name,
+            certificate,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension],
+                false,
+            )?;
+            dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .store_certificate(&app_name, &key_name, decode(&certificate)?)?;
+
+            Ok(None)
+        }
+        ExtensionOperation::GetCertificate { provider, key_name } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension],
+                false,
+            )?;
+            let certificate = dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .get_certificate(&app_name, &key_name)?;
+
+            Ok(Some(ExtensionResult::Certificate {
+                certificate: base64::encode(&certificate),
+            }))
+        }
+        ExtensionOperation::DeleteCertificate { provider, key_name } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension],
+                false,
+            )?;
+            dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .delete_certificate(&app_name, &key_name)?;
+
+            Ok(None)
+        }
+        ExtensionOperation::SetKeyLabels {
+            provider,
+            key_name,
+            labels,
+        } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension],
+                false,
+            )?;
+            dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .set_key_labels(&app_name, &key_name, labels)?;
+
+            Ok(None)
+        }
+        ExtensionOperation::ListKeysByLabels { provider, labels } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension],
+                false,
+            )?;
+            let keys = dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .list_labeled_keys(&app_name, &labels)?
+                .into_iter()
+                .map(|(name, labels)| LabeledKey { name, labels })
+                .collect();
+
+            Ok(Some(ExtensionResult::Keys { keys }))
+        }
+        ExtensionOperation::ChangeBackendAuth { provider, new_auth } => {
+            let provider_id = provider_id_of(&provider)?;
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                provider_id,
+                &[extension],
+                true,
+            )?;
+            dispatcher
+                .backend(provider_id)
+                .ok_or(ResponseStatus::ProviderNotRegistered)?
+                .change_backend_auth(Some(app_name), decode(&new_auth)?)?;
+
+            Ok(None)
+        }
+        #[cfg(feature = "device-identity")]
+        ExtensionOperation::DeviceCertificate => {
+            let app_name = front_end_handler.authenticate(
+                auth_type,
+                &auth,
+                metadata,
+                ProviderID::Core,
+                &[extension],
+                false,
+            )?;
+            let certificate = dispatcher.device_certificate(Some(&app_name))?;
+
+            Ok(Some(ExtensionResult::Certificate {
+                certificate: base64::encode(&certificate),
+            }))
+        }
+        ExtensionOperation::Handshake => handshake(front_end_handler).map(Some),
+    }
+}
+
+// Describes the wire protocol, body types, authenticators and operations of this API accepted by
+// the service, from the core provider.
+fn handshake(
+    front_end_handler: &FrontEndHandler,
+) -> parsec_interface::requests::Result<ExtensionResult> {
+    let core = front_end_handler
+        .dispatcher()
+        .backend(ProviderID::Core)
+        .ok_or(ResponseStatus::ProviderNotRegistered)?;
+    let wire_protocol_version =
+        match core.execute_operation(NativeOperation::Ping(ping::Operation {}), None, None)? {
+            NativeResult::Ping(result) => format!(
+                "{}.{}",
+                result.wire_protocol_version_maj, result.wire_protocol_version_min
+            ),
+            _ => return Err(ResponseStatus::PsaErrorGenericError),
+        };
+    let authenticators = match core.execute_operation(
+        NativeOperation::ListAuthenticators(list_authenticators::Operation {}),
+        None,
+        None,
+    )? {
+        NativeResult::ListAuthenticators(result) => result
+            .authenticators
+            .iter()
+            .map(|authenticator| format!("{:?}", authenticator.id))
+            .collect(),
+        _ => return Err(ResponseStatus::PsaErrorGenericError),
+    };
+
+    Ok(ExtensionResult::Handshake {
+        wire_protocol_version,
+        content_types: vec![format!("{:?}", core.content_type())],
+        accept_types: vec![format!("{:?}", core.accept_type())],
+        authenticators,
+        extensions: extension_operations()
+            .into_iter()
+            .filter(|name| !front_end_handler.is_denied(Operation::Extension(name)))
+            .map(String::from)
+            .collect(),
+    })
+}
+
+/// Names of the operations of this API compiled in the service, which the configuration can deny
+/// as the opcodes.
+pub fn extension_operations() -> Vec<&'static str> {
+    #[cfg_attr(
+        not(any(feature = "key-counters", feature = "device-identity")),
+        allow(unused_mut)
+    )]
+    let mut operations = vec![
+        "MintDelegationToken",
+        "ExecuteDelegated",
+        "PlatformEvidence",
+        "GenerateKeys",
+        "VerifyHashWithPublicKey",
+        "ImportPeerKey",
+        "CopyKey",
+        "WrapKey",
+        "UnwrapKey",
+        "UnlockKey",
+        "ActivateKey",
+        "StoreCertificate",
+        "GetCertificate",
+        "DeleteCertificate",
+        "SetKeyLabels",
+        "ListKeysByLabels",
+        "ChangeBackendAuth",
+        "Handshake",
+    ];
+    #[cfg(feature = "key-counters")]
+    operations.extend_from_slice(&["CreateCounter", "IncrementAndSign"]);
+    #[cfg(feature = "device-identity")]
+    operations.push("DeviceCertificate");
+
+    operations
+}
+
+fn decode(field: &str) -> parsec_interface::requests::Result<Vec<u8>> {
+    base64::decode(field).or_else(|e| {
+        format_error!("Failed to decode a base64 field", e);
+        Err(ResponseStatus::InvalidEncoding)
+    })
+}
+
+fn provider_id_of(provider_type: &str) -> parsec_interface::requests::Result<ProviderID> {
+    provider_id_from_type(provider_type).ok_or_else(|| {
+        error!("Unknown provider type in an extension API request.");
+        ResponseStatus::ProviderDoesNotExist
+    })
+}
+
+fn opcode_of(opcode_name: &str) -> parsec_interface::requests::Result<Opcode> {
+    opcode_from_name(opcode_name).ok_or_else(|| {
+        error!("Unknown operation in an extension API request.");
+        ResponseStatus::OpcodeDoesNotExist
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{handle_request, ExtensionResponse, ExtensionResult, LabeledKey};
+    use crate::authenticators::authenticator_chain::ChainedAuthenticator;
+    use crate::authenticators::{ApplicationName, Authenticate};
+    use crate::back::backend_handler::BackEndHandlerBuilder;
+    use crate::back::canary_keys::CanaryKeys;
+    use crate::back::delegation_tokens::DelegationTokens;
+    use crate::back::dispatcher::DispatcherBuilder;
+    use crate::back::platform_evidence::Quote;
+    use crate::front::front_end::{FrontEndHandler, FrontEndHandlerBuilder};
+    use crate::front::listener::ConnectionMetadata;
+    use crate::providers::Provide;
+    use parsec_interface::operations::list_authenticators::AuthenticatorInfo;
+    use parsec_interface::operations::psa_algorithm::{AsymmetricSignature, Hash, SignHash};
+    use parsec_interface::operations::psa_key_attributes::{Attributes, Type, UsageFlags};
+    use parsec_interface::operations::{
+        psa_aead_decrypt, psa_aead_encrypt, psa_export_public_key, psa_generate_key,
+        psa_import_key, psa_sign_hash, psa_verify_hash,
+    };
+    use parsec_interface::operations::{Convert, NativeOperation, NativeResult};
+    use parsec_interface::operations_protobuf::ProtobufConverter;
+    use parsec_interface::requests::request::RequestAuth;
+    use parsec_interface::requests::response::ResponseBody;
+    use parsec_interface::requests::{
+        AuthType, BodyType, Opcode, ProviderID, ResponseStatus, Result,
+    };
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use zeroize::Zeroizing;
+
+    const PUBLIC_KEY: [u8; 4] = [1, 2, 3, 4];
+    const PIN: &[u8] = b"1234";
+
+    // Admits the applications under the name given as authentication.
+    #[derive(Debug)]
+    struct NameAuthenticator;
+
+    impl Authenticate for NameAuthenticator {
+        fn describe(&self) -> Result<AuthenticatorInfo> {
+            Err(ResponseStatus::PsaErrorNotSupported)
+        }
+
+        fn authenticate(
+            &self,
+            auth: &RequestAuth,
+            _meta: Option<ConnectionMetadata>,
+        ) -> Result<ApplicationName> {
+            Ok(ApplicationName::new(
+                String::from_utf8(auth.bytes().to_vec())
+                    .map_err(|_| ResponseStatus::AuthenticationError)?,
+            ))
+        }
+
+        fn is_admin(&self, app_name: &ApplicationName) -> bool {
+            app_name.get_name() == "admin"
+        }
+    }
+
+    // Holds a key, "key" of "owner", and "locked", unlocked with PIN.
+    #[derive(Debug)]
+    struct KeyProvider;
+
+    impl Provide for KeyProvider {
+        fn psa_generate_key(
+            &self,
+            app_name: ApplicationName,
+            op: psa_generate_key::Operation,
+        ) -> Result<psa_generate_key::Result> {
+            if app_name.get_name() == "owner" && op.key_name == "key" {
+                Err(ResponseStatus::PsaErrorAlreadyExists)
+            } else {
+                Ok(psa_generate_key::Result {})
+            }
+        }
+
+        fn psa_export_public_key(
+            &self,
+            app_name: ApplicationName,
+            op: psa_export_public_key::Operation,
+        ) -> Result<psa_export_public_key::Result> {
+            if app_name.get_name() == "owner" && op.key_name == "key" {
+                Ok(psa_export_public_key::Result {
+                    data: PUBLIC_KEY.to_vec(),
+                })
+            } else {
+                Err(ResponseStatus::PsaErrorDoesNotExist)
+            }
+        }
+
+        fn psa_verify_hash_with_public_key(
+            &self,
+            _app_name: ApplicationName,
+            _attributes: Attributes,
+            public_key: Vec<u8>,
+            _alg: AsymmetricSignature,
+            hash: Vec<u8>,
+            signature: Vec<u8>,
+        ) -> Result<psa_verify_hash::Result> {
+            // The signatures of the test key are the hashes signed.
+            if public_key == PUBLIC_KEY && signature == hash {
+                Ok(psa_verify_hash::Result {})
+            } else {
+                Err(ResponseStatus::PsaErrorInvalidSignature)
+            }
+        }
+
+        fn psa_import_key(
+            &self,
+            _app_name: ApplicationName,
+            _op: psa_import_key::Operation,
+        ) -> Result<psa_import_key::Result> {
+            Ok(psa_import_key::Result {})
+        }
+
+        // "Encrypts" by appending the additional data to the plaintext.
+        fn psa_aead_encrypt(
+            &self,
+            _app_name: ApplicationName,
+            op: psa_aead_encrypt::Operation,
+        ) -> Result<psa_aead_encrypt::Result> {
+            let mut ciphertext = op.plaintext.to_vec();
+            ciphertext.extend_from_slice(&op.additional_data);
+            Ok(psa_aead_encrypt::Result {
+                ciphertext: ciphertext.into(),
+            })
+        }
+
+        fn psa_aead_decrypt(
+            &self,
+            _app_name: ApplicationName,
+            op: psa_aead_decrypt::Operation,
+        ) -> Result<psa_aead_decrypt::Result> {
+            let plaintext_len = op.ciphertext.len().saturating_sub(op.additional_data.len());
+            let (plaintext, additional_data) = op.ciphertext.split_at(plaintext_len);
+            if additional_data != op.additional_data.as_slice() {
+                return Err(ResponseStatus::PsaErrorInvalidSignature);
+            }
+            Ok(psa_aead_decrypt::Result {
+                plaintext: plaintext.to_vec().into(),
+            })
+        }
+
+        fn psa_copy_key(
+            &self,
+            app_name: ApplicationName,
+            key_name: String,
+            _destination_key_name: String,
+            usage_flags: UsageFlags,
+        ) -> Result<()> {
+            if app_name.get_name() != "owner" || key_name != "key" {
+                Err(ResponseStatus::PsaErrorDoesNotExist)
+            } else if usage_flags.sign_hash {
+                // The test key is only allowed to verify.
+                Err(ResponseStatus::PsaErrorInvalidArgument)
+            } else {
+                Ok(())
+            }
+        }
+
+        // The signatures of the test key are the hashes signed.
+        fn psa_sign_hash(
+            &self,
+            _app_name: ApplicationName,
+            op: psa_sign_hash::Operation,
+        ) -> Result<psa_sign_hash::Result> {
+            Ok(psa_sign_hash::Result {
+                signature: op.hash.to_vec().into(),
+            })
+        }
+
+        fn key_requires_unlock(&self, app_name: &ApplicationName, key_name: &str) -> Result<bool> {
+            Ok(app_name.get_name() == "owner" && key_name == "locked")
+        }
+
+        fn unlock_key(
+            &self,
+            _app_name: ApplicationName,
+            _key_name: String,
+            credential: &[u8],
+        ) -> Result<()> {
+            if credential == PIN {
+                Ok(())
+            } else {
+                Err(ResponseStatus::PsaErrorNotPermitted)
+            }
+        }
+
+        fn quote(&self, nonce: Vec<u8>) -> Result<Quote> {
+            Ok(Quote {
+                attest: nonce,
+                signature: vec![0; 4],
+                public_key: PUBLIC_KEY.to_vec(),
+            })
+        }
+
+        fn change_backend_auth(&self, auth: Zeroizing<Vec<u8>>) -> Result<()> {
+            if auth.is_empty() {
+                Err(ResponseStatus::PsaErrorInvalidArgument)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn front_end_handler() -> FrontEndHandler {
+        front_end_handler_with(|builder| builder)
+    }
+
+    // Builds the front end handler with the backend configured further.
+    fn front_end_handler_with(
+        configure: impl FnOnce(BackEndHandlerBuilder) -> BackEndHandlerBuilder,
+    ) -> FrontEndHandler {
+        front_end_handler_builder(configure).build().unwrap()
+    }
+
+    // Prepares the builder of the front end handler, with the backend configured further.
+    fn front_end_handler_builder(
+        configure: impl FnOnce(BackEndHandlerBuilder) -> BackEndHandlerBuilder,
+    ) -> FrontEndHandlerBuilder {
+        let backend = configure(
+            BackEndHandlerBuilder::new()
+                .with_provider(Box::from(KeyProvider))
+                .with_converter(Box::from(ProtobufConverter {}))
+                .with_provider_id(ProviderID::MbedCrypto)
+                .with_content_type(BodyType::Protobuf)
+                .with_accept_type(BodyType::Protobuf)
+                .with_app_keks(),
+        )
+        .build()
+        .unwrap();
+        let dispatcher = DispatcherBuilder::new()
+            .with_backend(ProviderID::MbedCrypto, backend)
+            .with_delegation_tokens(DelegationTokens::new(Duration::from_secs(60)).unwrap())
+            .build()
+            .unwrap();
+
+        FrontEndHandlerBuilder::new()
+            .with_dispatcher(dispatcher)
+            .with_authenticator(
+                AuthType::Direct,
+                ChainedAuthenticator::new(Box::from(NameAuthenticator)),
+            )
+            .with_body_len_limit(1 << 16)
+    }
+
+    fn export_public_key_body(key_name: &str) -> String {
+        let body = ProtobufConverter {}
+            .operation_to_body(NativeOperation::PsaExportPublicKey(
+                psa_export_public_key::Operation {
+                    key_name: key_name.to_string(),
+                },
+            ))
+            .unwrap();
+
+        base64::encode(body.bytes())
+    }
+
+    fn request(
+        front_end_handler: &FrontEndHandler,
+        app_name: &str,
+        fields: &str,
+    ) -> ExtensionResponse {
+        let line = format!(
+            "{{\"auth_type\":\"Direct\",\"auth\":\"{}\",{}}}\n",
+            base64::encode(app_name),
+            fields
+        );
+        handle_request(&line, None, front_end_handler)
+    }
+
+    fn mint(front_end_handler: &FrontEndHandler, app_name: &str) -> String {
+        match request(
+            front_end_handler,
+            app_name,
+            "\"operation\":\"MintDelegationToken\",\"provider\":\"MbedCrypto\",\"key_name\":\"key\",\
+             \"delegate\":\"delegate\",\"opcodes\":[\"PsaExportPublicKey\"],\"validity\":60",
+        ) {
+            ExtensionResponse {
+                result: Some(ExtensionResult::Token { token }),
+                ..
+            } => token,
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    fn execute(
+        front_end_handler: &FrontEndHandler,
+        app_name: &str,
+        token: &str,
+        key_name: &str,
+    ) -> ExtensionResponse {
+        request(
+            front_end_handler,
+            app_name,
+            &format!(
+                "\"operation\":\"ExecuteDelegated\",\"provider\":\"MbedCrypto\",\"token\":\"{}\",\
+                 \"opcode\":\"PsaExportPublicKey\",\"body\":\"{}\"",
+                token,
+                export_public_key_body(key_name)
+            ),
+        )
+    }
+
+    #[test]
+    fn delegated_operation_executed() {
+        let front_end_handler = front_end_handler();
+        let token = mint(&front_end_handler, "owner");
+
+        let body = match execute(&front_end_handler, "delegate", &token, "key") {
+            ExtensionResponse {
+                result: Some(ExtensionResult::Body { body }),
+                ..
+            } => body,
+            response => panic!("Unexpected response {:?}", response),
+        };
+        let result = ProtobufConverter {}
+            .body_to_result(
+                ResponseBody::from_bytes(base64::decode(&body).unwrap()),
+                Opcode::PsaExportPublicKey,
+            )
+            .unwrap();
+        match result {
+            NativeResult::PsaExportPublicKey(result) => {
+                assert_eq!(result.data.to_vec(), PUBLIC_KEY.to_vec())
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn token_checked() {
+        let front_end_handler = front_end_handler();
+        let token = mint(&front_end_handler, "owner");
+        let not_permitted = ExtensionResponse::from_status(ResponseStatus::PsaErrorNotPermitted);
+
+        // Only the delegate can present the token, for the key delegated.
+        assert_eq!(
+            execute(&front_end_handler, "other", &token, "key"),
+            not_permitted
+        );
+        assert_eq!(
+            execute(&front_end_handler, "delegate", &token, "other"),
+            not_permitted
+        );
+        // A token minted by another application only gives access to the keys of that one.
+        assert_eq!(
+            execute(
+                &front_end_handler,
+                "delegate",
+                &mint(&front_end_handler, "other"),
+                "key"
+            ),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorDoesNotExist)
+        );
+    }
+
+    #[test]
+    fn invalid_requests_refused() {
+        let front_end_handler = front_end_handler();
+        assert_eq!(
+            handle_request("{\"operation\":", None, &front_end_handler),
+            ExtensionResponse::from_status(ResponseStatus::DeserializingBodyFailed)
+        );
+        assert_eq!(
+            handle_request(
+                "{\"auth_type\":\"Unknown\",\"operation\":\"MintDelegationToken\",\
+                 \"provider\":\"MbedCrypto\",\"key_name\":\"key\",\"delegate\":\"delegate\",\
+                 \"opcodes\":[],\"validity\":60}",
+                None,
+                &front_end_handler
+            ),
+            ExtensionResponse::from_status(ResponseStatus::AuthenticatorDoesNotExist)
+        );
+        assert_eq!(
+            request(
+                &front_end_handler,
+                "owner",
+                "\"operation\":\"MintDelegationToken\",\"provider\":\"MbedCrypto\",\
+                 \"key_name\":\"key\",\"delegate\":\"delegate\",\"opcodes\":[\"Unknown\"],\
+                 \"validity\":60",
+            ),
+            ExtensionResponse::from_status(ResponseStatus::OpcodeDoesNotExist)
+        );
+        assert_eq!(
+            request(
+                &front_end_handler,
+                "owner",
+                "\"operation\":\"MintDelegationToken\",\"provider\":\"Unknown\",\
+                 \"key_name\":\"key\",\"delegate\":\"delegate\",\
+                 \"opcodes\":[\"PsaExportPublicKey\"],\"validity\":60",
+            ),
+            ExtensionResponse::from_status(ResponseStatus::ProviderDoesNotExist)
+        );
+    }
+
+    #[test]
+    fn platform_evidence_gathered() {
+        let front_end_handler = front_end_handler();
+        let fields = |nonce: &[u8]| {
+            format!(
+                "\"operation\":\"PlatformEvidence\",\"provider\":\"MbedCrypto\",\"nonce\":\"{}\"",
+                base64::encode(nonce)
+            )
+        };
+
+        match request(&front_end_handler, "verifier", &fields(&[1; 32])) {
+            ExtensionResponse {
+                result:
+                    Some(ExtensionResult::Evidence {
+                        attest, public_key, ..
+                    }),
+                ..
+            } => {
+                // The quote of the test provider is its nonce.
+                assert_eq!(attest, base64::encode(&[1; 32]));
+                assert_eq!(public_key, base64::encode(&PUBLIC_KEY));
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+        assert_eq!(
+            request(&front_end_handler, "verifier", &fields(&[])),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorInvalidArgument)
+        );
+    }
+
+    #[test]
+    fn key_unlocked() {
+        let front_end_handler = front_end_handler();
+        let client = ConnectionMetadata::UnixPeerCredentials {
+            uid: 1000,
+            gid: 1000,
+            pid: 1,
+        };
+        let unlock = |credential: &[u8], metadata| {
+            let line = format!(
+                "{{\"auth_type\":\"Direct\",\"auth\":\"{}\",\"operation\":\"UnlockKey\",\
+                 \"provider\":\"MbedCrypto\",\"key_name\":\"locked\",\"credential\":\"{}\"}}",
+                base64::encode("owner"),
+                base64::encode(credential)
+            );
+            handle_request(&line, metadata, &front_end_handler)
+        };
+
+        assert_eq!(
+            unlock(PIN, Some(client)),
+            ExtensionResponse::from_status(ResponseStatus::Success)
+        );
+        assert_eq!(
+            unlock(b"0000", Some(client)),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorNotPermitted)
+        );
+        // The credential can only be cached for a client identified by its connection.
+        assert_eq!(
+            unlock(PIN, None),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorNotPermitted)
+        );
+    }
+
+    // Fields of a GenerateKeys request for keys of the given names.
+    fn generate_keys(key_names: &[&str]) -> String {
+        let bodies: Vec<String> = key_names
+            .iter()
+            .map(|key_name| {
+                let body = ProtobufConverter {}
+                    .operation_to_body(NativeOperation::PsaGenerateKey(
+                        psa_generate_key::Operation {
+                            key_name: key_name.to_string(),
+                            attributes: CanaryKeys::attributes(),
+                        },
+                    ))
+                    .unwrap();
+                format!("\"{}\"", base64::encode(body.bytes()))
+            })
+            .collect();
+
+        format!(
+            "\"operation\":\"GenerateKeys\",\"provider\":\"MbedCrypto\",\"bodies\":[{}]",
+            bodies.join(",")
+        )
+    }
+
+    #[test]
+    fn keys_generated() {
+        let front_end_handler = front_end_handler();
+
+        // The key which already exists does not prevent the other one from being generated.
+        assert_eq!(
+            request(&front_end_handler, "owner", &generate_keys(&["new", "key"])),
+            ExtensionResponse {
+                status: format!("{:?}", ResponseStatus::Success),
+                result: Some(ExtensionResult::Statuses {
+                    statuses: vec![
+                        String::from("Success"),
+                        String::from("PsaErrorAlreadyExists")
+                    ],
+                }),
+            }
+        );
+    }
+
+    #[cfg(feature = "event-hooks")]
+    #[test]
+    fn generated_keys_notified() {
+        use crate::back::event_hooks::{EventHookConfig, EventHooks, EventKind};
+        use std::io::{ErrorKind, Read, Write};
+        use std::net::TcpListener;
+        use std::time::Instant;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let event_hooks = EventHooks::new(&[EventHookConfig {
+            events: vec![EventKind::KeyCreated],
+            command: None,
+            webhook: Some(format!("http://{}/events", listener.local_addr().unwrap())),
+        }])
+        .unwrap();
+        let front_end_handler =
+            front_end_handler_with(|builder| builder.with_event_hooks(event_hooks));
+        assert_eq!(
+            request(&front_end_handler, "owner", &generate_keys(&["new", "key"])).status,
+            String::from("Success")
+        );
+
+        // Only the key generated is notified, each event in its own request.
+        listener.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let mut events = Vec::new();
+        while Instant::now() < deadline {
+            let mut stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+                Err(e) => panic!("Failed to accept a webhook request: {}", e),
+            };
+            stream.set_nonblocking(false).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let mut http_request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !http_request.ends_with(b"}") {
+                let len = stream.read(&mut buffer).unwrap();
+                assert_ne!(len, 0);
+                http_request.extend_from_slice(&buffer[..len]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+            let http_request = String::from_utf8(http_request).unwrap();
+            let body = &http_request[http_request.find("\r\n\r\n").unwrap() + 4..];
+            let event: serde_json::Value = serde_json::from_str(body).unwrap();
+            events.push((event["kind"].clone(), event["key_name"].clone()));
+        }
+        assert_eq!(
+            events,
+            vec![(
+                serde_json::Value::from("KeyCreated"),
+                serde_json::Value::from("new")
+            )]
+        );
+    }
+
+    #[test]
+    fn hash_verified_with_public_key() {
+        let front_end_handler = front_end_handler();
+        let mut attributes = CanaryKeys::attributes();
+        attributes.key_type = Type::RsaPublicKey;
+        let alg = AsymmetricSignature::RsaPkcs1v15Sign {
+            hash_alg: SignHash::Specific(Hash::Sha256),
+        };
+        let verify = |public_key: &[u8], signature: &[u8]| {
+            request(
+                &front_end_handler,
+                "client",
+                &format!(
+                    "\"operation\":\"VerifyHashWithPublicKey\",\"provider\":\"MbedCrypto\",\
+                     \"attributes\":{},\"public_key\":\"{}\",\"alg\":{},\"hash\":\"{}\",\
+                     \"signature\":\"{}\"",
+                    serde_json::to_string(&attributes).unwrap(),
+                    base64::encode(public_key),
+                    serde_json::to_string(&alg).unwrap(),
+                    base64::encode(&[1; 32]),
+                    base64::encode(signature)
+                ),
+            )
+        };
+
+        assert_eq!(
+            verify(&PUBLIC_KEY, &[1; 32]),
+            ExtensionResponse::from_status(ResponseStatus::Success)
+        );
+        assert_eq!(
+            verify(&PUBLIC_KEY, &[2; 32]),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorInvalidSignature)
+        );
+        assert_eq!(
+            verify(&[5, 6, 7, 8], &[1; 32]),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorInvalidSignature)
+        );
+    }
+
+    #[test]
+    fn peer_key_imported() {
+        let front_end_handler = front_end_handler();
+        let import = |attributes: Attributes| {
+            let body = ProtobufConverter {}
+                .operation_to_body(NativeOperation::PsaImportKey(psa_import_key::Operation {
+                    key_name: String::from("peer"),
+                    attributes,
+                    data: PUBLIC_KEY.to_vec(),
+                }))
+                .unwrap();
+            request(
+                &front_end_handler,
+                "client",
+                &format!(
+                    "\"operation\":\"ImportPeerKey\",\"provider\":\"MbedCrypto\",\"body\":\"{}\",\
+                     \"time_to_live\":60",
+                    base64::encode(body.bytes())
+                ),
+            )
+        };
+
+        let mut attributes = CanaryKeys::attributes();
+        attributes.key_type = Type::RsaPublicKey;
+        attributes.policy.usage_flags.sign_hash = false;
+        attributes.policy.usage_flags.sign_message = false;
+        assert_eq!(
+            import(attributes),
+            ExtensionResponse::from_status(ResponseStatus::Success)
+        );
+        // Peer keys can only be used for verification.
+        attributes.policy.usage_flags.sign_hash = true;
+        assert_eq!(
+            import(attributes),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorInvalidArgument)
+        );
+    }
+
+    #[test]
+    fn key_wrapped() {
+        let front_end_handler = front_end_handler();
+        let wrapped = match request(
+            &front_end_handler,
+            "tenant",
+            &format!(
+                "\"operation\":\"WrapKey\",\"provider\":\"MbedCrypto\",\"key_material\":\"{}\",\
+                 \"label\":\"{}\"",
+                base64::encode(&[1; 16]),
+                base64::encode("label")
+            ),
+        ) {
+            ExtensionResponse {
+                result: Some(ExtensionResult::Wrapped { wrapped }),
+                ..
+            } => wrapped,
+            response => panic!("Unexpected response {:?}", response),
+        };
+        let unwrap = |label: &str| {
+            request(
+                &front_end_handler,
+                "tenant",
+                &format!(
+                    "\"operation\":\"UnwrapKey\",\"provider\":\"MbedCrypto\",\"wrapped\":\"{}\",\
+                     \"label\":\"{}\"",
+                    wrapped,
+                    base64::encode(label)
+                ),
+            )
+        };
+
+        assert_eq!(
+            unwrap("label").result,
+            Some(ExtensionResult::KeyMaterial {
+                key_material: base64::encode(&[1; 16])
+            })
+        );
+        // The wrapped key is bound to its label.
+        assert_eq!(
+            unwrap("other"),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorInvalidSignature)
+        );
+    }
+
+    #[test]
+    fn key_copied() {
+        let front_end_handler = front_end_handler();
+        let copy = |usage_flags: UsageFlags| {
+            request(
+                &front_end_handler,
+                "owner",
+                &format!(
+                    "\"operation\":\"CopyKey\",\"provider\":\"MbedCrypto\",\"key_name\":\"key\",\
+                     \"destination_key_name\":\"copy\",\"usage_flags\":{}",
+                    serde_json::to_string(&usage_flags).unwrap()
+                ),
+            )
+        };
+
+        let mut usage_flags = CanaryKeys::attributes().policy.usage_flags;
+        usage_flags.sign_hash = false;
+        usage_flags.sign_message = false;
+        assert_eq!(
+            copy(usage_flags),
+            ExtensionResponse::from_status(ResponseStatus::Success)
+        );
+        usage_flags.sign_hash = true;
+        assert_eq!(
+            copy(usage_flags),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorInvalidArgument)
+        );
+    }
+
+    #[test]
+    fn denied_operation_refused() {
+        let front_end_handler = front_end_handler_builder(|builder| builder)
+            .with_denied_extensions(
+                vec![String::from("CopyKey"), String::from("Handshake")]
+                    .into_iter()
+                    .collect(),
+            )
+            .build()
+            .unwrap();
+        let mut usage_flags = CanaryKeys::attributes().policy.usage_flags;
+        usage_flags.sign_hash = false;
+        usage_flags.sign_message = false;
+
+        assert_eq!(
+            request(
+                &front_end_handler,
+                "owner",
+                &format!(
+                    "\"operation\":\"CopyKey\",\"provider\":\"MbedCrypto\",\"key_name\":\"key\",\
+                     \"destination_key_name\":\"copy\",\"usage_flags\":{}",
+                    serde_json::to_string(&usage_flags).unwrap()
+                ),
+            ),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorNotPermitted)
+        );
+        assert_eq!(
+            request(&front_end_handler, "owner", "\"operation\":\"Handshake\""),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorNotPermitted)
+        );
+        // The other operations are still served.
+        let _ = mint(&front_end_handler, "owner");
+    }
+
+    #[cfg(all(feature = "key-counters", feature = "memory-manager"))]
+    #[test]
+    fn counter_signed() {
+        use crate::back::key_counters::{self, KeyCounters};
+        use crate::key_info_managers::key_info_store::KeyInfoStore;
+        use crate::key_info_managers::memory_manager::MemoryKeyInfoManager;
+        use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+        use std::sync::Arc;
+
+        let store = Arc::new(KeyInfoStore::new(Box::new(MemoryKeyInfoManager::new())).unwrap());
+        let _ = store
+            .write()
+            .insert(
+                KeyTriple::new(
+                    ApplicationName::new(String::from("owner")),
+                    ProviderID::MbedCrypto,
+                    String::from("key"),
+                ),
+                KeyInfo {
+                    id: vec![1],
+                    attributes: CanaryKeys::attributes(),
+                    state: KeyState::Active,
+                    created_at: None,
+                    counter: None,
+                    certificate: None,
+                    labels: BTreeMap::new(),
+                },
+            )
+            .unwrap();
+        let front_end_handler = front_end_handler_with(|builder| {
+            builder.with_key_counters(KeyCounters::new(store.clone()))
+        });
+        let alg = AsymmetricSignature::RsaPkcs1v15Sign {
+            hash_alg: SignHash::Specific(Hash::Sha256),
+        };
+        let increment_and_sign = || {
+            request(
+                &front_end_handler,
+                "owner",
+                &format!(
+                    "\"operation\":\"IncrementAndSign\",\"provider\":\"MbedCrypto\",\
+                     \"key_name\":\"key\",\"alg\":{},\"data\":\"{}\"",
+                    serde_json::to_string(&alg).unwrap(),
+                    base64::encode("state")
+                ),
+            )
+        };
+
+        assert_eq!(
+            increment_and_sign(),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorDoesNotExist)
+        );
+        assert_eq!(
+            request(
+                &front_end_handler,
+                "owner",
+                "\"operation\":\"CreateCounter\",\"provider\":\"MbedCrypto\",\"key_name\":\"key\"",
+            ),
+            ExtensionResponse::from_status(ResponseStatus::Success)
+        );
+        assert_eq!(
+            increment_and_sign().result,
+            Some(ExtensionResult::Counter {
+                counter: 1,
+                signature: base64::encode(&key_counters::counter_hash(alg, 1, b"state").unwrap()),
+            })
+        );
+    }
+
+    #[cfg(feature = "memory-manager")]
+    #[test]
+    fn key_activated() {
+        use crate::back::key_activation::KeyActivation;
+        use crate::key_info_managers::key_info_store::KeyInfoStore;
+        use crate::key_info_managers::memory_manager::MemoryKeyInfoManager;
+        use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+        use std::sync::Arc;
+
+        let store = Arc::new(KeyInfoStore::new(Box::new(MemoryKeyInfoManager::new())).unwrap());
+        let key_triple = KeyTriple::new(
+            ApplicationName::new(String::from("owner")),
+            ProviderID::MbedCrypto,
+            String::from("key"),
+        );
+        let _ = store
+            .write()
+            .insert(
+                key_triple.clone(),
+                KeyInfo {
+                    id: vec![1],
+                    attributes: CanaryKeys::attributes(),
+                    state: KeyState::PreActive,
+                    created_at: None,
+                    counter: None,
+                    certificate: None,
+                    labels: BTreeMap::new(),
+                },
+            )
+            .unwrap();
+        let front_end_handler = front_end_handler_with(|builder| {
+            builder.with_key_activation(KeyActivation::new(None, store.clone()))
+        });
+        let activate = |app_name| {
+            request(
+                &front_end_handler,
+                app_name,
+                "\"operation\":\"ActivateKey\",\"provider\":\"MbedCrypto\",\
+                 \"key_owner\":\"owner\",\"key_name\":\"key\"",
+            )
+        };
+
+        // Only the owner and the administrators can activate a key.
+        assert_eq!(
+            activate("other"),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorNotPermitted)
+        );
+        assert_eq!(
+            store.read().get(&key_triple).unwrap().unwrap().state,
+            KeyState::PreActive
+        );
+        assert_eq!(
+            activate("admin"),
+            ExtensionResponse::from_status(ResponseStatus::Success)
+        );
+        assert_eq!(
+            store.read().get(&key_triple).unwrap().unwrap().state,
+            KeyState::Active
+        );
+        // The owner of the key is the application of the request by default.
+        assert_eq!(
+            request(
+                &front_end_handler,
+                "owner",
+                "\"operation\":\"ActivateKey\",\"provider\":\"MbedCrypto\",\"key_name\":\"key\"",
+            ),
+            ExtensionResponse::from_status(ResponseStatus::Success)
+        );
+    }
+
+    #[test]
+    fn backend_auth_changed_by_administrators() {
+        let front_end_handler = front_end_handler();
+        let change = |app_name, new_auth: &str| {
+            request(
+                &front_end_handler,
+                app_name,
+                &format!(
+                    "\"operation\":\"ChangeBackendAuth\",\"provider\":\"MbedCrypto\",\
+                     \"new_auth\":\"{}\"",
+                    base64::encode(new_auth)
+                ),
+            )
+        };
+
+        assert_eq!(
+            change("owner", "new"),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorNotPermitted)
+        );
+        assert_eq!(
+            change("admin", "new"),
+            ExtensionResponse::from_status(ResponseStatus::Success)
+        );
+        assert_eq!(
+            change("admin", ""),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorInvalidArgument)
+        );
+    }
+
+    #[cfg(feature = "device-identity")]
+    #[test]
+    fn device_certificate_not_configured() {
+        assert_eq!(
+            request(
+                &front_end_handler(),
+                "owner",
+                "\"operation\":\"DeviceCertificate\""
+            ),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorDoesNotExist)
+        );
+    }
+
+    #[cfg(all(feature = "memory-manager", feature = "device-identity"))]
+    #[test]
+    fn certificate_stored() {
+        use crate::back::key_certificates::KeyCertificates;
+        use crate::key_info_managers::key_info_store::KeyInfoStore;
+        use crate::key_info_managers::memory_manager::MemoryKeyInfoManager;
+        use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+        use crate::utils::enrollment::der;
+        use std::sync::Arc;
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let certificate = der::certificate(
+            &der::tbs_certificate(&[0x04; 65], "key", &[1], UNIX_EPOCH, SystemTime::now()),
+            &[0; 64],
+        );
+        let store_certificate = format!(
+            "\"operation\":\"StoreCertificate\",\"provider\":\"MbedCrypto\",\"key_name\":\"key\",\
+             \"certificate\":\"{}\"",
+            base64::encode(&certificate)
+        );
+        let get_certificate =
+            "\"operation\":\"GetCertificate\",\"provider\":\"MbedCrypto\",\"key_name\":\"key\"";
+
+        // The provider of the tests can not store certificates.
+        assert_eq!(
+            request(&front_end_handler(), "owner", &store_certificate),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorNotSupported)
+        );
+
+        let store = Arc::new(KeyInfoStore::new(Box::new(MemoryKeyInfoManager::new())).unwrap());
+        let _ = store
+            .write()
+            .insert(
+                KeyTriple::new(
+                    ApplicationName::new(String::from("owner")),
+                    ProviderID::MbedCrypto,
+                    String::from("key"),
+                ),
+                KeyInfo {
+                    id: vec![1],
+                    attributes: CanaryKeys::attributes(),
+                    state: KeyState::Active,
+                    created_at: None,
+                    counter: None,
+                    certificate: None,
+                    labels: BTreeMap::new(),
+                },
+            )
+            .unwrap();
+        let front_end_handler = front_end_handler_with(|builder| {
+            builder.with_key_certificates(KeyCertificates::new(store.clone()))
+        });
+        assert_eq!(
+            request(&front_end_handler, "owner", &store_certificate),
+            ExtensionResponse::from_status(ResponseStatus::Success)
+        );
+        assert_eq!(
+            request(&front_end_handler, "owner", get_certificate).result,
+            Some(ExtensionResult::Certificate {
+                certificate: base64::encode(&certificate),
+            })
+        );
+        // The certificates of the keys of the other applications can not be read.
+        assert_eq!(
+            request(&front_end_handler, "other", get_certificate),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorDoesNotExist)
+        );
+        assert_eq!(
+            request(
+                &front_end_handler,
+                "owner",
+                "\"operation\":\"DeleteCertificate\",\"provider\":\"MbedCrypto\",\"key_name\":\"key\"",
+            ),
+            ExtensionResponse::from_status(ResponseStatus::Success)
+        );
+        assert_eq!(
+            request(&front_end_handler, "owner", get_certificate),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorDoesNotExist)
+        );
+    }
+
+    #[cfg(feature = "memory-manager")]
+    #[test]
+    fn keys_listed_by_labels() {
+        use crate::back::key_labels::KeyLabels;
+        use crate::key_info_managers::key_info_store::KeyInfoStore;
+        use crate::key_info_managers::memory_manager::MemoryKeyInfoManager;
+        use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+        use std::sync::Arc;
+
+        let set_labels = "\"operation\":\"SetKeyLabels\",\"provider\":\"MbedCrypto\",\
+                          \"key_name\":\"key\",\"labels\":{\"device\":\"1\"}";
+        let list_keys = "\"operation\":\"ListKeysByLabels\",\"provider\":\"MbedCrypto\",\
+                         \"labels\":{\"device\":\"1\"}";
+
+        // The keys of the provider of the tests are not stored in a Key Info Manager.
+        assert_eq!(
+            request(&front_end_handler(), "owner", set_labels),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorNotSupported)
+        );
+
+        let store = Arc::new(KeyInfoStore::new(Box::new(MemoryKeyInfoManager::new())).unwrap());
+        let _ = store
+            .write()
+            .insert(
+                KeyTriple::new(
+                    ApplicationName::new(String::from("owner")),
+                    ProviderID::MbedCrypto,
+                    String::from("key"),
+                ),
+                KeyInfo {
+                    id: vec![1],
+                    attributes: CanaryKeys::attributes(),
+                    state: KeyState::Active,
+                    created_at: None,
+                    counter: None,
+                    certificate: None,
+                    labels: BTreeMap::new(),
+                },
+            )
+            .unwrap();
+        let front_end_handler = front_end_handler_with(|builder| {
+            builder.with_key_labels(KeyLabels::new(store.clone()))
+        });
+        assert_eq!(
+            request(&front_end_handler, "owner", list_keys).result,
+            Some(ExtensionResult::Keys { keys: Vec::new() })
+        );
+        assert_eq!(
+            request(&front_end_handler, "owner", set_labels),
+            ExtensionResponse::from_status(ResponseStatus::Success)
+        );
+        let labels: BTreeMap<String, String> = vec![(String::from("device"), String::from("1"))]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            request(&front_end_handler, "owner", list_keys).result,
+            Some(ExtensionResult::Keys {
+                keys: vec![LabeledKey {
+                    name: String::from("key"),
+                    labels,
+                }],
+            })
+        );
+        // The keys of the other applications are not listed, nor can their labels be set.
+        assert_eq!(
+            request(&front_end_handler, "other", list_keys).result,
+            Some(ExtensionResult::Keys { keys: Vec::new() })
+        );
+        assert_eq!(
+            request(&front_end_handler, "other", set_labels),
+            ExtensionResponse::from_status(ResponseStatus::PsaErrorDoesNotExist)
+        );
+    }
+
+    #[test]
+    fn handshake_without_authentication() {
+        use crate::providers::core_provider::CoreProviderBuilder;
+
+        let core_provider = CoreProviderBuilder::new()
+            .unwrap()
+            .with_wire_protocol_version(0, 1)
+            .with_authenticator_info(AuthenticatorInfo {
+                description: String::from("Names"),
+                version_maj: 0,
+                version_min: 1,
+                version_rev: 0,
+                id: AuthType::Direct,
+            })
+            .build()
+            .unwrap();
+        let core = BackEndHandlerBuilder::new()
+            .with_provider(Box::from(core_provider))
+            .with_converter(Box::from(ProtobufConverter {}))
+            .with_provider_id(ProviderID::Core)
+            .with_content_type(BodyType::Protobuf)
+            .with_accept_type(BodyType::Protobuf)
+            .build()
+            .unwrap();
+        let front_end_handler = FrontEndHandlerBuilder::new()
+            .with_dispatcher(
+                DispatcherBuilder::new()
+                    .with_backend(ProviderID::Core, core)
+                    .build()
+                    .unwrap(),
+            )
+            .with_authenticator(
+                AuthType::Direct,
+                ChainedAuthenticator::new(Box::from(NameAuthenticator)),
+            )
+            .with_body_len_limit(1 << 16)
+            .build()
+            .unwrap();
+
+        match handle_request("{\"operation\":\"Handshake\"}", None, &front_end_handler) {
+            ExtensionResponse {
+                result:
+                    Some(ExtensionResult::Handshake {
+                        wire_protocol_version,
+                        content_types,
+                        accept_types,
+                        authenticators,
+                        extensions,
+                    }),
+                ..
+            } => {
+                assert_eq!(wire_protocol_version, "1.0");
+                assert_eq!(content_types, vec![String::from("Protobuf")]);
+                assert_eq!(accept_types, vec![String::from("Protobuf")]);
+                assert_eq!(authenticators, vec![String::from("Direct")]);
+                assert!(extensions.contains(&String::from("Handshake")));
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+}
@@ -4,16 +4,99 @@
 //!
 //! The front end handler accepts streams of data that it can use to read requests,
 //! pass them to the rest of the service and write the responses back.
-use crate::authenticators::Authenticate;
+//!
+//! Request bodies are read once from the stream and their ownership is then moved down to the
+//! back end handler which converts them to native operations. They must not be cloned on the
+//! way as they can be large (e.g. key material being imported).
+//!
+//! A payload is hence copied twice in the service: from the socket into the request body, then by
+//! the protobuf decoder into the native operation, the body being dropped once decoded. The
+//! operation is moved to the provider, which reads the payload where it is, and is only copied
+//! again for a shadowing provider, if one is registered. The response is encoded once and, if
+//! padded, copied once into a buffer of its padded length. The decoder copy can only go away with
+//! a decoder borrowing the payloads from a shared buffer, which the interface crate, where the
+//! bodies, the converters and the operations are defined, would have to provide.
+//!
+//! Response bodies are always written uncompressed. The wire protocol header has no field in which
+//! a client could ask for, or the service could flag, a compressed body and the wire encoder is
+//! part of the interface crate, so compressing large responses (such as exported certificate chains
+//! or wrapped keys) first needs a new version of the wire protocol, negotiated through the
+//! `wire_protocol_version` returned by the Ping operation.
+//!
+//! A client disconnecting while its request is executed is only noticed when its response can not
+//! be written: operations can not be cancelled once passed to a provider. If the request created a
+//! key, the key is then destroyed as the client never learnt about it, and the lost response is
+//! counted in the statistics.
+//!
+//! The response bodies can be padded to size buckets, see the `response_padding` module.
+//!
+//! Each request is executed with a correlation ID, generated or, if the service is configured to,
+//! chosen by the client, which the log lines written while executing it carry, see the
+//! `correlation_id` module.
+//!
+//! The administrative operations are only executed for the applications that their authenticator
+//! recognizes as administrators. The authenticators are chained as configured, each prefixing the
+//! names of the applications it admits and restricting the providers they use if configured to.
+//! The requests of the extension API, for the operations which are not part of the wire protocol,
+//! are authenticated and authorized by the same chain and policies.
+//!
+//! Each connection carries a single request, clients connecting again for the next one. Multi-part
+//! operations, such as the PSA key derivation family, can hence not keep their state per
+//! connection: it would have to be stored in the service under a handle returned to the client and
+//! given back in the following requests, bound to the application and expired when abandoned.
+//! The operations and their handle also need to be defined in `parsec-interface` first, along with
+//! operations for an application to list its contexts and abort them, freeing the resources held
+//! in the provider. The administration API being read-only, it could only list them, without the
+//! application names.
+//!
+//! Connections do not each hold a thread: the main loop accepts them and queues them to the thread
+//! pool, a worker reading the request of a connection only once its turn comes, within the timeout
+//! of the listener. Many clients connecting at once hence wait in that queue, and in the backlog of
+//! the listening socket, rather than each taking a thread. An asynchronous front end, for example
+//! on tokio, would save the time the workers spend waiting for slow clients, which the timeout
+//! bounds, but not the time spent in the providers: they are synchronous and would run behind
+//! `spawn_blocking` on a pool of the same size, their concurrency being set by `thread_pool_size`
+//! and the `max_concurrency` of each provider.
+use crate::authenticators::authenticator_chain::ChainedAuthenticator;
+use crate::authenticators::{ApplicationName, Authenticate};
 use crate::back::dispatcher::Dispatcher;
+use crate::front::listener::{Connection, ConnectionMetadata};
+#[cfg(feature = "policy-engine")]
+use crate::front::policy_engine::PolicyEngine;
+use crate::front::response_padding::ResponsePadding;
+use crate::utils::correlation_id::{self, CorrelationId, CorrelationIds};
+use crate::utils::name_policy::NamePolicy;
 use derivative::Derivative;
-use log::{info, trace};
-use parsec_interface::requests::AuthType;
+use log::{error, info, trace};
+use parsec_interface::requests::request::RequestAuth;
 use parsec_interface::requests::ResponseStatus;
+use parsec_interface::requests::{AuthType, Opcode, ProviderID};
 use parsec_interface::requests::{Request, Response};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::io::{Error, ErrorKind, Result};
-use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Operations reserved to the administrator applications.
+const ADMIN_OPCODES: [Opcode; 2] = [Opcode::ListClients, Opcode::DeleteClient];
+
+/// Operation a request is checked for against the denied operations and the policy engine
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// Operation of the wire protocol
+    Opcode(Opcode),
+    /// Operation of an extension API, named as in it
+    Extension(&'static str),
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Opcode(opcode) => write!(f, "{:?}", opcode),
+            Operation::Extension(name) => write!(f, "{}", name),
+        }
+    }
+}
 
 /// Read and verify request from IPC stream
 ///
@@ -25,45 +108,118 @@ use std::io::{Read, Write};
 #[derivative(Debug)]
 pub struct FrontEndHandler {
     dispatcher: Dispatcher,
-    // Send and Sync are required for Arc<FrontEndHandler> to be Send.
-    #[derivative(Debug = "ignore")]
-    authenticators: HashMap<AuthType, Box<dyn Authenticate + Send + Sync>>,
+    authenticators: HashMap<AuthType, ChainedAuthenticator>,
     /// Value used to limit the size of the request body to be that can be accepted by the service.
     body_len_limit: usize,
+    /// Opcodes refused for all the clients.
+    denied_opcodes: HashSet<Opcode>,
+    /// Names of the extension operations refused for all the clients.
+    denied_extensions: HashSet<String>,
+    name_policy: NamePolicy,
+    /// External engine authorizing the authenticated requests.
+    #[cfg(feature = "policy-engine")]
+    policy_engine: Option<PolicyEngine>,
+    /// Padding of the response bodies to size buckets.
+    response_padding: Option<ResponsePadding>,
+    correlation_ids: CorrelationIds,
+    requests_received: AtomicU64,
+    requests_failed: AtomicU64,
+    responses_lost: AtomicU64,
+    connections_active: AtomicU64,
+}
+
+/// Number of requests handled by a `FrontEndHandler`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RequestStatistics {
+    /// Number of requests read from the clients
+    pub received: u64,
+    /// Number of requests whose response does not have a success status
+    pub failed: u64,
+    /// Number of responses which could not be written back, the client having disconnected
+    pub lost: u64,
+    /// Number of connections whose request is being read, executed or answered
+    pub active: u64,
+}
+
+// Counts a connection as active until it is dropped.
+#[derive(Debug)]
+struct ActiveConnection<'a>(&'a AtomicU64);
+
+impl<'a> ActiveConnection<'a> {
+    fn new(connections_active: &'a AtomicU64) -> Self {
+        let _ = connections_active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(connections_active)
+    }
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        let _ = self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl FrontEndHandler {
     /// Handle new connections on the underlying IPC mechanism.
     ///
-    /// Unmarshalls a request from the stream, passes it to the dispatcher and marshalls
-    /// the response back onto the stream.
+    /// Unmarshalls a request from the stream of the connection, passes it to the dispatcher, along
+    /// with the connection metadata, and marshalls the response back onto the stream.
     ///
     /// If an error occurs during (un)marshalling, no operation will be performed and the
     /// method will return.
-    pub fn handle_request<T: Read + Write>(&self, mut stream: T) {
+    pub fn handle_request(&self, mut connection: Connection) {
         trace!("handle_request ingress");
+        let _active = ActiveConnection::new(&self.connections_active);
         // Read bytes from stream
         // De-Serialise bytes into a request
-        let request = match Request::read_from_stream(&mut stream, self.body_len_limit) {
+        let request = match Request::read_from_stream(&mut connection.stream, self.body_len_limit) {
             Ok(request) => request,
             Err(status) => {
                 format_error!("Failed to read request", status);
 
                 let response = Response::from_status(status);
-                if let Err(status) = response.write_to_stream(&mut stream) {
+                if let Err(status) = response.write_to_stream(&mut connection.stream) {
                     format_error!("Failed to write response", status);
                 }
                 return;
             }
         };
 
+        let _ = self.requests_received.fetch_add(1, Ordering::Relaxed);
+        // The session field is only read as the correlation ID if configured to.
+        let session_correlation_ids = crate::utils::GlobalConfig::session_correlation_ids();
+        let correlation_id = match request.header.session {
+            session if session_correlation_ids && session != 0 => CorrelationId::new(session),
+            _ => self.correlation_ids.generate(),
+        };
+        let _correlation = correlation_id::enter(correlation_id);
+
+        // Refuse the operations denied by configuration before anything else is done
+        let (app_name, err_response) = if self.denied_opcodes.contains(&request.header.opcode) {
+            error!(
+                "Operation {:?} is denied by the configuration.",
+                request.header.opcode
+            );
+            (
+                None,
+                Some(Response::from_request_header(
+                    request.header,
+                    ResponseStatus::PsaErrorNotPermitted,
+                )),
+            )
         // Check if the request was sent without authentication
-        let (app_name, err_response) = if AuthType::NoAuth == request.header.auth_type {
+        } else if AuthType::NoAuth == request.header.auth_type {
             (None, None)
         // Otherwise find an authenticator that is capable to authenticate the request
         } else if let Some(authenticator) = self.authenticators.get(&request.header.auth_type) {
             // Authenticate the request
-            match authenticator.authenticate(&request.auth) {
+            match self.authenticate_with(
+                authenticator,
+                &request.auth,
+                connection.metadata,
+                request.header.provider,
+                &[Operation::Opcode(request.header.opcode)],
+                ADMIN_OPCODES.contains(&request.header.opcode),
+            ) {
                 // Send the request to the dispatcher
                 // Get a response back
                 Ok(app_name) => (Some(app_name), None),
@@ -82,27 +238,40 @@ impl FrontEndHandler {
             )
         };
 
-        let response = if let Some(err_response) = err_response {
-            err_response
+        let (mut response, created_key) = if let Some(err_response) = err_response {
+            (err_response, None)
         } else {
             if crate::utils::GlobalConfig::log_error_details() {
-                if let Some(app_name_string) = app_name.clone() {
+                if let Some(app_name_string) = &app_name {
                     info!(
-                        "New request received from application name \"{}\"",
-                        app_name_string
+                        "New request received from application name \"{}\", admitted by the {:?} authenticator",
+                        app_name_string, request.header.auth_type
                     )
                 } else {
                     info!("New request received without authentication")
                 }
             };
-            let response = self.dispatcher.dispatch_request(request, app_name.clone());
+            let response =
+                self.dispatcher
+                    .dispatch_request(request, app_name.clone(), connection.metadata);
             trace!("dispatch_request egress");
             response
         };
 
+        if response.header.status != ResponseStatus::Success {
+            let _ = self.requests_failed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if let Some(response_padding) = &self.response_padding {
+            response_padding.pad(&mut response);
+        }
+        if session_correlation_ids {
+            response.header.session = correlation_id.value();
+        }
+
         // Serialise the response into bytes
         // Write bytes to stream
-        match response.write_to_stream(&mut stream) {
+        match response.write_to_stream(&mut connection.stream) {
             Ok(_) => {
                 if crate::utils::GlobalConfig::log_error_details() {
                     if let Some(app_name_string) = app_name {
@@ -115,9 +284,155 @@ impl FrontEndHandler {
                     }
                 }
             }
-            Err(err) => format_error!("Failed to send response", err),
+            Err(err) => {
+                format_error!("Failed to send response", err);
+                let _ = self.responses_lost.fetch_add(1, Ordering::Relaxed);
+                // The client disconnected, or stopped reading, and does not know about the key it
+                // asked for: it would stay in the provider without anyone to use or destroy it.
+                if let Some(created_key) = created_key {
+                    self.dispatcher.roll_back_key_creation(&created_key);
+                }
+            }
         }
     }
+
+    /// Authenticates the request of an operation which is not part of the wire protocol, received
+    /// by an extension API, and checks that the application can execute it as if it was read
+    /// from the listener. The operations are the extension operation itself and the ones of the
+    /// wire protocol executed on behalf of the request, if any, which must all be allowed. The
+    /// operations reserved to the administrators are refused to the other applications.
+    ///
+    /// # Errors
+    ///
+    /// Returns `AuthenticatorNotRegistered` if no authenticator of this type is configured,
+    /// `PsaErrorNotPermitted` if one of the operations is denied by the configuration or the
+    /// application can not execute it, and the error of the authenticator otherwise.
+    pub fn authenticate(
+        &self,
+        auth_type: AuthType,
+        auth: &RequestAuth,
+        metadata: Option<ConnectionMetadata>,
+        provider_id: ProviderID,
+        operations: &[Operation],
+        admin_only: bool,
+    ) -> parsec_interface::requests::Result<ApplicationName> {
+        if let Some(operation) = operations
+            .iter()
+            .find(|operation| self.is_denied(**operation))
+        {
+            error!("Operation {} is denied by the configuration.", operation);
+            return Err(ResponseStatus::PsaErrorNotPermitted);
+        }
+        let authenticator = self
+            .authenticators
+            .get(&auth_type)
+            .ok_or(ResponseStatus::AuthenticatorNotRegistered)?;
+
+        self.authenticate_with(
+            authenticator,
+            auth,
+            metadata,
+            provider_id,
+            operations,
+            admin_only,
+        )
+    }
+
+    /// Checks if an operation is denied by the configuration.
+    pub fn is_denied(&self, operation: Operation) -> bool {
+        match operation {
+            Operation::Opcode(opcode) => self.denied_opcodes.contains(&opcode),
+            Operation::Extension(name) => self.denied_extensions.contains(name),
+        }
+    }
+
+    /// Checks if an application, authenticated by the authenticator of the given type, is an
+    /// administrator.
+    pub fn is_admin(&self, auth_type: AuthType, app_name: &ApplicationName) -> bool {
+        self.authenticators
+            .get(&auth_type)
+            .map_or(false, |authenticator| authenticator.is_admin(app_name))
+    }
+
+    // Authenticates a request with the authenticator of its type and applies the policies of the
+    // service to the application admitted.
+    fn authenticate_with(
+        &self,
+        authenticator: &ChainedAuthenticator,
+        auth: &RequestAuth,
+        metadata: Option<ConnectionMetadata>,
+        provider_id: ProviderID,
+        operations: &[Operation],
+        admin_only: bool,
+    ) -> parsec_interface::requests::Result<ApplicationName> {
+        authenticator
+            .authenticate(auth, metadata)
+            .and_then(|app_name| self.name_policy.normalize_app_name(app_name))
+            .and_then(|app_name| {
+                if admin_only && !authenticator.is_admin(&app_name) {
+                    error!("The operation is reserved to the administrators.");
+                    Err(ResponseStatus::PsaErrorNotPermitted)
+                } else {
+                    Ok(app_name)
+                }
+            })
+            .and_then(|app_name| authenticator.check_provider(provider_id).map(|_| app_name))
+            .and_then(|app_name| {
+                operations.iter().try_fold(app_name, |app_name, operation| {
+                    self.authorize(app_name, provider_id, *operation)
+                })
+            })
+    }
+
+    /// Asks the policy engine, if one is configured, whether the application can execute the
+    /// operation.
+    #[cfg(feature = "policy-engine")]
+    fn authorize(
+        &self,
+        app_name: ApplicationName,
+        provider_id: ProviderID,
+        operation: Operation,
+    ) -> parsec_interface::requests::Result<ApplicationName> {
+        match &self.policy_engine {
+            Some(policy_engine) => policy_engine
+                .authorize(&app_name, provider_id, operation)
+                .map(|_| app_name),
+            None => Ok(app_name),
+        }
+    }
+
+    /// Without a policy engine, all the authenticated requests are authorized.
+    #[cfg(not(feature = "policy-engine"))]
+    fn authorize(
+        &self,
+        app_name: ApplicationName,
+        _provider_id: ProviderID,
+        _operation: Operation,
+    ) -> parsec_interface::requests::Result<ApplicationName> {
+        Ok(app_name)
+    }
+
+    /// Destroys the peer keys whose time to live has elapsed.
+    pub fn reap_expired_peer_keys(&self) {
+        self.dispatcher.reap_expired_peer_keys();
+    }
+
+    /// Gets the number of requests handled since the front end handler was built. Requests which
+    /// could not be read from their stream are not counted, their connection is only counted as
+    /// active while it is read.
+    pub fn statistics(&self) -> RequestStatistics {
+        RequestStatistics {
+            received: self.requests_received.load(Ordering::Relaxed),
+            failed: self.requests_failed.load(Ordering::Relaxed),
+            lost: self.responses_lost.load(Ordering::Relaxed),
+            active: self.connections_active.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Gets the dispatcher the requests are passed to.
+    pub fn dispatcher(&self) -> &Dispatcher {
+        &self.dispatcher
+    }
 }
 
 /// Builder for `FrontEndHandler`
@@ -125,9 +440,14 @@ impl FrontEndHandler {
 #[derivative(Debug)]
 pub struct FrontEndHandlerBuilder {
     dispatcher: Option<Dispatcher>,
-    #[derivative(Debug = "ignore")]
-    authenticators: Option<HashMap<AuthType, Box<dyn Authenticate + Send + Sync>>>,
+    authenticators: Option<HashMap<AuthType, ChainedAuthenticator>>,
     body_len_limit: Option<usize>,
+    denied_opcodes: HashSet<Opcode>,
+    denied_extensions: HashSet<String>,
+    name_policy: Option<NamePolicy>,
+    #[cfg(feature = "policy-engine")]
+    policy_engine: Option<PolicyEngine>,
+    response_padding: Option<ResponsePadding>,
 }
 
 impl FrontEndHandlerBuilder {
@@ -136,6 +456,12 @@ impl FrontEndHandlerBuilder {
             dispatcher: None,
             authenticators: None,
             body_len_limit: None,
+            denied_opcodes: HashSet::new(),
+            denied_extensions: HashSet::new(),
+            name_policy: None,
+            #[cfg(feature = "policy-engine")]
+            policy_engine: None,
+            response_padding: None,
         }
     }
 
@@ -147,7 +473,7 @@ impl FrontEndHandlerBuilder {
     pub fn with_authenticator(
         mut self,
         auth_type: AuthType,
-        authenticator: Box<dyn Authenticate + Send + Sync>,
+        authenticator: ChainedAuthenticator,
     ) -> Self {
         match &mut self.authenticators {
             Some(authenticators) => {
@@ -168,6 +494,33 @@ impl FrontEndHandlerBuilder {
         self
     }
 
+    pub fn with_denied_opcodes(mut self, denied_opcodes: HashSet<Opcode>) -> Self {
+        self.denied_opcodes = denied_opcodes;
+        self
+    }
+
+    /// Refuses the extension operations of the given names for all the clients.
+    pub fn with_denied_extensions(mut self, denied_extensions: HashSet<String>) -> Self {
+        self.denied_extensions = denied_extensions;
+        self
+    }
+
+    pub fn with_name_policy(mut self, name_policy: NamePolicy) -> Self {
+        self.name_policy = Some(name_policy);
+        self
+    }
+
+    #[cfg(feature = "policy-engine")]
+    pub fn with_policy_engine(mut self, policy_engine: PolicyEngine) -> Self {
+        self.policy_engine = Some(policy_engine);
+        self
+    }
+
+    pub fn with_response_padding(mut self, response_padding: ResponsePadding) -> Self {
+        self.response_padding = Some(response_padding);
+        self
+    }
+
     pub fn build(self) -> Result<FrontEndHandler> {
         Ok(FrontEndHandler {
             dispatcher: self
@@ -179,6 +532,17 @@ impl FrontEndHandlerBuilder {
             body_len_limit: self
                 .body_len_limit
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "body_len_limit is missing"))?,
+            denied_opcodes: self.denied_opcodes,
+            denied_extensions: self.denied_extensions,
+            name_policy: self.name_policy.unwrap_or_default(),
+            #[cfg(feature = "policy-engine")]
+            policy_engine: self.policy_engine,
+            response_padding: self.response_padding,
+            correlation_ids: Default::default(),
+            requests_received: AtomicU64::new(0),
+            requests_failed: AtomicU64::new(0),
+            responses_lost: AtomicU64::new(0),
+            connections_active: AtomicU64::new(0),
         })
     }
 }
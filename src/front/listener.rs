@@ -5,7 +5,26 @@
 //! The [`Listen`](https://parallaxsecond.github.io/parsec-book/parsec_service/listeners.html)
 //! trait acts as an interface for the operations that must be supported by any implementation
 //! of the IPC mechanism used as a Parsec front.
+//!
+//! The listeners, and the service around them, are Unix only for now. A named pipe listener for
+//! Windows would fit behind `Listen`, with the access to the pipe given by a security descriptor
+//! written in SDDL and the SID of the client token as connection metadata, but the trait hands the
+//! listening socket off as a `RawFd` on warm restarts and the binary is driven by Unix signals and
+//! `sd_notify`: the hand-off would have to become optional per platform and the main loop would
+//! need a Windows service control handler in place of the signals. There is no provider for the
+//! Windows CNG or TBS interfaces either, which the port would be for.
+#[cfg(feature = "tls-listener")]
+use super::tls_listener::TlsListenerConfig;
+#[cfg(feature = "vsock-listener")]
+use super::vsock_listener::VsockListenerConfig;
+use derivative::Derivative;
 use serde::Deserialize;
+use std::fmt;
+use std::io::{Error, Result};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::str;
 use std::time::Duration;
 
 // This trait is created to allow the iterator returned by incoming to iterate over a trait object
@@ -14,15 +33,109 @@ pub trait ReadWrite: std::io::Read + std::io::Write {}
 // Automatically implements ReadWrite for all types that implement Read and Write.
 impl<T: std::io::Read + std::io::Write> ReadWrite for T {}
 
-#[derive(Copy, Clone, Deserialize, Debug)]
+#[derive(Copy, Clone, Deserialize, Debug, PartialEq)]
 pub enum ListenerType {
     DomainSocket,
+    Tls,
+    Vsock,
 }
 
-#[derive(Copy, Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug, PartialEq)]
 pub struct ListenerConfig {
     pub listener_type: ListenerType,
     pub timeout: u64,
+    #[cfg(feature = "tls-listener")]
+    pub tls: Option<TlsListenerConfig>,
+    #[cfg(feature = "vsock-listener")]
+    pub vsock: Option<VsockListenerConfig>,
+}
+
+/// Common name of the subject of a certificate, at most 64 bytes long as per RFC 5280
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CommonName {
+    len: u8,
+    bytes: [u8; CommonName::MAX_LEN],
+}
+
+impl CommonName {
+    /// Maximum length of a common name, the `ub-common-name` of RFC 5280
+    pub const MAX_LEN: usize = 64;
+
+    /// Creates the common name, if it is not longer than the maximum length.
+    pub fn new(name: &str) -> Option<CommonName> {
+        if name.len() > CommonName::MAX_LEN {
+            return None;
+        }
+        let mut bytes = [0; CommonName::MAX_LEN];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+
+        Some(CommonName {
+            len: name.len() as u8,
+            bytes,
+        })
+    }
+
+    /// Gets the common name as a string.
+    pub fn as_str(&self) -> &str {
+        // The bytes were copied from a string.
+        str::from_utf8(&self.bytes[..usize::from(self.len)]).unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for CommonName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// Metadata associated with a connection, identifying the client on the other end.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ConnectionMetadata {
+    /// Credentials of the peer process of a Unix domain socket, as given by `SO_PEERCRED`.
+    UnixPeerCredentials { uid: u32, gid: u32, pid: i32 },
+    /// Common name of the certificate of a client authenticated by mutual TLS.
+    TlsClientCertificate { common_name: CommonName },
+    /// Context ID of the virtual machine on the other end of a vsock socket.
+    VsockPeer { cid: u32 },
+}
+
+/// Gets the credentials of the process on the other end of a Unix domain socket stream.
+pub fn peer_credentials(stream: &UnixStream) -> Result<ConnectionMetadata> {
+    // Safety: ucred is a plain structure of integers for which all-zero is a valid value.
+    let mut ucred: libc::ucred = unsafe { mem::zeroed() };
+    let ucred_ptr: *mut libc::ucred = &mut ucred;
+    let mut ucred_size = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // Safety: the pointer and size given describe a valid ucred structure, which is what the
+    // SO_PEERCRED option writes.
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            ucred_ptr as *mut libc::c_void,
+            &mut ucred_size,
+        )
+    };
+    if ret != 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ConnectionMetadata::UnixPeerCredentials {
+            uid: ucred.uid,
+            gid: ucred.gid,
+            pid: ucred.pid,
+        })
+    }
+}
+
+/// Connection to a single client
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Connection {
+    /// Stream to read the request from and write the response to
+    #[derivative(Debug = "ignore")]
+    pub stream: Box<dyn ReadWrite + Send>,
+    /// Metadata about the client, if the listener could retrieve any
+    pub metadata: Option<ConnectionMetadata>,
 }
 
 /// IPC front manager interface
@@ -33,10 +146,10 @@ pub trait Listen {
     /// Set the timeout on read and write calls on any stream returned by this listener.
     fn set_timeout(&mut self, duration: Duration);
 
-    /// Non-blocking call that gets the next client connection and returns a stream
-    /// (a Read and Write trait object). Requests are read from the stream and responses are written
-    /// to it. Streams returned by this method should have a timeout period as set by the
-    /// `set_timeout` method.
+    /// Non-blocking call that gets the next client connection and returns it, with a stream
+    /// (a Read and Write trait object) and metadata identifying the client. Requests are read from
+    /// the stream and responses are written to it. Streams returned by this method should have a
+    /// timeout period as set by the `set_timeout` method.
     /// If no connections are present, return `None`.
     /// If there are any errors in establishing the connection other than the missing
     /// initialization, the implementation should log them and return `None`.
@@ -45,5 +158,11 @@ pub trait Listen {
     /// # Panics
     ///
     /// If the listener has not been initialised before, with the `init` method.
-    fn accept(&self) -> Option<Box<dyn ReadWrite + Send>>;
+    fn accept(&self) -> Option<Connection>;
+
+    /// Returns the file descriptor of the listening socket, for it to be handed off to a new
+    /// binary of the service, or `None` if the listener can not be handed off.
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
 }
@@ -1,6 +1,18 @@
 // Copyright 2019 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
 //! IPC front handlers
+#[cfg(feature = "admin-api")]
+pub mod admin_api;
+#[cfg(feature = "unix-socket-listener")]
 pub mod domain_socket;
+#[cfg(feature = "extension-api")]
+pub mod extension_api;
 pub mod front_end;
 pub mod listener;
+#[cfg(feature = "policy-engine")]
+pub mod policy_engine;
+pub mod response_padding;
+#[cfg(feature = "tls-listener")]
+pub mod tls_listener;
+#[cfg(feature = "vsock-listener")]
+pub mod vsock_listener;
@@ -0,0 +1,204 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Delegation of the authorization decisions to an external policy engine
+//!
+//! Tenancy rules too complex to be expressed in the configuration can be evaluated by an external
+//! policy engine, such as Open Policy Agent behind a small adapter, listening on a Unix domain
+//! socket. Once a request is authenticated, the front end handler asks the engine whether the
+//! application can execute the operation on the provider, and refuses it with
+//! `PsaErrorNotPermitted` otherwise. Unauthenticated requests are not submitted to the engine.
+//!
+//! The query is a JSON object on a single line, with the `app_name`, `provider` and `opcode`
+//! fields, the operations of the extension API being given under their names in the latter, to
+//! which the engine answers with a JSON object on a single line whose `allow` field is
+//! the decision. The body of the request is not decoded at this point, so the decisions can not
+//! depend on the key used. Decisions are cached for a configurable time to live. When the engine
+//! can not be reached or answers something invalid, the request is refused unless the integration
+//! is configured to fail open.
+use crate::authenticators::ApplicationName;
+use crate::front::front_end::Operation;
+use log::{error, warn};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_CACHE_TIME_TO_LIVE: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+// Maximum size of an answer of the engine.
+const MAX_ANSWER_LEN: u64 = 4096;
+
+/// Configuration of the policy engine integration
+#[derive(Clone, Deserialize, Debug)]
+pub struct PolicyEngineConfig {
+    /// Path of the socket the engine listens on
+    pub socket_path: String,
+    /// Time for which the decisions are cached, in seconds, defaults to 10
+    pub cache_time_to_live: Option<u64>,
+    /// Time to wait for a decision, in milliseconds, defaults to 1000
+    pub timeout: Option<u64>,
+    /// Allow the requests when no decision can be obtained, defaults to false
+    pub fail_open: Option<bool>,
+}
+
+#[derive(Serialize, Debug)]
+struct Query<'a> {
+    app_name: &'a str,
+    provider: String,
+    opcode: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Decision {
+    allow: bool,
+}
+
+type CacheKey = (String, ProviderID, Operation);
+
+/// Client of the external policy engine
+#[derive(Debug)]
+pub struct PolicyEngine {
+    socket_path: PathBuf,
+    cache_time_to_live: Duration,
+    timeout: Duration,
+    fail_open: bool,
+    cache: Mutex<HashMap<CacheKey, (bool, Instant)>>,
+}
+
+impl PolicyEngine {
+    /// Creates the client from its configuration.
+    pub fn new(config: &PolicyEngineConfig) -> PolicyEngine {
+        PolicyEngine {
+            socket_path: PathBuf::from(&config.socket_path),
+            cache_time_to_live: config
+                .cache_time_to_live
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_CACHE_TIME_TO_LIVE),
+            timeout: config
+                .timeout
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_TIMEOUT),
+            fail_open: config.fail_open.unwrap_or(false),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks that the application is allowed to execute the operation on the provider.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorNotPermitted` if the engine denies the request, or if no decision could be
+    /// obtained while failing closed.
+    pub fn authorize(
+        &self,
+        app_name: &ApplicationName,
+        provider_id: ProviderID,
+        operation: Operation,
+    ) -> Result<()> {
+        let cache_key = (app_name.get_name().to_string(), provider_id, operation);
+        let now = Instant::now();
+        let cached = {
+            let mut cache = self.cache.lock().expect("Policy cache lock poisoned");
+            cache.retain(|_, (_, expiry)| *expiry > now);
+            cache.get(&cache_key).map(|(allow, _)| *allow)
+        };
+
+        let allow = match cached {
+            Some(allow) => allow,
+            None => match self.query(app_name, provider_id, operation) {
+                Ok(allow) => {
+                    let _ = self
+                        .cache
+                        .lock()
+                        .expect("Policy cache lock poisoned")
+                        .insert(cache_key, (allow, now + self.cache_time_to_live));
+                    allow
+                }
+                Err(e) => {
+                    format_error!("Failed to get a decision from the policy engine", e);
+                    if self.fail_open {
+                        warn!("Allowing the request as the policy engine integration fails open.");
+                    }
+                    self.fail_open
+                }
+            },
+        };
+
+        if allow {
+            Ok(())
+        } else {
+            error!("The policy engine denied the request.");
+            Err(ResponseStatus::PsaErrorNotPermitted)
+        }
+    }
+
+    fn query(
+        &self,
+        app_name: &ApplicationName,
+        provider_id: ProviderID,
+        operation: Operation,
+    ) -> std::io::Result<bool> {
+        let query = Query {
+            app_name: app_name.get_name(),
+            provider: provider_id.to_string(),
+            opcode: operation.to_string(),
+        };
+        let mut query = serde_json::to_vec(&query)?;
+        query.push(b'\n');
+
+        let mut stream = UnixStream::connect(&self.socket_path)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.write_all(&query)?;
+
+        let mut answer = String::new();
+        let _ =
+            BufReader::new(std::io::Read::take(&stream, MAX_ANSWER_LEN)).read_line(&mut answer)?;
+        let decision: Decision = serde_json::from_str(&answer)?;
+
+        Ok(decision.allow)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PolicyEngine, PolicyEngineConfig};
+    use crate::authenticators::ApplicationName;
+    use crate::front::front_end::Operation;
+    use parsec_interface::requests::{Opcode, ProviderID, ResponseStatus};
+
+    fn engine(fail_open: bool) -> PolicyEngine {
+        PolicyEngine::new(&PolicyEngineConfig {
+            socket_path: String::from("/nonexistent/policy.sock"),
+            cache_time_to_live: None,
+            timeout: None,
+            fail_open: Some(fail_open),
+        })
+    }
+
+    #[test]
+    fn unreachable_engine_fails_closed() {
+        let app_name = ApplicationName::new(String::from("app"));
+        assert_eq!(
+            engine(false)
+                .authorize(
+                    &app_name,
+                    ProviderID::Tpm,
+                    Operation::Opcode(Opcode::PsaSignHash),
+                )
+                .unwrap_err(),
+            ResponseStatus::PsaErrorNotPermitted
+        );
+        engine(true)
+            .authorize(
+                &app_name,
+                ProviderID::Tpm,
+                Operation::Opcode(Opcode::PsaSignHash),
+            )
+            .unwrap();
+    }
+}
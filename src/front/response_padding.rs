@@ -0,0 +1,180 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Padding of the response bodies to size buckets
+//!
+//! The length of a response body reveals information about the operation, such as the size of
+//! the key which made a signature or whether the operation failed. When the socket is observable,
+//! for example once forwarded over a network tunnel, the bodies can be padded up to the smallest
+//! configured size bucket which fits them, the buckets being per opcode or defaulting to global
+//! ones. A body larger than all the buckets is padded to a multiple of the largest one.
+//!
+//! The padding is an extra length-delimited field, of a number no operation uses, appended to the
+//! protobuf body: decoders skip unknown fields, so clients do not need to know about it. Its
+//! length is always encoded on the same number of bytes, padded varints being valid protobuf.
+use log::error;
+use parsec_interface::requests::response::ResponseBody;
+use parsec_interface::requests::{Opcode, Response};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+
+// Key of the padding field: field number 2047 with the length-delimited wire type, as a varint.
+const PADDING_FIELD_KEY: [u8; 2] = [0xFA, 0x7F];
+// Number of bytes on which the length of the padding is encoded.
+const PADDING_LEN_BYTES: usize = 3;
+const MIN_PADDING_LEN: usize = PADDING_FIELD_KEY.len() + PADDING_LEN_BYTES;
+// Largest bucket size, so that the length of the padding fits in PADDING_LEN_BYTES.
+const MAX_BUCKET_SIZE: usize = 1 << 20;
+
+/// Size buckets of the responses of an operation
+#[derive(Clone, Deserialize, Debug)]
+pub struct OpcodeBucketsConfig {
+    /// Name of the operation
+    pub name: String,
+    /// Sizes to which its response bodies are padded, in bytes
+    pub bucket_sizes: Vec<usize>,
+}
+
+/// Configuration of the padding of the response bodies
+#[derive(Clone, Deserialize, Debug)]
+pub struct ResponsePaddingConfig {
+    /// Sizes to which the response bodies are padded, in bytes, for the operations without
+    /// buckets of their own
+    pub bucket_sizes: Vec<usize>,
+    /// Buckets of specific operations
+    pub opcode: Option<Vec<OpcodeBucketsConfig>>,
+}
+
+/// Padding of the response bodies
+#[derive(Debug)]
+pub struct ResponsePadding {
+    bucket_sizes: Vec<usize>,
+    opcode_bucket_sizes: HashMap<Opcode, Vec<usize>>,
+}
+
+fn check_bucket_sizes(mut bucket_sizes: Vec<usize>) -> Result<Vec<usize>> {
+    bucket_sizes.sort_unstable();
+    bucket_sizes.dedup();
+    match (bucket_sizes.first(), bucket_sizes.last()) {
+        (Some(&smallest), Some(&largest))
+            if smallest >= MIN_PADDING_LEN && largest <= MAX_BUCKET_SIZE =>
+        {
+            Ok(bucket_sizes)
+        }
+        _ => {
+            error!(
+                "Response padding buckets must be given, between {} and {} bytes.",
+                MIN_PADDING_LEN, MAX_BUCKET_SIZE
+            );
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                "invalid padding buckets",
+            ))
+        }
+    }
+}
+
+impl ResponsePadding {
+    /// Creates the padding with the default buckets and the buckets of specific operations.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if a list of buckets is empty or has a size smaller
+    /// than the padding field or larger than 1 MiB.
+    pub fn new(
+        bucket_sizes: Vec<usize>,
+        opcode_bucket_sizes: HashMap<Opcode, Vec<usize>>,
+    ) -> Result<ResponsePadding> {
+        let mut checked_opcode_bucket_sizes = HashMap::new();
+        for (opcode, bucket_sizes) in opcode_bucket_sizes {
+            let _ = checked_opcode_bucket_sizes.insert(opcode, check_bucket_sizes(bucket_sizes)?);
+        }
+
+        Ok(ResponsePadding {
+            bucket_sizes: check_bucket_sizes(bucket_sizes)?,
+            opcode_bucket_sizes: checked_opcode_bucket_sizes,
+        })
+    }
+
+    /// Length to which a body of an operation is padded.
+    fn padded_len(&self, opcode: Opcode, len: usize) -> usize {
+        let bucket_sizes = self
+            .opcode_bucket_sizes
+            .get(&opcode)
+            .unwrap_or(&self.bucket_sizes);
+        let min_len = len + MIN_PADDING_LEN;
+        match bucket_sizes
+            .iter()
+            .find(|&&bucket_size| bucket_size >= min_len)
+        {
+            Some(&bucket_size) => bucket_size,
+            None => {
+                // Buckets are checked not to be empty.
+                let largest = bucket_sizes[bucket_sizes.len() - 1];
+                (min_len + largest - 1) / largest * largest
+            }
+        }
+    }
+
+    /// Pads the body of the response.
+    ///
+    /// The body is copied once, into a buffer of the padded length in which the padding field is
+    /// then encoded in place: the body of a response can not be extended where it is.
+    pub fn pad(&self, response: &mut Response) {
+        let body = response.body.bytes();
+        let padded_len = self.padded_len(response.header.opcode, body.len());
+        let mut padded_body = Vec::with_capacity(padded_len);
+        padded_body.extend_from_slice(body);
+        append_padding_field(&mut padded_body, padded_len - body.len());
+        response.body = ResponseBody::from_bytes(padded_body);
+    }
+}
+
+/// Encodes a padding field of the given total length, which is at least `MIN_PADDING_LEN` and at
+/// most `MAX_BUCKET_SIZE`, at the end of the buffer.
+fn append_padding_field(buffer: &mut Vec<u8>, total_len: usize) {
+    let data_len = total_len - MIN_PADDING_LEN;
+    let end = buffer.len() + total_len;
+    buffer.extend_from_slice(&PADDING_FIELD_KEY);
+    for i in 0..PADDING_LEN_BYTES {
+        let group = ((data_len >> (7 * i)) & 0x7F) as u8;
+        if i < PADDING_LEN_BYTES - 1 {
+            buffer.push(group | 0x80);
+        } else {
+            buffer.push(group);
+        }
+    }
+    buffer.resize(end, 0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{append_padding_field, ResponsePadding, MIN_PADDING_LEN};
+    use parsec_interface::requests::Opcode;
+    use std::collections::HashMap;
+
+    #[test]
+    fn bodies_padded_to_buckets() {
+        let mut opcode_bucket_sizes = HashMap::new();
+        let _ = opcode_bucket_sizes.insert(Opcode::PsaSignHash, vec![600]);
+        let padding = ResponsePadding::new(vec![1024, 64, 256], opcode_bucket_sizes).unwrap();
+
+        assert_eq!(padding.padded_len(Opcode::Ping, 0), 64);
+        assert_eq!(padding.padded_len(Opcode::Ping, 64 - MIN_PADDING_LEN), 64);
+        assert_eq!(
+            padding.padded_len(Opcode::Ping, 64 - MIN_PADDING_LEN + 1),
+            256
+        );
+        assert_eq!(padding.padded_len(Opcode::Ping, 1500), 2048);
+        assert_eq!(padding.padded_len(Opcode::PsaSignHash, 256), 600);
+
+        let mut field = vec![0x08, 0x01];
+        append_padding_field(&mut field, 300);
+        assert_eq!(field.len(), 302);
+        // Field key then the length of the data, 295, as a padded varint, after the body.
+        assert_eq!(&field[..7], &[0x08, 0x01, 0xFA, 0x7F, 0xA7, 0x82, 0x00]);
+
+        assert!(ResponsePadding::new(Vec::new(), HashMap::new()).is_err());
+        assert!(ResponsePadding::new(vec![2], HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Service front using TCP with mutual TLS
+//!
+//! Expose Parsec functionality to remote clients, such as containers on another host or the guest
+//! of a microVM, over TCP. Both ends authenticate with certificates: the clients have to present a
+//! certificate issued by one of the configured authorities and the common name of its subject is
+//! the identity of the client, given to the authenticators in the connection metadata. The TLS
+//! handshake is done when the connection is accepted, bounded by the timeout of the listener.
+//!
+//! As for the Unix domain socket, the listening socket can be given by systemd or by a previous
+//! binary of the service. This module is only compiled with the `tls-listener` feature.
+use super::listener::{CommonName, Connection, ConnectionMetadata, Listen};
+use derivative::Derivative;
+use log::error;
+use picky::x509::Cert;
+use rustls::internal::pemfile;
+use rustls::{
+    AllowAnyAuthenticatedClient, RootCertStore, ServerConfig, ServerSession, Session, StreamOwned,
+};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Configuration of the TLS listener
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct TlsListenerConfig {
+    /// Address and port to listen on, such as "0.0.0.0:8443"
+    pub address: String,
+    /// Path of the certificate chain of the service, in PEM
+    pub certificate_path: String,
+    /// Path of the private key of the service, in PEM as PKCS #8 or PKCS #1
+    pub private_key_path: String,
+    /// Path of the certificates of the authorities issuing the client certificates, in PEM
+    pub client_ca_path: String,
+}
+
+/// TLS IPC manager
+///
+/// Listener implementation for TCP connections authenticated with mutual TLS.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct TlsListener {
+    listener: TcpListener,
+    #[derivative(Debug = "ignore")]
+    tls_config: Arc<ServerConfig>,
+    timeout: Duration,
+}
+
+fn open(path: &str) -> Result<BufReader<File>> {
+    let file = File::open(path).map_err(|e| {
+        format_error!(format!("Failed to open {}", path), e);
+        e
+    })?;
+
+    Ok(BufReader::new(file))
+}
+
+fn invalid_pem(description: &str) -> Error {
+    error!("The {} of the TLS listener are not valid PEM.", description);
+    Error::new(ErrorKind::InvalidData, "invalid PEM file")
+}
+
+fn server_config(config: &TlsListenerConfig) -> Result<ServerConfig> {
+    let mut client_cas = RootCertStore::empty();
+    let (added, _) = client_cas
+        .add_pem_file(&mut open(&config.client_ca_path)?)
+        .map_err(|_| invalid_pem("client certificate authorities"))?;
+    if added == 0 {
+        error!("No client certificate authority could be read for the TLS listener.");
+        return Err(Error::new(ErrorKind::InvalidData, "no client CA"));
+    }
+
+    let certificates = pemfile::certs(&mut open(&config.certificate_path)?)
+        .map_err(|_| invalid_pem("certificates"))?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(&config.private_key_path)?)
+        .map_err(|_| invalid_pem("private keys"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(&config.private_key_path)?)
+            .map_err(|_| invalid_pem("private keys"))?;
+    }
+    let key = keys.into_iter().next().ok_or_else(|| {
+        error!("No private key could be read for the TLS listener.");
+        Error::new(ErrorKind::InvalidData, "no private key")
+    })?;
+
+    let mut server_config = ServerConfig::new(AllowAnyAuthenticatedClient::new(client_cas));
+    server_config
+        .set_single_cert(certificates, key)
+        .map_err(|e| {
+            format_error!("Invalid certificate or private key for the TLS listener", e);
+            Error::new(ErrorKind::InvalidData, "invalid TLS certificate")
+        })?;
+
+    Ok(server_config)
+}
+
+/// Get the common name of the certificate the client authenticated with.
+fn client_common_name(session: &ServerSession) -> Option<CommonName> {
+    let certificates = session.get_peer_certificates()?;
+    let certificate = Cert::from_der(&certificates.first()?.0)
+        .map_err(|e| format_error!("Failed to parse the client certificate", e))
+        .ok()?;
+    let subject = certificate.subject_name();
+    let common_name = subject.find_common_name()?.to_utf8_lossy();
+
+    CommonName::new(&common_name)
+}
+
+impl TlsListener {
+    /// Initialise the TCP listener and the TLS configuration.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the certificates or private key can not be read,
+    /// or the error of the bind.
+    pub fn new(timeout: Duration, config: &TlsListenerConfig) -> Result<Self> {
+        let tls_config = Arc::new(server_config(config)?);
+        // The listener can be given by systemd or by a previous binary of the service, as for the
+        // Unix domain socket.
+        let listener = match sd_notify::listen_fds()? {
+            0 => {
+                let listener = TcpListener::bind(&config.address)?;
+                listener.set_nonblocking(true)?;
+
+                listener
+            }
+            1 => {
+                let nfd = sd_notify::SD_LISTEN_FDS_START;
+                // Safe as listen_fds gives us the information that one file descriptor was
+                // received and its value starts from SD_LISTEN_FDS_START.
+                let listener = unsafe { TcpListener::from_raw_fd(nfd) };
+                listener.set_nonblocking(true)?;
+
+                listener
+            }
+            n => {
+                error!(
+                    "Received too many file descriptors ({} received, 0 or 1 expected).",
+                    n
+                );
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "too many file descriptors received",
+                ));
+            }
+        };
+
+        Ok(Self {
+            listener,
+            tls_config,
+            timeout,
+        })
+    }
+
+    /// Authenticate the client of a new connection.
+    fn handshake(&self, stream: TcpStream) -> Option<Connection> {
+        if let Err(err) = stream.set_read_timeout(Some(self.timeout)) {
+            format_error!("Failed to set read timeout", err);
+            return None;
+        }
+        if let Err(err) = stream.set_write_timeout(Some(self.timeout)) {
+            format_error!("Failed to set write timeout", err);
+            return None;
+        }
+        if let Err(err) = stream.set_nonblocking(false) {
+            format_error!("Failed to set stream as blocking", err);
+            return None;
+        }
+
+        let mut stream = StreamOwned::new(ServerSession::new(&self.tls_config), stream);
+        while stream.sess.is_handshaking() {
+            if let Err(err) = stream.sess.complete_io(&mut stream.sock) {
+                format_error!("TLS handshake with the client failed", err);
+                return None;
+            }
+        }
+        let common_name = match client_common_name(&stream.sess) {
+            Some(common_name) => common_name,
+            None => {
+                error!("The client certificate does not have a valid common name.");
+                return None;
+            }
+        };
+
+        Some(Connection {
+            stream: Box::from(stream),
+            metadata: Some(ConnectionMetadata::TlsClientCertificate { common_name }),
+        })
+    }
+}
+
+impl Listen for TlsListener {
+    fn set_timeout(&mut self, duration: Duration) {
+        self.timeout = duration;
+    }
+
+    fn accept(&self) -> Option<Connection> {
+        match self.listener.accept() {
+            Ok((stream, _)) => self.handshake(stream),
+            Err(err) => {
+                // Check if the error is because no connections are currently present.
+                if err.kind() != ErrorKind::WouldBlock {
+                    // Only log the real errors.
+                    format_error!("Failed to connect with a TcpStream", err);
+                }
+                None
+            }
+        }
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.listener.as_raw_fd())
+    }
+}
+
+/// Builder for `TlsListener`
+#[derive(Clone, Debug, Default)]
+pub struct TlsListenerBuilder {
+    timeout: Option<Duration>,
+    config: Option<TlsListenerConfig>,
+}
+
+impl TlsListenerBuilder {
+    pub fn new() -> Self {
+        TlsListenerBuilder {
+            timeout: None,
+            config: None,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_config(mut self, config: TlsListenerConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn build(self) -> Result<TlsListener> {
+        let timeout = self.timeout.ok_or_else(|| {
+            error!("The listener timeout was not set.");
+            Error::new(ErrorKind::InvalidInput, "listener timeout missing")
+        })?;
+        let config = self.config.ok_or_else(|| {
+            error!("The TLS listener needs a [listener.tls] section in the configuration.");
+            Error::new(
+                ErrorKind::InvalidInput,
+                "TLS listener configuration missing",
+            )
+        })?;
+
+        TlsListener::new(timeout, &config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::listener::CommonName;
+
+    #[test]
+    fn common_name_bounded() {
+        let common_name = CommonName::new("client.example.org").unwrap();
+        assert_eq!(common_name.as_str(), "client.example.org");
+        assert_eq!(CommonName::new(&"a".repeat(64)).unwrap().as_str().len(), 64);
+        assert!(CommonName::new(&"a".repeat(65)).is_none());
+    }
+}
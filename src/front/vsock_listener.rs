@@ -0,0 +1,306 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Service front using virtio vsock sockets
+//!
+//! Expose Parsec functionality to the guests of the virtual machines of the host over `AF_VSOCK`,
+//! which needs no network configuration in the guests. The context ID of the guest connecting,
+//! given by the hypervisor, is part of the connection metadata: the authenticator chain qualifies
+//! the names of the applications with it, so that the applications of different guests are
+//! distinct even if they claim the same name.
+//!
+//! As for the Unix domain socket, the listening socket can be given by systemd or by a previous
+//! binary of the service. This module is only compiled with the `vsock-listener` feature.
+use super::listener::{Connection, ConnectionMetadata, Listen};
+use log::error;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+// Length of the queue of the connections not accepted yet.
+const BACKLOG: libc::c_int = 128;
+
+/// Configuration of the vsock listener
+#[derive(Copy, Clone, Deserialize, Debug, PartialEq)]
+pub struct VsockListenerConfig {
+    /// Port to listen on
+    pub port: u32,
+    /// Context ID to listen on, defaults to any
+    pub cid: Option<u32>,
+}
+
+// Socket file descriptor, closed when dropped.
+#[derive(Debug)]
+struct Socket(RawFd);
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        // Safety: the file descriptor is owned by this structure.
+        let _ = unsafe { libc::close(self.0) };
+    }
+}
+
+fn check(ret: libc::c_int) -> Result<libc::c_int> {
+    if ret < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Stream of a connection accepted on the vsock listener
+#[derive(Debug)]
+struct VsockStream {
+    socket: Socket,
+}
+
+impl VsockStream {
+    fn set_timeout(&self, option: libc::c_int, duration: Duration) -> Result<()> {
+        let timeout = libc::timeval {
+            tv_sec: libc::time_t::try_from(duration.as_secs())
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "timeout too long"))?,
+            tv_usec: duration.subsec_micros() as libc::suseconds_t,
+        };
+        let timeout_ptr: *const libc::timeval = &timeout;
+        // Safety: the pointer and size given describe a valid timeval structure, which is what
+        // the timeout options read.
+        let _ = check(unsafe {
+            libc::setsockopt(
+                self.socket.0,
+                libc::SOL_SOCKET,
+                option,
+                timeout_ptr as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        })?;
+
+        Ok(())
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        // Safety: the pointer and length given describe the buffer.
+        let ret = unsafe {
+            libc::read(
+                self.socket.0,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if ret < 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+}
+
+impl Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        // Safety: the pointer and length given describe the buffer.
+        let ret = unsafe {
+            libc::write(
+                self.socket.0,
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+            )
+        };
+        if ret < 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Vsock IPC manager
+///
+/// Listener implementation for the `AF_VSOCK` sockets of the guests of the host.
+#[derive(Debug)]
+pub struct VsockListener {
+    socket: Socket,
+    timeout: Duration,
+}
+
+fn bind(config: &VsockListenerConfig) -> Result<Socket> {
+    // Safety: creating a socket has no memory safety requirement.
+    let socket = Socket(check(unsafe {
+        libc::socket(
+            libc::AF_VSOCK,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    })?);
+    // Safety: sockaddr_vm is a plain structure of integers for which all-zero is a valid value.
+    let mut address: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    address.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    address.svm_port = config.port;
+    address.svm_cid = config.cid.unwrap_or(libc::VMADDR_CID_ANY);
+    let address_ptr: *const libc::sockaddr_vm = &address;
+    // Safety: the pointer and size given describe a valid sockaddr_vm structure.
+    let _ = check(unsafe {
+        libc::bind(
+            socket.0,
+            address_ptr as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+        )
+    })?;
+    // Safety: listening has no memory safety requirement.
+    let _ = check(unsafe { libc::listen(socket.0, BACKLOG) })?;
+
+    Ok(socket)
+}
+
+impl VsockListener {
+    /// Initialise the vsock listener.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of the creation of the socket or of the bind, for example if the host
+    /// does not support vsock.
+    pub fn new(timeout: Duration, config: &VsockListenerConfig) -> Result<Self> {
+        // The listener can be given by systemd or by a previous binary of the service, as for the
+        // Unix domain socket.
+        let socket = match sd_notify::listen_fds()? {
+            0 => bind(config)?,
+            1 => {
+                let nfd = sd_notify::SD_LISTEN_FDS_START;
+                // Safety: listen_fds gives us the information that one file descriptor was
+                // received and its value starts from SD_LISTEN_FDS_START.
+                let flags = check(unsafe { libc::fcntl(nfd, libc::F_GETFL) })?;
+                let _ =
+                    check(unsafe { libc::fcntl(nfd, libc::F_SETFL, flags | libc::O_NONBLOCK) })?;
+
+                Socket(nfd)
+            }
+            n => {
+                error!(
+                    "Received too many file descriptors ({} received, 0 or 1 expected).",
+                    n
+                );
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "too many file descriptors received",
+                ));
+            }
+        };
+
+        Ok(Self { socket, timeout })
+    }
+
+    /// Accepts a connection, returning its stream and the context ID of the peer.
+    fn accept_stream(&self) -> Result<(VsockStream, u32)> {
+        // Safety: sockaddr_vm is a plain structure of integers for which all-zero is a valid value.
+        let mut address: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        let address_ptr: *mut libc::sockaddr_vm = &mut address;
+        let mut address_size = mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+        // Safety: the pointer and size given describe a valid sockaddr_vm structure, which is what
+        // accept writes for a vsock socket. The stream is blocking, as the other streams.
+        let stream = VsockStream {
+            socket: Socket(check(unsafe {
+                libc::accept4(
+                    self.socket.0,
+                    address_ptr as *mut libc::sockaddr,
+                    &mut address_size,
+                    libc::SOCK_CLOEXEC,
+                )
+            })?),
+        };
+        stream.set_timeout(libc::SO_RCVTIMEO, self.timeout)?;
+        stream.set_timeout(libc::SO_SNDTIMEO, self.timeout)?;
+
+        Ok((stream, address.svm_cid))
+    }
+}
+
+impl Listen for VsockListener {
+    fn set_timeout(&mut self, duration: Duration) {
+        self.timeout = duration;
+    }
+
+    fn accept(&self) -> Option<Connection> {
+        match self.accept_stream() {
+            Ok((stream, cid)) => Some(Connection {
+                stream: Box::from(stream),
+                metadata: Some(ConnectionMetadata::VsockPeer { cid }),
+            }),
+            Err(err) => {
+                // Check if the error is because no connections are currently present.
+                if err.kind() != ErrorKind::WouldBlock {
+                    // Only log the real errors.
+                    format_error!("Failed to connect with a vsock stream", err);
+                }
+                None
+            }
+        }
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.socket.0)
+    }
+}
+
+/// Builder for `VsockListener`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct VsockListenerBuilder {
+    timeout: Option<Duration>,
+    config: Option<VsockListenerConfig>,
+}
+
+impl VsockListenerBuilder {
+    pub fn new() -> Self {
+        VsockListenerBuilder {
+            timeout: None,
+            config: None,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_config(mut self, config: VsockListenerConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn build(self) -> Result<VsockListener> {
+        let timeout = self.timeout.ok_or_else(|| {
+            error!("The listener timeout was not set.");
+            Error::new(ErrorKind::InvalidInput, "listener timeout missing")
+        })?;
+        let config = self.config.ok_or_else(|| {
+            error!("The vsock listener needs a [listener.vsock] section in the configuration.");
+            Error::new(
+                ErrorKind::InvalidInput,
+                "vsock listener configuration missing",
+            )
+        })?;
+
+        VsockListener::new(timeout, &config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::VsockListenerBuilder;
+    use std::io::ErrorKind;
+    use std::time::Duration;
+
+    #[test]
+    fn configuration_needed() {
+        let error = VsockListenerBuilder::new()
+            .with_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+}
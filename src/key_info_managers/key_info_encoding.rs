@@ -0,0 +1,363 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Versioned encoding of the key information
+//!
+//! The key information used to be stored as the bincode serialization of `KeyInfo`, which follows
+//! the `Attributes` of `parsec-interface` field by field: a field added to them in a new version of
+//! the interface made the stored mappings unreadable. It is now stored in a representation owned by
+//! the service, made of:
+//! * a header, the `PARSECKI` magic followed by the version of the representation
+//! * the fields of the representation as a JSON object, the usage flags being listed by name
+//!
+//! Decoding ignores the fields and usage flags it does not know, so that a version of the service
+//! adding some can be rolled back; ignoring a usage flag only takes a permission away. Fields which
+//! can not be ignored need a new version of the representation, which older services refuse to
+//! decode. Mappings stored with bincode, without the header, are still decoded.
+//!
+//! The version 2 adds the state of the key, which an older service would ignore and use a
+//! pre-active key as an active one: only the pre-active keys are written in it, the active ones
+//! still being written in the version 1 so that services which do not know the state can read them.
+//! The creation time of the keys can be ignored and is written in both versions.
+//!
+//! The version 3 adds the counter bound to a key: an older service ignoring it would drop it the
+//! next time it writes the mapping and let the counter start again, defeating its purpose. Only the
+//! keys with a counter are written in it, along with their state.
+//!
+//! The certificate stored with a key, for the providers which can not store it themselves, can be
+//! ignored and is written in any version: an older service drops it the next time it writes the
+//! mapping, after which it has to be stored again. So can the labels of a key, which an older
+//! service drops in the same way.
+use super::{KeyInfo, KeyState};
+use log::warn;
+use parsec_interface::operations::psa_algorithm::Algorithm;
+use parsec_interface::operations::psa_key_attributes::{
+    Attributes, Lifetime, Policy, Type, UsageFlags,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const MAGIC: &[u8; 8] = b"PARSECKI";
+/// Version of the representation written for the active keys
+pub const CURRENT_VERSION: u8 = 1;
+/// Version of the representation written for the keys in another state
+pub const STATE_VERSION: u8 = 2;
+/// Version of the representation written for the keys with a counter
+pub const COUNTER_VERSION: u8 = 3;
+
+// Representation of the versions 1 to 3. Fields can be added to it as long as older services can
+// ignore them, otherwise they need a new version.
+#[derive(Serialize, Deserialize, Debug)]
+struct KeyInfoV1 {
+    id: Vec<u8>,
+    lifetime: Lifetime,
+    key_type: Type,
+    bits: usize,
+    usage_flags: Vec<String>,
+    permitted_algorithms: Algorithm,
+    // Only written in the version 2.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    state: Option<KeyState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+    // Only written in the version 3.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    counter: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    certificate: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+}
+
+/// Gets the names of the usage flags set, as stored in the representation.
+pub fn usage_flag_names(usage_flags: &UsageFlags) -> Vec<String> {
+    let flags = [
+        ("export", usage_flags.export),
+        ("copy", usage_flags.copy),
+        ("cache", usage_flags.cache),
+        ("encrypt", usage_flags.encrypt),
+        ("decrypt", usage_flags.decrypt),
+        ("sign_message", usage_flags.sign_message),
+        ("verify_message", usage_flags.verify_message),
+        ("sign_hash", usage_flags.sign_hash),
+        ("verify_hash", usage_flags.verify_hash),
+        ("derive", usage_flags.derive),
+    ];
+
+    flags
+        .iter()
+        .filter(|(_, is_set)| *is_set)
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+fn usage_flags_from_names(names: &[String]) -> UsageFlags {
+    let mut usage_flags = UsageFlags {
+        export: false,
+        copy: false,
+        cache: false,
+        encrypt: false,
+        decrypt: false,
+        sign_message: false,
+        verify_message: false,
+        sign_hash: false,
+        verify_hash: false,
+        derive: false,
+    };
+    for name in names {
+        match name.as_str() {
+            "export" => usage_flags.export = true,
+            "copy" => usage_flags.copy = true,
+            "cache" => usage_flags.cache = true,
+            "encrypt" => usage_flags.encrypt = true,
+            "decrypt" => usage_flags.decrypt = true,
+            "sign_message" => usage_flags.sign_message = true,
+            "verify_message" => usage_flags.verify_message = true,
+            "sign_hash" => usage_flags.sign_hash = true,
+            "verify_hash" => usage_flags.verify_hash = true,
+            "derive" => usage_flags.derive = true,
+            _ => warn!("Unknown usage flag \"{}\" of a stored key ignored.", name),
+        }
+    }
+
+    usage_flags
+}
+
+/// Encodes the key information in the oldest version of the representation able to carry it.
+///
+/// # Errors
+///
+/// Returns an error as a String if the representation could not be serialized.
+pub fn encode(key_info: &KeyInfo) -> Result<Vec<u8>, String> {
+    let attributes = &key_info.attributes;
+    let representation = KeyInfoV1 {
+        id: key_info.id.clone(),
+        lifetime: attributes.lifetime,
+        key_type: attributes.key_type,
+        bits: attributes.bits,
+        usage_flags: usage_flag_names(&attributes.policy.usage_flags),
+        permitted_algorithms: attributes.policy.permitted_algorithms,
+        state: match key_info.state {
+            KeyState::Active => None,
+            state => Some(state),
+        },
+        created_at: key_info.created_at,
+        counter: key_info.counter,
+        certificate: key_info.certificate.clone(),
+        labels: key_info.labels.clone(),
+    };
+
+    let mut encoded = MAGIC.to_vec();
+    encoded.push(if representation.counter.is_some() {
+        COUNTER_VERSION
+    } else if representation.state.is_some() {
+        STATE_VERSION
+    } else {
+        CURRENT_VERSION
+    });
+    serde_json::to_writer(&mut encoded, &representation).map_err(|e| e.to_string())?;
+
+    Ok(encoded)
+}
+
+/// Decodes key information stored in any known version of the representation, or with bincode.
+///
+/// # Errors
+///
+/// Returns an error as a String if the version of the representation is unknown or if the
+/// information could not be deserialized.
+pub fn decode(encoded: &[u8]) -> Result<KeyInfo, String> {
+    if !encoded.starts_with(MAGIC) {
+        // Stored before the representation was versioned.
+        return bincode::deserialize(encoded).map_err(|e| e.to_string());
+    }
+
+    match encoded.get(MAGIC.len()) {
+        Some(&version)
+            if version == CURRENT_VERSION
+                || version == STATE_VERSION
+                || version == COUNTER_VERSION =>
+        {
+            let representation: KeyInfoV1 =
+                serde_json::from_slice(&encoded[MAGIC.len() + 1..]).map_err(|e| e.to_string())?;
+            Ok(KeyInfo {
+                id: representation.id,
+                attributes: Attributes {
+                    lifetime: representation.lifetime,
+                    key_type: representation.key_type,
+                    bits: representation.bits,
+                    policy: Policy {
+                        usage_flags: usage_flags_from_names(&representation.usage_flags),
+                        permitted_algorithms: representation.permitted_algorithms,
+                    },
+                },
+                state: representation.state.unwrap_or_default(),
+                created_at: representation.created_at,
+                counter: representation.counter,
+                certificate: representation.certificate,
+                labels: representation.labels,
+            })
+        }
+        Some(version) => Err(format!(
+            "version {} of the key info representation is not known",
+            version
+        )),
+        None => Err(String::from("the key info representation has no version")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode, COUNTER_VERSION, CURRENT_VERSION, MAGIC, STATE_VERSION};
+    use crate::key_info_managers::{KeyInfo, KeyState};
+    use parsec_interface::operations::psa_algorithm::{
+        Algorithm, AsymmetricSignature, Hash, SignHash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, EccFamily, Lifetime, Policy, Type, UsageFlags,
+    };
+    use std::collections::BTreeMap;
+
+    fn key_info() -> KeyInfo {
+        KeyInfo {
+            id: vec![0x11, 0x22, 0x33],
+            attributes: Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::EccKeyPair {
+                    curve_family: EccFamily::SecpR1,
+                },
+                bits: 256,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: true,
+                        verify_hash: true,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: false,
+                        decrypt: false,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::AsymmetricSignature(
+                        AsymmetricSignature::Ecdsa {
+                            hash_alg: SignHash::Specific(Hash::Sha256),
+                        },
+                    ),
+                },
+            },
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn versions_decoded() {
+        let key_info = key_info();
+        let encoded = encode(&key_info).unwrap();
+        assert_eq!(decode(&encoded).unwrap(), key_info);
+
+        // Mappings stored before the representation was versioned.
+        let legacy = bincode::serialize(&key_info).unwrap();
+        assert_eq!(decode(&legacy).unwrap(), key_info);
+
+        // Fields and usage flags added by a later service are ignored.
+        let mut fields: serde_json::Value =
+            serde_json::from_slice(&encoded[MAGIC.len() + 1..]).unwrap();
+        fields["usage_flags"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::Value::from("future_flag"));
+        fields["future_field"] = serde_json::Value::from(42);
+        let mut future = MAGIC.to_vec();
+        future.push(1);
+        future.extend(serde_json::to_vec(&fields).unwrap());
+        assert_eq!(decode(&future).unwrap(), key_info);
+
+        // A representation which can not be understood is refused.
+        future[MAGIC.len()] = 4;
+        assert!(decode(&future).is_err());
+    }
+
+    #[test]
+    fn baseline_bincode_decoded() {
+        // Mapping of an RSA key pair as stored by the services which wrote the bincode
+        // serialization of the `id` and `attributes` of `KeyInfo`, before it was versioned.
+        let stored: [u8; 49] = [
+            0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // length of the ID
+            0x11, 0x22, 0x33, // ID
+            0x01, 0x00, 0x00, 0x00, // Lifetime::Persistent
+            0x09, 0x00, 0x00, 0x00, // Type::RsaKeyPair
+            0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 2048 bits
+            0x00, 0x00, 0x00, 0x00, 0x00, // export, copy, cache, encrypt, decrypt
+            0x00, 0x00, // sign_message, verify_message
+            0x01, 0x01, // sign_hash, verify_hash
+            0x00, // derive
+            0x05, 0x00, 0x00, 0x00, // Algorithm::AsymmetricSignature
+            0x00, 0x00, 0x00, 0x00, // AsymmetricSignature::RsaPkcs1v15Sign
+            0x01, 0x00, 0x00, 0x00, // SignHash::Any
+        ];
+        let mut key_info = key_info();
+        key_info.attributes.key_type = Type::RsaKeyPair;
+        key_info.attributes.bits = 2048;
+        key_info.attributes.policy.permitted_algorithms =
+            Algorithm::AsymmetricSignature(AsymmetricSignature::RsaPkcs1v15Sign {
+                hash_alg: SignHash::Any,
+            });
+
+        assert_eq!(decode(&stored).unwrap(), key_info);
+    }
+
+    #[test]
+    fn state_versioned() {
+        let mut key_info = key_info();
+        assert_eq!(encode(&key_info).unwrap()[MAGIC.len()], CURRENT_VERSION);
+
+        key_info.state = KeyState::PreActive;
+        let encoded = encode(&key_info).unwrap();
+        assert_eq!(encoded[MAGIC.len()], STATE_VERSION);
+        assert_eq!(decode(&encoded).unwrap(), key_info);
+    }
+
+    #[test]
+    fn creation_time_kept() {
+        let mut key_info = key_info();
+        key_info.created_at = Some(1_614_600_000);
+        let encoded = encode(&key_info).unwrap();
+        assert_eq!(encoded[MAGIC.len()], CURRENT_VERSION);
+        assert_eq!(decode(&encoded).unwrap(), key_info);
+    }
+
+    #[test]
+    fn counter_versioned() {
+        let mut key_info = key_info();
+        key_info.state = KeyState::PreActive;
+        key_info.counter = Some(7);
+        let encoded = encode(&key_info).unwrap();
+        assert_eq!(encoded[MAGIC.len()], COUNTER_VERSION);
+        assert_eq!(decode(&encoded).unwrap(), key_info);
+    }
+
+    #[test]
+    fn certificate_kept() {
+        let mut key_info = key_info();
+        key_info.certificate = Some(vec![0x30, 0x03, 0x02, 0x01, 0x01]);
+        let encoded = encode(&key_info).unwrap();
+        assert_eq!(encoded[MAGIC.len()], CURRENT_VERSION);
+        assert_eq!(decode(&encoded).unwrap(), key_info);
+    }
+
+    #[test]
+    fn labels_kept() {
+        let mut key_info = key_info();
+        let _ = key_info
+            .labels
+            .insert(String::from("device"), String::from("1"));
+        let encoded = encode(&key_info).unwrap();
+        assert_eq!(encoded[MAGIC.len()], CURRENT_VERSION);
+        assert_eq!(decode(&encoded).unwrap(), key_info);
+    }
+}
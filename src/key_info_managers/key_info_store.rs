@@ -0,0 +1,356 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Key Info Manager shared between the providers
+//!
+//! Most operations only need to read the key information, for example to find the ID of the key to
+//! sign with, while mutations are rare. The `KeyInfoStore` keeps an immutable snapshot of all the
+//! mappings which readers get without taking any lock. Mutations are serialized, persisted by the
+//! `ManageKeyInfo` implementation behind the store and applied on a copy of the snapshot which
+//! atomically replaces it once the writer is done. Pending mappings are only reachable by the
+//! writers, the snapshot only holding the committed ones.
+//!
+//! The store records the time the keys are created, when their first mapping is inserted. The time
+//! and the counter of a key are kept when its mapping is replaced by one which does not set them.
+use super::{Compaction, KeyInfo, KeyTriple, ManageKeyInfo};
+use arc_swap::ArcSwap;
+use derivative::Derivative;
+use parsec_interface::requests::ProviderID;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type KeyInfoMap = HashMap<KeyTriple, KeyInfo>;
+
+// Providers which can store keys in a Key Info Manager.
+const KEY_PROVIDERS: [ProviderID; 3] =
+    [ProviderID::MbedCrypto, ProviderID::Pkcs11, ProviderID::Tpm];
+
+/// Key Info Manager with a lock-free read path
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct KeyInfoStore {
+    #[derivative(Debug = "ignore")]
+    snapshot: ArcSwap<KeyInfoMap>,
+    #[derivative(Debug = "ignore")]
+    manager: Mutex<Box<dyn ManageKeyInfo + Send + Sync>>,
+}
+
+impl KeyInfoStore {
+    /// Creates a store in front of the given Key Info Manager, loading its current mappings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if the mappings could not be read from the Key Info Manager.
+    pub fn new(manager: Box<dyn ManageKeyInfo + Send + Sync>) -> Result<KeyInfoStore, String> {
+        let mut key_infos = HashMap::new();
+        for provider_id in KEY_PROVIDERS.iter() {
+            for key_triple in manager.get_all(*provider_id)? {
+                if let Some(key_info) = manager.get(key_triple)? {
+                    let _ = key_infos.insert(key_triple.clone(), key_info.clone());
+                }
+            }
+        }
+
+        Ok(KeyInfoStore {
+            snapshot: ArcSwap::from_pointee(key_infos),
+            manager: Mutex::new(manager),
+        })
+    }
+
+    /// Gets the current snapshot of the mappings, without waiting for writers.
+    pub fn read(&self) -> KeyInfoSnapshot {
+        KeyInfoSnapshot(self.snapshot.load_full())
+    }
+
+    /// Gets exclusive access to modify the mappings. Other writers wait until the returned guard
+    /// is dropped, at which point the modifications become visible to readers.
+    pub fn write(&self) -> KeyInfoStoreWriteGuard<'_> {
+        // The snapshot is loaded after the lock is taken so that it contains the modifications of
+        // the previous writer.
+        let manager = self.manager.lock().expect("Key store lock poisoned");
+        KeyInfoStoreWriteGuard {
+            snapshot: &self.snapshot,
+            manager,
+            key_infos: self.snapshot.load_full(),
+            is_modified: false,
+        }
+    }
+
+    /// Compacts the Key Info Manager behind the store, writers waiting until it is done. The
+    /// mappings, and hence the snapshot, are not modified.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if the Key Info Manager could not be compacted.
+    pub fn compact(&self) -> Result<Compaction, String> {
+        self.manager
+            .lock()
+            .expect("Key store lock poisoned")
+            .compact()
+    }
+}
+
+/// Immutable view of the mappings at a point in time
+///
+/// Trying to modify the mappings through it fails.
+#[derive(Debug)]
+pub struct KeyInfoSnapshot(Arc<KeyInfoMap>);
+
+fn get_all(key_infos: &KeyInfoMap, provider_id: ProviderID) -> Vec<&KeyTriple> {
+    key_infos
+        .keys()
+        .filter(|key_triple| key_triple.belongs_to_provider(provider_id))
+        .collect()
+}
+
+impl ManageKeyInfo for KeyInfoSnapshot {
+    fn get(&self, key_triple: &KeyTriple) -> Result<Option<&KeyInfo>, String> {
+        Ok(self.0.get(key_triple))
+    }
+
+    fn get_all(&self, provider_id: ProviderID) -> Result<Vec<&KeyTriple>, String> {
+        Ok(get_all(&self.0, provider_id))
+    }
+
+    fn insert(
+        &mut self,
+        _key_triple: KeyTriple,
+        _key_info: KeyInfo,
+    ) -> Result<Option<KeyInfo>, String> {
+        Err(String::from(
+            "a Key Info Manager snapshot can not be modified",
+        ))
+    }
+
+    fn remove(&mut self, _key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        Err(String::from(
+            "a Key Info Manager snapshot can not be modified",
+        ))
+    }
+
+    fn exists(&self, key_triple: &KeyTriple) -> Result<bool, String> {
+        Ok(self.0.contains_key(key_triple))
+    }
+
+    fn insert_pending(&mut self, _key_triple: KeyTriple, _key_info: KeyInfo) -> Result<(), String> {
+        Err(String::from(
+            "a Key Info Manager snapshot can not be modified",
+        ))
+    }
+
+    fn commit(&mut self, _key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        Err(String::from(
+            "a Key Info Manager snapshot can not be modified",
+        ))
+    }
+
+    fn abort(&mut self, _key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        Err(String::from(
+            "a Key Info Manager snapshot can not be modified",
+        ))
+    }
+
+    fn get_all_pending(
+        &self,
+        _provider_id: ProviderID,
+    ) -> Result<Vec<(&KeyTriple, &KeyInfo)>, String> {
+        Ok(Vec::new())
+    }
+}
+
+/// Exclusive access to modify the mappings
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct KeyInfoStoreWriteGuard<'a> {
+    #[derivative(Debug = "ignore")]
+    snapshot: &'a ArcSwap<KeyInfoMap>,
+    #[derivative(Debug = "ignore")]
+    manager: MutexGuard<'a, Box<dyn ManageKeyInfo + Send + Sync>>,
+    // Copied from the snapshot on the first modification only.
+    key_infos: Arc<KeyInfoMap>,
+    is_modified: bool,
+}
+
+impl KeyInfoStoreWriteGuard<'_> {
+    // Records the creation time of a new key, an existing key keeping its own, as well as its
+    // counter unless a new value is given.
+    fn with_kept_fields(&self, key_triple: &KeyTriple, mut key_info: KeyInfo) -> KeyInfo {
+        if key_info.counter.is_none() {
+            key_info.counter = self
+                .key_infos
+                .get(key_triple)
+                .and_then(|previous| previous.counter);
+        }
+        if key_info.created_at.is_none() {
+            key_info.created_at = match self.key_infos.get(key_triple) {
+                Some(previous) => previous.created_at,
+                None => SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|since_epoch| since_epoch.as_secs()),
+            };
+        }
+
+        key_info
+    }
+}
+
+impl ManageKeyInfo for KeyInfoStoreWriteGuard<'_> {
+    fn get(&self, key_triple: &KeyTriple) -> Result<Option<&KeyInfo>, String> {
+        Ok(self.key_infos.get(key_triple))
+    }
+
+    fn get_all(&self, provider_id: ProviderID) -> Result<Vec<&KeyTriple>, String> {
+        Ok(get_all(&self.key_infos, provider_id))
+    }
+
+    fn insert(
+        &mut self,
+        key_triple: KeyTriple,
+        key_info: KeyInfo,
+    ) -> Result<Option<KeyInfo>, String> {
+        let key_info = self.with_kept_fields(&key_triple, key_info);
+        let previous = self.manager.insert(key_triple.clone(), key_info.clone())?;
+        let _ = Arc::make_mut(&mut self.key_infos).insert(key_triple, key_info);
+        self.is_modified = true;
+
+        Ok(previous)
+    }
+
+    fn remove(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        let removed = self.manager.remove(key_triple)?;
+        let _ = Arc::make_mut(&mut self.key_infos).remove(key_triple);
+        self.is_modified = true;
+
+        Ok(removed)
+    }
+
+    fn exists(&self, key_triple: &KeyTriple) -> Result<bool, String> {
+        Ok(self.key_infos.contains_key(key_triple))
+    }
+
+    fn insert_pending(&mut self, key_triple: KeyTriple, key_info: KeyInfo) -> Result<(), String> {
+        let key_info = self.with_kept_fields(&key_triple, key_info);
+        self.manager.insert_pending(key_triple, key_info)
+    }
+
+    fn commit(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        let previous = self.manager.commit(key_triple)?;
+        if let Some(key_info) = self.manager.get(key_triple)?.cloned() {
+            let _ = Arc::make_mut(&mut self.key_infos).insert(key_triple.clone(), key_info);
+            self.is_modified = true;
+        }
+
+        Ok(previous)
+    }
+
+    fn abort(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        self.manager.abort(key_triple)
+    }
+
+    fn get_all_pending(
+        &self,
+        provider_id: ProviderID,
+    ) -> Result<Vec<(&KeyTriple, &KeyInfo)>, String> {
+        self.manager.get_all_pending(provider_id)
+    }
+}
+
+impl Drop for KeyInfoStoreWriteGuard<'_> {
+    fn drop(&mut self) {
+        if self.is_modified {
+            self.snapshot.store(self.key_infos.clone());
+        }
+    }
+}
+
+#[cfg(all(test, feature = "on-disk-manager"))]
+mod test {
+    use super::KeyInfoStore;
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::on_disk_manager::OnDiskKeyInfoManagerBuilder;
+    use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+    use parsec_interface::operations::psa_algorithm::{
+        Algorithm, AsymmetricSignature, Hash, SignHash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::ProviderID;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    fn test_key_info() -> KeyInfo {
+        KeyInfo {
+            id: vec![0x11, 0x22, 0x33],
+            attributes: Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::RsaKeyPair,
+                bits: 2048,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: true,
+                        verify_hash: false,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: false,
+                        decrypt: false,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::AsymmetricSignature(
+                        AsymmetricSignature::RsaPkcs1v15Sign {
+                            hash_alg: SignHash::Specific(Hash::Sha256),
+                        },
+                    ),
+                },
+            },
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn snapshot_updated_when_writer_done() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/key_info_store_mappings");
+        let manager = OnDiskKeyInfoManagerBuilder::new()
+            .with_mappings_dir_path(path)
+            .build()
+            .unwrap();
+        let store = KeyInfoStore::new(Box::new(manager)).unwrap();
+        let key_triple = KeyTriple::new(
+            ApplicationName::new(String::from("app")),
+            ProviderID::MbedCrypto,
+            String::from("snapshot_updated_when_writer_done"),
+        );
+
+        let snapshot = store.read();
+        {
+            let mut writer = store.write();
+            assert!(writer
+                .insert(key_triple.clone(), test_key_info())
+                .unwrap()
+                .is_none());
+            assert!(writer.exists(&key_triple).unwrap());
+            assert!(!store.read().exists(&key_triple).unwrap());
+        }
+        assert!(!snapshot.exists(&key_triple).unwrap());
+        let stored = store.read().get(&key_triple).unwrap().cloned().unwrap();
+        assert!(stored.created_at.is_some());
+        assert_eq!(
+            KeyInfo {
+                created_at: None,
+                ..stored
+            },
+            test_key_info()
+        );
+
+        let _ = store.write().remove(&key_triple).unwrap();
+        assert!(!store.read().exists(&key_triple).unwrap());
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! A key info manager keeping the key triple to key info mapping in memory only
+//!
+//! The mappings are lost when the service stops or reloads its configuration, so this manager is
+//! meant for test environments and for providers whose keys do not survive a restart anyway, for
+//! example a software provider whose storage is volatile. It never writes to the filesystem, which
+//! can then be read-only.
+use super::{KeyInfo, KeyTriple, ManageKeyInfo};
+use parsec_interface::requests::ProviderID;
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct MemoryKeyInfoManager {
+    key_store: HashMap<KeyTriple, KeyInfo>,
+    pending: HashMap<KeyTriple, KeyInfo>,
+}
+
+impl MemoryKeyInfoManager {
+    /// Creates a manager without any mapping.
+    pub fn new() -> MemoryKeyInfoManager {
+        MemoryKeyInfoManager {
+            key_store: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl ManageKeyInfo for MemoryKeyInfoManager {
+    fn get(&self, key_triple: &KeyTriple) -> Result<Option<&KeyInfo>, String> {
+        Ok(self.key_store.get(key_triple))
+    }
+
+    fn get_all(&self, provider_id: ProviderID) -> Result<Vec<&KeyTriple>, String> {
+        Ok(self
+            .key_store
+            .keys()
+            .filter(|key_triple| key_triple.belongs_to_provider(provider_id))
+            .collect())
+    }
+
+    fn insert(
+        &mut self,
+        key_triple: KeyTriple,
+        key_info: KeyInfo,
+    ) -> Result<Option<KeyInfo>, String> {
+        Ok(self.key_store.insert(key_triple, key_info))
+    }
+
+    fn remove(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        Ok(self.key_store.remove(key_triple))
+    }
+
+    fn exists(&self, key_triple: &KeyTriple) -> Result<bool, String> {
+        Ok(self.key_store.contains_key(key_triple))
+    }
+
+    fn insert_pending(&mut self, key_triple: KeyTriple, key_info: KeyInfo) -> Result<(), String> {
+        if self.pending.contains_key(&key_triple) {
+            return Err(String::from("a mapping of the key is already pending"));
+        }
+        let _ = self.pending.insert(key_triple, key_info);
+
+        Ok(())
+    }
+
+    fn commit(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        let key_info = self
+            .pending
+            .remove(key_triple)
+            .ok_or_else(|| String::from("no mapping of the key is pending"))?;
+
+        Ok(self.key_store.insert(key_triple.clone(), key_info))
+    }
+
+    fn abort(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        Ok(self.pending.remove(key_triple))
+    }
+
+    fn get_all_pending(
+        &self,
+        provider_id: ProviderID,
+    ) -> Result<Vec<(&KeyTriple, &KeyInfo)>, String> {
+        Ok(self
+            .pending
+            .iter()
+            .filter(|(key_triple, _)| key_triple.belongs_to_provider(provider_id))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+    use super::MemoryKeyInfoManager;
+    use crate::authenticators::ApplicationName;
+    use parsec_interface::operations::psa_algorithm::{Algorithm, Hash};
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::ProviderID;
+    use std::collections::BTreeMap;
+
+    fn test_key_info(id: Vec<u8>) -> KeyInfo {
+        KeyInfo {
+            id,
+            attributes: Attributes {
+                lifetime: Lifetime::Volatile,
+                key_type: Type::Hmac,
+                bits: 256,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: true,
+                        verify_hash: true,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: false,
+                        decrypt: false,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::Hash(Hash::Sha256),
+                },
+            },
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn mappings_kept_in_memory() {
+        let mut manager = MemoryKeyInfoManager::new();
+        let key_triple = KeyTriple::new(
+            ApplicationName::new("app".to_string()),
+            ProviderID::MbedCrypto,
+            "key".to_string(),
+        );
+
+        assert!(!manager.exists(&key_triple).unwrap());
+        assert!(manager
+            .insert(key_triple.clone(), test_key_info(vec![1]))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            manager
+                .insert(key_triple.clone(), test_key_info(vec![2]))
+                .unwrap(),
+            Some(test_key_info(vec![1]))
+        );
+        assert_eq!(
+            manager.get(&key_triple).unwrap(),
+            Some(&test_key_info(vec![2]))
+        );
+        assert_eq!(manager.get_all(ProviderID::MbedCrypto).unwrap().len(), 1);
+        assert!(manager.get_all(ProviderID::Tpm).unwrap().is_empty());
+        assert!(manager.remove(&key_triple).unwrap().is_some());
+        assert_eq!(manager.remove(&key_triple).unwrap(), None);
+    }
+
+    #[test]
+    fn pending_mappings_hidden_until_committed() {
+        let mut manager = MemoryKeyInfoManager::new();
+        let key_triple = KeyTriple::new(
+            ApplicationName::new("app".to_string()),
+            ProviderID::MbedCrypto,
+            "key".to_string(),
+        );
+
+        manager
+            .insert_pending(key_triple.clone(), test_key_info(vec![1]))
+            .unwrap();
+        assert!(manager
+            .insert_pending(key_triple.clone(), test_key_info(vec![2]))
+            .is_err());
+        assert!(!manager.exists(&key_triple).unwrap());
+        assert_eq!(
+            manager.get_all_pending(ProviderID::MbedCrypto).unwrap(),
+            vec![(&key_triple, &test_key_info(vec![1]))]
+        );
+        assert!(manager.commit(&key_triple).unwrap().is_none());
+        assert!(manager.commit(&key_triple).is_err());
+        assert_eq!(
+            manager.get(&key_triple).unwrap(),
+            Some(&test_key_info(vec![1]))
+        );
+
+        manager
+            .insert_pending(key_triple.clone(), test_key_info(vec![2]))
+            .unwrap();
+        assert_eq!(
+            manager.abort(&key_triple).unwrap(),
+            Some(test_key_info(vec![2]))
+        );
+        assert!(manager
+            .get_all_pending(ProviderID::MbedCrypto)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            manager.get(&key_triple).unwrap(),
+            Some(&test_key_info(vec![1]))
+        );
+    }
+}
@@ -5,26 +5,46 @@
 //! This module declares a [`ManageKeyInfo`](https://parallaxsecond.github.io/parsec-book/parsec_service/key_info_managers.html)
 //! trait to help providers to store in a persistent manner the mapping between the name and the
 //! information of the keys they manage. Different implementors might store this mapping using different
-//! means but it has to be persistent.
+//! means but it has to be persistent: the `on_disk_manager` uses a file per mapping and the
+//! `sqlite_manager` an SQLite database. The `memory_manager` is the exception, for keys which do
+//! not need to outlive the service. Each of them is compiled with its own feature, named after it,
+//! the `sqlite-manager` being the only one not compiled by default.
 
 use crate::authenticators::ApplicationName;
 use parsec_interface::operations::psa_key_attributes::Attributes;
 use parsec_interface::requests::{ProviderID, ResponseStatus};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
 
+#[cfg(any(
+    feature = "on-disk-manager",
+    feature = "sqlite-manager",
+    feature = "key-manifest"
+))]
+pub mod key_info_encoding;
+pub mod key_info_store;
+#[cfg(feature = "memory-manager")]
+pub mod memory_manager;
+#[cfg(feature = "on-disk-manager")]
 pub mod on_disk_manager;
+#[cfg(feature = "sqlite-manager")]
+pub mod sqlite_manager;
 
-#[derive(Copy, Clone, Deserialize, Debug)]
+#[derive(Copy, Clone, Deserialize, Debug, PartialEq)]
 pub enum KeyInfoManagerType {
     OnDisk,
+    Sqlite,
+    Memory,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug, PartialEq)]
 pub struct KeyInfoManagerConfig {
     pub name: String,
     pub manager_type: KeyInfoManagerType,
     pub store_path: Option<String>,
+    pub integrity_key: Option<String>,
+    pub create_integrity_tag: Option<bool>,
 }
 
 /// This structure corresponds to a unique identifier of the key. It is used internally by the Key
@@ -53,6 +73,79 @@ pub struct KeyInfo {
     pub id: Vec<u8>,
     /// Attributes of a key
     pub attributes: Attributes,
+    /// State of the key in its lifecycle, not part of the bincode serialization of older versions
+    #[serde(skip)]
+    pub state: KeyState,
+    /// Time the key was created, in seconds since the Unix epoch, if it was recorded. It is set by
+    /// the `KeyInfoStore` when the mapping of a new key is inserted.
+    #[serde(skip)]
+    pub created_at: Option<u64>,
+    /// Value of the monotonic counter bound to the key, if one was created for it, see the
+    /// `key_counters` module of the back end.
+    #[serde(skip)]
+    pub counter: Option<u64>,
+    /// DER-encoded X.509 certificate of the key, for the providers which can not store it
+    /// themselves, see the `key_certificates` module of the back end.
+    #[serde(skip)]
+    pub certificate: Option<Vec<u8>>,
+    /// Labels given to the key by its application, see the `key_labels` module of the back end.
+    #[serde(skip)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// State of a key in its lifecycle
+///
+/// Keys are active, usable for cryptographic operations, unless they were created pre-active. A
+/// pre-active key can only be exported, if its policy allows it, or destroyed until it is
+/// activated, see the `key_activation` module of the back end.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum KeyState {
+    Active,
+    PreActive,
+}
+
+impl Default for KeyState {
+    fn default() -> Self {
+        KeyState::Active
+    }
+}
+
+/// Result of the reconciliation of the mappings of a provider with the keys it holds
+///
+/// Providers able to check that the keys exist do so for all their mappings when they start. A
+/// mapping whose key is missing is orphaned: it is removed unless the provider is configured to
+/// only report it, for example when its keys live on a token which may be unplugged. The pending
+/// mappings of the keys whose creation was interrupted are rolled back at the same time.
+#[derive(Copy, Clone, Serialize, Debug, Default, PartialEq)]
+pub struct MappingHealth {
+    /// Number of mappings checked
+    pub checked: usize,
+    /// Number of mappings whose key could not be found in the provider
+    pub orphaned: usize,
+    /// Number of orphaned mappings removed
+    pub purged: usize,
+    /// Number of pending mappings rolled back
+    pub rolled_back: usize,
+}
+
+/// Result of the compaction of a Key Info Manager
+#[derive(Copy, Clone, Serialize, Debug, Default, PartialEq)]
+pub struct Compaction {
+    /// Number of mappings rewritten
+    pub rewritten: usize,
+    /// Number of stale files and directories removed
+    pub removed: usize,
+    /// Number of bytes reclaimed on the storage
+    pub reclaimed_bytes: u64,
+}
+
+impl Compaction {
+    /// Adds the result of the compaction of another Key Info Manager.
+    pub fn add(&mut self, other: Compaction) {
+        self.rewritten += other.rewritten;
+        self.removed += other.removed;
+        self.reclaimed_bytes += other.reclaimed_bytes;
+    }
 }
 
 impl KeyTriple {
@@ -65,6 +158,21 @@ impl KeyTriple {
         }
     }
 
+    /// Gets the name of the application owning the key.
+    pub fn app_name(&self) -> &ApplicationName {
+        &self.app_name
+    }
+
+    /// Gets the name of the key.
+    pub fn key_name(&self) -> &str {
+        &self.key_name
+    }
+
+    /// Gets the ID of the provider storing the key.
+    pub fn provider_id(&self) -> ProviderID {
+        self.provider_id
+    }
+
     /// Checks if this key belongs to a specific provider.
     pub fn belongs_to_provider(&self, provider_id: ProviderID) -> bool {
         self.provider_id == provider_id
@@ -84,6 +192,13 @@ pub fn to_response_status(error_string: String) -> ResponseStatus {
 /// Management interface for key name to key info mapping
 ///
 /// Interface to be implemented for persistent storage of key name -> key info mappings.
+///
+/// Providers creating a key under an ID they allocate do so in two phases: the mapping is first
+/// inserted as pending with `insert_pending`, the key is created in the provider and the mapping is
+/// then committed, or aborted if the creation failed. Pending mappings are persisted but not
+/// returned by `get`, `get_all` and `exists`, so that a mapping is never visible without its key,
+/// and a creation interrupted by a crash or a power cut leaves a pending mapping from which the
+/// provider can find and destroy the key when it starts again, instead of leaking its ID.
 pub trait ManageKeyInfo {
     /// Returns a reference to the key info corresponding to this key triple or `None` if it does not
     /// exist.
@@ -126,4 +241,53 @@ pub trait ManageKeyInfo {
     ///
     /// Returns an error as a String if there was a problem accessing the Key Info Manager.
     fn exists(&self, key_triple: &KeyTriple) -> Result<bool, String>;
+
+    /// Inserts the mapping of a key about to be created, which stays pending until it is
+    /// committed or aborted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager or if a
+    /// mapping of the key triple is already pending.
+    fn insert_pending(&mut self, key_triple: KeyTriple, key_info: KeyInfo) -> Result<(), String>;
+
+    /// Makes the pending mapping of a key triple a usual one, once its key was created. If the
+    /// triple already has a mapping, it is overwritten and the old `KeyInfo` is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager or if no
+    /// mapping of the key triple is pending. A mapping which could not be committed is still
+    /// pending: the provider destroys its key and aborts it.
+    fn commit(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String>;
+
+    /// Removes the pending mapping of a key triple and returns it. Does nothing and returns `None`
+    /// if no mapping is pending.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager.
+    fn abort(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String>;
+
+    /// Returns the pending mappings of this provider, left by key creations which were
+    /// interrupted.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager.
+    fn get_all_pending(
+        &self,
+        provider_id: ProviderID,
+    ) -> Result<Vec<(&KeyTriple, &KeyInfo)>, String>;
+
+    /// Rewrites the stored mappings and removes what the previous modifications left behind,
+    /// returning what was reclaimed. The mappings themselves are not modified. Managers which do
+    /// not leave anything behind have nothing to compact.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error as a String if there was a problem accessing the Key Info Manager.
+    fn compact(&mut self) -> Result<Compaction, String> {
+        Ok(Compaction::default())
+    }
 }
@@ -0,0 +1,262 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Integrity tag of the on-disk mappings
+//!
+//! The file permissions protect the mappings against other users, but not against offline
+//! tampering, for example swapping the key IDs of two applications so that one of them uses the
+//! key of the other. When an integrity key is configured, a HMAC-SHA256 tag over all the mappings
+//! is kept in the `integrity_tag` file of the mappings directory, updated on each modification
+//! and checked when the mappings are loaded. The service does not start if the check fails.
+//!
+//! The integrity key is read from an external secret source so that it is not stored next to the
+//! mappings: a systemd credential encrypted with `systemd-creds --with-key=tpm2` has it sealed to
+//! the TPM of the platform. It can not be held by a provider as the key info managers are needed to
+//! build the providers.
+//!
+//! A crash between the modification of a mapping and the update of the tag makes the next check
+//! fail. After verifying the mappings, the tag can then be recreated by starting the service once
+//! with `create_integrity_tag`, which is also how the check is enabled on existing mappings.
+//!
+//! The tag covers the key information in the versioned encoding of the `key_info_encoding` module.
+//! A tag computed over their bincode serialization, by older versions of the service, is still
+//! accepted and replaced.
+use super::super::{key_info_encoding, KeyInfo, KeyTriple};
+use log::{error, warn};
+use ring::hmac;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+
+/// Name of the file, in the mappings directory, containing the tag
+pub const INTEGRITY_TAG_FILE: &str = "integrity_tag";
+/// Minimum length of the integrity key, in bytes
+pub const MIN_INTEGRITY_KEY_LEN: usize = 32;
+
+/// Key used to compute the integrity tag of the mappings
+#[derive(Debug)]
+pub struct IntegrityTag {
+    key: hmac::Key,
+    create_if_missing: bool,
+}
+
+impl IntegrityTag {
+    /// Creates the integrity tag computation from its key. If `create_if_missing` is set, a missing
+    /// tag is created from the mappings instead of failing the check.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the key is shorter than `MIN_INTEGRITY_KEY_LEN`.
+    pub fn new(key: &[u8], create_if_missing: bool) -> Result<IntegrityTag> {
+        if key.len() < MIN_INTEGRITY_KEY_LEN {
+            error!(
+                "The integrity key of the mappings must be at least {} bytes long.",
+                MIN_INTEGRITY_KEY_LEN
+            );
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "integrity key is too short",
+            ));
+        }
+
+        Ok(IntegrityTag {
+            key: hmac::Key::new(hmac::HMAC_SHA256, key),
+            create_if_missing,
+        })
+    }
+
+    /// Computes the tag of the mappings, with the key information in the versioned encoding or,
+    /// for tags of older versions, serialized with bincode. The mappings are sorted so that the tag
+    /// does not depend on the order in which they were read.
+    fn compute(
+        &self,
+        key_store: &HashMap<KeyTriple, KeyInfo>,
+        with_bincode: bool,
+    ) -> Result<hmac::Tag> {
+        let mut mappings: Vec<(&KeyTriple, &KeyInfo)> = key_store.iter().collect();
+        mappings.sort_by(|(left, _), (right, _)| {
+            (
+                left.app_name().get_name(),
+                left.provider_id() as u8,
+                left.key_name(),
+            )
+                .cmp(&(
+                    right.app_name().get_name(),
+                    right.provider_id() as u8,
+                    right.key_name(),
+                ))
+        });
+
+        let mut context = hmac::Context::with_key(&self.key);
+        for (key_triple, key_info) in mappings {
+            let key_info = if with_bincode {
+                bincode::serialize(key_info).map_err(|e| e.to_string())
+            } else {
+                key_info_encoding::encode(key_info)
+            }
+            .map_err(|e| {
+                format_error!("Error serializing key info", e);
+                Error::new(ErrorKind::Other, "error serializing key info")
+            })?;
+            let provider_id = [key_triple.provider_id() as u8];
+            let fields: [&[u8]; 4] = [
+                key_triple.app_name().get_name().as_bytes(),
+                &provider_id,
+                key_triple.key_name().as_bytes(),
+                &key_info,
+            ];
+            // Each field is prefixed by its length so that no two sets of mappings are encoded the
+            // same way.
+            for field in fields.iter() {
+                context.update(&(field.len() as u64).to_be_bytes());
+                context.update(field);
+            }
+        }
+
+        Ok(context.sign())
+    }
+
+    /// Checks the tag stored in the mappings directory against the mappings loaded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the tag does not match the mappings, or if it is
+    /// missing while there are mappings and it should not be created.
+    pub fn check(
+        &self,
+        mappings_dir_path: &Path,
+        key_store: &HashMap<KeyTriple, KeyInfo>,
+    ) -> Result<()> {
+        let tag_path = mappings_dir_path.join(INTEGRITY_TAG_FILE);
+        if !tag_path.exists() {
+            if key_store.is_empty() || self.create_if_missing {
+                warn!("Creating the integrity tag of the mappings.");
+                return self.update(mappings_dir_path, key_store);
+            }
+            error!("The integrity tag of the mappings is missing.");
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "mappings integrity tag is missing",
+            ));
+        }
+
+        let stored_tag = hex::decode(fs::read_to_string(&tag_path)?.trim()).or_else(|e| {
+            format_error!("Failed to decode the integrity tag of the mappings", e);
+            Err(Error::new(ErrorKind::InvalidData, "invalid integrity tag"))
+        })?;
+        if self.create_if_missing {
+            warn!("The integrity tag of the mappings is not recreated as it exists.");
+        }
+        let matches = |with_bincode| -> Result<bool> {
+            let tag = self.compute(key_store, with_bincode)?;
+            Ok(ring::constant_time::verify_slices_are_equal(tag.as_ref(), &stored_tag).is_ok())
+        };
+        if matches(false)? {
+            Ok(())
+        } else if matches(true)? {
+            warn!("Replacing the integrity tag of the mappings computed by an older version.");
+            self.update(mappings_dir_path, key_store)
+        } else {
+            error!("The mappings do not match their integrity tag, they might have been tampered with.");
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                "mappings integrity check failed",
+            ))
+        }
+    }
+
+    /// Writes the tag of the mappings in the mappings directory.
+    pub fn update(
+        &self,
+        mappings_dir_path: &Path,
+        key_store: &HashMap<KeyTriple, KeyInfo>,
+    ) -> Result<()> {
+        let tag = hex::encode(self.compute(key_store, false)?.as_ref());
+        // The tag is replaced atomically so that it can not be left half written.
+        let tag_path = mappings_dir_path.join(INTEGRITY_TAG_FILE);
+        let temporary_path = mappings_dir_path.join(format!("{}.new", INTEGRITY_TAG_FILE));
+        let mut tag_file = fs::File::create(&temporary_path)?;
+        tag_file.write_all(tag.as_bytes())?;
+        tag_file.sync_all()?;
+        fs::rename(temporary_path, tag_path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IntegrityTag, INTEGRITY_TAG_FILE};
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple};
+    use parsec_interface::operations::psa_algorithm::{Algorithm, Hash};
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::ProviderID;
+    use std::collections::BTreeMap;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn key_info(id: u8) -> KeyInfo {
+        KeyInfo {
+            id: vec![id],
+            attributes: Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::Derive,
+                bits: 0,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: false,
+                        verify_hash: false,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: false,
+                        decrypt: false,
+                        cache: false,
+                        copy: false,
+                        derive: true,
+                    },
+                    permitted_algorithms: Algorithm::Hash(Hash::Sha256),
+                },
+            },
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn swapped_ids_detected() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/integrity_tag_mappings");
+        fs::create_dir_all(&path).unwrap();
+        let _ = fs::remove_file(path.join(INTEGRITY_TAG_FILE));
+        let alice = KeyTriple::new(
+            ApplicationName::new(String::from("alice")),
+            ProviderID::Tpm,
+            String::from("key"),
+        );
+        let bob = KeyTriple::new(
+            ApplicationName::new(String::from("bob")),
+            ProviderID::Tpm,
+            String::from("key"),
+        );
+        let mut key_store = HashMap::new();
+        let _ = key_store.insert(alice.clone(), key_info(1));
+        let _ = key_store.insert(bob.clone(), key_info(2));
+
+        let integrity_tag = IntegrityTag::new(&[0x42; 32], false).unwrap();
+        assert!(integrity_tag.check(&path, &key_store).is_err());
+        integrity_tag.update(&path, &key_store).unwrap();
+        integrity_tag.check(&path, &key_store).unwrap();
+
+        let _ = key_store.insert(alice, key_info(2));
+        let _ = key_store.insert(bob, key_info(1));
+        assert!(integrity_tag.check(&path, &key_store).is_err());
+
+        assert!(IntegrityTag::new(&[0x42; 16], false).is_err());
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -12,27 +12,54 @@
 //! example, for operating systems having a limit of 255 characters for filenames (Unix systems),
 //! names will be limited to 188 bytes of UTF-8 characters.
 //! For security reasons, only the PARSEC service should have the ability to modify these files.
-use super::{KeyInfo, KeyTriple, ManageKeyInfo};
+//! Their integrity can also be checked at startup with a tag, see the `integrity_tag` module.
+//! The key information is stored with the versioned encoding of the `key_info_encoding` module,
+//! the files written with bincode by older versions of the service still being read.
+//! The mapping of a key being created is written in a file with the `.pending` extension next to
+//! the mapping files, which is renamed when the mapping is committed. The dot not being part of the
+//! base64 alphabet used, such a file can not be mistaken for a mapping. The integrity tag only
+//! covers the committed mappings.
+//! The mapping of a key with a counter is written in a file with the `.update` extension, synced
+//! and renamed over the previous one, so that a crash leaves either the previous value of the
+//! counter or the new one. An update file left by a crash is removed at the next start.
+//! Removing mappings leaves the directories of their application and provider behind, which are
+//! only removed when the mappings are compacted. Compaction also rewrites all the mappings, so that
+//! those stored with bincode are converted to the versioned encoding.
+use super::{key_info_encoding, Compaction, KeyInfo, KeyTriple, ManageKeyInfo};
 use crate::authenticators::ApplicationName;
 use log::{error, info, warn};
 use parsec_interface::requests::ProviderID;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::ffi::OsStr;
 use std::fs;
 use std::fs::{DirEntry, File};
 use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::fs::MetadataExt;
 use std::path::PathBuf;
 
+pub mod integrity_tag;
+
+pub use integrity_tag::IntegrityTag;
+
 pub const DEFAULT_MAPPINGS_PATH: &str = "./mappings";
 
+/// Extension of the files of the pending mappings
+const PENDING_EXTENSION: &str = "pending";
+/// Extension of the files of the mappings being replaced
+const UPDATE_EXTENSION: &str = "update";
+
 #[derive(Debug)]
 pub struct OnDiskKeyInfoManager {
     /// Internal mapping, used for non-modifying operations.
     key_store: HashMap<KeyTriple, KeyInfo>,
+    /// Mappings of the keys being created, not committed yet.
+    pending: HashMap<KeyTriple, KeyInfo>,
     /// Folder where all the key triple to key info mappings are saved. This folder will be created
     /// if it does already exist.
     mappings_dir_path: PathBuf,
+    /// Integrity tag of the mappings, kept up to date if set.
+    integrity_tag: Option<IntegrityTag>,
 }
 
 /// Encodes a KeyTriple's data into base64 strings that can be used as filenames.
@@ -46,6 +73,12 @@ fn key_triple_to_base64_filenames(key_triple: &KeyTriple) -> (String, String, St
     )
 }
 
+/// Gets the path of the file of a mapping, relative to the mappings directory.
+pub fn mapping_path(key_triple: &KeyTriple) -> PathBuf {
+    let (app_name, prov, key_name) = key_triple_to_base64_filenames(key_triple);
+    [app_name, prov, key_name].iter().collect()
+}
+
 /// Decodes base64 bytes to its original String value.
 ///
 /// # Errors
@@ -135,6 +168,22 @@ fn list_dirs(path: &PathBuf) -> std::io::Result<Vec<PathBuf>> {
         .collect())
 }
 
+/// Gets the space used on disk by the given directory and all it contains, in bytes.
+fn disk_usage(path: &PathBuf) -> std::io::Result<u64> {
+    // The blocks are counted in units of 512 bytes.
+    let mut usage = fs::metadata(path)?.blocks() * 512;
+    for dir_entry in path.read_dir()? {
+        let entry_path = dir_entry?.path();
+        usage += if entry_path.is_dir() {
+            disk_usage(&entry_path)?
+        } else {
+            fs::metadata(&entry_path)?.blocks() * 512
+        };
+    }
+
+    Ok(usage)
+}
+
 /// Lists all the file paths in the given directory path.
 fn list_files(path: &PathBuf) -> std::io::Result<Vec<PathBuf>> {
     let dir_entries: std::io::Result<Vec<DirEntry>> = path.read_dir()?.collect();
@@ -175,11 +224,18 @@ impl OnDiskKeyInfoManager {
     /// Each mapping is contained in its own file to prevent the modification of one mapping
     /// impacting the other ones.
     ///
+    /// If an integrity tag is given, the mappings read are checked against it.
+    ///
     /// # Errors
     ///
-    /// Returns an std::io error if the function failed reading the mapping files.
-    fn new(mappings_dir_path: PathBuf) -> std::io::Result<OnDiskKeyInfoManager> {
+    /// Returns an std::io error if the function failed reading the mapping files, or an error of
+    /// kind `InvalidData` if they do not pass the integrity check.
+    fn new(
+        mappings_dir_path: PathBuf,
+        integrity_tag: Option<IntegrityTag>,
+    ) -> std::io::Result<OnDiskKeyInfoManager> {
         let mut key_store = HashMap::new();
+        let mut pending = HashMap::new();
 
         // Will ignore if the mappings directory already exists.
         fs::create_dir_all(&mappings_dir_path)?;
@@ -187,13 +243,26 @@ impl OnDiskKeyInfoManager {
         for app_name_dir_path in list_dirs(&mappings_dir_path)?.iter() {
             for provider_dir_path in list_dirs(&app_name_dir_path)?.iter() {
                 for key_name_file_path in list_files(&provider_dir_path)?.iter() {
+                    if key_name_file_path.extension() == Some(OsStr::new(UPDATE_EXTENSION)) {
+                        // The previous mapping is still in place.
+                        warn!("Removing a mapping update interrupted by a crash");
+                        fs::remove_file(&key_name_file_path)?;
+                        continue;
+                    }
                     let mut key_info = Vec::new();
                     let mut key_info_file = File::open(&key_name_file_path)?;
                     let _ = key_info_file.read_to_end(&mut key_info)?;
-                    let key_info = bincode::deserialize(&key_info[..]).or_else(|e| {
+                    let key_info = key_info_encoding::decode(&key_info[..]).or_else(|e| {
                         format_error!("Error deserializing key info", e);
                         Err(Error::new(ErrorKind::Other, "error deserializing key info"))
                     })?;
+                    let is_pending =
+                        key_name_file_path.extension() == Some(OsStr::new(PENDING_EXTENSION));
+                    let key_name_file_path = if is_pending {
+                        key_name_file_path.with_extension("")
+                    } else {
+                        key_name_file_path.clone()
+                    };
                     match base64_data_triple_to_key_triple(
                         os_str_to_u8_ref(app_name_dir_path.file_name().expect(
                             "The application name directory path should contain a final component.",
@@ -205,6 +274,9 @@ impl OnDiskKeyInfoManager {
                             "The key name directory path should contain a final component.",
                         ))?,
                     ) {
+                        Ok(key_triple) if is_pending => {
+                            let _ = pending.insert(key_triple, key_info);
+                        }
                         Ok(key_triple) => {
                             if crate::utils::GlobalConfig::log_error_details() {
                                 warn!(
@@ -228,56 +300,167 @@ impl OnDiskKeyInfoManager {
         if !crate::utils::GlobalConfig::log_error_details() {
             info!("Found {} mapping files", key_store.len());
         }
+        if !pending.is_empty() {
+            warn!(
+                "Found {} mappings of keys whose creation was interrupted",
+                pending.len()
+            );
+        }
+
+        if let Some(integrity_tag) = &integrity_tag {
+            integrity_tag.check(&mappings_dir_path, &key_store)?;
+            info!("The mappings passed the integrity check.");
+        }
 
         Ok(OnDiskKeyInfoManager {
             key_store,
+            pending,
             mappings_dir_path,
+            integrity_tag,
         })
     }
 
+    /// Gets the path of the mapping file and, if the mapping is pending, of its pending file.
+    fn mapping_file_path(&self, key_triple: &KeyTriple, pending: bool) -> PathBuf {
+        let key_name_file_path = self.mappings_dir_path.join(mapping_path(key_triple));
+        if pending {
+            key_name_file_path.with_extension(PENDING_EXTENSION)
+        } else {
+            key_name_file_path
+        }
+    }
+
     /// Saves the key triple to key info mapping in its own file.
     /// The filename will be `mappings/[APP_NAME]/[PROVIDER_NAME]/[KEY_NAME]` under the same path as the
-    /// on-disk manager. It will contain the Key info data.
-    fn save_mapping(&self, key_triple: &KeyTriple, key_info: &KeyInfo) -> std::io::Result<()> {
+    /// on-disk manager, with the `.pending` extension if the mapping is pending. It will contain
+    /// the Key info data.
+    fn save_mapping(
+        &self,
+        key_triple: &KeyTriple,
+        key_info: &KeyInfo,
+        pending: bool,
+    ) -> std::io::Result<()> {
         if crate::utils::GlobalConfig::log_error_details() {
             warn!(
                 "Saving Key Triple ({}) mapping to disk.",
                 key_triple.clone()
             );
         }
+        if !pending && key_info.counter.is_some() {
+            // The mapping of a key with a counter must never be lost nor revert to an older value.
+            return self.replace_mapping(key_triple, key_info);
+        }
         // Create the directories with base64 names.
-        let (app_name, prov, key_name) = key_triple_to_base64_filenames(key_triple);
-        let provider_dir_path = self.mappings_dir_path.join(app_name).join(prov);
-        let key_name_file_path = provider_dir_path.join(key_name);
+        let key_name_file_path = self.mapping_file_path(key_triple, pending);
         // Will ignore if they already exist.
-        fs::create_dir_all(&provider_dir_path)?;
+        fs::create_dir_all(
+            key_name_file_path
+                .parent()
+                .expect("The mapping file path should have a parent directory."),
+        )?;
 
         if key_name_file_path.exists() {
             fs::remove_file(&key_name_file_path)?;
         }
 
         let mut mapping_file = fs::File::create(&key_name_file_path)?;
-        mapping_file.write_all(&bincode::serialize(key_info).or_else(|e| {
+        mapping_file.write_all(&key_info_encoding::encode(key_info).or_else(|e| {
             format_error!("Error serializing key info", e);
             Err(Error::new(ErrorKind::Other, "error serializing key info"))
-        })?)
+        })?)?;
+        if pending {
+            // The pending mapping must be on disk before the key is created.
+            mapping_file.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// Saves the mapping in its update file, which is synced and renamed over the mapping file so
+    /// that the previous mapping is replaced atomically.
+    fn replace_mapping(&self, key_triple: &KeyTriple, key_info: &KeyInfo) -> std::io::Result<()> {
+        let key_name_file_path = self.mapping_file_path(key_triple, false);
+        let update_file_path = key_name_file_path.with_extension(UPDATE_EXTENSION);
+        // Will ignore if they already exist.
+        fs::create_dir_all(
+            key_name_file_path
+                .parent()
+                .expect("The mapping file path should have a parent directory."),
+        )?;
+
+        let mut update_file = fs::File::create(&update_file_path)?;
+        update_file.write_all(&key_info_encoding::encode(key_info).or_else(|e| {
+            format_error!("Error serializing key info", e);
+            Err(Error::new(ErrorKind::Other, "error serializing key info"))
+        })?)?;
+        update_file.sync_all()?;
+        fs::rename(&update_file_path, &key_name_file_path)
+    }
+
+    /// Rewrites all the mappings in the current encoding and removes the files and directories
+    /// which are not part of any mapping, such as the directories of the applications whose keys
+    /// were all removed.
+    fn compact_mappings(&self) -> std::io::Result<Compaction> {
+        let usage_before = disk_usage(&self.mappings_dir_path)?;
+        let mut compaction = Compaction::default();
+        let mut mapping_files = HashSet::new();
+        for (key_triple, key_info) in &self.key_store {
+            self.replace_mapping(key_triple, key_info)?;
+            compaction.rewritten += 1;
+            let _ = mapping_files.insert(self.mapping_file_path(key_triple, false));
+        }
+        for key_triple in self.pending.keys() {
+            let _ = mapping_files.insert(self.mapping_file_path(key_triple, true));
+        }
+
+        for app_name_dir_path in list_dirs(&self.mappings_dir_path)?.iter() {
+            for provider_dir_path in list_dirs(app_name_dir_path)?.iter() {
+                for file_path in list_files(provider_dir_path)?.iter() {
+                    if !mapping_files.contains(file_path) {
+                        warn!("Removing a file of the mappings directory which is not a mapping");
+                        fs::remove_file(file_path)?;
+                        compaction.removed += 1;
+                    }
+                }
+                if provider_dir_path.read_dir()?.next().is_none() {
+                    fs::remove_dir(provider_dir_path)?;
+                    compaction.removed += 1;
+                }
+            }
+            if app_name_dir_path.read_dir()?.next().is_none() {
+                fs::remove_dir(app_name_dir_path)?;
+                compaction.removed += 1;
+            }
+        }
+        compaction.reclaimed_bytes =
+            usage_before.saturating_sub(disk_usage(&self.mappings_dir_path)?);
+
+        Ok(compaction)
     }
 
-    /// Removes the mapping file.
+    /// Removes the mapping file, or the pending one.
     /// Will do nothing if the mapping file does not exist.
-    fn delete_mapping(&self, key_triple: &KeyTriple) -> std::io::Result<()> {
-        let (app_name, prov, key_name) = key_triple_to_base64_filenames(key_triple);
-        let key_name_file_path = self
-            .mappings_dir_path
-            .join(app_name)
-            .join(prov)
-            .join(key_name);
+    fn delete_mapping(&self, key_triple: &KeyTriple, pending: bool) -> std::io::Result<()> {
+        let key_name_file_path = self.mapping_file_path(key_triple, pending);
         if key_name_file_path.exists() {
             fs::remove_file(key_name_file_path)
         } else {
             Ok(())
         }
     }
+
+    /// Updates the integrity tag after the mappings were modified, if there is one.
+    fn update_integrity_tag(&self) -> Result<(), String> {
+        match &self.integrity_tag {
+            Some(integrity_tag) => integrity_tag
+                .update(&self.mappings_dir_path, &self.key_store)
+                .or_else(|e| {
+                    format_error!("Failed to update the integrity tag of the mappings", e);
+                    Err(e.to_string())
+                }),
+            None => Ok(()),
+        }
+    }
 }
 
 impl ManageKeyInfo for OnDiskKeyInfoManager {
@@ -304,17 +487,20 @@ impl ManageKeyInfo for OnDiskKeyInfoManager {
         key_triple: KeyTriple,
         key_info: KeyInfo,
     ) -> Result<Option<KeyInfo>, String> {
-        if let Err(err) = self.save_mapping(&key_triple, &key_info) {
+        if let Err(err) = self.save_mapping(&key_triple, &key_info, false) {
             Err(err.to_string())
         } else {
-            Ok(self.key_store.insert(key_triple, key_info))
+            let old_key_info = self.key_store.insert(key_triple, key_info);
+            self.update_integrity_tag()?;
+            Ok(old_key_info)
         }
     }
 
     fn remove(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
-        if let Err(err) = self.delete_mapping(key_triple) {
+        if let Err(err) = self.delete_mapping(key_triple, false) {
             Err(err.to_string())
         } else if let Some(key_info) = self.key_store.remove(key_triple) {
+            self.update_integrity_tag()?;
             Ok(Some(key_info))
         } else {
             Ok(None)
@@ -324,17 +510,91 @@ impl ManageKeyInfo for OnDiskKeyInfoManager {
     fn exists(&self, key_triple: &KeyTriple) -> Result<bool, String> {
         Ok(self.key_store.contains_key(key_triple))
     }
+
+    fn insert_pending(&mut self, key_triple: KeyTriple, key_info: KeyInfo) -> Result<(), String> {
+        if self.pending.contains_key(&key_triple) {
+            return Err(String::from("a mapping of the key is already pending"));
+        }
+        self.save_mapping(&key_triple, &key_info, true)
+            .map_err(|err| err.to_string())?;
+        let _ = self.pending.insert(key_triple, key_info);
+
+        Ok(())
+    }
+
+    fn commit(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        if !self.pending.contains_key(key_triple) {
+            return Err(String::from("no mapping of the key is pending"));
+        }
+        // Renaming the file replaces the previous mapping atomically.
+        fs::rename(
+            self.mapping_file_path(key_triple, true),
+            self.mapping_file_path(key_triple, false),
+        )
+        .map_err(|err| err.to_string())?;
+        let key_info = self
+            .pending
+            .remove(key_triple)
+            .expect("The mapping should be pending.");
+        let old_key_info = self.key_store.insert(key_triple.clone(), key_info.clone());
+        if let Err(err) = self.update_integrity_tag() {
+            // The mapping is put back pending, as the tag still covers the previous mappings, for
+            // the provider to destroy the key and abort it.
+            let _ = self.pending.insert(key_triple.clone(), key_info);
+            let _ = match old_key_info {
+                Some(old_key_info) => self.key_store.insert(key_triple.clone(), old_key_info),
+                None => self.key_store.remove(key_triple),
+            };
+            if let Err(err) = fs::rename(
+                self.mapping_file_path(key_triple, false),
+                self.mapping_file_path(key_triple, true),
+            ) {
+                format_error!("Failed to put the mapping back pending", err);
+            }
+            if let Some(old_key_info) = self.key_store.get(key_triple) {
+                if let Err(err) = self.save_mapping(key_triple, old_key_info, false) {
+                    format_error!("Failed to restore the previous mapping", err);
+                }
+            }
+            return Err(err);
+        }
+
+        Ok(old_key_info)
+    }
+
+    fn abort(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        self.delete_mapping(key_triple, true)
+            .map_err(|err| err.to_string())?;
+        Ok(self.pending.remove(key_triple))
+    }
+
+    fn get_all_pending(
+        &self,
+        provider_id: ProviderID,
+    ) -> Result<Vec<(&KeyTriple, &KeyInfo)>, String> {
+        Ok(self
+            .pending
+            .iter()
+            .filter(|(key_triple, _)| key_triple.belongs_to_provider(provider_id))
+            .collect())
+    }
+
+    fn compact(&mut self) -> Result<Compaction, String> {
+        self.compact_mappings().map_err(|err| err.to_string())
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct OnDiskKeyInfoManagerBuilder {
     mappings_dir_path: Option<PathBuf>,
+    integrity_tag: Option<IntegrityTag>,
 }
 
 impl OnDiskKeyInfoManagerBuilder {
     pub fn new() -> OnDiskKeyInfoManagerBuilder {
         OnDiskKeyInfoManagerBuilder {
             mappings_dir_path: None,
+            integrity_tag: None,
         }
     }
 
@@ -344,18 +604,30 @@ impl OnDiskKeyInfoManagerBuilder {
         self
     }
 
+    pub fn with_integrity_tag(
+        mut self,
+        integrity_tag: IntegrityTag,
+    ) -> OnDiskKeyInfoManagerBuilder {
+        self.integrity_tag = Some(integrity_tag);
+
+        self
+    }
+
     pub fn build(self) -> std::io::Result<OnDiskKeyInfoManager> {
-        OnDiskKeyInfoManager::new(self.mappings_dir_path.ok_or_else(|| {
-            error!("Mappings directory path is missing");
-            Error::new(ErrorKind::InvalidData, "mappings directory path is missing")
-        })?)
+        OnDiskKeyInfoManager::new(
+            self.mappings_dir_path.ok_or_else(|| {
+                error!("Mappings directory path is missing");
+                Error::new(ErrorKind::InvalidData, "mappings directory path is missing")
+            })?,
+            self.integrity_tag,
+        )
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::super::{KeyInfo, KeyTriple, ManageKeyInfo};
-    use super::OnDiskKeyInfoManager;
+    use super::super::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+    use super::{OnDiskKeyInfoManager, UPDATE_EXTENSION};
     use crate::authenticators::ApplicationName;
     use parsec_interface::operations::psa_algorithm::{
         Algorithm, AsymmetricSignature, Hash, SignHash,
@@ -364,6 +636,7 @@ mod test {
         Attributes, Lifetime, Policy, Type, UsageFlags,
     };
     use parsec_interface::requests::ProviderID;
+    use std::collections::BTreeMap;
     use std::fs;
     use std::path::PathBuf;
 
@@ -398,13 +671,18 @@ mod test {
         KeyInfo {
             id: vec![0x11, 0x22, 0x33],
             attributes: test_key_attributes(),
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
         }
     }
 
     #[test]
     fn insert_get_key_info() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/insert_get_key_info_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
 
         let key_triple = new_key_triple("insert_get_key_info".to_string());
         let key_info = test_key_info();
@@ -430,7 +708,7 @@ mod test {
     #[test]
     fn insert_remove_key() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/insert_remove_key_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
 
         let key_triple = new_key_triple("insert_remove_key".to_string());
         let key_info = test_key_info();
@@ -444,7 +722,7 @@ mod test {
     #[test]
     fn remove_unexisting_key() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/remove_unexisting_key_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
 
         let key_triple = new_key_triple("remove_unexisting_key".to_string());
         assert_eq!(manager.remove(&key_triple).unwrap(), None);
@@ -454,7 +732,7 @@ mod test {
     #[test]
     fn exists() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/exists_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
 
         let key_triple = new_key_triple("exists".to_string());
         let key_info = test_key_info();
@@ -472,13 +750,18 @@ mod test {
     #[test]
     fn insert_overwrites() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/insert_overwrites_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
 
         let key_triple = new_key_triple("insert_overwrites".to_string());
         let key_info_1 = test_key_info();
         let key_info_2 = KeyInfo {
             id: vec![0xaa, 0xbb, 0xcc],
             attributes: test_key_attributes(),
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
         };
 
         let _ = manager.insert(key_triple.clone(), key_info_1).unwrap();
@@ -500,7 +783,7 @@ mod test {
     #[test]
     fn big_names_ascii() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/big_names_ascii_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
 
         let big_app_name_ascii = ApplicationName::new("  Lorem ipsum dolor sit amet, ei suas viris sea, deleniti repudiare te qui. Natum paulo decore ut nec, ne propriae offendit adipisci has. Eius clita legere mel at, ei vis minimum tincidunt.".to_string());
         let big_key_name_ascii = "  Lorem ipsum dolor sit amet, ei suas viris sea, deleniti repudiare te qui. Natum paulo decore ut nec, ne propriae offendit adipisci has. Eius clita legere mel at, ei vis minimum tincidunt.".to_string();
@@ -518,7 +801,7 @@ mod test {
     #[test]
     fn big_names_emoticons() {
         let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/big_names_emoticons_mappings");
-        let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+        let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
 
         let big_app_name_emoticons = ApplicationName::new("😀😁😂😃😄😅😆😇😈😉😊😋😌😍😎😏😐😑😒😓😔😕😖😗😘😙😚😛😜😝😞😟😠😡😢😣😤😥😦😧😨😩😪😫😬😭😮".to_string());
         let big_key_name_emoticons = "😀😁😂😃😄😅😆😇😈😉😊😋😌😍😎😏😐😑😒😓😔😕😖😗😘😙😚😛😜😝😞😟😠😡😢😣😤😥😦😧😨😩😪😫😬😭😮".to_string();
@@ -552,6 +835,11 @@ mod test {
         let key_info2 = KeyInfo {
             id: vec![0x12, 0x22, 0x32],
             attributes: test_key_attributes(),
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
         };
 
         let app_name3 = ApplicationName::new("😈 Application Three 😈".to_string());
@@ -560,9 +848,14 @@ mod test {
         let key_info3 = KeyInfo {
             id: vec![0x13, 0x23, 0x33],
             attributes: test_key_attributes(),
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
         };
         {
-            let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+            let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
 
             let _ = manager
                 .insert(key_triple1.clone(), key_info1.clone())
@@ -576,7 +869,7 @@ mod test {
         }
         // The local hashmap is dropped when leaving the inner scope.
         {
-            let mut manager = OnDiskKeyInfoManager::new(path.clone()).unwrap();
+            let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
 
             assert_eq!(manager.remove(&key_triple1).unwrap().unwrap(), key_info1);
             assert_eq!(manager.remove(&key_triple2).unwrap().unwrap(), key_info2);
@@ -586,6 +879,149 @@ mod test {
         fs::remove_dir_all(path).unwrap();
     }
 
+    #[test]
+    fn pending_mappings_committed_and_loaded() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/pending_mappings");
+        let committed_key_triple = new_key_triple("committed".to_string());
+        let interrupted_key_triple = new_key_triple("interrupted".to_string());
+        let key_info = test_key_info();
+        {
+            let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
+
+            manager
+                .insert_pending(committed_key_triple.clone(), key_info.clone())
+                .unwrap();
+            assert!(!manager.exists(&committed_key_triple).unwrap());
+            assert!(manager.commit(&committed_key_triple).unwrap().is_none());
+            manager
+                .insert_pending(interrupted_key_triple.clone(), key_info.clone())
+                .unwrap();
+        }
+        {
+            let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
+
+            assert_eq!(manager.get(&committed_key_triple).unwrap(), Some(&key_info));
+            assert!(!manager.exists(&interrupted_key_triple).unwrap());
+            assert_eq!(
+                manager.get_all_pending(ProviderID::MbedCrypto).unwrap(),
+                vec![(&interrupted_key_triple, &key_info)]
+            );
+            assert_eq!(
+                manager.abort(&interrupted_key_triple).unwrap(),
+                Some(key_info)
+            );
+        }
+        {
+            let manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
+
+            assert!(manager
+                .get_all_pending(ProviderID::MbedCrypto)
+                .unwrap()
+                .is_empty());
+        }
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn failed_commit_left_pending() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/failed_commit");
+        let key_triple = new_key_triple("failed".to_string());
+        let key_info = test_key_info();
+        let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
+
+        manager
+            .insert_pending(key_triple.clone(), key_info.clone())
+            .unwrap();
+        // The pending file disappearing makes the rename fail.
+        fs::remove_file(manager.mapping_file_path(&key_triple, true)).unwrap();
+        assert!(manager.commit(&key_triple).is_err());
+        assert!(!manager.exists(&key_triple).unwrap());
+        assert_eq!(
+            manager.get_all_pending(ProviderID::MbedCrypto).unwrap(),
+            vec![(&key_triple, &key_info)]
+        );
+        assert_eq!(manager.abort(&key_triple).unwrap(), Some(key_info));
+        assert!(manager
+            .get_all_pending(ProviderID::MbedCrypto)
+            .unwrap()
+            .is_empty());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn counter_updates_replace_mapping() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/counter_updates_mappings");
+        let key_triple = new_key_triple("counter_updates".to_string());
+        let key_info = KeyInfo {
+            counter: Some(41),
+            ..test_key_info()
+        };
+        let update_file_path;
+        {
+            let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
+            let _ = manager
+                .insert(key_triple.clone(), key_info.clone())
+                .unwrap();
+            let _ = manager
+                .insert(
+                    key_triple.clone(),
+                    KeyInfo {
+                        counter: Some(42),
+                        ..key_info.clone()
+                    },
+                )
+                .unwrap();
+            update_file_path = manager
+                .mapping_file_path(&key_triple, false)
+                .with_extension(UPDATE_EXTENSION);
+            assert!(!update_file_path.exists());
+            // Update interrupted by a crash.
+            fs::write(&update_file_path, b"partial").unwrap();
+        }
+        {
+            let manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
+
+            assert_eq!(manager.get(&key_triple).unwrap().unwrap().counter, Some(42));
+            assert!(!update_file_path.exists());
+        }
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn compaction_removes_stale_files() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/compaction_mappings");
+        let mut manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
+        let key_triple = new_key_triple("compaction".to_string());
+        let removed_key_triple = KeyTriple::new(
+            ApplicationName::new("Removed Application".to_string()),
+            ProviderID::MbedCrypto,
+            "compaction".to_string(),
+        );
+        let _ = manager.insert(key_triple.clone(), test_key_info()).unwrap();
+        let _ = manager
+            .insert(removed_key_triple.clone(), test_key_info())
+            .unwrap();
+        let _ = manager.remove(&removed_key_triple).unwrap();
+        let stale_file_path = manager
+            .mapping_file_path(&key_triple, false)
+            .with_file_name("not a mapping");
+        fs::write(&stale_file_path, vec![0; 8192]).unwrap();
+
+        let compaction = manager.compact().unwrap();
+        assert_eq!(compaction.rewritten, 1);
+        // The stale file and the two directories of the removed application.
+        assert_eq!(compaction.removed, 3);
+        assert!(compaction.reclaimed_bytes > 0);
+        assert!(!stale_file_path.exists());
+
+        let manager = OnDiskKeyInfoManager::new(path.clone(), None).unwrap();
+        assert_eq!(manager.get(&key_triple).unwrap(), Some(&test_key_info()));
+        fs::remove_dir_all(path).unwrap();
+    }
+
     fn new_key_triple(key_name: String) -> KeyTriple {
         KeyTriple::new(
             ApplicationName::new("Testing Application 😎".to_string()),
@@ -0,0 +1,533 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! A key info manager storing key triple to key info mapping in an SQLite database
+//!
+//! The on-disk manager replaces a mapping by removing its file and writing a new one: a crash in
+//! between loses the mapping or leaves a truncated file which prevents the service from starting.
+//! This manager stores the mappings as rows of a table and modifies them in transactions
+//! committed with full synchronization of the write-ahead log, so that after a crash each mapping
+//! is either entirely stored or not stored at all.
+//!
+//! Rows are keyed by the application name, the provider ID and the key name, the key information
+//! being stored with the versioned encoding of the `key_info_encoding` module. The mappings are
+//! also kept in memory for the non-modifying operations. As for the on-disk manager, only the
+//! Parsec service should be able to modify the database, and there should not be two instances of
+//! this manager using it at a time.
+//!
+//! The mappings of the keys being created are stored in a second table with the same schema until
+//! they are committed, which moves them to the first one in a single transaction.
+use super::{key_info_encoding, Compaction, KeyInfo, KeyTriple, ManageKeyInfo};
+use crate::authenticators::ApplicationName;
+use log::{error, info, warn};
+use parsec_interface::requests::ProviderID;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub const DEFAULT_DATABASE_PATH: &str = "./mappings.sqlite";
+
+// The tables are created with the key triple as primary key so that inserting a mapping replaces
+// any previous one in the same statement.
+const SCHEMA: &str = "PRAGMA journal_mode = WAL;
+PRAGMA synchronous = FULL;
+CREATE TABLE IF NOT EXISTS mappings (
+    app_name TEXT NOT NULL,
+    provider_id INTEGER NOT NULL,
+    key_name TEXT NOT NULL,
+    key_info BLOB NOT NULL,
+    PRIMARY KEY (app_name, provider_id, key_name)
+);
+CREATE TABLE IF NOT EXISTS pending_mappings (
+    app_name TEXT NOT NULL,
+    provider_id INTEGER NOT NULL,
+    key_name TEXT NOT NULL,
+    key_info BLOB NOT NULL,
+    PRIMARY KEY (app_name, provider_id, key_name)
+);";
+const MAPPINGS_TABLE: &str = "mappings";
+const PENDING_MAPPINGS_TABLE: &str = "pending_mappings";
+
+#[derive(Debug)]
+pub struct SqliteKeyInfoManager {
+    /// Internal mapping, used for non-modifying operations.
+    key_store: HashMap<KeyTriple, KeyInfo>,
+    /// Mappings of the keys being created, not committed yet.
+    pending: HashMap<KeyTriple, KeyInfo>,
+    /// Connection to the database, which can only be used by one thread at a time.
+    connection: Mutex<Connection>,
+}
+
+fn to_io_error(error: rusqlite::Error) -> Error {
+    format_error!("SQLite error", error);
+    Error::new(ErrorKind::Other, "key info database error")
+}
+
+impl SqliteKeyInfoManager {
+    /// Opens the database, creating it if it does not exist, and reads all the mappings.
+    ///
+    /// # Errors
+    ///
+    /// Returns an std::io error if the database can not be opened or read, or if one of the
+    /// mappings can not be decoded.
+    fn new(database_path: PathBuf) -> std::io::Result<SqliteKeyInfoManager> {
+        let connection = Connection::open(&database_path).map_err(to_io_error)?;
+        connection.execute_batch(SCHEMA).map_err(to_io_error)?;
+
+        let key_store = read_mappings(&connection, MAPPINGS_TABLE)?;
+        let pending = read_mappings(&connection, PENDING_MAPPINGS_TABLE)?;
+
+        info!("Found {} mappings in the database", key_store.len());
+        if !pending.is_empty() {
+            warn!(
+                "Found {} mappings of keys whose creation was interrupted",
+                pending.len()
+            );
+        }
+
+        Ok(SqliteKeyInfoManager {
+            key_store,
+            pending,
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Stores the mapping in the table in its own transaction, replacing any previous one.
+    fn save_mapping(
+        &self,
+        table: &str,
+        key_triple: &KeyTriple,
+        key_info: &KeyInfo,
+    ) -> Result<(), String> {
+        let key_info = key_info_encoding::encode(key_info)?;
+        let mut connection = self.connection.lock().expect("Database lock poisoned");
+        let transaction = connection.transaction().map_err(|e| e.to_string())?;
+        let _ = transaction
+            .execute(
+                &format!(
+                    "INSERT OR REPLACE INTO {} (app_name, provider_id, key_name, key_info) \
+                     VALUES (?1, ?2, ?3, ?4)",
+                    table
+                ),
+                params![
+                    key_triple.app_name().get_name(),
+                    key_triple.provider_id() as u8,
+                    key_triple.key_name(),
+                    key_info
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        transaction.commit().map_err(|e| e.to_string())
+    }
+
+    /// Rewrites all the mappings in the current encoding, in a single transaction, and rebuilds the
+    /// database to release the pages left free by the removed mappings.
+    fn compact_database(&self) -> Result<Compaction, String> {
+        let mut connection = self.connection.lock().expect("Database lock poisoned");
+        let size_before = database_size(&connection)?;
+        let transaction = connection.transaction().map_err(|e| e.to_string())?;
+        for (key_triple, key_info) in &self.key_store {
+            let _ = transaction
+                .execute(
+                    &format!(
+                        "UPDATE {} SET key_info = ?4 \
+                         WHERE app_name = ?1 AND provider_id = ?2 AND key_name = ?3",
+                        MAPPINGS_TABLE
+                    ),
+                    params![
+                        key_triple.app_name().get_name(),
+                        key_triple.provider_id() as u8,
+                        key_triple.key_name(),
+                        key_info_encoding::encode(key_info)?
+                    ],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        transaction.commit().map_err(|e| e.to_string())?;
+        connection
+            .execute_batch("VACUUM;")
+            .map_err(|e| e.to_string())?;
+
+        Ok(Compaction {
+            rewritten: self.key_store.len(),
+            removed: 0,
+            reclaimed_bytes: size_before.saturating_sub(database_size(&connection)?),
+        })
+    }
+
+    /// Deletes the mapping from the table in its own transaction. Does nothing if it is not
+    /// stored.
+    fn delete_mapping(&self, table: &str, key_triple: &KeyTriple) -> Result<(), String> {
+        let mut connection = self.connection.lock().expect("Database lock poisoned");
+        let transaction = connection.transaction().map_err(|e| e.to_string())?;
+        let _ = transaction
+            .execute(
+                &format!(
+                    "DELETE FROM {} WHERE app_name = ?1 AND provider_id = ?2 AND key_name = ?3",
+                    table
+                ),
+                params![
+                    key_triple.app_name().get_name(),
+                    key_triple.provider_id() as u8,
+                    key_triple.key_name()
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        transaction.commit().map_err(|e| e.to_string())
+    }
+
+    /// Moves the pending mapping to the mappings table in a single transaction, replacing any
+    /// previous mapping.
+    fn commit_mapping(&self, key_triple: &KeyTriple) -> Result<(), String> {
+        let mut connection = self.connection.lock().expect("Database lock poisoned");
+        let transaction = connection.transaction().map_err(|e| e.to_string())?;
+        let key_triple_params = params![
+            key_triple.app_name().get_name(),
+            key_triple.provider_id() as u8,
+            key_triple.key_name()
+        ];
+        let _ = transaction
+            .execute(
+                "INSERT OR REPLACE INTO mappings (app_name, provider_id, key_name, key_info) \
+                 SELECT app_name, provider_id, key_name, key_info FROM pending_mappings \
+                 WHERE app_name = ?1 AND provider_id = ?2 AND key_name = ?3",
+                key_triple_params,
+            )
+            .map_err(|e| e.to_string())?;
+        let _ = transaction
+            .execute(
+                "DELETE FROM pending_mappings \
+                 WHERE app_name = ?1 AND provider_id = ?2 AND key_name = ?3",
+                key_triple_params,
+            )
+            .map_err(|e| e.to_string())?;
+        transaction.commit().map_err(|e| e.to_string())
+    }
+}
+
+/// Reads all the mappings of the table.
+fn read_mappings(
+    connection: &Connection,
+    table: &str,
+) -> std::io::Result<HashMap<KeyTriple, KeyInfo>> {
+    let mut mappings = HashMap::new();
+    let mut statement = connection
+        .prepare(&format!(
+            "SELECT app_name, provider_id, key_name, key_info FROM {}",
+            table
+        ))
+        .map_err(to_io_error)?;
+    let rows = statement
+        .query_map(params![], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u8>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Vec<u8>>(3)?,
+            ))
+        })
+        .map_err(to_io_error)?;
+    for row in rows {
+        let (app_name, provider_id, key_name, key_info) = row.map_err(to_io_error)?;
+        let provider_id = ProviderID::try_from(provider_id)
+            .map_err(|status| Error::new(ErrorKind::InvalidData, status.to_string()))?;
+        let key_info = key_info_encoding::decode(&key_info).map_err(|e| {
+            format_error!("Error deserializing key info", e);
+            Error::new(ErrorKind::InvalidData, "error deserializing key info")
+        })?;
+        let key_triple = KeyTriple::new(ApplicationName::new(app_name), provider_id, key_name);
+        let _ = mappings.insert(key_triple, key_info);
+    }
+
+    Ok(mappings)
+}
+
+impl ManageKeyInfo for SqliteKeyInfoManager {
+    fn get(&self, key_triple: &KeyTriple) -> Result<Option<&KeyInfo>, String> {
+        Ok(self.key_store.get(key_triple))
+    }
+
+    fn get_all(&self, provider_id: ProviderID) -> Result<Vec<&KeyTriple>, String> {
+        Ok(self
+            .key_store
+            .keys()
+            .filter(|key_triple| key_triple.belongs_to_provider(provider_id))
+            .collect())
+    }
+
+    fn insert(
+        &mut self,
+        key_triple: KeyTriple,
+        key_info: KeyInfo,
+    ) -> Result<Option<KeyInfo>, String> {
+        // The mapping is only visible once the transaction is committed.
+        self.save_mapping(MAPPINGS_TABLE, &key_triple, &key_info)?;
+        Ok(self.key_store.insert(key_triple, key_info))
+    }
+
+    fn remove(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        self.delete_mapping(MAPPINGS_TABLE, key_triple)?;
+        Ok(self.key_store.remove(key_triple))
+    }
+
+    fn exists(&self, key_triple: &KeyTriple) -> Result<bool, String> {
+        Ok(self.key_store.contains_key(key_triple))
+    }
+
+    fn insert_pending(&mut self, key_triple: KeyTriple, key_info: KeyInfo) -> Result<(), String> {
+        if self.pending.contains_key(&key_triple) {
+            return Err(String::from("a mapping of the key is already pending"));
+        }
+        self.save_mapping(PENDING_MAPPINGS_TABLE, &key_triple, &key_info)?;
+        let _ = self.pending.insert(key_triple, key_info);
+
+        Ok(())
+    }
+
+    fn commit(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        if !self.pending.contains_key(key_triple) {
+            return Err(String::from("no mapping of the key is pending"));
+        }
+        self.commit_mapping(key_triple)?;
+        match self.pending.remove(key_triple) {
+            Some(key_info) => Ok(self.key_store.insert(key_triple.clone(), key_info)),
+            None => Ok(None),
+        }
+    }
+
+    fn abort(&mut self, key_triple: &KeyTriple) -> Result<Option<KeyInfo>, String> {
+        self.delete_mapping(PENDING_MAPPINGS_TABLE, key_triple)?;
+        Ok(self.pending.remove(key_triple))
+    }
+
+    fn get_all_pending(
+        &self,
+        provider_id: ProviderID,
+    ) -> Result<Vec<(&KeyTriple, &KeyInfo)>, String> {
+        Ok(self
+            .pending
+            .iter()
+            .filter(|(key_triple, _)| key_triple.belongs_to_provider(provider_id))
+            .collect())
+    }
+
+    fn compact(&mut self) -> Result<Compaction, String> {
+        self.compact_database()
+    }
+}
+
+// Gets the size of the database, in bytes.
+fn database_size(connection: &Connection) -> Result<u64, String> {
+    let pragma = |name: &str| -> Result<i64, String> {
+        connection
+            .query_row(&format!("PRAGMA {}", name), params![], |row| row.get(0))
+            .map_err(|e| e.to_string())
+    };
+
+    Ok((pragma("page_count")? * pragma("page_size")?) as u64)
+}
+
+#[derive(Debug, Default)]
+pub struct SqliteKeyInfoManagerBuilder {
+    database_path: Option<PathBuf>,
+}
+
+impl SqliteKeyInfoManagerBuilder {
+    pub fn new() -> SqliteKeyInfoManagerBuilder {
+        SqliteKeyInfoManagerBuilder {
+            database_path: None,
+        }
+    }
+
+    pub fn with_database_path(mut self, path: PathBuf) -> SqliteKeyInfoManagerBuilder {
+        self.database_path = Some(path);
+
+        self
+    }
+
+    pub fn build(self) -> std::io::Result<SqliteKeyInfoManager> {
+        SqliteKeyInfoManager::new(self.database_path.ok_or_else(|| {
+            error!("Database path is missing");
+            Error::new(ErrorKind::InvalidData, "database path is missing")
+        })?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
+    use super::SqliteKeyInfoManager;
+    use crate::authenticators::ApplicationName;
+    use parsec_interface::operations::psa_algorithm::{
+        Algorithm, AsymmetricSignature, Hash, SignHash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{
+        Attributes, Lifetime, Policy, Type, UsageFlags,
+    };
+    use parsec_interface::requests::ProviderID;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn test_key_info(id: Vec<u8>) -> KeyInfo {
+        KeyInfo {
+            id,
+            attributes: Attributes {
+                lifetime: Lifetime::Persistent,
+                key_type: Type::RsaKeyPair,
+                bits: 2048,
+                policy: Policy {
+                    usage_flags: UsageFlags {
+                        sign_hash: true,
+                        verify_hash: true,
+                        sign_message: false,
+                        verify_message: false,
+                        export: false,
+                        encrypt: false,
+                        decrypt: false,
+                        cache: false,
+                        copy: false,
+                        derive: false,
+                    },
+                    permitted_algorithms: Algorithm::AsymmetricSignature(
+                        AsymmetricSignature::RsaPkcs1v15Sign {
+                            hash_alg: SignHash::Specific(Hash::Sha256),
+                        },
+                    ),
+                },
+            },
+            state: KeyState::Active,
+            created_at: None,
+            counter: None,
+            certificate: None,
+            labels: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn mappings_persisted() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/mappings_persisted.sqlite");
+        let _ = fs::remove_file(&path);
+        let key_triple = KeyTriple::new(
+            ApplicationName::new("😀 Application 😀".to_string()),
+            ProviderID::Pkcs11,
+            "key".to_string(),
+        );
+        let removed_key_triple = KeyTriple::new(
+            ApplicationName::new("app".to_string()),
+            ProviderID::Tpm,
+            "removed".to_string(),
+        );
+
+        {
+            let mut manager = SqliteKeyInfoManager::new(path.clone()).unwrap();
+            assert!(manager
+                .insert(key_triple.clone(), test_key_info(vec![1]))
+                .unwrap()
+                .is_none());
+            assert_eq!(
+                manager
+                    .insert(key_triple.clone(), test_key_info(vec![2]))
+                    .unwrap(),
+                Some(test_key_info(vec![1]))
+            );
+            let _ = manager
+                .insert(removed_key_triple.clone(), test_key_info(vec![3]))
+                .unwrap();
+            assert!(manager.remove(&removed_key_triple).unwrap().is_some());
+            assert_eq!(manager.remove(&removed_key_triple).unwrap(), None);
+        }
+
+        let manager = SqliteKeyInfoManager::new(path.clone()).unwrap();
+        assert_eq!(
+            manager.get(&key_triple).unwrap(),
+            Some(&test_key_info(vec![2]))
+        );
+        assert!(!manager.exists(&removed_key_triple).unwrap());
+        assert_eq!(manager.get_all(ProviderID::Pkcs11).unwrap().len(), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn pending_mappings_persisted() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/pending_mappings_persisted.sqlite");
+        let _ = fs::remove_file(&path);
+        let committed_key_triple = KeyTriple::new(
+            ApplicationName::new("app".to_string()),
+            ProviderID::Pkcs11,
+            "committed".to_string(),
+        );
+        let interrupted_key_triple = KeyTriple::new(
+            ApplicationName::new("app".to_string()),
+            ProviderID::Pkcs11,
+            "interrupted".to_string(),
+        );
+
+        {
+            let mut manager = SqliteKeyInfoManager::new(path.clone()).unwrap();
+            manager
+                .insert_pending(committed_key_triple.clone(), test_key_info(vec![1]))
+                .unwrap();
+            assert!(!manager.exists(&committed_key_triple).unwrap());
+            assert!(manager.commit(&committed_key_triple).unwrap().is_none());
+            manager
+                .insert_pending(interrupted_key_triple.clone(), test_key_info(vec![2]))
+                .unwrap();
+        }
+
+        let mut manager = SqliteKeyInfoManager::new(path.clone()).unwrap();
+        assert_eq!(
+            manager.get(&committed_key_triple).unwrap(),
+            Some(&test_key_info(vec![1]))
+        );
+        assert!(!manager.exists(&interrupted_key_triple).unwrap());
+        assert_eq!(
+            manager.get_all_pending(ProviderID::Pkcs11).unwrap(),
+            vec![(&interrupted_key_triple, &test_key_info(vec![2]))]
+        );
+        assert!(manager.abort(&interrupted_key_triple).unwrap().is_some());
+        assert!(manager
+            .get_all_pending(ProviderID::Pkcs11)
+            .unwrap()
+            .is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn compaction_keeps_mappings() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/compaction.sqlite");
+        let _ = fs::remove_file(&path);
+        let key_triple = KeyTriple::new(
+            ApplicationName::new("app".to_string()),
+            ProviderID::Pkcs11,
+            "key".to_string(),
+        );
+
+        let mut manager = SqliteKeyInfoManager::new(path.clone()).unwrap();
+        for id in 0..64 {
+            let removed_key_triple = KeyTriple::new(
+                ApplicationName::new("app".to_string()),
+                ProviderID::Pkcs11,
+                format!("removed {}", id),
+            );
+            let _ = manager
+                .insert(removed_key_triple.clone(), test_key_info(vec![id; 512]))
+                .unwrap();
+            let _ = manager.remove(&removed_key_triple).unwrap();
+        }
+        let _ = manager
+            .insert(key_triple.clone(), test_key_info(vec![1]))
+            .unwrap();
+        assert_eq!(manager.compact().unwrap().rewritten, 1);
+        drop(manager);
+
+        let manager = SqliteKeyInfoManager::new(path.clone()).unwrap();
+        assert_eq!(
+            manager.get(&key_triple).unwrap(),
+            Some(&test_key_info(vec![1]))
+        );
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -5,18 +5,49 @@
 //! The core provider acts as a source of information for the Parsec service,
 //! aiding clients in discovering the capabilities offered by their underlying
 //! platform.
+//!
+//! It also lists the keys of an application, as recorded in the Key Info Managers, so that a
+//! client can find the keys it created before crashing, and the applications owning keys for the
+//! administrators. The keys of the remote provider are not stored locally and are not listed.
+//! The whole inventory of the keys, with their information, is given for the key manifest.
+//!
+//! The descriptions and opcodes of the providers are gathered when the service is built and served
+//! from this cache. The providers discovering their capabilities from their backend, such as the
+//! remote provider, update their entry when they discover them again, see
+//! `Dispatcher::refresh_capabilities`.
 use super::Provide;
+use crate::authenticators::{ApplicationName, AuthenticatorInfo};
+use crate::key_info_managers::key_info_store::KeyInfoStore;
+use crate::key_info_managers::{self, Compaction, KeyInfo, KeyTriple, ManageKeyInfo};
 use log::trace;
 use parsec_interface::operations::list_providers::ProviderInfo;
-use parsec_interface::operations::{list_opcodes, list_providers, ping};
+use parsec_interface::operations::{
+    delete_client, list_authenticators, list_clients, list_keys, list_opcodes, list_providers, ping,
+};
 use parsec_interface::requests::{Opcode, ProviderID, ResponseStatus, Result};
 use std::collections::{HashMap, HashSet};
 use std::io::{Error, ErrorKind};
 use std::str::FromStr;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 use uuid::Uuid;
 use version::{version, Version};
 
-const SUPPORTED_OPCODES: [Opcode; 3] = [Opcode::ListProviders, Opcode::ListOpcodes, Opcode::Ping];
+const SUPPORTED_OPCODES: [Opcode; 7] = [
+    Opcode::ListProviders,
+    Opcode::ListOpcodes,
+    Opcode::ListAuthenticators,
+    Opcode::ListKeys,
+    Opcode::ListClients,
+    Opcode::DeleteClient,
+    Opcode::Ping,
+];
+
+// Descriptions and opcodes of the providers available.
+#[derive(Debug, Default)]
+struct ProviderDetails {
+    provider_info: Vec<ProviderInfo>,
+    provider_opcodes: HashMap<ProviderID, HashSet<Opcode>>,
+}
 
 /// Service information provider
 ///
@@ -27,8 +58,47 @@ const SUPPORTED_OPCODES: [Opcode; 3] = [Opcode::ListProviders, Opcode::ListOpcod
 pub struct CoreProvider {
     wire_protocol_version_min: u8,
     wire_protocol_version_maj: u8,
-    provider_info: Vec<ProviderInfo>,
-    provider_opcodes: HashMap<ProviderID, HashSet<Opcode>>,
+    provider_details: RwLock<ProviderDetails>,
+    denied_opcodes: HashSet<Opcode>,
+    authenticator_info: Vec<AuthenticatorInfo>,
+    key_info_stores: Vec<Arc<KeyInfoStore>>,
+}
+
+impl CoreProvider {
+    fn provider_details(&self) -> RwLockReadGuard<ProviderDetails> {
+        self.provider_details
+            .read()
+            .expect("Provider details lock poisoned")
+    }
+
+    // Gets the keys stored in the Key Info Managers for the providers running.
+    fn stored_keys(&self) -> Result<Vec<(KeyTriple, KeyInfo)>> {
+        let provider_ids: Vec<ProviderID> = self
+            .provider_details()
+            .provider_opcodes
+            .keys()
+            .copied()
+            .collect();
+        let mut keys = Vec::new();
+        for key_info_store in &self.key_info_stores {
+            let store_handle = key_info_store.read();
+            for provider_id in &provider_ids {
+                for key_triple in store_handle
+                    .get_all(*provider_id)
+                    .map_err(key_info_managers::to_response_status)?
+                {
+                    if let Some(key_info) = store_handle
+                        .get(key_triple)
+                        .map_err(key_info_managers::to_response_status)?
+                    {
+                        keys.push((key_triple.clone(), key_info.clone()));
+                    }
+                }
+            }
+        }
+
+        Ok(keys)
+    }
 }
 
 impl Provide for CoreProvider {
@@ -36,6 +106,7 @@ impl Provide for CoreProvider {
         trace!("list_opcodes ingress");
         Ok(list_opcodes::Result {
             opcodes: self
+                .provider_details()
                 .provider_opcodes
                 .get(&op.provider_id)
                 .ok_or(ResponseStatus::ProviderNotRegistered)?
@@ -46,10 +117,99 @@ impl Provide for CoreProvider {
     fn list_providers(&self, _op: list_providers::Operation) -> Result<list_providers::Result> {
         trace!("list_providers ingress");
         Ok(list_providers::Result {
-            providers: self.provider_info.clone(),
+            providers: self.provider_details().provider_info.clone(),
         })
     }
 
+    fn update_provider_details(&self, provider_info: ProviderInfo, mut opcodes: HashSet<Opcode>) {
+        trace!("update_provider_details ingress");
+        opcodes.retain(|opcode| !self.denied_opcodes.contains(opcode));
+        let mut provider_details = self
+            .provider_details
+            .write()
+            .expect("Provider details lock poisoned");
+        let _ = provider_details
+            .provider_opcodes
+            .insert(provider_info.id, opcodes);
+        match provider_details
+            .provider_info
+            .iter_mut()
+            .find(|info| info.id == provider_info.id)
+        {
+            Some(info) => *info = provider_info,
+            None => provider_details.provider_info.push(provider_info),
+        }
+    }
+
+    fn list_authenticators(
+        &self,
+        _op: list_authenticators::Operation,
+    ) -> Result<list_authenticators::Result> {
+        trace!("list_authenticators ingress");
+        Ok(list_authenticators::Result {
+            authenticators: self.authenticator_info.clone(),
+        })
+    }
+
+    fn list_keys(
+        &self,
+        app_name: ApplicationName,
+        _op: list_keys::Operation,
+    ) -> Result<list_keys::Result> {
+        trace!("list_keys ingress");
+        let keys = self
+            .stored_keys()?
+            .into_iter()
+            .filter(|(key_triple, _)| key_triple.app_name() == &app_name)
+            .map(|(key_triple, key_info)| list_keys::KeyInfo {
+                provider_id: key_triple.provider_id(),
+                name: key_triple.key_name().to_string(),
+                attributes: key_info.attributes,
+            })
+            .collect();
+
+        Ok(list_keys::Result { keys })
+    }
+
+    fn list_clients(&self, _op: list_clients::Operation) -> Result<list_clients::Result> {
+        trace!("list_clients ingress");
+        let mut clients: Vec<String> = self
+            .stored_keys()?
+            .into_iter()
+            .map(|(key_triple, _)| key_triple.app_name().to_string())
+            .collect::<HashSet<String>>()
+            .into_iter()
+            .collect();
+        clients.sort();
+
+        Ok(list_clients::Result { clients })
+    }
+
+    fn key_inventory(&self) -> Result<Vec<(KeyTriple, KeyInfo)>> {
+        trace!("key_inventory ingress");
+        self.stored_keys()
+    }
+
+    fn compact_key_stores(&self) -> Result<Compaction> {
+        trace!("compact_key_stores ingress");
+        let mut compaction = Compaction::default();
+        for key_info_store in &self.key_info_stores {
+            compaction.add(
+                key_info_store
+                    .compact()
+                    .map_err(key_info_managers::to_response_status)?,
+            );
+        }
+
+        Ok(compaction)
+    }
+
+    fn delete_client(&self, _op: delete_client::Operation) -> Result<delete_client::Result> {
+        trace!("delete_client ingress");
+        // The keys were destroyed by the dispatcher, see `Dispatcher::dispatch_request`.
+        Ok(delete_client::Result {})
+    }
+
     fn ping(&self, _op: ping::Operation) -> Result<ping::Result> {
         trace!("ping ingress");
         let result = ping::Result {
@@ -68,6 +228,9 @@ pub struct CoreProviderBuilder {
     version_min: Option<u8>,
     provider_info: Vec<ProviderInfo>,
     provider_opcodes: HashMap<ProviderID, HashSet<Opcode>>,
+    authenticator_info: Vec<AuthenticatorInfo>,
+    denied_opcodes: HashSet<Opcode>,
+    key_info_stores: Vec<Arc<KeyInfoStore>>,
 }
 
 impl CoreProviderBuilder {
@@ -105,6 +268,9 @@ impl CoreProviderBuilder {
             version_min: None,
             provider_info,
             provider_opcodes,
+            authenticator_info: Vec::new(),
+            denied_opcodes: HashSet::new(),
+            key_info_stores: Vec::new(),
         })
     }
 
@@ -126,7 +292,32 @@ impl CoreProviderBuilder {
         self
     }
 
-    pub fn build(self) -> std::io::Result<CoreProvider> {
+    pub fn with_authenticator_info(mut self, authenticator_info: AuthenticatorInfo) -> Self {
+        self.authenticator_info.push(authenticator_info);
+
+        self
+    }
+
+    /// Adds a Key Info Manager whose keys are listed.
+    pub fn with_key_info_store(mut self, key_info_store: Arc<KeyInfoStore>) -> Self {
+        self.key_info_stores.push(key_info_store);
+
+        self
+    }
+
+    /// Sets the opcodes denied by configuration, which are not listed as supported by any
+    /// provider.
+    pub fn with_denied_opcodes(mut self, denied_opcodes: HashSet<Opcode>) -> Self {
+        self.denied_opcodes = denied_opcodes;
+
+        self
+    }
+
+    pub fn build(mut self) -> std::io::Result<CoreProvider> {
+        for opcodes in self.provider_opcodes.values_mut() {
+            opcodes.retain(|opcode| !self.denied_opcodes.contains(opcode));
+        }
+
         let core_provider = CoreProvider {
             wire_protocol_version_maj: self
                 .version_maj
@@ -134,8 +325,13 @@ impl CoreProviderBuilder {
             wire_protocol_version_min: self
                 .version_min
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "version min is missing"))?,
-            provider_opcodes: self.provider_opcodes,
-            provider_info: self.provider_info,
+            provider_details: RwLock::new(ProviderDetails {
+                provider_info: self.provider_info,
+                provider_opcodes: self.provider_opcodes,
+            }),
+            denied_opcodes: self.denied_opcodes,
+            authenticator_info: self.authenticator_info,
+            key_info_stores: self.key_info_stores,
         };
 
         Ok(core_provider)
@@ -151,8 +347,10 @@ mod tests {
         let provider = CoreProvider {
             wire_protocol_version_min: 8,
             wire_protocol_version_maj: 10,
-            provider_info: Vec::new(),
-            provider_opcodes: HashMap::new(),
+            provider_details: Default::default(),
+            denied_opcodes: HashSet::new(),
+            authenticator_info: Vec::new(),
+            key_info_stores: Vec::new(),
         };
         let op = ping::Operation {};
         let result = provider.ping(op).unwrap();
@@ -165,4 +363,47 @@ mod tests {
             provider.wire_protocol_version_min
         );
     }
+
+    #[test]
+    fn provider_details_updated() {
+        let provider_info = |description: &str| ProviderInfo {
+            uuid: Uuid::nil(),
+            description: String::from(description),
+            vendor: String::new(),
+            version_maj: 0,
+            version_min: 1,
+            version_rev: 0,
+            id: ProviderID::Pkcs11,
+        };
+        let provider = CoreProviderBuilder::new()
+            .unwrap()
+            .with_wire_protocol_version(0, 1)
+            .with_provider_details(
+                provider_info("Remote: before"),
+                vec![Opcode::PsaSignHash].into_iter().collect(),
+            )
+            .with_denied_opcodes(vec![Opcode::PsaDestroyKey].into_iter().collect())
+            .build()
+            .unwrap();
+
+        provider.update_provider_details(
+            provider_info("Remote: after"),
+            vec![Opcode::PsaSignHash, Opcode::PsaDestroyKey]
+                .into_iter()
+                .collect(),
+        );
+        let providers = provider
+            .list_providers(list_providers::Operation {})
+            .unwrap()
+            .providers;
+        assert_eq!(providers.len(), 2);
+        assert_eq!(providers[1].description, "Remote: after");
+        let opcodes = provider
+            .list_opcodes(list_opcodes::Operation {
+                provider_id: ProviderID::Pkcs11,
+            })
+            .unwrap()
+            .opcodes;
+        assert_eq!(opcodes, vec![Opcode::PsaSignHash].into_iter().collect());
+    }
 }
@@ -0,0 +1,168 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Deadline of the operation being executed by a provider
+//!
+//! The back end handler gives each operation the deadline configured for its opcode, and cancels
+//! the operations in flight when the service shuts down and they outlive the shutdown timeout.
+//! Providers can not be interrupted: the deadline is surfaced to them so that their long-running
+//! loops, such as the waits for the TPM to accept a command again, check it and give up early
+//! instead of holding their worker thread after the client or the service stopped waiting.
+//!
+//! The operations being executed synchronously on the thread of their request, the deadline is
+//! given to the provider through the thread rather than through the methods of `Provide`, which
+//! the providers added programmatically by embedding programs also implement: `current` gets the
+//! deadline of the operation executed by the calling thread. Operations executed by the service
+//! within another one keep the earliest of their deadlines.
+use log::error;
+use parsec_interface::requests::{ResponseStatus, Result};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static CURRENT: RefCell<Deadline> = RefCell::new(Deadline::default());
+}
+
+/// Deadline of an operation, which can also be cancelled before it expires
+#[derive(Clone, Debug, Default)]
+pub struct Deadline {
+    expires_at: Option<Instant>,
+    cancellations: Vec<Arc<AtomicBool>>,
+}
+
+impl Deadline {
+    /// Creates a deadline expiring after the given duration, if set, and cancelled with the given
+    /// flag.
+    pub fn new(duration: Option<Duration>, cancellation: Arc<AtomicBool>) -> Deadline {
+        Deadline {
+            expires_at: duration.map(|duration| Instant::now() + duration),
+            cancellations: vec![cancellation],
+        }
+    }
+
+    /// Gets the time left before the deadline expires, if it expires.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.expires_at
+            .map(|expires_at| expires_at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns true if the deadline expires, false if the operation can run until it is cancelled.
+    pub fn expires(&self) -> bool {
+        self.expires_at.is_some()
+    }
+
+    /// Returns true if the deadline expired or if the operation was cancelled.
+    pub fn is_exceeded(&self) -> bool {
+        self.remaining() == Some(Duration::from_secs(0))
+            || self
+                .cancellations
+                .iter()
+                .any(|cancelled| cancelled.load(Ordering::Relaxed))
+    }
+
+    /// Checks that the operation can go on.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorCommunicationFailure` if the deadline expired or if the operation was
+    /// cancelled.
+    pub fn check(&self) -> Result<()> {
+        if self.is_exceeded() {
+            error!("The operation was cancelled or exceeded its deadline.");
+            return Err(ResponseStatus::PsaErrorCommunicationFailure);
+        }
+
+        Ok(())
+    }
+
+    // Gets the deadline of an operation executed within another with this deadline.
+    fn nested(&self, inner: Deadline) -> Deadline {
+        let expires_at = match (self.expires_at, inner.expires_at) {
+            (Some(outer), Some(inner)) => Some(outer.min(inner)),
+            (outer, inner) => outer.or(inner),
+        };
+        let mut cancellations = self.cancellations.clone();
+        cancellations.extend(inner.cancellations);
+
+        Deadline {
+            expires_at,
+            cancellations,
+        }
+    }
+}
+
+/// Scope in which an operation is executed with a deadline, the previous deadline of the thread
+/// being restored when it is dropped
+#[derive(Debug)]
+pub struct DeadlineScope {
+    previous: Deadline,
+}
+
+impl Drop for DeadlineScope {
+    fn drop(&mut self) {
+        let previous = std::mem::take(&mut self.previous);
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Makes the deadline the one of the operation executed by the calling thread, until the returned
+/// scope is dropped.
+pub fn enter(deadline: Deadline) -> DeadlineScope {
+    CURRENT.with(|current| {
+        let previous = current.borrow().clone();
+        *current.borrow_mut() = previous.nested(deadline);
+        DeadlineScope { previous }
+    })
+}
+
+/// Gets the deadline of the operation executed by the calling thread, which never expires outside
+/// of an operation.
+pub fn current() -> Deadline {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{current, enter, Deadline};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn deadline_scoped() {
+        assert!(!current().expires());
+        let cancellation = Arc::new(AtomicBool::new(false));
+        {
+            let _scope = enter(Deadline::new(
+                Some(Duration::from_secs(60)),
+                cancellation.clone(),
+            ));
+            assert!(current().expires());
+            {
+                // The nested operation keeps the earliest deadline.
+                let _nested = enter(Deadline::new(
+                    Some(Duration::from_secs(3600)),
+                    Arc::new(AtomicBool::new(false)),
+                ));
+                assert!(current().remaining().unwrap() <= Duration::from_secs(60));
+                assert!(current().check().is_ok());
+                cancellation.store(true, Ordering::Relaxed);
+                assert!(current().check().is_err());
+            }
+            assert!(current().is_exceeded());
+        }
+        assert!(!current().expires());
+        assert!(!current().is_exceeded());
+    }
+
+    #[test]
+    fn deadline_expired() {
+        let deadline = Deadline::new(
+            Some(Duration::from_secs(0)),
+            Arc::new(AtomicBool::new(false)),
+        );
+        assert!(deadline.is_exceeded());
+        assert!(!Deadline::new(None, Arc::new(AtomicBool::new(false))).is_exceeded());
+    }
+}
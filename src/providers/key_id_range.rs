@@ -0,0 +1,96 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Partitioning of the key IDs of a backend
+//!
+//! Several users of the same backend, for example multiple service instances sharing a PKCS 11
+//! token or an Mbed Crypto storage, would choose colliding IDs for their keys. Each of them can be
+//! configured with its own range of key IDs, in which its provider allocates all the IDs for the
+//! new keys. When the provider is created, the IDs of the keys stored in its Key Info Manager are
+//! checked to be in the range, so that a range changed to overlap with the keys of another instance
+//! is detected before any key is created.
+use crate::key_info_managers::KeyTriple;
+use log::error;
+use serde::Deserialize;
+use std::io::{Error, ErrorKind, Result};
+
+/// Inclusive range of key IDs allocated by a provider
+#[derive(Copy, Clone, Deserialize, Debug, PartialEq)]
+pub struct KeyIdRange {
+    /// Lowest key ID of the range
+    pub min: u32,
+    /// Highest key ID of the range
+    pub max: u32,
+}
+
+impl KeyIdRange {
+    /// Returns true if the key ID is in the range.
+    pub fn contains(&self, key_id: u32) -> bool {
+        self.min <= key_id && key_id <= self.max
+    }
+
+    /// Number of key IDs in the range.
+    pub fn size(&self) -> u64 {
+        u64::from(self.max) - u64::from(self.min) + 1
+    }
+
+    /// Checks that the range is not empty and within the IDs accepted by the backend.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if it is not.
+    pub fn check_within(&self, bounds: KeyIdRange) -> Result<()> {
+        if self.min > self.max || self.min < bounds.min || self.max > bounds.max {
+            error!(
+                "The key ID range {}-{} is empty or not within the IDs accepted by the backend, {}-{}.",
+                self.min, self.max, bounds.min, bounds.max
+            );
+            return Err(Error::new(ErrorKind::InvalidData, "invalid key ID range"));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the ID of a key stored in the Key Info Manager is in the range, logging an
+    /// error otherwise.
+    pub fn check_stored_id(&self, key_triple: &KeyTriple, key_id: u32) -> bool {
+        if self.contains(key_id) {
+            return true;
+        }
+        if crate::utils::GlobalConfig::log_error_details() {
+            error!(
+                "The ID {} of key {} is not in the key ID range {}-{}, it might collide with the keys of another user of the backend.",
+                key_id, key_triple, self.min, self.max
+            );
+        } else {
+            error!(
+                "The ID of a stored key is not in the key ID range {}-{}, it might collide with the keys of another user of the backend.",
+                self.min, self.max
+            );
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KeyIdRange;
+
+    #[test]
+    fn range_checked() {
+        let bounds = KeyIdRange { min: 1, max: 1000 };
+        let range = KeyIdRange { min: 10, max: 19 };
+        assert!(range.contains(10) && range.contains(19));
+        assert!(!range.contains(9) && !range.contains(20));
+        assert_eq!(range.size(), 10);
+        range.check_within(bounds).unwrap();
+
+        for range in &[
+            KeyIdRange { min: 20, max: 10 },
+            KeyIdRange { min: 0, max: 10 },
+            KeyIdRange { min: 10, max: 1001 },
+        ] {
+            assert!(range.check_within(bounds).is_err());
+        }
+    }
+}
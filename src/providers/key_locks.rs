@@ -0,0 +1,130 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Limit of the operations executed concurrently with the same key
+//!
+//! Some backends misbehave when the same key object is used by several operations at once, for
+//! example PKCS 11 tokens signing concurrently with one private key object. The providers driving
+//! them take a lock on the key before each operation using it: once the limit of concurrent
+//! operations of the key is reached, the next ones wait for the running ones to finish instead of
+//! failing, while the operations with other keys are not delayed.
+use crate::key_info_managers::KeyTriple;
+use log::error;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Condvar, Mutex};
+
+/// Per-key lock map, counting the operations running with each key
+#[derive(Debug)]
+pub struct KeyLocks {
+    max_concurrent_operations: usize,
+    running: Mutex<HashMap<KeyTriple, usize>>,
+    finished: Condvar,
+}
+
+/// Lock on a key, released when dropped
+#[derive(Debug)]
+pub struct KeyLock<'a> {
+    key_locks: &'a KeyLocks,
+    key_triple: KeyTriple,
+}
+
+impl KeyLocks {
+    /// Creates the lock map allowing the given number of concurrent operations per key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the limit is 0.
+    pub fn new(max_concurrent_operations: usize) -> Result<KeyLocks> {
+        if max_concurrent_operations == 0 {
+            error!("The operations per key limit must allow at least one operation.");
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "invalid operations per key limit",
+            ));
+        }
+
+        Ok(KeyLocks {
+            max_concurrent_operations,
+            running: Mutex::new(HashMap::new()),
+            finished: Condvar::new(),
+        })
+    }
+
+    /// Takes a lock on the key, waiting for one of the operations with the key to finish if the
+    /// limit is reached.
+    pub fn lock(&self, key_triple: &KeyTriple) -> KeyLock<'_> {
+        let mut running = self.running.lock().expect("Key locks lock poisoned");
+        while running.get(key_triple).copied().unwrap_or(0) >= self.max_concurrent_operations {
+            running = self
+                .finished
+                .wait(running)
+                .expect("Key locks lock poisoned");
+        }
+        *running.entry(key_triple.clone()).or_insert(0) += 1;
+
+        KeyLock {
+            key_locks: self,
+            key_triple: key_triple.clone(),
+        }
+    }
+}
+
+impl Drop for KeyLock<'_> {
+    fn drop(&mut self) {
+        let mut running = self
+            .key_locks
+            .running
+            .lock()
+            .expect("Key locks lock poisoned");
+        if let Some(count) = running.get_mut(&self.key_triple) {
+            *count -= 1;
+            if *count == 0 {
+                let _ = running.remove(&self.key_triple);
+            }
+        }
+        // The waiting operations can be for different keys, all of them check their own.
+        self.key_locks.finished.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KeyLocks;
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::KeyTriple;
+    use parsec_interface::requests::ProviderID;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn operations_queued() {
+        let key_locks = Arc::new(KeyLocks::new(1).unwrap());
+        let running = Arc::new(AtomicUsize::new(0));
+        let key_triple = KeyTriple::new(
+            ApplicationName::new(String::from("app")),
+            ProviderID::Pkcs11,
+            String::from("key"),
+        );
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let key_locks = key_locks.clone();
+                let running = running.clone();
+                let key_triple = key_triple.clone();
+                thread::spawn(move || {
+                    let _lock = key_locks.lock(&key_triple);
+                    assert_eq!(running.fetch_add(1, Ordering::SeqCst), 0);
+                    thread::sleep(Duration::from_millis(10));
+                    let _ = running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(KeyLocks::new(0).is_err());
+    }
+}
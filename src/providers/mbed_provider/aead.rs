@@ -0,0 +1,124 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::{key_management, MbedProvider};
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::KeyTriple;
+use log::{error, info};
+use parsec_interface::operations::psa_algorithm::{Aead, AeadWithDefaultLengthTag};
+use parsec_interface::operations::{psa_aead_decrypt, psa_aead_encrypt};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use psa_crypto::operations::aead;
+use psa_crypto::types::key;
+
+/// Checks that the algorithm is AES-GCM or ChaCha20-Poly1305, possibly with a shortened tag.
+fn check_alg(alg: Aead) -> Result<()> {
+    let base_alg = match alg {
+        Aead::AeadWithDefaultLengthTag(base_alg) => base_alg,
+        Aead::AeadWithShortenedTag { aead_alg, .. } => aead_alg,
+    };
+    match base_alg {
+        AeadWithDefaultLengthTag::Gcm | AeadWithDefaultLengthTag::Chacha20Poly1305 => Ok(()),
+        _ => {
+            error!("Only AES-GCM and ChaCha20-Poly1305 are supported for AEAD.");
+            Err(ResponseStatus::PsaErrorNotSupported)
+        }
+    }
+}
+
+impl MbedProvider {
+    pub(super) fn psa_aead_encrypt_internal(
+        &self,
+        app_name: ApplicationName,
+        op: psa_aead_encrypt::Operation,
+    ) -> Result<psa_aead_encrypt::Result> {
+        info!("Mbed Provider - AEAD Encrypt");
+        let alg = op.alg;
+        let key_triple = KeyTriple::new(app_name, ProviderID::MbedCrypto, op.key_name.clone());
+        let store_handle = self.key_info_store.read();
+        let (key_id, key_attributes) = key_management::get_key_info(&key_triple, &store_handle)?;
+
+        key_attributes.can_encrypt_message()?;
+        key_attributes.permits_alg(alg.into())?;
+        key_attributes.compatible_with_alg(alg.into())?;
+        check_alg(alg)?;
+
+        let _guard = self
+            .key_handle_mutex
+            .lock()
+            .expect("Grabbing key handle mutex failed");
+
+        let id = key::Id::from_persistent_key_id(key_id);
+        let key_attributes = key::Attributes::from_key_id(id)?;
+        let buffer_size = key_attributes.aead_encrypt_output_size(alg, op.plaintext.len())?;
+        let mut ciphertext = vec![0u8; buffer_size];
+
+        match aead::encrypt(
+            id,
+            alg,
+            &op.nonce,
+            &op.additional_data,
+            &op.plaintext,
+            &mut ciphertext,
+        ) {
+            Ok(size) => {
+                ciphertext.resize(size, 0);
+                Ok(psa_aead_encrypt::Result {
+                    ciphertext: ciphertext.into(),
+                })
+            }
+            Err(error) => {
+                let error = ResponseStatus::from(error);
+                format_error!("AEAD encrypt status: {}", error);
+                Err(error)
+            }
+        }
+    }
+
+    pub(super) fn psa_aead_decrypt_internal(
+        &self,
+        app_name: ApplicationName,
+        op: psa_aead_decrypt::Operation,
+    ) -> Result<psa_aead_decrypt::Result> {
+        info!("Mbed Provider - AEAD Decrypt");
+        let alg = op.alg;
+        let key_triple = KeyTriple::new(app_name, ProviderID::MbedCrypto, op.key_name.clone());
+        let store_handle = self.key_info_store.read();
+        let (key_id, key_attributes) = key_management::get_key_info(&key_triple, &store_handle)?;
+
+        key_attributes.can_decrypt_message()?;
+        key_attributes.permits_alg(alg.into())?;
+        key_attributes.compatible_with_alg(alg.into())?;
+        check_alg(alg)?;
+
+        let _guard = self
+            .key_handle_mutex
+            .lock()
+            .expect("Grabbing key handle mutex failed");
+
+        let id = key::Id::from_persistent_key_id(key_id);
+        let key_attributes = key::Attributes::from_key_id(id)?;
+        let buffer_size = key_attributes.aead_decrypt_output_size(alg, op.ciphertext.len())?;
+        let mut plaintext = vec![0u8; buffer_size];
+
+        match aead::decrypt(
+            id,
+            alg,
+            &op.nonce,
+            &op.additional_data,
+            &op.ciphertext,
+            &mut plaintext,
+        ) {
+            Ok(size) => {
+                plaintext.resize(size, 0);
+                Ok(psa_aead_decrypt::Result {
+                    plaintext: plaintext.into(),
+                })
+            }
+            Err(error) => {
+                let error = ResponseStatus::from(error);
+                format_error!("AEAD decrypt status: {}", error);
+                Err(error)
+            }
+        }
+    }
+}
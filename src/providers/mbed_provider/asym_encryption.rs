@@ -0,0 +1,108 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::{key_management, MbedProvider};
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::KeyTriple;
+use log::{error, info};
+use parsec_interface::operations::psa_algorithm::AsymmetricEncryption;
+use parsec_interface::operations::{psa_asymmetric_decrypt, psa_asymmetric_encrypt};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use psa_crypto::operations::asym_encryption;
+use psa_crypto::types::key;
+
+/// Checks that a salt is only given for OAEP, the only algorithm using one.
+fn check_salt(alg: AsymmetricEncryption, salt: Option<&[u8]>) -> Result<()> {
+    match (alg, salt) {
+        (AsymmetricEncryption::RsaPkcs1v15Crypt, Some(_)) => {
+            error!("A salt can only be used with RSA OAEP.");
+            Err(ResponseStatus::PsaErrorInvalidArgument)
+        }
+        _ => Ok(()),
+    }
+}
+
+impl MbedProvider {
+    pub(super) fn psa_asymmetric_encrypt_internal(
+        &self,
+        app_name: ApplicationName,
+        op: psa_asymmetric_encrypt::Operation,
+    ) -> Result<psa_asymmetric_encrypt::Result> {
+        info!("Mbed Provider - Asym Encrypt");
+        let alg = op.alg;
+        let salt = op.salt.as_ref().map(|salt| &salt[..]);
+        let key_triple = KeyTriple::new(app_name, ProviderID::MbedCrypto, op.key_name.clone());
+        let store_handle = self.key_info_store.read();
+        let (key_id, key_attributes) = key_management::get_key_info(&key_triple, &store_handle)?;
+
+        key_attributes.can_encrypt_message()?;
+        key_attributes.permits_alg(alg.into())?;
+        key_attributes.compatible_with_alg(alg.into())?;
+        check_salt(alg, salt)?;
+
+        let _guard = self
+            .key_handle_mutex
+            .lock()
+            .expect("Grabbing key handle mutex failed");
+
+        let id = key::Id::from_persistent_key_id(key_id);
+        let key_attributes = key::Attributes::from_key_id(id)?;
+        let buffer_size = key_attributes.asymmetric_encrypt_output_size(alg)?;
+        let mut ciphertext = vec![0u8; buffer_size];
+
+        match asym_encryption::encrypt(id, alg, &op.plaintext, salt, &mut ciphertext) {
+            Ok(size) => {
+                ciphertext.resize(size, 0);
+                Ok(psa_asymmetric_encrypt::Result {
+                    ciphertext: ciphertext.into(),
+                })
+            }
+            Err(error) => {
+                let error = ResponseStatus::from(error);
+                format_error!("Encrypt status: {}", error);
+                Err(error)
+            }
+        }
+    }
+
+    pub(super) fn psa_asymmetric_decrypt_internal(
+        &self,
+        app_name: ApplicationName,
+        op: psa_asymmetric_decrypt::Operation,
+    ) -> Result<psa_asymmetric_decrypt::Result> {
+        info!("Mbed Provider - Asym Decrypt");
+        let alg = op.alg;
+        let salt = op.salt.as_ref().map(|salt| &salt[..]);
+        let key_triple = KeyTriple::new(app_name, ProviderID::MbedCrypto, op.key_name.clone());
+        let store_handle = self.key_info_store.read();
+        let (key_id, key_attributes) = key_management::get_key_info(&key_triple, &store_handle)?;
+
+        key_attributes.can_decrypt_message()?;
+        key_attributes.permits_alg(alg.into())?;
+        key_attributes.compatible_with_alg(alg.into())?;
+        check_salt(alg, salt)?;
+
+        let _guard = self
+            .key_handle_mutex
+            .lock()
+            .expect("Grabbing key handle mutex failed");
+
+        let id = key::Id::from_persistent_key_id(key_id);
+        let key_attributes = key::Attributes::from_key_id(id)?;
+        let buffer_size = key_attributes.asymmetric_decrypt_output_size(alg)?;
+        let mut plaintext = vec![0u8; buffer_size];
+
+        match asym_encryption::decrypt(id, alg, &op.ciphertext, salt, &mut plaintext) {
+            Ok(size) => {
+                plaintext.resize(size, 0);
+                Ok(psa_asymmetric_decrypt::Result {
+                    plaintext: plaintext.into(),
+                })
+            }
+            Err(error) => {
+                let error = ResponseStatus::from(error);
+                format_error!("Decrypt status: {}", error);
+                Err(error)
+            }
+        }
+    }
+}
@@ -3,10 +3,14 @@
 use super::{key_management, MbedProvider};
 use crate::authenticators::ApplicationName;
 use crate::key_info_managers::KeyTriple;
-use log::info;
+use log::{error, info};
+use parsec_interface::operations::psa_algorithm::{Algorithm, AsymmetricSignature};
+use parsec_interface::operations::psa_key_attributes::{
+    Attributes, Lifetime, Policy, Type, UsageFlags,
+};
 use parsec_interface::operations::{psa_sign_hash, psa_verify_hash};
 use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
-use psa_crypto::operations::asym_signature;
+use psa_crypto::operations::{asym_signature, key_management as psa_crypto_key_management};
 use psa_crypto::types::key;
 
 impl MbedProvider {
@@ -20,8 +24,12 @@ impl MbedProvider {
         let hash = op.hash;
         let alg = op.alg;
         let key_triple = KeyTriple::new(app_name, ProviderID::MbedCrypto, key_name);
-        let store_handle = self.key_info_store.read().expect("Key store lock poisoned");
-        let key_id = key_management::get_key_id(&key_triple, &*store_handle)?;
+        let store_handle = self.key_info_store.read();
+        let (key_id, key_attributes) = key_management::get_key_info(&key_triple, &store_handle)?;
+
+        key_attributes.can_sign_hash()?;
+        key_attributes.permits_alg(alg.into())?;
+        key_attributes.compatible_with_alg(alg.into())?;
 
         let _guard = self
             .key_handle_mutex
@@ -57,8 +65,12 @@ impl MbedProvider {
         let alg = op.alg;
         let signature = op.signature;
         let key_triple = KeyTriple::new(app_name, ProviderID::MbedCrypto, key_name);
-        let store_handle = self.key_info_store.read().expect("Key store lock poisoned");
-        let key_id = key_management::get_key_id(&key_triple, &*store_handle)?;
+        let store_handle = self.key_info_store.read();
+        let (key_id, key_attributes) = key_management::get_key_info(&key_triple, &store_handle)?;
+
+        key_attributes.can_verify_hash()?;
+        key_attributes.permits_alg(alg.into())?;
+        key_attributes.compatible_with_alg(alg.into())?;
 
         let _guard = self
             .key_handle_mutex
@@ -75,4 +87,76 @@ impl MbedProvider {
             }
         }
     }
+
+    pub(super) fn psa_verify_hash_with_public_key_internal(
+        &self,
+        _app_name: ApplicationName,
+        attributes: Attributes,
+        public_key: Vec<u8>,
+        alg: AsymmetricSignature,
+        hash: Vec<u8>,
+        signature: Vec<u8>,
+    ) -> Result<psa_verify_hash::Result> {
+        info!("Mbed Provider - Asym Verify with Public Key");
+        match attributes.key_type {
+            Type::RsaPublicKey | Type::EccPublicKey { .. } => (),
+            _ => {
+                error!("A public key is needed to verify a signature.");
+                return Err(ResponseStatus::PsaErrorInvalidArgument);
+            }
+        }
+        let attributes = Attributes {
+            lifetime: Lifetime::Volatile,
+            policy: Policy {
+                usage_flags: UsageFlags {
+                    export: false,
+                    copy: false,
+                    cache: false,
+                    encrypt: false,
+                    decrypt: false,
+                    sign_message: false,
+                    verify_message: false,
+                    sign_hash: false,
+                    verify_hash: true,
+                    derive: false,
+                },
+                permitted_algorithms: Algorithm::AsymmetricSignature(alg),
+            },
+            ..attributes
+        };
+
+        let _guard = self
+            .key_handle_mutex
+            .lock()
+            .expect("Grabbing key handle mutex failed");
+
+        let id = match psa_crypto_key_management::import(attributes, None, &public_key) {
+            Ok(id) => id,
+            Err(error) => {
+                let error = ResponseStatus::from(error);
+                format_error!("Import public key status: {}", error);
+                return Err(error);
+            }
+        };
+        let verify_status = asym_signature::verify_hash(id, alg, &hash, &signature);
+
+        // Safety:
+        //   * at this point the provider has been instantiated so Mbed Crypto has been initialized
+        //   * self.key_handle_mutex prevents concurrent accesses
+        //   * the volatile key was imported above and is not referenced anywhere else
+        let destroy_status = unsafe { psa_crypto_key_management::destroy(id) };
+        if let Err(error) = destroy_status {
+            let error = ResponseStatus::from(error);
+            format_error!("Destroy public key status: {}", error);
+        }
+
+        match verify_status {
+            Ok(()) => Ok(psa_verify_hash::Result {}),
+            Err(error) => {
+                let error = ResponseStatus::from(error);
+                format_error!("Verify status: {}", error);
+                Err(error)
+            }
+        }
+    }
 }
@@ -0,0 +1,69 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::{key_management, MbedProvider};
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::KeyTriple;
+use log::{error, info};
+use parsec_interface::operations::psa_algorithm::{Algorithm, KeyAgreement, RawKeyAgreement};
+use parsec_interface::operations::psa_raw_key_agreement;
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use psa_crypto::operations::key_agreement;
+use psa_crypto::types::key;
+
+/// Checks that the algorithm is ECDH, the only key agreement Mbed Crypto supports.
+fn check_alg(alg: RawKeyAgreement) -> Result<()> {
+    match alg {
+        RawKeyAgreement::Ecdh => Ok(()),
+        _ => {
+            error!("Only ECDH is supported for raw key agreement.");
+            Err(ResponseStatus::PsaErrorNotSupported)
+        }
+    }
+}
+
+impl MbedProvider {
+    pub(super) fn psa_raw_key_agreement_internal(
+        &self,
+        app_name: ApplicationName,
+        op: psa_raw_key_agreement::Operation,
+    ) -> Result<psa_raw_key_agreement::Result> {
+        info!("Mbed Provider - Raw Key Agreement");
+        let alg = op.alg;
+        let key_triple = KeyTriple::new(
+            app_name,
+            ProviderID::MbedCrypto,
+            op.private_key_name.clone(),
+        );
+        let store_handle = self.key_info_store.read();
+        let (key_id, key_attributes) = key_management::get_key_info(&key_triple, &store_handle)?;
+
+        key_attributes.can_derive_from()?;
+        key_attributes.permits_alg(Algorithm::KeyAgreement(KeyAgreement::Raw(alg)))?;
+        key_attributes.compatible_with_alg(Algorithm::KeyAgreement(KeyAgreement::Raw(alg)))?;
+        check_alg(alg)?;
+
+        let _guard = self
+            .key_handle_mutex
+            .lock()
+            .expect("Grabbing key handle mutex failed");
+
+        let id = key::Id::from_persistent_key_id(key_id);
+        let key_attributes = key::Attributes::from_key_id(id)?;
+        let buffer_size = key_attributes.raw_key_agreement_output_size(alg)?;
+        let mut shared_secret = vec![0u8; buffer_size];
+
+        match key_agreement::raw_key_agreement(alg, id, &op.peer_key, &mut shared_secret) {
+            Ok(size) => {
+                shared_secret.resize(size, 0);
+                Ok(psa_raw_key_agreement::Result {
+                    shared_secret: shared_secret.into(),
+                })
+            }
+            Err(error) => {
+                let error = ResponseStatus::from(error);
+                format_error!("Raw key agreement status: {}", error);
+                Err(error)
+            }
+        }
+    }
+}
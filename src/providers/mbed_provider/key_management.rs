@@ -3,16 +3,17 @@
 use super::MbedProvider;
 use crate::authenticators::ApplicationName;
 use crate::key_info_managers;
-use crate::key_info_managers::{KeyInfo, KeyTriple, ManageKeyInfo};
+use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple, ManageKeyInfo};
 use log::error;
 use log::{info, warn};
-use parsec_interface::operations::psa_key_attributes::Attributes;
+use parsec_interface::operations::psa_key_attributes::{Attributes, UsageFlags};
 use parsec_interface::operations::{
     psa_destroy_key, psa_export_public_key, psa_generate_key, psa_import_key,
 };
 use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
 use psa_crypto::operations::key_management as psa_crypto_key_management;
 use psa_crypto::types::key;
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
 
 /// Gets a PSA Key ID from the Key Info Manager.
@@ -22,56 +23,114 @@ pub fn get_key_id(
     key_triple: &KeyTriple,
     store_handle: &dyn ManageKeyInfo,
 ) -> Result<key::psa_key_id_t> {
+    let (key_id, _) = get_key_info(key_triple, store_handle)?;
+    Ok(key_id)
+}
+
+/// Gets a PSA Key ID and the attributes of the key from the Key Info Manager.
+pub fn get_key_info(
+    key_triple: &KeyTriple,
+    store_handle: &dyn ManageKeyInfo,
+) -> Result<(key::psa_key_id_t, Attributes)> {
     match store_handle.get(key_triple) {
-        Ok(Some(key_info)) => {
-            if key_info.id.len() == 4 {
-                let mut dst = [0; 4];
-                dst.copy_from_slice(&key_info.id);
-                Ok(u32::from_ne_bytes(dst))
-            } else {
-                format_error!(
-                    "Stored Key ID is not valid.",
-                    ResponseStatus::KeyInfoManagerError
-                );
-                Err(ResponseStatus::KeyInfoManagerError)
-            }
-        }
+        Ok(Some(key_info)) => Ok((key_info_to_id(key_info)?, key_info.attributes)),
         Ok(None) => Err(ResponseStatus::PsaErrorDoesNotExist),
         Err(string) => Err(key_info_managers::to_response_status(string)),
     }
 }
 
-/// Creates a new PSA Key ID and stores it in the Key Info Manager.
+/// Converts the ID stored in the key information to the psa_key_id_t type.
+pub fn key_info_to_id(key_info: &KeyInfo) -> Result<key::psa_key_id_t> {
+    if key_info.id.len() == 4 {
+        let mut dst = [0; 4];
+        dst.copy_from_slice(&key_info.id);
+        Ok(u32::from_ne_bytes(dst))
+    } else {
+        format_error!(
+            "Stored Key ID is not valid.",
+            ResponseStatus::KeyInfoManagerError
+        );
+        Err(ResponseStatus::KeyInfoManagerError)
+    }
+}
+
+/// Creates a new PSA Key ID and stores it in the Key Info Manager as a pending mapping.
+///
+/// The mapping must be committed with `commit_key_id` once the key is created, or aborted with
+/// `abort_key_id` if the creation failed. A mapping still pending when the service starts is rolled
+/// back by the provider.
 fn create_key_id(
     key_triple: KeyTriple,
     key_attributes: Attributes,
     store_handle: &mut dyn ManageKeyInfo,
     max_current_id: &AtomicU32,
+    max_key_id: key::psa_key_id_t,
 ) -> Result<key::psa_key_id_t> {
     // fetch_add adds 1 to the old value and returns the old value, so add 1 to local value for new ID
     let new_key_id = max_current_id.fetch_add(1, Relaxed) + 1;
-    if new_key_id > key::PSA_KEY_ID_USER_MAX {
+    if new_key_id > max_key_id {
         // If storing key failed and no other keys were created in the mean time, it is safe to
         // decrement the key counter.
-        let _ = max_current_id.store(key::PSA_KEY_ID_USER_MAX, Relaxed);
-        error!(
-            "PSA max key ID limit of {} reached",
-            key::PSA_KEY_ID_USER_MAX
-        );
+        let _ = max_current_id.store(max_key_id, Relaxed);
+        error!("Max key ID limit of {} reached", max_key_id);
         return Err(ResponseStatus::PsaErrorInsufficientMemory);
     }
 
     let key_info = KeyInfo {
         id: new_key_id.to_ne_bytes().to_vec(),
         attributes: key_attributes,
+        state: KeyState::Active,
+        created_at: None,
+        counter: None,
+        certificate: None,
+        labels: BTreeMap::new(),
     };
-    match store_handle.insert(key_triple.clone(), key_info) {
+    match store_handle.insert_pending(key_triple, key_info) {
+        Ok(()) => Ok(new_key_id),
+        Err(string) => Err(key_info_managers::to_response_status(string)),
+    }
+}
+
+/// Commits the pending mapping of a key which was created.
+///
+/// If the mapping can not be committed, the key is destroyed and the mapping aborted, so that the
+/// client can create the key again under the same name. The mapping is only left pending, for the
+/// provider to roll it back when it starts again, if the key can not be destroyed.
+///
+/// # Safety
+///
+/// The key handle mutex must be held, as for `psa_destroy_key_internal`.
+unsafe fn commit_key_id(
+    key_triple: &KeyTriple,
+    key_id: key::Id,
+    store_handle: &mut dyn ManageKeyInfo,
+) -> Result<()> {
+    match store_handle.commit(key_triple) {
         Ok(insert_option) => {
             if insert_option.is_some() {
                 warn!("Overwriting Key triple mapping ({})", key_triple);
             }
-            Ok(new_key_id)
+            Ok(())
+        }
+        Err(string) => {
+            format_error!("Failed to commit the mapping of the key", string);
+            let status = key_info_managers::to_response_status(string);
+            if let Err(error) = psa_crypto_key_management::destroy(key_id) {
+                let error = ResponseStatus::from(error);
+                format_error!("Failed to destroy the key whose mapping failed", error);
+                return Err(status);
+            }
+            abort_key_id(key_triple, store_handle)?;
+            Err(status)
         }
+    }
+}
+
+/// Aborts the pending mapping of a key whose creation failed.
+fn abort_key_id(key_triple: &KeyTriple, store_handle: &mut dyn ManageKeyInfo) -> Result<()> {
+    // ID Counter not affected as overhead and extra complication deemed unnecessary
+    match store_handle.abort(key_triple) {
+        Ok(_) => Ok(()),
         Err(string) => Err(key_info_managers::to_response_status(string)),
     }
 }
@@ -84,6 +143,24 @@ fn remove_key_id(key_triple: &KeyTriple, store_handle: &mut dyn ManageKeyInfo) -
     }
 }
 
+/// Returns true if the usage flags only allow usages also allowed by the original ones.
+fn restricts(usage_flags: UsageFlags, original: UsageFlags) -> bool {
+    [
+        (usage_flags.export, original.export),
+        (usage_flags.copy, original.copy),
+        (usage_flags.cache, original.cache),
+        (usage_flags.encrypt, original.encrypt),
+        (usage_flags.decrypt, original.decrypt),
+        (usage_flags.sign_message, original.sign_message),
+        (usage_flags.verify_message, original.verify_message),
+        (usage_flags.sign_hash, original.sign_hash),
+        (usage_flags.verify_hash, original.verify_hash),
+        (usage_flags.derive, original.derive),
+    ]
+    .iter()
+    .all(|&(allowed, originally_allowed)| !allowed || originally_allowed)
+}
+
 pub fn key_info_exists(key_triple: &KeyTriple, store_handle: &dyn ManageKeyInfo) -> Result<bool> {
     store_handle
         .exists(key_triple)
@@ -100,18 +177,16 @@ impl MbedProvider {
         let key_name = op.key_name;
         let key_attributes = op.attributes;
         let key_triple = KeyTriple::new(app_name, ProviderID::MbedCrypto, key_name);
-        let mut store_handle = self
-            .key_info_store
-            .write()
-            .expect("Key store lock poisoned");
-        if key_info_exists(&key_triple, &*store_handle)? {
+        let mut store_handle = self.key_info_store.write();
+        if key_info_exists(&key_triple, &store_handle)? {
             return Err(ResponseStatus::PsaErrorAlreadyExists);
         }
         let key_id = create_key_id(
             key_triple.clone(),
             key_attributes,
-            &mut *store_handle,
+            &mut store_handle,
             &self.id_counter,
+            self.key_id_range.max,
         )?;
 
         let _guard = self
@@ -120,9 +195,13 @@ impl MbedProvider {
             .expect("Grabbing key handle mutex failed");
 
         match psa_crypto_key_management::generate(key_attributes, Some(key_id)) {
-            Ok(_) => Ok(psa_generate_key::Result {}),
+            Ok(id) => {
+                // Safety: the key handle mutex is held.
+                unsafe { commit_key_id(&key_triple, id, &mut store_handle)? };
+                Ok(psa_generate_key::Result {})
+            }
             Err(error) => {
-                remove_key_id(&key_triple, &mut *store_handle)?;
+                abort_key_id(&key_triple, &mut store_handle)?;
                 let error = ResponseStatus::from(error);
                 format_error!("Generate key status: {}", error);
                 Err(error)
@@ -140,18 +219,16 @@ impl MbedProvider {
         let key_attributes = op.attributes;
         let key_data = op.data;
         let key_triple = KeyTriple::new(app_name, ProviderID::MbedCrypto, key_name);
-        let mut store_handle = self
-            .key_info_store
-            .write()
-            .expect("Key store lock poisoned");
-        if key_info_exists(&key_triple, &*store_handle)? {
+        let mut store_handle = self.key_info_store.write();
+        if key_info_exists(&key_triple, &store_handle)? {
             return Err(ResponseStatus::PsaErrorAlreadyExists);
         }
         let key_id = create_key_id(
             key_triple.clone(),
             key_attributes,
-            &mut *store_handle,
+            &mut store_handle,
             &self.id_counter,
+            self.key_id_range.max,
         )?;
 
         let _guard = self
@@ -160,9 +237,13 @@ impl MbedProvider {
             .expect("Grabbing key handle mutex failed");
 
         match psa_crypto_key_management::import(key_attributes, Some(key_id), &key_data[..]) {
-            Ok(_) => Ok(psa_import_key::Result {}),
+            Ok(id) => {
+                // Safety: the key handle mutex is held.
+                unsafe { commit_key_id(&key_triple, id, &mut store_handle)? };
+                Ok(psa_import_key::Result {})
+            }
             Err(error) => {
-                remove_key_id(&key_triple, &mut *store_handle)?;
+                abort_key_id(&key_triple, &mut store_handle)?;
                 let error = ResponseStatus::from(error);
                 format_error!("Import key status: {}", error);
                 Err(error)
@@ -170,6 +251,61 @@ impl MbedProvider {
         }
     }
 
+    pub(super) fn psa_copy_key_internal(
+        &self,
+        app_name: ApplicationName,
+        key_name: String,
+        destination_key_name: String,
+        usage_flags: UsageFlags,
+    ) -> Result<()> {
+        info!("Mbed Provider - Copy Key");
+        let key_triple = KeyTriple::new(app_name.clone(), ProviderID::MbedCrypto, key_name);
+        let destination_key_triple =
+            KeyTriple::new(app_name, ProviderID::MbedCrypto, destination_key_name);
+        let mut store_handle = self.key_info_store.write();
+        let (key_id, key_attributes) = get_key_info(&key_triple, &store_handle)?;
+        if !key_attributes.policy.usage_flags.copy {
+            error!("The key is not permitted to be copied.");
+            return Err(ResponseStatus::PsaErrorNotPermitted);
+        }
+        if !restricts(usage_flags, key_attributes.policy.usage_flags) {
+            error!("The copy of a key can not be allowed usages the key is not allowed.");
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
+        if key_info_exists(&destination_key_triple, &store_handle)? {
+            return Err(ResponseStatus::PsaErrorAlreadyExists);
+        }
+        let mut destination_attributes = key_attributes;
+        destination_attributes.policy.usage_flags = usage_flags;
+        let destination_key_id = create_key_id(
+            destination_key_triple.clone(),
+            destination_attributes,
+            &mut store_handle,
+            &self.id_counter,
+            self.key_id_range.max,
+        )?;
+
+        let _guard = self
+            .key_handle_mutex
+            .lock()
+            .expect("Grabbing key handle mutex failed");
+
+        let id = key::Id::from_persistent_key_id(key_id);
+        match psa_crypto_key_management::copy(id, destination_attributes, Some(destination_key_id))
+        {
+            Ok(copy_id) => {
+                // Safety: the key handle mutex is held.
+                unsafe { commit_key_id(&destination_key_triple, copy_id, &mut store_handle) }
+            }
+            Err(error) => {
+                abort_key_id(&destination_key_triple, &mut store_handle)?;
+                let error = ResponseStatus::from(error);
+                format_error!("Copy key status: {}", error);
+                Err(error)
+            }
+        }
+    }
+
     pub(super) fn psa_export_public_key_internal(
         &self,
         app_name: ApplicationName,
@@ -178,8 +314,8 @@ impl MbedProvider {
         info!("Mbed Provider - Export Public Key");
         let key_name = op.key_name;
         let key_triple = KeyTriple::new(app_name, ProviderID::MbedCrypto, key_name);
-        let store_handle = self.key_info_store.read().expect("Key store lock poisoned");
-        let key_id = get_key_id(&key_triple, &*store_handle)?;
+        let store_handle = self.key_info_store.read();
+        let key_id = get_key_id(&key_triple, &store_handle)?;
 
         let _guard = self
             .key_handle_mutex
@@ -205,11 +341,8 @@ impl MbedProvider {
         info!("Mbed Provider - Destroy Key");
         let key_name = op.key_name;
         let key_triple = KeyTriple::new(app_name, ProviderID::MbedCrypto, key_name);
-        let mut store_handle = self
-            .key_info_store
-            .write()
-            .expect("Key store lock poisoned");
-        let key_id = get_key_id(&key_triple, &*store_handle)?;
+        let mut store_handle = self.key_info_store.write();
+        let key_id = get_key_id(&key_triple, &store_handle)?;
 
         let _guard = self
             .key_handle_mutex
@@ -220,7 +353,7 @@ impl MbedProvider {
         // Safety:
         //   * at this point the provider has been instantiated so Mbed Crypto has been initialized
         //   * self.key_handle_mutex prevents concurrent accesses
-        //   * self.key_slot_semaphore prevents overflowing key slots
+        //   * the concurrency limit of the backend handler prevents overflowing key slots
         let id = key::Id::from_persistent_key_id(key_id);
         unsafe {
             destroy_key_status = psa_crypto_key_management::destroy(id);
@@ -228,7 +361,7 @@ impl MbedProvider {
 
         match destroy_key_status {
             Ok(()) => {
-                remove_key_id(&key_triple, &mut *store_handle)?;
+                remove_key_id(&key_triple, &mut store_handle)?;
                 Ok(psa_destroy_key::Result {})
             }
             Err(error) => {
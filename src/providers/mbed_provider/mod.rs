@@ -1,47 +1,78 @@
 // Copyright 2019 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
-use super::Provide;
+//! Mbed Crypto provider
+//!
+//! This provider is a software implementation of the PSA Crypto API, using Mbed Crypto through
+//! the `psa-crypto` bindings. Mbed Crypto is linked statically in the service, in the version
+//! selected when building `psa-crypto`, and keeps a global state initialised once per process.
+//!
+//! Two versions of Mbed Crypto can hence not be used by the same service: they export the same C
+//! symbols and would share their global state. During a transition between versions, a second
+//! service built against the other version can be run instead, its provider being made available
+//! to the clients of the first service through the remote provider.
+use super::key_id_range::KeyIdRange;
+use super::{Provide, StorageSnapshot};
 use crate::authenticators::ApplicationName;
-use crate::key_info_managers::{KeyTriple, ManageKeyInfo};
+use crate::key_info_managers::key_info_store::KeyInfoStore;
+use crate::key_info_managers::{self, KeyInfo, KeyTriple, ManageKeyInfo, MappingHealth};
 use derivative::Derivative;
-use log::{error, trace};
+use log::{error, trace, warn};
 use parsec_interface::operations::list_providers::ProviderInfo;
+use parsec_interface::operations::psa_algorithm::AsymmetricSignature;
+use parsec_interface::operations::psa_key_attributes::{Attributes, UsageFlags};
 use parsec_interface::operations::{
-    psa_destroy_key, psa_export_public_key, psa_generate_key, psa_import_key, psa_sign_hash,
-    psa_verify_hash,
+    psa_aead_decrypt, psa_aead_encrypt, psa_asymmetric_decrypt, psa_asymmetric_encrypt,
+    psa_destroy_key, psa_export_public_key, psa_generate_key, psa_hash_compare, psa_hash_compute,
+    psa_import_key, psa_raw_key_agreement, psa_sign_hash, psa_verify_hash,
 };
 use parsec_interface::requests::{Opcode, ProviderID, ResponseStatus, Result};
+use psa_crypto::operations::key_management as psa_crypto_key_management;
 use psa_crypto::types::{key, status};
 use std::collections::HashSet;
+use std::fs;
 use std::io::{Error, ErrorKind};
 use std::sync::{
     atomic::{AtomicU32, Ordering::Relaxed},
-    Arc, Mutex, RwLock,
+    Arc, Mutex,
 };
 use uuid::Uuid;
 
+mod aead;
+mod asym_encryption;
 mod asym_sign;
+mod hash;
+mod key_agreement;
 #[allow(dead_code)]
 mod key_management;
 
-const SUPPORTED_OPCODES: [Opcode; 6] = [
+// Suffix of the files in which Mbed Crypto stores the persistent keys, in the working directory of
+// the service.
+const ITS_FILE_SUFFIX: &str = ".psa_its";
+
+const SUPPORTED_OPCODES: [Opcode; 13] = [
     Opcode::PsaGenerateKey,
     Opcode::PsaDestroyKey,
     Opcode::PsaSignHash,
     Opcode::PsaVerifyHash,
     Opcode::PsaImportKey,
     Opcode::PsaExportPublicKey,
+    Opcode::PsaAsymmetricEncrypt,
+    Opcode::PsaAsymmetricDecrypt,
+    Opcode::PsaAeadEncrypt,
+    Opcode::PsaAeadDecrypt,
+    Opcode::PsaRawKeyAgreement,
+    Opcode::PsaHashCompute,
+    Opcode::PsaHashCompare,
 ];
 
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct MbedProvider {
-    // When calling write on a reference of key_info_store, a type
-    // std::sync::RwLockWriteGuard<dyn ManageKeyInfo + Send + Sync> is returned. We need to use the
-    // dereference operator (*) to access the inner type dyn ManageKeyInfo + Send + Sync and then
-    // reference it to match with the method prototypes.
+    // Calling read or write on key_info_store returns a snapshot or a write guard, both
+    // implementing ManageKeyInfo. They need to be referenced to match with the method
+    // prototypes.
     #[derivative(Debug = "ignore")]
-    key_info_store: Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>,
+    key_info_store: Arc<KeyInfoStore>,
     // Calls to `psa_open_key`, `psa_generate_key` and `psa_destroy_key` are not thread safe - the slot
     // allocation mechanism in Mbed Crypto can return the same key slot for overlapping calls.
     // `key_handle_mutex` is use as a way of securing access to said operations among the threads.
@@ -50,43 +81,65 @@ pub struct MbedProvider {
     key_handle_mutex: Mutex<()>,
 
     // Holds the highest ID of all keys (including destroyed keys). New keys will receive an ID of
-    // id_counter + 1. Once id_counter reaches the highest allowed ID, no more keys can be created.
+    // id_counter + 1. Once id_counter reaches the highest ID of the range, no more keys can be
+    // created.
     id_counter: AtomicU32,
+    key_id_range: KeyIdRange,
+    mapping_health: MappingHealth,
 }
 
+/// Range of the key IDs allocated by default, all the IDs available to the users of Mbed Crypto
+pub const DEFAULT_KEY_ID_RANGE: KeyIdRange = KeyIdRange {
+    min: key::PSA_KEY_ID_USER_MIN,
+    max: key::PSA_KEY_ID_USER_MAX,
+};
+
+// Number of key slots of Mbed Crypto (`MBEDTLS_PSA_KEY_SLOT_COUNT`). Each operation opens the key
+// it uses in a slot, so that more concurrent operations would fail with
+// `PsaErrorInsufficientMemory`.
+const KEY_SLOT_COUNT: usize = 32;
+
 impl MbedProvider {
     /// Creates and initialise a new instance of MbedProvider.
     /// Checks if there are not more keys stored in the Key Info Manager than in the MbedProvider and
-    /// if there, delete them. Adds Key IDs currently in use in the local IDs store.
-    /// Returns `None` if the initialisation failed.
-    fn new(key_info_store: Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>) -> Option<MbedProvider> {
+    /// if there, delete them unless `purge_orphaned_mappings` is false. Rolls back the pending
+    /// mappings of the keys whose creation was interrupted, destroying the keys if they were
+    /// created. Adds Key IDs currently in use in the local IDs store.
+    /// Returns `None` if the initialisation failed or if stored keys are not in the key ID range.
+    fn new(
+        key_info_store: Arc<KeyInfoStore>,
+        key_id_range: KeyIdRange,
+        purge_orphaned_mappings: bool,
+    ) -> Option<MbedProvider> {
         // Safety: this function should be called before any of the other Mbed Crypto functions
         // are.
         if let Err(error) = psa_crypto::init() {
             format_error!("Error when initialising Mbed Crypto", error);
             return None;
         }
-        let mbed_provider = MbedProvider {
+        let mut mbed_provider = MbedProvider {
             key_info_store,
             key_handle_mutex: Mutex::new(()),
-            id_counter: AtomicU32::new(key::PSA_KEY_ID_USER_MIN),
+            id_counter: AtomicU32::new(key_id_range.min - 1),
+            key_id_range,
+            mapping_health: Default::default(),
         };
-        let mut max_key_id: key::psa_key_id_t = key::PSA_KEY_ID_USER_MIN;
+        let mut max_key_id: key::psa_key_id_t = key_id_range.min - 1;
+        let mut committed_key_ids = HashSet::new();
+        let mut mapping_health = MappingHealth::default();
         {
             // The local scope allows to drop store_handle and local_ids_handle in order to return
             // the mbed_provider.
-            let mut store_handle = mbed_provider
-                .key_info_store
-                .write()
-                .expect("Key store lock poisoned");
+            let mut store_handle = mbed_provider.key_info_store.write();
             let mut to_remove: Vec<KeyTriple> = Vec::new();
             // Go through all MbedProvider key triple to key info mappings and check if they are still
             // present.
             // Delete those who are not present and add to the local_store the ones present.
             match store_handle.get_all(ProviderID::MbedCrypto) {
                 Ok(key_triples) => {
+                    mapping_health.checked = key_triples.len();
                     for key_triple in key_triples.iter().cloned() {
-                        let key_id = match key_management::get_key_id(key_triple, &*store_handle) {
+                        let key_id = match key_management::get_key_id(key_triple, &store_handle) {
                             Ok(key_id) => key_id,
                             Err(response_status) => {
                                 error!("Error getting the Key ID for triple:\n{}\n(error: {}), continuing...", key_triple, response_status);
@@ -94,15 +147,21 @@ impl MbedProvider {
                                 continue;
                             }
                         };
+                        if !key_id_range.check_stored_id(key_triple, key_id) {
+                            return None;
+                        }
+                        let _ = committed_key_ids.insert(key_id);
 
                         let pc_key_id = key::Id::from_persistent_key_id(key_id);
                         match key::Attributes::from_key_id(pc_key_id) {
-                            Ok(_) => {
-                                if key_id > max_key_id {
-                                    max_key_id = key_id;
+                            Ok(_) => (),
+                            Err(status::Error::DoesNotExist) => {
+                                to_remove.push(key_triple.clone());
+                                // The IDs of the orphaned mappings kept are not given to new keys.
+                                if purge_orphaned_mappings {
+                                    continue;
                                 }
                             }
-                            Err(status::Error::DoesNotExist) => to_remove.push(key_triple.clone()),
                             Err(e) => {
                                 format_error!(
                                     "Error {} when opening a persistent Mbed Crypto key.",
@@ -111,6 +170,9 @@ impl MbedProvider {
                                 return None;
                             }
                         };
+                        if key_id > max_key_id {
+                            max_key_id = key_id;
+                        }
                     }
                 }
                 Err(string) => {
@@ -118,14 +180,79 @@ impl MbedProvider {
                     return None;
                 }
             };
+            let pending: Vec<(KeyTriple, KeyInfo)> =
+                match store_handle.get_all_pending(ProviderID::MbedCrypto) {
+                    Ok(pending) => pending
+                        .into_iter()
+                        .map(|(key_triple, key_info)| (key_triple.clone(), key_info.clone()))
+                        .collect(),
+                    Err(string) => {
+                        error!("Key Info Manager error: {}", string);
+                        return None;
+                    }
+                };
+            for (key_triple, key_info) in pending.iter() {
+                // The pending mappings are not covered by the integrity tag: a key is only
+                // destroyed if its ID is one this provider allocates and no other key uses.
+                match key_management::key_info_to_id(key_info) {
+                    Ok(key_id)
+                        if key_id_range.contains(key_id) && !committed_key_ids.contains(&key_id) =>
+                    {
+                        let pc_key_id = key::Id::from_persistent_key_id(key_id);
+                        if key::Attributes::from_key_id(pc_key_id).is_ok() {
+                            // Safety:
+                            //   * Mbed Crypto has been initialized above
+                            //   * the provider is not shared with other threads yet
+                            if let Err(e) =
+                                unsafe { psa_crypto_key_management::destroy(pc_key_id) }
+                            {
+                                format_error!(
+                                    "Failed to destroy a key whose creation was interrupted",
+                                    e
+                                );
+                                return None;
+                            }
+                        }
+                        // The ID is not given to a new key in case the key exists but could not
+                        // be opened.
+                        if key_id > max_key_id {
+                            max_key_id = key_id;
+                        }
+                    }
+                    _ => warn!("The key of a pending mapping can not be destroyed safely, only the mapping is rolled back."),
+                }
+                if let Err(string) = store_handle.abort(key_triple) {
+                    error!("Key Info Manager error: {}", string);
+                    return None;
+                }
+                mapping_health.rolled_back += 1;
+            }
+            mapping_health.orphaned = to_remove.len();
+            if !purge_orphaned_mappings {
+                to_remove.clear();
+            }
             for key_triple in to_remove.iter() {
                 if let Err(string) = store_handle.remove(key_triple) {
                     error!("Key Info Manager error: {}", string);
                     return None;
                 }
+                mapping_health.purged += 1;
             }
         }
+        if mapping_health.orphaned > mapping_health.purged {
+            warn!(
+                "{} mappings of the Key Info Manager do not have a key in Mbed Crypto and were kept.",
+                mapping_health.orphaned - mapping_health.purged
+            );
+        }
+        if mapping_health.rolled_back > 0 {
+            warn!(
+                "Rolled back the creation of {} keys interrupted before their mapping was committed.",
+                mapping_health.rolled_back
+            );
+        }
         mbed_provider.id_counter.store(max_key_id, Relaxed);
+        mbed_provider.mapping_health = mapping_health;
         Some(mbed_provider)
     }
 }
@@ -145,6 +272,55 @@ impl Provide for MbedProvider {
         }, SUPPORTED_OPCODES.iter().copied().collect()))
     }
 
+    fn mapping_health(&self) -> Option<MappingHealth> {
+        trace!("mapping_health ingress");
+        Some(self.mapping_health)
+    }
+
+    fn max_concurrency(&self) -> Option<usize> {
+        trace!("max_concurrency ingress");
+        Some(KEY_SLOT_COUNT)
+    }
+
+    fn key_exists(&self, key_triple: &KeyTriple) -> Result<bool> {
+        trace!("key_exists ingress");
+        let key_id = key_management::get_key_id(key_triple, &self.key_info_store.read())?;
+        let _guard = self
+            .key_handle_mutex
+            .lock()
+            .expect("Grabbing key handle mutex failed");
+        match key::Attributes::from_key_id(key::Id::from_persistent_key_id(key_id)) {
+            Ok(_) => Ok(true),
+            Err(status::Error::DoesNotExist) => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn snapshot_storage(&self) -> Result<StorageSnapshot> {
+        trace!("snapshot_storage ingress");
+        // The keys are only created and destroyed while the Key Info Manager is locked for
+        // writing: holding the lock keeps the files of Mbed Crypto consistent with the mappings.
+        let store_handle = self.key_info_store.write();
+        let mut mappings = Vec::new();
+        for key_triple in store_handle
+            .get_all(ProviderID::MbedCrypto)
+            .map_err(key_info_managers::to_response_status)?
+        {
+            if let Some(key_info) = store_handle
+                .get(key_triple)
+                .map_err(key_info_managers::to_response_status)?
+            {
+                mappings.push((key_triple.clone(), key_info.clone()));
+            }
+        }
+        let files = its_files().map_err(|e| {
+            format_error!("Failed to read the files of Mbed Crypto", e);
+            ResponseStatus::PsaErrorStorageFailure
+        })?;
+
+        Ok(StorageSnapshot { files, mappings })
+    }
+
     fn psa_generate_key(
         &self,
         app_name: ApplicationName,
@@ -198,35 +374,161 @@ impl Provide for MbedProvider {
         trace!("psa_verify_hash ingress");
         self.psa_verify_hash_internal(app_name, op)
     }
+
+    fn psa_verify_hash_with_public_key(
+        &self,
+        app_name: ApplicationName,
+        attributes: Attributes,
+        public_key: Vec<u8>,
+        alg: AsymmetricSignature,
+        hash: Vec<u8>,
+        signature: Vec<u8>,
+    ) -> Result<psa_verify_hash::Result> {
+        trace!("psa_verify_hash_with_public_key ingress");
+        self.psa_verify_hash_with_public_key_internal(
+            app_name, attributes, public_key, alg, hash, signature,
+        )
+    }
+
+    fn psa_asymmetric_encrypt(
+        &self,
+        app_name: ApplicationName,
+        op: psa_asymmetric_encrypt::Operation,
+    ) -> Result<psa_asymmetric_encrypt::Result> {
+        trace!("psa_asymmetric_encrypt ingress");
+        self.psa_asymmetric_encrypt_internal(app_name, op)
+    }
+
+    fn psa_asymmetric_decrypt(
+        &self,
+        app_name: ApplicationName,
+        op: psa_asymmetric_decrypt::Operation,
+    ) -> Result<psa_asymmetric_decrypt::Result> {
+        trace!("psa_asymmetric_decrypt ingress");
+        self.psa_asymmetric_decrypt_internal(app_name, op)
+    }
+
+    fn psa_aead_encrypt(
+        &self,
+        app_name: ApplicationName,
+        op: psa_aead_encrypt::Operation,
+    ) -> Result<psa_aead_encrypt::Result> {
+        trace!("psa_aead_encrypt ingress");
+        self.psa_aead_encrypt_internal(app_name, op)
+    }
+
+    fn psa_aead_decrypt(
+        &self,
+        app_name: ApplicationName,
+        op: psa_aead_decrypt::Operation,
+    ) -> Result<psa_aead_decrypt::Result> {
+        trace!("psa_aead_decrypt ingress");
+        self.psa_aead_decrypt_internal(app_name, op)
+    }
+
+    fn psa_raw_key_agreement(
+        &self,
+        app_name: ApplicationName,
+        op: psa_raw_key_agreement::Operation,
+    ) -> Result<psa_raw_key_agreement::Result> {
+        trace!("psa_raw_key_agreement ingress");
+        self.psa_raw_key_agreement_internal(app_name, op)
+    }
+
+    fn psa_copy_key(
+        &self,
+        app_name: ApplicationName,
+        key_name: String,
+        destination_key_name: String,
+        usage_flags: UsageFlags,
+    ) -> Result<()> {
+        trace!("psa_copy_key ingress");
+        self.psa_copy_key_internal(app_name, key_name, destination_key_name, usage_flags)
+    }
+
+    fn psa_hash_compute(
+        &self,
+        op: psa_hash_compute::Operation,
+    ) -> Result<psa_hash_compute::Result> {
+        trace!("psa_hash_compute ingress");
+        self.psa_hash_compute_internal(op)
+    }
+
+    fn psa_hash_compare(
+        &self,
+        op: psa_hash_compare::Operation,
+    ) -> Result<psa_hash_compare::Result> {
+        trace!("psa_hash_compare ingress");
+        self.psa_hash_compare_internal(op)
+    }
+}
+
+// Reads the files of Mbed Crypto storing the persistent keys, sorted by name.
+fn its_files() -> std::io::Result<Vec<(String, Vec<u8>)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(".")? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.ends_with(ITS_FILE_SUFFIX) && entry.file_type()?.is_file() {
+            files.push((name, fs::read(entry.path())?));
+        }
+    }
+    files.sort();
+
+    Ok(files)
 }
 
 #[derive(Default, Derivative)]
 #[derivative(Debug)]
 pub struct MbedProviderBuilder {
     #[derivative(Debug = "ignore")]
-    key_info_store: Option<Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>>,
+    key_info_store: Option<Arc<KeyInfoStore>>,
+    key_id_range: Option<KeyIdRange>,
+    purge_orphaned_mappings: Option<bool>,
 }
 
 impl MbedProviderBuilder {
     pub fn new() -> MbedProviderBuilder {
         MbedProviderBuilder {
             key_info_store: None,
+            key_id_range: None,
+            purge_orphaned_mappings: None,
         }
     }
 
-    pub fn with_key_info_store(
+    pub fn with_key_info_store(mut self, key_info_store: Arc<KeyInfoStore>) -> MbedProviderBuilder {
+        self.key_info_store = Some(key_info_store);
+
+        self
+    }
+
+    /// Sets the range in which the IDs of the new keys are allocated, defaults to
+    /// `DEFAULT_KEY_ID_RANGE`.
+    pub fn with_key_id_range(mut self, key_id_range: Option<KeyIdRange>) -> MbedProviderBuilder {
+        self.key_id_range = key_id_range;
+
+        self
+    }
+
+    /// Sets if the mappings whose key does not exist are removed when the provider starts,
+    /// defaults to true.
+    pub fn with_purge_orphaned_mappings(
         mut self,
-        key_info_store: Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>,
+        purge_orphaned_mappings: Option<bool>,
     ) -> MbedProviderBuilder {
-        self.key_info_store = Some(key_info_store);
+        self.purge_orphaned_mappings = purge_orphaned_mappings;
 
         self
     }
 
     pub fn build(self) -> std::io::Result<MbedProvider> {
+        let key_id_range = self.key_id_range.unwrap_or(DEFAULT_KEY_ID_RANGE);
+        key_id_range.check_within(DEFAULT_KEY_ID_RANGE)?;
         MbedProvider::new(
             self.key_info_store
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing key info store"))?,
+            key_id_range,
+            self.purge_orphaned_mappings.unwrap_or(true),
         )
         .ok_or_else(|| {
             Error::new(
@@ -6,12 +6,24 @@
 //! are the real implementors of the operations that Parsec claims to support. They map to
 //! functionality in the underlying hardware which allows the PSA Crypto operations to be
 //! backed by a hardware root of trust.
+//!
+//! Each provider is identified by a `ProviderID` which is part of the wire protocol header of the
+//! requests. Adding a new kind of provider, for example one delegating the operations to an Intel
+//! SGX or AMD SEV-SNP enclave, hence first requires an ID to be assigned to it in the interface
+//! crate, so that clients can address it. The same goes for providers driving secure elements
+//! without a PKCS 11 layer directly, such as the NXP SE050 and SE051 through the Plug & Trust
+//! middleware.
+use crate::utils::dependency_probe::DependencyProbeConfig;
+use key_id_range::KeyIdRange;
 use log::trace;
 use parsec_interface::requests::{Opcode, ProviderID};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 
 pub mod core_provider;
+pub mod deadline;
+pub mod key_id_range;
+pub mod key_locks;
 
 #[cfg(feature = "pkcs11-provider")]
 pub mod pkcs11_provider;
@@ -22,6 +34,9 @@ pub mod mbed_provider;
 #[cfg(feature = "tpm-provider")]
 pub mod tpm_provider;
 
+#[cfg(feature = "remote-provider")]
+pub mod remote_provider;
+
 #[derive(Deserialize, Debug)]
 // For providers configs in parsec config.toml we use a format similar
 // to the one described in the Internally Tagged Enum representation
@@ -31,59 +46,174 @@ pub mod tpm_provider;
 pub enum ProviderConfig {
     MbedCrypto {
         key_info_manager: String,
+        key_id_range: Option<KeyIdRange>,
+        purge_orphaned_mappings: Option<bool>,
+        wait_for: Option<DependencyProbeConfig>,
+        max_concurrency: Option<usize>,
     },
     Pkcs11 {
         key_info_manager: String,
         library_path: String,
         slot_number: usize,
         user_pin: Option<String>,
+        library_sha256: Option<String>,
+        key_id_range: Option<KeyIdRange>,
+        purge_orphaned_mappings: Option<bool>,
+        offline_verify: Option<bool>,
+        public_key_cache_capacity: Option<usize>,
+        public_key_cache_eviction: Option<EvictionPolicy>,
+        max_concurrent_operations_per_key: Option<usize>,
+        wait_for: Option<DependencyProbeConfig>,
+        max_concurrency: Option<usize>,
     },
     Tpm {
         key_info_manager: String,
         tcti: String,
         owner_hierarchy_auth: String,
+        hierarchy: Option<String>,
+        trusted_tpm_path: Option<bool>,
+        root_key_digest: Option<String>,
+        wait_for: Option<DependencyProbeConfig>,
+        max_concurrency: Option<usize>,
+    },
+    Remote {
+        socket_path: String,
+        remote_provider_type: String,
+        app_name_prefix: Option<String>,
+        timeout: Option<u64>,
+        wait_for: Option<DependencyProbeConfig>,
+        max_concurrency: Option<usize>,
     },
 }
 
-use self::ProviderConfig::{MbedCrypto, Pkcs11, Tpm};
+use self::ProviderConfig::{MbedCrypto, Pkcs11, Remote, Tpm};
+
+/// What a full cache of a provider does with a new entry
+#[derive(Copy, Clone, Deserialize, Debug, PartialEq)]
+pub enum EvictionPolicy {
+    /// Evict the entry used the least recently
+    LeastRecentlyUsed,
+    /// Keep the cached entries and do not cache the new one
+    KeepExisting,
+}
+
+/// Usage of the cache of a provider
+#[derive(Copy, Clone, Serialize, Debug, PartialEq)]
+pub struct CacheUsage {
+    /// Number of entries cached
+    pub entries: usize,
+    /// Maximum number of entries
+    pub capacity: usize,
+    /// Number of entries evicted, to make room or to release memory
+    pub evictions: u64,
+}
+
+/// Copy of the files in which a provider stores its keys, taken with the mappings of its Key Info
+/// Manager while no key could be created or destroyed
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StorageSnapshot {
+    /// Names and contents of the files of the provider
+    pub files: Vec<(String, Vec<u8>)>,
+    /// Mappings of the keys of the provider
+    pub mappings: Vec<(KeyTriple, KeyInfo)>,
+}
+
+/// Gets the ID of the provider with the given type, as named by the `provider_type` field of the
+/// provider configurations.
+pub fn provider_id_from_type(provider_type: &str) -> Option<ProviderID> {
+    match provider_type {
+        "MbedCrypto" => Some(ProviderID::MbedCrypto),
+        "Pkcs11" => Some(ProviderID::Pkcs11),
+        "Tpm" => Some(ProviderID::Tpm),
+        _ => None,
+    }
+}
 
 impl ProviderConfig {
-    pub fn key_info_manager(&self) -> &String {
+    /// Gets the name of the key info manager of the provider, if it stores keys locally.
+    pub fn key_info_manager(&self) -> Option<&String> {
         match *self {
             MbedCrypto {
                 ref key_info_manager,
                 ..
-            } => key_info_manager,
+            } => Some(key_info_manager),
             Pkcs11 {
                 ref key_info_manager,
                 ..
-            } => key_info_manager,
+            } => Some(key_info_manager),
             Tpm {
                 ref key_info_manager,
                 ..
-            } => key_info_manager,
+            } => Some(key_info_manager),
+            Remote { .. } => None,
+        }
+    }
+
+    /// Gets the dependencies the provider waits for when it is built, if configured.
+    pub fn wait_for(&self) -> Option<&DependencyProbeConfig> {
+        match *self {
+            MbedCrypto { ref wait_for, .. }
+            | Pkcs11 { ref wait_for, .. }
+            | Tpm { ref wait_for, .. }
+            | Remote { ref wait_for, .. } => wait_for.as_ref(),
+        }
+    }
+
+    /// Gets the number of operations the provider executes concurrently, if configured.
+    pub fn max_concurrency(&self) -> Option<usize> {
+        match *self {
+            MbedCrypto {
+                max_concurrency, ..
+            }
+            | Pkcs11 {
+                max_concurrency, ..
+            }
+            | Tpm {
+                max_concurrency, ..
+            }
+            | Remote {
+                max_concurrency, ..
+            } => max_concurrency,
         }
     }
-    pub fn provider_id(&self) -> ProviderID {
+
+    /// Gets the ID of the provider. Remote providers take the ID of the provider they forward the
+    /// operations to, or `None` if its type is unknown.
+    pub fn provider_id(&self) -> Option<ProviderID> {
         match *self {
-            MbedCrypto { .. } => ProviderID::MbedCrypto,
-            Pkcs11 { .. } => ProviderID::Pkcs11,
-            Tpm { .. } => ProviderID::Tpm,
+            MbedCrypto { .. } => Some(ProviderID::MbedCrypto),
+            Pkcs11 { .. } => Some(ProviderID::Pkcs11),
+            Tpm { .. } => Some(ProviderID::Tpm),
+            Remote {
+                ref remote_provider_type,
+                ..
+            } => provider_id_from_type(remote_provider_type),
         }
     }
 }
 
 use crate::authenticators::ApplicationName;
+use crate::back::platform_evidence::Quote;
+use crate::key_info_managers::{Compaction, KeyInfo, KeyTriple, MappingHealth};
+use parsec_interface::operations::psa_algorithm::AsymmetricSignature;
+use parsec_interface::operations::psa_key_attributes::{Attributes, UsageFlags};
 use parsec_interface::operations::{
-    list_opcodes, list_providers, ping, psa_destroy_key, psa_export_public_key, psa_generate_key,
-    psa_import_key, psa_sign_hash, psa_verify_hash,
+    delete_client, list_authenticators, list_clients, list_keys, list_opcodes, list_providers,
+    ping, psa_aead_decrypt, psa_aead_encrypt, psa_asymmetric_decrypt, psa_asymmetric_encrypt,
+    psa_destroy_key, psa_export_public_key, psa_generate_key, psa_hash_compare, psa_hash_compute,
+    psa_import_key, psa_raw_key_agreement, psa_sign_hash, psa_verify_hash,
 };
 use parsec_interface::requests::{ResponseStatus, Result};
+use zeroize::Zeroizing;
 
 /// Provider interface for servicing client operations
 ///
 /// Definition of the interface that a provider must implement to
 /// be linked into the service through a backend handler.
+///
+/// Each operation is executed with a deadline, which the providers get with `deadline::current`
+/// and must check in their loops which can last, such as retries, to give the operation up once
+/// it expired or was cancelled.
 pub trait Provide {
     /// Return a description of the current provider.
     ///
@@ -105,6 +235,118 @@ pub trait Provide {
         Err(ResponseStatus::PsaErrorNotSupported)
     }
 
+    /// Get the usage of the cache of the provider, if it has one.
+    fn cache_usage(&self) -> Option<CacheUsage> {
+        trace!("cache_usage ingress");
+        None
+    }
+
+    /// Get the result of the reconciliation of the mappings of the provider with its keys, if it
+    /// checked them when it started.
+    fn mapping_health(&self) -> Option<MappingHealth> {
+        trace!("mapping_health ingress");
+        None
+    }
+
+    /// Check that the provider still holds the key of a mapping. Providers whose key information
+    /// is the key itself, or which can not tell, do not support it.
+    fn key_exists(&self, _key_triple: &KeyTriple) -> Result<bool> {
+        trace!("key_exists ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Compact the Key Info Managers, returning what was reclaimed. Only the Core Provider has
+    /// access to all of them.
+    fn compact_key_stores(&self) -> Result<Compaction> {
+        trace!("compact_key_stores ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Copy the files in which the provider stores its keys along with the mappings of its Key
+    /// Info Manager, consistently with each other. Only providers keeping their keys in files can.
+    fn snapshot_storage(&self) -> Result<StorageSnapshot> {
+        trace!("snapshot_storage ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Evict part of the cached entries to release memory, called when the requests of the
+    /// provider exceed their soft memory limit.
+    fn shrink_caches(&self) {
+        trace!("shrink_caches ingress");
+    }
+
+    /// Get the number of operations the provider can execute concurrently, if it is limited. The
+    /// backend handler makes the operations above it wait.
+    fn max_concurrency(&self) -> Option<usize> {
+        trace!("max_concurrency ingress");
+        None
+    }
+
+    /// Return true if the capabilities of the provider, as given by `describe`, may have changed
+    /// since they were discovered, for example because its backend was replaced.
+    fn capabilities_stale(&self) -> bool {
+        trace!("capabilities_stale ingress");
+        false
+    }
+
+    /// Discover the capabilities of the provider again from its backend and return its new
+    /// description, or `None` if the capabilities of the provider can not change.
+    fn refresh_capabilities(
+        &self,
+    ) -> Result<Option<(list_providers::ProviderInfo, HashSet<Opcode>)>> {
+        trace!("refresh_capabilities ingress");
+        Ok(None)
+    }
+
+    /// Replace the description of a provider with the one discovered again. Only the Core Provider
+    /// keeps the descriptions of the other providers.
+    fn update_provider_details(
+        &self,
+        _provider_info: list_providers::ProviderInfo,
+        _opcodes: HashSet<Opcode>,
+    ) {
+        trace!("update_provider_details ingress");
+    }
+
+    /// List the authenticators supported by the service.
+    fn list_authenticators(
+        &self,
+        _op: list_authenticators::Operation,
+    ) -> Result<list_authenticators::Result> {
+        trace!("list_authenticators ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// List the keys of the application, in all the providers.
+    fn list_keys(
+        &self,
+        _app_name: ApplicationName,
+        _op: list_keys::Operation,
+    ) -> Result<list_keys::Result> {
+        trace!("list_keys ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// List the applications owning keys, in all the providers.
+    fn list_clients(&self, _op: list_clients::Operation) -> Result<list_clients::Result> {
+        trace!("list_clients ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// List all the keys stored locally, of all the applications and providers, with their
+    /// information.
+    fn key_inventory(&self) -> Result<Vec<(KeyTriple, KeyInfo)>> {
+        trace!("key_inventory ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Complete a DeleteClient operation, once all the keys of the client have been destroyed
+    /// through the backend of their provider.
+    fn delete_client(&self, _op: delete_client::Operation) -> Result<delete_client::Result> {
+        trace!("delete_client ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
     /// Execute a Ping operation to get the wire protocol version major and minor information.
     ///
     /// # Errors
@@ -126,6 +368,27 @@ pub trait Provide {
         Err(ResponseStatus::PsaErrorNotSupported)
     }
 
+    /// Execute a CreateKey operation for each of the keys given, returning the result of each of
+    /// them. The operations of keys failing to be created do not affect the others.
+    ///
+    /// Providers can override this method to share the per-call overhead, for example opening a
+    /// session, between all the keys. By default, the keys are created one after the other.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error only if none of the keys could be processed.
+    fn psa_generate_keys(
+        &self,
+        app_name: ApplicationName,
+        ops: Vec<psa_generate_key::Operation>,
+    ) -> Result<Vec<Result<psa_generate_key::Result>>> {
+        trace!("psa_generate_keys ingress");
+        Ok(ops
+            .into_iter()
+            .map(|op| self.psa_generate_key(app_name.clone(), op))
+            .collect())
+    }
+
     /// Execute a ImportKey operation.
     fn psa_import_key(
         &self,
@@ -176,4 +439,172 @@ pub trait Provide {
         trace!("psa_verify_hash ingress");
         Err(ResponseStatus::PsaErrorNotSupported)
     }
+
+    /// Execute an AsymmetricEncrypt operation, with RSA PKCS#1 v1.5 or OAEP. A salt can only be
+    /// given for OAEP.
+    fn psa_asymmetric_encrypt(
+        &self,
+        _app_name: ApplicationName,
+        _op: psa_asymmetric_encrypt::Operation,
+    ) -> Result<psa_asymmetric_encrypt::Result> {
+        trace!("psa_asymmetric_encrypt ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Execute an AsymmetricDecrypt operation, with RSA PKCS#1 v1.5 or OAEP. A salt can only be
+    /// given for OAEP.
+    fn psa_asymmetric_decrypt(
+        &self,
+        _app_name: ApplicationName,
+        _op: psa_asymmetric_decrypt::Operation,
+    ) -> Result<psa_asymmetric_decrypt::Result> {
+        trace!("psa_asymmetric_decrypt ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Execute an AeadEncrypt operation, the authentication tag being appended to the
+    /// ciphertext.
+    fn psa_aead_encrypt(
+        &self,
+        _app_name: ApplicationName,
+        _op: psa_aead_encrypt::Operation,
+    ) -> Result<psa_aead_encrypt::Result> {
+        trace!("psa_aead_encrypt ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Execute an AeadDecrypt operation, the authentication tag being at the end of the
+    /// ciphertext.
+    fn psa_aead_decrypt(
+        &self,
+        _app_name: ApplicationName,
+        _op: psa_aead_decrypt::Operation,
+    ) -> Result<psa_aead_decrypt::Result> {
+        trace!("psa_aead_decrypt ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Execute a RawKeyAgreement operation between a private key and the public key of a peer,
+    /// returning the shared secret.
+    fn psa_raw_key_agreement(
+        &self,
+        _app_name: ApplicationName,
+        _op: psa_raw_key_agreement::Operation,
+    ) -> Result<psa_raw_key_agreement::Result> {
+        trace!("psa_raw_key_agreement ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Copy a key of the application under a new name, the copy being only allowed the given
+    /// usages, which must all be allowed to the original key. The original key must be allowed to
+    /// be copied.
+    fn psa_copy_key(
+        &self,
+        _app_name: ApplicationName,
+        _key_name: String,
+        _destination_key_name: String,
+        _usage_flags: UsageFlags,
+    ) -> Result<()> {
+        trace!("psa_copy_key ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Execute a HashCompute operation. No key is used, so the operation does not need an
+    /// application name.
+    fn psa_hash_compute(
+        &self,
+        _op: psa_hash_compute::Operation,
+    ) -> Result<psa_hash_compute::Result> {
+        trace!("psa_hash_compute ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Execute a HashCompare operation, failing with `PsaErrorInvalidSignature` if the hash does
+    /// not match the input.
+    fn psa_hash_compare(
+        &self,
+        _op: psa_hash_compare::Operation,
+    ) -> Result<psa_hash_compare::Result> {
+        trace!("psa_hash_compare ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Execute a VerifyHash operation with the public key given instead of a stored key.
+    ///
+    /// The key, imported in the format of ExportPublicKey, is only used for this verification and
+    /// is never stored: only its type and size are taken from the attributes, its policy being
+    /// restricted to the verification with the algorithm given.
+    fn psa_verify_hash_with_public_key(
+        &self,
+        _app_name: ApplicationName,
+        _attributes: Attributes,
+        _public_key: Vec<u8>,
+        _alg: AsymmetricSignature,
+        _hash: Vec<u8>,
+        _signature: Vec<u8>,
+    ) -> Result<psa_verify_hash::Result> {
+        trace!("psa_verify_hash_with_public_key ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Check if the key of the given name needs to be unlocked with a credential, such as a PIN,
+    /// every time it is used.
+    fn key_requires_unlock(&self, _app_name: &ApplicationName, _key_name: &str) -> Result<bool> {
+        trace!("key_requires_unlock ingress");
+        Ok(false)
+    }
+
+    /// Unlock the key of the given name with the credential given by the client, for the next
+    /// operation using it. The credential must be checked by the provider: the service caches it
+    /// once a first unlock succeeded and calls this method again before each use of the key.
+    fn unlock_key(
+        &self,
+        _app_name: ApplicationName,
+        _key_name: String,
+        _credential: &[u8],
+    ) -> Result<()> {
+        trace!("unlock_key ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Quote the PCRs of the TPM with the nonce given as qualifying data, to prove the state of
+    /// the platform to a remote verifier. Only providers backed by the TPM measuring the boot of
+    /// the platform can implement this.
+    fn quote(&self, _nonce: Vec<u8>) -> Result<Quote> {
+        trace!("quote ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Change the authentication value the provider presents to its backend, such as the hierarchy
+    /// authentication value of a TPM or the user PIN of a PKCS 11 token, to the one given. The
+    /// provider uses the new value from then on, the configuration having to be updated for the
+    /// next start of the service.
+    fn change_backend_auth(&self, _auth: Zeroizing<Vec<u8>>) -> Result<()> {
+        trace!("change_backend_auth ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Store a DER-encoded X.509 certificate alongside the key of the given name, replacing any
+    /// certificate previously stored for it.
+    fn store_certificate(
+        &self,
+        _app_name: ApplicationName,
+        _key_name: String,
+        _certificate: Vec<u8>,
+    ) -> Result<()> {
+        trace!("store_certificate ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Get the certificate stored alongside the key of the given name.
+    fn get_certificate(&self, _app_name: ApplicationName, _key_name: String) -> Result<Vec<u8>> {
+        trace!("get_certificate ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
+
+    /// Delete the certificate stored alongside the key of the given name.
+    fn delete_certificate(&self, _app_name: ApplicationName, _key_name: String) -> Result<()> {
+        trace!("delete_certificate ingress");
+        Err(ResponseStatus::PsaErrorNotSupported)
+    }
 }
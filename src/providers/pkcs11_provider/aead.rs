@@ -0,0 +1,191 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::Pkcs11Provider;
+use super::{key_management::get_key_info, utils, KeyPairType, ReadWriteSession, Session};
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::KeyTriple;
+use log::{error, info, trace};
+use parsec_interface::operations::psa_algorithm::{Aead, AeadWithDefaultLengthTag};
+use parsec_interface::operations::{psa_aead_decrypt, psa_aead_encrypt};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use pkcs11::types::{CK_GCM_PARAMS, CK_MECHANISM};
+use std::mem;
+
+// Length of the AES-GCM tag when it is not shortened, in bytes.
+const GCM_TAG_LENGTH: usize = 16;
+
+/// Returns the length of the tag, in bytes, if the algorithm is AES-GCM, possibly with a shortened
+/// tag.
+fn gcm_tag_length(alg: Aead) -> Result<usize> {
+    match alg {
+        Aead::AeadWithDefaultLengthTag(AeadWithDefaultLengthTag::Gcm) => Ok(GCM_TAG_LENGTH),
+        Aead::AeadWithShortenedTag {
+            aead_alg: AeadWithDefaultLengthTag::Gcm,
+            tag_length,
+        } => Ok(tag_length),
+        _ => {
+            error!("The PKCS 11 provider currently only supports AES-GCM for AEAD.");
+            Err(ResponseStatus::PsaErrorNotSupported)
+        }
+    }
+}
+
+// The parameters point to the nonce and additional data, which must outlive the operation.
+fn gcm_params(nonce: &mut [u8], additional_data: &mut [u8], tag_length: usize) -> CK_GCM_PARAMS {
+    CK_GCM_PARAMS {
+        pIv: nonce.as_mut_ptr(),
+        ulIvLen: nonce.len(),
+        ulIvBits: nonce.len() * 8,
+        pAAD: additional_data.as_mut_ptr(),
+        ulAADLen: additional_data.len(),
+        ulTagBits: tag_length * 8,
+    }
+}
+
+// The mechanism points to the parameters, which must outlive the operation.
+fn gcm_mechanism(params: &mut CK_GCM_PARAMS) -> CK_MECHANISM {
+    let params: *mut CK_GCM_PARAMS = params;
+    CK_MECHANISM {
+        mechanism: pkcs11::types::CKM_AES_GCM,
+        pParameter: params as pkcs11::types::CK_VOID_PTR,
+        ulParameterLen: mem::size_of::<CK_GCM_PARAMS>(),
+    }
+}
+
+impl Pkcs11Provider {
+    pub(super) fn psa_aead_encrypt_internal(
+        &self,
+        app_name: ApplicationName,
+        op: psa_aead_encrypt::Operation,
+    ) -> Result<psa_aead_encrypt::Result> {
+        info!("Pkcs11 Provider - AEAD Encrypt");
+
+        let alg = op.alg;
+        let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, op.key_name.clone());
+        let store_handle = self.key_info_store.read();
+        let (key_id, key_attributes) = get_key_info(&key_triple, &store_handle)?;
+
+        key_attributes.can_encrypt_message()?;
+        key_attributes.permits_alg(alg.into())?;
+        key_attributes.compatible_with_alg(alg.into())?;
+        let tag_length = gcm_tag_length(alg)?;
+
+        let mut nonce = op.nonce.to_vec();
+        let mut additional_data = op.additional_data.to_vec();
+        let mut params = gcm_params(&mut nonce, &mut additional_data, tag_length);
+        let mech = gcm_mechanism(&mut params);
+
+        let session = Session::new(self, ReadWriteSession::ReadWrite)?;
+        if crate::utils::GlobalConfig::log_error_details() {
+            info!("AEAD encrypt in session {}", session.session_handle());
+        }
+
+        let key = self.find_key(session.session_handle(), key_id, KeyPairType::SecretKey)?;
+        info!("Located encryption key.");
+
+        trace!("EncryptInit command");
+        if let Err(e) = self
+            .backend
+            .encrypt_init(session.session_handle(), &mech, key)
+        {
+            format_error!("Failed to initialize encryption operation", e);
+            return Err(utils::to_response_status(e));
+        }
+        trace!("Encrypt command");
+        match self
+            .backend
+            .encrypt(session.session_handle(), &op.plaintext)
+        {
+            Ok(ciphertext) => Ok(psa_aead_encrypt::Result {
+                ciphertext: ciphertext.into(),
+            }),
+            Err(e) => {
+                format_error!("Failed to execute encryption operation", e);
+                Err(utils::to_response_status(e))
+            }
+        }
+    }
+
+    pub(super) fn psa_aead_decrypt_internal(
+        &self,
+        app_name: ApplicationName,
+        op: psa_aead_decrypt::Operation,
+    ) -> Result<psa_aead_decrypt::Result> {
+        info!("Pkcs11 Provider - AEAD Decrypt");
+
+        let alg = op.alg;
+        let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, op.key_name.clone());
+        let store_handle = self.key_info_store.read();
+        let (key_id, key_attributes) = get_key_info(&key_triple, &store_handle)?;
+
+        key_attributes.can_decrypt_message()?;
+        key_attributes.permits_alg(alg.into())?;
+        key_attributes.compatible_with_alg(alg.into())?;
+        let tag_length = gcm_tag_length(alg)?;
+
+        let mut nonce = op.nonce.to_vec();
+        let mut additional_data = op.additional_data.to_vec();
+        let mut params = gcm_params(&mut nonce, &mut additional_data, tag_length);
+        let mech = gcm_mechanism(&mut params);
+
+        let session = Session::new(self, ReadWriteSession::ReadWrite)?;
+        if crate::utils::GlobalConfig::log_error_details() {
+            info!("AEAD decrypt in session {}", session.session_handle());
+        }
+
+        let key = self.find_key(session.session_handle(), key_id, KeyPairType::SecretKey)?;
+        info!("Located decryption key.");
+
+        trace!("DecryptInit command");
+        if let Err(e) = self
+            .backend
+            .decrypt_init(session.session_handle(), &mech, key)
+        {
+            format_error!("Failed to initialize decryption operation", e);
+            return Err(utils::to_response_status(e));
+        }
+        trace!("Decrypt command");
+        match self
+            .backend
+            .decrypt(session.session_handle(), &op.ciphertext)
+        {
+            Ok(plaintext) => Ok(psa_aead_decrypt::Result {
+                plaintext: plaintext.into(),
+            }),
+            Err(e) => {
+                format_error!("Failed to execute decryption operation", e);
+                Err(utils::to_response_status(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{gcm_tag_length, GCM_TAG_LENGTH};
+    use parsec_interface::operations::psa_algorithm::{Aead, AeadWithDefaultLengthTag};
+    use parsec_interface::requests::ResponseStatus;
+
+    #[test]
+    fn only_gcm_supported() {
+        assert_eq!(
+            gcm_tag_length(Aead::AeadWithDefaultLengthTag(
+                AeadWithDefaultLengthTag::Gcm
+            )),
+            Ok(GCM_TAG_LENGTH)
+        );
+        assert_eq!(
+            gcm_tag_length(Aead::AeadWithShortenedTag {
+                aead_alg: AeadWithDefaultLengthTag::Gcm,
+                tag_length: 12,
+            }),
+            Ok(12)
+        );
+        assert_eq!(
+            gcm_tag_length(Aead::AeadWithDefaultLengthTag(
+                AeadWithDefaultLengthTag::Chacha20Poly1305
+            )),
+            Err(ResponseStatus::PsaErrorNotSupported)
+        );
+    }
+}
@@ -31,8 +31,8 @@ impl Pkcs11Provider {
         let hash = op.hash;
         let alg = op.alg;
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
-        let store_handle = self.key_info_store.read().expect("Key store lock poisoned");
-        let (key_id, key_attributes) = get_key_info(&key_triple, &*store_handle)?;
+        let store_handle = self.key_info_store.read();
+        let (key_id, key_attributes) = get_key_info(&key_triple, &store_handle)?;
 
         key_attributes.can_sign_hash()?;
         key_attributes.permits_alg(alg.into())?;
@@ -82,6 +82,7 @@ impl Pkcs11Provider {
         match self.backend.sign_init(session.session_handle(), &mech, key) {
             Ok(_) => {
                 info!("Signing operation initialized.");
+                self.login_for_operation(session.session_handle(), &key_triple)?;
                 let digest_info = DigestInfo {
                     oid: AlgorithmIdentifier::new_sha(SHAVariant::SHA2_256),
                     digest: hash.into(),
@@ -118,8 +119,8 @@ impl Pkcs11Provider {
         let signature = op.signature;
         let alg = op.alg;
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
-        let store_handle = self.key_info_store.read().expect("Key store lock poisoned");
-        let (key_id, key_attributes) = get_key_info(&key_triple, &*store_handle)?;
+        let store_handle = self.key_info_store.read();
+        let (key_id, key_attributes) = get_key_info(&key_triple, &store_handle)?;
 
         key_attributes.can_verify_hash()?;
         key_attributes.permits_alg(alg.into())?;
@@ -0,0 +1,166 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::Pkcs11Provider;
+use super::{key_management::get_key_info, utils, KeyPairType, ReadWriteSession, Session};
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::KeyTriple;
+use log::{error, info, trace};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use pkcs11::types::{CKR_OK, CK_ATTRIBUTE};
+
+// Certificates are stored as CKO_CERTIFICATE objects on the token, with the same CKA_ID as the key
+// they are associated with, which is how TLS stacks usually match them.
+impl Pkcs11Provider {
+    pub(super) fn store_certificate_internal(
+        &self,
+        app_name: ApplicationName,
+        key_name: String,
+        certificate: Vec<u8>,
+    ) -> Result<()> {
+        info!("Pkcs11 Provider - Store Certificate");
+
+        let subject = utils::x509_subject(&certificate).ok_or_else(|| {
+            error!("The certificate is not a valid DER-encoded X.509 certificate.");
+            ResponseStatus::PsaErrorInvalidArgument
+        })?;
+
+        let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
+        let store_handle = self.key_info_store.read();
+        let (key_id, _) = get_key_info(&key_triple, &store_handle)?;
+
+        let session = Session::new(self, ReadWriteSession::ReadWrite)?;
+        if crate::utils::GlobalConfig::log_error_details() {
+            info!(
+                "Storing certificate in session {}",
+                session.session_handle()
+            );
+        }
+
+        match self.find_key(session.session_handle(), key_id, KeyPairType::Certificate) {
+            Ok(previous_certificate) => {
+                trace!("DestroyObject command");
+                if let Err(e) = self
+                    .backend
+                    .destroy_object(session.session_handle(), previous_certificate)
+                {
+                    format_error!("Failed to destroy the previous certificate", e);
+                    return Err(utils::to_response_status(e));
+                }
+            }
+            Err(ResponseStatus::PsaErrorDoesNotExist) => (),
+            Err(e) => return Err(e),
+        }
+
+        let template = vec![
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_CLASS)
+                .with_ck_ulong(&pkcs11::types::CKO_CERTIFICATE),
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_CERTIFICATE_TYPE)
+                .with_ck_ulong(&pkcs11::types::CKC_X_509),
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_TOKEN).with_bool(&pkcs11::types::CK_TRUE),
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_ID).with_bytes(&key_id),
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_SUBJECT).with_bytes(subject),
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_VALUE).with_bytes(&certificate),
+        ];
+
+        trace!("CreateObject command");
+        match self
+            .backend
+            .create_object(session.session_handle(), &template)
+        {
+            Ok(_certificate) => Ok(()),
+            Err(e) => {
+                format_error!("Storing the certificate failed", e);
+                Err(utils::to_response_status(e))
+            }
+        }
+    }
+
+    pub(super) fn get_certificate_internal(
+        &self,
+        app_name: ApplicationName,
+        key_name: String,
+    ) -> Result<Vec<u8>> {
+        info!("Pkcs11 Provider - Get Certificate");
+
+        let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
+        let store_handle = self.key_info_store.read();
+        let (key_id, _) = get_key_info(&key_triple, &store_handle)?;
+
+        let session = Session::new(self, ReadWriteSession::ReadOnly)?;
+        let certificate =
+            self.find_key(session.session_handle(), key_id, KeyPairType::Certificate)?;
+
+        // Get the length of the certificate first.
+        let mut size_attrs = vec![CK_ATTRIBUTE::new(pkcs11::types::CKA_VALUE)];
+        trace!("GetAttributeValue command");
+        let value_len = match self.backend.get_attribute_value(
+            session.session_handle(),
+            certificate,
+            &mut size_attrs,
+        ) {
+            Ok((rv, attrs)) => {
+                if rv != CKR_OK {
+                    format_error!("Error when extracting attribute", rv);
+                    Err(utils::rv_to_response_status(rv))
+                } else {
+                    Ok(attrs[0].ulValueLen)
+                }
+            }
+            Err(e) => {
+                format_error!("Failed to read attributes from certificate", e);
+                Err(utils::to_response_status(e))
+            }
+        }?;
+
+        let mut value: Vec<pkcs11::types::CK_BYTE> = vec![0; value_len];
+        let mut extract_attrs =
+            vec![CK_ATTRIBUTE::new(pkcs11::types::CKA_VALUE).with_bytes(value.as_mut_slice())];
+        trace!("GetAttributeValue command");
+        match self.backend.get_attribute_value(
+            session.session_handle(),
+            certificate,
+            &mut extract_attrs,
+        ) {
+            Ok((rv, attrs)) => {
+                if rv != CKR_OK {
+                    format_error!("Error when extracting attribute", rv);
+                    Err(utils::rv_to_response_status(rv))
+                } else {
+                    Ok(attrs[0].get_bytes())
+                }
+            }
+            Err(e) => {
+                format_error!("Failed to read attributes from certificate", e);
+                Err(utils::to_response_status(e))
+            }
+        }
+    }
+
+    pub(super) fn delete_certificate_internal(
+        &self,
+        app_name: ApplicationName,
+        key_name: String,
+    ) -> Result<()> {
+        info!("Pkcs11 Provider - Delete Certificate");
+
+        let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
+        let store_handle = self.key_info_store.read();
+        let (key_id, _) = get_key_info(&key_triple, &store_handle)?;
+
+        let session = Session::new(self, ReadWriteSession::ReadWrite)?;
+        let certificate =
+            self.find_key(session.session_handle(), key_id, KeyPairType::Certificate)?;
+
+        trace!("DestroyObject command");
+        match self
+            .backend
+            .destroy_object(session.session_handle(), certificate)
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                format_error!("Failed to destroy the certificate", e);
+                Err(utils::to_response_status(e))
+            }
+        }
+    }
+}
@@ -7,6 +7,7 @@ use super::{
 use crate::authenticators::ApplicationName;
 use crate::key_info_managers::KeyTriple;
 use crate::key_info_managers::{self, ManageKeyInfo};
+use crate::providers::key_id_range::KeyIdRange;
 use log::{error, info, trace, warn};
 use parsec_interface::operations::psa_key_attributes::*;
 use parsec_interface::operations::{
@@ -15,6 +16,9 @@ use parsec_interface::operations::{
 use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
 use picky_asn1::wrapper::IntegerAsn1;
 use pkcs11::types::{CKR_OK, CK_ATTRIBUTE, CK_MECHANISM, CK_OBJECT_HANDLE, CK_SESSION_HANDLE};
+use rand::distributions::Uniform;
+use rand::Rng;
+use std::collections::BTreeMap;
 use std::mem;
 
 // Public exponent value for all RSA keys.
@@ -41,29 +45,42 @@ pub fn get_key_info(
     }
 }
 
+/// Creates a new key identifier and stores it in the Key Info Manager as a pending mapping.
+///
+/// The mapping must be committed with `Pkcs11Provider::commit_key_id` once the key is created, or
+/// aborted with `abort_key_id` if the creation failed. A mapping still pending when the service
+/// starts is rolled back by the provider.
 pub fn create_key_id(
     key_triple: KeyTriple,
     key_attributes: Attributes,
     store_handle: &mut dyn ManageKeyInfo,
     local_ids_handle: &mut LocalIdStore,
+    key_id_range: KeyIdRange,
 ) -> Result<[u8; 4]> {
-    let mut key_id = rand::random::<[u8; 4]>();
+    if local_ids_handle.len() as u64 >= key_id_range.size() {
+        error!(
+            "All the key IDs of the range {}-{} are used.",
+            key_id_range.min, key_id_range.max
+        );
+        return Err(ResponseStatus::PsaErrorInsufficientMemory);
+    }
+    let ids = Uniform::new_inclusive(key_id_range.min, key_id_range.max);
+    let mut rng = rand::thread_rng();
+    let mut key_id = rng.sample(ids).to_be_bytes();
     while local_ids_handle.contains(&key_id) {
-        key_id = rand::random::<[u8; 4]>();
+        key_id = rng.sample(ids).to_be_bytes();
     }
     let key_info = KeyInfo {
         id: key_id.to_vec(),
         attributes: key_attributes,
+        state: key_info_managers::KeyState::Active,
+        created_at: None,
+        counter: None,
+        certificate: None,
+        labels: BTreeMap::new(),
     };
-    match store_handle.insert(key_triple.clone(), key_info) {
-        Ok(insert_option) => {
-            if insert_option.is_some() {
-                if crate::utils::GlobalConfig::log_error_details() {
-                    warn!("Overwriting Key triple mapping ({})", key_triple);
-                } else {
-                    warn!("Overwriting Key triple mapping");
-                }
-            }
+    match store_handle.insert_pending(key_triple, key_info) {
+        Ok(()) => {
             let _ = local_ids_handle.insert(key_id);
 
             Ok(key_id)
@@ -72,6 +89,22 @@ pub fn create_key_id(
     }
 }
 
+/// Aborts the pending mapping of a key whose creation failed.
+pub fn abort_key_id(
+    key_triple: &KeyTriple,
+    key_id: [u8; 4],
+    store_handle: &mut dyn ManageKeyInfo,
+    local_ids_handle: &mut LocalIdStore,
+) -> Result<()> {
+    match store_handle.abort(key_triple) {
+        Ok(_) => {
+            let _ = local_ids_handle.remove(&key_id);
+            Ok(())
+        }
+        Err(string) => Err(key_info_managers::to_response_status(string)),
+    }
+}
+
 pub fn remove_key_id(
     key_triple: &KeyTriple,
     key_id: [u8; 4],
@@ -95,6 +128,44 @@ pub fn key_info_exists(key_triple: &KeyTriple, store_handle: &dyn ManageKeyInfo)
 }
 
 impl Pkcs11Provider {
+    /// Commits the pending mapping of a key which was created.
+    ///
+    /// If the mapping can not be committed, the objects of the key are destroyed and the mapping
+    /// aborted, so that the client can create the key again under the same name. The mapping is
+    /// only left pending, for the provider to roll it back when it starts again, if the objects
+    /// can not be destroyed.
+    fn commit_key_id(
+        &self,
+        session: CK_SESSION_HANDLE,
+        key_triple: &KeyTriple,
+        key_id: [u8; 4],
+        store_handle: &mut dyn ManageKeyInfo,
+        local_ids_handle: &mut LocalIdStore,
+    ) -> Result<()> {
+        match store_handle.commit(key_triple) {
+            Ok(insert_option) => {
+                if insert_option.is_some() {
+                    if crate::utils::GlobalConfig::log_error_details() {
+                        warn!("Overwriting Key triple mapping ({})", key_triple);
+                    } else {
+                        warn!("Overwriting Key triple mapping");
+                    }
+                }
+                Ok(())
+            }
+            Err(string) => {
+                format_error!("Failed to commit the mapping of the key", string);
+                let status = key_info_managers::to_response_status(string);
+                if let Err(e) = self.destroy_objects(session, key_id) {
+                    format_error!("Failed to destroy the key whose mapping failed", e);
+                    return Err(status);
+                }
+                abort_key_id(key_triple, key_id, store_handle, local_ids_handle)?;
+                Err(status)
+            }
+        }
+    }
+
     /// Find the PKCS 11 object handle corresponding to the key ID and the key type (public or
     /// private key) given as parameters for the current session.
     pub(super) fn find_key(
@@ -113,6 +184,14 @@ impl Pkcs11Provider {
                 CK_ATTRIBUTE::new(pkcs11::types::CKA_CLASS)
                     .with_ck_ulong(&pkcs11::types::CKO_PRIVATE_KEY),
             ),
+            KeyPairType::SecretKey => template.push(
+                CK_ATTRIBUTE::new(pkcs11::types::CKA_CLASS)
+                    .with_ck_ulong(&pkcs11::types::CKO_SECRET_KEY),
+            ),
+            KeyPairType::Certificate => template.push(
+                CK_ATTRIBUTE::new(pkcs11::types::CKA_CLASS)
+                    .with_ck_ulong(&pkcs11::types::CKO_CERTIFICATE),
+            ),
             KeyPairType::Any => (),
         }
 
@@ -141,6 +220,30 @@ impl Pkcs11Provider {
             }
         }
     }
+
+    /// Destroys all the objects with the key ID, returning how many were destroyed.
+    pub(super) fn destroy_objects(
+        &self,
+        session: CK_SESSION_HANDLE,
+        key_id: [u8; 4],
+    ) -> Result<usize> {
+        let mut destroyed = 0;
+        loop {
+            match self.find_key(session, key_id, KeyPairType::Any) {
+                Ok(object) => {
+                    trace!("DestroyObject command");
+                    if let Err(e) = self.backend.destroy_object(session, object) {
+                        format_error!("Failed to destroy an object of the key", e);
+                        return Err(utils::to_response_status(e));
+                    }
+                    destroyed += 1;
+                }
+                Err(ResponseStatus::PsaErrorDoesNotExist) => return Ok(destroyed),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     pub(super) fn psa_generate_key_internal(
         &self,
         app_name: ApplicationName,
@@ -148,9 +251,52 @@ impl Pkcs11Provider {
     ) -> Result<psa_generate_key::Result> {
         info!("Pkcs11 Provider - Create Key");
 
-        if op.attributes.key_type != Type::RsaKeyPair {
-            error!("The PKCS11 provider currently only supports creating RSA key pairs.");
-            return Err(ResponseStatus::PsaErrorNotSupported);
+        let session = Session::new(self, ReadWriteSession::ReadWrite).or_else(|err| {
+            format_error!("Error creating a new session", err);
+            Err(err)
+        })?;
+
+        self.generate_key_in_session(&session, app_name, op)
+    }
+
+    /// Generates all the keys in the same session, so that the session is only opened, and the
+    /// user logged in, once. Returns the result of the generation of each key.
+    pub(super) fn psa_generate_keys_internal(
+        &self,
+        app_name: ApplicationName,
+        ops: Vec<psa_generate_key::Operation>,
+    ) -> Result<Vec<Result<psa_generate_key::Result>>> {
+        info!("Pkcs11 Provider - Create Keys");
+
+        let session = Session::new(self, ReadWriteSession::ReadWrite).or_else(|err| {
+            format_error!("Error creating a new session", err);
+            Err(err)
+        })?;
+
+        Ok(ops
+            .into_iter()
+            .map(|op| self.generate_key_in_session(&session, app_name.clone(), op))
+            .collect())
+    }
+
+    fn generate_key_in_session(
+        &self,
+        session: &Session<'_>,
+        app_name: ApplicationName,
+        op: psa_generate_key::Operation,
+    ) -> Result<psa_generate_key::Result> {
+        match op.attributes.key_type {
+            Type::RsaKeyPair => (),
+            Type::Aes => {
+                if ![128, 192, 256].contains(&op.attributes.bits) {
+                    error!("AES keys must be 128, 192 or 256 bits long.");
+                    return Err(ResponseStatus::PsaErrorInvalidArgument);
+                }
+            }
+            _ => {
+                error!("The PKCS11 provider currently only supports creating RSA key pairs and AES keys.");
+                return Err(ResponseStatus::PsaErrorNotSupported);
+            }
         }
 
         let key_name = op.key_name;
@@ -159,21 +305,54 @@ impl Pkcs11Provider {
         let key_size = std::convert::TryFrom::try_from(op.attributes.bits).unwrap();
 
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
-        let mut store_handle = self
-            .key_info_store
-            .write()
-            .expect("Key store lock poisoned");
+        let mut store_handle = self.key_info_store.write();
         let mut local_ids_handle = self.local_ids.write().expect("Local ID lock poisoned");
-        if key_info_exists(&key_triple, &*store_handle)? {
+        if key_info_exists(&key_triple, &store_handle)? {
             return Err(ResponseStatus::PsaErrorAlreadyExists);
         }
         let key_id = create_key_id(
             key_triple.clone(),
             key_attributes,
-            &mut *store_handle,
+            &mut store_handle,
             &mut local_ids_handle,
+            self.key_id_range,
         )?;
 
+        let generated = if key_attributes.key_type == Type::Aes {
+            self.generate_aes_key(session, key_id, key_attributes, key_size)
+        } else {
+            self.generate_rsa_key_pair(session, key_id, key_size)
+        };
+        match generated {
+            Ok(()) => {
+                self.commit_key_id(
+                    session.session_handle(),
+                    &key_triple,
+                    key_id,
+                    &mut store_handle,
+                    &mut local_ids_handle,
+                )?;
+                Ok(psa_generate_key::Result {})
+            }
+            Err(e) => {
+                format_error!("Generate Key operation failed", e);
+                abort_key_id(
+                    &key_triple,
+                    key_id,
+                    &mut store_handle,
+                    &mut local_ids_handle,
+                )?;
+                Err(utils::to_response_status(e))
+            }
+        }
+    }
+
+    fn generate_rsa_key_pair(
+        &self,
+        session: &Session<'_>,
+        key_id: [u8; 4],
+        key_size: pkcs11::types::CK_ULONG,
+    ) -> std::result::Result<(), pkcs11::errors::Error> {
         let mech = CK_MECHANISM {
             mechanism: pkcs11::types::CKM_RSA_PKCS_KEY_PAIR_GEN,
             pParameter: std::ptr::null_mut(),
@@ -205,17 +384,6 @@ impl Pkcs11Provider {
         pub_template
             .push(CK_ATTRIBUTE::new(pkcs11::types::CKA_ENCRYPT).with_bool(&pkcs11::types::CK_TRUE));
 
-        let session = Session::new(self, ReadWriteSession::ReadWrite).or_else(|err| {
-            format_error!("Error creating a new session", err);
-            remove_key_id(
-                &key_triple,
-                key_id,
-                &mut *store_handle,
-                &mut local_ids_handle,
-            )?;
-            Err(err)
-        })?;
-
         if crate::utils::GlobalConfig::log_error_details() {
             info!(
                 "Generating RSA key pair in session {}",
@@ -224,24 +392,85 @@ impl Pkcs11Provider {
         }
 
         trace!("GenerateKeyPair command");
-        match self.backend.generate_key_pair(
-            session.session_handle(),
-            &mech,
-            &pub_template,
-            &priv_template,
-        ) {
-            Ok(_key) => Ok(psa_generate_key::Result {}),
-            Err(e) => {
-                format_error!("Generate Key Pair operation failed", e);
-                remove_key_id(
-                    &key_triple,
-                    key_id,
-                    &mut *store_handle,
-                    &mut local_ids_handle,
-                )?;
-                Err(utils::to_response_status(e))
+        self.backend
+            .generate_key_pair(
+                session.session_handle(),
+                &mech,
+                &pub_template,
+                &priv_template,
+            )
+            .map(|_| ())
+    }
+
+    // The AES keys are secret key objects which never leave the token and are only usable with
+    // AES-GCM, for the AEAD operations their usage flags allow.
+    fn generate_aes_key(
+        &self,
+        session: &Session<'_>,
+        key_id: [u8; 4],
+        key_attributes: Attributes,
+        key_size: pkcs11::types::CK_ULONG,
+    ) -> std::result::Result<(), pkcs11::errors::Error> {
+        let mech = CK_MECHANISM {
+            mechanism: pkcs11::types::CKM_AES_KEY_GEN,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let value_len = key_size / 8;
+        let usage_flags = key_attributes.policy.usage_flags;
+        let flag = |allowed: bool| {
+            if allowed {
+                &pkcs11::types::CK_TRUE
+            } else {
+                &pkcs11::types::CK_FALSE
             }
+        };
+
+        let mut template: Vec<CK_ATTRIBUTE> = Vec::new();
+        template.push(
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_CLASS)
+                .with_ck_ulong(&pkcs11::types::CKO_SECRET_KEY),
+        );
+        template.push(
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_KEY_TYPE).with_ck_ulong(&pkcs11::types::CKK_AES),
+        );
+        template.push(CK_ATTRIBUTE::new(pkcs11::types::CKA_ID).with_bytes(&key_id));
+        template.push(CK_ATTRIBUTE::new(pkcs11::types::CKA_VALUE_LEN).with_ck_ulong(&value_len));
+        template
+            .push(CK_ATTRIBUTE::new(pkcs11::types::CKA_TOKEN).with_bool(&pkcs11::types::CK_TRUE));
+        template.push(
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_SENSITIVE).with_bool(&pkcs11::types::CK_TRUE),
+        );
+        template.push(
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_EXTRACTABLE).with_bool(&pkcs11::types::CK_FALSE),
+        );
+        template.push(
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_ENCRYPT).with_bool(flag(usage_flags.encrypt)),
+        );
+        template.push(
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_DECRYPT).with_bool(flag(usage_flags.decrypt)),
+        );
+
+        // Restrict to AES-GCM.
+        let allowed_mechanisms = [pkcs11::types::CKM_AES_GCM];
+        // The attribute contains a pointer to the allowed_mechanism array and its size as
+        // ulValueLen.
+        let mut allowed_mechanisms_attribute =
+            CK_ATTRIBUTE::new(pkcs11::types::CKA_ALLOWED_MECHANISMS);
+        allowed_mechanisms_attribute.ulValueLen = mem::size_of_val(&allowed_mechanisms);
+        allowed_mechanisms_attribute.pValue = &allowed_mechanisms
+            as *const pkcs11::types::CK_MECHANISM_TYPE
+            as pkcs11::types::CK_VOID_PTR;
+        template.push(allowed_mechanisms_attribute);
+
+        if crate::utils::GlobalConfig::log_error_details() {
+            info!("Generating AES key in session {}", session.session_handle());
         }
+
+        trace!("GenerateKey command");
+        self.backend
+            .generate_key(session.session_handle(), &mech, &template)
+            .map(|_| ())
     }
 
     pub(super) fn psa_import_key_internal(
@@ -259,29 +488,27 @@ impl Pkcs11Provider {
         let key_name = op.key_name;
         let key_attributes = op.attributes;
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
-        let mut store_handle = self
-            .key_info_store
-            .write()
-            .expect("Key store lock poisoned");
+        let mut store_handle = self.key_info_store.write();
         let mut local_ids_handle = self.local_ids.write().expect("Local ID lock poisoned");
-        if key_info_exists(&key_triple, &*store_handle)? {
+        if key_info_exists(&key_triple, &store_handle)? {
             return Err(ResponseStatus::PsaErrorAlreadyExists);
         }
         let key_id = create_key_id(
             key_triple.clone(),
             key_attributes,
-            &mut *store_handle,
+            &mut store_handle,
             &mut local_ids_handle,
+            self.key_id_range,
         )?;
 
         let mut template: Vec<CK_ATTRIBUTE> = Vec::new();
 
         let public_key: RsaPublicKey = picky_asn1_der::from_bytes(&op.data).or_else(|e| {
             format_error!("Failed to parse RsaPublicKey data", e);
-            remove_key_id(
+            abort_key_id(
                 &key_triple,
                 key_id,
-                &mut *store_handle,
+                &mut store_handle,
                 &mut local_ids_handle,
             )?;
             Err(ResponseStatus::PsaErrorInvalidArgument)
@@ -289,10 +516,10 @@ impl Pkcs11Provider {
 
         if public_key.modulus.is_negative() || public_key.public_exponent.is_negative() {
             error!("Only positive modulus and public exponent are supported.");
-            remove_key_id(
+            abort_key_id(
                 &key_triple,
                 key_id,
-                &mut *store_handle,
+                &mut store_handle,
                 &mut local_ids_handle,
             )?;
             return Err(ResponseStatus::PsaErrorInvalidArgument);
@@ -311,6 +538,12 @@ impl Pkcs11Provider {
             } else {
                 error!("`bits` field of key attributes must be either 0 or equal to the size of the key in `data`.");
             }
+            abort_key_id(
+                &key_triple,
+                key_id,
+                &mut store_handle,
+                &mut local_ids_handle,
+            )?;
             return Err(ResponseStatus::PsaErrorInvalidArgument);
         }
 
@@ -350,10 +583,10 @@ impl Pkcs11Provider {
 
         let session = Session::new(self, ReadWriteSession::ReadWrite).or_else(|err| {
             format_error!("Error creating a new session", err);
-            remove_key_id(
+            abort_key_id(
                 &key_triple,
                 key_id,
-                &mut *store_handle,
+                &mut store_handle,
                 &mut local_ids_handle,
             )?;
             Err(err)
@@ -371,13 +604,22 @@ impl Pkcs11Provider {
             .backend
             .create_object(session.session_handle(), &template)
         {
-            Ok(_key) => Ok(psa_import_key::Result {}),
+            Ok(_key) => {
+                self.commit_key_id(
+                    session.session_handle(),
+                    &key_triple,
+                    key_id,
+                    &mut store_handle,
+                    &mut local_ids_handle,
+                )?;
+                Ok(psa_import_key::Result {})
+            }
             Err(e) => {
                 format_error!("Import operation failed", e);
-                remove_key_id(
+                abort_key_id(
                     &key_triple,
                     key_id,
-                    &mut *store_handle,
+                    &mut store_handle,
                     &mut local_ids_handle,
                 )?;
                 Err(utils::to_response_status(e))
@@ -394,8 +636,12 @@ impl Pkcs11Provider {
 
         let key_name = op.key_name;
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
-        let store_handle = self.key_info_store.read().expect("Key store lock poisoned");
-        let (key_id, _key_attributes) = get_key_info(&key_triple, &*store_handle)?;
+        let store_handle = self.key_info_store.read();
+        let (key_id, key_attributes) = get_key_info(&key_triple, &store_handle)?;
+        if key_attributes.key_type == Type::Aes {
+            error!("An AES key has no public key to export.");
+            return Err(ResponseStatus::PsaErrorInvalidArgument);
+        }
 
         let session = Session::new(self, ReadWriteSession::ReadOnly)?;
         if crate::utils::GlobalConfig::log_error_details() {
@@ -492,12 +738,9 @@ impl Pkcs11Provider {
 
         let key_name = op.key_name;
         let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
-        let mut store_handle = self
-            .key_info_store
-            .write()
-            .expect("Key store lock poisoned");
+        let mut store_handle = self.key_info_store.write();
         let mut local_ids_handle = self.local_ids.write().expect("Local ID lock poisoned");
-        let (key_id, _) = get_key_info(&key_triple, &*store_handle)?;
+        let (key_id, _) = get_key_info(&key_triple, &store_handle)?;
 
         let session = Session::new(self, ReadWriteSession::ReadWrite)?;
         if crate::utils::GlobalConfig::log_error_details() {
@@ -507,6 +750,26 @@ impl Pkcs11Provider {
             );
         }
 
+        // The certificate stored alongside the key, if any, shares its ID and is destroyed first so
+        // that only the key objects are found below.
+        match self.find_key(session.session_handle(), key_id, KeyPairType::Certificate) {
+            Ok(certificate) => {
+                trace!("DestroyObject command");
+                if let Err(e) = self
+                    .backend
+                    .destroy_object(session.session_handle(), certificate)
+                {
+                    format_error!("Failed to destroy the certificate of the key", e);
+                    return Err(utils::to_response_status(e));
+                }
+            }
+            Err(ResponseStatus::PsaErrorDoesNotExist) => (),
+            Err(e) => {
+                format_error!("Error destroying key", e);
+                return Err(e);
+            }
+        };
+
         match self.find_key(session.session_handle(), key_id, KeyPairType::Any) {
             Ok(key) => {
                 trace!("DestroyObject command");
@@ -547,7 +810,7 @@ impl Pkcs11Provider {
         remove_key_id(
             &key_triple,
             key_id,
-            &mut *store_handle,
+            &mut store_handle,
             &mut local_ids_handle,
         )?;
 
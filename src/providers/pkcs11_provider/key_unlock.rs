@@ -0,0 +1,131 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::Pkcs11Provider;
+use super::{key_management::get_key_info, utils, KeyPairType, ReadWriteSession, Session};
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::KeyTriple;
+use log::{error, info, trace};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use pkcs11::errors::Error;
+use pkcs11::types::{
+    CKA_ALWAYS_AUTHENTICATE, CKM_RSA_PKCS, CKR_ATTRIBUTE_TYPE_INVALID, CKR_OK, CKR_PIN_INCORRECT,
+    CKR_PIN_LEN_RANGE, CKR_PIN_LOCKED, CKU_CONTEXT_SPECIFIC, CK_ATTRIBUTE, CK_MECHANISM,
+    CK_SESSION_HANDLE, CK_TRUE,
+};
+use zeroize::Zeroizing;
+
+// Private keys with CKA_ALWAYS_AUTHENTICATE set need a context specific login with their PIN after
+// each C_SignInit. The PIN given to unlock such a key is checked by logging in for a signature which
+// is then not made, and kept for the next signature only: the backend handler unlocks the key again
+// before each use with the PIN it cached for the client.
+impl Pkcs11Provider {
+    pub(super) fn key_requires_unlock_internal(
+        &self,
+        app_name: &ApplicationName,
+        key_name: &str,
+    ) -> Result<bool> {
+        let key_triple = KeyTriple::new(app_name.clone(), ProviderID::Pkcs11, key_name.to_string());
+        let (key_id, _) = get_key_info(&key_triple, &self.key_info_store.read())?;
+
+        let session = Session::new(self, ReadWriteSession::ReadOnly)?;
+        let key = self.find_key(session.session_handle(), key_id, KeyPairType::PrivateKey)?;
+
+        let mut always_authenticate: Vec<pkcs11::types::CK_BYTE> = vec![0];
+        let mut attrs = vec![CK_ATTRIBUTE::new(CKA_ALWAYS_AUTHENTICATE)
+            .with_bytes(always_authenticate.as_mut_slice())];
+        trace!("GetAttributeValue command");
+        match self
+            .backend
+            .get_attribute_value(session.session_handle(), key, &mut attrs)
+        {
+            Ok((CKR_OK, attrs)) => Ok(attrs[0].get_bytes() == [CK_TRUE]),
+            // Tokens older than PKCS 11 v2.20 do not define the attribute.
+            Ok((CKR_ATTRIBUTE_TYPE_INVALID, _)) => Ok(false),
+            Ok((rv, _)) => {
+                format_error!("Error when extracting attribute", rv);
+                Err(utils::rv_to_response_status(rv))
+            }
+            Err(e) => {
+                format_error!("Failed to read attributes from private key", e);
+                Err(utils::to_response_status(e))
+            }
+        }
+    }
+
+    pub(super) fn unlock_key_internal(
+        &self,
+        app_name: ApplicationName,
+        key_name: String,
+        credential: &[u8],
+    ) -> Result<()> {
+        info!("Pkcs11 Provider - Unlock Key");
+
+        let pin = std::str::from_utf8(credential).or_else(|_| {
+            error!("The PIN of the key is not valid UTF-8.");
+            Err(ResponseStatus::PsaErrorInvalidArgument)
+        })?;
+        let key_triple = KeyTriple::new(app_name, ProviderID::Pkcs11, key_name);
+        let (key_id, _) = get_key_info(&key_triple, &self.key_info_store.read())?;
+
+        {
+            let session = Session::new(self, ReadWriteSession::ReadWrite)?;
+            let key = self.find_key(session.session_handle(), key_id, KeyPairType::PrivateKey)?;
+            let mech = CK_MECHANISM {
+                mechanism: CKM_RSA_PKCS,
+                pParameter: std::ptr::null_mut(),
+                ulParameterLen: 0,
+            };
+            trace!("SignInit command");
+            if let Err(e) = self.backend.sign_init(session.session_handle(), &mech, key) {
+                format_error!("Failed to initialize signing operation", e);
+                return Err(utils::to_response_status(e));
+            }
+            self.context_specific_login(session.session_handle(), pin)?;
+            // Closing the session terminates the signing operation.
+        }
+
+        let _ = self
+            .context_logins
+            .lock()
+            .expect("Context logins lock poisoned")
+            .insert(key_triple, Zeroizing::new(pin.to_string()));
+
+        Ok(())
+    }
+
+    /// Logs in for the signing operation just initialized in the session, with the PIN of the key
+    /// unlocked for it if it needs one.
+    pub(super) fn login_for_operation(
+        &self,
+        session: CK_SESSION_HANDLE,
+        key_triple: &KeyTriple,
+    ) -> Result<()> {
+        let pin = self
+            .context_logins
+            .lock()
+            .expect("Context logins lock poisoned")
+            .remove(key_triple);
+
+        match pin {
+            Some(pin) => self.context_specific_login(session, &pin),
+            None => Ok(()),
+        }
+    }
+
+    fn context_specific_login(&self, session: CK_SESSION_HANDLE, pin: &str) -> Result<()> {
+        trace!("Login command");
+        match self.backend.login(session, CKU_CONTEXT_SPECIFIC, Some(pin)) {
+            Ok(_) => Ok(()),
+            Err(Error::Pkcs11(CKR_PIN_INCORRECT))
+            | Err(Error::Pkcs11(CKR_PIN_LEN_RANGE))
+            | Err(Error::Pkcs11(CKR_PIN_LOCKED)) => {
+                error!("The PIN of the key was refused by the token.");
+                Err(ResponseStatus::PsaErrorNotPermitted)
+            }
+            Err(e) => {
+                format_error!("Context specific login failed", e);
+                Err(utils::to_response_status(e))
+            }
+        }
+    }
+}
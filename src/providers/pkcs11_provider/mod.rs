@@ -4,38 +4,84 @@
 //!
 //! This provider allows clients to access any PKCS 11 compliant device
 //! through the Parsec interface.
-use super::Provide;
+//!
+//! Devices are driven through the generic PKCS 11 object model only. PIV devices such as Yubikeys
+//! are usable as any token, but their specifics are not exposed: the provider chooses random
+//! 4 bytes key IDs where PIV keys live in the fixed 9a, 9c, 9d and 9e slots, and the touch and PIN
+//! policies of these slots are set when the keys are created, outside of PKCS 11. An operation
+//! waiting for the operator to touch the device also has no specific response status in the
+//! interface to report to the client: it fails with the status of the PKCS 11 error once timed out.
+//!
+//! The key triples are mapped to the `CKA_ID` of the key objects through the key info manager.
+//! The IDs of the keys are chosen randomly, in the configured key ID range if there is one, the
+//! IDs being read as big-endian 32 bits integers for that purpose.
+//!
+//! The SHA-256 digest of the library is measured before it is loaded and reported in the
+//! description of the provider, for auditing. If the configuration pins the expected digest, a
+//! library which does not match it is not loaded and the provider is not created.
+//!
+//! Signatures, verifications and AEAD operations are limited to one operation at a time per key by
+//! default, as some tokens misbehave when a key object is used concurrently. The limit can be
+//! raised for tokens known to support it.
+//!
+//! Keys whose private key object has `CKA_ALWAYS_AUTHENTICATE` set need to be unlocked with their
+//! PIN, given by the client, before they are used: the provider logs in with it in the
+//! `CKU_CONTEXT_SPECIFIC` user type after each signing operation is initialized.
+//!
+//! Once the user PIN was changed on the token, the administrators can give the new one to the
+//! provider while the service runs: it is checked by logging in with it and used from then on.
+//!
+//! RSA key pairs and AES keys are supported. The AES keys are generated with `CKM_AES_KEY_GEN` as
+//! sensitive, non-extractable secret key objects restricted to `CKM_AES_GCM`, for the AEAD
+//! operations. The service does not dispatch cipher or key wrapping operations, so the other AES
+//! mechanisms of the token are not used.
+use super::key_id_range::KeyIdRange;
+use super::key_locks::KeyLocks;
+use super::{CacheUsage, EvictionPolicy, Provide};
 use crate::authenticators::ApplicationName;
-use crate::key_info_managers::{KeyInfo, KeyTriple, ManageKeyInfo};
+use crate::key_info_managers::key_info_store::KeyInfoStore;
+use crate::key_info_managers::{KeyInfo, KeyTriple, ManageKeyInfo, MappingHealth};
+use crate::utils::secrets;
 use derivative::Derivative;
 use log::{error, info, trace, warn};
 use parsec_interface::operations::list_providers::ProviderInfo;
 use parsec_interface::operations::{
-    psa_destroy_key, psa_export_public_key, psa_generate_key, psa_import_key, psa_sign_hash,
-    psa_verify_hash,
+    psa_aead_decrypt, psa_aead_encrypt, psa_destroy_key, psa_export_public_key, psa_generate_key,
+    psa_import_key, psa_sign_hash, psa_verify_hash,
 };
 use parsec_interface::requests::{Opcode, ProviderID, ResponseStatus, Result};
 use pkcs11::types::{CKF_OS_LOCKING_OK, CK_C_INITIALIZE_ARGS, CK_SLOT_ID};
 use pkcs11::Ctx;
-use std::collections::HashSet;
+use public_key_cache::PublicKeyCache;
+use ring::digest;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::io::{Error, ErrorKind};
 use std::sync::{Arc, Mutex, RwLock};
 use utils::{KeyPairType, ReadWriteSession, RsaPublicKey, Session};
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 type LocalIdStore = HashSet<[u8; 4]>;
 
+mod aead;
 mod asym_sign;
+mod certificate;
 mod key_management;
+mod key_unlock;
+mod public_key_cache;
+mod user_pin;
 mod utils;
 
-const SUPPORTED_OPCODES: [Opcode; 6] = [
+const SUPPORTED_OPCODES: [Opcode; 8] = [
     Opcode::PsaGenerateKey,
     Opcode::PsaDestroyKey,
     Opcode::PsaSignHash,
     Opcode::PsaVerifyHash,
     Opcode::PsaImportKey,
     Opcode::PsaExportPublicKey,
+    Opcode::PsaAeadEncrypt,
+    Opcode::PsaAeadDecrypt,
 ];
 
 /// Provider for Public Key Cryptography Standard #11
@@ -47,7 +93,7 @@ const SUPPORTED_OPCODES: [Opcode; 6] = [
 #[derivative(Debug)]
 pub struct Pkcs11Provider {
     #[derivative(Debug = "ignore")]
-    key_info_store: Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>,
+    key_info_store: Arc<KeyInfoStore>,
     // TODO: the local ID store is currently only used to prevent creating a key that does not
     // exist, it should also act as a cache for non-desctrucitve operations. Same for Mbed Crypto.
     local_ids: RwLock<LocalIdStore>,
@@ -63,36 +109,69 @@ pub struct Pkcs11Provider {
     backend: Ctx,
     slot_number: CK_SLOT_ID,
     // Some PKCS 11 devices do not need a pin, the None variant means that.
-    user_pin: Option<String>,
+    // The pin is wiped from memory when the provider is dropped or when it is changed.
+    #[derivative(Debug = "ignore")]
+    user_pin: RwLock<Option<Zeroizing<String>>>,
+    // SHA-256 digest of the library loaded, encoded in hexadecimal.
+    library_sha256: String,
+    key_id_range: KeyIdRange,
+    // Public keys cached to keep verifying when the token is unreachable, if enabled.
+    public_key_cache: Option<PublicKeyCache>,
+    key_locks: KeyLocks,
+    mapping_health: MappingHealth,
+    // PINs of the keys needing a context specific login, unlocked for their next signature.
+    #[derivative(Debug = "ignore")]
+    context_logins: Mutex<HashMap<KeyTriple, Zeroizing<String>>>,
 }
 
+/// Range of the key IDs allocated by default, all the 4 bytes IDs
+pub const DEFAULT_KEY_ID_RANGE: KeyIdRange = KeyIdRange {
+    min: 0,
+    max: u32::MAX,
+};
+
+/// Number of operations executed concurrently with the same key by default
+pub const DEFAULT_MAX_CONCURRENT_OPERATIONS_PER_KEY: usize = 1;
+
 impl Pkcs11Provider {
     /// Creates and initialise a new instance of Pkcs11Provider.
     /// Checks if there are not more keys stored in the Key Info Manager than in the PKCS 11 library
-    /// and if there are, delete them. Adds Key IDs currently in use in the local IDs store.
-    /// Returns `None` if the initialisation failed.
+    /// and if there are, delete them unless `purge_orphaned_mappings` is false. Rolls back the
+    /// pending mappings of the keys whose creation was interrupted, destroying the key objects if
+    /// they were created. Adds Key IDs currently in use in the local IDs store.
+    /// Returns `None` if the initialisation failed or if stored keys are not in the key ID range.
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        key_info_store: Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>,
+        key_info_store: Arc<KeyInfoStore>,
         backend: Ctx,
         slot_number: usize,
-        user_pin: Option<String>,
+        user_pin: Option<Zeroizing<String>>,
+        library_sha256: String,
+        key_id_range: KeyIdRange,
+        public_key_cache: Option<PublicKeyCache>,
+        key_locks: KeyLocks,
+        purge_orphaned_mappings: bool,
     ) -> Option<Pkcs11Provider> {
         #[allow(clippy::mutex_atomic)]
-        let pkcs11_provider = Pkcs11Provider {
+        let mut pkcs11_provider = Pkcs11Provider {
             key_info_store,
             local_ids: RwLock::new(HashSet::new()),
             logged_sessions_counter: Mutex::new(0),
             backend,
             slot_number,
-            user_pin,
+            user_pin: RwLock::new(user_pin),
+            library_sha256,
+            key_id_range,
+            public_key_cache,
+            key_locks,
+            mapping_health: Default::default(),
+            context_logins: Mutex::new(HashMap::new()),
         };
+        let mut mapping_health = MappingHealth::default();
         {
             // The local scope allows to drop store_handle and local_ids_handle in order to return
             // the pkcs11_provider.
-            let mut store_handle = pkcs11_provider
-                .key_info_store
-                .write()
-                .expect("Key store lock poisoned");
+            let mut store_handle = pkcs11_provider.key_info_store.write();
             let mut local_ids_handle = pkcs11_provider
                 .local_ids
                 .write()
@@ -105,11 +184,12 @@ impl Pkcs11Provider {
                 Ok(key_triples) => {
                     let session =
                         Session::new(&pkcs11_provider, ReadWriteSession::ReadOnly).ok()?;
+                    mapping_health.checked = key_triples.len();
 
                     for key_triple in key_triples.iter().cloned() {
                         let (key_id, _) = match key_management::get_key_info(
                             key_triple,
-                            &*store_handle,
+                            &store_handle,
                         ) {
                             Ok(key_id) => key_id,
                             Err(response_status) => {
@@ -121,6 +201,9 @@ impl Pkcs11Provider {
                                 continue;
                             }
                         };
+                        if !key_id_range.check_stored_id(key_triple, u32::from_be_bytes(key_id)) {
+                            return None;
+                        }
                         match pkcs11_provider.find_key(
                             session.session_handle(),
                             key_id,
@@ -138,13 +221,27 @@ impl Pkcs11Provider {
                                 let _ = local_ids_handle.insert(key_id);
                             }
                             Err(ResponseStatus::PsaErrorDoesNotExist) => {
+                                mapping_health.orphaned += 1;
+                                if !purge_orphaned_mappings {
+                                    if crate::utils::GlobalConfig::log_error_details() {
+                                        warn!(
+                                            "Key {} not found in the PKCS 11 library, keeping it.",
+                                            key_triple
+                                        );
+                                    } else {
+                                        warn!("Key not found in the PKCS 11 library, keeping it.");
+                                    }
+                                    // The ID is not given to a new key while the mapping exists.
+                                    let _ = local_ids_handle.insert(key_id);
+                                    continue;
+                                }
                                 if crate::utils::GlobalConfig::log_error_details() {
                                     warn!(
                                         "Key {} not found in the PKCS 11 library, deleting it.",
                                         key_triple
                                     );
                                 } else {
-                                    warn!("Key not found in the PKCS 11 library, adding it.");
+                                    warn!("Key not found in the PKCS 11 library, deleting it.");
                                 }
                                 to_remove.push(key_triple.clone());
                             }
@@ -165,8 +262,60 @@ impl Pkcs11Provider {
                     format_error!("Key Info Manager error", string);
                     return None;
                 }
+                mapping_health.purged += 1;
+            }
+
+            let pending: Vec<(KeyTriple, KeyInfo)> =
+                match store_handle.get_all_pending(ProviderID::Pkcs11) {
+                    Ok(pending) => pending
+                        .into_iter()
+                        .map(|(key_triple, key_info)| (key_triple.clone(), key_info.clone()))
+                        .collect(),
+                    Err(string) => {
+                        format_error!("Key Info Manager error", string);
+                        return None;
+                    }
+                };
+            if !pending.is_empty() {
+                let session = Session::new(&pkcs11_provider, ReadWriteSession::ReadWrite).ok()?;
+                for (key_triple, key_info) in pending.iter() {
+                    // The pending mappings are not covered by the integrity tag: the objects are
+                    // only destroyed if their ID is one this provider allocates and no other key
+                    // uses.
+                    if key_info.id.len() == 4 {
+                        let mut key_id = [0; 4];
+                        key_id.copy_from_slice(&key_info.id);
+                        if key_id_range.contains(u32::from_be_bytes(key_id))
+                            && !local_ids_handle.contains(&key_id)
+                        {
+                            if let Err(e) =
+                                pkcs11_provider.destroy_objects(session.session_handle(), key_id)
+                            {
+                                format_error!(
+                                    "Failed to destroy a key whose creation was interrupted",
+                                    e
+                                );
+                                return None;
+                            }
+                        }
+                    } else {
+                        warn!("The pending mapping of a key has an invalid ID, only the mapping is rolled back.");
+                    }
+                    if let Err(string) = store_handle.abort(key_triple) {
+                        format_error!("Key Info Manager error", string);
+                        return None;
+                    }
+                    mapping_health.rolled_back += 1;
+                }
             }
         }
+        if mapping_health.rolled_back > 0 {
+            warn!(
+                "Rolled back the creation of {} keys interrupted before their mapping was committed.",
+                mapping_health.rolled_back
+            );
+        }
+        pkcs11_provider.mapping_health = mapping_health;
 
         Some(pkcs11_provider)
     }
@@ -180,8 +329,9 @@ impl Provide for Pkcs11Provider {
                 // Assigned UUID for this provider: 30e39502-eba6-4d60-a4af-c518b7f5e38f
                 uuid: Uuid::parse_str("30e39502-eba6-4d60-a4af-c518b7f5e38f")
                     .or(Err(ResponseStatus::InvalidEncoding))?,
-                description: String::from(
-                    "PKCS #11 provider, interfacing with a PKCS #11 library.",
+                description: format!(
+                    "PKCS #11 provider, interfacing with a PKCS #11 library (SHA-256: {}).",
+                    self.library_sha256
                 ),
                 vendor: String::from("OASIS Standard."),
                 version_maj: 0,
@@ -202,6 +352,15 @@ impl Provide for Pkcs11Provider {
         self.psa_generate_key_internal(app_name, op)
     }
 
+    fn psa_generate_keys(
+        &self,
+        app_name: ApplicationName,
+        ops: Vec<psa_generate_key::Operation>,
+    ) -> Result<Vec<Result<psa_generate_key::Result>>> {
+        trace!("psa_generate_keys ingress");
+        self.psa_generate_keys_internal(app_name, ops)
+    }
+
     fn psa_import_key(
         &self,
         app_name: ApplicationName,
@@ -217,7 +376,7 @@ impl Provide for Pkcs11Provider {
         op: psa_export_public_key::Operation,
     ) -> Result<psa_export_public_key::Result> {
         trace!("psa_export_public_key ingress");
-        self.psa_export_public_key_internal(app_name, op)
+        self.psa_export_public_key_cached(app_name, op)
     }
 
     fn psa_destroy_key(
@@ -226,6 +385,16 @@ impl Provide for Pkcs11Provider {
         op: psa_destroy_key::Operation,
     ) -> Result<psa_destroy_key::Result> {
         trace!("psa_destroy_key ingress");
+        self.forget_public_key(&app_name, &op.key_name);
+        let _ = self
+            .context_logins
+            .lock()
+            .expect("Context logins lock poisoned")
+            .remove(&KeyTriple::new(
+                app_name.clone(),
+                ProviderID::Pkcs11,
+                op.key_name.clone(),
+            ));
         self.psa_destroy_key_internal(app_name, op)
     }
 
@@ -235,7 +404,18 @@ impl Provide for Pkcs11Provider {
         op: psa_sign_hash::Operation,
     ) -> Result<psa_sign_hash::Result> {
         trace!("psa_sign_hash ingress");
+        let _key_lock = self.key_locks.lock(&KeyTriple::new(
+            app_name.clone(),
+            ProviderID::Pkcs11,
+            op.key_name.clone(),
+        ));
         self.psa_sign_hash_internal(app_name, op)
+            .map_err(|status| {
+                if self.public_key_cache.is_some() && public_key_cache::is_unreachable(status) {
+                    error!("The PKCS 11 token is unreachable, only verifications with the cached public keys are available.");
+                }
+                status
+            })
     }
 
     fn psa_verify_hash(
@@ -244,7 +424,108 @@ impl Provide for Pkcs11Provider {
         op: psa_verify_hash::Operation,
     ) -> Result<psa_verify_hash::Result> {
         trace!("psa_verify_hash ingress");
-        self.psa_verify_hash_internal(app_name, op)
+        let _key_lock = self.key_locks.lock(&KeyTriple::new(
+            app_name.clone(),
+            ProviderID::Pkcs11,
+            op.key_name.clone(),
+        ));
+        self.psa_verify_hash_cached(app_name, op)
+    }
+
+    fn psa_aead_encrypt(
+        &self,
+        app_name: ApplicationName,
+        op: psa_aead_encrypt::Operation,
+    ) -> Result<psa_aead_encrypt::Result> {
+        trace!("psa_aead_encrypt ingress");
+        let _key_lock = self.key_locks.lock(&KeyTriple::new(
+            app_name.clone(),
+            ProviderID::Pkcs11,
+            op.key_name.clone(),
+        ));
+        self.psa_aead_encrypt_internal(app_name, op)
+    }
+
+    fn psa_aead_decrypt(
+        &self,
+        app_name: ApplicationName,
+        op: psa_aead_decrypt::Operation,
+    ) -> Result<psa_aead_decrypt::Result> {
+        trace!("psa_aead_decrypt ingress");
+        let _key_lock = self.key_locks.lock(&KeyTriple::new(
+            app_name.clone(),
+            ProviderID::Pkcs11,
+            op.key_name.clone(),
+        ));
+        self.psa_aead_decrypt_internal(app_name, op)
+    }
+
+    fn store_certificate(
+        &self,
+        app_name: ApplicationName,
+        key_name: String,
+        certificate: Vec<u8>,
+    ) -> Result<()> {
+        trace!("store_certificate ingress");
+        self.store_certificate_internal(app_name, key_name, certificate)
+    }
+
+    fn get_certificate(&self, app_name: ApplicationName, key_name: String) -> Result<Vec<u8>> {
+        trace!("get_certificate ingress");
+        self.get_certificate_internal(app_name, key_name)
+    }
+
+    fn delete_certificate(&self, app_name: ApplicationName, key_name: String) -> Result<()> {
+        trace!("delete_certificate ingress");
+        self.delete_certificate_internal(app_name, key_name)
+    }
+
+    fn key_requires_unlock(&self, app_name: &ApplicationName, key_name: &str) -> Result<bool> {
+        trace!("key_requires_unlock ingress");
+        self.key_requires_unlock_internal(app_name, key_name)
+    }
+
+    fn unlock_key(
+        &self,
+        app_name: ApplicationName,
+        key_name: String,
+        credential: &[u8],
+    ) -> Result<()> {
+        trace!("unlock_key ingress");
+        self.unlock_key_internal(app_name, key_name, credential)
+    }
+
+    fn change_backend_auth(&self, auth: Zeroizing<Vec<u8>>) -> Result<()> {
+        trace!("change_backend_auth ingress");
+        self.change_backend_auth_internal(auth)
+    }
+
+    fn mapping_health(&self) -> Option<MappingHealth> {
+        trace!("mapping_health ingress");
+        Some(self.mapping_health)
+    }
+
+    fn key_exists(&self, key_triple: &KeyTriple) -> Result<bool> {
+        trace!("key_exists ingress");
+        let (key_id, _) = key_management::get_key_info(key_triple, &self.key_info_store.read())?;
+        let session = Session::new(self, ReadWriteSession::ReadOnly)?;
+        match self.find_key(session.session_handle(), key_id, KeyPairType::Any) {
+            Ok(_) => Ok(true),
+            Err(ResponseStatus::PsaErrorDoesNotExist) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn cache_usage(&self) -> Option<CacheUsage> {
+        trace!("cache_usage ingress");
+        self.public_key_cache.as_ref().map(PublicKeyCache::usage)
+    }
+
+    fn shrink_caches(&self) {
+        trace!("shrink_caches ingress");
+        if let Some(public_key_cache) = &self.public_key_cache {
+            public_key_cache.shrink();
+        }
     }
 }
 
@@ -262,10 +543,18 @@ impl Drop for Pkcs11Provider {
 #[derivative(Debug)]
 pub struct Pkcs11ProviderBuilder {
     #[derivative(Debug = "ignore")]
-    key_info_store: Option<Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>>,
+    key_info_store: Option<Arc<KeyInfoStore>>,
     pkcs11_library_path: Option<String>,
     slot_number: Option<usize>,
-    user_pin: Option<String>,
+    #[derivative(Debug = "ignore")]
+    user_pin: Option<Zeroizing<String>>,
+    library_sha256: Option<String>,
+    key_id_range: Option<KeyIdRange>,
+    offline_verify: Option<bool>,
+    public_key_cache_capacity: Option<usize>,
+    public_key_cache_eviction: Option<EvictionPolicy>,
+    max_concurrent_operations_per_key: Option<usize>,
+    purge_orphaned_mappings: Option<bool>,
 }
 
 impl Pkcs11ProviderBuilder {
@@ -275,12 +564,19 @@ impl Pkcs11ProviderBuilder {
             pkcs11_library_path: None,
             slot_number: None,
             user_pin: None,
+            library_sha256: None,
+            key_id_range: None,
+            offline_verify: None,
+            public_key_cache_capacity: None,
+            public_key_cache_eviction: None,
+            max_concurrent_operations_per_key: None,
+            purge_orphaned_mappings: None,
         }
     }
 
     pub fn with_key_info_store(
         mut self,
-        key_info_store: Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>,
+        key_info_store: Arc<KeyInfoStore>,
     ) -> Pkcs11ProviderBuilder {
         self.key_info_store = Some(key_info_store);
 
@@ -302,13 +598,93 @@ impl Pkcs11ProviderBuilder {
         self
     }
 
+    /// Sets the user pin. If the value starts with one of the `file:`, `env:` or `cred:` prefixes,
+    /// the pin is read from the corresponding source when the provider is built.
     pub fn with_user_pin(mut self, user_pin: Option<String>) -> Pkcs11ProviderBuilder {
-        self.user_pin = user_pin;
+        self.user_pin = user_pin.map(Zeroizing::new);
+
+        self
+    }
+
+    /// Pins the expected SHA-256 digest of the library, encoded in hexadecimal.
+    pub fn with_library_sha256(mut self, library_sha256: Option<String>) -> Pkcs11ProviderBuilder {
+        self.library_sha256 = library_sha256;
+
+        self
+    }
+
+    /// Sets the range in which the IDs of the new keys are chosen, defaults to
+    /// `DEFAULT_KEY_ID_RANGE`.
+    pub fn with_key_id_range(mut self, key_id_range: Option<KeyIdRange>) -> Pkcs11ProviderBuilder {
+        self.key_id_range = key_id_range;
+
+        self
+    }
+
+    /// Allows verifying with the cached public keys when the token is unreachable, defaults to
+    /// false.
+    pub fn with_offline_verify(mut self, offline_verify: Option<bool>) -> Pkcs11ProviderBuilder {
+        self.offline_verify = offline_verify;
+
+        self
+    }
+
+    /// Sets the number of public keys cached for the offline verifications,
+    /// `public_key_cache::DEFAULT_CAPACITY` if not set, and what the full cache does with a new
+    /// key, evicting the least recently used one if not set.
+    pub fn with_public_key_cache(
+        mut self,
+        capacity: Option<usize>,
+        eviction: Option<EvictionPolicy>,
+    ) -> Pkcs11ProviderBuilder {
+        self.public_key_cache_capacity = capacity;
+        self.public_key_cache_eviction = eviction;
+
+        self
+    }
+
+    /// Limit the number of signatures and verifications executed concurrently with the same key,
+    /// `DEFAULT_MAX_CONCURRENT_OPERATIONS_PER_KEY` if not set.
+    pub fn with_max_concurrent_operations_per_key(
+        mut self,
+        max_concurrent_operations_per_key: Option<usize>,
+    ) -> Pkcs11ProviderBuilder {
+        self.max_concurrent_operations_per_key = max_concurrent_operations_per_key;
+
+        self
+    }
+
+    fn get_user_pin(&self) -> std::io::Result<Option<Zeroizing<String>>> {
+        let user_pin = match &self.user_pin {
+            Some(user_pin) => user_pin,
+            None => return Ok(None),
+        };
+        match secrets::read_external_secret(user_pin) {
+            Some(secret) => {
+                let secret = Zeroizing::new(secret?);
+                let user_pin = std::str::from_utf8(&secret).or_else(|_| {
+                    error!("The user pin is not valid UTF-8.");
+                    Err(Error::new(ErrorKind::InvalidData, "invalid user pin"))
+                })?;
+                Ok(Some(Zeroizing::new(String::from(user_pin))))
+            }
+            None => Ok(Some(user_pin.clone())),
+        }
+    }
+
+    /// Sets if the mappings whose key object does not exist are removed when the provider
+    /// starts, defaults to true.
+    pub fn with_purge_orphaned_mappings(
+        mut self,
+        purge_orphaned_mappings: Option<bool>,
+    ) -> Pkcs11ProviderBuilder {
+        self.purge_orphaned_mappings = purge_orphaned_mappings;
 
         self
     }
 
     pub fn build(self) -> std::io::Result<Pkcs11Provider> {
+        let user_pin = self.get_user_pin()?;
         let library_path = self
             .pkcs11_library_path
             .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing library path"))?;
@@ -319,6 +695,23 @@ impl Pkcs11ProviderBuilder {
         let slot_number = self
             .slot_number
             .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing slot number"))?;
+        let key_id_range = self.key_id_range.unwrap_or(DEFAULT_KEY_ID_RANGE);
+        key_id_range.check_within(DEFAULT_KEY_ID_RANGE)?;
+        let key_locks = KeyLocks::new(
+            self.max_concurrent_operations_per_key
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_OPERATIONS_PER_KEY),
+        )?;
+        let library_sha256 = measure_library(&library_path)?;
+        info!("SHA-256 digest of the PKCS 11 library: {}", library_sha256);
+        if let Some(expected_sha256) = &self.library_sha256 {
+            if !expected_sha256.eq_ignore_ascii_case(&library_sha256) {
+                error!("The PKCS 11 library does not match the SHA-256 digest pinned in the configuration, refusing to load it.");
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "PKCS 11 library digest mismatch",
+                ));
+            }
+        }
         let mut backend = Ctx::new(library_path).or_else(|e| {
             format_error!("Error creating a PKCS 11 context", e);
             Err(Error::new(
@@ -346,8 +739,86 @@ impl Pkcs11ProviderBuilder {
                 .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing key info store"))?,
             backend,
             slot_number,
-            self.user_pin,
+            user_pin,
+            library_sha256,
+            key_id_range,
+            if self.offline_verify.unwrap_or(false) {
+                Some(PublicKeyCache::new(
+                    self.public_key_cache_capacity
+                        .unwrap_or(public_key_cache::DEFAULT_CAPACITY),
+                    self.public_key_cache_eviction
+                        .unwrap_or(EvictionPolicy::LeastRecentlyUsed),
+                ))
+            } else {
+                None
+            },
+            key_locks,
+            self.purge_orphaned_mappings.unwrap_or(true),
         )
         .ok_or_else(|| Error::new(ErrorKind::InvalidData, "PKCS 11 initialization failed"))?)
     }
 }
+
+// Computes the SHA-256 digest of the library, encoded in hexadecimal.
+fn measure_library(library_path: &str) -> std::io::Result<String> {
+    let library = fs::read(library_path).or_else(|e| {
+        format_error!("Failed to read the PKCS 11 library", e);
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "PKCS 11 library can not be read",
+        ))
+    })?;
+
+    Ok(hex::encode(digest::digest(&digest::SHA256, &library)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Pkcs11ProviderBuilder;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn user_pin_read_from_its_source() {
+        let builder = |user_pin: Option<&str>| {
+            Pkcs11ProviderBuilder::new().with_user_pin(user_pin.map(String::from))
+        };
+        assert!(builder(None).get_user_pin().unwrap().is_none());
+        assert_eq!(
+            builder(Some("123456"))
+                .get_user_pin()
+                .unwrap()
+                .unwrap()
+                .as_str(),
+            "123456"
+        );
+
+        let path = env!("OUT_DIR").to_owned() + "/user_pin";
+        fs::write(&path, "654321\n").unwrap();
+        assert_eq!(
+            builder(Some(&format!("file:{}", path)))
+                .get_user_pin()
+                .unwrap()
+                .unwrap()
+                .as_str(),
+            "654321"
+        );
+        fs::write(&path, [0xff, 0xfe]).unwrap();
+        assert!(builder(Some(&format!("file:{}", path)))
+            .get_user_pin()
+            .is_err());
+
+        env::set_var("PARSEC_TEST_USER_PIN", "111111");
+        assert_eq!(
+            builder(Some("env:PARSEC_TEST_USER_PIN"))
+                .get_user_pin()
+                .unwrap()
+                .unwrap()
+                .as_str(),
+            "111111"
+        );
+        assert!(builder(Some("env:PARSEC_TEST_MISSING_USER_PIN"))
+            .get_user_pin()
+            .is_err());
+    }
+}
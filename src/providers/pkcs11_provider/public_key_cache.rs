@@ -0,0 +1,287 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Verification with cached public keys when the token is unreachable
+//!
+//! When enabled, the public keys exported from the token are kept in memory so that, if the token
+//! becomes unreachable, the verification and public key export operations of these keys keep
+//! working, the former being done in software. The other operations fail with the status of the
+//! token error. Only the keys whose public half has been exported once since the service started
+//! are cached.
+//!
+//! The number of public keys cached is limited. A full cache evicts the key used the least
+//! recently or keeps its keys, depending on its eviction policy, and half of the keys are evicted
+//! when the service is under memory pressure.
+use super::{key_management::get_key_info, Pkcs11Provider};
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::KeyTriple;
+use crate::providers::{CacheUsage, EvictionPolicy};
+use log::{error, warn};
+use parsec_interface::operations::psa_algorithm::{AsymmetricSignature, Hash as PsaHash, SignHash};
+use parsec_interface::operations::{psa_export_public_key, psa_verify_hash};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use rsa::{Hash, PaddingScheme, PublicKey, RSAPublicKey};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+/// Number of public keys cached by default
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+struct CachedPublicKey {
+    public_key: Vec<u8>,
+    // Value of the use counter of the cache when the key was last used.
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    public_keys: HashMap<KeyTriple, CachedPublicKey>,
+    uses: u64,
+    evictions: u64,
+}
+
+impl Entries {
+    fn next_use(&mut self) -> u64 {
+        self.uses += 1;
+        self.uses
+    }
+
+    // Evicts the given number of keys, the least recently used first.
+    fn evict(&mut self, count: usize) {
+        let mut last_uses: Vec<u64> = self
+            .public_keys
+            .values()
+            .map(|cached| cached.last_used)
+            .collect();
+        if count == 0 || last_uses.is_empty() {
+            return;
+        }
+        last_uses.sort_unstable();
+        let threshold = last_uses[count.min(last_uses.len()) - 1];
+        let len = self.public_keys.len();
+        self.public_keys
+            .retain(|_, cached| cached.last_used > threshold);
+        self.evictions += (len - self.public_keys.len()) as u64;
+    }
+}
+
+/// Public keys exported from the token, in the PKCS #1 `RSAPublicKey` format
+#[derive(Debug)]
+pub struct PublicKeyCache {
+    entries: Mutex<Entries>,
+    capacity: usize,
+    eviction: EvictionPolicy,
+}
+
+impl PublicKeyCache {
+    /// Creates a cache of at most `capacity` public keys.
+    pub fn new(capacity: usize, eviction: EvictionPolicy) -> PublicKeyCache {
+        PublicKeyCache {
+            entries: Default::default(),
+            capacity,
+            eviction,
+        }
+    }
+
+    fn lock_entries(&self) -> MutexGuard<Entries> {
+        self.entries.lock().expect("Public key cache lock poisoned")
+    }
+
+    /// Caches the public key exported for a key.
+    pub fn insert(&self, key_triple: KeyTriple, public_key: Vec<u8>) {
+        let mut entries = self.lock_entries();
+        if !entries.public_keys.contains_key(&key_triple)
+            && entries.public_keys.len() >= self.capacity
+        {
+            match self.eviction {
+                EvictionPolicy::LeastRecentlyUsed if self.capacity > 0 => entries.evict(1),
+                _ => return,
+            }
+        }
+        let last_used = entries.next_use();
+        let _ = entries.public_keys.insert(
+            key_triple,
+            CachedPublicKey {
+                public_key,
+                last_used,
+            },
+        );
+    }
+
+    /// Removes the public key of a destroyed key.
+    pub fn remove(&self, key_triple: &KeyTriple) {
+        let _ = self.lock_entries().public_keys.remove(key_triple);
+    }
+
+    /// Gets the cached public key of a key.
+    pub fn get(&self, key_triple: &KeyTriple) -> Option<Vec<u8>> {
+        let mut entries = self.lock_entries();
+        let last_used = entries.next_use();
+        let cached = entries.public_keys.get_mut(key_triple)?;
+        cached.last_used = last_used;
+        Some(cached.public_key.clone())
+    }
+
+    /// Evicts the least recently used half of the keys.
+    pub fn shrink(&self) {
+        let mut entries = self.lock_entries();
+        let count = (entries.public_keys.len() + 1) / 2;
+        entries.evict(count);
+    }
+
+    /// Gets the usage of the cache.
+    pub fn usage(&self) -> CacheUsage {
+        let entries = self.lock_entries();
+        CacheUsage {
+            entries: entries.public_keys.len(),
+            capacity: self.capacity,
+            evictions: entries.evictions,
+        }
+    }
+}
+
+/// Returns true if the status is the one of a token which can not be reached.
+pub fn is_unreachable(status: ResponseStatus) -> bool {
+    status == ResponseStatus::PsaErrorHardwareFailure
+        || status == ResponseStatus::PsaErrorCommunicationFailure
+}
+
+impl Pkcs11Provider {
+    // Gets the cached public key of a key if the token failed because it is unreachable, and
+    // returns the error of the token otherwise.
+    fn cached_public_key(&self, status: ResponseStatus, key_triple: &KeyTriple) -> Result<Vec<u8>> {
+        let public_key = match &self.public_key_cache {
+            Some(cache) if is_unreachable(status) => cache.get(key_triple),
+            _ => None,
+        };
+        match public_key {
+            Some(public_key) => {
+                warn!("The PKCS 11 token is unreachable, using the cached public key.");
+                Ok(public_key)
+            }
+            None => Err(status),
+        }
+    }
+
+    pub(super) fn psa_export_public_key_cached(
+        &self,
+        app_name: ApplicationName,
+        op: psa_export_public_key::Operation,
+    ) -> Result<psa_export_public_key::Result> {
+        let key_triple = KeyTriple::new(app_name.clone(), ProviderID::Pkcs11, op.key_name.clone());
+        match self.psa_export_public_key_internal(app_name, op) {
+            Ok(result) => {
+                if let Some(cache) = &self.public_key_cache {
+                    cache.insert(key_triple, result.data.clone());
+                }
+                Ok(result)
+            }
+            Err(status) => Ok(psa_export_public_key::Result {
+                data: self.cached_public_key(status, &key_triple)?,
+            }),
+        }
+    }
+
+    pub(super) fn psa_verify_hash_cached(
+        &self,
+        app_name: ApplicationName,
+        op: psa_verify_hash::Operation,
+    ) -> Result<psa_verify_hash::Result> {
+        if self.public_key_cache.is_none() {
+            return self.psa_verify_hash_internal(app_name, op);
+        }
+
+        let key_triple = KeyTriple::new(app_name.clone(), ProviderID::Pkcs11, op.key_name.clone());
+        let status = match self.psa_verify_hash_internal(app_name, op.clone()) {
+            Ok(result) => return Ok(result),
+            Err(status) => status,
+        };
+        let public_key = self.cached_public_key(status, &key_triple)?;
+
+        // The key info manager is local, the policy of the key can still be checked.
+        let (_, key_attributes) = get_key_info(&key_triple, &self.key_info_store.read())?;
+        key_attributes.can_verify_hash()?;
+        key_attributes.permits_alg(op.alg.into())?;
+        key_attributes.compatible_with_alg(op.alg.into())?;
+        if op.alg
+            != (AsymmetricSignature::RsaPkcs1v15Sign {
+                hash_alg: SignHash::Specific(PsaHash::Sha256),
+            })
+        {
+            error!("Only RSA PKCS#1 v1.5 signatures of SHA-256 hashes can be verified with the cached public keys.");
+            return Err(ResponseStatus::PsaErrorNotSupported);
+        }
+
+        verify_sha256_pkcs1v15(&public_key, &op.hash, &op.signature)?;
+        Ok(psa_verify_hash::Result {})
+    }
+
+    pub(super) fn forget_public_key(&self, app_name: &ApplicationName, key_name: &str) {
+        if let Some(cache) = &self.public_key_cache {
+            cache.remove(&KeyTriple::new(
+                app_name.clone(),
+                ProviderID::Pkcs11,
+                key_name.to_string(),
+            ));
+        }
+    }
+}
+
+/// Verifies a RSA PKCS #1 v1.5 signature of a SHA-256 hash in software.
+pub fn verify_sha256_pkcs1v15(public_key: &[u8], hash: &[u8], signature: &[u8]) -> Result<()> {
+    let public_key = RSAPublicKey::from_pkcs1(public_key).or_else(|e| {
+        format_error!("Failed to parse the cached public key", e);
+        Err(ResponseStatus::PsaErrorCorruptionDetected)
+    })?;
+
+    public_key
+        .verify(
+            PaddingScheme::new_pkcs1v15_sign(Some(Hash::SHA2_256)),
+            hash,
+            signature,
+        )
+        .or_else(|_| {
+            error!("The signature does not verify with the cached public key.");
+            Err(ResponseStatus::PsaErrorInvalidSignature)
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::PublicKeyCache;
+    use crate::authenticators::ApplicationName;
+    use crate::key_info_managers::KeyTriple;
+    use crate::providers::EvictionPolicy;
+    use parsec_interface::requests::ProviderID;
+
+    fn key_triple(key_name: &str) -> KeyTriple {
+        KeyTriple::new(
+            ApplicationName::new(String::from("app")),
+            ProviderID::Pkcs11,
+            String::from(key_name),
+        )
+    }
+
+    #[test]
+    fn least_recently_used_evicted() {
+        let cache = PublicKeyCache::new(2, EvictionPolicy::LeastRecentlyUsed);
+        cache.insert(key_triple("a"), vec![1]);
+        cache.insert(key_triple("b"), vec![2]);
+        let _ = cache.get(&key_triple("a"));
+        cache.insert(key_triple("c"), vec![3]);
+
+        assert_eq!(cache.get(&key_triple("a")), Some(vec![1]));
+        assert_eq!(cache.get(&key_triple("b")), None);
+        assert_eq!(cache.usage().evictions, 1);
+
+        cache.shrink();
+        assert_eq!(cache.usage().entries, 1);
+        assert_eq!(cache.get(&key_triple("a")), Some(vec![1]));
+
+        let cache = PublicKeyCache::new(1, EvictionPolicy::KeepExisting);
+        cache.insert(key_triple("a"), vec![1]);
+        cache.insert(key_triple("b"), vec![2]);
+        assert_eq!(cache.get(&key_triple("b")), None);
+        assert_eq!(cache.usage().evictions, 0);
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::utils;
+use super::Pkcs11Provider;
+use log::{error, info, trace, warn};
+use parsec_interface::requests::{ResponseStatus, Result};
+use pkcs11::errors::Error;
+use pkcs11::types::{
+    CKF_SERIAL_SESSION, CKR_PIN_INCORRECT, CKR_PIN_LEN_RANGE, CKR_PIN_LOCKED, CKU_USER,
+};
+use zeroize::Zeroizing;
+
+// The user PIN is changed on the token outside of the service, which is then given the new one to
+// log in with from then on. The new PIN is checked by logging in with it first, which is only
+// possible while no session is logged in: the user is already logged in otherwise and the token
+// does not check the PIN again.
+impl Pkcs11Provider {
+    pub(super) fn change_backend_auth_internal(&self, auth: Zeroizing<Vec<u8>>) -> Result<()> {
+        info!("Pkcs11 Provider - Change User Pin");
+
+        let pin = std::str::from_utf8(&auth).or_else(|_| {
+            error!("The user pin is not valid UTF-8.");
+            Err(ResponseStatus::PsaErrorInvalidArgument)
+        })?;
+        // The sessions log in under this lock: none logs in with the previous PIN meanwhile.
+        #[allow(clippy::mutex_atomic)]
+        let logged_sessions_counter = self
+            .logged_sessions_counter
+            .lock()
+            .expect("Error while locking mutex.");
+        if *logged_sessions_counter == 0 {
+            self.check_user_pin(pin)?;
+        } else {
+            warn!("The new user pin can not be checked while sessions are logged in with the previous one.");
+        }
+        *self.user_pin.write().expect("User pin lock poisoned") =
+            Some(Zeroizing::new(pin.to_string()));
+
+        Ok(())
+    }
+
+    // Logs in and out with the PIN given, on a session opened for this only.
+    fn check_user_pin(&self, pin: &str) -> Result<()> {
+        trace!("OpenSession command");
+        let session = self
+            .backend
+            .open_session(self.slot_number, CKF_SERIAL_SESSION, None, None)
+            .or_else(|e| {
+                format_error!("Error opening session", e);
+                Err(utils::to_response_status(e))
+            })?;
+
+        trace!("Login command");
+        let result = match self.backend.login(session, CKU_USER, Some(pin)) {
+            Ok(_) => {
+                trace!("Logout command");
+                self.backend.logout(session).or_else(|e| {
+                    format_error!("Logout operation failed", e);
+                    Err(utils::to_response_status(e))
+                })
+            }
+            Err(Error::Pkcs11(CKR_PIN_INCORRECT))
+            | Err(Error::Pkcs11(CKR_PIN_LEN_RANGE))
+            | Err(Error::Pkcs11(CKR_PIN_LOCKED)) => {
+                error!("The new user pin was refused by the token.");
+                Err(ResponseStatus::PsaErrorNotPermitted)
+            }
+            Err(e) => {
+                format_error!("Login operation failed", e);
+                Err(utils::to_response_status(e))
+            }
+        };
+
+        trace!("CloseSession command");
+        if let Err(e) = self.backend.close_session(session) {
+            format_error!("Error closing session", e);
+        }
+
+        result
+    }
+}
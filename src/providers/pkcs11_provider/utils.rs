@@ -19,8 +19,9 @@ use serde::{Deserialize, Serialize};
 /// library should suceed with the values crafted by the provider.
 /// If an error happens in the PKCS11 library, it means that it was badly used by the provider or
 /// that it failed in an unexpected way and hence the PsaErrorCommunicationFailure error.
-/// The errors translated to response status are related with signature verification failure, lack
-/// of memory, hardware failure, corruption detection, lack of entropy and unsupported operations.
+/// The errors translated to response status are related with signature and tag verification
+/// failure, lack of memory, hardware failure, corruption detection, lack of entropy and unsupported
+/// operations.
 pub fn to_response_status(error: Error) -> ResponseStatus {
     match error {
         Error::Io(e) => ResponseStatus::from(e),
@@ -41,6 +42,8 @@ pub fn rv_to_response_status(rv: CK_RV) -> ResponseStatus {
         CKR_DEVICE_REMOVED => ResponseStatus::PsaErrorHardwareFailure,
         CKR_SIGNATURE_INVALID => ResponseStatus::PsaErrorInvalidSignature,
         CKR_SIGNATURE_LEN_RANGE => ResponseStatus::PsaErrorInvalidSignature,
+        // The tag of the ciphertext given to an AEAD decryption does not match.
+        CKR_ENCRYPTED_DATA_INVALID => ResponseStatus::PsaErrorInvalidSignature,
         CKR_TOKEN_NOT_PRESENT => ResponseStatus::PsaErrorHardwareFailure,
         CKR_TOKEN_NOT_RECOGNIZED => ResponseStatus::PsaErrorHardwareFailure,
         CKR_RANDOM_NO_RNG => ResponseStatus::PsaErrorInsufficientEntropy,
@@ -72,13 +75,76 @@ pub struct RsaPublicKey {
 }
 
 // For PKCS 11, a key pair consists of two independant public and private keys. Both will share the
-// same key ID.
+// same key ID, as does the certificate optionally stored alongside them. A symmetric key is a
+// single secret key object.
 pub enum KeyPairType {
     PublicKey,
     PrivateKey,
+    SecretKey,
+    Certificate,
     Any,
 }
 
+// Splits the first DER element of the data into its tag, its content and the remaining data.
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.get(0)?;
+    let first_length_byte = *data.get(1)?;
+    let (header_len, content_len) = if first_length_byte < 0x80 {
+        (2, usize::from(first_length_byte))
+    } else {
+        let length_bytes = usize::from(first_length_byte & 0x7f);
+        if length_bytes == 0 || length_bytes > 4 {
+            return None;
+        }
+        let content_len = data
+            .get(2..2 + length_bytes)?
+            .iter()
+            .fold(0, |len, byte| (len << 8) | usize::from(*byte));
+        (2 + length_bytes, content_len)
+    };
+    let content = data.get(header_len..header_len.checked_add(content_len)?)?;
+
+    Some((tag, content, &data[header_len + content_len..]))
+}
+
+/// Extracts the DER-encoded subject name of a DER-encoded X.509 certificate.
+///
+/// Certificate ::= SEQUENCE { tbsCertificate TBSCertificate, ... }
+/// TBSCertificate ::= SEQUENCE { version [0] EXPLICIT OPTIONAL, serialNumber, signature, issuer,
+///                               validity, subject, ... }
+///
+/// Returns `None` if the data is not a well-formed certificate.
+pub fn x509_subject(certificate: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE_TAG: u8 = 0x30;
+    const VERSION_TAG: u8 = 0xa0;
+
+    let (tag, certificate_content, _) = der_element(certificate)?;
+    if tag != SEQUENCE_TAG {
+        return None;
+    }
+    let (tag, tbs_content, _) = der_element(certificate_content)?;
+    if tag != SEQUENCE_TAG {
+        return None;
+    }
+
+    let mut remaining = tbs_content;
+    let (tag, _, rest) = der_element(remaining)?;
+    if tag == VERSION_TAG {
+        remaining = rest;
+    }
+    // Skip the serial number, signature algorithm, issuer and validity.
+    for _ in 0..4 {
+        let (_, _, rest) = der_element(remaining)?;
+        remaining = rest;
+    }
+    let (tag, _, rest) = der_element(remaining)?;
+    if tag != SEQUENCE_TAG {
+        return None;
+    }
+
+    Some(&remaining[..remaining.len() - rest.len()])
+}
+
 // Representation of a PKCS 11 session.
 pub struct Session<'a> {
     provider: &'a Pkcs11Provider,
@@ -167,13 +233,19 @@ impl Session<'_> {
             *logged_sessions_counter += 1;
             self.is_logged_in = true;
             Ok(())
-        } else if let Some(user_pin) = self.provider.user_pin.as_ref() {
+        } else if let Some(user_pin) = self
+            .provider
+            .user_pin
+            .read()
+            .expect("User pin lock poisoned")
+            .as_ref()
+        {
             trace!("Login command");
-            match self
-                .provider
-                .backend
-                .login(self.session_handle, CKU_USER, Some(user_pin))
-            {
+            match self.provider.backend.login(
+                self.session_handle,
+                CKU_USER,
+                Some(user_pin.as_str()),
+            ) {
                 Ok(_) => {
                     if crate::utils::GlobalConfig::log_error_details() {
                         info!("Logging in session {}.", self.session_handle);
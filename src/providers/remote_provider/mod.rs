@@ -0,0 +1,475 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Remote provider
+//!
+//! This provider forwards the operations to a provider of another Parsec service, for example so
+//! that edge devices without secure hardware can use the HSM of a gateway. It takes the ID of the
+//! remote provider, so that clients use it exactly as if it was local, and stores no key: keys
+//! only exist in the remote service.
+//!
+//! Requests are sent with direct authentication, as the local application name prefixed by the
+//! configured `app_name_prefix`, over a Unix domain socket. This socket has to be the end of an
+//! authenticated and encrypted transport to the remote service, such as an SSH or TLS tunnel,
+//! which the remote service trusts to assert application names.
+//!
+//! The description and opcodes of the remote provider are discovered when the provider is built
+//! and cached, as asking the remote service is slow. The cache is marked stale when the answers of
+//! the remote service show that its providers changed, for example when it can not be reached or
+//! does not know the provider or the opcode any more, and is discovered again by
+//! `refresh_capabilities`.
+//!
+//! The answer of the remote service is waited for up to the configured timeout, or up to the
+//! deadline of the operation if it expires first.
+use super::{deadline, Provide};
+use crate::authenticators::ApplicationName;
+use crate::utils::correlation_id;
+use derivative::Derivative;
+use log::{error, info, trace};
+use parsec_interface::operations::list_providers::ProviderInfo;
+use parsec_interface::operations::{
+    list_opcodes, list_providers, psa_aead_decrypt, psa_aead_encrypt, psa_asymmetric_decrypt,
+    psa_asymmetric_encrypt, psa_destroy_key, psa_export_public_key, psa_generate_key,
+    psa_import_key, psa_raw_key_agreement, psa_sign_hash, psa_verify_hash,
+};
+use parsec_interface::operations::{Convert, NativeOperation, NativeResult};
+use parsec_interface::operations_protobuf::ProtobufConverter;
+use parsec_interface::requests::request::{RequestAuth, RequestHeader};
+use parsec_interface::requests::{
+    AuthType, BodyType, Opcode, ProviderID, Request, Response, ResponseStatus, Result,
+};
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+// Limit on the size of the response bodies accepted from the remote service.
+const RESPONSE_BODY_LEN_LIMIT: usize = 1 << 19;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Operations which can be forwarded, if the remote provider supports them.
+const FORWARDED_OPCODES: [Opcode; 11] = [
+    Opcode::PsaGenerateKey,
+    Opcode::PsaDestroyKey,
+    Opcode::PsaSignHash,
+    Opcode::PsaVerifyHash,
+    Opcode::PsaImportKey,
+    Opcode::PsaExportPublicKey,
+    Opcode::PsaAsymmetricEncrypt,
+    Opcode::PsaAsymmetricDecrypt,
+    Opcode::PsaAeadEncrypt,
+    Opcode::PsaAeadDecrypt,
+    Opcode::PsaRawKeyAgreement,
+];
+
+/// Provider forwarding the operations to a remote Parsec service
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct RemoteProvider {
+    socket_path: PathBuf,
+    provider_id: ProviderID,
+    app_name_prefix: String,
+    timeout: Duration,
+    #[derivative(Debug = "ignore")]
+    converter: ProtobufConverter,
+    capabilities: RwLock<(ProviderInfo, HashSet<Opcode>)>,
+    capabilities_stale: AtomicBool,
+}
+
+// Sends an operation to a provider of the remote service and returns its result.
+fn execute(
+    socket_path: &Path,
+    timeout: Duration,
+    converter: &ProtobufConverter,
+    provider_id: ProviderID,
+    auth: Option<String>,
+    operation: NativeOperation,
+) -> Result<NativeResult> {
+    let opcode = operation.opcode();
+    let (auth_type, auth) = match auth {
+        Some(app_name) => (AuthType::Direct, RequestAuth::new(app_name.into_bytes())),
+        None => (AuthType::NoAuth, RequestAuth::new(Vec::new())),
+    };
+    let request = Request {
+        header: RequestHeader {
+            provider: provider_id,
+            // The remote service logs the request with the same correlation ID, if the session
+            // field carries them.
+            session: correlation_id::current()
+                .filter(|_| crate::utils::GlobalConfig::session_correlation_ids())
+                .map_or(0, |correlation_id| correlation_id.value()),
+            content_type: BodyType::Protobuf,
+            accept_type: BodyType::Protobuf,
+            auth_type,
+            opcode,
+        },
+        body: converter.operation_to_body(operation)?,
+        auth,
+    };
+
+    // The remote service is not waited for past the deadline of the operation.
+    let deadline = deadline::current();
+    deadline.check()?;
+    let timeout = deadline.remaining().map_or(timeout, |remaining| {
+        remaining.min(timeout).max(Duration::from_millis(1))
+    });
+    let mut stream = UnixStream::connect(socket_path).or_else(|e| {
+        format_error!("Failed to connect to the remote service", e);
+        Err(ResponseStatus::ConnectionError)
+    })?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .or_else(|e| {
+            format_error!("Failed to set the timeout of the remote connection", e);
+            Err(ResponseStatus::ConnectionError)
+        })?;
+    request.write_to_stream(&mut stream)?;
+    let response = Response::read_from_stream(&mut stream, RESPONSE_BODY_LEN_LIMIT)?;
+    if response.header.status != ResponseStatus::Success {
+        format_error!(
+            &format!("The remote service failed to execute {:?}", opcode),
+            response.header.status
+        );
+        return Err(response.header.status);
+    }
+
+    converter.body_to_result(response.body, opcode)
+}
+
+// Returned when the remote service answers with the result of another operation.
+fn unexpected_result() -> ResponseStatus {
+    error!("The remote service returned the result of another operation.");
+    ResponseStatus::InvalidEncoding
+}
+
+// Gets the description and the forwarded opcodes of the provider from the remote service.
+fn discover(
+    socket_path: &Path,
+    timeout: Duration,
+    converter: &ProtobufConverter,
+    provider_id: ProviderID,
+) -> Result<(ProviderInfo, HashSet<Opcode>)> {
+    let mut provider_info = match execute(
+        socket_path,
+        timeout,
+        converter,
+        ProviderID::Core,
+        None,
+        NativeOperation::ListProviders(list_providers::Operation {}),
+    )? {
+        NativeResult::ListProviders(result) => result
+            .providers
+            .into_iter()
+            .find(|provider_info| provider_info.id == provider_id)
+            .ok_or_else(|| {
+                error!("The provider is not available in the remote service.");
+                ResponseStatus::ProviderNotRegistered
+            })?,
+        _ => return Err(unexpected_result()),
+    };
+    provider_info.description = format!("Remote: {}", provider_info.description);
+    let opcodes = match execute(
+        socket_path,
+        timeout,
+        converter,
+        ProviderID::Core,
+        None,
+        NativeOperation::ListOpcodes(list_opcodes::Operation { provider_id }),
+    )? {
+        NativeResult::ListOpcodes(result) => result
+            .opcodes
+            .into_iter()
+            .filter(|opcode| FORWARDED_OPCODES.contains(opcode))
+            .collect(),
+        _ => return Err(unexpected_result()),
+    };
+
+    Ok((provider_info, opcodes))
+}
+
+// Checks if the remote service failing with this status may have changed its providers.
+fn backend_changed(status: ResponseStatus) -> bool {
+    match status {
+        ResponseStatus::ConnectionError
+        | ResponseStatus::ProviderNotRegistered
+        | ResponseStatus::ProviderDoesNotExist
+        | ResponseStatus::OpcodeDoesNotExist => true,
+        _ => false,
+    }
+}
+
+impl RemoteProvider {
+    fn execute(
+        &self,
+        app_name: ApplicationName,
+        operation: NativeOperation,
+    ) -> Result<NativeResult> {
+        execute(
+            &self.socket_path,
+            self.timeout,
+            &self.converter,
+            self.provider_id,
+            Some(format!("{}{}", self.app_name_prefix, app_name)),
+            operation,
+        )
+        .map_err(|status| {
+            if backend_changed(status) {
+                self.capabilities_stale.store(true, Ordering::Relaxed);
+            }
+            status
+        })
+    }
+}
+
+impl Provide for RemoteProvider {
+    fn describe(&self) -> Result<(ProviderInfo, HashSet<Opcode>)> {
+        trace!("describe ingress");
+        Ok(self
+            .capabilities
+            .read()
+            .expect("Capabilities lock poisoned")
+            .clone())
+    }
+
+    fn capabilities_stale(&self) -> bool {
+        trace!("capabilities_stale ingress");
+        self.capabilities_stale.load(Ordering::Relaxed)
+    }
+
+    fn refresh_capabilities(&self) -> Result<Option<(ProviderInfo, HashSet<Opcode>)>> {
+        trace!("refresh_capabilities ingress");
+        let capabilities = discover(
+            &self.socket_path,
+            self.timeout,
+            &self.converter,
+            self.provider_id,
+        )?;
+        *self
+            .capabilities
+            .write()
+            .expect("Capabilities lock poisoned") = capabilities.clone();
+        self.capabilities_stale.store(false, Ordering::Relaxed);
+        info!(
+            "Capabilities of the remote provider discovered again ({} opcodes).",
+            capabilities.1.len()
+        );
+
+        Ok(Some(capabilities))
+    }
+
+    fn psa_generate_key(
+        &self,
+        app_name: ApplicationName,
+        op: psa_generate_key::Operation,
+    ) -> Result<psa_generate_key::Result> {
+        trace!("psa_generate_key ingress");
+        match self.execute(app_name, NativeOperation::PsaGenerateKey(op))? {
+            NativeResult::PsaGenerateKey(result) => Ok(result),
+            _ => Err(unexpected_result()),
+        }
+    }
+
+    fn psa_import_key(
+        &self,
+        app_name: ApplicationName,
+        op: psa_import_key::Operation,
+    ) -> Result<psa_import_key::Result> {
+        trace!("psa_import_key ingress");
+        match self.execute(app_name, NativeOperation::PsaImportKey(op))? {
+            NativeResult::PsaImportKey(result) => Ok(result),
+            _ => Err(unexpected_result()),
+        }
+    }
+
+    fn psa_export_public_key(
+        &self,
+        app_name: ApplicationName,
+        op: psa_export_public_key::Operation,
+    ) -> Result<psa_export_public_key::Result> {
+        trace!("psa_export_public_key ingress");
+        match self.execute(app_name, NativeOperation::PsaExportPublicKey(op))? {
+            NativeResult::PsaExportPublicKey(result) => Ok(result),
+            _ => Err(unexpected_result()),
+        }
+    }
+
+    fn psa_destroy_key(
+        &self,
+        app_name: ApplicationName,
+        op: psa_destroy_key::Operation,
+    ) -> Result<psa_destroy_key::Result> {
+        trace!("psa_destroy_key ingress");
+        match self.execute(app_name, NativeOperation::PsaDestroyKey(op))? {
+            NativeResult::PsaDestroyKey(result) => Ok(result),
+            _ => Err(unexpected_result()),
+        }
+    }
+
+    fn psa_sign_hash(
+        &self,
+        app_name: ApplicationName,
+        op: psa_sign_hash::Operation,
+    ) -> Result<psa_sign_hash::Result> {
+        trace!("psa_sign_hash ingress");
+        match self.execute(app_name, NativeOperation::PsaSignHash(op))? {
+            NativeResult::PsaSignHash(result) => Ok(result),
+            _ => Err(unexpected_result()),
+        }
+    }
+
+    fn psa_verify_hash(
+        &self,
+        app_name: ApplicationName,
+        op: psa_verify_hash::Operation,
+    ) -> Result<psa_verify_hash::Result> {
+        trace!("psa_verify_hash ingress");
+        match self.execute(app_name, NativeOperation::PsaVerifyHash(op))? {
+            NativeResult::PsaVerifyHash(result) => Ok(result),
+            _ => Err(unexpected_result()),
+        }
+    }
+
+    fn psa_asymmetric_encrypt(
+        &self,
+        app_name: ApplicationName,
+        op: psa_asymmetric_encrypt::Operation,
+    ) -> Result<psa_asymmetric_encrypt::Result> {
+        trace!("psa_asymmetric_encrypt ingress");
+        match self.execute(app_name, NativeOperation::PsaAsymmetricEncrypt(op))? {
+            NativeResult::PsaAsymmetricEncrypt(result) => Ok(result),
+            _ => Err(unexpected_result()),
+        }
+    }
+
+    fn psa_asymmetric_decrypt(
+        &self,
+        app_name: ApplicationName,
+        op: psa_asymmetric_decrypt::Operation,
+    ) -> Result<psa_asymmetric_decrypt::Result> {
+        trace!("psa_asymmetric_decrypt ingress");
+        match self.execute(app_name, NativeOperation::PsaAsymmetricDecrypt(op))? {
+            NativeResult::PsaAsymmetricDecrypt(result) => Ok(result),
+            _ => Err(unexpected_result()),
+        }
+    }
+
+    fn psa_aead_encrypt(
+        &self,
+        app_name: ApplicationName,
+        op: psa_aead_encrypt::Operation,
+    ) -> Result<psa_aead_encrypt::Result> {
+        trace!("psa_aead_encrypt ingress");
+        match self.execute(app_name, NativeOperation::PsaAeadEncrypt(op))? {
+            NativeResult::PsaAeadEncrypt(result) => Ok(result),
+            _ => Err(unexpected_result()),
+        }
+    }
+
+    fn psa_aead_decrypt(
+        &self,
+        app_name: ApplicationName,
+        op: psa_aead_decrypt::Operation,
+    ) -> Result<psa_aead_decrypt::Result> {
+        trace!("psa_aead_decrypt ingress");
+        match self.execute(app_name, NativeOperation::PsaAeadDecrypt(op))? {
+            NativeResult::PsaAeadDecrypt(result) => Ok(result),
+            _ => Err(unexpected_result()),
+        }
+    }
+
+    fn psa_raw_key_agreement(
+        &self,
+        app_name: ApplicationName,
+        op: psa_raw_key_agreement::Operation,
+    ) -> Result<psa_raw_key_agreement::Result> {
+        trace!("psa_raw_key_agreement ingress");
+        match self.execute(app_name, NativeOperation::PsaRawKeyAgreement(op))? {
+            NativeResult::PsaRawKeyAgreement(result) => Ok(result),
+            _ => Err(unexpected_result()),
+        }
+    }
+}
+
+/// Builder for RemoteProvider
+#[derive(Debug, Default)]
+pub struct RemoteProviderBuilder {
+    socket_path: Option<PathBuf>,
+    provider_id: Option<ProviderID>,
+    app_name_prefix: Option<String>,
+    timeout: Option<Duration>,
+}
+
+impl RemoteProviderBuilder {
+    pub fn new() -> RemoteProviderBuilder {
+        RemoteProviderBuilder {
+            socket_path: None,
+            provider_id: None,
+            app_name_prefix: None,
+            timeout: None,
+        }
+    }
+
+    pub fn with_socket_path(mut self, socket_path: PathBuf) -> RemoteProviderBuilder {
+        self.socket_path = Some(socket_path);
+
+        self
+    }
+
+    pub fn with_provider_id(mut self, provider_id: ProviderID) -> RemoteProviderBuilder {
+        self.provider_id = Some(provider_id);
+
+        self
+    }
+
+    pub fn with_app_name_prefix(
+        mut self,
+        app_name_prefix: Option<String>,
+    ) -> RemoteProviderBuilder {
+        self.app_name_prefix = app_name_prefix;
+
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> RemoteProviderBuilder {
+        self.timeout = timeout;
+
+        self
+    }
+
+    /// Builds the provider, getting the description and the opcodes of the remote provider from
+    /// the remote service.
+    pub fn build(self) -> std::io::Result<RemoteProvider> {
+        let socket_path = self
+            .socket_path
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing socket path"))?;
+        let provider_id = self
+            .provider_id
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing provider ID"))?;
+        let timeout = self.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        let converter = ProtobufConverter {};
+        info!(
+            "Building a remote provider for provider {} of the service behind {}",
+            provider_id,
+            socket_path.display()
+        );
+
+        let capabilities =
+            discover(&socket_path, timeout, &converter, provider_id).map_err(|status| {
+                format_error!("Failed to describe the remote provider", status);
+                Error::new(ErrorKind::InvalidData, "remote provider not available")
+            })?;
+
+        Ok(RemoteProvider {
+            socket_path,
+            provider_id,
+            app_name_prefix: self.app_name_prefix.unwrap_or_default(),
+            timeout,
+            converter,
+            capabilities: RwLock::new(capabilities),
+            capabilities_stale: AtomicBool::new(false),
+        })
+    }
+}
@@ -16,14 +16,14 @@ impl TpmProvider {
     ) -> Result<psa_sign_hash::Result> {
         let key_triple = KeyTriple::new(app_name, ProviderID::Tpm, op.key_name.clone());
 
-        let store_handle = self.key_info_store.read().expect("Key store lock poisoned");
+        let store_handle = self.key_info_store.read();
         let mut esapi_context = self
             .esapi_context
             .lock()
             .expect("ESAPI Context lock poisoned");
 
         let (password_context, key_attributes) =
-            key_management::get_password_context(&*store_handle, key_triple)?;
+            key_management::get_password_context(&store_handle, key_triple)?;
 
         match op.alg {
             AsymmetricSignature::RsaPkcs1v15Sign { .. } => (),
@@ -43,18 +43,13 @@ impl TpmProvider {
 
         op.validate(key_attributes)?;
 
-        let signature = esapi_context
-            .sign(
-                password_context.context,
+        let signature = utils::retry(|| {
+            esapi_context.sign(
+                password_context.context.clone(),
                 &password_context.auth_value,
                 &op.hash,
             )
-            .or_else(|e| {
-                if crate::utils::GlobalConfig::log_error_details() {
-                    error!("Error signing: {}.", e);
-                }
-                Err(utils::to_response_status(e))
-            })?;
+        })?;
 
         Ok(psa_sign_hash::Result {
             signature: utils::signature_data_to_bytes(signature.signature, key_attributes)?,
@@ -68,14 +63,14 @@ impl TpmProvider {
     ) -> Result<psa_verify_hash::Result> {
         let key_triple = KeyTriple::new(app_name, ProviderID::Tpm, op.key_name.clone());
 
-        let store_handle = self.key_info_store.read().expect("Key store lock poisoned");
+        let store_handle = self.key_info_store.read();
         let mut esapi_context = self
             .esapi_context
             .lock()
             .expect("ESAPI Context lock poisoned");
 
         let (password_context, key_attributes) =
-            key_management::get_password_context(&*store_handle, key_triple)?;
+            key_management::get_password_context(&store_handle, key_triple)?;
 
         match op.alg {
             AsymmetricSignature::RsaPkcs1v15Sign { .. } => (),
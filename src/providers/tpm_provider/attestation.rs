@@ -0,0 +1,154 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::utils::{self, RsaPublicKey};
+use super::{TpmProvider, ROOT_KEY_SIZE};
+use crate::back::platform_evidence::Quote;
+use log::error;
+use parsec_interface::requests::{ResponseStatus, Result};
+use picky_asn1::wrapper::IntegerAsn1;
+use std::convert::TryFrom;
+use tss_esapi::constants::TPM2_ALG_NULL;
+use tss_esapi::tss2_esys::{
+    ESYS_TR, ESYS_TR_NONE, ESYS_TR_PASSWORD, ESYS_TR_RH_ENDORSEMENT, TPMT_SIG_SCHEME,
+};
+use tss_esapi::utils::algorithm_specifiers::HashingAlgorithm;
+use tss_esapi::utils::{
+    self as tss_utils, AsymSchemeUnion, PcrSelectionsBuilder, PcrSlot, Signature, SignatureData,
+};
+use tss_esapi::{Context, Tcti};
+
+// Public exponent of the attestation key.
+const PUBLIC_EXPONENT: [u8; 3] = [0x01, 0x00, 0x01];
+// PCRs quoted, in the SHA-256 bank.
+const PCR_SLOTS: [PcrSlot; 24] = [
+    PcrSlot::Slot0,
+    PcrSlot::Slot1,
+    PcrSlot::Slot2,
+    PcrSlot::Slot3,
+    PcrSlot::Slot4,
+    PcrSlot::Slot5,
+    PcrSlot::Slot6,
+    PcrSlot::Slot7,
+    PcrSlot::Slot8,
+    PcrSlot::Slot9,
+    PcrSlot::Slot10,
+    PcrSlot::Slot11,
+    PcrSlot::Slot12,
+    PcrSlot::Slot13,
+    PcrSlot::Slot14,
+    PcrSlot::Slot15,
+    PcrSlot::Slot16,
+    PcrSlot::Slot17,
+    PcrSlot::Slot18,
+    PcrSlot::Slot19,
+    PcrSlot::Slot20,
+    PcrSlot::Slot21,
+    PcrSlot::Slot22,
+    PcrSlot::Slot23,
+];
+
+/// Context the quotes are produced on, with the attestation key loaded in it
+///
+/// The transient key context of the provider does not expose TPM2_Quote, so a second context is
+/// opened on the same TCTI the first time a quote is asked for.
+pub(super) struct QuoteContext {
+    context: Context,
+    attestation_key: ESYS_TR,
+    // DER-encoded RSAPublicKey of the attestation key.
+    public_key: Vec<u8>,
+}
+
+impl QuoteContext {
+    // Opens the context and creates the attestation key in it. The key is a primary key of the
+    // endorsement hierarchy, whose authorization value is expected to be empty: created from the
+    // same template, it is the same key each time, which verifiers can enroll once.
+    //
+    // Safety: the TCTI must handle multiple contexts, the transient key context of the provider
+    // being opened on it as well.
+    unsafe fn open(tcti: Tcti) -> Result<QuoteContext> {
+        let mut context = Context::new(tcti).or_else(|e| {
+            format_error!(
+                "Error opening the TSS context for the quotes, the TCTI might not handle multiple contexts",
+                e
+            );
+            Err(ResponseStatus::PsaErrorNotSupported)
+        })?;
+        context.set_sessions((ESYS_TR_PASSWORD, ESYS_TR_NONE, ESYS_TR_NONE));
+        let template = tss_utils::create_unrestricted_signing_rsa_public(
+            AsymSchemeUnion::RSASSA(HashingAlgorithm::Sha256),
+            ROOT_KEY_SIZE,
+            0,
+        )
+        .or_else(|e| {
+            format_error!("Error creating the attestation key template", e);
+            Err(utils::to_response_status(e))
+        })?;
+        let attestation_key = utils::retry(|| {
+            context.create_primary_key(ESYS_TR_RH_ENDORSEMENT, &template, &[], &[], &[], &[])
+        })?;
+        let public = utils::retry(|| context.read_public(attestation_key))?;
+        // The attestation key is an RSA key.
+        let modulus = public.publicArea.unique.rsa;
+        let public_key = picky_asn1_der::to_vec(&RsaPublicKey {
+            modulus: IntegerAsn1::from_unsigned_bytes_be(
+                modulus.buffer[..usize::from(modulus.size)].to_vec(),
+            ),
+            public_exponent: IntegerAsn1::from_signed_bytes_be(PUBLIC_EXPONENT.to_vec()),
+        })
+        .or(Err(ResponseStatus::PsaErrorGenericError))?;
+
+        Ok(QuoteContext {
+            context,
+            attestation_key,
+            public_key,
+        })
+    }
+}
+
+impl TpmProvider {
+    pub(super) fn quote_internal(&self, nonce: Vec<u8>) -> Result<Quote> {
+        let mut quote_context = self
+            .quote_context
+            .lock()
+            .expect("Quote context lock poisoned");
+        if quote_context.is_none() {
+            // Safe as per the contract of the builder of the provider.
+            *quote_context = Some(unsafe { QuoteContext::open(self.tcti)? });
+        }
+        let quote_context = quote_context
+            .as_mut()
+            .ok_or(ResponseStatus::PsaErrorGenericError)?;
+
+        let attestation_key = quote_context.attestation_key;
+        let context = &mut quote_context.context;
+        let (attest, signature) = utils::retry(|| {
+            context.quote(
+                attestation_key,
+                &nonce,
+                TPMT_SIG_SCHEME {
+                    scheme: TPM2_ALG_NULL,
+                    details: Default::default(),
+                },
+                PcrSelectionsBuilder::new()
+                    .with_selection(HashingAlgorithm::Sha256, &PCR_SLOTS)
+                    .build(),
+            )
+        })?;
+        let signature = match Signature::try_from(signature) {
+            Ok(Signature {
+                signature: SignatureData::RsaSignature(signature),
+                ..
+            }) => signature,
+            _ => {
+                error!("The TPM returned a quote signature of an unexpected type.");
+                return Err(ResponseStatus::PsaErrorCommunicationFailure);
+            }
+        };
+
+        Ok(Quote {
+            attest: attest.attestationData[..usize::from(attest.size)].to_vec(),
+            signature,
+            public_key: quote_context.public_key.clone(),
+        })
+    }
+}
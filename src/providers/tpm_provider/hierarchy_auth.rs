@@ -0,0 +1,38 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::sessions;
+use super::utils;
+use super::TpmProvider;
+use log::info;
+use parsec_interface::requests::Result;
+use zeroize::Zeroizing;
+
+// The transient key context of the provider does not expose TPM2_HierarchyChangeAuth, so the
+// command is sent on a context opened on the same TCTI for the time of the change. The keys already
+// created are not affected: their root key, created with the previous authentication value when the
+// provider was built, stays loaded in the transient key context.
+impl TpmProvider {
+    pub(super) fn change_backend_auth_internal(&self, auth: Zeroizing<Vec<u8>>) -> Result<()> {
+        info!("TPM Provider - Change Hierarchy Auth");
+
+        let mut hierarchy_auth = self
+            .hierarchy_auth
+            .lock()
+            .expect("Hierarchy auth lock poisoned");
+        // The new authentication value is sent encrypted, through a session salted as configured.
+        // Safe as per the contract of the builder of the provider.
+        let mut context = unsafe {
+            sessions::open_context(
+                self.tcti,
+                &self.salting,
+                self.hierarchy,
+                &hierarchy_auth,
+                self.context_cipher,
+            )
+        }?;
+        utils::retry(|| context.hierarchy_change_auth(self.hierarchy.esys_rh(), &auth))?;
+        *hierarchy_auth = auth;
+
+        Ok(())
+    }
+}
@@ -6,13 +6,15 @@ use super::TpmProvider;
 use crate::authenticators::ApplicationName;
 use crate::key_info_managers;
 use crate::key_info_managers::KeyTriple;
-use crate::key_info_managers::{KeyInfo, ManageKeyInfo};
+use crate::key_info_managers::{KeyInfo, KeyState, ManageKeyInfo};
 use log::error;
 use parsec_interface::operations::psa_key_attributes::*;
 use parsec_interface::operations::{
     psa_destroy_key, psa_export_public_key, psa_generate_key, psa_import_key,
 };
 use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use std::collections::BTreeMap;
+use tss_esapi::TransientKeyContext;
 
 // Public exponent value for all RSA keys.
 const PUBLIC_EXPONENT: [u8; 3] = [0x01, 0x00, 0x01];
@@ -30,6 +32,11 @@ fn insert_password_context(
     let key_info = KeyInfo {
         id: bincode::serialize(&password_context)?,
         attributes: key_attributes,
+        state: KeyState::Active,
+        created_at: None,
+        counter: None,
+        certificate: None,
+        labels: BTreeMap::new(),
     };
 
     if store_handle
@@ -66,43 +73,67 @@ pub fn get_password_context(
     Ok((bincode::deserialize(&key_info.id)?, key_info.attributes))
 }
 
+// Creates a key in the TPM and inserts its mapping.
+fn generate_key(
+    store_handle: &mut dyn ManageKeyInfo,
+    esapi_context: &mut TransientKeyContext,
+    app_name: ApplicationName,
+    op: psa_generate_key::Operation,
+) -> Result<psa_generate_key::Result> {
+    let key_name = op.key_name;
+    let attributes = op.attributes;
+    let key_triple = KeyTriple::new(app_name, ProviderID::Tpm, key_name);
+
+    let key_params = utils::parsec_to_tpm_params(attributes)?;
+    let (key_context, auth_value) =
+        utils::retry(|| esapi_context.create_signing_key(key_params, AUTH_VAL_LEN))?;
+
+    insert_password_context(
+        store_handle,
+        key_triple,
+        PasswordContext {
+            context: key_context,
+            auth_value,
+        },
+        attributes,
+    )?;
+
+    Ok(psa_generate_key::Result {})
+}
+
 impl TpmProvider {
     pub(super) fn psa_generate_key_internal(
         &self,
         app_name: ApplicationName,
         op: psa_generate_key::Operation,
     ) -> Result<psa_generate_key::Result> {
-        let key_name = op.key_name;
-        let attributes = op.attributes;
-        let key_triple = KeyTriple::new(app_name, ProviderID::Tpm, key_name);
-
-        let mut store_handle = self
-            .key_info_store
-            .write()
-            .expect("Key store lock poisoned");
+        let mut store_handle = self.key_info_store.write();
         let mut esapi_context = self
             .esapi_context
             .lock()
             .expect("ESAPI Context lock poisoned");
 
-        let (key_context, auth_value) = esapi_context
-            .create_signing_key(utils::parsec_to_tpm_params(attributes)?, AUTH_VAL_LEN)
-            .or_else(|e| {
-                format_error!("Error creating a RSA signing key", e);
-                Err(utils::to_response_status(e))
-            })?;
+        generate_key(&mut store_handle, &mut esapi_context, app_name, op)
+    }
 
-        insert_password_context(
-            &mut *store_handle,
-            key_triple,
-            PasswordContext {
-                context: key_context,
-                auth_value,
-            },
-            attributes,
-        )?;
+    /// Generates all the keys holding the Key Info Manager and the ESAPI context once, so that
+    /// the keys of other requests are not created in between. Returns the result of the
+    /// generation of each key.
+    pub(super) fn psa_generate_keys_internal(
+        &self,
+        app_name: ApplicationName,
+        ops: Vec<psa_generate_key::Operation>,
+    ) -> Result<Vec<Result<psa_generate_key::Result>>> {
+        let mut store_handle = self.key_info_store.write();
+        let mut esapi_context = self
+            .esapi_context
+            .lock()
+            .expect("ESAPI Context lock poisoned");
 
-        Ok(psa_generate_key::Result {})
+        Ok(ops
+            .into_iter()
+            .map(|op| generate_key(&mut store_handle, &mut esapi_context, app_name.clone(), op))
+            .collect())
     }
 
     pub(super) fn psa_import_key_internal(
@@ -120,10 +151,7 @@ impl TpmProvider {
         let key_triple = KeyTriple::new(app_name, ProviderID::Tpm, key_name);
         let key_data = op.data;
 
-        let mut store_handle = self
-            .key_info_store
-            .write()
-            .expect("Key store lock poisoned");
+        let mut store_handle = self.key_info_store.write();
         let mut esapi_context = self
             .esapi_context
             .lock()
@@ -186,7 +214,7 @@ impl TpmProvider {
             })?;
 
         insert_password_context(
-            &mut *store_handle,
+            &mut store_handle,
             key_triple,
             PasswordContext {
                 context: pub_key_context,
@@ -206,13 +234,13 @@ impl TpmProvider {
         let key_name = op.key_name;
         let key_triple = KeyTriple::new(app_name, ProviderID::Tpm, key_name);
 
-        let store_handle = self.key_info_store.read().expect("Key store lock poisoned");
+        let store_handle = self.key_info_store.read();
         let mut esapi_context = self
             .esapi_context
             .lock()
             .expect("ESAPI Context lock poisoned");
 
-        let (password_context, key_attributes) = get_password_context(&*store_handle, key_triple)?;
+        let (password_context, key_attributes) = get_password_context(&store_handle, key_triple)?;
 
         let pub_key_data = esapi_context
             .read_public_key(password_context.context)
@@ -233,10 +261,7 @@ impl TpmProvider {
     ) -> Result<psa_destroy_key::Result> {
         let key_name = op.key_name;
         let key_triple = KeyTriple::new(app_name, ProviderID::Tpm, key_name);
-        let mut store_handle = self
-            .key_info_store
-            .write()
-            .expect("Key store lock poisoned");
+        let mut store_handle = self.key_info_store.write();
 
         if store_handle
             .remove(&key_triple)
@@ -4,9 +4,35 @@
 //!
 //! Provider allowing clients to use hardware or software TPM 2.0 implementations
 //! for their Parsec operations.
+//!
+//! All the commands sent to the TPM go through an HMAC session with parameter encryption, using
+//! the best symmetric cipher found to be supported by the TPM (see
+//! `find_default_context_cipher`). Key material and authentication values are hence never
+//! exposed in clear on the TPM command bus. If none of the ciphers sought is supported, the
+//! provider refuses to start instead of falling back to unencrypted sessions.
+//! The sessions are salted with the root key of the hierarchy, the primary key created from the
+//! template of the transient key context root key. The provider creates it when starting and checks
+//! that its public key has the digest configured, enrolled once, refusing to start otherwise: the
+//! salt, and the keys of the sessions derived from it, can then only be known to the TPM which
+//! holds the seed of the hierarchy, even if the authentication value of the hierarchy is weak or
+//! known. Without a digest configured, the one of the root key is logged for it to be enrolled.
+//! The check can be left out on platforms where the path to the TPM is trusted, where the
+//! provider's own sessions are not salted either.
+//! The authentication value of the hierarchy can be changed by the administrators while the service
+//! runs, the new value being sent to the TPM through such a session as well.
+//!
+//! Keys are created under the configured hierarchy and are not made persistent in the TPM: the
+//! Key Info Manager stores, in place of a key ID, the key context wrapped by the TPM along with the
+//! authentication value of the key, from which the key is loaded again for each operation.
+//!
+//! HMAC keys are not supported: the interface does not define the MAC operations yet, and the
+//! transient key context the provider drives the TPM with only creates signing keys, without
+//! exposing the keyed-hash objects and the TPM2_HMAC command. Both are needed first.
 use super::Provide;
 use crate::authenticators::ApplicationName;
-use crate::key_info_managers::ManageKeyInfo;
+use crate::back::platform_evidence::Quote;
+use crate::key_info_managers::key_info_store::KeyInfoStore;
+use crate::utils::secrets;
 use derivative::Derivative;
 use log::{error, info, trace};
 use parsec_interface::operations::list_providers::ProviderInfo;
@@ -17,13 +43,18 @@ use parsec_interface::operations::{
 use parsec_interface::requests::{Opcode, ProviderID, ResponseStatus, Result};
 use std::collections::HashSet;
 use std::io::ErrorKind;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, Mutex};
 use tss_esapi::utils::algorithm_specifiers::Cipher;
+use tss_esapi::utils::Hierarchy;
 use tss_esapi::Tcti;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 mod asym_sign;
+mod attestation;
+mod hierarchy_auth;
 mod key_management;
+mod sessions;
 mod utils;
 
 const SUPPORTED_OPCODES: [Opcode; 6] = [
@@ -39,6 +70,7 @@ const ROOT_KEY_SIZE: u16 = 2048;
 const ROOT_KEY_AUTH_SIZE: usize = 32;
 const AUTH_STRING_PREFIX: &str = "str:";
 const AUTH_HEX_PREFIX: &str = "hex:";
+const ROOT_KEY_DIGEST_SIZE: usize = 32;
 
 /// Provider for Trusted Platform Modules
 ///
@@ -53,20 +85,41 @@ pub struct TpmProvider {
     // structure that is shared between threads and because two threads are not allowed the same
     // ESAPI context simultaneously.
     esapi_context: Mutex<tss_esapi::TransientKeyContext>,
+    // Context the quotes are produced on, opened the first time one is asked for.
+    #[derivative(Debug = "ignore")]
+    quote_context: Mutex<Option<attestation::QuoteContext>>,
+    tcti: Tcti,
+    hierarchy: Hierarchy,
+    // Current authentication value of the hierarchy, kept to change it.
+    #[derivative(Debug = "ignore")]
+    hierarchy_auth: Mutex<Zeroizing<Vec<u8>>>,
+    context_cipher: Cipher,
+    salting: sessions::Salting,
     // The Key Info Manager stores the key context and its associated authValue (a PasswordContext
     // structure).
     #[derivative(Debug = "ignore")]
-    key_info_store: Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>,
+    key_info_store: Arc<KeyInfoStore>,
 }
 
 impl TpmProvider {
     // Creates and initialise a new instance of TpmProvider.
     fn new(
-        key_info_store: Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>,
+        key_info_store: Arc<KeyInfoStore>,
         esapi_context: tss_esapi::TransientKeyContext,
+        tcti: Tcti,
+        hierarchy: Hierarchy,
+        hierarchy_auth: Zeroizing<Vec<u8>>,
+        context_cipher: Cipher,
+        salting: sessions::Salting,
     ) -> Option<TpmProvider> {
         Some(TpmProvider {
             esapi_context: Mutex::new(esapi_context),
+            quote_context: Mutex::new(None),
+            tcti,
+            hierarchy,
+            hierarchy_auth: Mutex::new(hierarchy_auth),
+            context_cipher,
+            salting,
             key_info_store,
         })
     }
@@ -96,6 +149,15 @@ impl Provide for TpmProvider {
         self.psa_generate_key_internal(app_name, op)
     }
 
+    fn psa_generate_keys(
+        &self,
+        app_name: ApplicationName,
+        ops: Vec<psa_generate_key::Operation>,
+    ) -> Result<Vec<Result<psa_generate_key::Result>>> {
+        trace!("psa_generate_keys ingress");
+        self.psa_generate_keys_internal(app_name, ops)
+    }
+
     fn psa_import_key(
         &self,
         app_name: ApplicationName,
@@ -140,6 +202,16 @@ impl Provide for TpmProvider {
         trace!("psa_verify_hash ingress");
         self.psa_verify_hash_internal(app_name, op)
     }
+
+    fn quote(&self, nonce: Vec<u8>) -> Result<Quote> {
+        trace!("quote ingress");
+        self.quote_internal(nonce)
+    }
+
+    fn change_backend_auth(&self, auth: Zeroizing<Vec<u8>>) -> Result<()> {
+        trace!("change_backend_auth ingress");
+        self.change_backend_auth_internal(auth)
+    }
 }
 
 impl Drop for TpmProvider {
@@ -153,9 +225,12 @@ impl Drop for TpmProvider {
 #[derivative(Debug)]
 pub struct TpmProviderBuilder {
     #[derivative(Debug = "ignore")]
-    key_info_store: Option<Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>>,
+    key_info_store: Option<Arc<KeyInfoStore>>,
     tcti: Option<Tcti>,
     owner_hierarchy_auth: Option<String>,
+    hierarchy: Option<String>,
+    trusted_tpm_path: Option<bool>,
+    root_key_digest: Option<String>,
 }
 
 impl TpmProviderBuilder {
@@ -164,13 +239,13 @@ impl TpmProviderBuilder {
             key_info_store: None,
             tcti: None,
             owner_hierarchy_auth: None,
+            hierarchy: None,
+            trusted_tpm_path: None,
+            root_key_digest: None,
         }
     }
 
-    pub fn with_key_info_store(
-        mut self,
-        key_info_store: Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>,
-    ) -> TpmProviderBuilder {
+    pub fn with_key_info_store(mut self, key_info_store: Arc<KeyInfoStore>) -> TpmProviderBuilder {
         self.key_info_store = Some(key_info_store);
 
         self
@@ -198,25 +273,96 @@ impl TpmProviderBuilder {
         self
     }
 
-    fn get_hierarchy_auth(&mut self) -> std::io::Result<Vec<u8>> {
-        match self.owner_hierarchy_auth.take() {
-            None => Err(std::io::Error::new(
+    /// Set the hierarchy under which the keys will be created: "owner" (the default) or
+    /// "endorsement".
+    pub fn with_hierarchy(mut self, hierarchy: Option<String>) -> TpmProviderBuilder {
+        self.hierarchy = hierarchy;
+
+        self
+    }
+
+    /// Set if the path to the TPM is trusted, in which case the root key of the hierarchy is not
+    /// checked and the sessions opened by the provider are not salted with it. Defaults to false.
+    pub fn with_trusted_tpm_path(mut self, trusted_tpm_path: Option<bool>) -> TpmProviderBuilder {
+        self.trusted_tpm_path = trusted_tpm_path;
+
+        self
+    }
+
+    /// Set the SHA-256 digest, hex-encoded, that the DER-encoded RSAPublicKey of the root key of
+    /// the hierarchy must have.
+    pub fn with_root_key_digest(mut self, root_key_digest: Option<String>) -> TpmProviderBuilder {
+        self.root_key_digest = root_key_digest;
+
+        self
+    }
+
+    fn get_salting(&self) -> std::io::Result<sessions::Salting> {
+        match (self.trusted_tpm_path, self.root_key_digest.as_deref()) {
+            (Some(true), Some(_)) => Err(std::io::Error::new(
                 ErrorKind::InvalidData,
-                "missing owner hierarchy auth",
+                "root key digest given for a trusted TPM path",
             )),
-            Some(mut auth) if auth.starts_with(AUTH_STRING_PREFIX) => {
-                Ok(auth.split_off(AUTH_STRING_PREFIX.len()).into())
-            }
-            Some(mut auth) if auth.starts_with(AUTH_HEX_PREFIX) => Ok(hex::decode(
-                auth.split_off(AUTH_STRING_PREFIX.len()),
-            )
-            .or_else(|_| {
+            (Some(true), None) => Ok(sessions::Salting::Unsalted),
+            (_, None) => Ok(sessions::Salting::RootKey(None)),
+            (_, Some(root_key_digest)) => match hex::decode(root_key_digest) {
+                Ok(root_key_digest) if root_key_digest.len() == ROOT_KEY_DIGEST_SIZE => {
+                    Ok(sessions::Salting::RootKey(Some(root_key_digest)))
+                }
+                _ => Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid hex SHA-256 root key digest",
+                )),
+            },
+        }
+    }
+
+    fn get_hierarchy(&self) -> std::io::Result<Hierarchy> {
+        match self.hierarchy.as_deref() {
+            None | Some("owner") => Ok(Hierarchy::Owner),
+            Some("endorsement") => Ok(Hierarchy::Endorsement),
+            Some(hierarchy) => {
+                if crate::utils::GlobalConfig::log_error_details() {
+                    error!("The string {} does not match a TPM hierarchy.", hierarchy);
+                }
                 Err(std::io::Error::new(
                     ErrorKind::InvalidData,
-                    "invalid hex owner hierarchy auth",
+                    "invalid hierarchy",
                 ))
-            })?),
-            Some(auth) => Ok(auth.into()),
+            }
+        }
+    }
+
+    // The prefixes are checked in this order: what follows "str:" is used as is, even if it starts
+    // with one of the other prefixes.
+    fn get_hierarchy_auth(&mut self) -> std::io::Result<Zeroizing<Vec<u8>>> {
+        let auth = match self.owner_hierarchy_auth.take().map(Zeroizing::new) {
+            None => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "missing owner hierarchy auth",
+                ))
+            }
+            Some(auth) => auth,
+        };
+        if auth.starts_with(AUTH_STRING_PREFIX) {
+            Ok(Zeroizing::new(
+                auth[AUTH_STRING_PREFIX.len()..].as_bytes().to_vec(),
+            ))
+        } else if auth.starts_with(AUTH_HEX_PREFIX) {
+            Ok(Zeroizing::new(
+                hex::decode(&auth[AUTH_HEX_PREFIX.len()..]).or_else(|_| {
+                    Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        "invalid hex owner hierarchy auth",
+                    ))
+                })?,
+            ))
+        } else {
+            match secrets::read_external_secret(&auth) {
+                Some(secret) => secret.map(Zeroizing::new),
+                None => Ok(Zeroizing::new(auth.as_bytes().to_vec())),
+            }
         }
     }
 
@@ -260,13 +406,28 @@ impl TpmProviderBuilder {
     /// # Safety
     ///
     /// Undefined behaviour might appear if two instances of TransientObjectContext are created
-    /// using a same TCTI that does not handle multiple applications concurrently.
+    /// using a same TCTI that does not handle multiple applications concurrently. The provider
+    /// opens a second context on its TCTI to check the root key of the hierarchy, the first time it
+    /// is asked for a quote and to change the authentication value of the hierarchy.
     pub unsafe fn build(mut self) -> std::io::Result<TpmProvider> {
+        let hierarchy = self.get_hierarchy()?;
+        let salting = self.get_salting()?;
         let hierarchy_auth = self.get_hierarchy_auth()?;
         let default_cipher = self.find_default_context_cipher()?;
         let tcti = self
             .tcti
             .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "missing TCTI"))?;
+        // The root key is checked before the transient key context creates it in turn.
+        if salting != sessions::Salting::Unsalted {
+            let _ =
+                sessions::open_context(tcti, &salting, hierarchy, &hierarchy_auth, default_cipher)
+                    .or_else(|_| {
+                        Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            "failed verifying the root key of the hierarchy",
+                        ))
+                    })?;
+        }
         TpmProvider::new(
             self.key_info_store.ok_or_else(|| {
                 std::io::Error::new(ErrorKind::InvalidData, "missing key info store")
@@ -275,8 +436,8 @@ impl TpmProviderBuilder {
                 .with_tcti(tcti)
                 .with_root_key_size(ROOT_KEY_SIZE)
                 .with_root_key_auth_size(ROOT_KEY_AUTH_SIZE)
-                .with_hierarchy_auth(hierarchy_auth)
-                .with_hierarchy(tss_esapi::utils::Hierarchy::Owner)
+                .with_hierarchy_auth(hierarchy_auth.to_vec())
+                .with_hierarchy(hierarchy)
                 .with_session_hash_alg(
                     tss_esapi::utils::algorithm_specifiers::HashingAlgorithm::Sha256.into(),
                 )
@@ -289,9 +450,58 @@ impl TpmProviderBuilder {
                         "failed initializing TSS context",
                     ))
                 })?,
+            tcti,
+            hierarchy,
+            hierarchy_auth,
+            default_cipher,
+            salting,
         )
         .ok_or_else(|| {
             std::io::Error::new(ErrorKind::InvalidData, "failed initializing TPM provider")
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::sessions::Salting;
+    use super::TpmProviderBuilder;
+
+    #[test]
+    fn salting_configured() {
+        let digest = "ab".repeat(32);
+
+        assert_eq!(
+            TpmProviderBuilder::new().get_salting().unwrap(),
+            Salting::RootKey(None)
+        );
+        assert_eq!(
+            TpmProviderBuilder::new()
+                .with_trusted_tpm_path(Some(false))
+                .with_root_key_digest(Some(digest.clone()))
+                .get_salting()
+                .unwrap(),
+            Salting::RootKey(Some(vec![0xab; 32]))
+        );
+        assert_eq!(
+            TpmProviderBuilder::new()
+                .with_trusted_tpm_path(Some(true))
+                .get_salting()
+                .unwrap(),
+            Salting::Unsalted
+        );
+        assert!(TpmProviderBuilder::new()
+            .with_trusted_tpm_path(Some(true))
+            .with_root_key_digest(Some(digest))
+            .get_salting()
+            .is_err());
+        assert!(TpmProviderBuilder::new()
+            .with_root_key_digest(Some("ab".repeat(31)))
+            .get_salting()
+            .is_err());
+        assert!(TpmProviderBuilder::new()
+            .with_root_key_digest(Some("zz".repeat(32)))
+            .get_salting()
+            .is_err());
+    }
+}
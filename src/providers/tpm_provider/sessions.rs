@@ -0,0 +1,132 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+use super::utils::{self, RsaPublicKey};
+use super::ROOT_KEY_SIZE;
+use log::{error, warn};
+use parsec_interface::requests::{ResponseStatus, Result};
+use picky_asn1::wrapper::IntegerAsn1;
+use ring::digest::{digest, SHA256};
+use tss_esapi::constants::{TPM2_SE_HMAC, TPMA_SESSION_DECRYPT, TPMA_SESSION_ENCRYPT};
+use tss_esapi::tss2_esys::{ESYS_TR, ESYS_TR_NONE};
+use tss_esapi::utils::algorithm_specifiers::{Cipher, HashingAlgorithm};
+use tss_esapi::utils::{self as tss_utils, Hierarchy, TpmaSessionBuilder};
+use tss_esapi::{Context, Tcti};
+
+// Public exponent of the root key, the default one of its template.
+const PUBLIC_EXPONENT: [u8; 3] = [0x01, 0x00, 0x01];
+
+/// Salt of the sessions the provider opens on its own contexts
+///
+/// The salt is encrypted with the root key of the hierarchy: the primary key created from the
+/// template the transient key context of the provider creates its own root key from, and hence the
+/// same key, which the sessions of the transient key context are salted with. A primary key is
+/// derived from the seed of its hierarchy, which never leaves the TPM, so the digest of its public
+/// key, enrolled once, identifies the TPM.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Salting {
+    /// The TPM path is trusted: the sessions are only encrypted, with keys derived from the
+    /// authentication value of the hierarchy.
+    Unsalted,
+    /// The sessions are salted with the root key, which must have the SHA-256 digest given, if any,
+    /// of its DER-encoded RSAPublicKey.
+    RootKey(Option<Vec<u8>>),
+}
+
+/// Opens a context on the TCTI, with the authentication value of the hierarchy set and its
+/// commands going through an HMAC session with parameter encryption, salted as configured.
+///
+/// # Errors
+///
+/// Returns `PsaErrorCommunicationFailure` if the root key does not have the configured digest.
+///
+/// # Safety
+///
+/// The TCTI must handle multiple contexts if others are opened on it.
+pub(super) unsafe fn open_context(
+    tcti: Tcti,
+    salting: &Salting,
+    hierarchy: Hierarchy,
+    hierarchy_auth: &[u8],
+    cipher: Cipher,
+) -> Result<Context> {
+    let mut context = Context::new(tcti).or_else(|e| {
+        format_error!(
+            "Error opening a TSS context, the TCTI might not handle multiple contexts",
+            e
+        );
+        Err(ResponseStatus::PsaErrorNotSupported)
+    })?;
+    utils::retry(|| context.tr_set_auth(hierarchy.esys_rh(), hierarchy_auth))?;
+    // The root key is created through an unsalted session first, which the authentication value
+    // of the hierarchy is not sent in clear through.
+    let unsalted_session = start_session(&mut context, ESYS_TR_NONE, cipher)?;
+    let expected_digest = match salting {
+        Salting::Unsalted => return Ok(context),
+        Salting::RootKey(expected_digest) => expected_digest,
+    };
+
+    let template = tss_utils::create_restricted_decryption_rsa_public(cipher, ROOT_KEY_SIZE, 0)
+        .or_else(|e| {
+            format_error!("Error creating the root key template", e);
+            Err(utils::to_response_status(e))
+        })?;
+    let root_key = utils::retry(|| {
+        context.create_primary_key(hierarchy.esys_rh(), &template, &[], &[], &[], &[])
+    })?;
+    let root_key_digest = root_key_digest(&mut context, root_key)?;
+    match expected_digest {
+        Some(expected_digest) if *expected_digest != root_key_digest => {
+            error!("The root key of the hierarchy does not have the configured digest, the TPM the provider talks to might not be the one enrolled.");
+            return Err(ResponseStatus::PsaErrorCommunicationFailure);
+        }
+        Some(_) => (),
+        None => warn!(
+            "The root key of the hierarchy is not verified: the root_key_digest of the TPM provider can be set to {} to verify it.",
+            hex::encode(&root_key_digest)
+        ),
+    }
+    let _ = start_session(&mut context, root_key, cipher)?;
+    // The salt is only used to start the session, neither key nor session is needed anymore.
+    utils::retry(|| context.flush_context(unsalted_session))?;
+    utils::retry(|| context.flush_context(root_key))?;
+
+    Ok(context)
+}
+
+// Starts an HMAC session with parameter encryption, salted with the key given if any, and sends
+// the commands of the context through it.
+fn start_session(context: &mut Context, tpm_key: ESYS_TR, cipher: Cipher) -> Result<ESYS_TR> {
+    let session = utils::retry(|| {
+        context.start_auth_session(
+            tpm_key,
+            ESYS_TR_NONE,
+            &[],
+            TPM2_SE_HMAC,
+            cipher.into(),
+            HashingAlgorithm::Sha256.into(),
+        )
+    })?;
+    let session_attr = TpmaSessionBuilder::new()
+        .with_flag(TPMA_SESSION_DECRYPT)
+        .with_flag(TPMA_SESSION_ENCRYPT)
+        .build();
+    utils::retry(|| context.set_session_attr(session, session_attr))?;
+    context.set_sessions((session, ESYS_TR_NONE, ESYS_TR_NONE));
+
+    Ok(session)
+}
+
+// SHA-256 digest of the DER-encoded RSAPublicKey of the root key.
+fn root_key_digest(context: &mut Context, root_key: ESYS_TR) -> Result<Vec<u8>> {
+    let public = utils::retry(|| context.read_public(root_key))?;
+    let modulus = public.publicArea.unique.rsa;
+    let public_key = picky_asn1_der::to_vec(&RsaPublicKey {
+        modulus: IntegerAsn1::from_unsigned_bytes_be(
+            modulus.buffer[..usize::from(modulus.size)].to_vec(),
+        ),
+        public_exponent: IntegerAsn1::from_signed_bytes_be(PUBLIC_EXPONENT.to_vec()),
+    })
+    .or(Err(ResponseStatus::PsaErrorGenericError))?;
+
+    Ok(digest(&SHA256, &public_key).as_ref().to_vec())
+}
@@ -1,7 +1,8 @@
 // Copyright 2020 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
 
-use log::error;
+use crate::providers::deadline;
+use log::{error, warn};
 use parsec_interface::operations::psa_algorithm::*;
 use parsec_interface::operations::psa_key_attributes::*;
 use parsec_interface::requests::{ResponseStatus, Result};
@@ -9,11 +10,15 @@ use picky_asn1::wrapper::IntegerAsn1;
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 use std::convert::TryInto;
+use std::thread;
+use std::time::Duration;
 use tss_esapi::abstraction::transient::KeyParams;
 use tss_esapi::response_code::{Error, Tss2ResponseCodeKind};
 use tss_esapi::utils::algorithm_specifiers::{EllipticCurve, HashingAlgorithm};
 use tss_esapi::utils::{AsymSchemeUnion, PublicKey, Signature, SignatureData, TpmsContext};
 const PUBLIC_EXPONENT: [u8; 3] = [0x01, 0x00, 0x01];
+// Time to wait before sending again a command the TPM asked to retry.
+const RETRY_SLEEP: Duration = Duration::from_millis(50);
 
 /// Convert the TSS library specific error values to ResponseStatus values that are returned on
 /// the wire protocol
@@ -84,6 +89,34 @@ pub fn to_response_status(error: Error) -> ResponseStatus {
     }
 }
 
+/// Sends a command to the TPM again while it answers that it should be retried, for example when
+/// it is busy with another command, until the deadline of the operation expires.
+///
+/// The command is not retried for the operations without a deadline: it fails as it would have
+/// with `PsaErrorHardwareFailure`.
+pub fn retry<T>(mut command: impl FnMut() -> std::result::Result<T, Error>) -> Result<T> {
+    let deadline = deadline::current();
+    loop {
+        match command() {
+            Err(Error::Tss2Error(e))
+                if deadline.expires() && e.kind() == Some(Tss2ResponseCodeKind::Retry) =>
+            {
+                if deadline.is_exceeded() {
+                    warn!("The TPM asked to retry the command until the deadline expired.");
+                    return Err(ResponseStatus::PsaErrorCommunicationFailure);
+                }
+                thread::sleep(RETRY_SLEEP);
+            }
+            result => {
+                return result.or_else(|e| {
+                    format_error!("TPM command failed", e);
+                    Err(to_response_status(e))
+                })
+            }
+        }
+    }
+}
+
 // The RSA Public Key data are DER encoded with the following representation:
 // RSAPublicKey ::= SEQUENCE {
 //     modulus            INTEGER,  -- n
@@ -0,0 +1,362 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! ACME client issuing certificates for keys held in a provider
+//!
+//! Edge devices serving TLS need certificates renewed before they expire, for keys which should
+//! never leave their hardware. For each configured certificate, the service runs the ACME (RFC
+//! 8555) flows itself, on behalf of the configured application:
+//! * the account key and the certificate key are handled as described in the `enrollment` module,
+//!   the requests to the CA being signed with the account key as ES256 JWS
+//! * the domains are validated with the `http-01` challenge: the key authorizations are written in
+//!   a directory which a web server must serve as `http://<domain>/.well-known/acme-challenge/`
+//!
+//! The certificates are checked when the service starts and then periodically, and renewed when
+//! they expire within the configured number of days, the same certificate key being kept.
+//! Failures are logged and retried at the next check. This module is only compiled with the
+//! `acme-client` feature.
+use super::enrollment::{self, ProviderKey, DEFAULT_RENEW_BEFORE_DAYS};
+use crate::authenticators::ApplicationName;
+use crate::back::dispatcher::Dispatcher;
+use log::info;
+use picky::pem::Pem;
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+// Number of times the status of an authorization or an order is polled before giving up.
+const POLL_ATTEMPTS: usize = 30;
+const POLL_PERIOD: Duration = Duration::from_secs(2);
+
+/// Configuration of a certificate issued through ACME
+#[derive(Clone, Deserialize, Debug)]
+pub struct AcmeConfig {
+    /// URL of the directory of the CA
+    pub directory_url: String,
+    /// Contact URLs of the account, such as `mailto:` ones
+    pub contact: Option<Vec<String>>,
+    /// Type of the provider holding the keys
+    pub provider_type: String,
+    /// Application owning the keys
+    pub app_name: String,
+    /// Name of the key of the ACME account
+    pub account_key_name: String,
+    /// Name of the key certified
+    pub key_name: String,
+    /// Domains certified, the first one being the common name of the certificate
+    pub domains: Vec<String>,
+    /// Directory served by a web server as the `.well-known/acme-challenge/` path of the domains
+    pub challenge_directory: String,
+    /// Path of the file the certificate chain is written in, as PEM
+    pub certificate_path: Option<String>,
+    /// Number of days before its expiry when the certificate is renewed, defaults to 30
+    pub renew_before_days: Option<u64>,
+}
+
+fn acme_error(message: &str) -> Error {
+    Error::new(ErrorKind::Other, message.to_string())
+}
+
+fn base64_url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// Gets the JSON Web Key of an uncompressed P-256 point, with its members in the lexicographic
+/// order of the thumbprint computation (RFC 7638).
+fn jwk(public_key: &[u8]) -> Result<String> {
+    if public_key.len() != 65 || public_key[0] != 0x04 {
+        return Err(acme_error(
+            "the ACME keys must be uncompressed P-256 points",
+        ));
+    }
+    Ok(format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+        base64_url(&public_key[1..33]),
+        base64_url(&public_key[33..])
+    ))
+}
+
+/// Client of an ACME server, signing its requests with the account key
+struct AcmeClient<'a> {
+    account_key: ProviderKey<'a>,
+    jwk: String,
+    directory: Value,
+    nonce: Option<String>,
+    account_url: Option<String>,
+}
+
+impl<'a> AcmeClient<'a> {
+    fn new(directory_url: &str, account_key: ProviderKey<'a>) -> Result<AcmeClient<'a>> {
+        let jwk = jwk(&account_key.public_key()?)?;
+        let response = ureq::get(directory_url).call();
+        if response.error() {
+            return Err(acme_error("failed to get the ACME directory"));
+        }
+        let directory = response.into_json()?;
+
+        Ok(AcmeClient {
+            account_key,
+            jwk,
+            directory,
+            nonce: None,
+            account_url: None,
+        })
+    }
+
+    fn directory_url(&self, name: &str) -> Result<String> {
+        self.directory[name]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| acme_error("incomplete ACME directory"))
+    }
+
+    fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = ureq::head(&self.directory_url("newNonce")?).call();
+        response
+            .header("Replay-Nonce")
+            .map(String::from)
+            .ok_or_else(|| acme_error("failed to get an ACME nonce"))
+    }
+
+    // Posts the payload signed as a JWS, or an empty one for a POST-as-GET request, returning
+    // the location header, if any, and the body of the response.
+    fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<(Option<String>, String)> {
+        let key = match &self.account_url {
+            Some(account_url) => format!(r#""kid":"{}""#, account_url),
+            None => format!(r#""jwk":{}"#, self.jwk),
+        };
+        let protected = format!(
+            r#"{{"alg":"ES256",{},"nonce":"{}","url":"{}"}}"#,
+            key,
+            self.nonce()?,
+            url
+        );
+        let protected = base64_url(protected.as_bytes());
+        let payload = match payload {
+            Some(payload) => base64_url(payload.to_string().as_bytes()),
+            None => String::new(),
+        };
+        let signature = self
+            .account_key
+            .sign(format!("{}.{}", protected, payload).as_bytes())?;
+        let body = json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": base64_url(&signature),
+        });
+
+        let response = ureq::post(url)
+            .set("Content-Type", "application/jose+json")
+            .send_string(&body.to_string());
+        self.nonce = response.header("Replay-Nonce").map(String::from);
+        let location = response.header("Location").map(String::from);
+        let is_error = response.error();
+        let body = response.into_string()?;
+        if is_error {
+            format_error!("ACME request failed", body);
+            return Err(acme_error("ACME request failed"));
+        }
+
+        Ok((location, body))
+    }
+
+    fn post_json(&mut self, url: &str, payload: Option<&Value>) -> Result<(Option<String>, Value)> {
+        let (location, body) = self.post(url, payload)?;
+        let body = serde_json::from_str(&body)?;
+        Ok((location, body))
+    }
+
+    fn register(&mut self, contact: &[String]) -> Result<()> {
+        let url = self.directory_url("newAccount")?;
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": contact });
+        // An account already registered with the key is returned as is.
+        let (location, _) = self.post_json(&url, Some(&payload))?;
+        self.account_url = Some(location.ok_or_else(|| acme_error("ACME account URL missing"))?);
+        Ok(())
+    }
+
+    // Polls the object until its status is the expected one.
+    fn poll(&mut self, url: &str, expected: &str) -> Result<Value> {
+        for _ in 0..POLL_ATTEMPTS {
+            let (_, object) = self.post_json(url, None)?;
+            match object["status"].as_str() {
+                Some(status) if status == expected => return Ok(object),
+                Some("invalid") => {
+                    format_error!("ACME object invalid", object);
+                    return Err(acme_error("ACME object invalid"));
+                }
+                _ => thread::sleep(POLL_PERIOD),
+            }
+        }
+
+        Err(acme_error("timed out waiting for the ACME server"))
+    }
+
+    fn authorize(&mut self, authorization_url: &str, challenge_directory: &Path) -> Result<()> {
+        let (_, authorization) = self.post_json(authorization_url, None)?;
+        if authorization["status"].as_str() == Some("valid") {
+            return Ok(());
+        }
+        let challenge = authorization["challenges"]
+            .as_array()
+            .and_then(|challenges| {
+                challenges
+                    .iter()
+                    .find(|challenge| challenge["type"].as_str() == Some("http-01"))
+            })
+            .ok_or_else(|| acme_error("no http-01 challenge offered"))?;
+        let (challenge_url, token) = match (challenge["url"].as_str(), challenge["token"].as_str())
+        {
+            // The token is used as a file name.
+            (Some(url), Some(token)) if !token.contains('/') && !token.contains('.') => {
+                (url.to_string(), token.to_string())
+            }
+            _ => return Err(acme_error("invalid http-01 challenge")),
+        };
+
+        let thumbprint = base64_url(digest(&SHA256, self.jwk.as_bytes()).as_ref());
+        let token_path = challenge_directory.join(&token);
+        fs::write(&token_path, format!("{}.{}", token, thumbprint))?;
+        let result = self
+            .post_json(&challenge_url, Some(&json!({})))
+            .and_then(|_| self.poll(authorization_url, "valid"));
+        let _ = fs::remove_file(&token_path);
+
+        result.map(|_| ())
+    }
+}
+
+/// Issues the certificate, returning the chain as PEM.
+fn issue(
+    config: &AcmeConfig,
+    account_key: ProviderKey<'_>,
+    certified_key: &ProviderKey<'_>,
+) -> Result<String> {
+    let mut client = AcmeClient::new(&config.directory_url, account_key)?;
+    client.register(
+        config
+            .contact
+            .as_ref()
+            .map_or(&[][..], |contact| &contact[..]),
+    )?;
+
+    let identifiers: Vec<Value> = config
+        .domains
+        .iter()
+        .map(|domain| json!({ "type": "dns", "value": domain }))
+        .collect();
+    let new_order_url = client.directory_url("newOrder")?;
+    let (order_url, order) =
+        client.post_json(&new_order_url, Some(&json!({ "identifiers": identifiers })))?;
+    let order_url = order_url.ok_or_else(|| acme_error("ACME order URL missing"))?;
+    for authorization in order["authorizations"].as_array().unwrap_or(&Vec::new()) {
+        let authorization_url = authorization
+            .as_str()
+            .ok_or_else(|| acme_error("invalid ACME authorization"))?;
+        client.authorize(authorization_url, Path::new(&config.challenge_directory))?;
+    }
+
+    let common_name = config
+        .domains
+        .first()
+        .ok_or_else(|| acme_error("no domain to certify"))?;
+    let csr = certified_key.certificate_request(common_name, &config.domains)?;
+    let finalize_url = order["finalize"]
+        .as_str()
+        .ok_or_else(|| acme_error("ACME finalize URL missing"))?;
+    let _ = client.post_json(finalize_url, Some(&json!({ "csr": base64_url(&csr) })))?;
+    let order = client.poll(&order_url, "valid")?;
+    let certificate_url = order["certificate"]
+        .as_str()
+        .ok_or_else(|| acme_error("ACME certificate URL missing"))?;
+    let (_, chain) = client.post(certificate_url, None)?;
+
+    Ok(chain)
+}
+
+/// Renews the certificate if it is missing or expires soon.
+///
+/// # Errors
+///
+/// Returns an error if the provider is not available or if issuing or storing the certificate
+/// failed.
+pub fn renew_certificate(config: &AcmeConfig, dispatcher: &Dispatcher) -> Result<()> {
+    let backend = enrollment::backend(dispatcher, &config.provider_type)?;
+    let app_name = ApplicationName::new(config.app_name.clone());
+    let certified_key = ProviderKey {
+        backend,
+        app_name: &app_name,
+        key_name: &config.key_name,
+    };
+    let current_certificate = certified_key.current_certificate(config.certificate_path.as_ref());
+    if !enrollment::needs_renewal(
+        current_certificate.as_deref(),
+        config
+            .renew_before_days
+            .unwrap_or(DEFAULT_RENEW_BEFORE_DAYS),
+    ) {
+        return Ok(());
+    }
+
+    info!(
+        "Requesting a certificate for the key \"{}\".",
+        config.key_name
+    );
+    let account_key = ProviderKey {
+        backend,
+        app_name: &app_name,
+        key_name: &config.account_key_name,
+    };
+    let chain = issue(config, account_key, &certified_key)?;
+    let certificate: Pem = chain
+        .parse()
+        .map_err(|_| acme_error("invalid certificate chain"))?;
+    certified_key.store_certificate(
+        certificate.data().to_vec(),
+        &chain,
+        config.certificate_path.as_ref(),
+    )?;
+    info!("Certificate of the key \"{}\" issued.", config.key_name);
+
+    Ok(())
+}
+
+/// Renews the configured certificates which are missing or expire soon, logging the failures.
+pub fn renew_certificates(configs: &[AcmeConfig], dispatcher: &Dispatcher) {
+    for config in configs {
+        if let Err(e) = renew_certificate(config, dispatcher) {
+            format_error!(
+                &format!("Failed to renew the certificate of \"{}\"", config.key_name),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::jwk;
+
+    #[test]
+    fn jwk_encoded() {
+        let mut public_key = vec![0x04];
+        public_key.extend_from_slice(&[0xFF; 32]);
+        public_key.extend_from_slice(&[0x00; 32]);
+        let encoded = jwk(&public_key).unwrap();
+        assert_eq!(
+            encoded,
+            r#"{"crv":"P-256","kty":"EC","x":"__________________________________________8","y":"AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"}"#
+        );
+        let value: serde_json::Value = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(value["kty"], "EC");
+
+        assert!(jwk(&public_key[..33]).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Verification of the signature of the configuration file
+//!
+//! The configuration decides which libraries the providers load and where the keys are stored:
+//! tampering with it, for example to point the PKCS 11 provider at a rogue library, compromises
+//! the keys. With this verification, the configuration file must come with a detached Ed25519
+//! signature, in a file named as the configuration file with a `.sig` extension added, made with
+//! the private key of the policy owner. The service refuses to start or to reload a configuration
+//! file whose signature does not verify, before any key is touched.
+//!
+//! The public key of the policy, encoded in hexadecimal, is either baked in the service at build
+//! time through the `PARSEC_CONFIG_POLICY_KEY` environment variable or given on the command line.
+//! The latter accepts the prefixes of the `secrets` module, so that the key can be a systemd
+//! credential sealed to the TPM (`systemd-creds encrypt --with-key=tpm2`) which can not be
+//! replaced without being detected by systemd.
+use super::secrets;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+/// Public key of the policy baked in at build time, encoded in hexadecimal
+pub const BAKED_IN_POLICY_KEY: Option<&str> = option_env!("PARSEC_CONFIG_POLICY_KEY");
+/// Extension added to the configuration file path to get the path of its signature
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// Gets the public key of the policy, from the command line value if there is one and from the
+/// key baked in otherwise.
+///
+/// The command line value is read as a secret if it has one of the prefixes of the `secrets`
+/// module, and as the path of a file otherwise.
+///
+/// # Errors
+///
+/// Returns an error of kind `NotFound` if no key is available, and of kind `InvalidData` if the
+/// key can not be read or decoded.
+pub fn policy_key(command_line_value: Option<&str>) -> Result<Vec<u8>> {
+    let encoded_key = match command_line_value {
+        Some(value) => secrets::read_external_secret(value)
+            .unwrap_or_else(|| secrets::read_secret_file(value))?,
+        None => BAKED_IN_POLICY_KEY
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    "no policy key to verify the configuration signature with",
+                )
+            })?
+            .as_bytes()
+            .to_vec(),
+    };
+
+    hex::decode(String::from_utf8_lossy(&encoded_key).trim()).or_else(|e| {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Failed to decode the policy key ({})", e),
+        ))
+    })
+}
+
+/// Path of the detached signature of a configuration file.
+pub fn signature_path<P: AsRef<Path>>(config_path: P) -> PathBuf {
+    let mut signature_path = config_path.as_ref().as_os_str().to_owned();
+    signature_path.push(".");
+    signature_path.push(SIGNATURE_EXTENSION);
+
+    PathBuf::from(signature_path)
+}
+
+/// Verifies the detached signature of the contents of a configuration file.
+///
+/// # Errors
+///
+/// Returns an error of kind `PermissionDenied` if the signature is missing or does not verify
+/// with the policy key.
+pub fn verify_config_file<P: AsRef<Path>>(
+    config_path: P,
+    config_contents: &[u8],
+    policy_key: &[u8],
+) -> Result<()> {
+    let signature_path = signature_path(config_path);
+    let signature = fs::read(&signature_path).or_else(|e| {
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Failed to read the configuration signature {} ({})",
+                signature_path.display(),
+                e
+            ),
+        ))
+    })?;
+
+    verify(config_contents, &signature, policy_key)
+}
+
+/// Verifies an Ed25519 signature of the configuration with the policy key.
+///
+/// # Errors
+///
+/// Returns an error of kind `PermissionDenied` if the signature does not verify.
+pub fn verify(config_contents: &[u8], signature: &[u8], policy_key: &[u8]) -> Result<()> {
+    UnparsedPublicKey::new(&ED25519, policy_key)
+        .verify(config_contents, signature)
+        .or_else(|_| {
+            Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "the configuration signature does not verify with the policy key",
+            ))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{signature_path, verify};
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::io::ErrorKind;
+    use std::path::PathBuf;
+
+    #[test]
+    fn signature_checked() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let config = b"[core_settings]\n";
+        let signature = key_pair.sign(config);
+
+        verify(config, signature.as_ref(), key_pair.public_key().as_ref()).unwrap();
+        assert_eq!(
+            verify(
+                b"[core_settings]\nlog_level = \"trace\"\n",
+                signature.as_ref(),
+                key_pair.public_key().as_ref()
+            )
+            .unwrap_err()
+            .kind(),
+            ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn signature_next_to_config() {
+        assert_eq!(
+            signature_path("/etc/parsec/config.toml"),
+            PathBuf::from("/etc/parsec/config.toml.sig")
+        );
+    }
+}
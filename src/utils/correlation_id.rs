@@ -0,0 +1,123 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Correlation IDs of the requests
+//!
+//! Following a request through the log lines of the front end, of the back end and of the
+//! providers, interleaved with the ones of the other requests, needs an ID shared by all of them.
+//! Each request is given a correlation ID when its connection is handled, which stays the one of
+//! the thread executing it until its response is written: the log lines written meanwhile carry
+//! it, and so do the events notified to the hooks and the requests the remote provider forwards.
+//!
+//! The service generates the IDs, which are only written to the logs. The `session` field of the
+//! headers is reserved by the wire protocol for sessions: only if `session_correlation_ids` is set
+//! in the core settings, for deployments whose clients agree on this use, clients can choose the
+//! ID of their request by setting it in the `session` field of the request header, the ID of each
+//! request is returned in the `session` field of its response, and the remote provider forwards it
+//! in the requests it sends. The generated IDs are made of a random prefix, drawn when the service
+//! starts, and a counter, so that the logs of different instances of the service do not mix up
+//! their requests.
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+thread_local! {
+    static CURRENT: Cell<Option<CorrelationId>> = Cell::new(None);
+}
+
+/// Correlation ID of a request, formatted as 16 hexadecimal digits
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CorrelationId(u64);
+
+impl CorrelationId {
+    /// Creates a correlation ID from its value.
+    pub fn new(value: u64) -> CorrelationId {
+        CorrelationId(value)
+    }
+
+    /// Gets the value of the correlation ID, as set in the `session` field of the headers.
+    pub fn value(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Generator of the correlation IDs of the requests without one chosen by their client
+#[derive(Debug)]
+pub struct CorrelationIds {
+    prefix: u32,
+    next: AtomicU32,
+}
+
+impl Default for CorrelationIds {
+    fn default() -> Self {
+        CorrelationIds {
+            prefix: rand::random(),
+            next: AtomicU32::new(1),
+        }
+    }
+}
+
+impl CorrelationIds {
+    /// Generates a new correlation ID.
+    pub fn generate(&self) -> CorrelationId {
+        let counter = self.next.fetch_add(1, Ordering::Relaxed);
+        CorrelationId((u64::from(self.prefix) << 32) | u64::from(counter))
+    }
+}
+
+/// Scope in which the calling thread executes a request, its previous correlation ID being restored
+/// when it is dropped
+#[derive(Debug)]
+pub struct CorrelationScope {
+    previous: Option<CorrelationId>,
+}
+
+impl Drop for CorrelationScope {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+/// Makes the correlation ID the one of the request executed by the calling thread, until the
+/// returned scope is dropped.
+pub fn enter(correlation_id: CorrelationId) -> CorrelationScope {
+    CorrelationScope {
+        previous: CURRENT.with(|current| current.replace(Some(correlation_id))),
+    }
+}
+
+/// Gets the correlation ID of the request executed by the calling thread, if any.
+pub fn current() -> Option<CorrelationId> {
+    CURRENT.with(Cell::get)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{current, enter, CorrelationId, CorrelationIds};
+
+    #[test]
+    fn correlation_id_scoped() {
+        let ids = CorrelationIds::default();
+        let first = ids.generate();
+        let second = ids.generate();
+        assert_ne!(first, second);
+        assert_eq!(first.value() >> 32, second.value() >> 32);
+
+        assert_eq!(current(), None);
+        {
+            let _scope = enter(first);
+            assert_eq!(current(), Some(first));
+            {
+                let _nested = enter(CorrelationId::new(0x2a));
+                assert_eq!(current().unwrap().to_string(), "000000000000002a");
+            }
+            assert_eq!(current(), Some(first));
+        }
+        assert_eq!(current(), None);
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Placement of the worker threads on CPUs
+//!
+//! Worker threads can be pinned to a set of CPUs, either given directly with the Linux cpuset list
+//! format (e.g. "0-3,8") or derived from the affinity of an IRQ, for example the one of the
+//! interface to a hardware security module. This lets high-throughput deployments keep the
+//! request processing close to the hardware they are using.
+//!
+//! On NUMA machines, the threads can be placed on all the CPUs of the memory node of the IRQ
+//! instead of only the CPUs handling it, so that they do not compete with the interrupt handling
+//! while staying local to the memory and the bus of the device.
+//!
+//! The placement of the requests is measured: the number of threads pinned and of pinnings which
+//! failed, and the number of requests which started on a CPU of the set or outside of it, which
+//! happens when the threads could not be pinned, are exposed as metrics by the administration API.
+use log::info;
+use std::cell::Cell;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+thread_local! {
+    static IS_PINNED: Cell<bool> = Cell::new(false);
+}
+
+static PINNED_THREADS: AtomicU64 = AtomicU64::new(0);
+static PINNING_FAILURES: AtomicU64 = AtomicU64::new(0);
+static REQUESTS_IN_CPU_SET: AtomicU64 = AtomicU64::new(0);
+static REQUESTS_OUTSIDE_CPU_SET: AtomicU64 = AtomicU64::new(0);
+
+/// Placement of the worker threads and of the requests they handled
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PlacementStatistics {
+    /// Number of worker threads pinned to their CPU set
+    pub pinned_threads: u64,
+    /// Number of worker threads which could not be pinned
+    pub pinning_failures: u64,
+    /// Number of requests which started on a CPU of the set
+    pub requests_in_cpu_set: u64,
+    /// Number of requests which started on a CPU outside of the set
+    pub requests_outside_cpu_set: u64,
+}
+
+/// Gets the placement of the worker threads and of the requests since the service started, all
+/// zero if the threads are not pinned.
+pub fn placement_statistics() -> PlacementStatistics {
+    PlacementStatistics {
+        pinned_threads: PINNED_THREADS.load(Ordering::Relaxed),
+        pinning_failures: PINNING_FAILURES.load(Ordering::Relaxed),
+        requests_in_cpu_set: REQUESTS_IN_CPU_SET.load(Ordering::Relaxed),
+        requests_outside_cpu_set: REQUESTS_OUTSIDE_CPU_SET.load(Ordering::Relaxed),
+    }
+}
+
+/// Parses a CPU list in the Linux cpuset list format (e.g. "0-3,8,10-11") into the list of CPU
+/// numbers it contains.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if the list is malformed or empty.
+pub fn parse_cpu_list(cpu_list: &str) -> Result<Vec<usize>> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "invalid CPU list");
+    let mut cpus = Vec::new();
+
+    for range in cpu_list.trim().split(',') {
+        let mut bounds = range.trim().splitn(2, '-');
+        let start = bounds
+            .next()
+            .ok_or_else(invalid)?
+            .parse::<usize>()
+            .or_else(|_| Err(invalid()))?;
+        let end = match bounds.next() {
+            Some(end) => end.parse::<usize>().or_else(|_| Err(invalid()))?,
+            None => start,
+        };
+        if end < start {
+            return Err(invalid());
+        }
+        cpus.extend(start..=end);
+    }
+
+    if cpus.is_empty() {
+        return Err(invalid());
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+
+    Ok(cpus)
+}
+
+/// Reads the list of CPUs which are allowed to handle the given IRQ.
+pub fn irq_cpu_list(irq: u32) -> Result<Vec<usize>> {
+    let cpu_list = fs::read_to_string(format!("/proc/irq/{}/smp_affinity_list", irq))?;
+    parse_cpu_list(&cpu_list)
+}
+
+/// Reads the NUMA node of the memory local to the given IRQ, `None` if the machine is not NUMA.
+pub fn irq_numa_node(irq: u32) -> Result<Option<usize>> {
+    parse_numa_node(&fs::read_to_string(format!("/proc/irq/{}/node", irq))?)
+}
+
+/// Reads the list of CPUs of the given NUMA node.
+pub fn numa_node_cpu_list(node: usize) -> Result<Vec<usize>> {
+    let cpu_list = fs::read_to_string(format!("/sys/devices/system/node/node{}/cpulist", node))?;
+    parse_cpu_list(&cpu_list)
+}
+
+// Parses a NUMA node number, -1 meaning that there is no NUMA node.
+fn parse_numa_node(node: &str) -> Result<Option<usize>> {
+    match node.trim().parse::<i64>() {
+        Ok(-1) => Ok(None),
+        Ok(node) if node >= 0 => Ok(Some(node as usize)),
+        _ => Err(Error::new(ErrorKind::InvalidData, "invalid NUMA node")),
+    }
+}
+
+/// Pins the current thread to the given CPUs, then records whether the request it is about to
+/// handle starts on one of them. The pinning is only done once per thread, for the first request.
+pub fn pin_current_thread(cpus: &[usize]) {
+    // Do not retry failed pinning for each request.
+    if !IS_PINNED.with(|is_pinned| is_pinned.replace(true)) {
+        match set_current_thread_affinity(cpus) {
+            Ok(()) => {
+                info!("Worker thread pinned to CPUs {:?}.", cpus);
+                let _ = PINNED_THREADS.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                format_error!("Failed to pin worker thread", e);
+                let _ = PINNING_FAILURES.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Safety: sched_getcpu has no precondition, it returns -1 if the CPU can not be known.
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu >= 0 {
+        let requests = if cpus.contains(&(cpu as usize)) {
+            &REQUESTS_IN_CPU_SET
+        } else {
+            &REQUESTS_OUTSIDE_CPU_SET
+        };
+        let _ = requests.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn set_current_thread_affinity(cpus: &[usize]) -> Result<()> {
+    // Safety: cpu_set_t is a bit mask for which all-zero is a valid (empty) value.
+    let mut cpu_set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for cpu in cpus {
+        if *cpu >= libc::CPU_SETSIZE as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "CPU number too large"));
+        }
+        // Safety: the CPU number was checked to fit in the set.
+        unsafe { libc::CPU_SET(*cpu, &mut cpu_set) };
+    }
+
+    // Safety: the pointer and size given describe a valid cpu_set_t structure. A PID of 0 targets
+    // the calling thread.
+    let ret = unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &cpu_set) };
+    if ret != 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_cpu_list, parse_numa_node, pin_current_thread, placement_statistics};
+    use std::thread;
+
+    #[test]
+    fn parse_single_cpus_and_ranges() {
+        assert_eq!(parse_cpu_list("0").unwrap(), vec![0]);
+        assert_eq!(parse_cpu_list("0-3").unwrap(), vec![0, 1, 2, 3]);
+        assert_eq!(
+            parse_cpu_list("8,0-2, 10-11\n").unwrap(),
+            vec![0, 1, 2, 8, 10, 11]
+        );
+        assert_eq!(parse_cpu_list("1,1-2").unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn parse_invalid_lists() {
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a-b").is_err());
+        assert!(parse_cpu_list("1,,2").is_err());
+    }
+
+    #[test]
+    fn parse_numa_nodes() {
+        assert_eq!(parse_numa_node("1\n").unwrap(), Some(1));
+        assert_eq!(parse_numa_node("-1\n").unwrap(), None);
+        assert!(parse_numa_node("-2").is_err());
+        assert!(parse_numa_node("").is_err());
+    }
+
+    #[test]
+    fn placement_measured() {
+        let before = placement_statistics();
+        // A fresh thread, pinned to CPU 0 which every machine has.
+        thread::spawn(|| {
+            pin_current_thread(&[0]);
+            pin_current_thread(&[0]);
+        })
+        .join()
+        .unwrap();
+        let after = placement_statistics();
+
+        assert_eq!(
+            after.pinned_threads + after.pinning_failures,
+            before.pinned_threads + before.pinning_failures + 1
+        );
+        if after.pinned_threads > before.pinned_threads {
+            // Both requests started on the CPU the thread was pinned to.
+            assert_eq!(after.requests_in_cpu_set, before.requests_in_cpu_set + 2);
+        }
+    }
+}
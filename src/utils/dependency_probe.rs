@@ -0,0 +1,152 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Waiting for the dependencies of a provider
+//!
+//! On embedded systems the service can start before the PKCS 11 token, the TPM device or the
+//! socket of a daemon it uses is available. A provider configured with dependencies waits, when it
+//! is built, for its files and device nodes to exist and for its Unix sockets to accept
+//! connections, and then retries building the provider until it succeeds, both within the same
+//! timeout. A provider still failing after the timeout is not created, as for any provider failing.
+use log::{error, info, warn};
+use serde::Deserialize;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Dependencies of a provider
+#[derive(Clone, Deserialize, Debug, Default)]
+pub struct DependencyProbeConfig {
+    /// Files or device nodes which must exist
+    pub paths: Option<Vec<String>>,
+    /// Unix sockets which must accept connections
+    pub sockets: Option<Vec<String>>,
+    /// Time to wait for the dependencies and to retry building the provider, in seconds,
+    /// defaults to 30
+    pub timeout: Option<u64>,
+}
+
+impl DependencyProbeConfig {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS))
+    }
+
+    /// Lists the dependencies which are not available.
+    fn missing(&self) -> Vec<&str> {
+        let missing_paths = self
+            .paths
+            .iter()
+            .flatten()
+            .filter(|path| !Path::new(path).exists());
+        let missing_sockets = self
+            .sockets
+            .iter()
+            .flatten()
+            .filter(|socket| UnixStream::connect(socket).is_err());
+
+        missing_paths
+            .chain(missing_sockets)
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// Waits for the dependencies to be available.
+///
+/// # Errors
+///
+/// Returns an error of kind `TimedOut` if some dependencies are still missing after the timeout.
+pub fn wait_for(config: &DependencyProbeConfig) -> Result<()> {
+    let deadline = Instant::now() + config.timeout();
+    let mut waiting = false;
+    loop {
+        let missing = config.missing();
+        if missing.is_empty() {
+            if waiting {
+                info!("The dependencies of the provider are available.");
+            }
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            error!(
+                "Dependencies of the provider still missing after the timeout: {}.",
+                missing.join(", ")
+            );
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                "provider dependencies missing",
+            ));
+        }
+        if !waiting {
+            info!(
+                "Waiting for the dependencies of the provider: {}.",
+                missing.join(", ")
+            );
+            waiting = true;
+        }
+        thread::sleep(PROBE_INTERVAL);
+    }
+}
+
+/// Waits for the dependencies and calls the build function until it succeeds, returning its last
+/// error once the timeout has elapsed.
+pub fn build_with_retries<T>(
+    config: &DependencyProbeConfig,
+    mut build: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let deadline = Instant::now() + config.timeout();
+    wait_for(config)?;
+    loop {
+        match build() {
+            Ok(built) => return Ok(built),
+            Err(_) if Instant::now() < deadline => {
+                warn!("Building the provider failed, retrying.");
+                thread::sleep(PROBE_INTERVAL);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{build_with_retries, wait_for, DependencyProbeConfig};
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn dependencies_probed() {
+        let config = DependencyProbeConfig {
+            paths: Some(vec![String::from("./Cargo.toml")]),
+            sockets: None,
+            timeout: Some(0),
+        };
+        wait_for(&config).unwrap();
+
+        let config = DependencyProbeConfig {
+            paths: Some(vec![String::from("./missing_dependency")]),
+            sockets: Some(vec![String::from("./missing_dependency.sock")]),
+            timeout: Some(0),
+        };
+        assert_eq!(config.missing().len(), 2);
+        assert_eq!(wait_for(&config).unwrap_err().kind(), ErrorKind::TimedOut);
+
+        let config = DependencyProbeConfig {
+            timeout: Some(1),
+            ..Default::default()
+        };
+        let mut attempts = 0;
+        let built = build_with_retries(&config, || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(Error::new(ErrorKind::NotFound, "not yet"))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(built.unwrap(), 3);
+    }
+}
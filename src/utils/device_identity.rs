@@ -0,0 +1,311 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Identity of the device, bootstrapped on its first boot
+//!
+//! Each device of a fleet needs a unique identity that the rest of the fleet can authenticate,
+//! without an operator provisioning it by hand. When configured, the service creates a NIST P-256
+//! ECDSA key on its first start, in the most trusted provider available, and either certifies it
+//! itself or writes a certificate signing request for the fleet authority to sign. The key is
+//! owned by the reserved `parsec-device-identity` application, which no client can authenticate
+//! as, and is found again in the Key Info Managers on the following starts.
+//!
+//! The certificate of the device is read from the provider, if it stores certificates, or from the
+//! configured certificate file, where the one issued for the signing request is to be installed. It
+//! can be fetched by any authenticated application, through `Dispatcher::device_certificate`, which
+//! the `DeviceCertificate` operation of the extension API exposes to the clients.
+use super::enrollment::{der, status_error, ProviderKey};
+use crate::authenticators::ApplicationName;
+use crate::back::backend_handler::BackEndHandler;
+use crate::providers::provider_id_from_type;
+use log::{error, info, warn};
+use parsec_interface::operations::{list_keys, NativeOperation, NativeResult};
+use parsec_interface::requests::{ProviderID, ResponseStatus};
+use picky::pem::Pem;
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, SystemTime};
+
+pub use super::name_policy::DEVICE_IDENTITY_APP_NAME;
+/// Name of the device identity key
+pub const DEVICE_IDENTITY_KEY_NAME: &str = "device-identity";
+/// Default validity of the self-signed certificates, in days
+pub const DEFAULT_VALIDITY_DAYS: u64 = 7300;
+// Providers the key is created in, from the most trusted one, if not configured.
+const DEFAULT_PROVIDERS: [ProviderID; 3] =
+    [ProviderID::Tpm, ProviderID::Pkcs11, ProviderID::MbedCrypto];
+
+/// Configuration of the device identity
+#[derive(Clone, Deserialize, Debug)]
+pub struct DeviceIdentityConfig {
+    /// Types of the providers the key can be created in, from the most trusted one, defaults to
+    /// "Tpm", "Pkcs11" and "MbedCrypto"
+    pub provider_types: Option<Vec<String>>,
+    /// Common name of the subject of the certificate, defaults to "parsec-device-" followed by
+    /// the start of the SHA-256 digest of the public key, in hexadecimal
+    pub common_name: Option<String>,
+    /// If the device certifies its key itself instead of requesting a certificate, defaults to
+    /// false
+    pub self_signed: Option<bool>,
+    /// Path of the file the certificate signing request is written in, as PEM
+    pub csr_path: Option<String>,
+    /// Path of the file the certificate is read from or, if self-signed, written in, as PEM
+    pub certificate_path: Option<String>,
+    /// Number of days the self-signed certificate is valid for, defaults to 7300
+    pub validity_days: Option<u64>,
+}
+
+/// Identity key of the device and the provider holding it
+#[derive(Clone, Debug)]
+pub struct DeviceIdentity {
+    provider_id: ProviderID,
+    certificate_path: Option<String>,
+    // Self-signed certificate, for the providers which do not store certificates.
+    certificate: Option<Vec<u8>>,
+}
+
+/// Gets the reserved application owning the device identity key.
+pub fn app_name() -> ApplicationName {
+    ApplicationName::new(String::from(DEVICE_IDENTITY_APP_NAME))
+}
+
+// Providers the key can be created in, from the most trusted one.
+fn provider_ids(config: &DeviceIdentityConfig) -> Result<Vec<ProviderID>> {
+    match &config.provider_types {
+        Some(provider_types) => provider_types
+            .iter()
+            .map(|provider_type| {
+                provider_id_from_type(provider_type).ok_or_else(|| {
+                    format_error!(
+                        "Unknown provider type for the device identity",
+                        provider_type
+                    );
+                    Error::new(ErrorKind::InvalidData, "unknown provider type")
+                })
+            })
+            .collect(),
+        None => Ok(DEFAULT_PROVIDERS.to_vec()),
+    }
+}
+
+// Common name derived from the public key, unique to the device.
+fn default_common_name(public_key: &[u8]) -> String {
+    let public_key_digest = digest(&SHA256, public_key);
+    format!(
+        "parsec-device-{}",
+        hex::encode(&public_key_digest.as_ref()[..8])
+    )
+}
+
+// Finds the provider already holding the device identity key, if any.
+fn existing_provider(backends: &HashMap<ProviderID, BackEndHandler>) -> Option<ProviderID> {
+    let operation = NativeOperation::ListKeys(list_keys::Operation {});
+    match backends
+        .get(&ProviderID::Core)?
+        .execute_operation(operation, Some(app_name()), None)
+    {
+        Ok(NativeResult::ListKeys(result)) => result
+            .keys
+            .into_iter()
+            .find(|key| key.name == DEVICE_IDENTITY_KEY_NAME)
+            .map(|key| key.provider_id),
+        Ok(_) => None,
+        Err(status) => {
+            format_error!("Failed to look for the device identity key", status);
+            None
+        }
+    }
+}
+
+/// Finds the device identity key or creates it in the most trusted provider available, and
+/// certifies it itself or writes its certificate signing request if it does not have a
+/// certificate yet.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if a provider type is unknown or none of the providers
+/// configured is available, and the error of the provider or of writing the files otherwise.
+pub fn bootstrap(
+    config: &DeviceIdentityConfig,
+    backends: &HashMap<ProviderID, BackEndHandler>,
+) -> Result<DeviceIdentity> {
+    let provider_ids = provider_ids(config)?;
+    let provider_id = match existing_provider(backends) {
+        Some(provider_id) => provider_id,
+        None => {
+            let provider_id = provider_ids
+                .iter()
+                .copied()
+                .find(|provider_id| backends.contains_key(provider_id))
+                .ok_or_else(|| {
+                    error!("None of the providers of the device identity is available.");
+                    Error::new(ErrorKind::InvalidData, "provider not available")
+                })?;
+            info!(
+                "Creating the device identity key in the {} provider.",
+                provider_id
+            );
+            provider_id
+        }
+    };
+    if !provider_ids.contains(&provider_id) {
+        warn!(
+            "The device identity key is held by the {} provider, which is not one of the configured ones.",
+            provider_id
+        );
+    }
+
+    let backend = backends.get(&provider_id).ok_or_else(|| {
+        error!(
+            "The {} provider holding the device identity key is not available.",
+            provider_id
+        );
+        Error::new(ErrorKind::InvalidData, "provider not available")
+    })?;
+    let app_name = app_name();
+    let key = ProviderKey {
+        backend,
+        app_name: &app_name,
+        key_name: DEVICE_IDENTITY_KEY_NAME,
+    };
+    let public_key = key.public_key()?;
+    // The key must be usable even if the keys of the provider are created pre-active.
+    match key.backend.activate_key(
+        Some(app_name.clone()),
+        true,
+        app_name.clone(),
+        DEVICE_IDENTITY_KEY_NAME.to_string(),
+    ) {
+        Ok(()) | Err(ResponseStatus::PsaErrorNotSupported) => (),
+        Err(status) => return Err(status_error(status)),
+    }
+
+    let mut identity = DeviceIdentity {
+        provider_id,
+        certificate_path: config.certificate_path.clone(),
+        certificate: None,
+    };
+    if key
+        .current_certificate(config.certificate_path.as_ref())
+        .is_some()
+    {
+        return Ok(identity);
+    }
+
+    let common_name = config
+        .common_name
+        .clone()
+        .unwrap_or_else(|| default_common_name(&public_key));
+    if config.self_signed.unwrap_or(false) {
+        let not_before = SystemTime::now();
+        let validity_days = config.validity_days.unwrap_or(DEFAULT_VALIDITY_DAYS);
+        let not_after = not_before + Duration::from_secs(validity_days * 86_400);
+        let serial_number = &digest(&SHA256, &public_key).as_ref()[..16];
+        let tbs_certificate = der::tbs_certificate(
+            &public_key,
+            &common_name,
+            serial_number,
+            not_before,
+            not_after,
+        );
+        let certificate = der::certificate(&tbs_certificate, &key.sign(&tbs_certificate)?);
+        let chain = format!("{}\n", Pem::new("CERTIFICATE", &certificate[..]));
+        key.store_certificate(
+            certificate.clone(),
+            &chain,
+            config.certificate_path.as_ref(),
+        )?;
+        info!("Device identity \"{}\" certified by itself.", common_name);
+        identity.certificate = Some(certificate);
+    } else {
+        let csr = key.certificate_request(&common_name, &[])?;
+        match &config.csr_path {
+            Some(csr_path) => {
+                fs::write(
+                    csr_path,
+                    format!("{}\n", Pem::new("CERTIFICATE REQUEST", &csr[..])),
+                )?;
+                info!(
+                    "Certificate signing request of the device identity \"{}\" written to {}.",
+                    common_name, csr_path
+                );
+            }
+            None => warn!(
+                "The device identity does not have a certificate and no path is configured to write its signing request to."
+            ),
+        }
+    }
+
+    Ok(identity)
+}
+
+impl DeviceIdentity {
+    /// Gets the provider holding the device identity key.
+    pub fn provider_id(&self) -> ProviderID {
+        self.provider_id
+    }
+
+    /// Gets the certificate of the device identity, as DER, through the backend of its provider.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorDoesNotExist` if the key is not certified yet.
+    pub fn certificate(
+        &self,
+        backend: &BackEndHandler,
+    ) -> std::result::Result<Vec<u8>, ResponseStatus> {
+        let app_name = app_name();
+        let key = ProviderKey {
+            backend,
+            app_name: &app_name,
+            key_name: DEVICE_IDENTITY_KEY_NAME,
+        };
+        key.current_certificate(self.certificate_path.as_ref())
+            .or_else(|| self.certificate.clone())
+            .ok_or_else(|| {
+                error!("The device identity does not have a certificate yet.");
+                ResponseStatus::PsaErrorDoesNotExist
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{default_common_name, provider_ids, DeviceIdentityConfig, DEFAULT_PROVIDERS};
+    use parsec_interface::requests::ProviderID;
+
+    fn config(provider_types: Option<Vec<String>>) -> DeviceIdentityConfig {
+        DeviceIdentityConfig {
+            provider_types,
+            common_name: None,
+            self_signed: None,
+            csr_path: None,
+            certificate_path: None,
+            validity_days: None,
+        }
+    }
+
+    #[test]
+    fn providers_in_order_of_trust() {
+        assert_eq!(provider_ids(&config(None)).unwrap(), DEFAULT_PROVIDERS);
+        assert_eq!(
+            provider_ids(&config(Some(vec![
+                String::from("Pkcs11"),
+                String::from("MbedCrypto")
+            ])))
+            .unwrap(),
+            vec![ProviderID::Pkcs11, ProviderID::MbedCrypto]
+        );
+        assert!(provider_ids(&config(Some(vec![String::from("Unknown")]))).is_err());
+    }
+
+    #[test]
+    fn common_name_derived_from_key() {
+        let common_name = default_common_name(&[0x04; 65]);
+        assert!(common_name.starts_with("parsec-device-"));
+        assert_eq!(common_name.len(), "parsec-device-".len() + 16);
+        assert_ne!(common_name, default_common_name(&[0x05; 65]));
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! DER encoding of the certificate signing requests and decoding of the issued certificates
+//!
+//! Only the structures needed by the enrollment clients are handled: a PKCS #10 request for a NIST
+//! P-256 key, signed with ECDSA and SHA-256, with a common name and DNS names as subject
+//! alternative names, the expiry date of a certificate and the certificates of a PKCS #7
+//! certs-only message, along with the self-signed certificates of the device identity and the
+//! signatures of the key manifests.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0C;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const CONTEXT_CONSTRUCTED_0: u8 = 0xA0;
+const CONTEXT_PRIMITIVE_2: u8 = 0x82;
+
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
+const OID_EXTENSION_REQUEST: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x09, 0x0E];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1D, 0x11];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02];
+const OID_SIGNED_DATA: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x07, 0x02];
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else if len <= 0xFF {
+        encoded.extend_from_slice(&[0x81, len as u8]);
+    } else {
+        encoded.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]);
+    }
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+fn concat(parts: &[Vec<u8>]) -> Vec<u8> {
+    parts.concat()
+}
+
+// Encodes an unsigned big-endian integer, which needs a leading zero if its high bit is set.
+fn unsigned_integer(bytes: &[u8]) -> Vec<u8> {
+    let first_non_zero = bytes
+        .iter()
+        .position(|byte| *byte != 0)
+        .unwrap_or(bytes.len());
+    let mut content = bytes[first_non_zero..].to_vec();
+    if content.first().map_or(true, |byte| byte & 0x80 != 0) {
+        content.insert(0, 0);
+    }
+    tlv(INTEGER, &content)
+}
+
+fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    let mut content = vec![0];
+    content.extend_from_slice(bytes);
+    tlv(BIT_STRING, &content)
+}
+
+/// Builds the part of the request covered by its signature, for the key whose public key is given
+/// as an uncompressed point. The subject alternative names are only requested if there are DNS
+/// names.
+pub fn certification_request_info(
+    public_key: &[u8],
+    common_name: &str,
+    dns_names: &[String],
+) -> Vec<u8> {
+    let attributes = if dns_names.is_empty() {
+        tlv(CONTEXT_CONSTRUCTED_0, &[])
+    } else {
+        let general_names: Vec<Vec<u8>> = dns_names
+            .iter()
+            .map(|dns_name| tlv(CONTEXT_PRIMITIVE_2, dns_name.as_bytes()))
+            .collect();
+        let extensions = tlv(
+            SEQUENCE,
+            &tlv(
+                SEQUENCE,
+                &concat(&[
+                    tlv(OBJECT_IDENTIFIER, OID_SUBJECT_ALT_NAME),
+                    tlv(OCTET_STRING, &tlv(SEQUENCE, &concat(&general_names))),
+                ]),
+            ),
+        );
+        tlv(
+            CONTEXT_CONSTRUCTED_0,
+            &tlv(
+                SEQUENCE,
+                &concat(&[
+                    tlv(OBJECT_IDENTIFIER, OID_EXTENSION_REQUEST),
+                    tlv(SET, &extensions),
+                ]),
+            ),
+        )
+    };
+
+    tlv(
+        SEQUENCE,
+        &concat(&[
+            unsigned_integer(&[0]),
+            name(common_name),
+            subject_public_key_info(public_key),
+            attributes,
+        ]),
+    )
+}
+
+/// Builds the request from its signed part and the signature, given as the concatenation of r and
+/// s as returned by the PSA sign operations.
+pub fn certification_request(request_info: &[u8], signature: &[u8]) -> Vec<u8> {
+    signed(request_info, signature)
+}
+
+/// Builds the part of a self-signed certificate covered by its signature, for the key whose public
+/// key is given as an uncompressed point. The certificate is a version 1 one, without extensions,
+/// whose issuer and subject only have the common name.
+pub fn tbs_certificate(
+    public_key: &[u8],
+    common_name: &str,
+    serial_number: &[u8],
+    not_before: SystemTime,
+    not_after: SystemTime,
+) -> Vec<u8> {
+    tlv(
+        SEQUENCE,
+        &concat(&[
+            unsigned_integer(serial_number),
+            tlv(SEQUENCE, &tlv(OBJECT_IDENTIFIER, OID_ECDSA_WITH_SHA256)),
+            name(common_name),
+            tlv(SEQUENCE, &concat(&[time(not_before), time(not_after)])),
+            name(common_name),
+            subject_public_key_info(public_key),
+        ]),
+    )
+}
+
+/// Builds the certificate from its signed part and the signature, given as the concatenation of r
+/// and s.
+pub fn certificate(tbs_certificate: &[u8], signature: &[u8]) -> Vec<u8> {
+    signed(tbs_certificate, signature)
+}
+
+// Name made of a common name only.
+fn name(common_name: &str) -> Vec<u8> {
+    tlv(
+        SEQUENCE,
+        &tlv(
+            SET,
+            &tlv(
+                SEQUENCE,
+                &concat(&[
+                    tlv(OBJECT_IDENTIFIER, OID_COMMON_NAME),
+                    tlv(UTF8_STRING, common_name.as_bytes()),
+                ]),
+            ),
+        ),
+    )
+}
+
+fn subject_public_key_info(public_key: &[u8]) -> Vec<u8> {
+    tlv(
+        SEQUENCE,
+        &concat(&[
+            tlv(
+                SEQUENCE,
+                &concat(&[
+                    tlv(OBJECT_IDENTIFIER, OID_EC_PUBLIC_KEY),
+                    tlv(OBJECT_IDENTIFIER, OID_PRIME256V1),
+                ]),
+            ),
+            bit_string(public_key),
+        ]),
+    )
+}
+
+/// Encodes an ECDSA signature, given as the concatenation of r and s, as an `Ecdsa-Sig-Value`.
+pub fn ecdsa_signature(signature: &[u8]) -> Vec<u8> {
+    let (r, s) = signature.split_at(signature.len() / 2);
+    tlv(
+        SEQUENCE,
+        &concat(&[unsigned_integer(r), unsigned_integer(s)]),
+    )
+}
+
+// Appends the ECDSA with SHA-256 signature to the data it covers.
+fn signed(data: &[u8], signature: &[u8]) -> Vec<u8> {
+    let signature_value = ecdsa_signature(signature);
+
+    tlv(
+        SEQUENCE,
+        &concat(&[
+            data.to_vec(),
+            tlv(SEQUENCE, &tlv(OBJECT_IDENTIFIER, OID_ECDSA_WITH_SHA256)),
+            bit_string(&signature_value),
+        ]),
+    )
+}
+
+// Encodes a time as a UTCTime until 2049 and as a GeneralizedTime after, as RFC 5280 requires.
+fn time(time: SystemTime) -> Vec<u8> {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let time_of_day = seconds % 86_400;
+    let rest = format!(
+        "{:02}{:02}{:02}{:02}{:02}Z",
+        month,
+        day,
+        time_of_day / 3_600,
+        time_of_day % 3_600 / 60,
+        time_of_day % 60
+    );
+    if year < 2050 {
+        tlv(UTC_TIME, format!("{:02}{}", year % 100, rest).as_bytes())
+    } else {
+        tlv(GENERALIZED_TIME, format!("{:04}{}", year, rest).as_bytes())
+    }
+}
+
+// Splits the first element of the slice, returning its tag, its content and the elements after it.
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.get(0)?;
+    let first_len = *data.get(1)? as usize;
+    let (len, header_len) = match first_len {
+        0..=0x7F => (first_len, 2),
+        0x81 => (*data.get(2)? as usize, 3),
+        0x82 => (((*data.get(2)? as usize) << 8) | *data.get(3)? as usize, 4),
+        0x83 => (
+            ((*data.get(2)? as usize) << 16)
+                | ((*data.get(3)? as usize) << 8)
+                | *data.get(4)? as usize,
+            5,
+        ),
+        _ => return None,
+    };
+    let content = data.get(header_len..header_len.checked_add(len)?)?;
+    Some((tag, content, &data[header_len + len..]))
+}
+
+// Days between the epoch and the given date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Date of the proleptic Gregorian calendar the given number of days after the epoch.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = (if days >= 0 { days } else { days - 146_096 }) / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn parse_time(tag: u8, content: &[u8]) -> Option<SystemTime> {
+    let text = std::str::from_utf8(content).ok()?;
+    let (year, rest) = match tag {
+        UTC_TIME => {
+            let year: i64 = text.get(0..2)?.parse().ok()?;
+            (if year >= 50 { 1900 + year } else { 2000 + year }, 2)
+        }
+        GENERALIZED_TIME => (text.get(0..4)?.parse().ok()?, 4),
+        _ => return None,
+    };
+    let field =
+        |index: usize| -> Option<i64> { text.get(rest + index..rest + index + 2)?.parse().ok() };
+    let days = days_from_civil(year, field(0)?, field(2)?);
+    let seconds = days * 86_400 + field(4)? * 3_600 + field(6)? * 60 + field(8)?;
+    if seconds < 0 {
+        return None;
+    }
+
+    Some(UNIX_EPOCH + Duration::from_secs(seconds as u64))
+}
+
+/// Reads the end of the validity period of a DER-encoded certificate.
+pub fn not_after(certificate: &[u8]) -> Option<SystemTime> {
+    let (_, certificate, _) = read_tlv(certificate)?;
+    let (_, tbs_certificate, _) = read_tlv(certificate)?;
+    let (tag, _, mut rest) = read_tlv(tbs_certificate)?;
+    // The version is optional, the serial number being first without it.
+    if tag == CONTEXT_CONSTRUCTED_0 {
+        rest = read_tlv(rest)?.2;
+    }
+    // Signature algorithm and issuer.
+    rest = read_tlv(rest)?.2;
+    rest = read_tlv(rest)?.2;
+    let (_, validity, _) = read_tlv(rest)?;
+    let (_, _, validity) = read_tlv(validity)?;
+    let (tag, not_after, _) = read_tlv(validity)?;
+
+    parse_time(tag, not_after)
+}
+
+/// Reads the certificates of a PKCS #7 certs-only message, as returned by EST servers.
+pub fn pkcs7_certificates(message: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let (_, content_info, _) = read_tlv(message)?;
+    let (tag, content_type, rest) = read_tlv(content_info)?;
+    if tag != OBJECT_IDENTIFIER || content_type != OID_SIGNED_DATA {
+        return None;
+    }
+    let (_, explicit_content, _) = read_tlv(rest)?;
+    let (_, signed_data, _) = read_tlv(explicit_content)?;
+    // Version, digest algorithms and encapsulated content.
+    let mut rest = read_tlv(signed_data)?.2;
+    rest = read_tlv(rest)?.2;
+    rest = read_tlv(rest)?.2;
+    let (tag, mut certificates, _) = read_tlv(rest)?;
+    if tag != CONTEXT_CONSTRUCTED_0 {
+        return None;
+    }
+
+    let mut decoded = Vec::new();
+    while !certificates.is_empty() {
+        let (_, content, remaining) = read_tlv(certificates)?;
+        let header_len = certificates.len() - remaining.len() - content.len();
+        decoded.push(certificates[..header_len + content.len()].to_vec());
+        certificates = remaining;
+    }
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        certificate, certification_request, certification_request_info, not_after,
+        pkcs7_certificates, read_tlv, tbs_certificate, tlv,
+    };
+    use super::{
+        CONTEXT_CONSTRUCTED_0, INTEGER, OBJECT_IDENTIFIER, OID_SIGNED_DATA, SEQUENCE, SET, UTC_TIME,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn request_encoded_and_expiry_decoded() {
+        let mut public_key = vec![0x04];
+        public_key.extend_from_slice(&[0xAB; 64]);
+        let domains = vec![String::from("device.example.com")];
+        let info = certification_request_info(&public_key, &domains[0], &domains);
+        let mut signature = vec![0x80; 32];
+        signature.extend_from_slice(&[0x00, 0x01]);
+        signature.extend_from_slice(&[0x7F; 30]);
+        let request = certification_request(&info, &signature);
+
+        let (tag, content, rest) = read_tlv(&request).unwrap();
+        assert_eq!(tag, SEQUENCE);
+        assert!(rest.is_empty());
+        assert!(content.starts_with(&info));
+        // r has its high bit set and s a leading zero byte: their integers take 35 and 33 bytes.
+        let integers = &content[content.len() - 68..];
+        assert_eq!(&integers[..4], &[INTEGER, 33, 0x00, 0x80]);
+        assert_eq!(&integers[35..38], &[INTEGER, 31, 0x01]);
+
+        let tbs_certificate = [
+            tlv(CONTEXT_CONSTRUCTED_0, &tlv(INTEGER, &[2])),
+            tlv(INTEGER, &[1]),
+            tlv(SEQUENCE, &[]),
+            tlv(SEQUENCE, &[]),
+            tlv(
+                SEQUENCE,
+                &[
+                    tlv(UTC_TIME, b"200101000000Z"),
+                    tlv(UTC_TIME, b"210301120000Z"),
+                ]
+                .concat(),
+            ),
+        ]
+        .concat();
+        let certificate = tlv(SEQUENCE, &tlv(SEQUENCE, &tbs_certificate));
+        assert_eq!(
+            not_after(&certificate),
+            Some(UNIX_EPOCH + Duration::from_secs(1_614_600_000))
+        );
+
+        let signed_data = [
+            tlv(INTEGER, &[1]),
+            tlv(SET, &[]),
+            tlv(SEQUENCE, &tlv(OBJECT_IDENTIFIER, &[0x2A])),
+            tlv(
+                CONTEXT_CONSTRUCTED_0,
+                &[certificate.clone(), certificate.clone()].concat(),
+            ),
+            tlv(SET, &[]),
+        ]
+        .concat();
+        let message = tlv(
+            SEQUENCE,
+            &[
+                tlv(OBJECT_IDENTIFIER, OID_SIGNED_DATA),
+                tlv(CONTEXT_CONSTRUCTED_0, &tlv(SEQUENCE, &signed_data)),
+            ]
+            .concat(),
+        );
+        assert_eq!(
+            pkcs7_certificates(&message),
+            Some(vec![certificate.clone(), certificate])
+        );
+    }
+
+    #[test]
+    fn self_signed_certificate_encoded() {
+        let mut public_key = vec![0x04];
+        public_key.extend_from_slice(&[0xAB; 64]);
+        let not_before = UNIX_EPOCH + Duration::from_secs(1_614_600_000);
+        // After 2049, encoded as a GeneralizedTime.
+        let expiry = UNIX_EPOCH + Duration::from_secs(2_871_763_199);
+        let tbs = tbs_certificate(&public_key, "device", &[0x42; 16], not_before, expiry);
+        let certificate = certificate(&tbs, &[0x01; 64]);
+
+        let (tag, content, rest) = read_tlv(&certificate).unwrap();
+        assert_eq!(tag, SEQUENCE);
+        assert!(rest.is_empty());
+        assert!(content.starts_with(&tbs));
+        assert_eq!(not_after(&certificate), Some(expiry));
+    }
+}
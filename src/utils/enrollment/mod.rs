@@ -0,0 +1,207 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Certificate enrollment for keys held in a provider
+//!
+//! The ACME and EST clients share the handling of the keys they certify: NIST P-256 ECDSA keys
+//! created in the provider, on behalf of the configured application, if they do not exist, and
+//! used through the backend handler so that they are subject to the same checks as the keys of the
+//! clients. The certificate signing requests are built by the service and signed in the provider.
+//! Issued certificates are stored alongside their key when the provider supports it, and their
+//! chain written as PEM to a file if configured.
+use crate::authenticators::ApplicationName;
+use crate::back::backend_handler::BackEndHandler;
+use crate::back::dispatcher::Dispatcher;
+use crate::providers::provider_id_from_type;
+use log::info;
+use parsec_interface::operations::psa_algorithm::{AsymmetricSignature, Hash, SignHash};
+use parsec_interface::operations::psa_key_attributes::{
+    Attributes, EccFamily, Lifetime, Policy, Type, UsageFlags,
+};
+use parsec_interface::operations::{
+    psa_export_public_key, psa_generate_key, psa_sign_hash, NativeOperation, NativeResult,
+};
+use parsec_interface::requests::ResponseStatus;
+use picky::pem::Pem;
+use ring::digest::{digest, SHA256};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, SystemTime};
+
+pub mod der;
+
+/// Default number of days before its expiry when a certificate is renewed
+pub const DEFAULT_RENEW_BEFORE_DAYS: u64 = 30;
+
+/// Converts the status of an operation of the provider to an error.
+pub fn status_error(status: ResponseStatus) -> Error {
+    Error::new(ErrorKind::Other, status.to_string())
+}
+
+fn key_attributes() -> Attributes {
+    Attributes {
+        lifetime: Lifetime::Persistent,
+        key_type: Type::EccKeyPair {
+            curve_family: EccFamily::SecpR1,
+        },
+        bits: 256,
+        policy: Policy {
+            usage_flags: UsageFlags {
+                sign_hash: true,
+                verify_hash: true,
+                sign_message: false,
+                verify_message: false,
+                export: false,
+                encrypt: false,
+                decrypt: false,
+                cache: false,
+                copy: false,
+                derive: false,
+            },
+            permitted_algorithms: signature_algorithm().into(),
+        },
+    }
+}
+
+fn signature_algorithm() -> AsymmetricSignature {
+    AsymmetricSignature::Ecdsa {
+        hash_alg: SignHash::Specific(Hash::Sha256),
+    }
+}
+
+/// Gets the backend handler of the provider of the given type.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if the provider is unknown or not available.
+pub fn backend<'a>(dispatcher: &'a Dispatcher, provider_type: &str) -> Result<&'a BackEndHandler> {
+    provider_id_from_type(provider_type)
+        .and_then(|provider_id| dispatcher.backend(provider_id))
+        .ok_or_else(|| {
+            format_error!(
+                "The provider of the enrolled keys is not available",
+                provider_type
+            );
+            Error::new(ErrorKind::InvalidData, "provider not available")
+        })
+}
+
+/// Checks if the certificate is missing, can not be decoded or expires within the given number of
+/// days.
+pub fn needs_renewal(certificate: Option<&[u8]>, renew_before_days: u64) -> bool {
+    let renew_before = Duration::from_secs(renew_before_days * 86_400);
+    match certificate.and_then(der::not_after) {
+        Some(expiry) => expiry <= SystemTime::now() + renew_before,
+        None => true,
+    }
+}
+
+/// Key of a provider used on behalf of an application
+#[derive(Debug)]
+pub struct ProviderKey<'a> {
+    pub backend: &'a BackEndHandler,
+    pub app_name: &'a ApplicationName,
+    pub key_name: &'a str,
+}
+
+impl ProviderKey<'_> {
+    /// Creates the key if it does not exist and returns its public key, as an uncompressed point.
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        let generate = NativeOperation::PsaGenerateKey(psa_generate_key::Operation {
+            key_name: self.key_name.to_string(),
+            attributes: key_attributes(),
+        });
+        match self
+            .backend
+            .execute_operation(generate, Some(self.app_name.clone()), None)
+        {
+            Ok(_) => info!("Created the enrolled key \"{}\".", self.key_name),
+            Err(ResponseStatus::PsaErrorAlreadyExists) => (),
+            Err(status) => return Err(status_error(status)),
+        }
+
+        let export = NativeOperation::PsaExportPublicKey(psa_export_public_key::Operation {
+            key_name: self.key_name.to_string(),
+        });
+        match self
+            .backend
+            .execute_operation(export, Some(self.app_name.clone()), None)
+            .map_err(status_error)?
+        {
+            NativeResult::PsaExportPublicKey(result) => Ok(result.data.to_vec()),
+            _ => Err(Error::new(
+                ErrorKind::Other,
+                "unexpected result of the public key export",
+            )),
+        }
+    }
+
+    /// Signs the SHA-256 hash of the data, returning the concatenation of r and s.
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let hash = digest(&SHA256, data).as_ref().to_vec();
+        let sign = NativeOperation::PsaSignHash(psa_sign_hash::Operation {
+            key_name: self.key_name.to_string(),
+            alg: signature_algorithm(),
+            hash: hash.into(),
+        });
+        match self
+            .backend
+            .execute_operation(sign, Some(self.app_name.clone()), None)
+            .map_err(status_error)?
+        {
+            NativeResult::PsaSignHash(result) => Ok(result.signature.to_vec()),
+            _ => Err(Error::new(
+                ErrorKind::Other,
+                "unexpected result of the signature",
+            )),
+        }
+    }
+
+    /// Builds a certificate signing request for the key, creating it if needed, as DER.
+    pub fn certificate_request(&self, common_name: &str, dns_names: &[String]) -> Result<Vec<u8>> {
+        let request_info =
+            der::certification_request_info(&self.public_key()?, common_name, dns_names);
+        let signature = self.sign(&request_info)?;
+
+        Ok(der::certification_request(&request_info, &signature))
+    }
+
+    /// Gets the current certificate of the key, from the provider or, if the provider does not
+    /// store it, from the first certificate of the certificate file.
+    pub fn current_certificate(&self, certificate_path: Option<&String>) -> Option<Vec<u8>> {
+        self.backend
+            .get_certificate(self.app_name, self.key_name)
+            .ok()
+            .or_else(|| {
+                let chain = fs::read_to_string(certificate_path?).ok()?;
+                let pem: Pem = chain.parse().ok()?;
+                Some(pem.data().to_vec())
+            })
+    }
+
+    /// Stores the certificate alongside the key if the provider supports it, and writes the
+    /// chain, as PEM, to the certificate file if there is one.
+    pub fn store_certificate(
+        &self,
+        certificate: Vec<u8>,
+        chain: &str,
+        certificate_path: Option<&String>,
+    ) -> Result<()> {
+        match self
+            .backend
+            .store_certificate(self.app_name, self.key_name, certificate)
+        {
+            Ok(()) | Err(ResponseStatus::PsaErrorNotSupported) => (),
+            Err(status) => return Err(status_error(status)),
+        }
+        if let Some(certificate_path) = certificate_path {
+            // The file is replaced atomically so that servers reading it never see it half
+            // written.
+            let mut temporary_path = certificate_path.clone();
+            temporary_path.push_str(".new");
+            fs::write(&temporary_path, chain)?;
+            fs::rename(&temporary_path, certificate_path)?;
+        }
+
+        Ok(())
+    }
+}
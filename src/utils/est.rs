@@ -0,0 +1,221 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! EST client enrolling keys held in a provider
+//!
+//! Industrial deployments often run their PKI behind an EST (RFC 7030) server rather than ACME.
+//! For each configured certificate, the service enrolls a key handled as described in the
+//! `enrollment` module:
+//! * a key without certificate is enrolled with the `simpleenroll` operation
+//! * a key whose certificate expires within the configured number of days is enrolled again with
+//!   the `simplereenroll` operation, the same key being kept
+//!
+//! The certificate signing request is sent base64 encoded and the server returns the issued
+//! certificate in a base64 encoded PKCS #7 certs-only message. The service authenticates to the
+//! server with HTTP Basic credentials, the password being read as a secret: TLS client
+//! authentication with the key held in the provider is not supported. The CA certificates of the
+//! server must be trusted by the platform. A server answering that the enrollment is pending a
+//! manual approval is asked again at the next check.
+//!
+//! The certificates are checked when the service starts and then periodically, failures being
+//! logged and retried at the next check. This module is only compiled with the `est-client`
+//! feature.
+use super::enrollment::{self, der, ProviderKey, DEFAULT_RENEW_BEFORE_DAYS};
+use super::secrets;
+use crate::authenticators::ApplicationName;
+use crate::back::dispatcher::Dispatcher;
+use log::{error, info, warn};
+use picky::pem::Pem;
+use serde::Deserialize;
+use std::io::{Error, ErrorKind, Result};
+use zeroize::Zeroizing;
+
+/// Configuration of a certificate enrolled through EST
+#[derive(Clone, Deserialize, Debug)]
+pub struct EstConfig {
+    /// Base URL of the EST operations, such as `https://est.example.com/.well-known/est`
+    pub server_url: String,
+    /// Type of the provider holding the key
+    pub provider_type: String,
+    /// Application owning the key
+    pub app_name: String,
+    /// Name of the key enrolled
+    pub key_name: String,
+    /// Common name of the subject of the certificate
+    pub common_name: String,
+    /// DNS names requested as subject alternative names
+    pub dns_names: Option<Vec<String>>,
+    /// Name of the HTTP Basic authentication
+    pub username: Option<String>,
+    /// Password of the HTTP Basic authentication, as a `file:`, `env:` or `cred:` secret
+    pub password: Option<String>,
+    /// Path of the file the certificate chain is written in, as PEM
+    pub certificate_path: Option<String>,
+    /// Number of days before its expiry when the certificate is renewed, defaults to 30
+    pub renew_before_days: Option<u64>,
+}
+
+fn est_error(message: &str) -> Error {
+    Error::new(ErrorKind::Other, message.to_string())
+}
+
+// Gets the value of the HTTP Basic authorization header, if credentials are configured.
+fn authorization(config: &EstConfig) -> Result<Option<Zeroizing<String>>> {
+    let username = match &config.username {
+        Some(username) => username,
+        None => return Ok(None),
+    };
+    let password = match &config.password {
+        Some(password) => Zeroizing::new(secrets::read_external_secret(password).unwrap_or_else(
+            || {
+                error!("The EST password must be read from a file, an environment variable or a systemd credential.");
+                Err(Error::new(ErrorKind::InvalidData, "invalid EST password"))
+            },
+        )?),
+        None => Zeroizing::new(Vec::new()),
+    };
+    let mut credentials = Zeroizing::new(format!("{}:", username).into_bytes());
+    credentials.extend_from_slice(&password);
+
+    Ok(Some(Zeroizing::new(format!(
+        "Basic {}",
+        base64::encode(&credentials[..])
+    ))))
+}
+
+/// Sends the certificate signing request to the EST operation, returning the DER certificates of
+/// the response.
+fn enroll(config: &EstConfig, operation: &str, csr: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let url = format!("{}/{}", config.server_url.trim_end_matches('/'), operation);
+    let mut request = ureq::post(&url);
+    let _ = request
+        .set("Content-Type", "application/pkcs10")
+        .set("Content-Transfer-Encoding", "base64");
+    if let Some(authorization) = authorization(config)? {
+        let _ = request.set("Authorization", &authorization);
+    }
+
+    let response = request.send_string(&base64::encode(csr));
+    if response.status() == 202 {
+        warn!(
+            "The enrollment of the key \"{}\" is pending on the EST server.",
+            config.key_name
+        );
+        return Err(est_error("EST enrollment pending"));
+    }
+    let is_error = response.error();
+    let status = response.status();
+    let body = response.into_string()?;
+    if is_error {
+        format_error!(&format!("EST request failed with status {}", status), body);
+        return Err(est_error("EST request failed"));
+    }
+
+    let body: String = body.chars().filter(|c| !c.is_whitespace()).collect();
+    let message = base64::decode(&body).map_err(|_| est_error("invalid EST response encoding"))?;
+    match der::pkcs7_certificates(&message) {
+        Some(certificates) if !certificates.is_empty() => Ok(certificates),
+        _ => Err(est_error("no certificate in the EST response")),
+    }
+}
+
+/// Enrolls the key if it does not have a certificate, or enrolls it again if its certificate
+/// expires soon.
+///
+/// # Errors
+///
+/// Returns an error if the provider is not available or if enrolling the key or storing its
+/// certificate failed.
+pub fn renew_certificate(config: &EstConfig, dispatcher: &Dispatcher) -> Result<()> {
+    let backend = enrollment::backend(dispatcher, &config.provider_type)?;
+    let app_name = ApplicationName::new(config.app_name.clone());
+    let key = ProviderKey {
+        backend,
+        app_name: &app_name,
+        key_name: &config.key_name,
+    };
+    let current_certificate = key.current_certificate(config.certificate_path.as_ref());
+    if !enrollment::needs_renewal(
+        current_certificate.as_deref(),
+        config
+            .renew_before_days
+            .unwrap_or(DEFAULT_RENEW_BEFORE_DAYS),
+    ) {
+        return Ok(());
+    }
+
+    let operation = if current_certificate.is_some() {
+        "simplereenroll"
+    } else {
+        "simpleenroll"
+    };
+    info!(
+        "Enrolling the key \"{}\" with the EST {} operation.",
+        config.key_name, operation
+    );
+    let csr = key.certificate_request(
+        &config.common_name,
+        config
+            .dns_names
+            .as_ref()
+            .map_or(&[][..], |names| &names[..]),
+    )?;
+    let mut certificates = enroll(config, operation, &csr)?;
+    let chain: String = certificates
+        .iter()
+        .map(|certificate| format!("{}\n", Pem::new("CERTIFICATE", &certificate[..])))
+        .collect();
+    key.store_certificate(
+        certificates.remove(0),
+        &chain,
+        config.certificate_path.as_ref(),
+    )?;
+    info!("Certificate of the key \"{}\" enrolled.", config.key_name);
+
+    Ok(())
+}
+
+/// Enrolls the configured keys which do not have a certificate or whose certificate expires
+/// soon, logging the failures.
+pub fn renew_certificates(configs: &[EstConfig], dispatcher: &Dispatcher) {
+    for config in configs {
+        if let Err(e) = renew_certificate(config, dispatcher) {
+            format_error!(
+                &format!("Failed to enroll the key \"{}\"", config.key_name),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{authorization, EstConfig};
+
+    #[test]
+    fn basic_authorization() {
+        let mut config = EstConfig {
+            server_url: String::from("https://est.example.com/.well-known/est"),
+            provider_type: String::from("Pkcs11"),
+            app_name: String::from("plc"),
+            key_name: String::from("device-identity"),
+            common_name: String::from("plc-0042"),
+            dns_names: None,
+            username: None,
+            password: None,
+            certificate_path: None,
+            renew_before_days: None,
+        };
+        assert!(authorization(&config).unwrap().is_none());
+
+        config.username = Some(String::from("plc-0042"));
+        std::env::set_var("PARSEC_EST_TEST_PASSWORD", "secret");
+        config.password = Some(String::from("env:PARSEC_EST_TEST_PASSWORD"));
+        assert_eq!(
+            authorization(&config).unwrap().unwrap().as_str(),
+            "Basic cGxjLTAwNDI6c2VjcmV0"
+        );
+
+        config.password = Some(String::from("secret"));
+        assert!(authorization(&config).is_err());
+    }
+}
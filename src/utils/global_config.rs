@@ -8,12 +8,14 @@ use std::sync::atomic::Ordering;
 #[derive(Default, Debug)]
 pub struct GlobalConfig {
     log_error_details: AtomicBool,
+    session_correlation_ids: AtomicBool,
 }
 
 impl GlobalConfig {
     const fn new() -> Self {
         GlobalConfig {
             log_error_details: AtomicBool::new(false),
+            session_correlation_ids: AtomicBool::new(false),
         }
     }
 
@@ -22,18 +24,28 @@ impl GlobalConfig {
     pub fn log_error_details() -> bool {
         GLOBAL_CONFIG.log_error_details.load(Ordering::Relaxed)
     }
+
+    /// Determine whether the correlation IDs of the requests are
+    /// carried by the session field of the headers
+    pub fn session_correlation_ids() -> bool {
+        GLOBAL_CONFIG
+            .session_correlation_ids
+            .load(Ordering::Relaxed)
+    }
 }
 
 static GLOBAL_CONFIG: GlobalConfig = GlobalConfig::new();
 
 pub(super) struct GlobalConfigBuilder {
     log_error_details: bool,
+    session_correlation_ids: bool,
 }
 
 impl GlobalConfigBuilder {
     pub fn new() -> Self {
         GlobalConfigBuilder {
             log_error_details: false,
+            session_correlation_ids: false,
         }
     }
 
@@ -43,9 +55,18 @@ impl GlobalConfigBuilder {
         self
     }
 
+    pub fn with_session_correlation_ids(mut self, session_correlation_ids: bool) -> Self {
+        self.session_correlation_ids = session_correlation_ids;
+
+        self
+    }
+
     pub fn build(self) {
         GLOBAL_CONFIG
             .log_error_details
             .store(self.log_error_details, Ordering::Relaxed);
+        GLOBAL_CONFIG
+            .session_correlation_ids
+            .store(self.session_correlation_ids, Ordering::Relaxed);
     }
 }
@@ -0,0 +1,223 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! One-shot import of existing keys at startup
+//!
+//! To ease the adoption of Parsec on devices which already have keys in PEM files, the keys of a
+//! directory can be imported into a provider, on behalf of an application, when the service
+//! starts. The import only happens when the service is started with the `--import-keys` flag, so
+//! that it is not repeated on every start or configuration reload.
+//!
+//! Each file with the `.pem` extension is imported as a key named as the file without its
+//! extension. Only RSA keys in the PKCS #1 format (`RSA PRIVATE KEY` and `RSA PUBLIC KEY` labels)
+//! are supported as they are the import format of the PSA Crypto API, other files are skipped.
+//! The keys are imported through the backend handler of the provider, so that they are subject to
+//! the same checks as the keys imported by clients, and are allowed to sign and verify hashes with
+//! RSA PKCS #1 v1.5. Once imported, the files can optionally be shredded.
+//!
+//! Keys stored in PKCS 11 tokens, such as SoftHSM ones, can not be imported this way: they are
+//! usually not extractable, and the PKCS 11 provider should rather be configured to use the token
+//! directly.
+use crate::authenticators::ApplicationName;
+use crate::back::dispatcher::Dispatcher;
+use crate::providers::provider_id_from_type;
+use log::{error, info, warn};
+use parsec_interface::operations::psa_algorithm::{Algorithm, AsymmetricSignature, SignHash};
+use parsec_interface::operations::psa_import_key;
+use parsec_interface::operations::psa_key_attributes::{
+    Attributes, Lifetime, Policy, Type, UsageFlags,
+};
+use parsec_interface::operations::NativeOperation;
+use picky::pem::Pem;
+use serde::Deserialize;
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Configuration of the keys to import
+#[derive(Clone, Deserialize, Debug)]
+pub struct KeyImportConfig {
+    /// Directory containing the PEM files of the keys
+    pub directory: String,
+    /// Type of the provider to import the keys into
+    pub provider_type: String,
+    /// Application owning the imported keys
+    pub app_name: String,
+    /// Shred the files once their key is imported, defaults to false
+    pub shred: Option<bool>,
+}
+
+/// Imports the keys of the configured directory, returning how many were imported.
+///
+/// A key which can not be imported is reported and its file left untouched.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if the provider is unknown or not available and the
+/// error of reading the directory if it can not be read.
+pub fn import_keys(config: &KeyImportConfig, dispatcher: &Dispatcher) -> Result<usize> {
+    let backend = provider_id_from_type(&config.provider_type)
+        .and_then(|provider_id| dispatcher.backend(provider_id))
+        .ok_or_else(|| {
+            format_error!(
+                "The provider to import the keys into is not available",
+                config.provider_type
+            );
+            Error::new(ErrorKind::InvalidData, "provider not available")
+        })?;
+    let app_name = ApplicationName::new(config.app_name.clone());
+
+    let mut imported = 0;
+    for entry in fs::read_dir(&config.directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("pem") {
+            continue;
+        }
+        let key_name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(key_name) => String::from(key_name),
+            None => {
+                warn!("Skipping {}, its name is not valid UTF-8.", path.display());
+                continue;
+            }
+        };
+        let (key_type, data) = match read_pem_key(&path) {
+            Some(key) => key,
+            None => continue,
+        };
+
+        let operation = psa_import_key::Operation {
+            key_name,
+            attributes: import_attributes(key_type),
+            data: data.to_vec(),
+        };
+        if let Err(e) = backend.execute_operation(
+            NativeOperation::PsaImportKey(operation),
+            Some(app_name.clone()),
+            None,
+        ) {
+            format_error!(&format!("Failed to import {}", path.display()), e);
+            continue;
+        }
+        imported += 1;
+        info!("Imported the key of {}.", path.display());
+
+        if config.shred.unwrap_or(false) {
+            if let Err(e) = shred(&path) {
+                format_error!(&format!("Failed to shred {}", path.display()), e);
+            }
+        }
+    }
+
+    Ok(imported)
+}
+
+// Reads the key of a PEM file, returning `None` if it is not supported.
+fn read_pem_key(path: &Path) -> Option<(Type, Zeroizing<Vec<u8>>)> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => Zeroizing::new(contents),
+        Err(e) => {
+            format_error!(&format!("Failed to read {}", path.display()), e);
+            return None;
+        }
+    };
+    let pem: Pem = match contents.parse() {
+        Ok(pem) => pem,
+        Err(e) => {
+            format_error!(&format!("Failed to parse {}", path.display()), e);
+            return None;
+        }
+    };
+    match key_type_from_label(pem.label()) {
+        Some(key_type) => Some((key_type, Zeroizing::new(pem.data().to_vec()))),
+        None => {
+            warn!(
+                "Skipping {}, only PKCS #1 RSA keys are supported.",
+                path.display()
+            );
+            None
+        }
+    }
+}
+
+fn key_type_from_label(label: &str) -> Option<Type> {
+    match label {
+        "RSA PRIVATE KEY" => Some(Type::RsaKeyPair),
+        "RSA PUBLIC KEY" => Some(Type::RsaPublicKey),
+        _ => None,
+    }
+}
+
+fn import_attributes(key_type: Type) -> Attributes {
+    Attributes {
+        lifetime: Lifetime::Persistent,
+        key_type,
+        // The size of the key is deduced from its data.
+        bits: 0,
+        policy: Policy {
+            usage_flags: UsageFlags {
+                sign_hash: key_type == Type::RsaKeyPair,
+                verify_hash: true,
+                sign_message: false,
+                verify_message: false,
+                export: false,
+                encrypt: false,
+                decrypt: false,
+                cache: false,
+                copy: false,
+                derive: false,
+            },
+            permitted_algorithms: Algorithm::AsymmetricSignature(
+                AsymmetricSignature::RsaPkcs1v15Sign {
+                    hash_alg: SignHash::Any,
+                },
+            ),
+        },
+    }
+}
+
+// Overwrites the file with zeros before removing it.
+fn shred(path: &Path) -> Result<()> {
+    let len = fs::metadata(path)?.len();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let zeros = [0; 4096];
+    let mut written = 0;
+    while written < len {
+        let chunk = std::cmp::min(len - written, zeros.len() as u64);
+        file.write_all(&zeros[..chunk as usize])?;
+        written += chunk;
+    }
+    file.sync_all()?;
+
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{key_type_from_label, shred};
+    use parsec_interface::operations::psa_key_attributes::Type;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn only_pkcs1_keys_supported() {
+        assert_eq!(
+            key_type_from_label("RSA PRIVATE KEY"),
+            Some(Type::RsaKeyPair)
+        );
+        assert_eq!(
+            key_type_from_label("RSA PUBLIC KEY"),
+            Some(Type::RsaPublicKey)
+        );
+        assert_eq!(key_type_from_label("PRIVATE KEY"), None);
+        assert_eq!(key_type_from_label("CERTIFICATE"), None);
+    }
+
+    #[test]
+    fn shredded_file_removed() {
+        let path = PathBuf::from(env!("OUT_DIR").to_owned() + "/shredded_key.pem");
+        fs::write(&path, vec![0x42; 5000]).unwrap();
+
+        shred(&path).unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Signed manifest of the key inventory
+//!
+//! Compliance tooling auditing a fleet needs to know which keys each device holds and to notice
+//! when that changes. The manifest lists all the keys stored locally, of all the applications and
+//! providers, in a stable JSON schema identified by its `schema` field, with the entries sorted by
+//! provider, owner and name so that two manifests of a device can be diffed. Each entry gives:
+//! * the provider, the owner and the name of the key
+//! * its attributes, the usage flags being listed by name as in the Key Info Managers
+//! * its state and creation time, the latter being `null` for the keys created before it was
+//!   recorded
+//! * the SHA-256 digest of its public key, in hexadecimal, for the asymmetric keys whose public key
+//!   can be exported, and `null` otherwise
+//!
+//! The manifest is written along with a detached signature over its exact bytes, made with the key
+//! of the device identity: an ECDSA with SHA-256 signature, DER encoded, as `openssl dgst` checks
+//! it. The certificate of the device identity is part of the manifest, if the key is certified.
+//! The manifest is exported with the `--export-key-manifest` flag, by the administrator of the
+//! device.
+use super::device_identity::{self, DEVICE_IDENTITY_KEY_NAME};
+use super::enrollment::{der, ProviderKey};
+use crate::back::dispatcher::Dispatcher;
+use crate::key_info_managers::key_info_encoding::usage_flag_names;
+use crate::key_info_managers::{KeyInfo, KeyState, KeyTriple};
+use log::{error, info};
+use parsec_interface::operations::psa_algorithm::Algorithm;
+use parsec_interface::operations::psa_key_attributes::{Lifetime, Type};
+use parsec_interface::operations::{psa_export_public_key, NativeOperation, NativeResult};
+use parsec_interface::requests::ProviderID;
+use ring::digest::{digest, SHA256};
+use serde::Serialize;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Identifier of the schema of the manifests, changed if a field is removed or its meaning changes
+pub const SCHEMA: &str = "parsec-key-manifest/1";
+
+/// Key of the manifest
+#[derive(Clone, Serialize, Debug)]
+pub struct ManifestKey {
+    pub provider: String,
+    pub owner: String,
+    pub name: String,
+    pub key_type: Type,
+    pub bits: usize,
+    pub lifetime: Lifetime,
+    pub usage_flags: Vec<String>,
+    pub permitted_algorithms: Algorithm,
+    pub state: KeyState,
+    /// Creation time, in seconds since the Unix epoch
+    pub created_at: Option<u64>,
+    /// SHA-256 digest of the public key, in hexadecimal
+    pub public_key_sha256: Option<String>,
+}
+
+/// Device the keys of the manifest are held by
+#[derive(Clone, Serialize, Debug)]
+pub struct ManifestDevice {
+    /// Provider holding the device identity key
+    pub provider: String,
+    /// Certificate of the device identity, DER encoded in base64
+    pub certificate: Option<String>,
+}
+
+/// Manifest of the keys of the device
+#[derive(Clone, Serialize, Debug)]
+pub struct KeyManifest {
+    pub schema: &'static str,
+    /// Time the manifest was generated, in seconds since the Unix epoch
+    pub generated_at: u64,
+    pub device: ManifestDevice,
+    pub keys: Vec<ManifestKey>,
+}
+
+// Gets the digest of the public key, if the key is asymmetric and its public key can be exported.
+fn public_key_sha256(
+    dispatcher: &Dispatcher,
+    key_triple: &KeyTriple,
+    key_info: &KeyInfo,
+) -> Option<String> {
+    let is_asymmetric = match key_info.attributes.key_type {
+        Type::RsaKeyPair
+        | Type::RsaPublicKey
+        | Type::EccKeyPair { .. }
+        | Type::EccPublicKey { .. }
+        | Type::DhKeyPair { .. }
+        | Type::DhPublicKey { .. } => true,
+        _ => false,
+    };
+    if !is_asymmetric {
+        return None;
+    }
+    let operation = NativeOperation::PsaExportPublicKey(psa_export_public_key::Operation {
+        key_name: key_triple.key_name().to_string(),
+    });
+    match dispatcher
+        .backend(key_triple.provider_id())?
+        .execute_operation(operation, Some(key_triple.app_name().clone()), None)
+    {
+        Ok(NativeResult::PsaExportPublicKey(result)) => {
+            Some(hex::encode(digest(&SHA256, &result.data)))
+        }
+        _ => None,
+    }
+}
+
+fn manifest_key(
+    dispatcher: &Dispatcher,
+    key_triple: &KeyTriple,
+    key_info: &KeyInfo,
+) -> ManifestKey {
+    let attributes = &key_info.attributes;
+    ManifestKey {
+        provider: key_triple.provider_id().to_string(),
+        owner: key_triple.app_name().to_string(),
+        name: key_triple.key_name().to_string(),
+        key_type: attributes.key_type,
+        bits: attributes.bits,
+        lifetime: attributes.lifetime,
+        usage_flags: usage_flag_names(&attributes.policy.usage_flags),
+        permitted_algorithms: attributes.policy.permitted_algorithms,
+        state: key_info.state,
+        created_at: key_info.created_at,
+        public_key_sha256: public_key_sha256(dispatcher, key_triple, key_info),
+    }
+}
+
+fn not_available(message: &str) -> Error {
+    error!("{}", message);
+    Error::new(ErrorKind::InvalidData, "key manifest not available")
+}
+
+/// Builds the manifest of all the keys stored locally.
+///
+/// # Errors
+///
+/// Returns an error of kind `InvalidData` if the device identity is not configured, and the error
+/// of the core provider if the keys could not be listed.
+pub fn build(dispatcher: &Dispatcher) -> Result<KeyManifest> {
+    let device_identity = dispatcher.device_identity().ok_or_else(|| {
+        not_available("The key manifest needs the device identity, which is not configured.")
+    })?;
+    let key_inventory = dispatcher
+        .backend(ProviderID::Core)
+        .ok_or_else(|| not_available("The core provider is not available."))?
+        .key_inventory()
+        .map_err(|status| Error::new(ErrorKind::Other, status.to_string()))?;
+
+    let mut keys: Vec<ManifestKey> = key_inventory
+        .iter()
+        .map(|(key_triple, key_info)| manifest_key(dispatcher, key_triple, key_info))
+        .collect();
+    keys.sort_by(|a, b| (&a.provider, &a.owner, &a.name).cmp(&(&b.provider, &b.owner, &b.name)));
+
+    Ok(KeyManifest {
+        schema: SCHEMA,
+        generated_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or(0),
+        device: ManifestDevice {
+            provider: device_identity.provider_id().to_string(),
+            certificate: dispatcher
+                .device_certificate(Some(&device_identity::app_name()))
+                .ok()
+                .map(|certificate| base64::encode(&certificate)),
+        },
+        keys,
+    })
+}
+
+/// Writes the manifest of the keys to the given path, and its signature to the same path with the
+/// `.sig` extension added. Returns the number of keys listed.
+///
+/// # Errors
+///
+/// Returns an error if the manifest could not be built, signed or written.
+pub fn export(dispatcher: &Dispatcher, path: &str) -> Result<usize> {
+    let manifest = build(dispatcher)?;
+    let mut encoded = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+    encoded.push(b'\n');
+
+    let device_identity = dispatcher.device_identity().ok_or_else(|| {
+        not_available("The key manifest needs the device identity, which is not configured.")
+    })?;
+    let app_name = device_identity::app_name();
+    let key = ProviderKey {
+        backend: dispatcher
+            .backend(device_identity.provider_id())
+            .ok_or_else(|| {
+                not_available("The provider of the device identity is not available.")
+            })?,
+        app_name: &app_name,
+        key_name: DEVICE_IDENTITY_KEY_NAME,
+    };
+    let signature = der::ecdsa_signature(&key.sign(&encoded)?);
+
+    fs::write(path, &encoded)?;
+    fs::write(format!("{}.sig", path), &signature)?;
+    info!(
+        "Manifest of {} keys written to {}.",
+        manifest.keys.len(),
+        path
+    );
+
+    Ok(manifest.keys.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KeyManifest, ManifestDevice, ManifestKey, SCHEMA};
+    use crate::key_info_managers::KeyState;
+    use parsec_interface::operations::psa_algorithm::{
+        Algorithm, AsymmetricSignature, Hash, SignHash,
+    };
+    use parsec_interface::operations::psa_key_attributes::{EccFamily, Lifetime, Type};
+
+    #[test]
+    fn schema_fields() {
+        let manifest = KeyManifest {
+            schema: SCHEMA,
+            generated_at: 1_614_600_000,
+            device: ManifestDevice {
+                provider: String::from("TPM provider"),
+                certificate: None,
+            },
+            keys: vec![ManifestKey {
+                provider: String::from("TPM provider"),
+                owner: String::from("app"),
+                name: String::from("key"),
+                key_type: Type::EccKeyPair {
+                    curve_family: EccFamily::SecpR1,
+                },
+                bits: 256,
+                lifetime: Lifetime::Persistent,
+                usage_flags: vec![String::from("sign_hash")],
+                permitted_algorithms: Algorithm::AsymmetricSignature(AsymmetricSignature::Ecdsa {
+                    hash_alg: SignHash::Specific(Hash::Sha256),
+                }),
+                state: KeyState::Active,
+                created_at: None,
+                public_key_sha256: Some(String::from("00")),
+            }],
+        };
+
+        let value = serde_json::to_value(&manifest).unwrap();
+        assert_eq!(value["schema"], SCHEMA);
+        assert!(value["device"]["certificate"].is_null());
+        let key = &value["keys"][0];
+        for field in &[
+            "provider",
+            "owner",
+            "name",
+            "key_type",
+            "bits",
+            "lifetime",
+            "usage_flags",
+            "permitted_algorithms",
+            "state",
+            "created_at",
+            "public_key_sha256",
+        ] {
+            assert!(key.get(field).is_some(), "missing field {}", field);
+        }
+        assert_eq!(key["state"], "Active");
+    }
+}
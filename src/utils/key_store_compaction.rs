@@ -0,0 +1,84 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Compaction of the Key Info Managers
+//!
+//! After heavy key churn, the on-disk manager is left with the directories of the applications
+//! whose keys were all destroyed, and the SQLite database with the pages of the removed mappings.
+//! The compaction checks every mapping against its provider, rewrites the stores and removes what
+//! the destroyed keys left behind, logging the space reclaimed. It is done with the
+//! `--compact-key-stores` flag when the service starts and, if the
+//! `key_store_compaction_interval` is set, periodically.
+//!
+//! The mappings whose key the provider does not hold anymore are reported but not removed: the
+//! keys of a token may only be missing while it is unplugged, and the providers remove such
+//! mappings when they start if they are configured to. Providers which can not check their keys,
+//! such as the TPM provider whose key information is the wrapped key itself, are skipped.
+use crate::back::dispatcher::Dispatcher;
+use crate::key_info_managers::Compaction;
+use log::{error, info, warn};
+use parsec_interface::requests::{ProviderID, ResponseStatus};
+use serde::Serialize;
+use std::io::{Error, ErrorKind, Result};
+
+/// Result of the compaction of the Key Info Managers
+#[derive(Copy, Clone, Serialize, Debug, Default, PartialEq)]
+pub struct CompactionReport {
+    /// Number of mappings whose key was found in its provider
+    pub verified: usize,
+    /// Number of mappings whose key could not be found in its provider
+    pub orphaned: usize,
+    /// Number of mappings whose provider could not check the key
+    pub unverified: usize,
+    /// What the Key Info Managers reclaimed
+    pub compaction: Compaction,
+}
+
+/// Checks all the mappings against their provider and compacts the Key Info Managers.
+///
+/// # Errors
+///
+/// Returns an error if the mappings could not be listed or if a Key Info Manager could not be
+/// compacted.
+pub fn compact(dispatcher: &Dispatcher) -> Result<CompactionReport> {
+    let core_backend = dispatcher.backend(ProviderID::Core).ok_or_else(|| {
+        error!("The core provider is not available.");
+        Error::new(ErrorKind::Other, "core provider not available")
+    })?;
+    let status_error = |status: ResponseStatus| Error::new(ErrorKind::Other, status.to_string());
+
+    let mut report = CompactionReport::default();
+    for (key_triple, _) in core_backend.key_inventory().map_err(status_error)? {
+        let exists = match dispatcher.backend(key_triple.provider_id()) {
+            Some(backend) => backend.key_exists(&key_triple),
+            None => Err(ResponseStatus::ProviderNotRegistered),
+        };
+        match exists {
+            Ok(true) => report.verified += 1,
+            Ok(false) => {
+                if crate::utils::GlobalConfig::log_error_details() {
+                    warn!("The key of the mapping ({}) does not exist.", key_triple);
+                }
+                report.orphaned += 1;
+            }
+            Err(ResponseStatus::PsaErrorNotSupported) => report.unverified += 1,
+            Err(status) => {
+                format_error!("Failed to check the key of a mapping", status);
+                report.unverified += 1;
+            }
+        }
+    }
+    report.compaction = core_backend.compact_key_stores().map_err(status_error)?;
+
+    info!(
+        "Key Info Managers compacted: {} mappings rewritten, {} stale files and directories removed, {} bytes reclaimed.",
+        report.compaction.rewritten, report.compaction.removed, report.compaction.reclaimed_bytes
+    );
+    if report.orphaned > 0 {
+        warn!(
+            "{} mappings have no key in their provider, they are removed by the providers configured to purge them when they start.",
+            report.orphaned
+        );
+    }
+
+    Ok(report)
+}
@@ -1,8 +1,42 @@
 // Copyright 2019 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
 //! Service utilities
+#[cfg(feature = "acme-client")]
+pub mod acme;
+#[cfg(any(feature = "signed-config", feature = "policy-bundle"))]
+pub mod config_signature;
+pub mod correlation_id;
+pub mod cpu_affinity;
+pub mod dependency_probe;
+#[cfg(feature = "device-identity")]
+pub mod device_identity;
+#[cfg(any(
+    feature = "device-identity",
+    feature = "acme-client",
+    feature = "est-client"
+))]
+pub mod enrollment;
+#[cfg(feature = "est-client")]
+pub mod est;
 mod global_config;
+pub mod key_import;
+#[cfg(feature = "key-manifest")]
+pub mod key_manifest;
+pub mod key_store_compaction;
+pub mod name_policy;
+#[cfg(feature = "policy-bundle")]
+pub mod policy_bundle;
+pub mod secrets;
+#[cfg(feature = "self-check")]
+pub mod self_check;
+mod service;
 mod service_builder;
+#[cfg(feature = "on-disk-manager")]
+pub mod storage_snapshot;
+pub mod warm_restart;
 
 pub use global_config::GlobalConfig;
-pub use service_builder::{CoreSettings, ServiceBuilder, ServiceConfig};
+pub use service::{EmbeddedServiceBuilder, Service, StoppedService};
+pub use service_builder::{
+    opcode_from_name, CoreSettings, KeyInfoManagers, ServiceBuilder, ServiceConfig,
+};
@@ -0,0 +1,195 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Limits and normalization of key and application names
+//!
+//! Names coming from the clients end up in the Key Info Managers, for example as file names for
+//! the on-disk manager. To make sure that the backends can not be abused with pathological names,
+//! names are normalized to the Unicode Normalization Form C, so that two names only differing by
+//! their encoding are the same, and names containing control characters or longer than the
+//! configured limits, counted in bytes once normalized, are refused. The application owning the
+//! device identity is reserved to the service.
+//!
+//! Keys stored before these rules applied might not be reachable anymore if their name was not
+//! normalized or is too long. They are reported when the service starts, see `check_stored_keys`.
+use crate::authenticators::ApplicationName;
+use crate::key_info_managers::key_info_store::KeyInfoStore;
+use crate::key_info_managers::ManageKeyInfo;
+use log::{error, warn};
+use parsec_interface::requests::{ProviderID, ResponseStatus, Result};
+use unicode_normalization::UnicodeNormalization;
+
+/// Application owning the device identity key, which clients can not authenticate as. The name
+/// stays reserved when the service is built without the device identity, as its keys may exist.
+pub const DEVICE_IDENTITY_APP_NAME: &str = "parsec-device-identity";
+/// Default maximum length of the key names, in bytes
+pub const DEFAULT_MAX_KEY_NAME_LEN: usize = 256;
+/// Default maximum length of the application names, in bytes
+pub const DEFAULT_MAX_APP_NAME_LEN: usize = 256;
+
+/// Rules the key and application names must follow
+#[derive(Copy, Clone, Debug)]
+pub struct NamePolicy {
+    max_key_name_len: usize,
+    max_app_name_len: usize,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        NamePolicy {
+            max_key_name_len: DEFAULT_MAX_KEY_NAME_LEN,
+            max_app_name_len: DEFAULT_MAX_APP_NAME_LEN,
+        }
+    }
+}
+
+impl NamePolicy {
+    /// Creates the policy from the configured limits, the default ones being used for the limits
+    /// not given.
+    pub fn new(max_key_name_len: Option<usize>, max_app_name_len: Option<usize>) -> NamePolicy {
+        NamePolicy {
+            max_key_name_len: max_key_name_len.unwrap_or(DEFAULT_MAX_KEY_NAME_LEN),
+            max_app_name_len: max_app_name_len.unwrap_or(DEFAULT_MAX_APP_NAME_LEN),
+        }
+    }
+
+    /// Normalizes a key name given by a client.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PsaErrorInvalidArgument` if the name is empty, too long or contains control
+    /// characters.
+    pub fn normalize_key_name(&self, key_name: &str) -> Result<String> {
+        normalize(key_name, self.max_key_name_len).ok_or_else(|| {
+            error!("The key name is empty, too long or contains control characters.");
+            ResponseStatus::PsaErrorInvalidArgument
+        })
+    }
+
+    /// Normalizes the name of an authenticated application.
+    ///
+    /// # Errors
+    ///
+    /// Returns `AuthenticationError` if the name is empty, too long, contains control characters
+    /// or is reserved to the service.
+    pub fn normalize_app_name(&self, app_name: ApplicationName) -> Result<ApplicationName> {
+        let normalized =
+            normalize(app_name.get_name(), self.max_app_name_len).ok_or_else(|| {
+                error!("The application name is empty, too long or contains control characters.");
+                ResponseStatus::AuthenticationError
+            })?;
+        if normalized == DEVICE_IDENTITY_APP_NAME {
+            error!("The application name is reserved to the service.");
+            return Err(ResponseStatus::AuthenticationError);
+        }
+        if normalized == app_name.get_name() {
+            Ok(app_name)
+        } else {
+            Ok(ApplicationName::new(normalized))
+        }
+    }
+
+    /// Logs a warning for each key stored which could not be reached anymore because its key or
+    /// application name does not follow the policy, and returns how many there are.
+    pub fn check_stored_keys(&self, key_info_store: &KeyInfoStore) -> usize {
+        let store_handle = key_info_store.read();
+        let mut unreachable = 0;
+        for provider_id in &[ProviderID::MbedCrypto, ProviderID::Pkcs11, ProviderID::Tpm] {
+            let key_triples = match store_handle.get_all(*provider_id) {
+                Ok(key_triples) => key_triples,
+                Err(e) => {
+                    format_error!("Failed to list the keys stored", e);
+                    continue;
+                }
+            };
+            for key_triple in key_triples {
+                let app_name = key_triple.app_name().get_name();
+                let key_name = key_triple.key_name();
+                let reachable = normalize(app_name, self.max_app_name_len).as_deref()
+                    == Some(app_name)
+                    && normalize(key_name, self.max_key_name_len).as_deref() == Some(key_name);
+                if !reachable {
+                    unreachable += 1;
+                    if crate::utils::GlobalConfig::log_error_details() {
+                        warn!(
+                            "Key {} is not reachable anymore as its names are not normalized or too long.",
+                            key_triple
+                        );
+                    }
+                }
+            }
+        }
+        if unreachable > 0 {
+            warn!(
+                "{} stored keys are not reachable anymore as their names are not normalized or too long. They need to be migrated to new names.",
+                unreachable
+            );
+        }
+
+        unreachable
+    }
+}
+
+// Returns the NFC form of the name if it is valid.
+fn normalize(name: &str, max_len: usize) -> Option<String> {
+    let normalized: String = name.nfc().collect();
+    if normalized.is_empty()
+        || normalized.len() > max_len
+        || normalized.chars().any(char::is_control)
+    {
+        None
+    } else {
+        Some(normalized)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NamePolicy, DEVICE_IDENTITY_APP_NAME};
+    use crate::authenticators::ApplicationName;
+    use parsec_interface::requests::ResponseStatus;
+
+    #[test]
+    fn names_normalized() {
+        let policy = NamePolicy::new(Some(8), None);
+        // "e" followed by a combining acute accent is composed into a single character.
+        assert_eq!(
+            policy.normalize_key_name("cafe\u{301}").unwrap(),
+            "caf\u{e9}"
+        );
+        assert_eq!(
+            policy
+                .normalize_app_name(ApplicationName::new(String::from("a\u{30a}pp")))
+                .unwrap()
+                .get_name(),
+            "\u{e5}pp"
+        );
+    }
+
+    #[test]
+    fn invalid_names_refused() {
+        let policy = NamePolicy::new(Some(8), None);
+        for key_name in &["", "too-long-name", "key\nname", "key\u{7f}"] {
+            assert_eq!(
+                policy.normalize_key_name(key_name).unwrap_err(),
+                ResponseStatus::PsaErrorInvalidArgument
+            );
+        }
+        assert_eq!(
+            policy
+                .normalize_app_name(ApplicationName::new(String::from("app\0")))
+                .unwrap_err(),
+            ResponseStatus::AuthenticationError
+        );
+    }
+
+    #[test]
+    fn reserved_app_name_refused() {
+        let policy = NamePolicy::default();
+        assert_eq!(
+            policy
+                .normalize_app_name(ApplicationName::new(String::from(DEVICE_IDENTITY_APP_NAME)))
+                .unwrap_err(),
+            ResponseStatus::AuthenticationError
+        );
+    }
+}
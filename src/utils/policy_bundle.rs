@@ -0,0 +1,251 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Policy bundle distributed by a central management plane
+//!
+//! On large fleets, the key creation rules, the key size rules, the key naming rules, the application groups, the
+//! denied operations and the key slot quotas are decided centrally rather than in the configuration file of each device. The
+//! management plane writes them as a TOML bundle, with the same sections as the configuration
+//! file, next to a detached Ed25519 signature made with its private key in a file named as the
+//! bundle with a `.sig` extension added. It then sends SIGHUP to the service.
+//!
+//! When the configuration is read, the signature is verified with the public key of the
+//! management plane and every section present in the bundle replaces the one of the configuration
+//! file. The bundle is applied as a whole: if it can not be read, verified or parsed, none of it is
+//! applied and the configuration is refused. The bundle is signed but not encrypted as it only
+//! holds policies, no secrets.
+//!
+//! Each bundle has a `version`, which the management plane increases with each bundle it signs.
+//! The highest version applied is stored by the service, which refuses the bundles of a lower
+//! version: a bundle signed in the past, with looser policies, can not be replayed once replaced.
+//!
+//! The bundle is not pushed through the administration API as the latter is read-only and
+//! unauthenticated.
+use super::config_signature;
+use super::service_builder::ServiceConfig;
+use crate::back::key_creation_policy::{AppGroupConfig, KeyCreationRule, KeySizeRule};
+use crate::back::key_naming_policy::KeyNamingRule;
+use crate::back::key_slots::KeySlotsConfig;
+use log::info;
+use serde::Deserialize;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// Default path of the file storing the highest version of the bundles applied
+pub const DEFAULT_VERSION_PATH: &str = "./policy_bundle.version";
+
+/// Configuration of the policy bundle
+#[derive(Clone, Deserialize, Debug)]
+pub struct PolicyBundleConfig {
+    /// Path of the bundle, its signature being in the same path with a `.sig` extension added
+    pub path: String,
+    /// Public key of the management plane, encoded in hexadecimal, as a `file:`, `env:` or
+    /// `cred:` secret or the path of a file containing it
+    pub public_key: String,
+    /// Path of the file storing the highest version of the bundles applied, defaults to
+    /// `./policy_bundle.version`
+    pub version_path: Option<String>,
+}
+
+/// Policies of a bundle, each replacing the section of the configuration file if present
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyBundle {
+    /// Version of the bundle, increased by the management plane with each bundle, 0 if omitted
+    #[serde(default)]
+    pub version: u64,
+    pub app_group: Option<Vec<AppGroupConfig>>,
+    pub key_creation_rule: Option<Vec<KeyCreationRule>>,
+    pub key_size_rule: Option<Vec<KeySizeRule>>,
+    pub key_naming_rule: Option<Vec<KeyNamingRule>>,
+    pub key_slots: Option<Vec<KeySlotsConfig>>,
+    pub denied_opcodes: Option<Vec<String>>,
+}
+
+impl PolicyBundle {
+    /// Verifies the signature of the bundle contents with the key of the management plane and
+    /// parses them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `PermissionDenied` if the signature does not verify, and of kind
+    /// `InvalidData` if the bundle can not be parsed.
+    pub fn from_signed(contents: &[u8], signature: &[u8], public_key: &[u8]) -> Result<Self> {
+        config_signature::verify(contents, signature, public_key)?;
+
+        toml::from_str(&String::from_utf8_lossy(contents)).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Failed to parse the policy bundle ({})", e),
+            )
+        })
+    }
+
+    /// Replaces the sections of the configuration with the ones present in the bundle.
+    pub fn apply(self, config: &mut ServiceConfig) {
+        if self.app_group.is_some() {
+            config.app_group = self.app_group;
+        }
+        if self.key_creation_rule.is_some() {
+            config.key_creation_rule = self.key_creation_rule;
+        }
+        if self.key_size_rule.is_some() {
+            config.key_size_rule = self.key_size_rule;
+        }
+        if self.key_naming_rule.is_some() {
+            config.key_naming_rule = self.key_naming_rule;
+        }
+        if self.key_slots.is_some() {
+            config.key_slots = self.key_slots;
+        }
+        if self.denied_opcodes.is_some() {
+            config.core_settings.denied_opcodes = self.denied_opcodes;
+        }
+    }
+}
+
+/// Reads the configured policy bundle, if any, and applies it to the configuration, storing its
+/// version if it is the highest applied so far.
+///
+/// # Errors
+///
+/// Returns an error if the bundle, its signature or the public key can not be read, if the
+/// signature does not verify or if the bundle can not be parsed, of kind `PermissionDenied` if a
+/// bundle of a higher version was applied before, and the error of storing the version. The
+/// configuration is then left untouched.
+pub fn apply_configured(config: &mut ServiceConfig) -> Result<()> {
+    let bundle_config = match &config.policy_bundle {
+        Some(bundle_config) => bundle_config.clone(),
+        None => return Ok(()),
+    };
+
+    let public_key = config_signature::policy_key(Some(&bundle_config.public_key))?;
+    let contents = fs::read(&bundle_config.path)?;
+    let signature_path = config_signature::signature_path(&bundle_config.path);
+    let signature = fs::read(&signature_path).map_err(|e| {
+        Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "Failed to read the policy bundle signature {} ({})",
+                signature_path.display(),
+                e
+            ),
+        )
+    })?;
+
+    let bundle = PolicyBundle::from_signed(&contents, &signature, &public_key)?;
+    let version_path = bundle_config
+        .version_path
+        .as_deref()
+        .unwrap_or(DEFAULT_VERSION_PATH);
+    let version = bundle.version;
+    check_version(Path::new(version_path), version)?;
+    bundle.apply(config);
+    info!(
+        "Policy bundle {} applied, version {}.",
+        bundle_config.path, version
+    );
+
+    Ok(())
+}
+
+// Refuses the version of a bundle lower than the highest applied, otherwise stores it as the
+// highest.
+fn check_version(version_path: &Path, version: u64) -> Result<()> {
+    let highest_version = match fs::read_to_string(version_path) {
+        Ok(contents) => contents.trim().parse::<u64>().map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Failed to parse the version of the policy bundles {} ({})",
+                    version_path.display(),
+                    e
+                ),
+            )
+        })?,
+        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    if version < highest_version {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "The policy bundle has version {}, lower than the version {} applied before",
+                version, highest_version
+            ),
+        ));
+    }
+    if version > highest_version || !version_path.exists() {
+        let mut temporary_path = version_path.to_path_buf().into_os_string();
+        temporary_path.push(".tmp");
+        fs::write(&temporary_path, format!("{}\n", version))?;
+        fs::rename(&temporary_path, version_path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_version, PolicyBundle};
+    use crate::utils::ServiceConfig;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    #[test]
+    fn bundle_applied_when_signed() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let bundle = b"denied_opcodes = [\"PsaExportKey\"]\n";
+        let signature = key_pair.sign(bundle);
+
+        assert_eq!(
+            PolicyBundle::from_signed(
+                b"denied_opcodes = []\n",
+                signature.as_ref(),
+                key_pair.public_key().as_ref()
+            )
+            .unwrap_err()
+            .kind(),
+            ErrorKind::PermissionDenied
+        );
+
+        let mut config: ServiceConfig = toml::from_str(
+            "[core_settings]\ndenied_opcodes = [\"PsaSignHash\"]\n\
+             [listener]\nlistener_type = \"DomainSocket\"\ntimeout = 200\n",
+        )
+        .unwrap();
+        PolicyBundle::from_signed(bundle, signature.as_ref(), key_pair.public_key().as_ref())
+            .unwrap()
+            .apply(&mut config);
+        assert_eq!(
+            config.core_settings.denied_opcodes,
+            Some(vec![String::from("PsaExportKey")])
+        );
+        assert!(config.key_slots.is_none());
+    }
+
+    #[test]
+    fn older_bundle_refused() {
+        let version_path = Path::new("./policy_bundle_version_test");
+        let _ = fs::remove_file(version_path);
+
+        check_version(version_path, 2).unwrap();
+        check_version(version_path, 2).unwrap();
+        assert_eq!(
+            check_version(version_path, 1).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        check_version(version_path, 3).unwrap();
+        assert_eq!(fs::read_to_string(version_path).unwrap(), "3\n");
+        assert_eq!(
+            check_version(version_path, 2).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+
+        fs::remove_file(version_path).unwrap();
+    }
+}
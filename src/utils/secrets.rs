@@ -0,0 +1,182 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Retrieval of secrets from outside of the configuration file
+//!
+//! Secrets such as hierarchy authentication values or user PINs should preferably not be written
+//! in plaintext in the configuration file. These helpers read them from a separate file, which can
+//! have stricter access permissions, from an environment variable or from a systemd credential (see
+//! the `LoadCredential=` directive in `systemd.exec(5)`).
+//!
+//! The source is selected with a prefix on the configuration value: `file:` followed by a path,
+//! `env:` followed by a variable name or `cred:` followed by a credential name. As the secrets are
+//! read every time the service is built, they are also refreshed when the configuration is
+//! reloaded.
+//!
+//! A secret which itself starts with one of these prefixes would be read from the source it seems
+//! to designate: the configuration values using these helpers take such secrets after an escaping
+//! prefix of their own, such as the `str:` prefix of the TPM hierarchy authentication value.
+use log::error;
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// Prefix of the configuration values to be read from a file
+pub const FILE_PREFIX: &str = "file:";
+/// Prefix of the configuration values to be read from an environment variable
+pub const ENV_PREFIX: &str = "env:";
+/// Prefix of the configuration values to be read from a systemd credential
+pub const CREDENTIAL_PREFIX: &str = "cred:";
+
+/// Environment variable set by systemd to the directory containing the credentials of the unit.
+const CREDENTIALS_DIRECTORY_ENV: &str = "CREDENTIALS_DIRECTORY";
+
+/// Read a secret from the external source designated by the prefix of a configuration value.
+///
+/// Returns `None` if the value does not start with any of the known prefixes, in which case it
+/// should be used as is.
+pub fn read_external_secret(value: &str) -> Option<Result<Vec<u8>>> {
+    if value.starts_with(FILE_PREFIX) {
+        Some(read_secret_file(&value[FILE_PREFIX.len()..]))
+    } else if value.starts_with(ENV_PREFIX) {
+        Some(read_secret_env(&value[ENV_PREFIX.len()..]))
+    } else if value.starts_with(CREDENTIAL_PREFIX) {
+        Some(read_systemd_credential(&value[CREDENTIAL_PREFIX.len()..]))
+    } else {
+        None
+    }
+}
+
+/// Read a secret from a file. A single trailing newline character, as commonly added by text
+/// editors, is not considered part of the secret.
+pub fn read_secret_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let mut secret = fs::read(path).or_else(|e| {
+        format_error!("Failed to read the secret file", e);
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "secret file can not be read",
+        ))
+    })?;
+
+    if secret.last() == Some(&b'\n') {
+        let _ = secret.pop();
+    }
+
+    Ok(secret)
+}
+
+/// Read a secret from an environment variable.
+pub fn read_secret_env(name: &str) -> Result<Vec<u8>> {
+    match env::var(name) {
+        Ok(secret) => Ok(secret.into_bytes()),
+        Err(e) => {
+            format_error!("Failed to read the secret environment variable", e);
+            Err(Error::new(
+                ErrorKind::NotFound,
+                "secret environment variable can not be read",
+            ))
+        }
+    }
+}
+
+/// Read a secret from a systemd credential with the given name.
+pub fn read_systemd_credential(name: &str) -> Result<Vec<u8>> {
+    // Credential names are filenames inside the credentials directory.
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        error!("Invalid systemd credential name.");
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "invalid systemd credential name",
+        ));
+    }
+
+    let directory = env::var(CREDENTIALS_DIRECTORY_ENV).or_else(|_| {
+        error!(
+            "The {} environment variable is not set, is Parsec started by systemd with credentials?",
+            CREDENTIALS_DIRECTORY_ENV
+        );
+        Err(Error::new(
+            ErrorKind::NotFound,
+            "credentials directory not found",
+        ))
+    })?;
+
+    read_secret_file(Path::new(&directory).join(name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_external_secret, read_secret_env, read_secret_file, read_systemd_credential};
+    use std::env;
+    use std::fs;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn secret_file_read() {
+        let path = env!("OUT_DIR").to_owned() + "/secret_file";
+        fs::write(&path, "secret\n").unwrap();
+        assert_eq!(read_secret_file(&path).unwrap(), b"secret");
+        assert_eq!(
+            read_external_secret(&format!("file:{}", path))
+                .unwrap()
+                .unwrap(),
+            b"secret"
+        );
+
+        // Only a single trailing newline character is removed.
+        fs::write(&path, "secret\n\n").unwrap();
+        assert_eq!(read_secret_file(&path).unwrap(), b"secret\n");
+        fs::remove_file(&path).unwrap();
+        assert!(read_secret_file(&path).is_err());
+    }
+
+    #[test]
+    fn secret_env_read() {
+        env::set_var("PARSEC_TEST_SECRET", "secret");
+        assert_eq!(read_secret_env("PARSEC_TEST_SECRET").unwrap(), b"secret");
+        assert_eq!(
+            read_external_secret("env:PARSEC_TEST_SECRET")
+                .unwrap()
+                .unwrap(),
+            b"secret"
+        );
+        env::remove_var("PARSEC_TEST_SECRET");
+        assert_eq!(
+            read_secret_env("PARSEC_TEST_SECRET").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn values_without_prefix_used_as_is() {
+        assert!(read_external_secret("password").is_none());
+        assert!(read_external_secret("").is_none());
+        assert!(read_external_secret("str:file:/etc/parsec/tpm_auth").is_none());
+        assert!(read_external_secret("File:/etc/parsec/tpm_auth").is_none());
+    }
+
+    #[test]
+    fn systemd_credential_read() {
+        for name in &["", "..", ".", "../secret", "dir/secret"] {
+            assert_eq!(
+                read_systemd_credential(name).unwrap_err().kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+
+        let directory = env!("OUT_DIR").to_owned() + "/credentials";
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.clone() + "/tpm_auth", "secret").unwrap();
+        env::set_var("CREDENTIALS_DIRECTORY", &directory);
+        assert_eq!(
+            read_external_secret("cred:tpm_auth").unwrap().unwrap(),
+            b"secret"
+        );
+        assert!(read_systemd_credential("missing").is_err());
+        env::remove_var("CREDENTIALS_DIRECTORY");
+        assert_eq!(
+            read_systemd_credential("tpm_auth").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+}
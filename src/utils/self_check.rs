@@ -0,0 +1,151 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Integrity self-check of the service binary
+//!
+//! On devices whose secure boot does not cover the root filesystem, a replaced service binary is
+//! not detected before it handles the keys. When the check is configured, the service measures
+//! its own binary (through `/proc/self/exe`, so the file executed even if its path was since
+//! replaced) with SHA-256 when starting and logs the measurement. It then compares it with:
+//! * an expected hash, encoded in hexadecimal, given in the configuration or read with one of the
+//!   prefixes of the `secrets` module, for example from a systemd credential sealed to the TPM
+//! * the measurement of the binary recorded by the kernel Integrity Measurement Architecture, made
+//!   before the binary was executed, if IMA with the `ima-ng` template and SHA-256 is enabled
+//!
+//! A mismatch is logged as an error and, if the check is enforced, the service refuses to start.
+//! The check is only made when the service starts, not when the configuration is reloaded.
+use super::secrets;
+use log::{error, info, warn};
+use ring::digest::{Context, SHA256};
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Result};
+
+const SELF_EXE_PATH: &str = "/proc/self/exe";
+const IMA_MEASUREMENTS_PATH: &str = "/sys/kernel/security/ima/ascii_runtime_measurements";
+
+/// Configuration of the self-check
+#[derive(Clone, Deserialize, Debug)]
+pub struct SelfCheckConfig {
+    /// Expected SHA-256 of the binary, in hexadecimal or as a `file:`, `env:` or `cred:` secret
+    pub expected_hash: Option<String>,
+    /// Compare with the IMA measurement of the binary, defaults to false
+    pub ima: Option<bool>,
+    /// Refuse to start if the binary does not match, defaults to false
+    pub enforce: Option<bool>,
+}
+
+/// Measures the running service binary with SHA-256.
+pub fn measure() -> Result<Vec<u8>> {
+    let mut file = File::open(SELF_EXE_PATH)?;
+    let mut context = Context::new(&SHA256);
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let len = file.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        context.update(&buffer[..len]);
+    }
+
+    Ok(context.finish().as_ref().to_vec())
+}
+
+/// Finds the last SHA-256 measurement of the file in the IMA runtime measurements, in the
+/// `ima-ng` format: PCR, template hash, template name, `sha256:` file hash and file path.
+pub fn ima_measurement(measurements: &str, path: &str) -> Option<String> {
+    measurements
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [_, _, "ima-ng", file_hash, file_path, ..] if *file_path == path => {
+                    let mut file_hash = file_hash.splitn(2, ':');
+                    match (file_hash.next(), file_hash.next()) {
+                        (Some("sha256"), Some(file_hash)) => Some(file_hash.to_lowercase()),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            }
+        })
+        .last()
+}
+
+fn expected_hash(value: &str) -> Result<String> {
+    let encoded_hash = match secrets::read_external_secret(value) {
+        Some(secret) => String::from_utf8_lossy(&secret?).into_owned(),
+        None => value.to_string(),
+    };
+
+    Ok(encoded_hash.trim().to_lowercase())
+}
+
+/// Measures the service binary and compares it with the configured references.
+///
+/// # Errors
+///
+/// Returns an error of kind `PermissionDenied` if the binary does not match a reference and the
+/// check is enforced, and the error of reading the binary or a reference otherwise.
+pub fn check(config: &SelfCheckConfig) -> Result<()> {
+    let measurement = hex::encode(measure()?);
+    info!("SHA-256 of the service binary: {}.", measurement);
+
+    let mut matching = true;
+    let mut references = Vec::new();
+    if let Some(value) = &config.expected_hash {
+        references.push(("configured hash", expected_hash(value)?));
+    }
+    if config.ima.unwrap_or(false) {
+        let path = fs::read_link(SELF_EXE_PATH)?;
+        let measurements = fs::read_to_string(IMA_MEASUREMENTS_PATH)?;
+        match ima_measurement(&measurements, &path.to_string_lossy()) {
+            Some(ima_hash) => references.push(("IMA measurement", ima_hash)),
+            None => {
+                error!("No SHA-256 IMA measurement of the service binary was found.");
+                matching = false;
+            }
+        }
+    }
+    if references.is_empty() && matching {
+        warn!("The self-check is configured without any reference to compare with.");
+    }
+
+    for (reference_name, reference) in references {
+        if reference != measurement {
+            error!(
+                "The service binary does not match the {}, it might have been tampered with.",
+                reference_name
+            );
+            matching = false;
+        }
+    }
+
+    if !matching && config.enforce.unwrap_or(false) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "service binary integrity check failed",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::ima_measurement;
+
+    #[test]
+    fn ima_measurement_found() {
+        let measurements = "\
+10 91f34b5c671d73504b274a919661cf80dab1e127 ima-ng sha256:1111 boot_aggregate
+10 8b1683287f61f96e5448f40bdef6df32be86486a ima-ng sha256:AAAA /usr/bin/parsec
+10 ed893b1a0bc54ea5cd57014ca0a0f087ce71e4af ima-ng sha1:bbbb /usr/bin/parsec
+10 0ee6a7b85e4af2df1d6fd4b2d1c5e43a2f5a5d5c ima-ng sha256:cccc /usr/bin/other
+";
+        assert_eq!(
+            ima_measurement(measurements, "/usr/bin/parsec"),
+            Some(String::from("aaaa"))
+        );
+        assert_eq!(ima_measurement(measurements, "/usr/bin/missing"), None);
+    }
+}
@@ -0,0 +1,545 @@
+// Copyright 2019 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Service embedded in a program
+//!
+//! The `parsec` binary is a thin wrapper around `Service`, which other programs can embed instead
+//! of running the service on its own. The service is built from a `ServiceConfig`, which can be
+//! parsed from a string, to which `EmbeddedServiceBuilder` adds the components assembled
+//! programmatically: Key Info Managers created with `KeyInfoStore::new`, providers built with their
+//! own builders, authenticators and a listener. The sections of the configuration set per provider
+//! only apply to the providers it declares.
+//!
+//! The service does not start any thread for the requests: it accepts the connections and runs
+//! them, with its periodic tasks, on the thread pool given by the embedding program, from the
+//! thread calling `poll` or `run`. The signals, the notifications to systemd and the reload of the
+//! configuration are left to the embedding program: `stop` leaves the listener and the Key Info
+//! Managers to the next service built with `EmbeddedServiceBuilder::reusing`, and `shut_down`
+//! waits for the operations in flight before dropping the providers.
+#[cfg(feature = "acme-client")]
+use super::acme;
+use super::cpu_affinity;
+#[cfg(feature = "est-client")]
+use super::est;
+use super::key_store_compaction;
+use super::service_builder::{ServiceBuilder, ServiceComponents};
+#[cfg(feature = "on-disk-manager")]
+use super::storage_snapshot;
+use super::{KeyInfoManagers, ServiceConfig};
+use crate::authenticators::Authenticate;
+#[cfg(feature = "admin-api")]
+use crate::front::admin_api::AdminApiServer;
+#[cfg(feature = "extension-api")]
+use crate::front::extension_api::ExtensionApiServer;
+use crate::front::front_end::FrontEndHandler;
+use crate::front::listener::{Listen, ListenerConfig, ListenerType};
+use crate::key_info_managers::key_info_store::KeyInfoStore;
+use crate::providers::Provide;
+use derivative::Derivative;
+use log::{info, trace, warn};
+use parsec_interface::requests::AuthType;
+use std::io::Result;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
+
+const MAIN_LOOP_DEFAULT_SLEEP: u64 = 10;
+// Period at which the expired peer keys are destroyed.
+const PEER_KEYS_REAPER_PERIOD: Duration = Duration::from_secs(1);
+// Time given by default to the operations in flight to finish when shutting down, in seconds.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+// Time to wait between two checks for the operations in flight to be finished.
+const DRAIN_SLEEP: Duration = Duration::from_millis(10);
+// Time given to the operations cancelled once the shutdown timeout elapsed to give up.
+const CANCELLATION_GRACE: Duration = Duration::from_secs(1);
+// Period at which the stale capabilities of the providers are discovered again.
+const CAPABILITIES_CHECK_PERIOD: Duration = Duration::from_secs(10);
+// Period at which the certificates issued through ACME or EST are checked for renewal.
+#[cfg(any(feature = "acme-client", feature = "est-client"))]
+const ENROLLMENT_CHECK_PERIOD: Duration = Duration::from_secs(12 * 3600);
+
+/// Builder for `Service`
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct EmbeddedServiceBuilder {
+    config: ServiceConfig,
+    key_info_managers: KeyInfoManagers,
+    #[derivative(Debug = "ignore")]
+    components: ServiceComponents,
+    #[derivative(Debug = "ignore")]
+    listener: Option<Box<dyn Listen>>,
+}
+
+impl EmbeddedServiceBuilder {
+    /// Creates a builder for the service of the configuration.
+    pub fn new(config: ServiceConfig) -> EmbeddedServiceBuilder {
+        EmbeddedServiceBuilder {
+            config,
+            key_info_managers: Default::default(),
+            components: Default::default(),
+            listener: None,
+        }
+    }
+
+    /// Reuses what a stopped service left: its Key Info Managers whose configuration did not
+    /// change and its listener, with the new timeout, if the configuration of the listener did not
+    /// otherwise change. The TLS listener is always started again for it to load the certificates
+    /// renewed.
+    pub fn reusing(mut self, stopped_service: StoppedService) -> Self {
+        let StoppedService {
+            config,
+            mut listener,
+            key_info_managers,
+        } = stopped_service;
+        self.key_info_managers = key_info_managers;
+        if keeps_listener(&config.listener, &self.config.listener) {
+            listener.set_timeout(Duration::from_millis(self.config.listener.timeout));
+            self.listener = Some(listener);
+        }
+
+        self
+    }
+
+    /// Adds a Key Info Manager, for the providers given programmatically and for the core provider
+    /// to list the keys it stores.
+    pub fn with_key_info_manager(mut self, name: &str, key_info_store: Arc<KeyInfoStore>) -> Self {
+        self.components
+            .key_info_managers
+            .push((name.to_string(), key_info_store));
+
+        self
+    }
+
+    /// Adds a provider, which must not also be declared in the configuration.
+    pub fn with_provider(mut self, provider: Box<dyn Provide + Send + Sync>) -> Self {
+        self.components.providers.push(provider);
+
+        self
+    }
+
+    /// Adds an authenticator, after the ones of the configuration.
+    pub fn with_authenticator(
+        mut self,
+        auth_type: AuthType,
+        authenticator: Box<dyn Authenticate + Send + Sync>,
+    ) -> Self {
+        self.components
+            .authenticators
+            .push((auth_type, authenticator));
+
+        self
+    }
+
+    /// Sets the listener to accept the connections from, instead of the one of the configuration.
+    pub fn with_listener(mut self, listener: Box<dyn Listen>) -> Self {
+        self.listener = Some(listener);
+
+        self
+    }
+
+    /// Builds the service and starts the listener, the administration API and the extension API,
+    /// if they are configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind `InvalidData` if the configuration or the components given are
+    /// inconsistent, and the errors of the components failing to start otherwise.
+    pub fn build(self) -> Result<Service> {
+        let EmbeddedServiceBuilder {
+            config,
+            mut key_info_managers,
+            components,
+            listener,
+        } = self;
+        let front_end_handler = Arc::new(ServiceBuilder::build_service_with_components(
+            &config,
+            &mut key_info_managers,
+            components,
+        )?);
+        #[cfg(feature = "admin-api")]
+        let admin_api_server = match &config.admin_api {
+            Some(admin_api_config) => Some(AdminApiServer::start(
+                admin_api_config,
+                front_end_handler.clone(),
+            )?),
+            None => None,
+        };
+        #[cfg(feature = "extension-api")]
+        let extension_api_server = match &config.extension_api {
+            Some(extension_api_config) => Some(ExtensionApiServer::start(
+                extension_api_config,
+                front_end_handler.clone(),
+            )?),
+            None => None,
+        };
+        let listener = match listener {
+            Some(listener) => listener,
+            None => ServiceBuilder::start_listener(config.listener.clone())?,
+        };
+        let worker_cpu_set = Arc::new(ServiceBuilder::build_worker_cpu_set(&config.core_settings)?);
+
+        Ok(Service {
+            config,
+            front_end_handler,
+            #[cfg(feature = "admin-api")]
+            admin_api_server,
+            #[cfg(feature = "extension-api")]
+            extension_api_server,
+            listener,
+            key_info_managers,
+            worker_cpu_set,
+            last_reap: Instant::now(),
+            last_capabilities_check: Instant::now(),
+            last_compaction: Instant::now(),
+            #[cfg(feature = "on-disk-manager")]
+            last_snapshot: Instant::now(),
+            // The certificates are checked as soon as the service runs.
+            #[cfg(any(feature = "acme-client", feature = "est-client"))]
+            last_enrollment_check: None,
+        })
+    }
+}
+
+/// Parsec service ready to accept connections
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Service {
+    config: ServiceConfig,
+    front_end_handler: Arc<FrontEndHandler>,
+    #[cfg(feature = "admin-api")]
+    admin_api_server: Option<AdminApiServer>,
+    #[cfg(feature = "extension-api")]
+    extension_api_server: Option<ExtensionApiServer>,
+    #[derivative(Debug = "ignore")]
+    listener: Box<dyn Listen>,
+    key_info_managers: KeyInfoManagers,
+    worker_cpu_set: Arc<Option<Vec<usize>>>,
+    last_reap: Instant,
+    last_capabilities_check: Instant,
+    last_compaction: Instant,
+    #[cfg(feature = "on-disk-manager")]
+    last_snapshot: Instant,
+    #[cfg(any(feature = "acme-client", feature = "est-client"))]
+    last_enrollment_check: Option<Instant>,
+}
+
+/// What a stopped service leaves to the next one, see `EmbeddedServiceBuilder::reusing`
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct StoppedService {
+    config: ServiceConfig,
+    #[derivative(Debug = "ignore")]
+    listener: Box<dyn Listen>,
+    key_info_managers: KeyInfoManagers,
+}
+
+impl StoppedService {
+    /// Gets the configuration the service was built from.
+    pub fn config(&self) -> &ServiceConfig {
+        &self.config
+    }
+
+    /// Gets the file descriptor of the listening socket, for it to be handed off to a new binary of
+    /// the service, if the listener can be handed off.
+    pub fn listener_raw_fd(&self) -> Option<RawFd> {
+        self.listener.raw_fd()
+    }
+}
+
+impl Service {
+    /// Gets the configuration the service was built from.
+    pub fn config(&self) -> &ServiceConfig {
+        &self.config
+    }
+
+    /// Gets the front end handler, through which the service can be used without a listener.
+    pub fn front_end_handler(&self) -> &Arc<FrontEndHandler> {
+        &self.front_end_handler
+    }
+
+    /// Runs the periodic tasks which are due and the request of the next connection, if any, and
+    /// of the next connection of the extension API, on the thread pool. Returns false if no
+    /// connection was waiting.
+    pub fn poll(&mut self, threadpool: &ThreadPool) -> bool {
+        if self.last_reap.elapsed() >= PEER_KEYS_REAPER_PERIOD {
+            self.last_reap = Instant::now();
+            let front_end_handler = self.front_end_handler.clone();
+            threadpool.execute(move || front_end_handler.reap_expired_peer_keys());
+        }
+
+        if self.last_capabilities_check.elapsed() >= CAPABILITIES_CHECK_PERIOD {
+            self.refresh_capabilities(threadpool, true);
+        }
+
+        if let Some(interval) = self
+            .config
+            .core_settings
+            .key_store_compaction_interval
+            .filter(|interval| *interval > 0)
+        {
+            if self.last_compaction.elapsed() >= Duration::from_secs(interval) {
+                self.last_compaction = Instant::now();
+                let front_end_handler = self.front_end_handler.clone();
+                threadpool.execute(move || {
+                    if let Err(e) = key_store_compaction::compact(front_end_handler.dispatcher()) {
+                        format_error!("Failed to compact the Key Info Managers", e);
+                    }
+                });
+            }
+        }
+
+        #[cfg(feature = "on-disk-manager")]
+        if let Some(config) = self
+            .config
+            .storage_snapshot
+            .clone()
+            .filter(|config| config.interval.unwrap_or(0) > 0)
+        {
+            if self.last_snapshot.elapsed() >= Duration::from_secs(config.interval.unwrap_or(0)) {
+                self.last_snapshot = Instant::now();
+                let front_end_handler = self.front_end_handler.clone();
+                threadpool.execute(move || {
+                    if let Err(e) =
+                        storage_snapshot::snapshot(&config, front_end_handler.dispatcher())
+                    {
+                        format_error!("Failed to take a snapshot of the storage", e);
+                    }
+                });
+            }
+        }
+
+        #[cfg(any(feature = "acme-client", feature = "est-client"))]
+        self.check_enrollments(threadpool);
+
+        #[cfg(feature = "extension-api")]
+        let extension_request = self
+            .extension_api_server
+            .as_ref()
+            .map_or(false, |server| server.poll(threadpool));
+        #[cfg(not(feature = "extension-api"))]
+        let extension_request = false;
+
+        match self.listener.accept() {
+            Some(connection) => {
+                let front_end_handler = self.front_end_handler.clone();
+                let worker_cpu_set = self.worker_cpu_set.clone();
+                threadpool.execute(move || {
+                    if let Some(cpu_set) = &*worker_cpu_set {
+                        cpu_affinity::pin_current_thread(cpu_set);
+                    }
+                    front_end_handler.handle_request(connection);
+                    trace!("handle_request egress");
+                });
+                true
+            }
+            None => extension_request,
+        }
+    }
+
+    /// Polls the service until `stop` is set, sleeping for the configured duration while no
+    /// connection is waiting.
+    pub fn run(&mut self, threadpool: &ThreadPool, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            if !self.poll(threadpool) {
+                self.idle();
+            }
+        }
+    }
+
+    /// Sleeps for the configured duration, to be called when `poll` found no connection waiting.
+    pub fn idle(&self) {
+        ::std::thread::sleep(Duration::from_millis(
+            self.config
+                .core_settings
+                .idle_listener_sleep_duration
+                .unwrap_or(MAIN_LOOP_DEFAULT_SLEEP),
+        ));
+    }
+
+    /// Discovers again the capabilities of the providers on the thread pool, only of the ones which
+    /// may have changed if `stale_only` is set.
+    pub fn refresh_capabilities(&mut self, threadpool: &ThreadPool, stale_only: bool) {
+        self.last_capabilities_check = Instant::now();
+        let front_end_handler = self.front_end_handler.clone();
+        threadpool.execute(move || {
+            let _ = front_end_handler
+                .dispatcher()
+                .refresh_capabilities(stale_only);
+        });
+    }
+
+    #[cfg(any(feature = "acme-client", feature = "est-client"))]
+    fn check_enrollments(&mut self, threadpool: &ThreadPool) {
+        if self
+            .last_enrollment_check
+            .map_or(false, |last| last.elapsed() < ENROLLMENT_CHECK_PERIOD)
+        {
+            return;
+        }
+        self.last_enrollment_check = Some(Instant::now());
+        #[cfg(feature = "acme-client")]
+        {
+            if let Some(acme_configs) = self.config.acme.clone() {
+                let front_end_handler = self.front_end_handler.clone();
+                threadpool.execute(move || {
+                    acme::renew_certificates(&acme_configs, front_end_handler.dispatcher())
+                });
+            }
+        }
+        #[cfg(feature = "est-client")]
+        {
+            if let Some(est_configs) = self.config.est.clone() {
+                let front_end_handler = self.front_end_handler.clone();
+                threadpool.execute(move || {
+                    est::renew_certificates(&est_configs, front_end_handler.dispatcher())
+                });
+            }
+        }
+    }
+
+    /// Waits for all the operations on the thread pool to finish and drops the providers, leaving
+    /// the listener, with the connections waiting on it, and the Key Info Managers to the next
+    /// service.
+    pub fn stop(self, threadpool: &ThreadPool) -> StoppedService {
+        threadpool.join();
+        let Service {
+            config,
+            front_end_handler,
+            #[cfg(feature = "admin-api")]
+            admin_api_server,
+            #[cfg(feature = "extension-api")]
+            extension_api_server,
+            listener,
+            key_info_managers,
+            ..
+        } = self;
+        // The providers are finalized before the next ones are built, some libraries can not be
+        // initialized twice.
+        #[cfg(feature = "admin-api")]
+        drop(admin_api_server);
+        #[cfg(feature = "extension-api")]
+        drop(extension_api_server);
+        drop(front_end_handler);
+
+        StoppedService {
+            config,
+            listener,
+            key_info_managers,
+        }
+    }
+
+    /// Stops accepting connections and waits for the operations queued and running on the thread
+    /// pool to finish, up to the configured shutdown timeout, before dropping the providers and
+    /// closing the Key Info Managers. The operations still running once it elapsed are cancelled,
+    /// giving up at the next point where they check their deadline. Returns false if some were
+    /// still running after that, the providers then being finalized by the last of them, if the
+    /// program does not exit first: the key creations interrupted are rolled back when the service
+    /// starts again.
+    pub fn shut_down(self, threadpool: &ThreadPool) -> bool {
+        let Service {
+            config,
+            front_end_handler,
+            #[cfg(feature = "admin-api")]
+            admin_api_server,
+            #[cfg(feature = "extension-api")]
+            extension_api_server,
+            listener,
+            key_info_managers,
+            ..
+        } = self;
+        // No connection is accepted anymore: clients are refused or, if the socket was given by
+        // systemd, wait for the next instance of the service.
+        drop(listener);
+        let shutdown_timeout = Duration::from_secs(
+            config
+                .core_settings
+                .shutdown_timeout
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+        );
+        if !drain(threadpool, shutdown_timeout) {
+            front_end_handler.dispatcher().cancel_operations();
+        }
+        if !drain(threadpool, CANCELLATION_GRACE) {
+            warn!(
+                "{} operations still running after {} seconds and their cancellation, leaving them.",
+                threadpool.active_count() + threadpool.queued_count(),
+                shutdown_timeout.as_secs()
+            );
+            return false;
+        }
+        #[cfg(feature = "admin-api")]
+        drop(admin_api_server);
+        #[cfg(feature = "extension-api")]
+        drop(extension_api_server);
+        // The last reference to the front end handler is dropped with it, finalizing the providers
+        // and closing the Key Info Managers once no operation uses them.
+        drop(front_end_handler);
+        drop(key_info_managers);
+        info!("Parsec service shut down.");
+
+        true
+    }
+}
+
+// Waits for the operations queued and running in the thread pool to finish, up to the timeout.
+// Returns false if some are still running once it elapsed.
+fn drain(threadpool: &ThreadPool, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while threadpool.active_count() + threadpool.queued_count() > 0 {
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        ::std::thread::sleep(DRAIN_SLEEP);
+    }
+
+    true
+}
+
+// Checks if the listener can be kept when the configuration is reloaded, only its timeout having
+// changed.
+fn keeps_listener(config: &ListenerConfig, new_config: &ListenerConfig) -> bool {
+    let mut config = config.clone();
+    config.timeout = new_config.timeout;
+    if config != *new_config {
+        return false;
+    }
+    if let ListenerType::Tls = config.listener_type {
+        info!("Starting the TLS listener again to load its certificates.");
+        return false;
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::keeps_listener;
+    use crate::front::listener::{ListenerConfig, ListenerType};
+
+    fn listener_config(listener_type: ListenerType, timeout: u64) -> ListenerConfig {
+        ListenerConfig {
+            listener_type,
+            timeout,
+            #[cfg(feature = "tls-listener")]
+            tls: None,
+            #[cfg(feature = "vsock-listener")]
+            vsock: None,
+        }
+    }
+
+    #[test]
+    fn listener_kept_if_only_timeout_changed() {
+        let config = listener_config(ListenerType::DomainSocket, 200);
+        assert!(keeps_listener(
+            &config,
+            &listener_config(ListenerType::DomainSocket, 500)
+        ));
+        assert!(!keeps_listener(
+            &config,
+            &listener_config(ListenerType::Vsock, 200)
+        ));
+        let tls_config = listener_config(ListenerType::Tls, 200);
+        assert!(!keeps_listener(&tls_config, &tls_config));
+    }
+}
@@ -4,47 +4,130 @@
 //!
 //! The service builder is required to bootstrap all the components based on a
 //! provided configuration.
+use super::cpu_affinity;
+use super::dependency_probe;
+#[cfg(feature = "device-identity")]
+use super::device_identity::{self, DeviceIdentityConfig};
 use super::global_config::GlobalConfigBuilder;
-use crate::authenticators::direct_authenticator::DirectAuthenticator;
+use super::key_import::KeyImportConfig;
+use super::name_policy::NamePolicy;
+#[cfg(feature = "policy-bundle")]
+use super::policy_bundle::PolicyBundleConfig;
+#[cfg(feature = "self-check")]
+use super::self_check::SelfCheckConfig;
+#[cfg(feature = "on-disk-manager")]
+use super::storage_snapshot::StorageSnapshotConfig;
+use crate::authenticators::authenticator_chain::{
+    auth_type_from_name, AuthenticatorConfig, ChainedAuthenticator,
+};
+#[cfg(feature = "jwt-svid-authenticator")]
+use crate::authenticators::jwt_svid_authenticator::{JwtSvidAuthenticator, JwtSvidConfig};
+use crate::authenticators::Authenticate;
+#[cfg(feature = "audit-log")]
+use crate::back::audit::{AuditConfig, AuditLog};
+#[cfg(feature = "dead-letters")]
+use crate::back::dead_letters::{DeadLetters, DeadLettersConfig};
+#[cfg(feature = "delegation-tokens")]
+use crate::back::delegation_tokens::{
+    DelegationTokens, DelegationTokensConfig, DEFAULT_MAX_VALIDITY,
+};
+#[cfg(feature = "key-counters")]
+use crate::back::key_counters::KeyCounters;
 use crate::back::{
     backend_handler::{BackEndHandler, BackEndHandlerBuilder},
+    canary_keys::{CanaryKeyConfig, CanaryKeys},
     dispatcher::DispatcherBuilder,
+    fault_injection::{FaultInjection, FaultInjectionConfig},
+    key_activation::{KeyActivation, KeyActivationConfig},
+    key_binding::KeyBindings,
+    key_certificates::KeyCertificates,
+    key_creation_policy::{AppGroupConfig, KeyCreationPolicy, KeyCreationRule, KeySizeRule},
+    key_labels::KeyLabels,
+    key_naming_policy::{KeyNamingPolicy, KeyNamingRule},
+    key_slots::{KeySlots, KeySlotsConfig},
+    memory_limits::{MemoryLimits, MemoryLimitsConfig},
+    operation_deadlines::{OperationDeadlines, OperationDeadlinesConfig},
+    shadow::{Shadow, ShadowConfig, SHADOWABLE_OPCODES},
 };
+#[cfg(feature = "event-hooks")]
+use crate::back::{
+    event_hooks::{EventHookConfig, EventHooks},
+    key_publisher::{KeyPublisher, KeyPublisherConfig},
+};
+#[cfg(feature = "admin-api")]
+use crate::front::admin_api::AdminApiConfig;
+#[cfg(feature = "extension-api")]
+use crate::front::extension_api::{self, ExtensionApiConfig};
 use crate::front::listener::{ListenerConfig, ListenerType};
+#[cfg(feature = "policy-engine")]
+use crate::front::policy_engine::{PolicyEngine, PolicyEngineConfig};
 use crate::front::{
-    domain_socket::DomainSocketListenerBuilder, front_end::FrontEndHandler,
-    front_end::FrontEndHandlerBuilder, listener::Listen,
-};
-use crate::key_info_managers::on_disk_manager::{
-    OnDiskKeyInfoManagerBuilder, DEFAULT_MAPPINGS_PATH,
+    front_end::FrontEndHandler,
+    front_end::FrontEndHandlerBuilder,
+    listener::Listen,
+    response_padding::{ResponsePadding, ResponsePaddingConfig},
 };
+use crate::key_info_managers::key_info_store::KeyInfoStore;
 use crate::key_info_managers::{KeyInfoManagerConfig, KeyInfoManagerType, ManageKeyInfo};
-use crate::providers::{core_provider::CoreProviderBuilder, Provide, ProviderConfig};
-use log::{error, warn, LevelFilter};
+use crate::providers::{
+    core_provider::CoreProviderBuilder, provider_id_from_type, Provide, ProviderConfig,
+};
+#[cfg(feature = "acme-client")]
+use crate::utils::acme::AcmeConfig;
+#[cfg(feature = "est-client")]
+use crate::utils::est::EstConfig;
+use derivative::Derivative;
+use log::{error, info, warn, LevelFilter};
 use parsec_interface::operations_protobuf::ProtobufConverter;
 use parsec_interface::requests::AuthType;
-use parsec_interface::requests::{BodyType, ProviderID};
+use parsec_interface::requests::{BodyType, Opcode, ProviderID};
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{Error, ErrorKind, Result};
-use std::path::PathBuf;
 use std::sync::Arc;
-use std::sync::RwLock;
 use std::time::Duration;
 use threadpool::{Builder as ThreadPoolBuilder, ThreadPool};
 
+#[cfg(feature = "on-disk-manager")]
+use super::secrets;
+#[cfg(feature = "direct-authenticator")]
+use crate::authenticators::direct_authenticator::DirectAuthenticator;
+#[cfg(feature = "unix-peer-credentials-authenticator")]
+use crate::authenticators::unix_peer_credentials_authenticator::UnixPeerCredentialsAuthenticator;
+#[cfg(feature = "signing-log")]
+use crate::back::signing_log::{SigningLog, SigningLogConfig};
+#[cfg(feature = "unix-socket-listener")]
+use crate::front::domain_socket::DomainSocketListenerBuilder;
+#[cfg(feature = "tls-listener")]
+use crate::front::tls_listener::TlsListenerBuilder;
+#[cfg(feature = "vsock-listener")]
+use crate::front::vsock_listener::VsockListenerBuilder;
+#[cfg(feature = "memory-manager")]
+use crate::key_info_managers::memory_manager::MemoryKeyInfoManager;
+#[cfg(feature = "on-disk-manager")]
+use crate::key_info_managers::on_disk_manager::{
+    IntegrityTag, OnDiskKeyInfoManagerBuilder, DEFAULT_MAPPINGS_PATH,
+};
+#[cfg(feature = "sqlite-manager")]
+use crate::key_info_managers::sqlite_manager::{
+    SqliteKeyInfoManagerBuilder, DEFAULT_DATABASE_PATH,
+};
 #[cfg(feature = "mbed-crypto-provider")]
 use crate::providers::mbed_provider::MbedProviderBuilder;
 #[cfg(feature = "pkcs11-provider")]
 use crate::providers::pkcs11_provider::Pkcs11ProviderBuilder;
+#[cfg(feature = "remote-provider")]
+use crate::providers::remote_provider::RemoteProviderBuilder;
 #[cfg(feature = "tpm-provider")]
 use crate::providers::tpm_provider::TpmProviderBuilder;
 #[cfg(any(
-    feature = "mbed-crypto-provider",
-    feature = "pkcs11-provider",
-    feature = "tpm-provider"
+    feature = "on-disk-manager",
+    feature = "sqlite-manager",
+    feature = "remote-provider"
 ))]
-use log::info;
+use std::path::PathBuf;
+#[cfg(feature = "on-disk-manager")]
+use zeroize::Zeroizing;
 
 const WIRE_PROTOCOL_VERSION_MINOR: u8 = 0;
 const WIRE_PROTOCOL_VERSION_MAJOR: u8 = 1;
@@ -52,17 +135,32 @@ const WIRE_PROTOCOL_VERSION_MAJOR: u8 = 1;
 /// Default value for the limit on the request body size (in bytes) - equal to 1MB
 const DEFAULT_BODY_LEN_LIMIT: usize = 1 << 19;
 
-type KeyInfoManager = Arc<RwLock<dyn ManageKeyInfo + Send + Sync>>;
+type KeyInfoManager = Arc<KeyInfoStore>;
 type Provider = Box<dyn Provide + Send + Sync>;
+type Authenticator = Box<dyn Authenticate + Send + Sync>;
 
-#[derive(Copy, Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug)]
 pub struct CoreSettings {
     pub thread_pool_size: Option<usize>,
+    pub thread_pool_cpu_list: Option<String>,
+    pub thread_pool_irq_affinity: Option<u32>,
+    pub thread_pool_irq_numa_local: Option<bool>,
     pub idle_listener_sleep_duration: Option<u64>,
     pub log_level: Option<LevelFilter>,
     pub log_timestamp: Option<bool>,
     pub body_len_limit: Option<usize>,
     pub log_error_details: Option<bool>,
+    pub process_bound_apps: Option<Vec<String>>,
+    pub app_kek_provider: Option<String>,
+    pub denied_opcodes: Option<Vec<String>>,
+    pub max_key_name_len: Option<usize>,
+    pub max_app_name_len: Option<usize>,
+    pub key_unlock_time_to_live: Option<u64>,
+    pub admins: Option<Vec<String>>,
+    pub upgrade_executable: Option<String>,
+    pub shutdown_timeout: Option<u64>,
+    pub key_store_compaction_interval: Option<u64>,
+    pub session_correlation_ids: Option<bool>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -71,6 +169,52 @@ pub struct ServiceConfig {
     pub listener: ListenerConfig,
     pub key_manager: Option<Vec<KeyInfoManagerConfig>>,
     pub provider: Option<Vec<ProviderConfig>>,
+    pub app_group: Option<Vec<AppGroupConfig>>,
+    pub key_creation_rule: Option<Vec<KeyCreationRule>>,
+    pub key_size_rule: Option<Vec<KeySizeRule>>,
+    pub key_naming_rule: Option<Vec<KeyNamingRule>>,
+    pub key_slots: Option<Vec<KeySlotsConfig>>,
+    pub key_activation: Option<Vec<KeyActivationConfig>>,
+    pub memory_limits: Option<Vec<MemoryLimitsConfig>>,
+    pub fault_injection: Option<Vec<FaultInjectionConfig>>,
+    pub operation_deadlines: Option<Vec<OperationDeadlinesConfig>>,
+    pub key_import: Option<KeyImportConfig>,
+    #[cfg(feature = "device-identity")]
+    pub device_identity: Option<DeviceIdentityConfig>,
+    #[cfg(feature = "signing-log")]
+    pub signing_log: Option<SigningLogConfig>,
+    #[cfg(feature = "audit-log")]
+    pub audit: Option<AuditConfig>,
+    #[cfg(feature = "event-hooks")]
+    pub event_hook: Option<Vec<EventHookConfig>>,
+    #[cfg(feature = "event-hooks")]
+    pub key_publisher: Option<Vec<KeyPublisherConfig>>,
+    #[cfg(feature = "policy-engine")]
+    pub policy_engine: Option<PolicyEngineConfig>,
+    pub response_padding: Option<ResponsePaddingConfig>,
+    #[cfg(feature = "self-check")]
+    pub self_check: Option<SelfCheckConfig>,
+    #[cfg(feature = "on-disk-manager")]
+    pub storage_snapshot: Option<StorageSnapshotConfig>,
+    pub shadow: Option<Vec<ShadowConfig>>,
+    pub canary_key: Option<Vec<CanaryKeyConfig>>,
+    #[cfg(feature = "dead-letters")]
+    pub dead_letters: Option<DeadLettersConfig>,
+    #[cfg(feature = "policy-bundle")]
+    pub policy_bundle: Option<PolicyBundleConfig>,
+    pub authenticator: Option<Vec<AuthenticatorConfig>>,
+    #[cfg(feature = "jwt-svid-authenticator")]
+    pub jwt_svid: Option<JwtSvidConfig>,
+    #[cfg(feature = "delegation-tokens")]
+    pub delegation_tokens: Option<DelegationTokensConfig>,
+    #[cfg(feature = "admin-api")]
+    pub admin_api: Option<AdminApiConfig>,
+    #[cfg(feature = "extension-api")]
+    pub extension_api: Option<ExtensionApiConfig>,
+    #[cfg(feature = "acme-client")]
+    pub acme: Option<Vec<AcmeConfig>>,
+    #[cfg(feature = "est-client")]
+    pub est: Option<Vec<EstConfig>>,
 }
 
 /// Service component builder and assembler
@@ -82,6 +226,59 @@ pub struct ServiceConfig {
 #[derive(Copy, Clone, Debug)]
 pub struct ServiceBuilder;
 
+/// Key Info Managers of the service, kept across the reloads of its configuration
+///
+/// The service is built again when its configuration is reloaded, but a Key Info Manager whose
+/// configuration did not change is given to the new providers as it is instead of being created
+/// again from its storage. The managers removed from the configuration, or whose configuration
+/// changed, are closed once the providers using them are dropped.
+#[derive(Derivative, Default)]
+#[derivative(Debug)]
+pub struct KeyInfoManagers {
+    #[derivative(Debug = "ignore")]
+    managers: HashMap<String, (KeyInfoManagerConfig, KeyInfoManager)>,
+}
+
+impl KeyInfoManagers {
+    // Builds the managers of the configuration, reusing the current ones which did not change.
+    fn update(
+        &mut self,
+        configs: &[KeyInfoManagerConfig],
+    ) -> Result<HashMap<String, KeyInfoManager>> {
+        let mut managers = HashMap::new();
+        for config in configs {
+            let manager = match self.managers.remove(&config.name) {
+                Some((current_config, manager)) if current_config == *config => {
+                    info!("Keeping the \"{}\" Key Info Manager.", config.name);
+                    manager
+                }
+                _ => get_key_info_manager(config)?,
+            };
+            let _ = managers.insert(config.name.clone(), (config.clone(), manager));
+        }
+        self.managers = managers;
+
+        Ok(self
+            .managers
+            .iter()
+            .map(|(name, (_, manager))| (name.clone(), manager.clone()))
+            .collect())
+    }
+}
+
+/// Components of the service assembled by the program embedding it, added to the ones of the
+/// configuration, see `EmbeddedServiceBuilder`
+#[derive(Derivative, Default)]
+#[derivative(Debug)]
+pub(super) struct ServiceComponents {
+    #[derivative(Debug = "ignore")]
+    pub(super) key_info_managers: Vec<(String, KeyInfoManager)>,
+    #[derivative(Debug = "ignore")]
+    pub(super) providers: Vec<Provider>,
+    #[derivative(Debug = "ignore")]
+    pub(super) authenticators: Vec<(AuthType, Authenticator)>,
+}
+
 impl ServiceBuilder {
     /// Evaluate the provided configuration and assemble a service based on it. If the configuration contains
     /// any errors or inconsistencies, an `Err` is returned.
@@ -90,35 +287,214 @@ impl ServiceBuilder {
     /// * if any of the fields specified in the configuration are inconsistent (e.g. key info manager with name 'X'
     /// requested for a certain provider does not exist) or if required fields are missing, an error of kind
     /// `InvalidData` is returned with a string describing the cause more accurately.
-    pub fn build_service(config: &ServiceConfig) -> Result<FrontEndHandler> {
+    ///
+    /// The Key Info Managers are taken from `key_info_managers` if their configuration did not
+    /// change since they were built, and created otherwise.
+    pub fn build_service(
+        config: &ServiceConfig,
+        key_info_managers: &mut KeyInfoManagers,
+    ) -> Result<FrontEndHandler> {
+        Self::build_service_with_components(config, key_info_managers, ServiceComponents::default())
+    }
+
+    // Assembles the service from the configuration and the components given programmatically.
+    pub(super) fn build_service_with_components(
+        config: &ServiceConfig,
+        key_info_managers: &mut KeyInfoManagers,
+        components: ServiceComponents,
+    ) -> Result<FrontEndHandler> {
         GlobalConfigBuilder::new()
             .with_log_error_details(config.core_settings.log_error_details.unwrap_or(false))
+            .with_session_correlation_ids(
+                config
+                    .core_settings
+                    .session_correlation_ids
+                    .unwrap_or(false),
+            )
             .build();
 
-        let key_info_managers =
-            build_key_info_managers(config.key_manager.as_ref().unwrap_or(&Vec::new()))?;
+        let mut key_info_managers =
+            key_info_managers.update(config.key_manager.as_ref().unwrap_or(&Vec::new()))?;
+        for (name, key_info_manager) in components.key_info_managers {
+            if key_info_managers
+                .insert(name.clone(), key_info_manager)
+                .is_some()
+            {
+                error!(
+                    "The Key Info Manager \"{}\" is declared more than once.",
+                    name
+                );
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "duplicate key info manager",
+                ));
+            }
+        }
 
-        let providers = build_providers(
+        let name_policy = NamePolicy::new(
+            config.core_settings.max_key_name_len,
+            config.core_settings.max_app_name_len,
+        );
+        for key_info_manager in key_info_managers.values() {
+            let _ = name_policy.check_stored_keys(key_info_manager);
+        }
+
+        let key_slots = build_key_slots(
+            config.key_slots.as_ref().unwrap_or(&Vec::new()),
+            config.provider.as_ref().unwrap_or(&Vec::new()),
+            &key_info_managers,
+        )?;
+        let key_activations = build_key_activations(
+            config.key_activation.as_ref().unwrap_or(&Vec::new()),
+            config.provider.as_ref().unwrap_or(&Vec::new()),
+            &key_info_managers,
+        )?;
+
+        let provider_key_info_managers = build_provider_key_info_managers(
+            config.provider.as_ref().unwrap_or(&Vec::new()),
+            &key_info_managers,
+        )?;
+
+        let key_info_stores = key_info_managers.values().cloned().collect();
+        let mut providers = build_providers(
             config.provider.as_ref().unwrap_or(&Vec::new()),
             key_info_managers,
         );
+        for provider in components.providers {
+            let provider_id = provider
+                .describe()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "error describing provider"))?
+                .0
+                .id;
+            if providers.insert(provider_id, provider).is_some() {
+                error!("The {} is declared more than once.", provider_id);
+                return Err(Error::new(ErrorKind::InvalidData, "duplicate provider"));
+            }
+        }
 
         if providers.is_empty() {
             error!("Parsec needs at least one provider to start. No valid provider could be created from the configuration.");
             return Err(Error::new(ErrorKind::InvalidData, "need one provider"));
         }
 
-        let backend_handlers = build_backend_handlers(providers)?;
+        let authenticators = build_authenticators(config, components.authenticators)?;
+
+        let key_bindings = Arc::new(KeyBindings::new(
+            config
+                .core_settings
+                .process_bound_apps
+                .clone()
+                .unwrap_or_default(),
+        ));
+
+        let key_creation_policy = Arc::new(KeyCreationPolicy::new(
+            config.app_group.as_ref().unwrap_or(&Vec::new()),
+            config.key_creation_rule.as_ref().unwrap_or(&Vec::new()),
+            config.key_size_rule.as_ref().unwrap_or(&Vec::new()),
+        )?);
+        let key_naming_policy = Arc::new(KeyNamingPolicy::new(
+            config.key_naming_rule.as_ref().unwrap_or(&Vec::new()),
+        )?);
 
-        let dispatcher = DispatcherBuilder::new()
-            .with_backends(backend_handlers)
-            .build()?;
+        let app_kek_provider = match &config.core_settings.app_kek_provider {
+            Some(provider_type) => Some(provider_id_from_type(provider_type).ok_or_else(|| {
+                format_error!(
+                    "Unknown provider type for the application KEKs",
+                    provider_type
+                );
+                Error::new(ErrorKind::InvalidData, "unknown provider type")
+            })?),
+            None => None,
+        };
+
+        let (denied_opcodes, denied_extensions) = build_denied_operations(
+            config
+                .core_settings
+                .denied_opcodes
+                .as_ref()
+                .unwrap_or(&Vec::new()),
+        )?;
+
+        let backend_handlers = build_backend_handlers(
+            providers,
+            &authenticators,
+            key_info_stores,
+            key_bindings,
+            key_creation_policy,
+            key_naming_policy,
+            key_slots,
+            key_activations,
+            provider_key_info_managers,
+            app_kek_provider,
+            denied_opcodes.clone(),
+            name_policy,
+            config
+                .core_settings
+                .key_unlock_time_to_live
+                .map(Duration::from_secs),
+            config,
+            build_canary_keys(config.canary_key.as_ref().unwrap_or(&Vec::new()))?,
+            build_memory_limits(config.memory_limits.as_ref().unwrap_or(&Vec::new()))?,
+            build_fault_injection(config.fault_injection.as_ref().unwrap_or(&Vec::new()))?,
+            build_operation_deadlines(config.operation_deadlines.as_ref().unwrap_or(&Vec::new()))?,
+        )?;
+
+        // The device identity is bootstrapped before any client can connect.
+        #[cfg(feature = "device-identity")]
+        let device_identity = match &config.device_identity {
+            Some(device_identity_config) => Some(device_identity::bootstrap(
+                device_identity_config,
+                &backend_handlers,
+            )?),
+            None => None,
+        };
 
-        let direct_authenticator = Box::from(DirectAuthenticator {});
+        let mut dispatcher_builder = DispatcherBuilder::new().with_backends(backend_handlers);
+        #[cfg(feature = "device-identity")]
+        {
+            if let Some(device_identity) = device_identity {
+                dispatcher_builder = dispatcher_builder.with_device_identity(device_identity);
+            }
+        }
+        for shadow_config in config.shadow.as_ref().unwrap_or(&Vec::new()) {
+            let (primary, shadow) = build_shadow(shadow_config)?;
+            dispatcher_builder = dispatcher_builder.with_shadow(primary, shadow);
+        }
+        #[cfg(feature = "delegation-tokens")]
+        {
+            if let Some(delegation_tokens_config) = &config.delegation_tokens {
+                dispatcher_builder = dispatcher_builder
+                    .with_delegation_tokens(build_delegation_tokens(delegation_tokens_config)?);
+            }
+        }
+        let dispatcher = dispatcher_builder.build()?;
 
-        Ok(FrontEndHandlerBuilder::new()
+        let mut front_end_handler_builder = FrontEndHandlerBuilder::new();
+        for (auth_type, authenticator) in authenticators {
+            front_end_handler_builder =
+                front_end_handler_builder.with_authenticator(auth_type, authenticator);
+        }
+        #[cfg(feature = "policy-engine")]
+        {
+            if let Some(policy_engine_config) = &config.policy_engine {
+                info!(
+                    "Delegating the authorization decisions to the policy engine at {}.",
+                    policy_engine_config.socket_path
+                );
+                front_end_handler_builder = front_end_handler_builder
+                    .with_policy_engine(PolicyEngine::new(policy_engine_config));
+            }
+        }
+        if let Some(response_padding_config) = &config.response_padding {
+            front_end_handler_builder = front_end_handler_builder
+                .with_response_padding(build_response_padding(response_padding_config)?);
+        }
+
+        Ok(front_end_handler_builder
             .with_dispatcher(dispatcher)
-            .with_authenticator(AuthType::Direct, direct_authenticator)
+            .with_denied_opcodes(denied_opcodes)
+            .with_denied_extensions(denied_extensions)
+            .with_name_policy(name_policy)
             .with_body_len_limit(
                 config
                     .core_settings
@@ -130,13 +506,11 @@ impl ServiceBuilder {
 
     /// Construct the service IPC front component and return ownership to it.
     pub fn start_listener(config: ListenerConfig) -> Result<Box<dyn Listen>> {
-        let listener = match config.listener_type {
-            ListenerType::DomainSocket => DomainSocketListenerBuilder::new()
-                .with_timeout(Duration::from_millis(config.timeout))
-                .build(),
-        }?;
-
-        Ok(Box::new(listener))
+        match config.listener_type {
+            ListenerType::DomainSocket => build_domain_socket_listener(config),
+            ListenerType::Tls => build_tls_listener(config),
+            ListenerType::Vsock => build_vsock_listener(config),
+        }
     }
 
     /// Construct the thread pool that will be used to process all service requests.
@@ -147,15 +521,127 @@ impl ServiceBuilder {
         }
         threadpool_builder.build()
     }
+
+    /// Find the set of CPUs the worker threads should be pinned to, if any.
+    ///
+    /// An explicit CPU list takes precedence over the affinity of an IRQ. The CPUs derived from an
+    /// IRQ are all the ones of its NUMA node if `thread_pool_irq_numa_local` is set, the ones
+    /// handling it otherwise or if the machine is not NUMA.
+    ///
+    /// # Errors
+    /// * if the CPU list is malformed or the IRQ affinity or NUMA node can not be read, an error of
+    /// kind `InvalidData` is returned.
+    pub fn build_worker_cpu_set(core_settings: &CoreSettings) -> Result<Option<Vec<usize>>> {
+        if let Some(cpu_list) = &core_settings.thread_pool_cpu_list {
+            return Ok(Some(cpu_affinity::parse_cpu_list(cpu_list).or_else(
+                |e| {
+                    format_error!("Failed to parse the worker threads CPU list", e);
+                    Err(Error::new(ErrorKind::InvalidData, "invalid CPU list"))
+                },
+            )?));
+        }
+        let irq = match core_settings.thread_pool_irq_affinity {
+            Some(irq) => irq,
+            None => return Ok(None),
+        };
+
+        let numa_node = if core_settings.thread_pool_irq_numa_local.unwrap_or(false) {
+            cpu_affinity::irq_numa_node(irq).or_else(|e| {
+                format_error!(&format!("Failed to read the NUMA node of IRQ {}", irq), e);
+                Err(Error::new(ErrorKind::InvalidData, "invalid IRQ NUMA node"))
+            })?
+        } else {
+            None
+        };
+        match numa_node {
+            Some(numa_node) => Ok(Some(cpu_affinity::numa_node_cpu_list(numa_node).or_else(
+                |e| {
+                    format_error!(
+                        &format!("Failed to read the CPUs of NUMA node {}", numa_node),
+                        e
+                    );
+                    Err(Error::new(ErrorKind::InvalidData, "invalid NUMA node"))
+                },
+            )?)),
+            None => {
+                if core_settings.thread_pool_irq_numa_local.unwrap_or(false) {
+                    info!("The machine is not NUMA, the worker threads are pinned to the CPUs handling IRQ {}.", irq);
+                }
+                Ok(Some(cpu_affinity::irq_cpu_list(irq).or_else(|e| {
+                    format_error!(
+                        &format!("Failed to read the CPU affinity of IRQ {}", irq),
+                        e
+                    );
+                    Err(Error::new(ErrorKind::InvalidData, "invalid IRQ affinity"))
+                })?))
+            }
+        }
+    }
 }
 
+// Each argument is a separate part of the configuration applied to all the back end handlers. The
+// configuration itself is given for the parts only compiled with a feature.
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(
+    any(not(feature = "signing-log"), not(feature = "key-counters")),
+    allow(unused_variables)
+)]
 fn build_backend_handlers(
     mut providers: HashMap<ProviderID, Provider>,
+    authenticators: &[(AuthType, ChainedAuthenticator)],
+    key_info_stores: Vec<KeyInfoManager>,
+    key_bindings: Arc<KeyBindings>,
+    key_creation_policy: Arc<KeyCreationPolicy>,
+    key_naming_policy: Arc<KeyNamingPolicy>,
+    mut key_slots: HashMap<ProviderID, KeySlots>,
+    mut key_activations: HashMap<ProviderID, KeyActivation>,
+    provider_key_info_managers: HashMap<ProviderID, KeyInfoManager>,
+    app_kek_provider: Option<ProviderID>,
+    denied_opcodes: HashSet<Opcode>,
+    name_policy: NamePolicy,
+    unlock_time_to_live: Option<Duration>,
+    config: &ServiceConfig,
+    mut canary_keys: HashMap<ProviderID, CanaryKeys>,
+    mut memory_limits: HashMap<ProviderID, MemoryLimits>,
+    mut fault_injection: HashMap<ProviderID, FaultInjection>,
+    mut operation_deadlines: HashMap<ProviderID, OperationDeadlines>,
 ) -> Result<HashMap<ProviderID, BackEndHandler>> {
     let mut map = HashMap::new();
 
+    #[cfg(feature = "signing-log")]
+    let signing_log = match &config.signing_log {
+        Some(signing_log_config) => Some(Arc::new(SigningLog::new(signing_log_config)?)),
+        None => None,
+    };
+
+    #[cfg(feature = "audit-log")]
+    let audit_log = match &config.audit {
+        Some(audit_config) => Some(Arc::new(AuditLog::new(audit_config)?)),
+        None => None,
+    };
+
+    #[cfg(feature = "dead-letters")]
+    let dead_letters = match &config.dead_letters {
+        Some(dead_letters_config) => Some(Arc::new(DeadLetters::new(dead_letters_config)?)),
+        None => None,
+    };
+
     let mut core_provider_builder = CoreProviderBuilder::new()?
-        .with_wire_protocol_version(WIRE_PROTOCOL_VERSION_MINOR, WIRE_PROTOCOL_VERSION_MAJOR);
+        .with_wire_protocol_version(WIRE_PROTOCOL_VERSION_MINOR, WIRE_PROTOCOL_VERSION_MAJOR)
+        .with_denied_opcodes(denied_opcodes);
+    for key_info_store in key_info_stores {
+        core_provider_builder = core_provider_builder.with_key_info_store(key_info_store);
+    }
+
+    for (_auth_type, authenticator) in authenticators {
+        let authenticator_info = authenticator.describe().or_else(|_| {
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                "error describing authenticator",
+            ))
+        })?;
+        core_provider_builder = core_provider_builder.with_authenticator_info(authenticator_info);
+    }
 
     for (provider_id, provider) in providers.drain() {
         let (info, opcodes) = provider.describe().or_else(|_| {
@@ -166,13 +652,96 @@ fn build_backend_handlers(
         })?;
         core_provider_builder = core_provider_builder.with_provider_details(info, opcodes);
 
-        let backend_handler = BackEndHandlerBuilder::new()
+        let mut backend_handler_builder = BackEndHandlerBuilder::new()
             .with_provider(provider)
             .with_converter(Box::from(ProtobufConverter {}))
             .with_provider_id(provider_id)
             .with_content_type(BodyType::Protobuf)
             .with_accept_type(BodyType::Protobuf)
-            .build()?;
+            .with_key_bindings(key_bindings.clone())
+            .with_key_creation_policy(key_creation_policy.clone())
+            .with_key_naming_policy(key_naming_policy.clone())
+            .with_name_policy(name_policy);
+        #[cfg(feature = "event-hooks")]
+        {
+            backend_handler_builder = backend_handler_builder
+                .with_event_hooks(EventHooks::new(
+                    config.event_hook.as_ref().unwrap_or(&Vec::new()),
+                )?)
+                .with_key_publisher(KeyPublisher::new(
+                    config.key_publisher.as_ref().unwrap_or(&Vec::new()),
+                )?);
+        }
+        if let Some(key_slots) = key_slots.remove(&provider_id) {
+            backend_handler_builder = backend_handler_builder.with_key_slots(key_slots);
+        }
+        if let Some(key_activation) = key_activations.remove(&provider_id) {
+            backend_handler_builder = backend_handler_builder.with_key_activation(key_activation);
+        }
+        // The keys of all the providers storing them locally can have a counter.
+        #[cfg(feature = "key-counters")]
+        {
+            if let Some(key_info_manager) = provider_key_info_managers.get(&provider_id) {
+                backend_handler_builder = backend_handler_builder
+                    .with_key_counters(KeyCounters::new(key_info_manager.clone()));
+            }
+        }
+        // Their certificates are stored with them when the provider can not store them itself,
+        // and so are their labels.
+        if let Some(key_info_manager) = provider_key_info_managers.get(&provider_id) {
+            backend_handler_builder = backend_handler_builder
+                .with_key_certificates(KeyCertificates::new(key_info_manager.clone()))
+                .with_key_labels(KeyLabels::new(key_info_manager.clone()));
+        }
+        if let Some(unlock_time_to_live) = unlock_time_to_live {
+            backend_handler_builder =
+                backend_handler_builder.with_unlock_time_to_live(unlock_time_to_live);
+        }
+        if app_kek_provider == Some(provider_id) {
+            backend_handler_builder = backend_handler_builder.with_app_keks();
+        }
+        #[cfg(feature = "signing-log")]
+        {
+            if let Some(signing_log) = &signing_log {
+                backend_handler_builder =
+                    backend_handler_builder.with_signing_log(signing_log.clone());
+            }
+        }
+        if let Some(canary_keys) = canary_keys.remove(&provider_id) {
+            backend_handler_builder = backend_handler_builder.with_canary_keys(canary_keys);
+        }
+        #[cfg(feature = "dead-letters")]
+        {
+            if let Some(dead_letters) = &dead_letters {
+                backend_handler_builder =
+                    backend_handler_builder.with_dead_letters(dead_letters.clone());
+            }
+        }
+        #[cfg(feature = "audit-log")]
+        {
+            if let Some(audit_log) = &audit_log {
+                backend_handler_builder = backend_handler_builder.with_audit_log(audit_log.clone());
+            }
+        }
+        if let Some(memory_limits) = memory_limits.remove(&provider_id) {
+            backend_handler_builder = backend_handler_builder.with_memory_limits(memory_limits);
+        }
+        if let Some(fault_injection) = fault_injection.remove(&provider_id) {
+            backend_handler_builder = backend_handler_builder.with_fault_injection(fault_injection);
+        }
+        if let Some(deadlines) = operation_deadlines.remove(&provider_id) {
+            backend_handler_builder = backend_handler_builder.with_operation_deadlines(deadlines);
+        }
+        let max_concurrency = config
+            .provider
+            .iter()
+            .flatten()
+            .find(|provider_config| provider_config.provider_id() == Some(provider_id))
+            .and_then(ProviderConfig::max_concurrency);
+        if let Some(max_concurrency) = max_concurrency {
+            backend_handler_builder = backend_handler_builder.with_max_concurrency(max_concurrency);
+        }
+        let backend_handler = backend_handler_builder.build()?;
         let _ = map.insert(provider_id, backend_handler);
     }
 
@@ -189,30 +758,480 @@ fn build_backend_handlers(
     Ok(map)
 }
 
+fn build_memory_limits(
+    configs: &[MemoryLimitsConfig],
+) -> Result<HashMap<ProviderID, MemoryLimits>> {
+    let mut map = HashMap::new();
+    for config in configs {
+        let provider_id = provider_id_from_type(&config.provider_type).ok_or_else(|| {
+            format_error!(
+                "Unknown provider type in the memory limits configuration",
+                config.provider_type
+            );
+            Error::new(ErrorKind::InvalidData, "unknown provider type")
+        })?;
+        if map
+            .insert(provider_id, MemoryLimits::new(config)?)
+            .is_some()
+        {
+            error!(
+                "The memory limits of the {} provider are configured twice.",
+                config.provider_type
+            );
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "duplicate memory limits",
+            ));
+        }
+    }
+
+    Ok(map)
+}
+
+fn build_fault_injection(
+    configs: &[FaultInjectionConfig],
+) -> Result<HashMap<ProviderID, FaultInjection>> {
+    let mut map = HashMap::new();
+    for config in configs {
+        let provider_id = provider_id_from_type(&config.provider_type).ok_or_else(|| {
+            format_error!(
+                "Unknown provider type in the fault injection configuration",
+                config.provider_type
+            );
+            Error::new(ErrorKind::InvalidData, "unknown provider type")
+        })?;
+        let opcodes = match &config.opcodes {
+            Some(opcode_names) => {
+                let mut opcodes = HashSet::new();
+                for opcode_name in opcode_names {
+                    let opcode = opcode_from_name(opcode_name).ok_or_else(|| {
+                        format_error!("Unknown operation in the fault injection", opcode_name);
+                        Error::new(ErrorKind::InvalidData, "unknown operation")
+                    })?;
+                    let _ = opcodes.insert(opcode);
+                }
+                Some(opcodes)
+            }
+            None => None,
+        };
+        if map
+            .insert(provider_id, FaultInjection::new(config, opcodes)?)
+            .is_some()
+        {
+            error!(
+                "The fault injection of the {} provider is configured twice.",
+                config.provider_type
+            );
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "duplicate fault injection",
+            ));
+        }
+        warn!(
+            "Injecting faults in the operations of the {} provider, with the seed {}. This is only meant for soak tests.",
+            provider_id, config.seed
+        );
+    }
+
+    Ok(map)
+}
+
+fn build_operation_deadlines(
+    configs: &[OperationDeadlinesConfig],
+) -> Result<HashMap<ProviderID, OperationDeadlines>> {
+    let deadline = |deadline_ms: u64| {
+        if deadline_ms == 0 {
+            error!("The deadline of an operation must be at least one millisecond.");
+            return Err(Error::new(ErrorKind::InvalidData, "invalid deadline"));
+        }
+        Ok(Duration::from_millis(deadline_ms))
+    };
+    let mut map = HashMap::new();
+    for config in configs {
+        let provider_id = provider_id_from_type(&config.provider_type).ok_or_else(|| {
+            format_error!(
+                "Unknown provider type in the operation deadlines configuration",
+                config.provider_type
+            );
+            Error::new(ErrorKind::InvalidData, "unknown provider type")
+        })?;
+        let mut opcode_deadlines = HashMap::new();
+        for opcode_config in config.opcode.as_ref().unwrap_or(&Vec::new()) {
+            let opcode = opcode_from_name(&opcode_config.name).ok_or_else(|| {
+                format_error!(
+                    "Unknown operation in the operation deadlines",
+                    opcode_config.name
+                );
+                Error::new(ErrorKind::InvalidData, "unknown operation")
+            })?;
+            let _ = opcode_deadlines.insert(opcode, deadline(opcode_config.deadline_ms)?);
+        }
+        let default = config.deadline_ms.map(deadline).transpose()?;
+        if map
+            .insert(
+                provider_id,
+                OperationDeadlines::new(default, opcode_deadlines),
+            )
+            .is_some()
+        {
+            error!(
+                "The operation deadlines of the {} provider are configured twice.",
+                config.provider_type
+            );
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "duplicate operation deadlines",
+            ));
+        }
+    }
+
+    Ok(map)
+}
+
+fn build_shadow(config: &ShadowConfig) -> Result<(ProviderID, Shadow)> {
+    let provider_id = |provider_type: &str| {
+        provider_id_from_type(provider_type).ok_or_else(|| {
+            format_error!(
+                "Unknown provider type in the shadow configuration",
+                provider_type
+            );
+            Error::new(ErrorKind::InvalidData, "unknown provider type")
+        })
+    };
+    let primary = provider_id(config.primary.as_str())?;
+    let secondary = provider_id(config.secondary.as_str())?;
+    if primary == secondary {
+        error!("A provider can not shadow itself.");
+        return Err(Error::new(ErrorKind::InvalidData, "invalid shadow"));
+    }
+
+    let mut opcodes = HashSet::new();
+    for opcode_name in &config.opcodes {
+        let opcode = opcode_from_name(opcode_name)
+            .filter(|opcode| SHADOWABLE_OPCODES.contains(opcode))
+            .ok_or_else(|| {
+                format_error!("Operation which can not be shadowed", opcode_name);
+                Error::new(ErrorKind::InvalidData, "invalid shadowed operation")
+            })?;
+        let _ = opcodes.insert(opcode);
+    }
+    warn!(
+        "Shadowing {:?} of the {} provider on the {} provider.",
+        opcodes, primary, secondary
+    );
+
+    Ok((
+        primary,
+        Shadow::new(secondary, opcodes, config.compare_results.unwrap_or(false)),
+    ))
+}
+
+fn build_canary_keys(configs: &[CanaryKeyConfig]) -> Result<HashMap<ProviderID, CanaryKeys>> {
+    let mut keys: HashMap<ProviderID, HashSet<(String, String)>> = HashMap::new();
+    for config in configs {
+        let provider_id = provider_id_from_type(&config.provider_type).ok_or_else(|| {
+            format_error!(
+                "Unknown provider type in canary key configuration",
+                config.provider_type
+            );
+            Error::new(ErrorKind::InvalidData, "unknown provider type")
+        })?;
+        let _ = keys
+            .entry(provider_id)
+            .or_default()
+            .insert((config.app_name.clone(), config.key_name.clone()));
+    }
+
+    Ok(keys
+        .into_iter()
+        .map(|(provider_id, keys)| (provider_id, CanaryKeys::new(keys)))
+        .collect())
+}
+
+#[cfg(feature = "delegation-tokens")]
+fn build_delegation_tokens(config: &DelegationTokensConfig) -> Result<DelegationTokens> {
+    let max_validity = config.max_validity.unwrap_or(DEFAULT_MAX_VALIDITY);
+    if max_validity == 0 {
+        error!("The longest validity of the delegation tokens must be at least one second.");
+        return Err(Error::new(ErrorKind::InvalidData, "invalid max_validity"));
+    }
+    let delegation_tokens = DelegationTokens::new(Duration::from_secs(max_validity))
+        .map_err(|status| Error::new(ErrorKind::Other, status.to_string()))?;
+    info!(
+        "Keys can be delegated for at most {} seconds.",
+        max_validity
+    );
+
+    Ok(delegation_tokens)
+}
+
+fn build_response_padding(config: &ResponsePaddingConfig) -> Result<ResponsePadding> {
+    let mut opcode_bucket_sizes = HashMap::new();
+    for opcode_config in config.opcode.as_ref().unwrap_or(&Vec::new()) {
+        let opcode = opcode_from_name(&opcode_config.name).ok_or_else(|| {
+            format_error!(
+                "Unknown operation in the response padding",
+                opcode_config.name
+            );
+            Error::new(ErrorKind::InvalidData, "unknown operation")
+        })?;
+        let _ = opcode_bucket_sizes.insert(opcode, opcode_config.bucket_sizes.clone());
+    }
+    info!("Padding the response bodies to size buckets.");
+
+    ResponsePadding::new(config.bucket_sizes.clone(), opcode_bucket_sizes)
+}
+
+// Splits the denied operations in the opcodes of the wire protocol and the names of the operations
+// of the extension API.
+fn build_denied_operations(
+    operation_names: &[String],
+) -> Result<(HashSet<Opcode>, HashSet<String>)> {
+    let mut denied_opcodes = HashSet::new();
+    let mut denied_extensions = HashSet::new();
+    for operation_name in operation_names {
+        if let Some(opcode) = opcode_from_name(operation_name) {
+            let _ = denied_opcodes.insert(opcode);
+        } else if is_extension_operation(operation_name) {
+            let _ = denied_extensions.insert(operation_name.clone());
+        } else {
+            format_error!("Unknown operation in the denied opcodes", operation_name);
+            return Err(Error::new(ErrorKind::InvalidData, "unknown operation"));
+        }
+    }
+    if !denied_opcodes.is_empty() || !denied_extensions.is_empty() {
+        info!(
+            "Operations denied by configuration: {:?} {:?}",
+            denied_opcodes, denied_extensions
+        );
+    }
+
+    Ok((denied_opcodes, denied_extensions))
+}
+
+#[cfg(feature = "extension-api")]
+fn is_extension_operation(operation_name: &str) -> bool {
+    extension_api::extension_operations().contains(&operation_name)
+}
+
+#[cfg(not(feature = "extension-api"))]
+fn is_extension_operation(_operation_name: &str) -> bool {
+    false
+}
+
+/// Gets the opcode of the operation with the given name, as named in the configuration.
+pub fn opcode_from_name(opcode_name: &str) -> Option<Opcode> {
+    match opcode_name {
+        "Ping" => Some(Opcode::Ping),
+        "ListProviders" => Some(Opcode::ListProviders),
+        "ListOpcodes" => Some(Opcode::ListOpcodes),
+        "ListAuthenticators" => Some(Opcode::ListAuthenticators),
+        "ListKeys" => Some(Opcode::ListKeys),
+        "ListClients" => Some(Opcode::ListClients),
+        "DeleteClient" => Some(Opcode::DeleteClient),
+        "PsaGenerateKey" => Some(Opcode::PsaGenerateKey),
+        "PsaImportKey" => Some(Opcode::PsaImportKey),
+        "PsaExportPublicKey" => Some(Opcode::PsaExportPublicKey),
+        "PsaDestroyKey" => Some(Opcode::PsaDestroyKey),
+        "PsaSignHash" => Some(Opcode::PsaSignHash),
+        "PsaVerifyHash" => Some(Opcode::PsaVerifyHash),
+        "PsaAsymmetricEncrypt" => Some(Opcode::PsaAsymmetricEncrypt),
+        "PsaAsymmetricDecrypt" => Some(Opcode::PsaAsymmetricDecrypt),
+        "PsaAeadEncrypt" => Some(Opcode::PsaAeadEncrypt),
+        "PsaAeadDecrypt" => Some(Opcode::PsaAeadDecrypt),
+        "PsaRawKeyAgreement" => Some(Opcode::PsaRawKeyAgreement),
+        "PsaHashCompute" => Some(Opcode::PsaHashCompute),
+        "PsaHashCompare" => Some(Opcode::PsaHashCompare),
+        _ => None,
+    }
+}
+
+fn build_key_slots(
+    configs: &[KeySlotsConfig],
+    provider_configs: &[ProviderConfig],
+    key_info_managers: &HashMap<String, KeyInfoManager>,
+) -> Result<HashMap<ProviderID, KeySlots>> {
+    let mut map = HashMap::new();
+    for config in configs {
+        let provider_id = provider_id_from_type(&config.provider_type).ok_or_else(|| {
+            format_error!(
+                "Unknown provider type in key slots configuration",
+                config.provider_type
+            );
+            Error::new(ErrorKind::InvalidData, "unknown provider type")
+        })?;
+        let provider_config = match provider_configs
+            .iter()
+            .find(|provider_config| provider_config.provider_id() == Some(provider_id))
+        {
+            Some(provider_config) => provider_config,
+            None => {
+                warn!(
+                    "Key slots configured for provider {} which is not configured, ignoring them.",
+                    provider_id
+                );
+                continue;
+            }
+        };
+        let key_info_manager = match provider_config.key_info_manager() {
+            Some(key_info_manager) => key_info_managers
+                .get(key_info_manager)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "key info manager not found"))?,
+            None => {
+                warn!(
+                    "Key slots configured for provider {} which does not store keys locally, ignoring them.",
+                    provider_id
+                );
+                continue;
+            }
+        };
+
+        let key_slots = KeySlots::new(provider_id, config, key_info_manager.clone())?;
+        match key_slots.usage() {
+            Ok(usage) => info!(
+                "Key slots of provider {}: {} used and {} reserved out of {}.",
+                provider_id, usage.used, usage.reserved, usage.capacity
+            ),
+            Err(e) => format_error!("Failed to get the key slots usage", e),
+        }
+        let _ = map.insert(provider_id, key_slots);
+    }
+
+    Ok(map)
+}
+
+// The state of the keys is checked for all the providers storing them locally, the configurations
+// only selecting the keys created pre-active.
+fn build_key_activations(
+    configs: &[KeyActivationConfig],
+    provider_configs: &[ProviderConfig],
+    key_info_managers: &HashMap<String, KeyInfoManager>,
+) -> Result<HashMap<ProviderID, KeyActivation>> {
+    let mut provider_key_activations = HashMap::new();
+    for config in configs {
+        let provider_id = provider_id_from_type(&config.provider_type).ok_or_else(|| {
+            format_error!(
+                "Unknown provider type in key activation configuration",
+                config.provider_type
+            );
+            Error::new(ErrorKind::InvalidData, "unknown provider type")
+        })?;
+        if provider_key_activations
+            .insert(provider_id, config)
+            .is_some()
+        {
+            error!(
+                "Key activation configured twice for provider {}.",
+                provider_id
+            );
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "duplicate key activation configuration",
+            ));
+        }
+    }
+
+    let mut map = HashMap::new();
+    for provider_config in provider_configs {
+        let provider_id = match provider_config.provider_id() {
+            Some(provider_id) => provider_id,
+            None => continue,
+        };
+        let config = provider_key_activations.remove(&provider_id);
+        let key_info_manager = match provider_config.key_info_manager() {
+            Some(key_info_manager) => key_info_managers
+                .get(key_info_manager)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "key info manager not found"))?,
+            None => {
+                if config.is_some() {
+                    warn!(
+                        "Key activation configured for provider {} which does not store keys locally, ignoring it.",
+                        provider_id
+                    );
+                }
+                continue;
+            }
+        };
+        if config.is_some() {
+            info!(
+                "New keys of provider {} can be created pre-active.",
+                provider_id
+            );
+        }
+        let _ = map.insert(
+            provider_id,
+            KeyActivation::new(config, key_info_manager.clone()),
+        );
+    }
+    for provider_id in provider_key_activations.keys() {
+        warn!(
+            "Key activation configured for provider {} which is not configured, ignoring it.",
+            provider_id
+        );
+    }
+
+    Ok(map)
+}
+
+// Gets the Key Info Manager of each provider storing its keys locally.
+fn build_provider_key_info_managers(
+    provider_configs: &[ProviderConfig],
+    key_info_managers: &HashMap<String, KeyInfoManager>,
+) -> Result<HashMap<ProviderID, KeyInfoManager>> {
+    let mut map = HashMap::new();
+    for provider_config in provider_configs {
+        let (provider_id, key_info_manager) = match (
+            provider_config.provider_id(),
+            provider_config.key_info_manager(),
+        ) {
+            (Some(provider_id), Some(key_info_manager)) => (provider_id, key_info_manager),
+            _ => continue,
+        };
+        let key_info_manager = key_info_managers
+            .get(key_info_manager)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "key info manager not found"))?;
+        let _ = map.insert(provider_id, key_info_manager.clone());
+    }
+
+    Ok(map)
+}
+
 fn build_providers(
     configs: &[ProviderConfig],
     key_info_managers: HashMap<String, KeyInfoManager>,
 ) -> HashMap<ProviderID, Provider> {
     let mut map = HashMap::new();
     for config in configs {
-        let provider_id = config.provider_id();
+        let provider_id = match config.provider_id() {
+            Some(provider_id) => provider_id,
+            None => {
+                error!("Unknown type of the remote provider, ignoring it and continuing...");
+                continue;
+            }
+        };
         if map.contains_key(&provider_id) {
             warn!("Parsec currently only supports one instance of each provider type. Ignoring {} and continuing...", provider_id);
             continue;
         }
 
-        let key_info_manager = match key_info_managers.get(config.key_info_manager()) {
-            Some(key_info_manager) => key_info_manager,
-            None => {
-                format_error!(
-                    "Key info manager with specified name was not found",
-                    config.key_info_manager()
-                );
-                continue;
-            }
+        let key_info_manager = match config.key_info_manager() {
+            Some(name) => match key_info_managers.get(name) {
+                Some(key_info_manager) => Some(key_info_manager.clone()),
+                None => {
+                    format_error!("Key info manager with specified name was not found", name);
+                    continue;
+                }
+            },
+            None => None,
         };
         // The safety is checked by the fact that only one instance per provider type is enforced.
-        let provider = match unsafe { get_provider(config, key_info_manager.clone()) } {
+        let provider = match config.wait_for() {
+            Some(wait_for) => dependency_probe::build_with_retries(wait_for, || unsafe {
+                get_provider(config, key_info_manager.clone())
+            }),
+            None => unsafe { get_provider(config, key_info_manager) },
+        };
+        let provider = match provider {
             Ok(provider) => provider,
             Err(e) => {
                 format_error!(
@@ -234,22 +1253,40 @@ fn build_providers(
     not(all(
         feature = "mbed-crypto-provider",
         feature = "pkcs11-provider",
-        feature = "tpm-provider"
+        feature = "tpm-provider",
+        feature = "remote-provider"
     )),
     allow(unused_variables),
     allow(clippy::match_single_binding)
 )]
 unsafe fn get_provider(
     config: &ProviderConfig,
-    key_info_manager: KeyInfoManager,
+    key_info_manager: Option<KeyInfoManager>,
 ) -> Result<Provider> {
+    // Providers storing keys locally always have a key info manager configured.
+    #[cfg(any(
+        feature = "mbed-crypto-provider",
+        feature = "pkcs11-provider",
+        feature = "tpm-provider"
+    ))]
+    let local_key_info_manager = || {
+        key_info_manager
+            .clone()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing key info manager"))
+    };
     match config {
         #[cfg(feature = "mbed-crypto-provider")]
-        ProviderConfig::MbedCrypto { .. } => {
+        ProviderConfig::MbedCrypto {
+            key_id_range,
+            purge_orphaned_mappings,
+            ..
+        } => {
             info!("Creating a Mbed Crypto Provider.");
             Ok(Box::from(
                 MbedProviderBuilder::new()
-                    .with_key_info_store(key_info_manager)
+                    .with_key_info_store(local_key_info_manager()?)
+                    .with_key_id_range(*key_id_range)
+                    .with_purge_orphaned_mappings(*purge_orphaned_mappings)
                     .build()?,
             ))
         }
@@ -258,15 +1295,28 @@ unsafe fn get_provider(
             library_path,
             slot_number,
             user_pin,
+            library_sha256,
+            key_id_range,
+            purge_orphaned_mappings,
+            offline_verify,
+            public_key_cache_capacity,
+            public_key_cache_eviction,
+            max_concurrent_operations_per_key,
             ..
         } => {
             info!("Creating a PKCS 11 Provider.");
             Ok(Box::from(
                 Pkcs11ProviderBuilder::new()
-                    .with_key_info_store(key_info_manager)
+                    .with_key_info_store(local_key_info_manager()?)
                     .with_pkcs11_library_path(library_path.clone())
                     .with_slot_number(*slot_number)
                     .with_user_pin(user_pin.clone())
+                    .with_library_sha256(library_sha256.clone())
+                    .with_key_id_range(*key_id_range)
+                    .with_purge_orphaned_mappings(*purge_orphaned_mappings)
+                    .with_offline_verify(*offline_verify)
+                    .with_public_key_cache(*public_key_cache_capacity, *public_key_cache_eviction)
+                    .with_max_concurrent_operations_per_key(*max_concurrent_operations_per_key)
                     .build()?,
             ))
         }
@@ -274,21 +1324,48 @@ unsafe fn get_provider(
         ProviderConfig::Tpm {
             tcti,
             owner_hierarchy_auth,
+            hierarchy,
+            trusted_tpm_path,
+            root_key_digest,
             ..
         } => {
             info!("Creating a TPM Provider.");
             Ok(Box::from(
                 TpmProviderBuilder::new()
-                    .with_key_info_store(key_info_manager)
+                    .with_key_info_store(local_key_info_manager()?)
                     .with_tcti(tcti)
                     .with_owner_hierarchy_auth(owner_hierarchy_auth.clone())
+                    .with_hierarchy(hierarchy.clone())
+                    .with_trusted_tpm_path(*trusted_tpm_path)
+                    .with_root_key_digest(root_key_digest.clone())
+                    .build()?,
+            ))
+        }
+        #[cfg(feature = "remote-provider")]
+        ProviderConfig::Remote {
+            socket_path,
+            remote_provider_type,
+            app_name_prefix,
+            timeout,
+            ..
+        } => {
+            info!("Creating a Remote Provider.");
+            Ok(Box::from(
+                RemoteProviderBuilder::new()
+                    .with_socket_path(PathBuf::from(socket_path))
+                    .with_provider_id(provider_id_from_type(remote_provider_type).ok_or_else(
+                        || Error::new(ErrorKind::InvalidData, "unknown remote provider type"),
+                    )?)
+                    .with_app_name_prefix(app_name_prefix.clone())
+                    .with_timeout(timeout.map(Duration::from_secs))
                     .build()?,
             ))
         }
         #[cfg(not(all(
             feature = "mbed-crypto-provider",
             feature = "pkcs11-provider",
-            feature = "tpm-provider"
+            feature = "tpm-provider",
+            feature = "remote-provider"
         )))]
         _ => {
             error!(
@@ -300,31 +1377,365 @@ unsafe fn get_provider(
     }
 }
 
-fn build_key_info_managers(
-    configs: &[KeyInfoManagerConfig],
-) -> Result<HashMap<String, KeyInfoManager>> {
-    let mut map = HashMap::new();
-    for config in configs {
-        let _ = map.insert(config.name.clone(), get_key_info_manager(config)?);
+#[cfg(feature = "unix-socket-listener")]
+fn build_domain_socket_listener(config: ListenerConfig) -> Result<Box<dyn Listen>> {
+    Ok(Box::new(
+        DomainSocketListenerBuilder::new()
+            .with_timeout(Duration::from_millis(config.timeout))
+            .build()?,
+    ))
+}
+
+#[cfg(not(feature = "unix-socket-listener"))]
+fn build_domain_socket_listener(_config: ListenerConfig) -> Result<Box<dyn Listen>> {
+    error!("The Domain Socket listener was not compiled in Parsec binary.");
+    Err(Error::new(ErrorKind::InvalidData, "listener not compiled"))
+}
+
+#[cfg(feature = "tls-listener")]
+fn build_tls_listener(config: ListenerConfig) -> Result<Box<dyn Listen>> {
+    let mut builder = TlsListenerBuilder::new().with_timeout(Duration::from_millis(config.timeout));
+    if let Some(tls_config) = config.tls {
+        builder = builder.with_config(tls_config);
     }
 
-    Ok(map)
+    Ok(Box::new(builder.build()?))
 }
 
-fn get_key_info_manager(config: &KeyInfoManagerConfig) -> Result<KeyInfoManager> {
-    let manager = match config.manager_type {
-        KeyInfoManagerType::OnDisk => {
-            let store_path = if let Some(store_path) = &config.store_path {
-                store_path.to_owned()
-            } else {
-                DEFAULT_MAPPINGS_PATH.to_string()
-            };
+#[cfg(not(feature = "tls-listener"))]
+fn build_tls_listener(_config: ListenerConfig) -> Result<Box<dyn Listen>> {
+    error!("The TLS listener was not compiled in Parsec binary.");
+    Err(Error::new(ErrorKind::InvalidData, "listener not compiled"))
+}
+
+#[cfg(feature = "vsock-listener")]
+fn build_vsock_listener(config: ListenerConfig) -> Result<Box<dyn Listen>> {
+    let mut builder =
+        VsockListenerBuilder::new().with_timeout(Duration::from_millis(config.timeout));
+    if let Some(vsock_config) = config.vsock {
+        builder = builder.with_config(vsock_config);
+    }
+
+    Ok(Box::new(builder.build()?))
+}
+
+#[cfg(not(feature = "vsock-listener"))]
+fn build_vsock_listener(_config: ListenerConfig) -> Result<Box<dyn Listen>> {
+    error!("The vsock listener was not compiled in Parsec binary.");
+    Err(Error::new(ErrorKind::InvalidData, "listener not compiled"))
+}
+
+fn build_authenticators(
+    config: &ServiceConfig,
+    embedded_authenticators: Vec<(AuthType, Authenticator)>,
+) -> Result<Vec<(AuthType, ChainedAuthenticator)>> {
+    let default_configs;
+    let configs = match &config.authenticator {
+        Some(configs) => configs,
+        None => {
+            default_configs = default_authenticator_configs(config);
+            &default_configs
+        }
+    };
+    if configs.is_empty() && embedded_authenticators.is_empty() {
+        error!("Parsec needs at least one authenticator to start.");
+        return Err(Error::new(ErrorKind::InvalidData, "need one authenticator"));
+    }
+
+    // The authenticators supported by the Parsec service.
+    // NOTE: order here is important. The order in which the elements are added here is the
+    // order in which they will be returned to any client requesting them!
+    let mut authenticators = Vec::new();
+    let mut auth_types = HashSet::new();
+    for authenticator_config in configs {
+        let auth_type = auth_type_from_name(&authenticator_config.auth_type).ok_or_else(|| {
+            error!(
+                "The authenticator type \"{}\" is unknown.",
+                authenticator_config.auth_type
+            );
+            Error::new(ErrorKind::InvalidData, "unknown authenticator type")
+        })?;
+        if !auth_types.insert(auth_type) {
+            error!(
+                "The {:?} authenticator is configured more than once.",
+                auth_type
+            );
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "duplicate authenticator",
+            ));
+        }
+
+        let mut authenticator = ChainedAuthenticator::new(get_authenticator(auth_type, config)?);
+        if let Some(app_name_prefix) = &authenticator_config.app_name_prefix {
+            authenticator = authenticator.with_app_name_prefix(app_name_prefix.clone());
+        }
+        if let Some(provider_types) = &authenticator_config.providers {
+            let providers = provider_types
+                .iter()
+                .map(|provider_type| {
+                    provider_id_from_type(provider_type).ok_or_else(|| {
+                        error!(
+                            "The provider type \"{}\" of the {:?} authenticator is unknown.",
+                            provider_type, auth_type
+                        );
+                        Error::new(ErrorKind::InvalidData, "unknown provider type")
+                    })
+                })
+                .collect::<Result<HashSet<ProviderID>>>()?;
+            authenticator = authenticator.with_providers(providers);
+        }
+        authenticators.push((auth_type, authenticator));
+    }
+    // The authenticators given programmatically come after the configured ones.
+    for (auth_type, authenticator) in embedded_authenticators {
+        if !auth_types.insert(auth_type) {
+            error!(
+                "The {:?} authenticator is declared more than once.",
+                auth_type
+            );
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "duplicate authenticator",
+            ));
+        }
+        authenticators.push((auth_type, ChainedAuthenticator::new(authenticator)));
+    }
+
+    Ok(authenticators)
+}
+
+// Authenticators accepted when the configuration does not declare them: the Direct one and, if
+// configured, the JwtSvid one.
+#[cfg_attr(not(feature = "jwt-svid-authenticator"), allow(unused_variables))]
+fn default_authenticator_configs(config: &ServiceConfig) -> Vec<AuthenticatorConfig> {
+    let mut auth_types = Vec::new();
+    if cfg!(feature = "direct-authenticator") {
+        auth_types.push("Direct");
+    }
+    #[cfg(feature = "jwt-svid-authenticator")]
+    {
+        if config.jwt_svid.is_some() {
+            auth_types.push("JwtSvid");
+        }
+    }
+
+    auth_types
+        .into_iter()
+        .map(|auth_type| AuthenticatorConfig {
+            auth_type: auth_type.to_string(),
+            app_name_prefix: None,
+            providers: None,
+        })
+        .collect()
+}
+
+#[cfg_attr(
+    not(any(
+        feature = "direct-authenticator",
+        feature = "unix-peer-credentials-authenticator",
+        feature = "jwt-svid-authenticator"
+    )),
+    allow(unused_variables)
+)]
+fn get_authenticator(auth_type: AuthType, config: &ServiceConfig) -> Result<Authenticator> {
+    let admins = config.core_settings.admins.clone().unwrap_or_default();
+    match auth_type {
+        #[cfg(feature = "direct-authenticator")]
+        AuthType::Direct => Ok(Box::from(DirectAuthenticator::new(admins))),
+        #[cfg(feature = "unix-peer-credentials-authenticator")]
+        AuthType::UnixPeerCredentials => {
+            Ok(Box::from(UnixPeerCredentialsAuthenticator::new(admins)))
+        }
+        #[cfg(feature = "jwt-svid-authenticator")]
+        AuthType::JwtSvid => {
+            match &config.jwt_svid {
+                Some(jwt_svid_config) => Ok(Box::from(JwtSvidAuthenticator::new(
+                    jwt_svid_config,
+                    admins,
+                ))),
+                None => {
+                    error!("The JwtSvid authenticator needs the jwt_svid section of the configuration.");
+                    Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "jwt_svid configuration missing",
+                    ))
+                }
+            }
+        }
+        _ => {
+            error!(
+                "The {:?} authenticator was not compiled in Parsec binary.",
+                auth_type
+            );
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                "authenticator not compiled",
+            ))
+        }
+    }
+}
 
-            OnDiskKeyInfoManagerBuilder::new()
-                .with_mappings_dir_path(PathBuf::from(store_path))
-                .build()?
+fn get_key_info_manager(config: &KeyInfoManagerConfig) -> Result<KeyInfoManager> {
+    let manager: Box<dyn ManageKeyInfo + Send + Sync> = match config.manager_type {
+        KeyInfoManagerType::OnDisk => get_on_disk_key_info_manager(config)?,
+        KeyInfoManagerType::Sqlite => {
+            if config.integrity_key.is_some() {
+                error!(
+                    "The integrity tag of the mappings is only supported by the OnDisk manager."
+                );
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "integrity tag not supported",
+                ));
+            }
+            get_sqlite_key_info_manager(config)?
         }
+        KeyInfoManagerType::Memory => get_memory_key_info_manager(config)?,
     };
 
-    Ok(Arc::new(RwLock::new(manager)))
+    let key_info_store = KeyInfoStore::new(manager).or_else(|e| {
+        format_error!("Failed to load the mappings of the Key Info Manager", e);
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "key info manager loading failed",
+        ))
+    })?;
+
+    Ok(Arc::new(key_info_store))
+}
+
+#[cfg(feature = "on-disk-manager")]
+fn get_on_disk_key_info_manager(
+    config: &KeyInfoManagerConfig,
+) -> Result<Box<dyn ManageKeyInfo + Send + Sync>> {
+    let store_path = if let Some(store_path) = &config.store_path {
+        store_path.to_owned()
+    } else {
+        DEFAULT_MAPPINGS_PATH.to_string()
+    };
+
+    let mut builder =
+        OnDiskKeyInfoManagerBuilder::new().with_mappings_dir_path(PathBuf::from(store_path));
+    if let Some(integrity_key) = &config.integrity_key {
+        // The key must not be written next to the mappings it protects.
+        let integrity_key = Zeroizing::new(
+            secrets::read_external_secret(integrity_key).unwrap_or_else(|| {
+                error!("The integrity key of the mappings must be read from a file, an environment variable or a systemd credential.");
+                Err(Error::new(ErrorKind::InvalidData, "invalid integrity key"))
+            })?,
+        );
+        builder = builder.with_integrity_tag(IntegrityTag::new(
+            &integrity_key,
+            config.create_integrity_tag.unwrap_or(false),
+        )?);
+    }
+
+    Ok(Box::new(builder.build()?))
+}
+
+#[cfg(not(feature = "on-disk-manager"))]
+fn get_on_disk_key_info_manager(
+    _config: &KeyInfoManagerConfig,
+) -> Result<Box<dyn ManageKeyInfo + Send + Sync>> {
+    error!("The OnDisk Key Info Manager was not compiled in Parsec binary.");
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        "key info manager not compiled",
+    ))
+}
+
+#[cfg(feature = "memory-manager")]
+fn get_memory_key_info_manager(
+    config: &KeyInfoManagerConfig,
+) -> Result<Box<dyn ManageKeyInfo + Send + Sync>> {
+    if config.store_path.is_some() || config.integrity_key.is_some() {
+        error!("The Memory Key Info Manager does not store the mappings.");
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "memory key info manager configured with a store",
+        ));
+    }
+    warn!(
+        "The mappings of the Key Info Manager \"{}\" are lost when the service stops.",
+        config.name
+    );
+    Ok(Box::new(MemoryKeyInfoManager::new()))
+}
+
+#[cfg(not(feature = "memory-manager"))]
+fn get_memory_key_info_manager(
+    _config: &KeyInfoManagerConfig,
+) -> Result<Box<dyn ManageKeyInfo + Send + Sync>> {
+    error!("The Memory Key Info Manager was not compiled in Parsec binary.");
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        "key info manager not compiled",
+    ))
+}
+
+#[cfg(feature = "sqlite-manager")]
+fn get_sqlite_key_info_manager(
+    config: &KeyInfoManagerConfig,
+) -> Result<Box<dyn ManageKeyInfo + Send + Sync>> {
+    let store_path = config
+        .store_path
+        .clone()
+        .unwrap_or_else(|| DEFAULT_DATABASE_PATH.to_string());
+
+    Ok(Box::new(
+        SqliteKeyInfoManagerBuilder::new()
+            .with_database_path(PathBuf::from(store_path))
+            .build()?,
+    ))
+}
+
+#[cfg(not(feature = "sqlite-manager"))]
+fn get_sqlite_key_info_manager(
+    _config: &KeyInfoManagerConfig,
+) -> Result<Box<dyn ManageKeyInfo + Send + Sync>> {
+    error!("The Sqlite Key Info Manager was not compiled in Parsec binary.");
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        "key info manager not compiled",
+    ))
+}
+
+#[cfg(all(test, feature = "memory-manager"))]
+mod test {
+    use super::KeyInfoManagers;
+    use crate::key_info_managers::{KeyInfoManagerConfig, KeyInfoManagerType};
+    use std::sync::Arc;
+
+    fn memory_manager_config(name: &str) -> KeyInfoManagerConfig {
+        KeyInfoManagerConfig {
+            name: String::from(name),
+            manager_type: KeyInfoManagerType::Memory,
+            store_path: None,
+            integrity_key: None,
+            create_integrity_tag: None,
+        }
+    }
+
+    #[test]
+    fn unchanged_managers_kept() {
+        let mut key_info_managers = KeyInfoManagers::default();
+        let configs = vec![
+            memory_manager_config("first"),
+            memory_manager_config("second"),
+        ];
+        let built = key_info_managers.update(&configs).unwrap();
+
+        let mut changed = memory_manager_config("second");
+        changed.create_integrity_tag = Some(false);
+        let reloaded = key_info_managers
+            .update(&[memory_manager_config("first"), changed])
+            .unwrap();
+        assert!(Arc::ptr_eq(&built["first"], &reloaded["first"]));
+        assert!(!Arc::ptr_eq(&built["second"], &reloaded["second"]));
+
+        let reloaded = key_info_managers.update(&configs[1..]).unwrap();
+        assert_eq!(reloaded.len(), 1);
+        assert!(reloaded.contains_key("second"));
+    }
 }
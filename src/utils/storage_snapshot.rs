@@ -0,0 +1,232 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Snapshot of the persistent storage of Mbed Crypto with its mappings
+//!
+//! Copying the files of Mbed Crypto and the mappings directory while the service runs can catch a
+//! key created or destroyed between the two copies, leaving a mapping without its key or a key
+//! without its mapping once restored. The snapshot is instead taken by the Mbed Crypto provider
+//! while it holds its Key Info Manager locked for writing, which all the key creations and
+//! destructions also do: the operations modifying the keys wait for the snapshot to be taken and
+//! resume after it, the others are not blocked.
+//!
+//! The snapshot is written as a tar archive, to a temporary file synced and renamed over the
+//! previous archive. It contains:
+//! * `mbed-crypto/<file>`: the `.psa_its` files of Mbed Crypto, found in the working directory of
+//!   the service;
+//! * `mappings/<application>/<provider>/<key>`: the mappings of the keys of Mbed Crypto, with the
+//!   layout and the encoding of the on-disk manager.
+//!
+//! To restore it, stop the service and extract the two directories in the working directory of the
+//! service and in the directory of the on-disk manager, in place of the existing ones. The archive
+//! does not contain the integrity tag of the mappings: if the on-disk manager checks one, set its
+//! `create_integrity_tag` option for the next start to tag the restored mappings.
+//!
+//! The snapshot is taken with the `--snapshot-storage` flag when the service starts and, if the
+//! `interval` of the `[storage_snapshot]` section is set, periodically.
+use crate::back::dispatcher::Dispatcher;
+use crate::key_info_managers::key_info_encoding;
+use crate::key_info_managers::on_disk_manager;
+use log::{error, info};
+use parsec_interface::requests::ProviderID;
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+
+/// Size of the blocks of a tar archive
+const BLOCK_SIZE: usize = 512;
+/// Longest path which fits in the header of a tar entry
+const MAX_HEADER_PATH_LEN: usize = 100;
+/// Directory of the archive containing the files of Mbed Crypto
+const MBED_CRYPTO_DIR: &str = "mbed-crypto";
+/// Directory of the archive containing the mappings
+const MAPPINGS_DIR: &str = "mappings";
+
+/// Configuration of the snapshots of the storage
+#[derive(Clone, Deserialize, Debug)]
+pub struct StorageSnapshotConfig {
+    /// Path of the archive written
+    pub path: String,
+    /// Interval between two snapshots, in seconds, none being taken periodically if unset or 0
+    pub interval: Option<u64>,
+}
+
+/// Takes a snapshot of the storage of the Mbed Crypto provider and of its mappings, and writes it
+/// to the archive configured.
+///
+/// # Errors
+///
+/// Returns an error if the Mbed Crypto provider is not available, if it failed to take the
+/// snapshot or if the archive could not be written.
+pub fn snapshot(config: &StorageSnapshotConfig, dispatcher: &Dispatcher) -> Result<()> {
+    let backend = dispatcher.backend(ProviderID::MbedCrypto).ok_or_else(|| {
+        error!("The Mbed Crypto provider is not available.");
+        Error::new(ErrorKind::Other, "Mbed Crypto provider not available")
+    })?;
+    let storage = backend
+        .snapshot_storage()
+        .map_err(|status| Error::new(ErrorKind::Other, status.to_string()))?;
+
+    let mut archive = Vec::new();
+    for (name, content) in &storage.files {
+        append_entry(
+            &mut archive,
+            &format!("{}/{}", MBED_CRYPTO_DIR, name),
+            content,
+        )?;
+    }
+    for (key_triple, key_info) in &storage.mappings {
+        let path = PathBuf::from(MAPPINGS_DIR).join(on_disk_manager::mapping_path(key_triple));
+        let content = key_info_encoding::encode(key_info).map_err(|e| {
+            format_error!("Error serializing key info", e);
+            Error::new(ErrorKind::Other, "error serializing key info")
+        })?;
+        append_entry(&mut archive, &path.to_string_lossy(), &content)?;
+    }
+    finish(&mut archive);
+
+    let path = PathBuf::from(&config.path);
+    let temporary_path = path.with_extension("tmp");
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(&archive)?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, &path)?;
+
+    info!(
+        "Snapshot of {} files of Mbed Crypto and {} mappings written to {}.",
+        storage.files.len(),
+        storage.mappings.len(),
+        config.path
+    );
+
+    Ok(())
+}
+
+// Writes a numeric field of a header, as a NUL-terminated octal number.
+fn write_octal(field: &mut [u8], value: u64) -> Result<()> {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    if digits.len() >= field.len() {
+        error!("A value is too large for the tar header.");
+        return Err(Error::new(ErrorKind::InvalidData, "value too large"));
+    }
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+    Ok(())
+}
+
+// Appends the header of an entry, whose path must fit in it.
+fn append_header(archive: &mut Vec<u8>, path: &str, size: u64, entry_type: u8) -> Result<()> {
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..path.len()].copy_from_slice(path.as_bytes());
+    write_octal(&mut header[100..108], 0o600)?;
+    write_octal(&mut header[108..116], 0)?;
+    write_octal(&mut header[116..124], 0)?;
+    write_octal(&mut header[124..136], size)?;
+    write_octal(&mut header[136..148], 0)?;
+    header[156] = entry_type;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // The checksum is computed with its own field filled with spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = header.iter().map(|byte| u64::from(*byte)).sum();
+    write_octal(&mut header[148..155], checksum)?;
+    header[155] = b' ';
+    archive.extend_from_slice(&header);
+    Ok(())
+}
+
+// Appends data, padded to a whole number of blocks.
+fn append_data(archive: &mut Vec<u8>, data: &[u8]) {
+    archive.extend_from_slice(data);
+    let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+    archive.resize(archive.len() + padding, 0);
+}
+
+// Appends a file. Paths too long for the header, as the base64 names of the mappings can be, are
+// given in a PAX extended header.
+fn append_entry(archive: &mut Vec<u8>, path: &str, content: &[u8]) -> Result<()> {
+    if path.len() > MAX_HEADER_PATH_LEN {
+        let record = pax_record("path", path);
+        append_header(archive, "PaxHeader", record.len() as u64, b'x')?;
+        append_data(archive, &record);
+        append_header(
+            archive,
+            &path[path.len() - MAX_HEADER_PATH_LEN..],
+            content.len() as u64,
+            b'0',
+        )?;
+    } else {
+        append_header(archive, path, content.len() as u64, b'0')?;
+    }
+    append_data(archive, content);
+    Ok(())
+}
+
+// Encodes a PAX record, "<length> <key>=<value>\n", the length counting its own digits.
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let record_len = key.len() + value.len() + 3;
+    let mut len = record_len + record_len.to_string().len();
+    if len.to_string().len() != record_len.to_string().len() {
+        len += 1;
+    }
+    format!("{} {}={}\n", len, key, value).into_bytes()
+}
+
+// Ends the archive with two empty blocks.
+fn finish(archive: &mut Vec<u8>) {
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{append_entry, finish, pax_record, BLOCK_SIZE};
+
+    fn octal(field: &[u8]) -> u64 {
+        let digits = std::str::from_utf8(field)
+            .unwrap()
+            .trim_matches(|c| c == '\0' || c == ' ');
+        u64::from_str_radix(digits, 8).unwrap()
+    }
+
+    #[test]
+    fn entries_archived() {
+        let mut archive = Vec::new();
+        append_entry(&mut archive, "mbed-crypto/00000001.psa_its", b"key").unwrap();
+        finish(&mut archive);
+        assert_eq!(archive.len(), 4 * BLOCK_SIZE);
+
+        let header = &archive[..BLOCK_SIZE];
+        assert_eq!(&header[..28], b"mbed-crypto/00000001.psa_its");
+        assert_eq!(octal(&header[124..136]), 3);
+        assert_eq!(header[156], b'0');
+        assert_eq!(&header[257..265], b"ustar\000");
+        let mut unsigned = header.to_vec();
+        unsigned[148..156].copy_from_slice(b"        ");
+        assert_eq!(
+            octal(&header[148..156]),
+            unsigned.iter().map(|byte| u64::from(*byte)).sum::<u64>()
+        );
+        assert_eq!(&archive[BLOCK_SIZE..BLOCK_SIZE + 3], b"key");
+        assert!(archive[BLOCK_SIZE + 3..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn long_paths_in_pax_header() {
+        let path = format!("mappings/{}/1/{}", "a".repeat(80), "b".repeat(80));
+        let mut archive = Vec::new();
+        append_entry(&mut archive, &path, b"").unwrap();
+
+        assert_eq!(archive[156], b'x');
+        let record = pax_record("path", &path);
+        assert_eq!(&archive[BLOCK_SIZE..BLOCK_SIZE + record.len()], &record[..]);
+        assert_eq!(archive[2 * BLOCK_SIZE + 156], b'0');
+        assert_eq!(archive.len(), 3 * BLOCK_SIZE);
+    }
+
+    #[test]
+    fn pax_record_length_counted() {
+        assert_eq!(pax_record("path", "a"), b"9 path=a\n".to_vec());
+        // The length going from one to two digits when it counts itself.
+        assert_eq!(pax_record("path", "ab"), b"11 path=ab\n".to_vec());
+    }
+}
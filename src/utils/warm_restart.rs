@@ -0,0 +1,71 @@
+// Copyright 2020 Contributors to the Parsec project.
+// SPDX-License-Identifier: Apache-2.0
+//! Hand-off of the listener to a new binary of the service
+//!
+//! Restarting the service after a package update removes its socket for the time it takes to start
+//! again: the clients connecting in between are refused and all reconnect at once. When SIGUSR2 is
+//! received, the service instead stops accepting connections, finishes the requests being handled
+//! and executes the service binary, with the same arguments, in place of the current process. The
+//! listening socket stays open across the exec and is given to the new binary as systemd does with
+//! socket activation: the connections arriving in the meantime wait in its backlog and are served
+//! once the new binary is ready. The process ID being kept, systemd keeps supervising the service.
+//!
+//! Each request is handled on its own connection, so no connection state has to be handed off. The
+//! state only kept in memory, such as the process bindings of the keys or the mappings of the
+//! in-memory Key Info Manager, is lost as on a configuration reload. If the binary can not be
+//! executed, the service reloads its configuration and keeps running.
+use std::env;
+use std::io::{Error, Result};
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{self, Command};
+
+// Makes the listener available to the new binary at the file descriptor where systemd would give
+// it.
+fn hand_off(listener_fd: RawFd) -> Result<()> {
+    let handed_off_fd = sd_notify::SD_LISTEN_FDS_START;
+    // Safety: both calls only act on file descriptors and dup2 atomically closes the one replaced,
+    // which all the file descriptors opened by the service are about to be anyway on exec.
+    let ret = if listener_fd == handed_off_fd {
+        // The descriptor is kept open across the exec.
+        unsafe { libc::fcntl(listener_fd, libc::F_SETFD, 0) }
+    } else {
+        // The duplicated descriptor is not closed on exec.
+        unsafe { libc::dup2(listener_fd, handed_off_fd) }
+    };
+    if ret < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Executes the service binary in place of the current process, handing off the listener if its
+/// file descriptor is given. The binary defaults to the program the service was started with.
+///
+/// Only returns, with the error, if the binary could not be executed.
+pub fn exec(executable: Option<&str>, listener_fd: Option<RawFd>) -> Error {
+    let mut args = env::args_os();
+    let program = args.next();
+    let mut command = match (executable, program) {
+        (Some(executable), _) => Command::new(executable),
+        (None, Some(program)) => Command::new(program),
+        (None, None) => Command::new("parsec"),
+    };
+    let _ = command.args(args).env_remove("LISTEN_FDNAMES");
+    match listener_fd {
+        Some(listener_fd) => {
+            if let Err(error) = hand_off(listener_fd) {
+                return error;
+            }
+            let _ = command
+                .env("LISTEN_FDS", "1")
+                .env("LISTEN_PID", process::id().to_string());
+        }
+        None => {
+            let _ = command.env_remove("LISTEN_FDS").env_remove("LISTEN_PID");
+        }
+    }
+
+    command.exec()
+}
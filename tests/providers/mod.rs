@@ -1,4 +1,4 @@
 // Copyright 2020 Contributors to the Parsec project.
 // SPDX-License-Identifier: Apache-2.0
-#[cfg(feature = "tpm-provider")]
+#[cfg(all(feature = "tpm-provider", feature = "on-disk-manager"))]
 mod tpm;
@@ -8,6 +8,7 @@ use parsec_interface::operations::{
 };
 use parsec_interface::requests::ResponseStatus;
 use parsec_service::authenticators::ApplicationName;
+use parsec_service::key_info_managers::key_info_store::KeyInfoStore;
 use parsec_service::key_info_managers::on_disk_manager::OnDiskKeyInfoManagerBuilder;
 use parsec_service::providers::tpm_provider::{TpmProvider, TpmProviderBuilder};
 use parsec_service::providers::Provide;
@@ -15,7 +16,7 @@ use ring::digest;
 use ring::signature::{self, UnparsedPublicKey};
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 
 lazy_static! {
     static ref TPM_PROVIDER: TpmProvider = {
@@ -25,7 +26,7 @@ lazy_static! {
             .unwrap();
         unsafe {
             TpmProviderBuilder::new()
-                .with_key_info_store(Arc::from(RwLock::from(kis)))
+                .with_key_info_store(Arc::new(KeyInfoStore::new(Box::new(kis)).unwrap()))
                 .with_tcti("mssim")
                 .with_owner_hierarchy_auth(String::from("tpm_pass"))
                 .build()
@@ -206,3 +207,17 @@ fn wildcard_hash_not_supported() {
         ResponseStatus::PsaErrorNotSupported
     );
 }
+
+#[test]
+fn quote_verified_with_ring() {
+    let nonce = vec![0x5a; 32];
+    let quote = TPM_PROVIDER.quote(nonce.clone()).unwrap();
+
+    let pk = UnparsedPublicKey::new(&signature::RSA_PKCS1_2048_8192_SHA256, &quote.public_key);
+    pk.verify(&quote.attest, &quote.signature).unwrap();
+    // The nonce is the qualifying data of the attest structure.
+    assert!(quote
+        .attest
+        .windows(nonce.len())
+        .any(|window| window == &nonce[..]));
+}